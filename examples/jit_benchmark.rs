@@ -0,0 +1,94 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Compare the run time of a filter in the interpreter and once JIT-compiled, on the same
+//! packets, and check that both return the same values. The filter parses the Ethernet, IPv4 and
+//! TCP or UDP headers of the packet, and returns a hash of its addresses and ports, or 0 if the
+//! packet is not TCP or UDP over IPv4.
+//!
+//! ```text
+//! cargo run --release --example jit_benchmark -- [RUNS]
+//! ```
+//!
+//! Each packet of a set of 64 packets is run `RUNS` times by each engine, 1000 by default.
+
+extern crate rbpf;
+
+use std::time::{Duration, Instant};
+
+use rbpf::packet::{PacketBuilder, TCP_SYN};
+
+const FLOW_HASH: &str = "
+    mov64 r0, 0
+    ldxh r2, [r1+12]
+    jne r2, 0x0008, +16
+    ldxb r2, [r1+23]
+    jeq r2, 6, +1
+    jne r2, 17, +13
+    ldxb r3, [r1+14]
+    and64 r3, 0xf
+    lsh64 r3, 2
+    add64 r3, r1
+    ldxw r4, [r1+26]
+    ldxw r5, [r1+30]
+    ldxw r6, [r3+14]
+    mov64 r0, r4
+    xor64 r0, r5
+    mul64 r0, 0x9e3779b1
+    xor64 r0, r6
+    mul64 r0, 0x9e3779b1
+    rsh64 r0, 16
+    exit
+";
+
+// Run `exec` on each packet `runs` times, and return the values returned and the time taken.
+fn bench<F: FnMut(&mut Vec<u8>) -> u64>(packets: &[Vec<u8>], runs: usize, mut exec: F) -> (Vec<u64>, Duration) {
+    let mut packets = packets.to_vec();
+    let mut results = vec![];
+    let start = Instant::now();
+    for packet in packets.iter_mut() {
+        let mut res = 0;
+        for _ in 0..runs {
+            res = exec(packet);
+        }
+        results.push(res);
+    }
+    (results, start.elapsed())
+}
+
+fn main() {
+    let runs = match std::env::args().nth(1) {
+        Some(runs) => runs.parse().expect("Usage: jit_benchmark [RUNS]"),
+        None       => 1000,
+    };
+    let prog = rbpf::assembler::assemble(FLOW_HASH).unwrap();
+    let packets: Vec<Vec<u8>> = (0..64u16).map(|i| {
+        let packet = PacketBuilder::new()
+            .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4([10, 0, (i >> 8) as u8, i as u8], [10, 0, 0, 254]);
+        match i % 3 {
+            0 => packet.udp(1024 + i, 53).payload(&[0; 32]).build(),
+            1 => packet.tcp(1024 + i, 443, TCP_SYN).build(),
+            _ => PacketBuilder::new().ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]).payload(&[0; 46]).build(),
+        }
+    }).collect();
+
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    let (interpreted, interpreter_time) = bench(&packets, runs, |packet| vm.prog_exec(packet));
+    vm.jit_compile();
+    let (compiled, jit_time) = bench(&packets, runs, |packet| vm.prog_exec_jit(packet));
+    assert_eq!(interpreted, compiled);
+    assert_eq!(interpreted.iter().filter(|&&res| res != 0).count(), 43);
+
+    let executions = (packets.len() * runs) as f64;
+    let per_packet = |time: Duration| time.as_nanos() as f64 / executions;
+    println!("{} executions, JIT-compiled code of {} bytes", packets.len() * runs,
+             vm.stats().jit_code_size.unwrap_or(0));
+    println!("interpreter: {:>8.1} ns/packet", per_packet(interpreter_time));
+    println!("JIT:         {:>8.1} ns/packet", per_packet(jit_time));
+    println!("speedup:     {:>8.1}x", per_packet(interpreter_time) / per_packet(jit_time).max(f64::MIN_POSITIVE));
+}
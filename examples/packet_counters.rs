@@ -0,0 +1,123 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Count packets with maps: a program increments, for each IPv4 packet, a counter of its IP
+//! protocol in an array map, and a counter of its source address in a hash map, inserting the
+//! latter on the first packet from the address. Several threads run the program, each with its own
+//! VM and the same maps, then the application reads the counters.
+//!
+//! ```text
+//! cargo run --example packet_counters
+//! ```
+
+extern crate rbpf;
+
+use std::sync::Arc;
+use std::thread;
+
+use rbpf::maps::{Map, MapType};
+use rbpf::packet::{PacketBuilder, TCP_SYN};
+
+const COUNTERS: &str = "
+    mov64 r6, r1
+    ldxh r2, [r6+12]
+    jne r2, 0x0008, +28
+
+    # Counter of the IP protocol, in map 0.
+    ldxb r2, [r6+23]
+    stxw [r10-4], r2
+    mov64 r1, 0
+    mov64 r2, r10
+    add64 r2, -4
+    call 1
+    jeq r0, 0, +3
+    ldxdw r1, [r0]
+    add64 r1, 1
+    stxdw [r0], r1
+
+    # Counter of the source address, in map 1.
+    ldxw r2, [r6+26]
+    stxw [r10-8], r2
+    mov64 r1, 1
+    mov64 r2, r10
+    add64 r2, -8
+    call 1
+    jeq r0, 0, +4
+    ldxdw r1, [r0]
+    add64 r1, 1
+    stxdw [r0], r1
+    ja +8
+
+    # First packet from this address: insert its counter.
+    stdw [r10-16], 1
+    mov64 r1, 1
+    mov64 r2, r10
+    add64 r2, -8
+    mov64 r3, r10
+    add64 r3, -16
+    mov64 r4, 0
+    call 2
+
+    mov64 r0, 0
+    exit
+";
+
+fn counter(value: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(value);
+    u64::from_le_bytes(bytes)
+}
+
+fn main() {
+    let prog = rbpf::assembler::assemble(COUNTERS).unwrap();
+    let protocols = Arc::new(Map::new(MapType::Array, 4, 8, 256));
+    let sources = Arc::new(Map::new(MapType::Hash, 4, 8, 1024));
+
+    // Each thread sends 10 UDP packets from its own address, and 5 TCP packets from a common one.
+    let workers: Vec<_> = (1..=4u8).map(|host| {
+        let (prog, protocols, sources) = (prog.clone(), protocols.clone(), sources.clone());
+        thread::spawn(move || {
+            let mut vm = rbpf::EbpfVmRaw::new(&prog);
+            assert_eq!(vm.register_map(protocols), 0);
+            assert_eq!(vm.register_map(sources), 1);
+            let packet = |src: [u8; 4]| PacketBuilder::new()
+                .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+                .ipv4(src, [10, 0, 0, 254]);
+            for _ in 0..10 {
+                vm.prog_exec(&mut packet([10, 0, 0, host]).udp(4000, 53).build());
+            }
+            for _ in 0..5 {
+                vm.prog_exec(&mut packet([192, 168, 0, 1]).tcp(4000, 80, TCP_SYN).build());
+            }
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    println!("Packets by IP protocol:");
+    for (key, value) in protocols.iter().filter(|(_, value)| counter(value) != 0) {
+        println!("  {:>3}: {}", key[0], counter(&value));
+    }
+    println!("Packets by source address:");
+    let mut by_source: Vec<_> = sources.iter().collect();
+    by_source.sort();
+    for (key, value) in by_source.iter() {
+        println!("  {}.{}.{}.{}: {}", key[0], key[1], key[2], key[3], counter(value));
+    }
+
+    assert_eq!(protocols.lookup(&17u32.to_le_bytes()).map(|v| counter(&v)), Some(40));
+    assert_eq!(protocols.lookup(&6u32.to_le_bytes()).map(|v| counter(&v)), Some(20));
+    assert_eq!(sources.len(), 5);
+    for host in 1..=4 {
+        assert_eq!(sources.lookup(&[10, 0, 0, host]).map(|v| counter(&v)), Some(10));
+    }
+    // The threads insert the counter of the common address concurrently: as in the kernel, the
+    // increments of the threads that failed to insert it may be lost, as well as concurrent ones.
+    let common = sources.lookup(&[192, 168, 0, 1]).map(|v| counter(&v)).unwrap();
+    assert!((5..=20).contains(&common));
+}
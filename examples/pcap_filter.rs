@@ -0,0 +1,186 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Load a filter from a section of an ELF object file, such as one compiled from C with
+//! `clang -target bpf -c`, and run it on each packet of a pcap file, with R1 pointing to the
+//! packet data as with `EbpfVmRaw`. The packets for which the filter returns a non-zero value are
+//! accepted. Relocations are not applied: the program must not use maps or global data.
+//!
+//! ```text
+//! cargo run --example pcap_filter --features pcap -- OBJECT SECTION CAPTURE
+//! ```
+//!
+//! Without arguments, the example builds an object file with a filter accepting the UDP packets
+//! to port 53, and a capture of a few packets, and checks the packets accepted.
+
+extern crate rbpf;
+
+use std::fs;
+use std::io::{self, Cursor};
+use std::process;
+
+use rbpf::capture::{self, PcapReader};
+use rbpf::packet::{PacketBuilder, TCP_SYN};
+
+const USAGE: &str = "Usage: pcap_filter [OBJECT SECTION CAPTURE]";
+
+// Accepts the UDP packets over IPv4 to port 53, assuming IPv4 headers without options.
+const DNS_FILTER: &str = "
+    mov64 r0, 0
+    ldxh r2, [r1+12]
+    jne r2, 0x0008, +5
+    ldxb r2, [r1+23]
+    jne r2, 17, +3
+    ldxh r2, [r1+36]
+    jne r2, 0x3500, +1
+    mov64 r0, 1
+    exit
+";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// The `len` bytes of the object file at `offset`.
+fn bytes(object: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    offset.checked_add(len).and_then(|end| object.get(offset..end)).ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn u16_at(object: &[u8], offset: usize) -> io::Result<usize> {
+    let b = bytes(object, offset, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+}
+
+fn u32_at(object: &[u8], offset: usize) -> io::Result<usize> {
+    let b = bytes(object, offset, 4)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+fn u64_at(object: &[u8], offset: usize) -> io::Result<usize> {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(bytes(object, offset, 8)?);
+    Ok(u64::from_le_bytes(b) as usize)
+}
+
+// The contents of section `name` of a 64-bit little-endian ELF object file.
+fn elf_section(object: &[u8], name: &str) -> io::Result<Vec<u8>> {
+    if !object.starts_with(b"\x7fELF\x02\x01") {
+        return Err(invalid("not a 64-bit little-endian ELF file"));
+    }
+    let (shoff, shentsize) = (u64_at(object, 0x28)?, u16_at(object, 0x3a)?);
+    let (shnum, shstrndx) = (u16_at(object, 0x3c)?, u16_at(object, 0x3e)?);
+    let header = |index: usize| shoff + index * shentsize;
+    let strtab = u64_at(object, header(shstrndx) + 0x18)?;
+    for index in 0..shnum {
+        let name_offset = strtab + u32_at(object, header(index))?;
+        let section_name = object.get(name_offset..).unwrap_or_default().split(|&b| b == 0).next();
+        if section_name == Some(name.as_bytes()) {
+            let (offset, size) = (u64_at(object, header(index) + 0x18)?, u64_at(object, header(index) + 0x20)?);
+            return Ok(bytes(object, offset, size)?.to_vec());
+        }
+    }
+    Err(invalid(&format!("no section {:?}", name)))
+}
+
+// A relocatable ELF object file for eBPF, with the program in section `name`, as clang emits.
+fn elf_object(name: &str, prog: &[u8]) -> Vec<u8> {
+    let strtab = format!("\0{}\0.shstrtab\0", name).into_bytes();
+    let strtab_offset = 64 + prog.len();
+    let shoff = (strtab_offset + strtab.len() + 7) & !7;
+    let mut object = b"\x7fELF\x02\x01\x01".to_vec();
+    object.resize(16, 0);
+    // Type ET_REL, machine EM_BPF, version 1, and no entry point or program header.
+    object.extend_from_slice(&[1, 0, 247, 0, 1, 0, 0, 0]);
+    object.extend_from_slice(&[0; 16]);
+    object.extend_from_slice(&(shoff as u64).to_le_bytes());
+    object.extend_from_slice(&[0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 64, 0, 3, 0, 2, 0]);
+    object.extend_from_slice(prog);
+    object.extend_from_slice(&strtab);
+    object.resize(shoff, 0);
+    // The null section, the program (SHT_PROGBITS, executable) and the names (SHT_STRTAB).
+    let sections = [(0, 0, 0, 0, 0), (1, 1, 6, 64, prog.len()), (name.len() + 2, 3, 0, strtab_offset, strtab.len())];
+    for &(name, kind, flags, offset, size) in sections.iter() {
+        object.extend_from_slice(&(name as u32).to_le_bytes());
+        object.extend_from_slice(&(kind as u32).to_le_bytes());
+        object.extend_from_slice(&(flags as u64).to_le_bytes());
+        object.extend_from_slice(&[0; 8]);
+        object.extend_from_slice(&(offset as u64).to_le_bytes());
+        object.extend_from_slice(&(size as u64).to_le_bytes());
+        object.extend_from_slice(&[0; 24]);
+    }
+    object
+}
+
+// A capture of Ethernet packets in pcap format.
+fn pcap_file(packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut file = vec![];
+    for word in [0xa1b2_c3d4u32, 0x0004_0002, 0, 0, 0xffff, 1].iter() {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+    for (i, packet) in packets.iter().enumerate() {
+        for word in [i as u32, 0, packet.len() as u32, packet.len() as u32].iter() {
+            file.extend_from_slice(&word.to_le_bytes());
+        }
+        file.extend_from_slice(packet);
+    }
+    file
+}
+
+// Run the filter on each packet of the capture, and return the indexes of the packets accepted.
+// The packets too short for the filter are rejected.
+fn filter(prog: &[u8], capture: &[u8]) -> io::Result<Vec<usize>> {
+    let prog = prog.to_vec();
+    rbpf::verifier::try_check(&prog).map_err(|err| invalid(&err.to_string()))?;
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let mut source = PcapReader::new(Cursor::new(capture))?;
+    let (mut index, mut accepted) = (0, vec![]);
+    capture::run(&mut source, |data| vm.try_prog_exec(data).unwrap_or(0), |ret, packet| {
+        if ret != 0 {
+            println!("packet {}: {} bytes, accepted ({})", index, packet.orig_len, ret);
+            accepted.push(index);
+        }
+        index += 1;
+    })?;
+    println!("{} packets, {} accepted", index, accepted.len());
+    Ok(accepted)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.len() {
+        3 => fs::read(&args[0])
+            .and_then(|object| elf_section(&object, &args[1]))
+            .and_then(|prog| filter(&prog, &fs::read(&args[2])?))
+            .map(|_| ()),
+        0 => {
+            let prog = rbpf::assembler::assemble(DNS_FILTER).unwrap();
+            let object = elf_object("socket", &prog);
+            assert_eq!(elf_section(&object, "socket").unwrap(), prog);
+
+            let (src, dst) = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
+            let ip = |packet: PacketBuilder| packet.ethernet(src, dst).ipv4([10, 0, 0, 1], [10, 0, 0, 2]);
+            let packets = vec![
+                ip(PacketBuilder::new()).udp(4000, 53).payload(b"query").build(),
+                ip(PacketBuilder::new()).tcp(4000, 53, TCP_SYN).build(),
+                ip(PacketBuilder::new()).udp(4000, 123).build(),
+                PacketBuilder::new().ethernet(src, dst).payload(&[0; 8]).build(),
+                ip(PacketBuilder::new()).udp(5000, 53).build(),
+            ];
+            let accepted = elf_section(&object, "socket").and_then(|prog| filter(&prog, &pcap_file(&packets)));
+            assert_eq!(accepted.unwrap(), vec![0, 4]);
+            Ok(())
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(err) = result {
+        eprintln!("pcap_filter: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Run a pipeline of programs chained with tail calls, in a `registry::ProgramRegistry`: a parser
+//! checks that the packet is an IPv4 packet, and tail-calls the classifier of its IP protocol,
+//! found at the index of the protocol in a program array. The classifiers return 1 to accept the
+//! packet, and the parser returns 0 to drop it when no classifier handles the protocol.
+//!
+//! Replacing a program in the registry, or a slot of the program array, changes the pipeline
+//! without reloading the other programs.
+//!
+//! ```text
+//! cargo run --example tail_call_pipeline
+//! ```
+
+extern crate rbpf;
+
+use rbpf::packet::{PacketBuilder, IPPROTO_TCP, IPPROTO_UDP, TCP_ACK, TCP_SYN};
+use rbpf::registry::ProgramRegistry;
+
+// Tail-calls the program at the index of the IP protocol in program array 0.
+const PARSER: &str = "
+    mov64 r0, 0
+    ldxh r2, [r1+12]
+    jne r2, 0x0008, +4
+    ldxb r3, [r1+23]
+    mov64 r2, 0
+    call 12
+    mov64 r0, 0
+    exit
+";
+
+// Accepts the TCP packets to port 22, other than those opening a connection (flag SYN).
+const SSH_ESTABLISHED: &str = "
+    mov64 r0, 0
+    ldxh r2, [r1+36]
+    jne r2, 0x1600, +3
+    ldxb r2, [r1+47]
+    jset r2, 0x02, +1
+    mov64 r0, 1
+    exit
+";
+
+// Accepts the UDP packets to port 53.
+const DNS: &str = "
+    mov64 r0, 0
+    ldxh r2, [r1+36]
+    jne r2, 0x3500, +1
+    mov64 r0, 1
+    exit
+";
+
+// Accepts all the packets.
+const ACCEPT: &str = "
+    mov64 r0, 1
+    exit
+";
+
+fn run(registry: &ProgramRegistry, packets: &[Vec<u8>]) -> Vec<u64> {
+    packets.iter().map(|packet| registry.prog_exec("parser", &mut packet.clone(), &mut [])).collect()
+}
+
+fn main() {
+    let mut registry = ProgramRegistry::new();
+    for &(name, source) in [("parser", PARSER), ("tcp", SSH_ESTABLISHED), ("udp", DNS)].iter() {
+        let prog = rbpf::assembler::assemble(source).unwrap();
+        rbpf::verifier::try_check(&prog).unwrap();
+        registry.load(name, prog);
+    }
+    registry.create_prog_array(0, 256);
+    registry.set_tail_call(0, IPPROTO_TCP as u32, "tcp");
+    registry.set_tail_call(0, IPPROTO_UDP as u32, "udp");
+
+    let ip = || PacketBuilder::new()
+        .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+        .ipv4([10, 0, 0, 1], [10, 0, 0, 2]);
+    let packets = vec![
+        ip().tcp(40000, 22, TCP_ACK).payload(b"ls\n").build(),
+        ip().tcp(40000, 22, TCP_SYN).build(),
+        ip().tcp(40000, 80, TCP_ACK).build(),
+        ip().udp(40000, 53).payload(b"query").build(),
+        ip().udp(40000, 123).build(),
+        ip().payload(&[0; 8]).build(),
+        PacketBuilder::new().ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+            .udp(40000, 53).build(),
+    ];
+
+    let verdicts = run(&registry, &packets);
+    println!("verdicts:                 {:?}", verdicts);
+    assert_eq!(verdicts, vec![1, 0, 0, 1, 0, 0, 0]);
+
+    // Accept all the TCP packets, by replacing a slot of the program array.
+    registry.load("accept", rbpf::assembler::assemble(ACCEPT).unwrap());
+    registry.set_tail_call(0, IPPROTO_TCP as u32, "accept");
+    let verdicts = run(&registry, &packets);
+    println!("with all TCP accepted:    {:?}", verdicts);
+    assert_eq!(verdicts, vec![1, 1, 1, 1, 0, 0, 0]);
+
+    // Drop all the UDP packets: the tail call fails, and the parser drops the packets.
+    registry.clear_tail_call(0, IPPROTO_UDP as u32);
+    let verdicts = run(&registry, &packets);
+    println!("with UDP dropped:         {:?}", verdicts);
+    assert_eq!(verdicts, vec![1, 1, 1, 0, 0, 0, 0]);
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+ *
+ * Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+ * the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+ * copied, modified, or distributed except according to those terms.
+ */
+
+/*
+ * C API for rbpf, built with the "capi" feature:
+ *
+ *     cargo rustc --release --features capi --crate-type cdylib
+ *
+ * The declarations below mirror the functions of module src/capi.rs, see the documentation of
+ * this module for details. Functions returning an int return 0 on success, and -1 on error; the
+ * message of the error is then available with rbpf_vm_last_error().
+ */
+
+#ifndef RBPF_H
+#define RBPF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A virtual machine running eBPF programs on packet data. */
+typedef struct RbpfVm rbpf_vm;
+
+/* A helper function, called by programs with its five arguments in R1 to R5. */
+typedef uint64_t (*rbpf_helper)(uint64_t, uint64_t, uint64_t, uint64_t, uint64_t);
+
+/* Create a new VM, with no program loaded. */
+rbpf_vm *rbpf_vm_new(void);
+
+/* Free a VM created with rbpf_vm_new(). Does nothing if vm is NULL. */
+void rbpf_vm_free(rbpf_vm *vm);
+
+/* Load a copy of the program of len bytes at prog, after checking it with the verifier. */
+int rbpf_vm_load(rbpf_vm *vm, const uint8_t *prog, size_t len);
+
+/* Register helper as the helper function with the given key. */
+int rbpf_vm_register_helper(rbpf_vm *vm, uint32_t key, rbpf_helper helper);
+
+/* Run the program on the mem_len bytes at mem, and store its return value at ret. */
+int rbpf_vm_exec(rbpf_vm *vm, uint8_t *mem, size_t mem_len, uint64_t *ret);
+
+/* Message of the error of the last call on vm, or NULL if it succeeded. */
+const char *rbpf_vm_last_error(const rbpf_vm *vm);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RBPF_H */
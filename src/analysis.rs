@@ -0,0 +1,1110 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides static analyses of eBPF programs, working on the bytecode without
+//! running it.
+//!
+//! The control flow graph of a program can be computed with `basic_blocks()`, and exported in
+//! the Graphviz dot format with `cfg_dot()`, to visualize the output of a compiler, for example.
+//!
+//! `dependencies()` lists the helpers, maps and context offsets a program uses, so that a policy
+//! can be applied to programs before they are loaded.
+//!
+//! `worst_case()` bounds the number of instructions a program executes on any input, from the
+//! bounds of its loops, so that programs too slow for a deployment can be rejected before they
+//! are attached.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Write};
+
+use disassembler;
+use ebpf;
+use helpers;
+
+/// A basic block: a sequence of instructions with a single entry, at its first instruction, and
+/// a single exit, at its last instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Index of the first instruction of the block.
+    pub start:       usize,
+    /// Index of the instruction following the last instruction of the block.
+    pub end:         usize,
+    /// First instruction of the block jumped to by the last instruction, if it is a jump.
+    pub branch:      Option<usize>,
+    /// First instruction of the next block, if execution can continue into it from the last
+    /// instruction (that is, if the last instruction is not `exit` or `ja`).
+    pub fallthrough: Option<usize>,
+}
+
+pub(crate) fn jump_target(insn: &disassembler::HLInsn) -> Option<usize> {
+    if !ebpf::is_jump(insn.opc) {
+        return None;
+    }
+    match insn.opc {
+        // `gotol` has its offset in the immediate.
+        ebpf::JA32 => Some((insn.ptr as isize + 1 + insn.imm as isize) as usize),
+        _ => Some((insn.ptr as isize + 1 + insn.off as isize) as usize),
+    }
+}
+
+/// Split an eBPF program into basic blocks, in the order of the program.
+///
+/// Calls to helpers do not end a basic block. Jumps to targets out of the program produce no
+/// edge; such programs are rejected by the verifier anyway.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::analysis;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let blocks = analysis::basic_blocks(&prog);
+///
+/// assert_eq!(blocks.len(), 3);
+/// assert_eq!((blocks[0].start, blocks[0].end), (0, 2));
+/// assert_eq!(blocks[0].branch, Some(3));
+/// assert_eq!(blocks[0].fallthrough, Some(2));
+/// assert_eq!(blocks[2].fallthrough, None);
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn basic_blocks(prog: &[u8]) -> Vec<BasicBlock> {
+    let insns = disassembler::to_insn_vec(prog);
+    let len = prog.len() / ebpf::INSN_SIZE;
+
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+    for (i, insn) in insns.iter().enumerate() {
+        let next = insns.get(i + 1).map_or(len, |n| n.ptr);
+        if let Some(target) = jump_target(insn) {
+            leaders.insert(target);
+            leaders.insert(next);
+        } else if insn.opc == ebpf::EXIT {
+            leaders.insert(next);
+        }
+    }
+    let leaders: Vec<usize> = leaders.into_iter().filter(|&l| l < len).collect();
+
+    let mut blocks = vec![];
+    for (i, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).cloned().unwrap_or(len);
+        let last = match insns.iter().rev().find(|insn| insn.ptr < end) {
+            Some(last) => last,
+            None       => continue,
+        };
+        let branch = jump_target(last).filter(|&t| t < len);
+        let fallthrough = match last.opc {
+            ebpf::JA | ebpf::JA32 | ebpf::EXIT => None,
+            _                                  => Some(end).filter(|&e| e < len),
+        };
+        blocks.push(BasicBlock { start, end, branch, fallthrough });
+    }
+    blocks
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Export the control flow graph of an eBPF program in the Graphviz dot format. Each node is a
+/// basic block, labelled with the disassembly of its instructions. Conditional jumps produce two
+/// edges, labelled `true` (jump taken) and `false` (fall through).
+///
+/// The output can be rendered with `dot -Tsvg cfg.dot -o cfg.svg`, for example.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::analysis;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let dot = analysis::cfg_dot(&prog);
+///
+/// assert!(dot.starts_with("digraph cfg {"));
+/// assert!(dot.contains("b0 [label=\"0: mov64 r0, 0x0\\l1: jeq r1, 0x0, +0x1\\l\"];"));
+/// assert!(dot.contains("b0 -> b3 [label=\"true\"];"));
+/// assert!(dot.contains("b0 -> b2 [label=\"false\"];"));
+/// assert!(dot.contains("b2 -> b3;"));
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn cfg_dot(prog: &[u8]) -> String {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+
+    let mut dot = String::new();
+    // Writing to a String cannot fail.
+    writeln!(dot, "digraph cfg {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    for block in &blocks {
+        let label: String = insns.iter()
+            .filter(|insn| block.start <= insn.ptr && insn.ptr < block.end)
+            .map(|insn| format!("{}: {}\\l", insn.ptr, dot_escape(&insn.desc)))
+            .collect();
+        writeln!(dot, "    b{} [label=\"{}\"];", block.start, label).unwrap();
+    }
+    for block in &blocks {
+        match (block.branch, block.fallthrough) {
+            (Some(b), Some(f)) => {
+                writeln!(dot, "    b{} -> b{} [label=\"true\"];", block.start, b).unwrap();
+                writeln!(dot, "    b{} -> b{} [label=\"false\"];", block.start, f).unwrap();
+            },
+            (Some(t), None) | (None, Some(t)) =>
+                writeln!(dot, "    b{} -> b{};", block.start, t).unwrap(),
+            (None, None) => (),
+        }
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+/// An access to the context of a program (the memory area pointed to by R1 when the program
+/// starts: the metadata buffer, or packet data if there is none).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CtxAccess {
+    /// Offset of the access from the beginning of the context.
+    pub offset: i64,
+    /// Number of bytes accessed: 1, 2, 4 or 8.
+    pub size:   usize,
+    /// `true` for a store, `false` for a load.
+    pub write:  bool,
+}
+
+/// The external resources used by a program, as found by `dependencies()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dependencies {
+    /// Keys of the helpers called by the program.
+    pub helpers:      BTreeSet<u32>,
+    /// Identifiers of the maps referenced by the program, that is, the immediates of `lddw`
+    /// instructions with `ebpf::BPF_PSEUDO_MAP_FD` or `ebpf::BPF_PSEUDO_MAP_VALUE` as source
+    /// register.
+    pub maps:         BTreeSet<u32>,
+    /// Context accesses that could be resolved to a constant offset.
+    pub ctx_accesses: BTreeSet<CtxAccess>,
+    /// `true` if the program uses the pointer to the context in a way that the analysis does not
+    /// follow, for example by spilling it to the stack, or by adding a non-constant value to it.
+    /// The program may then access the context at offsets not listed in `ctx_accesses`.
+    pub ctx_unknown:  bool,
+}
+
+// For each register: `Some(off)` if it holds the address of the context plus `off`.
+type CtxState = [Option<i64>; 11];
+
+fn access_size(opc: u8) -> usize {
+    match opc & 0x18 {
+        ebpf::BPF_B => 1,
+        ebpf::BPF_H => 2,
+        ebpf::BPF_W => 4,
+        _           => 8,
+    }
+}
+
+// Updates `state` with the effect of `insn`, and returns the context access it makes, if any.
+// R1 is the only register holding a pointer to the context when the program starts; it stays a
+// pointer to the context when copied, or when a constant is added or subtracted. Anything else
+// done with a pointer to the context makes us lose track of it, and sets `ctx_unknown`.
+fn ctx_transfer(insn: &disassembler::HLInsn, state: &mut CtxState, ctx_unknown: &mut bool)
+                -> Option<CtxAccess> {
+    let dst = insn.dst as usize;
+    let src = insn.src as usize;
+    let is_ctx = |reg: usize| state.get(reg).is_some_and(|r| r.is_some());
+    let class = insn.opc & ebpf::BPF_CLS_MASK;
+
+    let base = match class {
+        ebpf::BPF_LDX                => Some((src, false)),
+        ebpf::BPF_ST | ebpf::BPF_STX => Some((dst, true)),
+        _                            => None,
+    };
+    let access = match base.map(|(base, write)| (state.get(base), write)) {
+        Some((Some(Some(off)), write)) => Some(CtxAccess {
+            offset: off + insn.off as i64,
+            size:   access_size(insn.opc),
+            write,
+        }),
+        _ => None,
+    };
+    // Spilling the pointer to the stack, or to any other memory area.
+    if class == ebpf::BPF_STX && is_ctx(src) {
+        *ctx_unknown = true;
+    }
+
+    match insn.opc {
+        ebpf::MOV64_REG  => state[dst] = state[src],
+        ebpf::ADD64_IMM  => state[dst] = state[dst].map(|off| off + insn.imm),
+        ebpf::SUB64_IMM  => state[dst] = state[dst].map(|off| off - insn.imm),
+        // Helpers may receive the pointer to the context, but they do not return it.
+        ebpf::CALL       => for reg in state.iter_mut().take(6) {
+            *reg = None;
+        },
+        _ => match class {
+            ebpf::BPF_ALU | ebpf::BPF_ALU64                    => {
+                let from_ctx = insn.opc & ebpf::BPF_X != 0 && is_ctx(src);
+                let on_ctx = is_ctx(dst) && insn.opc != ebpf::MOV32_IMM && insn.opc != ebpf::MOV64_IMM;
+                if from_ctx || on_ctx {
+                    *ctx_unknown = true;
+                }
+                state[dst] = None;
+            },
+            ebpf::BPF_LD | ebpf::BPF_LDX if dst < state.len() => state[dst] = None,
+            _                                                  => (),
+        },
+    }
+    access
+}
+
+// Propagate the state of the registers along the control flow graph, starting with register
+// `base` holding a pointer to the base of a memory area, see `ctx_transfer()`. Returns the state at
+// the entry of each block (`None` for unreachable blocks), and whether the pointer was lost track
+// of when merging paths.
+fn ptr_states(insns: &[disassembler::HLInsn], blocks: &[BasicBlock], base: usize)
+              -> (Vec<Option<CtxState>>, bool) {
+    let mut ctx_unknown = false;
+
+    // Propagate the state of the registers until a fixed point is reached. States at the entry of
+    // blocks only lose information when merged, so this ends.
+    let mut entry_states: Vec<Option<CtxState>> = vec![None; blocks.len()];
+    if !blocks.is_empty() {
+        let mut initial = [None; 11];
+        initial[base] = Some(0);
+        entry_states[0] = Some(initial);
+    }
+    let mut scratch = false;
+    let mut worklist = vec![0];
+    while let Some(b) = worklist.pop() {
+        let mut state = match entry_states.get(b) {
+            Some(&Some(state)) => state,
+            _                  => continue,
+        };
+        for insn in block_insns(insns, &blocks[b]) {
+            ctx_transfer(insn, &mut state, &mut scratch);
+        }
+        for succ in successors(blocks, b) {
+            let merged = match entry_states[succ] {
+                None       => state,
+                Some(prev) => {
+                    let mut merged = prev;
+                    for (m, s) in merged.iter_mut().zip(state.iter()) {
+                        if m.is_some() && s.is_some() && m != s {
+                            ctx_unknown = true;
+                        }
+                        if m != s {
+                            *m = None;
+                        }
+                    }
+                    merged
+                },
+            };
+            if entry_states[succ] != Some(merged) {
+                entry_states[succ] = Some(merged);
+                worklist.push(succ);
+            }
+        }
+    }
+    (entry_states, ctx_unknown)
+}
+
+// The instructions of `block`.
+fn block_insns<'a>(insns: &'a [disassembler::HLInsn], block: &BasicBlock)
+                   -> impl Iterator<Item = &'a disassembler::HLInsn> {
+    let (start, end) = (block.start, block.end);
+    insns.iter().filter(move |i| start <= i.ptr && i.ptr < end)
+}
+
+// The indices in `blocks` of the successors of block `b`.
+fn successors(blocks: &[BasicBlock], b: usize) -> impl Iterator<Item = usize> + '_ {
+    let succs = blocks[b].branch.into_iter().chain(blocks[b].fallthrough);
+    succs.filter_map(move |s| blocks.iter().position(|block| block.start == s))
+}
+
+// Find the context accesses of the program by propagating the state of the registers along the
+// control flow graph, see `dependencies()`. Returns the index of each instruction accessing the
+// context with the access it makes, and whether the pointer to the context was lost track of.
+pub(crate) fn ctx_accesses(prog: &[u8]) -> (Vec<(usize, CtxAccess)>, bool) {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+    let (entry_states, mut ctx_unknown) = ptr_states(&insns, &blocks, 1);
+
+    // Collect the context accesses with the final states.
+    let mut accesses = vec![];
+    for (block, state) in blocks.iter().zip(entry_states.iter()) {
+        if let Some(mut state) = *state {
+            for insn in block_insns(&insns, block) {
+                if let Some(access) = ctx_transfer(insn, &mut state, &mut ctx_unknown) {
+                    accesses.push((insn.ptr, access));
+                }
+            }
+        }
+    }
+    (accesses, ctx_unknown)
+}
+
+/// List the helpers, maps and context offsets used by a program, without running it. This can
+/// be used to decide whether a program should be allowed to run, before loading it.
+///
+/// The helpers and maps are listed for all the instructions of the program, reachable or not.
+/// Context accesses are found by following the pointer to the context from R1 along all paths
+/// of the control flow graph, as long as it is only copied to other registers or offset by
+/// constants. When it is used in any other way, for example spilled to the stack, or when
+/// different paths lead to different offsets from the context in the same register,
+/// `ctx_unknown` is set.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::analysis::{self, CtxAccess};
+///
+/// let prog = vec![
+///     0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+///     0x18, 0x11, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // lddw r1, map 5
+///     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+///     0x61, 0x60, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r6+4]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let deps = analysis::dependencies(&prog);
+///
+/// assert_eq!(deps.helpers.into_iter().collect::<Vec<_>>(), vec![1]);
+/// assert_eq!(deps.maps.into_iter().collect::<Vec<_>>(), vec![5]);
+/// assert_eq!(deps.ctx_accesses.into_iter().collect::<Vec<_>>(),
+///            vec![CtxAccess { offset: 4, size: 4, write: false }]);
+/// assert!(!deps.ctx_unknown);
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn dependencies(prog: &[u8]) -> Dependencies {
+    let insns = disassembler::to_insn_vec(prog);
+    let mut deps = Dependencies::default();
+
+    for insn in &insns {
+        match insn.opc {
+            ebpf::CALL                                              => {
+                deps.helpers.insert(insn.imm as u32);
+            },
+            ebpf::LD_DW_IMM if insn.src == ebpf::BPF_PSEUDO_MAP_FD ||
+                               insn.src == ebpf::BPF_PSEUDO_MAP_VALUE => {
+                deps.maps.insert(insn.imm as u32);
+            },
+            _                                                       => (),
+        }
+    }
+
+    let (accesses, ctx_unknown) = ctx_accesses(prog);
+    deps.ctx_accesses.extend(accesses.into_iter().map(|(_, access)| access));
+    deps.ctx_unknown = ctx_unknown;
+    deps
+}
+
+/// A finding of `lint()`: code that is useless, or that does not do what it seems to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// Instructions `start` to `end` (excluded) can never run: no path of the control flow graph
+    /// leads to them from the first instruction.
+    Unreachable {
+        /// Index of the first unreachable instruction.
+        start: usize,
+        /// Index of the instruction following the last unreachable instruction.
+        end:   usize,
+    },
+    /// The instruction at `insn_ptr` writes register `reg`, but the value is never read: it is
+    /// overwritten, or the program exits, on all paths.
+    DeadStore {
+        /// Index of the instruction.
+        insn_ptr: usize,
+        /// The register written.
+        reg:      u8,
+    },
+    /// The conditional jump at `insn_ptr` always goes the same way, whatever the input of the
+    /// program.
+    ConstantCondition {
+        /// Index of the jump.
+        insn_ptr: usize,
+        /// `true` if the jump is always taken, `false` if it is never taken.
+        taken:    bool,
+    },
+    /// The store at `insn_ptr` writes bytes of the stack that are never loaded, neither by the
+    /// program nor by helpers.
+    UnusedStackStore {
+        /// Index of the store.
+        insn_ptr: usize,
+        /// Offset of the store from R10 (negative).
+        offset:   i64,
+        /// Number of bytes written: 1, 2, 4 or 8.
+        size:     usize,
+    },
+}
+
+impl Lint {
+    /// Index of the instruction the finding is about (the first one, for unreachable code).
+    pub fn insn_ptr(&self) -> usize {
+        match *self {
+            Lint::Unreachable { start, .. }            => start,
+            Lint::DeadStore { insn_ptr, .. }           => insn_ptr,
+            Lint::ConstantCondition { insn_ptr, .. }   => insn_ptr,
+            Lint::UnusedStackStore { insn_ptr, .. }    => insn_ptr,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Lint::Unreachable { start, end } if end == start + 1 =>
+                write!(f, "instruction {} is unreachable", start),
+            Lint::Unreachable { start, end } =>
+                write!(f, "instructions {} to {} are unreachable", start, end - 1),
+            Lint::DeadStore { insn_ptr, reg } =>
+                write!(f, "instruction {} writes r{}, which is never read", insn_ptr, reg),
+            Lint::ConstantCondition { insn_ptr, taken } =>
+                write!(f, "the condition of instruction {} is always {}", insn_ptr, taken),
+            Lint::UnusedStackStore { insn_ptr, offset, size } =>
+                write!(f, "instruction {} stores {} bytes at r10{:+}, which are never loaded",
+                       insn_ptr, size, offset),
+        }
+    }
+}
+
+// The registers an instruction writes on all its executions, and the registers it may read, as
+// bit masks. Writes only guaranteed by the kernel ABI (calls clobber R0 to R5) count as writes.
+fn defs_uses(insn: &disassembler::HLInsn) -> (u16, u16) {
+    let (dst, src) = (1u16 << insn.dst, 1u16 << insn.src);
+    let args = 0b11_1110;
+    match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => match insn.opc & ebpf::BPF_ALU_OP_MASK {
+            ebpf::BPF_MOV if insn.opc & ebpf::BPF_X != 0 => (dst, src),
+            ebpf::BPF_MOV                                => (dst, 0),
+            _ if insn.opc & ebpf::BPF_X != 0             => (dst, dst | src),
+            _                                            => (dst, dst),
+        },
+        ebpf::BPF_LD  => match insn.opc {
+            ebpf::LD_DW_IMM                                    => (dst, 0),
+            // Legacy packet loads read the context from R6, and clobber R1 to R5 in the kernel.
+            _ if insn.opc & 0xe0 == ebpf::BPF_IND              => (1, 1 << 6 | src),
+            _                                                  => (1, 1 << 6),
+        },
+        ebpf::BPF_LDX => (dst, src),
+        ebpf::BPF_ST  => (0, dst),
+        // Atomic operations may also write to R0 and to the source register, but not always.
+        ebpf::BPF_STX if insn.opc & 0xe0 == ebpf::BPF_XADD => (0, dst | src | 1),
+        ebpf::BPF_STX => (0, dst | src),
+        _             => match insn.opc {
+            ebpf::CALL | ebpf::TAIL_CALL       => (1 | args, args),
+            ebpf::EXIT                         => (0, 1),
+            ebpf::JA | ebpf::JA32              => (0, 0),
+            _ if insn.opc & ebpf::BPF_X != 0   => (0, dst | src),
+            _                                  => (0, dst),
+        },
+    }
+}
+
+// The registers an instruction may write, as a bit mask: the writes of `defs_uses()`, and those
+// that do not happen on all executions.
+pub(crate) fn may_defs(insn: &disassembler::HLInsn) -> u16 {
+    let (defs, _) = defs_uses(insn);
+    match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_LD                                       => defs | 0b11_1110,
+        ebpf::BPF_STX if insn.opc & 0xe0 == ebpf::BPF_XADD => 1 | 1 << insn.src,
+        _                                                  => defs,
+    }
+}
+
+// Whether each block of `blocks` is reachable from the first one.
+fn reachable_blocks(blocks: &[BasicBlock]) -> Vec<bool> {
+    let mut reachable = vec![false; blocks.len()];
+    let mut worklist = vec![0];
+    while let Some(b) = worklist.pop() {
+        if b < blocks.len() && !reachable[b] {
+            reachable[b] = true;
+            worklist.extend(successors(blocks, b));
+        }
+    }
+    reachable
+}
+
+// The instructions of the reachable blocks writing a register other than R10 that is not read
+// afterwards, on any path, found with the registers live at the exit of each block.
+fn dead_writes_in<'a>(insns: &'a [disassembler::HLInsn], blocks: &[BasicBlock], reachable: &[bool])
+                      -> Vec<&'a disassembler::HLInsn> {
+    // Registers live at the exit of each block, until a fixed point is reached.
+    let live_in = |b: usize, mut live: u16| {
+        let block: Vec<_> = block_insns(insns, &blocks[b]).collect();
+        for insn in block.iter().rev() {
+            let (defs, uses) = defs_uses(insn);
+            live = (live & !defs) | uses;
+        }
+        live
+    };
+    let mut live_out = vec![0u16; blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..blocks.len()).rev() {
+            let live = successors(blocks, b).fold(0, |live, s| live | live_in(s, live_out[s]));
+            if live != live_out[b] {
+                live_out[b] = live;
+                changed = true;
+            }
+        }
+    }
+    let mut dead = vec![];
+    for (b, block) in blocks.iter().enumerate().filter(|&(b, _)| reachable[b]) {
+        let mut live = live_out[b];
+        let block: Vec<_> = block_insns(insns, block).collect();
+        for insn in block.iter().rev() {
+            let (defs, uses) = defs_uses(insn);
+            if insn.dst < 10 && defs & (1 << insn.dst) != 0 && live & defs == 0 {
+                dead.push(*insn);
+            }
+            live = (live & !defs) | uses;
+        }
+    }
+    dead
+}
+
+// The indices of the instructions writing a register that is never read afterwards, see
+// `dead_writes_in()`. The JIT-compiler does not emit those that have no other effect.
+pub(crate) fn dead_writes(prog: &[u8]) -> HashSet<usize> {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+    dead_writes_in(&insns, &blocks, &reachable_blocks(&blocks)).into_iter().map(|insn| insn.ptr).collect()
+}
+
+// For each register: `Some(v)` if it holds the constant `v`.
+type ConstState = [Option<u64>; 11];
+
+// Updates `state` with the effect of `insn`. Only moves and the simple arithmetic and bitwise
+// operations on known values produce known values; divisions, for example, depend on the
+// configuration of the VM.
+fn const_transfer(insn: &disassembler::HLInsn, state: &mut ConstState) {
+    let (dst, src) = (insn.dst as usize, insn.src as usize);
+    let class = insn.opc & ebpf::BPF_CLS_MASK;
+    let value = match class {
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 if insn.off == 0 && dst < 10 => {
+            let b = match insn.opc & ebpf::BPF_X {
+                0 => Some(insn.imm as u64),
+                _ => state.get(src).cloned().flatten(),
+            };
+            let a = state[dst];
+            let (a, b) = match class {
+                ebpf::BPF_ALU => (a.map(|a| a as u32 as u64), b.map(|b| b as u32 as u64)),
+                _             => (a, b),
+            };
+            let mask = if class == ebpf::BPF_ALU { 31 } else { 63 };
+            let res = match insn.opc & ebpf::BPF_ALU_OP_MASK {
+                ebpf::BPF_MOV  => b,
+                ebpf::BPF_NEG  => a.map(|a| a.wrapping_neg()),
+                op             => match (a, b) {
+                    (Some(a), Some(b)) => match op {
+                        ebpf::BPF_ADD  => Some(a.wrapping_add(b)),
+                        ebpf::BPF_SUB  => Some(a.wrapping_sub(b)),
+                        ebpf::BPF_MUL  => Some(a.wrapping_mul(b)),
+                        ebpf::BPF_AND  => Some(a & b),
+                        ebpf::BPF_OR   => Some(a | b),
+                        ebpf::BPF_XOR  => Some(a ^ b),
+                        ebpf::BPF_LSH  => Some(a << (b & mask)),
+                        ebpf::BPF_RSH  => Some(a >> (b & mask)),
+                        ebpf::BPF_ARSH if class == ebpf::BPF_ALU => Some((a as i32 >> (b & mask)) as u64),
+                        ebpf::BPF_ARSH => Some((a as i64 >> (b & mask)) as u64),
+                        _              => None,
+                    },
+                    _ => None,
+                },
+            };
+            match class {
+                ebpf::BPF_ALU => res.map(|r| r as u32 as u64),
+                _             => res,
+            }
+        },
+        // References to maps are their indices, as loaded by the VM.
+        _ if insn.opc == ebpf::LD_DW_IMM &&
+             (insn.src == 0 || insn.src == ebpf::BPF_PSEUDO_MAP_FD) => Some(insn.imm as u64),
+        _ => None,
+    };
+
+    let may_defs = may_defs(insn);
+    for (reg, v) in state.iter_mut().enumerate().take(10) {
+        if may_defs & 1 << reg != 0 {
+            *v = None;
+        }
+    }
+    if value.is_some() && dst < 10 {
+        state[dst] = value;
+    }
+}
+
+// Whether the conditional jump `insn` is taken, for operands `a` and `b`.
+fn jmp_taken(insn: &disassembler::HLInsn, a: u64, b: u64) -> bool {
+    let (a, b) = match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_JMP32 => (a as u32 as i32 as i64 as u64, b as u32 as i32 as i64 as u64),
+        _               => (a, b),
+    };
+    let (ua, ub) = match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_JMP32 => (a as u32 as u64, b as u32 as u64),
+        _               => (a, b),
+    };
+    match insn.opc & ebpf::BPF_ALU_OP_MASK {
+        ebpf::BPF_JEQ  => ua == ub,
+        ebpf::BPF_JGT  => ua >  ub,
+        ebpf::BPF_JGE  => ua >= ub,
+        ebpf::BPF_JSET => ua &  ub != 0,
+        ebpf::BPF_JNE  => ua != ub,
+        ebpf::BPF_JSGT => (a as i64) >  b as i64,
+        ebpf::BPF_JSGE => (a as i64) >= b as i64,
+        ebpf::BPF_JLT  => ua <  ub,
+        ebpf::BPF_JLE  => ua <= ub,
+        ebpf::BPF_JSLT => (a as i64) <  b as i64,
+        _              => (a as i64) <= b as i64,
+    }
+}
+
+// The outcome of the conditional jump `insn` for the values of `state`, if it is known.
+fn known_condition(insn: &disassembler::HLInsn, state: &ConstState) -> Option<bool> {
+    let reg = insn.opc & ebpf::BPF_X != 0;
+    // Comparing a register with itself.
+    if reg && insn.src == insn.dst && insn.opc & ebpf::BPF_ALU_OP_MASK != ebpf::BPF_JSET {
+        return Some(jmp_taken(insn, 0, 0));
+    }
+    let a = state.get(insn.dst as usize).cloned().flatten()?;
+    let b = match reg {
+        true  => state.get(insn.src as usize).cloned().flatten()?,
+        false => insn.imm as u64,
+    };
+    Some(jmp_taken(insn, a, b))
+}
+
+// Propagate the constant values of the registers along the control flow graph, until a fixed
+// point is reached. Returns the state at the entry of each block (`None` for unreachable blocks).
+fn const_states(insns: &[disassembler::HLInsn], blocks: &[BasicBlock]) -> Vec<Option<ConstState>> {
+    let mut entry_states: Vec<Option<ConstState>> = vec![None; blocks.len()];
+    if !blocks.is_empty() {
+        entry_states[0] = Some([None; 11]);
+    }
+    let mut worklist = vec![0];
+    while let Some(b) = worklist.pop() {
+        let mut state = match entry_states.get(b) {
+            Some(&Some(state)) => state,
+            _                  => continue,
+        };
+        for insn in block_insns(insns, &blocks[b]) {
+            const_transfer(insn, &mut state);
+        }
+        for succ in successors(blocks, b) {
+            let merged = match entry_states[succ] {
+                None       => state,
+                Some(prev) => {
+                    let mut merged = prev;
+                    for (m, s) in merged.iter_mut().zip(state.iter()) {
+                        if m != s {
+                            *m = None;
+                        }
+                    }
+                    merged
+                },
+            };
+            if entry_states[succ] != Some(merged) {
+                entry_states[succ] = Some(merged);
+                worklist.push(succ);
+            }
+        }
+    }
+    entry_states
+}
+
+// Find the calls to `bpf_map_lookup_elem()` made with a constant map in R1, by propagating the
+// constant values of the registers along the control flow graph. Returns the index of each of
+// these calls with the index of the map. The JIT-compiler inlines the lookups in array maps.
+pub(crate) fn map_lookups(prog: &[u8]) -> Vec<(usize, u64)> {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+    let mut lookups = vec![];
+    for (block, state) in blocks.iter().zip(const_states(&insns, &blocks).iter()) {
+        if let Some(mut state) = *state {
+            for insn in block_insns(&insns, block) {
+                if insn.opc == ebpf::CALL && insn.imm as u32 == helpers::BPF_MAP_LOOKUP_ELEM_IDX {
+                    if let Some(map) = state[1] {
+                        lookups.push((insn.ptr, map));
+                    }
+                }
+                const_transfer(insn, &mut state);
+            }
+        }
+    }
+    lookups
+}
+
+/// Look for code that is useless, or that does not do what it seems to do, in an eBPF program.
+/// This helps cleaning up the output of compilers before deploying programs.
+///
+/// The findings, sorted by instruction, are:
+///
+/// * `Lint::Unreachable` for instructions that no path of the control flow graph reaches;
+/// * `Lint::DeadStore` for writes to registers whose value is never read (calls to helpers and
+///   atomic operations are not reported, as they have other effects);
+/// * `Lint::ConstantCondition` for conditional jumps whose outcome is known without running the
+///   program, because they compare constants, or a register with itself;
+/// * `Lint::UnusedStackStore` for stores to the stack whose bytes are never loaded. Helpers
+///   receiving a pointer to the stack are assumed to read everything from the pointer up to the
+///   top of the stack. When the analysis loses track of pointers to the stack (when they are
+///   spilled to memory, for example), no such finding is reported.
+///
+/// Branches of constant conditions are not pruned from the control flow graph, so the code they
+/// make unreachable is not reported as such.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::analysis::{self, Lint};
+///
+/// let prog = vec![
+///     0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r1, 1
+///     0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+///     0xb7, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r0, 3
+/// ];
+/// let lints = analysis::lint(&prog);
+///
+/// assert_eq!(lints, vec![
+///     Lint::ConstantCondition { insn_ptr: 1, taken: false },
+///     Lint::Unreachable { start: 4, end: 5 },
+/// ]);
+/// assert_eq!(lints[0].to_string(), "the condition of instruction 1 is always false");
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn lint(prog: &[u8]) -> Vec<Lint> {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+    let mut lints = vec![];
+
+    let reachable = reachable_blocks(&blocks);
+    let mut unreachable: Option<(usize, usize)> = None;
+    for (block, _) in blocks.iter().zip(reachable.iter()).filter(|(_, &r)| !r) {
+        unreachable = match unreachable {
+            Some((start, end)) if end == block.start => Some((start, block.end)),
+            Some((start, end)) => {
+                lints.push(Lint::Unreachable { start, end });
+                Some((block.start, block.end))
+            },
+            None => Some((block.start, block.end)),
+        };
+    }
+    if let Some((start, end)) = unreachable {
+        lints.push(Lint::Unreachable { start, end });
+    }
+
+    let dead = dead_writes_in(&insns, &blocks, &reachable);
+    lints.extend(dead.into_iter().filter(|insn| {
+        match insn.opc & ebpf::BPF_CLS_MASK {
+            ebpf::BPF_ALU | ebpf::BPF_ALU64 | ebpf::BPF_LDX => true,
+            _                                               => insn.opc == ebpf::LD_DW_IMM,
+        }
+    }).map(|insn| Lint::DeadStore { insn_ptr: insn.ptr, reg: insn.dst }));
+
+    // Constant values of the registers.
+    let entry_states = const_states(&insns, &blocks);
+    for (block, state) in blocks.iter().zip(entry_states.iter()) {
+        if let Some(mut state) = *state {
+            for insn in block_insns(&insns, block) {
+                if ebpf::is_conditional_jump(insn.opc) {
+                    if let Some(taken) = known_condition(insn, &state) {
+                        lints.push(Lint::ConstantCondition { insn_ptr: insn.ptr, taken });
+                    }
+                }
+                const_transfer(insn, &mut state);
+            }
+        }
+    }
+
+    // Stores to the stack and byte ranges loaded, following pointers derived from R10.
+    let (entry_states, mut stack_unknown) = ptr_states(&insns, &blocks, 10);
+    let mut stores = vec![];
+    let mut loads = vec![];
+    for (block, state) in blocks.iter().zip(entry_states.iter()) {
+        if let Some(mut state) = *state {
+            for insn in block_insns(&insns, block) {
+                let xadd = insn.opc & 0xe0 == ebpf::BPF_XADD;
+                match insn.opc & ebpf::BPF_CLS_MASK {
+                    ebpf::BPF_ST | ebpf::BPF_STX if !xadd => if let Some(Some(off)) = state.get(insn.dst as usize) {
+                        let off = off + insn.off as i64;
+                        stores.push((insn.ptr, off, access_size(insn.opc)));
+                    },
+                    ebpf::BPF_STX => if let Some(Some(off)) = state.get(insn.dst as usize) {
+                        let off = off + insn.off as i64;
+                        loads.push((off, off + access_size(insn.opc) as i64));
+                    },
+                    ebpf::BPF_LDX => if let Some(Some(off)) = state.get(insn.src as usize) {
+                        let off = off + insn.off as i64;
+                        loads.push((off, off + access_size(insn.opc) as i64));
+                    },
+                    _ if insn.opc == ebpf::CALL || insn.opc == ebpf::TAIL_CALL => {
+                        loads.extend(state[1..6].iter().flatten().map(|&off| (off, 0)));
+                    },
+                    _ => (),
+                }
+                ctx_transfer(insn, &mut state, &mut stack_unknown);
+            }
+        }
+    }
+    if !stack_unknown {
+        for (insn_ptr, offset, size) in stores {
+            let end = offset + size as i64;
+            if !loads.iter().any(|&(start, stop)| start < end && offset < stop) {
+                lints.push(Lint::UnusedStackStore { insn_ptr, offset, size });
+            }
+        }
+    }
+
+    lints.sort_by_key(|lint| lint.insn_ptr());
+    lints
+}
+
+/// An upper bound on the number of instructions executed by a program, as found by
+/// `worst_case()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorstCase {
+    /// Maximum number of instructions executed by a run of the program, `lddw` counting as one
+    /// instruction.
+    pub insns:       u64,
+    /// Bounds of the loops of the program, given or inferred: for the index of each backward jump,
+    /// the maximum number of times it is taken each time its loop is entered.
+    pub loop_bounds: BTreeMap<usize, u64>,
+}
+
+// Largest bound inferred for a loop: counters running longer are not followed.
+const MAX_INFERRED_BOUND: u64 = 1 << 20;
+
+// The number of times the backward jump ending block `b` is taken each time its loop, made of
+// blocks `h` to `e`, is entered, if it is a counted loop: the jump compares a register with an
+// immediate, the register holds a constant when entering the loop, and is updated once in each
+// iteration, by adding or subtracting an immediate.
+fn loop_bound(insns: &[disassembler::HLInsn], blocks: &[BasicBlock], succs: &[Vec<usize>],
+              states: &[Option<ConstState>], h: usize, e: usize, b: usize) -> Option<u64> {
+    let jump = block_insns(insns, &blocks[b]).last()?;
+    if !ebpf::is_conditional_jump(jump.opc) || jump.opc & ebpf::BPF_X != 0 {
+        return None;
+    }
+    let reg = jump.dst as usize;
+    let block_of = |ptr: usize| blocks.iter().position(|block| block.start <= ptr && ptr < block.end);
+
+    let mut defs = insns.iter().filter(|insn| {
+        blocks[h].start <= insn.ptr && insn.ptr < blocks[e].end && may_defs(insn) & 1 << reg != 0
+    });
+    let (def, step) = match (defs.next(), defs.next()) {
+        (Some(def), None) if def.opc == ebpf::ADD64_IMM => (def, def.imm as u64),
+        (Some(def), None) if def.opc == ebpf::SUB64_IMM => (def, (def.imm as u64).wrapping_neg()),
+        _ => return None,
+    };
+    let a = block_of(def.ptr)?;
+    // The update is not in an inner loop, and is on all paths from the header to the jump.
+    let inner = (h..=e).any(|y| y != b && succs[y].iter().any(|&t| t <= a && a <= y));
+    if inner || a > b {
+        return None;
+    }
+    let mut seen = vec![false; blocks.len()];
+    let mut worklist = vec![h];
+    while let Some(y) = worklist.pop() {
+        if y == a || seen[y] {
+            continue;
+        }
+        if y == b {
+            return None;
+        }
+        seen[y] = true;
+        worklist.extend(succs[y].iter().filter(|&&s| y < s && s <= e));
+    }
+
+    // The value of the register when entering the loop.
+    let mut initial = None;
+    for p in (0..blocks.len()).filter(|&p| (p < h || p > e) && succs[p].contains(&h)) {
+        let mut state = match states[p] {
+            Some(state) => state,
+            None        => continue,
+        };
+        for insn in block_insns(insns, &blocks[p]) {
+            const_transfer(insn, &mut state);
+        }
+        match (initial, state[reg]) {
+            (_, None)                      => return None,
+            (Some(v), Some(w)) if v != w   => return None,
+            (_, value)                     => initial = value,
+        }
+    }
+
+    let mut value = initial?;
+    let mut taken = 0;
+    loop {
+        value = value.wrapping_add(step);
+        if !jmp_taken(jump, value, jump.imm as u64) {
+            return Some(taken);
+        }
+        taken += 1;
+        if taken > MAX_INFERRED_BOUND {
+            return None;
+        }
+    }
+}
+
+// The length of the longest path starting at block `start` through blocks `start` to `end`, each
+// block weighing `cost`, with the loops already processed collapsed into the node of their header
+// (`node`). Edges back to `start` and out of the blocks are ignored.
+fn longest_path(succs: &[Vec<usize>], node: &[usize], cost: &[u64], start: usize, end: usize)
+                -> u64 {
+    let mut dist: Vec<Option<u64>> = vec![None; end + 1 - start];
+    dist[0] = Some(cost[start]);
+    let mut longest = 0;
+    for b in start..=end {
+        let d = match dist[node[b] - start] {
+            Some(d) => d,
+            None    => continue,
+        };
+        longest = longest.max(d);
+        for &s in succs[b].iter().filter(|&&s| start < s && s <= end && node[s] != node[b]) {
+            let n = node[s];
+            let len = d.saturating_add(cost[n]);
+            if dist[n - start].is_none_or(|old| old < len) {
+                dist[n - start] = Some(len);
+            }
+        }
+    }
+    longest
+}
+
+/// Compute an upper bound on the number of instructions `prog` executes on any input, so that
+/// deployments with a latency budget can reject programs before attaching them (see
+/// `verifier::check_worst_case()`).
+///
+/// Each backward jump closes a loop, from its target to the jump. `loop_bounds` gives, for the
+/// index of a backward jump, the maximum number of times it is taken each time its loop is
+/// entered. The bounds of counted loops, whose counter starts from a constant and is incremented
+/// or decremented by a constant once per iteration until a comparison with an immediate fails,
+/// are inferred when not given. The bound of the program is then the longest path in its control
+/// flow graph, each loop counting as the longest path through its body times its number of
+/// iterations.
+///
+/// Calls to helpers count as one instruction, and the programs jumped to with tail calls are not
+/// counted.
+///
+/// # Errors
+///
+/// Returns an error if the bound of a loop is neither given nor inferred, if jumps enter a loop
+/// elsewhere than at its first instruction or loops overlap, or if the program contains
+/// BPF-to-BPF calls.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::analysis;
+/// use std::collections::HashMap;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0xb7, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r1, 10
+///     0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+///     0x17, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r1, 1
+///     0x55, 0x01, 0xfd, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -3
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// // The loop runs 10 times: its backward jump is taken 9 times.
+/// let worst = analysis::worst_case(&prog, &HashMap::new()).unwrap();
+/// assert_eq!(worst.loop_bounds[&4], 9);
+/// assert_eq!(worst.insns, 2 + 10 * 3 + 1);
+///
+/// // Bounds can be given for the loops that are not inferred.
+/// let mut bounds = HashMap::new();
+/// bounds.insert(4, 4);
+/// assert_eq!(analysis::worst_case(&prog, &bounds).unwrap().insns, 2 + 5 * 3 + 1);
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn worst_case(prog: &[u8], loop_bounds: &HashMap<usize, u64>) -> Result<WorstCase, String> {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = basic_blocks(prog);
+    if let Some(call) = insns.iter().find(|insn| insn.opc == ebpf::CALL && insn.src == ebpf::BPF_PSEUDO_CALL) {
+        return Err(format!("BPF-to-BPF calls are not supported (insn #{})", call.ptr));
+    }
+    let succs: Vec<Vec<usize>> = (0..blocks.len()).map(|b| successors(&blocks, b).collect()).collect();
+    let last_ptr = |b: usize| block_insns(&insns, &blocks[b]).last().map_or(blocks[b].start, |insn| insn.ptr);
+
+    // The loops, by first block: their last block, and the blocks ending with a backward jump to
+    // the first one.
+    let mut loops: BTreeMap<usize, (usize, Vec<usize>)> = BTreeMap::new();
+    for (b, ss) in succs.iter().enumerate() {
+        for &h in ss.iter().filter(|&&h| h <= b) {
+            let lp = loops.entry(h).or_insert((b, vec![]));
+            lp.0 = lp.0.max(b);
+            lp.1.push(b);
+        }
+    }
+    for (&h, &(e, _)) in loops.iter() {
+        for (p, ss) in succs.iter().enumerate().filter(|&(p, _)| p < h || p > e) {
+            if ss.iter().any(|&s| h < s && s <= e) {
+                return Err(format!("jump into the loop starting at insn #{} (insn #{})",
+                                   blocks[h].start, last_ptr(p)));
+            }
+        }
+        if let Some((&inner, _)) = loops.range(h + 1..e + 1).find(|&(_, &(inner_e, _))| inner_e > e) {
+            return Err(format!("loops starting at insns #{} and #{} overlap",
+                               blocks[h].start, blocks[inner].start));
+        }
+    }
+
+    let states = const_states(&insns, &blocks);
+    let mut bounds = BTreeMap::new();
+    for (&h, &(e, ref ends)) in loops.iter() {
+        for &b in ends {
+            let ptr = last_ptr(b);
+            let inferred = match ends.len() {
+                1 => loop_bound(&insns, &blocks, &succs, &states, h, e, b),
+                _ => None,
+            };
+            match loop_bounds.get(&ptr).cloned().or(inferred) {
+                Some(bound) => bounds.insert(ptr, bound),
+                None        => return Err(format!("no bound for the loop closed by insn #{}", ptr)),
+            };
+        }
+    }
+
+    // Collapse the loops, innermost first, into nodes weighing the cost of all their iterations.
+    let mut cost: Vec<u64> = blocks.iter().map(|block| block_insns(&insns, block).count() as u64).collect();
+    let mut node: Vec<usize> = (0..blocks.len()).collect();
+    let mut order: Vec<_> = loops.iter().collect();
+    order.sort_by_key(|&(&h, &(e, _))| e - h);
+    for (&h, &(e, ref ends)) in order {
+        let iteration = longest_path(&succs, &node, &cost, h, e);
+        let taken = ends.iter().fold(0u64, |taken, &b| taken.saturating_add(bounds[&last_ptr(b)]));
+        cost[h] = iteration.saturating_mul(taken.saturating_add(1));
+        for n in node[h..=e].iter_mut() {
+            *n = h;
+        }
+    }
+    let insns = match blocks.len() {
+        0   => 0,
+        len => longest_path(&succs, &node, &cost, 0, len - 1),
+    };
+    Ok(WorstCase { insns, loop_bounds: bounds })
+}
@@ -0,0 +1,234 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module translates human-readable assembly into eBPF bytecode.
+//!
+//! The syntax is the one produced by module `disassembler`, so that the output of the
+//! disassembler assembles back into the same program: one instruction per line, for example
+//! `add64 r1, 0x2`, `ldxh r0, [r1+0x2]`, `jeq r1, r2, +0x3` or `exit`. Empty lines, and comments
+//! starting with `#` or `//` until the end of the line, are ignored. Numbers are decimal, or
+//! hexadecimal with a `0x` prefix, and may be negative; immediate values may also be given as
+//! their 32-bit two's complement, as printed by the disassembler. The ALU and jump operations
+//! without a `32` or `64` suffix are the 64-bit ones: `add r1, 2` is `add64 r1, 0x2`.
+
+use ebpf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Integer(i64),
+    Memory(u8, i64),
+}
+
+fn parse_integer(s: &str) -> Result<i64, String> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None         => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None      => digits.parse::<u64>(),
+    }.map_err(|_| format!("invalid number {:?}", s))?;
+    match negative {
+        true if value > i64::MIN.unsigned_abs() => Err(format!("number {:?} out of range", s)),
+        true  => Ok((value as i64).wrapping_neg()),
+        false => Ok(value as i64),
+    }
+}
+
+fn parse_register(s: &str) -> Result<u8, String> {
+    match s.strip_prefix('r').and_then(|n| n.parse::<u8>().ok()) {
+        Some(reg) if reg <= 10 => Ok(reg),
+        _ => Err(format!("invalid register {:?}", s)),
+    }
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+    if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let inner = inner.replace(' ', "");
+        return match inner.find(['+', '-']) {
+            Some(i) => Ok(Operand::Memory(parse_register(&inner[..i])?, parse_integer(&inner[i..])?)),
+            None    => Ok(Operand::Memory(parse_register(&inner)?, 0)),
+        };
+    }
+    match s.starts_with('r') {
+        true  => Ok(Operand::Register(parse_register(s)?)),
+        false => Ok(Operand::Integer(parse_integer(s)?)),
+    }
+}
+
+fn imm32(value: i64) -> Result<i32, String> {
+    match value {
+        v if v >= i32::MIN as i64 && v <= u32::MAX as i64 => Ok(v as i32),
+        v => Err(format!("immediate {:#x} does not fit in 32 bits", v)),
+    }
+}
+
+fn off16(value: i64) -> Result<i16, String> {
+    match value {
+        v if v >= i16::MIN as i64 && v <= i16::MAX as i64 => Ok(v as i16),
+        v => Err(format!("offset {} does not fit in 16 bits", v)),
+    }
+}
+
+fn insn(opc: u8, dst: u8, src: u8, off: i16, imm: i32) -> ebpf::Insn {
+    ebpf::Insn { opc, dst, src, off, imm }
+}
+
+// Splits the width suffix from `name`: `add32` is ("add", true), `add64` and `add` are
+// ("add", false).
+fn split_width(name: &str) -> (&str, bool) {
+    match (name.strip_suffix("32"), name.strip_suffix("64")) {
+        (Some(base), _) => (base, true),
+        (_, Some(base)) => (base, false),
+        _               => (name, false),
+    }
+}
+
+// Encodes `operands` for the operation described by `info`, with offset `off` for the arithmetic
+// operations. Returns `None` if the operands do not match the operation.
+fn encode_operands(info: &ebpf::OpcodeInfo, operands: &[Operand], off: i16)
+                   -> Option<Result<Vec<ebpf::Insn>, String>> {
+    use self::Operand::*;
+    use ebpf::OperandKind;
+
+    let opc = info.opc;
+    let insns = match (info.operands, operands) {
+        (OperandKind::None, &[]) => Ok(vec![insn(opc, 0, 0, 0, 0)]),
+        (OperandKind::Dst, &[Register(dst)]) => Ok(vec![insn(opc, dst, 0, 0, 0)]),
+        (OperandKind::DstImm, &[Register(dst), Integer(imm)]) =>
+            imm32(imm).map(|imm| vec![insn(opc, dst, 0, off, imm)]),
+        (OperandKind::DstSrc, &[Register(dst), Register(src)]) => Ok(vec![insn(opc, dst, src, off, 0)]),
+        (OperandKind::DstImm64, &[Register(dst), Integer(imm)]) => Ok(vec![
+            insn(opc, dst, 0, 0, imm as i32),
+            insn(0, 0, 0, 0, (imm >> 32) as i32),
+        ]),
+        (OperandKind::DstMem, &[Register(dst), Memory(src, off)]) =>
+            off16(off).map(|off| vec![insn(opc, dst, src, off, 0)]),
+        (OperandKind::MemImm, &[Memory(dst, off), Integer(imm)]) =>
+            off16(off).and_then(|off| Ok(vec![insn(opc, dst, 0, off, imm32(imm)?)])),
+        (OperandKind::MemSrc, &[Memory(dst, off), Register(src)]) =>
+            off16(off).map(|off| vec![insn(opc, dst, src, off, 0)]),
+        (OperandKind::Imm, &[Integer(imm)]) => imm32(imm).map(|imm| vec![insn(opc, 0, 0, 0, imm)]),
+        (OperandKind::SrcImm, &[Register(src), Integer(imm)]) =>
+            imm32(imm).map(|imm| vec![insn(opc, 0, src, 0, imm)]),
+        // `ja32` has its offset in the immediate.
+        (OperandKind::Off, &[Integer(off)]) if opc == ebpf::JA32 =>
+            imm32(off).map(|off| vec![insn(opc, 0, 0, 0, off)]),
+        (OperandKind::Off, &[Integer(off)]) => off16(off).map(|off| vec![insn(opc, 0, 0, off, 0)]),
+        (OperandKind::DstImmOff, &[Register(dst), Integer(imm), Integer(off)]) =>
+            off16(off).and_then(|off| Ok(vec![insn(opc, dst, 0, off, imm32(imm)?)])),
+        (OperandKind::DstSrcOff, &[Register(dst), Register(src), Integer(off)]) =>
+            off16(off).map(|off| vec![insn(opc, dst, src, off, 0)]),
+        _ => return None,
+    };
+    Some(insns)
+}
+
+// Encodes the instruction `name` with `operands`, into one or two (for `lddw`) instructions. The
+// mnemonics and operands of the operations are those of `ebpf::OPCODES`.
+fn encode(name: &str, operands: &[Operand]) -> Result<Vec<ebpf::Insn>, String> {
+    use self::Operand::*;
+
+    let invalid = || Err(format!("invalid operands for {}", name));
+
+    // Byte swaps, with the width in the mnemonic.
+    for (prefix, opc) in [("le", ebpf::LE), ("be", ebpf::BE), ("bswap", ebpf::BSWAP)] {
+        if let Some(bits @ ("16" | "32" | "64")) = name.strip_prefix(prefix) {
+            return match *operands {
+                [Register(dst)] => Ok(vec![insn(opc, dst, 0, 0, bits.parse().unwrap())]),
+                _ => invalid(),
+            };
+        }
+    }
+
+    // Signed divisions and modulos, and sign-extending moves, are variants of `div`, `mod` and
+    // `mov` selected by the offset. The ALU operations without a width are the 64-bit ones.
+    let (base, is32) = split_width(name);
+    let width = if is32 { "32" } else { "64" };
+    let (mnemonic, off, operands) = match base {
+        "sdiv" | "smod" => (format!("{}{}", &base[1..], width), 1, operands),
+        "movsx" => match *operands {
+            [Register(_), Register(_), Integer(bits @ (8 | 16 | 32))] =>
+                (format!("mov{}", width), bits as i16, &operands[..2]),
+            _ => return invalid(),
+        },
+        _ if ebpf::OPCODES.iter().any(|info| info.mnemonic == name) => (name.to_string(), 0, operands),
+        _ => (format!("{}{}", name, if is32 { "" } else { "64" }), 0, operands),
+    };
+
+    let mut known = false;
+    for info in ebpf::OPCODES.iter().filter(|info| info.mnemonic == mnemonic) {
+        known = true;
+        if let Some(insns) = encode_operands(info, operands, off) {
+            return insns;
+        }
+    }
+    match known {
+        true  => invalid(),
+        false => Err(format!("unknown instruction {}", name)),
+    }
+}
+
+// Assembles a single line, with no comment.
+fn assemble_line(line: &str) -> Result<Vec<ebpf::Insn>, String> {
+    let (name, operands) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None    => (line, ""),
+    };
+    let operands = match operands {
+        "" => vec![],
+        _  => operands.split(',').map(|op| parse_operand(op.trim())).collect::<Result<Vec<_>, _>>()?,
+    };
+    encode(name, &operands)
+}
+
+/// Assemble the program `src` into eBPF bytecode, see the module documentation for the syntax.
+/// The bytecode is not checked by the verifier.
+///
+/// # Errors
+///
+/// Returns a message with the number of the line (from 1) of the first instruction that cannot
+/// be assembled.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::assembler;
+///
+/// let prog = assembler::assemble("
+///     // Returns the first byte of packet data plus 2.
+///     ldxb r0, [r1]
+///     add64 r0, 0x2
+///     exit
+/// ").unwrap();
+///
+/// assert_eq!(prog, vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+/// ]);
+///
+/// assert_eq!(assembler::assemble("mov64 r11, 1"),
+///            Err("Error: invalid register \"r11\" (line 1)".to_string()));
+/// ```
+pub fn assemble(src: &str) -> Result<Vec<u8>, String> {
+    let mut prog = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let line = match line.find('#').into_iter().chain(line.find("//")).min() {
+            Some(comment) => &line[..comment],
+            None          => line,
+        }.trim();
+        if line.is_empty() {
+            continue;
+        }
+        for insn in assemble_line(line).map_err(|msg| format!("Error: {} (line {})", msg, i + 1))? {
+            prog.extend_from_slice(&insn.to_array());
+        }
+    }
+    Ok(prog)
+}
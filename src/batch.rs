@@ -0,0 +1,152 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides an executor running a program over a batch of packets, such as the
+//! packets of a capture file, on several threads.
+//!
+//! The batch is split into contiguous parts, one per thread. Each thread interprets the program
+//! over the packets of its part, on their own memory as with `EbpfVmRaw`, and all threads share
+//! the helpers and maps registered on the executor: maps updated by the program are seen by all
+//! threads, see module `maps` for their consistency guarantees. The results are returned in the
+//! order of the packets of the batch.
+//!
+//! Programs run by an executor are interpreted; they cannot be JIT-compiled.
+
+use std::collections::HashMap;
+use std::panic;
+use std::sync::Arc;
+use std::thread;
+
+use ebpf;
+use error::EbpfError;
+use interpreter;
+use maps::Map;
+use verifier;
+
+/// An executor running a program over batches of packets on several threads, see the module
+/// documentation.
+///
+/// # Examples
+///
+/// ```
+/// // Returns the first byte of packet data.
+/// let prog = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let mut packets: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i, 0xff]).collect();
+///
+/// let mut executor = rbpf::batch::BatchExecutor::new(prog);
+/// executor.set_threads(4);
+///
+/// let results = executor.prog_exec(&mut packets);
+/// assert_eq!(results, (0..100).collect::<Vec<u64>>());
+/// ```
+pub struct BatchExecutor {
+    prog:        Vec<u8>,
+    helpers:     HashMap<u32, ebpf::Helper>,
+    maps:        Vec<Arc<Map>>,
+    mem_helpers: bool,
+    threads:     usize,
+}
+
+impl BatchExecutor {
+
+    /// Create an executor for the program `prog`, running on as many threads as the host can run
+    /// in parallel. The program passes through the verifier.
+    ///
+    /// # Panics
+    ///
+    /// The verifier may panic if it finds errors in the eBPF program.
+    pub fn new(prog: Vec<u8>) -> BatchExecutor {
+        verifier::check(&prog);
+        BatchExecutor {
+            prog,
+            helpers:     HashMap::new(),
+            maps:        vec![],
+            mem_helpers: false,
+            threads:     thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Set the number of threads the batches are split across.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is 0.
+    pub fn set_threads(&mut self, threads: usize) {
+        if threads == 0 {
+            panic!("Error: cannot run a batch on 0 threads");
+        }
+        self.threads = threads;
+    }
+
+    /// The number of threads the batches are split across.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Register a helper function, shared by all threads. See `EbpfVmMbuff::register_helper()`.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Make the bounds-checked memory helpers available to the program. See
+    /// `EbpfVmMbuff::register_mem_helpers()`.
+    pub fn register_mem_helpers(&mut self) {
+        self.mem_helpers = true;
+    }
+
+    /// Register a map, shared by all threads, and return its identifier. See
+    /// `EbpfVmMbuff::register_map()`.
+    pub fn register_map(&mut self, map: Arc<Map>) -> u32 {
+        self.maps.push(map);
+        (self.maps.len() - 1) as u32
+    }
+
+    /// Run the program over each packet of `packets`, and return the values it returned, in the
+    /// order of the packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the runtime error of the first packet, in the order of the batch, that the
+    /// program fails to run on. See `try_prog_exec()`.
+    pub fn prog_exec<T: AsMut<[u8]> + Send>(&self, packets: &mut [T]) -> Vec<u64> {
+        self.try_prog_exec(packets).into_iter().map(|res| match res {
+            Ok(res)  => res,
+            Err(err) => panic!("{}", err),
+        }).collect()
+    }
+
+    /// Run the program over each packet of `packets`, in the same way as `prog_exec()`, but
+    /// return the result of each packet instead of panicking on runtime errors.
+    ///
+    /// Panics raised by helpers are propagated to the caller once all threads have stopped.
+    pub fn try_prog_exec<T: AsMut<[u8]> + Send>(&self, packets: &mut [T]) -> Vec<Result<u64, EbpfError>> {
+        if packets.is_empty() {
+            return vec![];
+        }
+        let part_len = packets.len().div_ceil(self.threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = packets.chunks_mut(part_len).map(|part| scope.spawn(move || {
+                part.iter_mut().map(|packet| self.exec(packet.as_mut())).collect::<Vec<_>>()
+            })).collect();
+            workers.into_iter().flat_map(|worker| {
+                worker.join().unwrap_or_else(|payload| panic::resume_unwind(payload))
+            }).collect()
+        })
+    }
+
+    fn exec(&self, mem: &mut [u8]) -> Result<u64, EbpfError> {
+        let options = interpreter::Options {
+            mem_helpers: self.mem_helpers,
+            maps:        &self.maps,
+            ..Default::default()
+        };
+        interpreter::try_execute_program(&self.prog, mem, &[], &self.helpers, options)
+    }
+}
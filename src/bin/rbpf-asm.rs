@@ -0,0 +1,83 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Assemble an eBPF program into bytecode, written as raw bytes or as a C array.
+
+extern crate rbpf;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use rbpf::{assembler, disassembler, ebpf};
+
+const USAGE: &str = "Usage: rbpf-asm [-f bin|c] [-o OUTPUT] [INPUT]
+
+Assemble the eBPF program in INPUT (or the standard input) into bytecode, written to OUTPUT (or
+the standard output) as raw bytes (format `bin`, the default), or as a C array (format `c`).";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("rbpf-asm: {}", msg);
+    process::exit(1);
+}
+
+// The bytecode as a C array, with the assembly of each instruction in comments.
+fn c_array(prog: &[u8]) -> String {
+    let insns = disassembler::to_insn_vec(prog);
+    let mut out = String::from("unsigned char prog[] = {\n");
+    for (i, slot) in prog.chunks(ebpf::INSN_SIZE).enumerate() {
+        let bytes: Vec<String> = slot.iter().map(|b| format!("{:#04x}", b)).collect();
+        let last = (i + 1) * ebpf::INSN_SIZE == prog.len();
+        out.push_str(&format!("    {}{}", bytes.join(", "), if last { " " } else { "," }));
+        if let Some(insn) = insns.iter().find(|insn| insn.ptr == i) {
+            out.push_str(&format!(" // {}", insn.desc));
+        }
+        out.push('\n');
+    }
+    out.push_str("};\n");
+    out
+}
+
+fn main() {
+    let (mut format, mut output, mut input) = ("bin".to_string(), None, None);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => format = args.next().unwrap_or_else(|| fail(USAGE)),
+            "-o" | "--output" => output = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "-h" | "--help"   => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+
+    let mut src = String::new();
+    let read = match input {
+        Some(ref path) => fs::read_to_string(path).map(|s| src = s),
+        None           => io::stdin().read_to_string(&mut src).map(|_| ()),
+    };
+    if let Err(err) = read {
+        fail(&format!("cannot read input: {}", err));
+    }
+
+    let prog = assembler::assemble(&src).unwrap_or_else(|msg| fail(&msg));
+    let bytes = match format.as_str() {
+        "bin" => prog,
+        "c"   => c_array(&prog).into_bytes(),
+        _     => fail(&format!("unknown format {:?}, expected bin or c", format)),
+    };
+    let written = match output {
+        Some(ref path) => fs::write(path, &bytes),
+        None           => io::stdout().write_all(&bytes),
+    };
+    if let Err(err) = written {
+        fail(&format!("cannot write output: {}", err));
+    }
+}
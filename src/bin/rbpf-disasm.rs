@@ -0,0 +1,101 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Disassemble eBPF bytecode, given as raw bytes or as a C array.
+
+extern crate rbpf;
+
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use rbpf::{disassembler, ebpf};
+
+const USAGE: &str = "Usage: rbpf-disasm [-f bin|c] [INPUT]
+
+Disassemble the eBPF bytecode in INPUT (or the standard input), given as raw bytes (format `bin`,
+the default), or as a C array (format `c`), and print one instruction per line.";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("rbpf-disasm: {}", msg);
+    process::exit(1);
+}
+
+// Removes the `//` and `/* */` comments of C source `src`.
+fn strip_comments(src: &str) -> String {
+    let mut out = String::new();
+    let mut rest = src;
+    while !rest.is_empty() {
+        match (rest.find("//"), rest.find("/*")) {
+            (Some(line), block) if block.is_none_or(|block| line < block) => {
+                out.push_str(&rest[..line]);
+                rest = rest[line..].find('\n').map_or("", |end| &rest[line + end..]);
+            },
+            (_, Some(block)) => {
+                out.push_str(&rest[..block]);
+                rest = rest[block..].find("*/").map_or("", |end| &rest[block + end + 2..]);
+            },
+            _ => {
+                out.push_str(rest);
+                rest = "";
+            },
+        }
+    }
+    out
+}
+
+// The bytes of a C array such as `unsigned char prog[] = { 0xb7, 0x00, ... };`, or of a bare
+// list of bytes.
+fn parse_c_array(src: &str) -> Result<Vec<u8>, String> {
+    let src = strip_comments(src);
+    let body = match (src.find('{'), src.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &src[start + 1..end],
+        _ => &src[..],
+    };
+    body.split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty()).map(|s| {
+        let byte = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None      => s.parse::<u8>(),
+        };
+        byte.map_err(|_| format!("invalid byte {:?}", s))
+    }).collect()
+}
+
+fn main() {
+    let (mut format, mut input) = ("bin".to_string(), None);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => format = args.next().unwrap_or_else(|| fail(USAGE)),
+            "-h" | "--help"   => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+
+    let mut bytes = vec![];
+    let read = match input {
+        Some(ref path) => fs::read(path).map(|b| bytes = b),
+        None           => io::stdin().read_to_end(&mut bytes).map(|_| ()),
+    };
+    if let Err(err) = read {
+        fail(&format!("cannot read input: {}", err));
+    }
+
+    let prog = match format.as_str() {
+        "bin" => bytes,
+        "c"   => parse_c_array(&String::from_utf8_lossy(&bytes)).unwrap_or_else(|msg| fail(&msg)),
+        _     => fail(&format!("unknown format {:?}, expected bin or c", format)),
+    };
+    if !prog.len().is_multiple_of(ebpf::INSN_SIZE) {
+        fail(&format!("bytecode length must be a multiple of {:?} bytes", ebpf::INSN_SIZE));
+    }
+    disassembler::disassemble(&prog);
+}
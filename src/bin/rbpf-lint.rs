@@ -0,0 +1,73 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Report unreachable code, dead stores and constant conditions in an eBPF program.
+
+extern crate rbpf;
+
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use rbpf::{analysis, assembler, disassembler, ebpf};
+
+const USAGE: &str = "Usage: rbpf-lint [-f bin|asm] [INPUT]
+
+Look for useless code in the eBPF program in INPUT (or the standard input), given as raw bytecode
+(format `bin`, the default), or as assembly (format `asm`). Each finding is printed with the
+instruction it is about. The exit status is 1 if there is any finding.";
+
+fn fail(msg: &str) -> ! {
+    eprintln!("rbpf-lint: {}", msg);
+    process::exit(2);
+}
+
+fn main() {
+    let (mut format, mut input) = ("bin".to_string(), None);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => format = args.next().unwrap_or_else(|| fail(USAGE)),
+            "-h" | "--help"   => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+
+    let mut bytes = vec![];
+    let read = match input {
+        Some(ref path) => fs::read(path).map(|b| bytes = b),
+        None           => io::stdin().read_to_end(&mut bytes).map(|_| ()),
+    };
+    if let Err(err) = read {
+        fail(&format!("cannot read input: {}", err));
+    }
+
+    let prog = match format.as_str() {
+        "bin" => bytes,
+        "asm" => assembler::assemble(&String::from_utf8_lossy(&bytes)).unwrap_or_else(|msg| fail(&msg)),
+        _     => fail(&format!("unknown format {:?}, expected bin or asm", format)),
+    };
+    if !prog.len().is_multiple_of(ebpf::INSN_SIZE) {
+        fail(&format!("bytecode length must be a multiple of {:?} bytes", ebpf::INSN_SIZE));
+    }
+
+    let insns = disassembler::to_insn_vec(&prog);
+    let lints = analysis::lint(&prog);
+    for lint in &lints {
+        match insns.iter().find(|insn| insn.ptr == lint.insn_ptr()) {
+            Some(insn) => println!("{}: {}", insn.desc, lint),
+            None       => println!("{}", lint),
+        }
+    }
+    if !lints.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! Interactive eBPF interpreter: run instructions one at a time, and inspect their effect on the
+//! registers, the stack and packet data.
+//!
+//! Each instruction runs in the interpreter, in a program that first restores the state left by
+//! the previous instructions: the registers and the stack are saved between instructions, packet
+//! data is kept in place. Pointers to the stack and to packet data are saved as offsets, as the
+//! stack moves from one program to the next.
+
+extern crate rbpf;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::panic;
+
+use rbpf::{assembler, ebpf, helpers};
+use rbpf::trace::{AccessKind, TraceLog};
+
+const HELP: &str = "Type an instruction to run it, for example `mov64 r1, 0x2a` or `ldxb r0, [r1]`,
+or one of the following commands:
+
+    .regs               print the registers
+    .stack              print the bytes of the stack that are not zero
+    .packet             print packet data
+    .packet BYTES...    set packet data, as hexadecimal bytes (`.packet aa bb 01`), and point R1
+                        to it
+    .helpers            list the helpers available to `call`
+    .reset              clear the registers and the stack, and point R1 to packet data
+    .help               print this message
+    .quit               leave the REPL
+
+Jumps are not taken: the REPL tells whether they would be.";
+
+// The helpers registered from module `helpers`, with the keys used in the uBPF tests.
+const HELPERS: [(u32, &str, ebpf::Helper); 5] = [
+    (0, "gather_bytes", helpers::gather_bytes),
+    (1, "memfrob", helpers::memfrob),
+    (3, "sqrti", helpers::sqrti),
+    (4, "strcmp", helpers::strcmp),
+    (helpers::BPF_TRACE_PRINTK_IDX, "bpf_trace_printk", helpers::bpf_trace_printf),
+];
+
+// The value of a register, with pointers kept relative to the memory area they point to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Scalar(u64),
+    Stack(i64),
+    Packet(u64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Scalar(v)           => write!(f, "{:#x}", v),
+            Value::Stack(off) if off < 0 => write!(f, "r10-{:#x}", -off),
+            Value::Stack(off)          => write!(f, "r10+{:#x}", off),
+            Value::Packet(off)         => write!(f, "packet+{:#x}", off),
+        }
+    }
+}
+
+struct Repl {
+    regs:   [Value; 10],
+    stack:  Vec<u8>,
+    packet: Vec<u8>,
+}
+
+fn push(prog: &mut Vec<u8>, src: &str) {
+    prog.extend(assembler::assemble(src).unwrap());
+}
+
+impl Repl {
+    fn new() -> Repl {
+        let mut repl = Repl { regs: [Value::Scalar(0); 10], stack: vec![], packet: vec![] };
+        repl.reset();
+        repl
+    }
+
+    fn reset(&mut self) {
+        self.regs = [Value::Scalar(0); 10];
+        self.regs[1] = Value::Packet(0);
+        self.stack = vec![0; ebpf::STACK_SIZE];
+    }
+
+    // The value of a register holding address `addr`, given the frame pointer of the run.
+    fn value(&self, addr: u64, r10: u64) -> Value {
+        let packet = self.packet.as_ptr() as u64;
+        if addr <= r10 && r10 - addr <= ebpf::STACK_SIZE as u64 {
+            Value::Stack(addr.wrapping_sub(r10) as i64)
+        } else if addr >= packet && addr - packet <= self.packet.len() as u64 {
+            Value::Packet(addr - packet)
+        } else {
+            Value::Scalar(addr)
+        }
+    }
+
+    // The program restoring the state, R10 being saved into R0 first to find the stack.
+    fn prologue(&self) -> Vec<u8> {
+        let mut prog = vec![];
+        push(&mut prog, "mov64 r0, r10");
+        for (i, slot) in self.stack.chunks(8).enumerate() {
+            let value = u64::from_le_bytes(slot.try_into().unwrap()) as i64;
+            let off = i as i64 * 8 - ebpf::STACK_SIZE as i64;
+            match value {
+                v if v >= i32::MIN as i64 && v <= i32::MAX as i64 => push(&mut prog, &format!("stdw [r10{:+}], {}", off, v)),
+                v => push(&mut prog, &format!("lddw r0, {}\nstxdw [r10{:+}], r0", v, off)),
+            }
+        }
+        for (reg, value) in self.regs.iter().enumerate() {
+            match *value {
+                Value::Scalar(v)   => push(&mut prog, &format!("lddw r{}, {:#x}", reg, v)),
+                Value::Stack(off)  => push(&mut prog, &format!("mov64 r{}, r10\nadd64 r{}, {}", reg, reg, off)),
+                Value::Packet(off) => push(&mut prog, &format!("lddw r{}, {:#x}", reg, self.packet.as_ptr() as u64 + off)),
+            }
+        }
+        prog
+    }
+
+    fn run(&mut self, line: &str) -> Result<(), String> {
+        let mut insn = assembler::assemble(line)?;
+        let opc = match insn.first() {
+            Some(&opc) => opc,
+            None       => return Ok(()),
+        };
+        if insn.len() > ebpf::INSN_SIZE && opc != ebpf::LD_DW_IMM {
+            return Err("Error: type one instruction at a time".to_string());
+        }
+        let class = opc & ebpf::BPF_CLS_MASK;
+        let jump = (class == ebpf::BPF_JMP || class == ebpf::BPF_JMP32) &&
+            ![ebpf::CALL, ebpf::TAIL_CALL, ebpf::EXIT].contains(&opc);
+
+        let mut prog = self.prologue();
+        let start = prog.len() / ebpf::INSN_SIZE;
+        if jump {
+            // Jump over the next `exit` if the jump is taken.
+            match opc {
+                ebpf::JA32 => insn[4..8].copy_from_slice(&1i32.to_le_bytes()),
+                _          => insn[2..4].copy_from_slice(&1i16.to_le_bytes()),
+            }
+            prog.extend(insn);
+            push(&mut prog, "exit\nexit");
+        } else {
+            prog.extend(insn);
+            for off in (0..ebpf::STACK_SIZE).step_by(8) {
+                push(&mut prog, &format!("ldxdw r0, [r10-{}]", ebpf::STACK_SIZE - off));
+            }
+            push(&mut prog, "exit");
+        }
+
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        for &(key, _, helper) in HELPERS.iter() {
+            vm.register_helper(key, helper);
+        }
+        vm.register_mem_helpers();
+        let mut log = TraceLog::new();
+        let ret = panic::catch_unwind(panic::AssertUnwindSafe(|| vm.prog_exec_trace(&mut self.packet, &mut log)));
+        let ret = ret.map_err(|payload| {
+            payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "Error: the instruction failed".to_string())
+        })?;
+
+        let entries = log.entries();
+        let r10 = entries[0].reg_deltas[0].new;
+        let entry = match entries.iter().find(|entry| entry.insn_ptr == start) {
+            Some(entry) => entry,
+            None        => return Err("Error: the instruction was not run".to_string()),
+        };
+        if let Some(access) = entry.mem_access {
+            let kind = match access.kind {
+                AccessKind::Load  => "load",
+                AccessKind::Store => "store",
+            };
+            println!("{} [{}] ({} bytes) = {:#x}", kind, self.value(access.addr, r10), access.len, access.value);
+        }
+        for delta in &entry.reg_deltas {
+            let (old, new) = (self.value(delta.old, r10), self.value(delta.new, r10));
+            println!("r{} = {} (was {})", delta.reg, new, old);
+            self.regs[delta.reg as usize] = new;
+        }
+        match opc {
+            ebpf::EXIT => println!("exit: the program returns {:#x}", ret),
+            _ if jump  => match entries.iter().any(|entry| entry.insn_ptr == start + 2) {
+                true  => println!("jump taken"),
+                false => println!("jump not taken"),
+            },
+            _ => {
+                // The epilogue loads the stack back, slot after slot.
+                for (i, entry) in entries.iter().filter(|entry| entry.insn_ptr > start).enumerate() {
+                    if let Some(access) = entry.mem_access.filter(|_| i < ebpf::STACK_SIZE / 8) {
+                        self.stack[i * 8..i * 8 + 8].copy_from_slice(&access.value.to_le_bytes());
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, cmd: &str, args: &[&str]) -> Result<(), String> {
+        match (cmd, args) {
+            (".regs", []) => {
+                for (reg, value) in self.regs.iter().enumerate() {
+                    println!("r{} = {}", reg, value);
+                }
+                println!("r10 = stack top, read-only");
+            },
+            (".stack", []) => {
+                for (i, row) in self.stack.chunks(16).enumerate().filter(|(_, row)| row.iter().any(|&b| b != 0)) {
+                    let bytes: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+                    println!("r10-{:#05x}: {}", ebpf::STACK_SIZE - i * 16, bytes.join(" "));
+                }
+            },
+            (".packet", []) => {
+                let bytes: Vec<String> = self.packet.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{} bytes: {}", self.packet.len(), bytes.join(" "));
+            },
+            (".packet", bytes) => {
+                self.packet = bytes.iter().map(|b| u8::from_str_radix(b.trim_start_matches("0x"), 16))
+                    .collect::<Result<_, _>>().map_err(|_| "Error: invalid packet bytes".to_string())?;
+                self.regs[1] = Value::Packet(0);
+            },
+            (".helpers", []) => {
+                for &(key, name, _) in HELPERS.iter() {
+                    println!("{:#x}: {}", key, name);
+                }
+                println!("{:#x}, {:#x}, {:#x}, {:#x}, {:#x}, {:#x}: memcpy, memset, memcmp, strtol, strtoul, csum_diff",
+                         helpers::MEMCPY_IDX, helpers::MEMSET_IDX, helpers::MEMCMP_IDX,
+                         helpers::BPF_STRTOL_IDX, helpers::BPF_STRTOUL_IDX, helpers::BPF_CSUM_DIFF_IDX);
+            },
+            (".reset", []) => self.reset(),
+            (".help", []) => println!("{}", HELP),
+            _ => return Err(format!("Error: unknown command {}, see .help", cmd)),
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    // Errors of the interpreter are caught and printed as messages.
+    panic::set_hook(Box::new(|_| {}));
+
+    println!("rbpf REPL, type .help for help");
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("rbpf> ");
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _              => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let res = match words.first() {
+            Some(&".quit") => break,
+            Some(cmd) if cmd.starts_with('.') => repl.command(cmd, &words[1..]),
+            Some(_) => repl.run(&line),
+            None    => Ok(()),
+        };
+        if let Err(msg) = res {
+            println!("{}", msg);
+        }
+    }
+    println!();
+}
@@ -0,0 +1,891 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module reads source line information, and the definitions of maps, from BTF (BPF Type
+//! Format) data.
+//!
+//! When compiled with debug information (`clang -g -target bpf`), an eBPF object file contains a
+//! `.BTF` section, holding in particular a table of strings, and a `.BTF.ext` section, holding
+//! for each program section a list of "line info" records. Each record associates the offset of
+//! an instruction in the program with a source file, a line, and a column. Its "func info"
+//! records name the functions of the section, by the offset of their first instruction, as the
+//! symbol table of the object file does.
+//!
+//! rbpf does not parse ELF files: the contents of these sections, and the name of the section
+//! holding the program, have to be extracted from the object file by the user, as for the
+//! bytecode itself. The resulting `LineInfo` can then be attached to a VM with `set_line_info()`,
+//! so that runtime errors report the source location of the faulty instruction, or to tracers
+//! (see `trace::TextWriter::with_line_info()`). Only little-endian BTF data is supported.
+//!
+//! The maps of a program are defined in the `.maps` section of the object file, whose types in
+//! the `.BTF` section describe each map (`MapDefinition::parse()`), or in the `maps` section of
+//! older object files, as an array of `struct bpf_map_def` (`MapDefinition::parse_legacy()`). The
+//! maps are created from these definitions, registered on the VM, and the instructions loading
+//! their addresses relocated to their identifiers with `relocate_maps()`, from the relocation
+//! section of the program.
+//!
+//! The global variables of a program are held, as libbpf does, in an array map for each section
+//! of the object file holding them, `.data`, `.bss` or `.rodata`, which the application reads and
+//! writes by the names of the variables, see `Globals`.
+//!
+//! See <https://www.kernel.org/doc/html/latest/bpf/btf.html> for the details of the format.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::sync::Arc;
+
+use ctx::Context;
+use ebpf;
+use maps::{self, Map, MapType};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Magic number at the beginning of BTF and BTF.ext data, in little-endian byte order.
+pub const BTF_MAGIC: u16 = 0xeb9f;
+
+/// The source location of an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceLocation {
+    /// Name of the source file, as recorded by the compiler.
+    pub file: String,
+    /// Line number in the source file, starting at 1.
+    pub line: u32,
+    /// Column number in the line, starting at 1, or 0 if unknown.
+    pub col: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.col {
+            0 => write!(f, "{}:{}", self.file, self.line),
+            _ => write!(f, "{}:{}:{}", self.file, self.line, self.col),
+        }
+    }
+}
+
+/// Line information for one eBPF program: a mapping from instruction indexes to source
+/// locations, and to the names of the functions holding them.
+///
+/// Compilers only emit a record for the first instruction generated for a given source
+/// location, so an instruction is mapped to the location of the closest record at or before it.
+/// Likewise, a function spans the instructions from its first one up to the next function.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::btf::{LineInfo, SourceLocation};
+///
+/// let mut info = LineInfo::new();
+/// info.insert(0, SourceLocation { file: "filter.c".to_string(), line: 40, col: 5 });
+/// info.insert(3, SourceLocation { file: "filter.c".to_string(), line: 42, col: 0 });
+///
+/// assert_eq!(info.lookup(2).unwrap().to_string(), "filter.c:40:5");
+/// assert_eq!(info.lookup(7).unwrap().to_string(), "filter.c:42");
+///
+/// info.insert_function(0, "filter");
+/// info.insert_function(5, "parse_ipv4");
+/// assert_eq!(info.function(2), Some("filter"));
+/// assert_eq!(info.function(7), Some("parse_ipv4"));
+/// assert_eq!(info.function_start("parse_ipv4"), Some(5));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineInfo {
+    // Sorted by instruction index.
+    records:   Vec<(usize, SourceLocation)>,
+    functions: Vec<(usize, String)>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16, Error> {
+    match data.get(off..off + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None    => Err(invalid("truncated BTF data")),
+    }
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32, Error> {
+    match data.get(off..off + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None    => Err(invalid("truncated BTF data")),
+    }
+}
+
+// Checks the common header of BTF and BTF.ext data, and returns its length.
+fn check_header(data: &[u8]) -> Result<usize, Error> {
+    match read_u16(data, 0)? {
+        BTF_MAGIC => (),
+        0x9feb    => return Err(invalid("big-endian BTF data is not supported")),
+        _         => return Err(invalid("invalid BTF magic number")),
+    }
+    if data.get(2) != Some(&1) {
+        return Err(invalid("unsupported BTF version"));
+    }
+    Ok(read_u32(data, 4)? as usize)
+}
+
+// Returns the null-terminated string at `off` in the string table of `.BTF` data.
+fn btf_str(strings: &[u8], off: u32) -> Result<String, Error> {
+    let bytes = match strings.get(off as usize..) {
+        Some(bytes) => bytes,
+        None        => return Err(invalid("BTF string offset out of bounds")),
+    };
+    match bytes.iter().position(|&b| b == 0) {
+        Some(end) => Ok(String::from_utf8_lossy(&bytes[..end]).into_owned()),
+        None      => Err(invalid("unterminated BTF string")),
+    }
+}
+
+impl LineInfo {
+    /// Create an empty mapping.
+    pub fn new() -> LineInfo {
+        LineInfo::default()
+    }
+
+    /// Read the line information for the program in section `section`, from the contents of the
+    /// `.BTF` and `.BTF.ext` sections of an object file, with the names of its functions, if the
+    /// `.BTF.ext` section holds func info records.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the data is malformed, or if there is no line
+    /// information for `section`.
+    pub fn parse(btf: &[u8], btf_ext: &[u8], section: &str) -> Result<LineInfo, Error> {
+        // .BTF: header, then type and string sections, with offsets relative to the end of the
+        // header.
+        let hdr_len = check_header(btf)?;
+        let str_off = hdr_len + read_u32(btf, 16)? as usize;
+        let str_len = read_u32(btf, 20)? as usize;
+        let strings = match btf.get(str_off..str_off + str_len) {
+            Some(strings) => strings,
+            None          => return Err(invalid("BTF string section out of bounds")),
+        };
+
+        // .BTF.ext: header, then func_info and line_info sections, with offsets relative to the
+        // end of the header.
+        let ext_hdr_len = check_header(btf_ext)?;
+        let line_off = ext_hdr_len + read_u32(btf_ext, 16)? as usize;
+        let line_len = read_u32(btf_ext, 20)? as usize;
+        let lines = match btf_ext.get(line_off..line_off + line_len) {
+            Some(lines) => lines,
+            None        => return Err(invalid("BTF.ext line info section out of bounds")),
+        };
+
+        // The line info section starts with the size of a record, followed by one block per
+        // program section: name of the section, number of records, and the records.
+        let rec_size = read_u32(lines, 0)? as usize;
+        if rec_size < 16 {
+            return Err(invalid("invalid BTF.ext line info record size"));
+        }
+        let mut off = 4;
+        while off < lines.len() {
+            let sec_name = btf_str(strings, read_u32(lines, off)?)?;
+            let num_info = read_u32(lines, off + 4)? as usize;
+            off += 8;
+            if sec_name != section {
+                off += num_info * rec_size;
+                continue;
+            }
+
+            let mut info = LineInfo::new();
+            for i in 0..num_info {
+                let rec = off + i * rec_size;
+                let insn_off = read_u32(lines, rec)? as usize;
+                let file = btf_str(strings, read_u32(lines, rec + 4)?)?;
+                let line_col = read_u32(lines, rec + 12)?;
+                info.insert(insn_off / ebpf::INSN_SIZE, SourceLocation {
+                    file,
+                    line: line_col >> 10,
+                    col:  line_col & 0x3ff,
+                });
+            }
+            info.parse_functions(btf, btf_ext, ext_hdr_len, section)?;
+            return Ok(info);
+        }
+
+        Err(invalid("no line info for this section"))
+    }
+
+    // Reads the names of the functions of section `section` from the func info section of
+    // `.BTF.ext` data, made of blocks as the line info section. Records are made of the offset of
+    // the first instruction of a function, and of its type in `.BTF` data, which names it.
+    fn parse_functions(&mut self, btf: &[u8], btf_ext: &[u8], ext_hdr_len: usize, section: &str)
+        -> Result<(), Error> {
+        let func_off = ext_hdr_len + read_u32(btf_ext, 8)? as usize;
+        let func_len = read_u32(btf_ext, 12)? as usize;
+        if func_len == 0 {
+            return Ok(());
+        }
+        let funcs = match btf_ext.get(func_off..func_off + func_len) {
+            Some(funcs) => funcs,
+            None        => return Err(invalid("BTF.ext func info section out of bounds")),
+        };
+        let btf = Btf::parse(btf)?;
+        let rec_size = read_u32(funcs, 0)? as usize;
+        if rec_size < 8 {
+            return Err(invalid("invalid BTF.ext func info record size"));
+        }
+        let mut off = 4;
+        while off < funcs.len() {
+            let sec_name = btf_str(btf.strings, read_u32(funcs, off)?)?;
+            let num_info = read_u32(funcs, off + 4)? as usize;
+            off += 8;
+            if sec_name == section {
+                for i in 0..num_info {
+                    let rec = off + i * rec_size;
+                    let insn_off = read_u32(funcs, rec)? as usize;
+                    let func = btf.get(read_u32(funcs, rec + 4)?)?;
+                    if func.kind != BTF_KIND_FUNC {
+                        return Err(invalid("BTF.ext func info record is not a function"));
+                    }
+                    self.insert_function(insn_off / ebpf::INSN_SIZE, &btf.name(func)?);
+                }
+            }
+            off += num_info * rec_size;
+        }
+        Ok(())
+    }
+
+    /// Map instruction `insn_ptr`, and the following ones up to the next mapped instruction, to
+    /// source location `location`.
+    pub fn insert(&mut self, insn_ptr: usize, location: SourceLocation) {
+        match self.records.binary_search_by_key(&insn_ptr, |&(idx, _)| idx) {
+            Ok(pos)  => self.records[pos].1 = location,
+            Err(pos) => self.records.insert(pos, (insn_ptr, location)),
+        }
+    }
+
+    /// Return the source location of instruction `insn_ptr`, if known.
+    pub fn lookup(&self, insn_ptr: usize) -> Option<&SourceLocation> {
+        match self.records.binary_search_by_key(&insn_ptr, |&(idx, _)| idx) {
+            Ok(pos)  => Some(&self.records[pos].1),
+            Err(0)   => None,
+            Err(pos) => Some(&self.records[pos - 1].1),
+        }
+    }
+
+    /// Return the number of line info records.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Return `true` if there is no line info record.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Name `name` the function starting at instruction `insn_ptr`, and spanning the following
+    /// instructions up to the next function. This is how the symbols of the functions of the
+    /// section of the program, in the symbol table of an object file, are added, with their
+    /// values divided by the size of an instruction.
+    pub fn insert_function(&mut self, insn_ptr: usize, name: &str) {
+        match self.functions.binary_search_by_key(&insn_ptr, |&(idx, _)| idx) {
+            Ok(pos)  => self.functions[pos].1 = name.to_string(),
+            Err(pos) => self.functions.insert(pos, (insn_ptr, name.to_string())),
+        }
+    }
+
+    /// Return the name of the function holding instruction `insn_ptr`, if known.
+    pub fn function(&self, insn_ptr: usize) -> Option<&str> {
+        match self.functions.binary_search_by_key(&insn_ptr, |&(idx, _)| idx) {
+            Ok(pos)  => Some(&self.functions[pos].1),
+            Err(0)   => None,
+            Err(pos) => Some(&self.functions[pos - 1].1),
+        }
+    }
+
+    /// Return the index of the first instruction of function `name`, if known.
+    pub fn function_start(&self, name: &str) -> Option<usize> {
+        self.functions.iter().find(|(_, func)| func == name).map(|&(idx, _)| idx)
+    }
+
+    /// Return the functions, by the index of their first instruction, sorted.
+    pub fn functions(&self) -> &[(usize, String)] {
+        &self.functions
+    }
+
+    /// Return the instructions of function `name` in `prog`, the bytecode of the whole section,
+    /// to load the program of a section holding several by the name of its symbol rather than by
+    /// the name of the section. The function must not call the other functions of the section,
+    /// which are not part of the bytecode returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::btf::LineInfo;
+    ///
+    /// // Two programs in the same section.
+    /// let section = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+    ///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov r0, 2
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut info = LineInfo::new();
+    /// info.insert_function(0, "allow");
+    /// info.insert_function(2, "drop");
+    ///
+    /// let prog = info.function_code(&section, "drop").unwrap();
+    /// assert_eq!(rbpf::EbpfVmNoData::new(&prog.to_vec()).prog_exec(), 2);
+    /// assert!(info.function_code(&section, "pass").is_none());
+    /// ```
+    pub fn function_code<'p>(&self, prog: &'p [u8], name: &str) -> Option<&'p [u8]> {
+        let pos = self.functions.iter().position(|(_, func)| func == name)?;
+        let start = self.functions[pos].0 * ebpf::INSN_SIZE;
+        let end = self.functions.get(pos + 1).map_or(prog.len(), |&(idx, _)| idx * ebpf::INSN_SIZE);
+        prog.get(start..end.min(prog.len()))
+    }
+}
+
+// Kinds of BTF types.
+const BTF_KIND_INT:       u32 = 1;
+const BTF_KIND_PTR:       u32 = 2;
+const BTF_KIND_ARRAY:     u32 = 3;
+const BTF_KIND_STRUCT:    u32 = 4;
+const BTF_KIND_UNION:     u32 = 5;
+const BTF_KIND_ENUM:      u32 = 6;
+const BTF_KIND_FWD:       u32 = 7;
+const BTF_KIND_TYPEDEF:   u32 = 8;
+const BTF_KIND_VOLATILE:  u32 = 9;
+const BTF_KIND_CONST:     u32 = 10;
+const BTF_KIND_RESTRICT:  u32 = 11;
+const BTF_KIND_FUNC:      u32 = 12;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR:       u32 = 14;
+const BTF_KIND_DATASEC:   u32 = 15;
+const BTF_KIND_FLOAT:     u32 = 16;
+const BTF_KIND_DECL_TAG:  u32 = 17;
+const BTF_KIND_TYPE_TAG:  u32 = 18;
+const BTF_KIND_ENUM64:    u32 = 19;
+
+// A type of `.BTF` data: its kind, name, number of members, size or referenced type, and the
+// offset of its kind-specific data in the type section.
+struct BtfType {
+    kind:         u32,
+    name_off:     u32,
+    vlen:         usize,
+    size_or_type: u32,
+    data:         usize,
+}
+
+// The types and strings of `.BTF` data. Type `id` is at index `id - 1`, 0 being `void`.
+struct Btf<'a> {
+    types:   &'a [u8],
+    strings: &'a [u8],
+    index:   Vec<BtfType>,
+}
+
+impl<'a> Btf<'a> {
+    fn parse(btf: &'a [u8]) -> Result<Btf<'a>, Error> {
+        let hdr_len = check_header(btf)?;
+        let type_off = hdr_len + read_u32(btf, 8)? as usize;
+        let type_len = read_u32(btf, 12)? as usize;
+        let str_off = hdr_len + read_u32(btf, 16)? as usize;
+        let str_len = read_u32(btf, 20)? as usize;
+        let types = match btf.get(type_off..type_off + type_len) {
+            Some(types) => types,
+            None        => return Err(invalid("BTF type section out of bounds")),
+        };
+        let strings = match btf.get(str_off..str_off + str_len) {
+            Some(strings) => strings,
+            None          => return Err(invalid("BTF string section out of bounds")),
+        };
+
+        // Each type is made of its name, its kind and number of members, and its size or the
+        // type it references, followed by data depending on its kind.
+        let mut index = vec![];
+        let mut off = 0;
+        while off < types.len() {
+            let info = read_u32(types, off + 4)?;
+            let (kind, vlen) = ((info >> 24) & 0x1f, (info & 0xffff) as usize);
+            let data_len = match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => 4,
+                BTF_KIND_ARRAY => 12,
+                BTF_KIND_STRUCT | BTF_KIND_UNION | BTF_KIND_DATASEC | BTF_KIND_ENUM64 => vlen * 12,
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => vlen * 8,
+                BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST |
+                BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT | BTF_KIND_TYPE_TAG => 0,
+                _ => return Err(invalid("unknown BTF type kind")),
+            };
+            index.push(BtfType {
+                kind,
+                name_off:     read_u32(types, off)?,
+                vlen,
+                size_or_type: read_u32(types, off + 8)?,
+                data:         off + 12,
+            });
+            off += 12 + data_len;
+        }
+        Ok(Btf { types, strings, index })
+    }
+
+    fn get(&self, id: u32) -> Result<&BtfType, Error> {
+        match (id as usize).checked_sub(1).and_then(|idx| self.index.get(idx)) {
+            Some(ty) => Ok(ty),
+            None     => Err(invalid("BTF type id out of bounds")),
+        }
+    }
+
+    fn name(&self, ty: &BtfType) -> Result<String, Error> {
+        btf_str(self.strings, ty.name_off)
+    }
+
+    // Returns the section named `name`, if any.
+    fn datasec(&self, name: &str) -> Result<Option<&BtfType>, Error> {
+        for ty in self.index.iter().filter(|ty| ty.kind == BTF_KIND_DATASEC) {
+            if self.name(ty)? == name {
+                return Ok(Some(ty));
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns the variables of section `datasec`: the variable, its offset and its size.
+    fn datasec_vars(&self, datasec: &BtfType) -> Result<Vec<(&BtfType, usize, usize)>, Error> {
+        let mut vars = vec![];
+        for i in 0..datasec.vlen {
+            let var = self.get(read_u32(self.types, datasec.data + i * 12)?)?;
+            if var.kind != BTF_KIND_VAR {
+                return Err(invalid("member of BTF section is not a variable"));
+            }
+            let offset = read_u32(self.types, datasec.data + i * 12 + 4)? as usize;
+            let size = read_u32(self.types, datasec.data + i * 12 + 8)? as usize;
+            vars.push((var, offset, size));
+        }
+        Ok(vars)
+    }
+
+    // Returns type `id`, skipping the typedefs and qualifiers.
+    fn resolve(&self, mut id: u32) -> Result<&BtfType, Error> {
+        // Bounds the chain of references, which may loop in malformed data.
+        for _ in 0..32 {
+            let ty = self.get(id)?;
+            match ty.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT |
+                BTF_KIND_TYPE_TAG => id = ty.size_or_type,
+                _ => return Ok(ty),
+            }
+        }
+        Err(invalid("BTF type references too deep"))
+    }
+
+    // Returns the size of type `id`, in bytes.
+    fn size(&self, id: u32) -> Result<usize, Error> {
+        let ty = self.resolve(id)?;
+        match ty.kind {
+            BTF_KIND_INT | BTF_KIND_STRUCT | BTF_KIND_UNION | BTF_KIND_ENUM | BTF_KIND_FLOAT |
+            BTF_KIND_ENUM64 => Ok(ty.size_or_type as usize),
+            BTF_KIND_PTR => Ok(8),
+            BTF_KIND_ARRAY => {
+                let nelems = read_u32(self.types, ty.data + 8)? as usize;
+                Ok(nelems * self.size(read_u32(self.types, ty.data)?)?)
+            },
+            _ => Err(invalid("BTF type has no size")),
+        }
+    }
+
+    // Returns the type pointed to by pointer type `id`, the way `__type()` declares the types of
+    // the keys and values of a map.
+    fn pointee(&self, id: u32) -> Result<u32, Error> {
+        let ty = self.resolve(id)?;
+        match ty.kind {
+            BTF_KIND_PTR => Ok(ty.size_or_type),
+            _            => Err(invalid("member of map definition is not a pointer")),
+        }
+    }
+
+    // Returns the value of the member of type `id` declared with `__uint()`: a pointer to an
+    // array with as many elements as the value.
+    fn uint(&self, id: u32) -> Result<u32, Error> {
+        let array = self.resolve(self.pointee(id)?)?;
+        match array.kind {
+            BTF_KIND_ARRAY => read_u32(self.types, array.data + 8),
+            _              => Err(invalid("member of map definition is not a pointer to an array")),
+        }
+    }
+}
+
+/// The definition of a map in an object file, from which the map is created.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use rbpf::btf::{self, MapDefinition};
+///
+/// // `maps` section holding a `struct bpf_map_def` for an array map of 16 values of 8 bytes.
+/// let mut maps = vec![];
+/// for x in &[2u32, 4, 8, 16, 0] {
+///     maps.extend_from_slice(&x.to_le_bytes());
+/// }
+/// // Returns the address of the map.
+/// let mut prog = vec![
+///     0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r0, map (relocated)
+///     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let definitions = MapDefinition::parse_legacy(&maps).unwrap();
+/// assert_eq!((definitions[0].value_size, definitions[0].max_entries), (8, 16));
+///
+/// // Relocation of the instruction at offset 0 to the symbol at offset 0 of the section.
+/// btf::relocate_maps(&mut prog, &[(0, 0)], &definitions).unwrap();
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// for definition in &definitions {
+///     vm.register_map(Arc::new(definition.create()));
+/// }
+/// assert_eq!(vm.prog_exec(), 0);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapDefinition {
+    /// Name of the map, empty for the definitions of `maps` sections, whose names are only in the
+    /// symbol table of the object file.
+    pub name:        String,
+    /// Type of the map.
+    pub map_type:    MapType,
+    /// Size of the keys, in bytes.
+    pub key_size:    usize,
+    /// Size of the values, in bytes.
+    pub value_size:  usize,
+    /// Maximum number of entries.
+    pub max_entries: usize,
+    /// Offset of the definition in its section, which is the value of the symbol of the map, as
+    /// referenced by relocations.
+    pub offset:      usize,
+}
+
+// Returns the map type of identifier `id` in the Linux kernel.
+fn map_type(id: u32) -> Result<MapType, Error> {
+    MapType::from_kernel_id(id).ok_or_else(|| invalid(&format!("unsupported map type {}", id)))
+}
+
+impl MapDefinition {
+    /// Read the definitions of the maps of the `.maps` section, from the contents of the `.BTF`
+    /// section of an object file, in the order of the section. Each map is a variable of the
+    /// section, whose type is a structure declaring its type, its number of entries, and its keys
+    /// and values with `__type()` or their sizes with `__uint()`, as in libbpf:
+    ///
+    /// ```c
+    /// struct {
+    ///     __uint(type, BPF_MAP_TYPE_HASH);
+    ///     __uint(max_entries, 1024);
+    ///     __type(key, __u32);
+    ///     __type(value, struct stats);
+    /// } counters SEC(".maps");
+    /// ```
+    ///
+    /// Other members, such as `map_flags`, are ignored. Returns no definitions if there is no
+    /// `.maps` section.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the data is malformed, if a definition lacks one
+    /// of the type, size of keys and values, or number of entries of the map, or if the type of
+    /// the map is not supported by `maps::MapType`.
+    pub fn parse(btf: &[u8]) -> Result<Vec<MapDefinition>, Error> {
+        let btf = Btf::parse(btf)?;
+        let datasec = match btf.datasec(".maps")? {
+            Some(datasec) => datasec,
+            None          => return Ok(vec![]),
+        };
+
+        let mut definitions = vec![];
+        for (var, offset, _) in btf.datasec_vars(datasec)? {
+            let name = btf.name(var)?;
+            let def = btf.resolve(var.size_or_type)?;
+            if def.kind != BTF_KIND_STRUCT {
+                return Err(invalid("map definition is not a struct"));
+            }
+
+            let (mut map_type_id, mut key_size, mut value_size, mut max_entries) = (None, None, None, None);
+            for j in 0..def.vlen {
+                // Members: name, type and offset.
+                let member = def.data + j * 12;
+                let member_name = btf_str(btf.strings, read_u32(btf.types, member)?)?;
+                let member_type = read_u32(btf.types, member + 4)?;
+                match member_name.as_str() {
+                    "type"        => map_type_id = Some(btf.uint(member_type)?),
+                    "key_size"    => key_size = Some(btf.uint(member_type)? as usize),
+                    "value_size"  => value_size = Some(btf.uint(member_type)? as usize),
+                    "max_entries" => max_entries = Some(btf.uint(member_type)? as usize),
+                    "key"         => key_size = Some(btf.size(btf.pointee(member_type)?)?),
+                    "value"       => value_size = Some(btf.size(btf.pointee(member_type)?)?),
+                    _             => (),
+                }
+            }
+            match (map_type_id, key_size, value_size, max_entries) {
+                (Some(id), Some(key_size), Some(value_size), Some(max_entries)) => {
+                    definitions.push(MapDefinition {
+                        name,
+                        map_type: map_type(id)?,
+                        key_size,
+                        value_size,
+                        max_entries,
+                        offset,
+                    });
+                },
+                _ => return Err(invalid(&format!("incomplete definition of map {}", name))),
+            }
+        }
+        Ok(definitions)
+    }
+
+    /// Read the definitions of the maps of a `maps` section, from its contents: an array of
+    /// `struct bpf_map_def`, made of the type of the map, the size of its keys and values, its
+    /// number of entries and its flags, as 32-bit integers. The flags are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the size of the section is not a multiple of the
+    /// size of the definitions, or if the type of a map is not supported by `maps::MapType`.
+    pub fn parse_legacy(maps: &[u8]) -> Result<Vec<MapDefinition>, Error> {
+        const DEF_SIZE: usize = 20;
+        if !maps.len().is_multiple_of(DEF_SIZE) {
+            return Err(invalid("invalid size of maps section"));
+        }
+        let mut definitions = vec![];
+        for offset in (0..maps.len()).step_by(DEF_SIZE) {
+            definitions.push(MapDefinition {
+                name:        String::new(),
+                map_type:    map_type(read_u32(maps, offset)?)?,
+                key_size:    read_u32(maps, offset + 4)? as usize,
+                value_size:  read_u32(maps, offset + 8)? as usize,
+                max_entries: read_u32(maps, offset + 12)? as usize,
+                offset,
+            });
+        }
+        Ok(definitions)
+    }
+
+    /// Create a new, empty map from the definition.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the invalid definitions rejected by `maps::Map::new()`.
+    pub fn create(&self) -> Map {
+        Map::new(self.map_type, self.key_size, self.value_size, self.max_entries)
+    }
+}
+
+/// Relocate the `lddw` instructions of `prog` loading the address of a map, so that they load the
+/// identifier of the map instead (see `ebpf::BPF_PSEUDO_MAP_FD`). Each relocation is made of the
+/// offset of the instruction in the program, in bytes, and of the value of the symbol it refers
+/// to, that is the offset of the definition of the map in its section, as read from the
+/// relocation section of the program (`.rel` followed by the name of the section of the
+/// program) and the symbol table of the object file. The map of `maps[i]` is given identifier
+/// `i`: the maps are to be registered on the VM in this order, before any other map.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if a relocation does not apply to a `lddw` instruction
+/// of the program, or does not refer to the definition of one of `maps`.
+pub fn relocate_maps(prog: &mut [u8], relocations: &[(usize, usize)], maps: &[MapDefinition])
+    -> Result<(), Error> {
+    for &(insn_off, map_off) in relocations {
+        let id = match maps.iter().position(|def| def.offset == map_off) {
+            Some(id) => id as u32,
+            None     => return Err(invalid(&format!("no map defined at offset {}", map_off))),
+        };
+        let insn = match prog.get_mut(insn_off..insn_off + 2 * ebpf::INSN_SIZE) {
+            Some(insn) if insn_off.is_multiple_of(ebpf::INSN_SIZE) && insn[0] == ebpf::LD_DW_IMM => insn,
+            _ => return Err(invalid(&format!("no lddw instruction to relocate at offset {}", insn_off))),
+        };
+        insn[1] = (insn[1] & 0x0f) | ebpf::BPF_PSEUDO_MAP_FD << 4;
+        insn[4..8].copy_from_slice(&id.to_le_bytes());
+        insn[12..16].copy_from_slice(&[0; 4]);
+    }
+    Ok(())
+}
+
+/// A global variable of a program, see `Globals`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalVariable {
+    /// Name of the variable.
+    pub name:   String,
+    /// Offset of the variable in its section.
+    pub offset: usize,
+    /// Size of the variable, in bytes.
+    pub size:   usize,
+}
+
+/// The global variables of a program in one section of its object file: `.data` for the
+/// initialized variables, `.bss` for the ones initialized to zero, or `.rodata` for constants.
+///
+/// As with libbpf, the contents of the section are held in an array map with a single entry, to
+/// register on the VM, and that the program accesses with `lddw` instructions loading the address
+/// of its value (see `ebpf::BPF_PSEUDO_MAP_VALUE`), once relocated with `relocate_globals()`. The
+/// application reads and writes the variables by name, for example to set the configuration of a
+/// program before running it, or to read the counters it maintains. The variables of `.rodata`
+/// are not protected against writes by the program.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::btf::{self, Globals};
+///
+/// fn push_u32(v: &mut Vec<u8>, x: u32) {
+///     v.extend_from_slice(&x.to_le_bytes());
+/// }
+///
+/// // `.BTF` section describing `static __u32 counter;` at offset 0 of section `.bss`.
+/// let strings = b"\0counter\0.bss\0u32\0";
+/// let mut types = vec![];
+/// for x in &[
+///     14, 1 << 24, 4, 32,             // 1: int u32
+///     1, 14 << 24, 1, 0,              // 2: var counter
+///     9, 15 << 24 | 1, 4, 2, 0, 4,    // 3: section .bss
+/// ] {
+///     push_u32(&mut types, *x);
+/// }
+/// let mut btf = vec![0x9f, 0xeb, 0x01, 0x00];
+/// for x in &[24, 0, types.len() as u32, types.len() as u32, strings.len() as u32] {
+///     push_u32(&mut btf, *x);
+/// }
+/// btf.extend_from_slice(&types);
+/// btf.extend_from_slice(strings);
+///
+/// // Increments `counter`, and returns its new value.
+/// let mut prog = vec![
+///     0x18, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, counter (relocated)
+///     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0x61, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r1]
+///     0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add32 r0, 1
+///     0x63, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxw [r1], r0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let globals = Globals::new(&btf, ".bss", &[]).unwrap();
+/// btf::relocate_globals(&mut prog, &[(0, 0)], 0).unwrap();
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// vm.register_map(globals.map().clone());
+///
+/// globals.write("counter", 41u32).unwrap();
+/// assert_eq!(vm.prog_exec(), 42);
+/// vm.jit_compile();
+/// assert_eq!(vm.prog_exec_jit(), 43);
+/// assert_eq!(globals.read::<u32>("counter"), Some(43));
+/// ```
+pub struct Globals {
+    map:       Arc<Map>,
+    variables: Vec<GlobalVariable>,
+}
+
+impl Globals {
+    /// Create the map holding the variables of section `section`, described by the contents of
+    /// the `.BTF` section of the object file, and initialized with `data`, the contents of the
+    /// section, or with zeroes if `data` is empty, as for `.bss`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the data is malformed, if it does not describe
+    /// `section`, or if `data` is neither empty nor of the size of the section.
+    pub fn new(btf: &[u8], section: &str, data: &[u8]) -> Result<Globals, Error> {
+        let btf = Btf::parse(btf)?;
+        let datasec = match btf.datasec(section)? {
+            Some(datasec) => datasec,
+            None          => return Err(invalid(&format!("no section {} in BTF data", section))),
+        };
+        let size = datasec.size_or_type as usize;
+        if size == 0 || (!data.is_empty() && data.len() != size) {
+            return Err(invalid(&format!("invalid size of section {}", section)));
+        }
+
+        let mut variables = vec![];
+        for (var, offset, var_size) in btf.datasec_vars(datasec)? {
+            if offset + var_size > size {
+                return Err(invalid("global variable out of its section"));
+            }
+            variables.push(GlobalVariable { name: btf.name(var)?, offset, size: var_size });
+        }
+        let map = Map::new(MapType::Array, 4, size, 1);
+        if !data.is_empty() {
+            // Cannot fail, the entry of an array map exists.
+            let _ = map.update(&0u32.to_ne_bytes(), data, maps::BPF_ANY);
+        }
+        Ok(Globals { map: Arc::new(map), variables })
+    }
+
+    /// The map holding the variables, to register on the VMs running the program.
+    pub fn map(&self) -> &Arc<Map> {
+        &self.map
+    }
+
+    /// The variables of the section.
+    pub fn variables(&self) -> &[GlobalVariable] {
+        &self.variables
+    }
+
+    // The variable `name`, if it has the size of a `T`.
+    fn variable<T>(&self, name: &str) -> Option<&GlobalVariable> {
+        self.variables.iter().find(|var| var.name == name && var.size == mem::size_of::<T>())
+    }
+
+    /// Read variable `name`, in the byte order of the host. Returns `None` if there is no such
+    /// variable, or if its size is not the one of `T`.
+    pub fn read<T: Context>(&self, name: &str) -> Option<T> {
+        let var = self.variable::<T>(name)?;
+        let value = self.map.lookup(&0u32.to_ne_bytes())?;
+        // `T` can hold any sequence of bytes, see `Context`.
+        Some(unsafe { (value[var.offset..].as_ptr() as *const T).read_unaligned() })
+    }
+
+    /// Write `value` into variable `name`, in the byte order of the host. As for
+    /// `maps::Map::update()`, the write is not atomic with respect to the programs running
+    /// meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if there is no such variable, or if its size is
+    /// not the one of `T`.
+    pub fn write<T: Context>(&self, name: &str, value: T) -> Result<(), Error> {
+        let var = match self.variable::<T>(name) {
+            Some(var) => var,
+            None      => return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "no global variable {} of {} bytes", name, mem::size_of::<T>()))),
+        };
+        let key = 0u32.to_ne_bytes();
+        let mut contents = self.map.lookup(&key).unwrap_or_default();
+        unsafe {
+            (contents[var.offset..].as_mut_ptr() as *mut T).write_unaligned(value);
+        }
+        let _ = self.map.update(&key, &contents, maps::BPF_ANY);
+        Ok(())
+    }
+}
+
+/// Relocate the `lddw` instructions of `prog` loading the address of a global variable, so that
+/// they load its address in the value of the map of its section (see `Globals`), registered on
+/// the VM with identifier `map_id`. Each relocation is made of the offset of the instruction in
+/// the program, in bytes, and of the value of the symbol it refers to, as for `relocate_maps()`:
+/// the offset of the variable in its section, or 0 for the symbol of the section itself, the
+/// immediate of the instruction then holding the offset of the variable, as compilers emit for
+/// static variables.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if a relocation does not apply to a `lddw` instruction
+/// of the program.
+pub fn relocate_globals(prog: &mut [u8], relocations: &[(usize, usize)], map_id: u32)
+    -> Result<(), Error> {
+    for &(insn_off, var_off) in relocations {
+        let insn = match prog.get_mut(insn_off..insn_off + 2 * ebpf::INSN_SIZE) {
+            Some(insn) if insn_off.is_multiple_of(ebpf::INSN_SIZE) && insn[0] == ebpf::LD_DW_IMM => insn,
+            _ => return Err(invalid(&format!("no lddw instruction to relocate at offset {}", insn_off))),
+        };
+        let offset = read_u32(insn, 4)?.wrapping_add(var_off as u32);
+        insn[1] = (insn[1] & 0x0f) | ebpf::BPF_PSEUDO_MAP_VALUE << 4;
+        insn[4..8].copy_from_slice(&map_id.to_le_bytes());
+        insn[12..16].copy_from_slice(&offset.to_le_bytes());
+    }
+    Ok(())
+}
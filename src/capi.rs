@@ -0,0 +1,183 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides a C API for rbpf, so that applications written in other languages can
+//! embed the VM. It is only available with the `capi` feature.
+//!
+//! The API is declared in header `include/rbpf.h`. To build a shared library exporting it, run:
+//!
+//! ```text
+//! cargo rustc --release --features capi --crate-type cdylib
+//! ```
+//!
+//! The VMs of this API run programs on packet data, without a metadata buffer, in the same way as
+//! `EbpfVmRaw`: R1 points to the memory passed to `rbpf_vm_exec()`. Programs are interpreted.
+//!
+//! Functions report errors by returning `-1`, and the message of the error can be retrieved with
+//! `rbpf_vm_last_error()`.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ptr;
+use std::slice;
+
+use libc::{c_char, c_int};
+
+use helpers::{HelperHook, HookVerdict};
+use interpreter;
+use verifier;
+
+/// A helper function provided by the application, see `rbpf_vm_register_helper()`.
+pub type RbpfHelper = extern "C" fn(u64, u64, u64, u64, u64) -> u64;
+
+/// A virtual machine, created with `rbpf_vm_new()`. Its contents are opaque to C.
+pub struct RbpfVm {
+    prog:       Option<Vec<u8>>,
+    helpers:    CHelpers,
+    last_error: Option<CString>,
+}
+
+// The helpers of a VM. The interpreter only calls Rust functions: these helpers are called by a
+// hook instead, which returns their result as the result of the call, before the interpreter
+// looks for a Rust helper.
+struct CHelpers(HashMap<u32, RbpfHelper>);
+
+impl HelperHook for CHelpers {
+    fn before_call(&self, key: u32, args: &[u64; 5]) -> HookVerdict {
+        match self.0.get(&key) {
+            Some(helper) => HookVerdict::Skip(helper(args[0], args[1], args[2], args[3], args[4])),
+            None         => HookVerdict::Allow,
+        }
+    }
+}
+
+impl RbpfVm {
+    // Records `msg` as the message of the last error, and returns -1.
+    fn fail(&mut self, msg: &str) -> c_int {
+        // Messages do not contain null bytes, but play it safe.
+        self.last_error = Some(CString::new(msg.replace('\0', " ")).unwrap());
+        -1
+    }
+}
+
+/// Create a new VM, with no program loaded. Returns a pointer to free with `rbpf_vm_free()`.
+#[no_mangle]
+pub extern "C" fn rbpf_vm_new() -> *mut RbpfVm {
+    let vm = RbpfVm { prog: None, helpers: CHelpers(HashMap::new()), last_error: None };
+    Box::into_raw(Box::new(vm))
+}
+
+/// Free a VM created with `rbpf_vm_new()`. Does nothing if `vm` is null.
+///
+/// # Safety
+///
+/// `vm` must be null or a pointer returned by `rbpf_vm_new()`, not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rbpf_vm_free(vm: *mut RbpfVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Load the program of `len` bytes at `prog` into the VM, replacing the previous program. The
+/// program is copied, and passes through the simple verifier. Returns 0, or -1 on error: if `vm`
+/// or `prog` is null, or if the verifier rejects the program.
+///
+/// # Safety
+///
+/// `vm` must be null or a valid VM, and `prog` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rbpf_vm_load(vm: *mut RbpfVm, prog: *const u8, len: usize) -> c_int {
+    let vm = match vm.as_mut() {
+        Some(vm) => vm,
+        None     => return -1,
+    };
+    if prog.is_null() {
+        return vm.fail("Error: null program");
+    }
+    let prog = slice::from_raw_parts(prog, len).to_vec();
+    match verifier::try_check(&prog) {
+        Ok(())   => {
+            vm.last_error = None;
+            vm.prog = Some(prog);
+            0
+        },
+        Err(err) => vm.fail(&err.to_string()),
+    }
+}
+
+/// Register `helper` as the helper function with key `key`, to be called by programs with
+/// instruction `call key`. Replaces any helper previously registered with the same key. Returns
+/// 0, or -1 if `vm` or `helper` is null.
+///
+/// # Safety
+///
+/// `vm` must be null or a valid VM.
+#[no_mangle]
+pub unsafe extern "C" fn rbpf_vm_register_helper(vm: *mut RbpfVm, key: u32,
+                                                 helper: Option<RbpfHelper>) -> c_int {
+    match (vm.as_mut(), helper) {
+        (Some(vm), Some(helper)) => {
+            vm.last_error = None;
+            vm.helpers.0.insert(key, helper);
+            0
+        },
+        (Some(vm), None)         => vm.fail("Error: null helper function"),
+        (None, _)                => -1,
+    }
+}
+
+/// Run the program loaded in the VM on the `mem_len` bytes at `mem`, and store the value it
+/// returns at `ret`. Returns 0, or -1 on error: if `vm` or `ret` is null, if no program is loaded,
+/// or if the execution fails (out of bounds access, division by zero, unknown helper...).
+///
+/// # Safety
+///
+/// `vm` must be null or a valid VM, `mem` must be null or point to `mem_len` bytes readable and
+/// writable, for the duration of the call, and `ret` must be null or point to a writable value.
+#[no_mangle]
+pub unsafe extern "C" fn rbpf_vm_exec(vm: *mut RbpfVm, mem: *mut u8, mem_len: usize,
+                                      ret: *mut u64) -> c_int {
+    let vm = match vm.as_mut() {
+        Some(vm) => vm,
+        None     => return -1,
+    };
+    if ret.is_null() {
+        return vm.fail("Error: null pointer for the return value");
+    }
+    let mem: &[u8] = match mem.is_null() || mem_len == 0 {
+        true  => &[],
+        false => slice::from_raw_parts(mem, mem_len),
+    };
+    let prog = match vm.prog {
+        Some(ref prog) => prog,
+        None           => return vm.fail("Error: no program loaded"),
+    };
+    let options = interpreter::Options { helper_hook: Some(&vm.helpers), ..Default::default() };
+    match interpreter::try_execute_program(prog, mem, &[], &HashMap::new(), options) {
+        Ok(value) => {
+            vm.last_error = None;
+            *ret = value;
+            0
+        },
+        Err(err)  => vm.fail(&err.to_string()),
+    }
+}
+
+/// Return the message of the last error of a function called on the VM, or null if the last call
+/// succeeded (or if `vm` is null). The message remains valid until the next call on the VM.
+///
+/// # Safety
+///
+/// `vm` must be null or a valid VM.
+#[no_mangle]
+pub unsafe extern "C" fn rbpf_vm_last_error(vm: *const RbpfVm) -> *const c_char {
+    match vm.as_ref().and_then(|vm| vm.last_error.as_ref()) {
+        Some(msg) => msg.as_ptr(),
+        None      => ptr::null(),
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module feeds packets from a source, such as a capture file or a network interface, to an
+//! eBPF program, so that rbpf can be used as a filtering engine in userspace.
+//!
+//! Sources of packets implement the `PacketSource` trait. Two of them are available behind
+//! features:
+//!
+//! * `PcapReader`, with feature `pcap`, reads packets from a file in pcap format.
+//! * `AfPacketSource`, with feature `af_packet` (Linux only), receives the packets of a network
+//!   interface from a raw socket. This requires the `CAP_NET_RAW` capability.
+//!
+//! Any iterator over packet data (`Vec<u8>`) is also a source. `run()` then runs a program on
+//! each packet, and passes the value it returns, along with the packet, to a handler deciding
+//! what to do with it: for instance, forward the packet if the program returned a non-zero value,
+//! or drop it otherwise.
+
+use std::io;
+use std::time::Duration;
+
+#[cfg(feature = "pcap")]
+use std::fs::File;
+#[cfg(feature = "pcap")]
+use std::io::{BufReader, Read};
+#[cfg(feature = "pcap")]
+use std::path::Path;
+
+/// A packet read from a `PacketSource`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// The packet data, possibly truncated by the source.
+    pub data:      Vec<u8>,
+    /// The length of the packet before truncation.
+    pub orig_len:  usize,
+    /// When the packet was captured, as a duration since the Unix epoch, if known.
+    pub timestamp: Option<Duration>,
+}
+
+impl Packet {
+    /// Create a packet with data `data`, not truncated, and with no timestamp.
+    pub fn new(data: Vec<u8>) -> Packet {
+        Packet { orig_len: data.len(), data, timestamp: None }
+    }
+}
+
+/// A source of packets.
+pub trait PacketSource {
+    /// Return the next packet, or `None` once the source is exhausted. Sources reading from a
+    /// network interface block until a packet is received, and are never exhausted.
+    fn next_packet(&mut self) -> io::Result<Option<Packet>>;
+}
+
+impl<I: Iterator<Item = Vec<u8>>> PacketSource for I {
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        Ok(self.next().map(Packet::new))
+    }
+}
+
+/// Run a program over all packets from `source`, until it is exhausted. For each packet, `exec`
+/// runs the program on the packet data, which it can modify, and returns the value returned by
+/// the program; `handler` then receives this value and the packet, possibly modified. Returns the
+/// number of packets processed, or the first error from the source.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::capture;
+///
+/// // Accepts the packets whose first byte is 0x2a.
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+///     0x55, 0x02, 0x01, 0x00, 0x2a, 0x00, 0x00, 0x00, // jne r2, 0x2a, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let vm = rbpf::EbpfVmRaw::new(&prog);
+///
+/// let mut source = vec![vec![0x2a, 0x01], vec![0x00, 0x02], vec![0x2a, 0x03]].into_iter();
+/// let mut accepted = vec![];
+/// let count = capture::run(&mut source, |data| vm.prog_exec(data), |ret, packet| {
+///     if ret != 0 {
+///         accepted.push(packet.data[1]);
+///     }
+/// }).unwrap();
+///
+/// assert_eq!(count, 3);
+/// assert_eq!(accepted, vec![0x01, 0x03]);
+/// ```
+pub fn run<S, E, H>(source: &mut S, mut exec: E, mut handler: H) -> io::Result<usize>
+    where S: PacketSource + ?Sized, E: FnMut(&mut Vec<u8>) -> u64, H: FnMut(u64, &Packet) {
+    let mut count = 0;
+    while let Some(mut packet) = source.next_packet()? {
+        let ret = exec(&mut packet.data);
+        handler(ret, &packet);
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Reading pcap files
+
+/// Reads packets from a file in pcap format (not pcapng), in either byte order, with timestamps
+/// in microseconds or nanoseconds. Available with feature `pcap`.
+///
+/// See <https://wiki.wireshark.org/Development/LibpcapFileFormat>.
+#[cfg(feature = "pcap")]
+pub struct PcapReader<R: Read> {
+    reader:     R,
+    big_endian: bool,
+    nanos:      bool,
+    snap_len:   u32,
+    link_type:  u32,
+}
+
+#[cfg(feature = "pcap")]
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+#[cfg(feature = "pcap")]
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+// Records larger than this are rejected, whatever the snapshot length of the file.
+#[cfg(feature = "pcap")]
+const PCAP_MAX_RECORD: u32 = 0x40_0000;
+
+#[cfg(feature = "pcap")]
+impl PcapReader<BufReader<File>> {
+    /// Open the pcap file at `path`, and read its header.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PcapReader<BufReader<File>>> {
+        PcapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(feature = "pcap")]
+impl<R: Read> PcapReader<R> {
+
+    /// Read the header of a pcap file from `reader`, and return a reader for its packets.
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (big_endian, nanos) = match (magic, magic.swap_bytes()) {
+            (PCAP_MAGIC, _)       => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC)       => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a pcap file")),
+        };
+        let mut pcap = PcapReader { reader, big_endian, nanos, snap_len: 0, link_type: 0 };
+        pcap.snap_len = pcap.u32_at(&header, 16);
+        pcap.link_type = pcap.u32_at(&header, 20);
+        Ok(pcap)
+    }
+
+    /// The link type of the packets, for example 1 for Ethernet. See
+    /// <https://www.tcpdump.org/linktypes.html>.
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    /// The maximum length of the packets in the file.
+    pub fn snap_len(&self) -> u32 {
+        self.snap_len
+    }
+
+    fn u32_at(&self, bytes: &[u8], offset: usize) -> u32 {
+        let word = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        match self.big_endian {
+            true  => u32::from_be_bytes(word),
+            false => u32::from_le_bytes(word),
+        }
+    }
+}
+
+#[cfg(feature = "pcap")]
+impl<R: Read> PacketSource for PcapReader<R> {
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        let mut header = [0u8; 16];
+        // The file can only end between two records.
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let secs = self.u32_at(&header, 0) as u64;
+        let frac = self.u32_at(&header, 4);
+        let incl_len = self.u32_at(&header, 8);
+        let orig_len = self.u32_at(&header, 12);
+        if incl_len > PCAP_MAX_RECORD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("pcap record too large: {} bytes", incl_len)));
+        }
+        let mut data = vec![0u8; incl_len as usize];
+        self.reader.read_exact(&mut data)?;
+        let timestamp = match self.nanos {
+            true  => Duration::new(secs, frac),
+            false => Duration::new(secs, frac.saturating_mul(1000)),
+        };
+        Ok(Some(Packet { data, orig_len: orig_len as usize, timestamp: Some(timestamp) }))
+    }
+}
+
+// Receiving packets from a network interface
+
+/// Receives all the packets (of all protocols) of a network interface, from a raw `AF_PACKET`
+/// socket. Available with feature `af_packet`, on Linux. Opening the socket requires the
+/// `CAP_NET_RAW` capability.
+///
+/// The data of a packet starts with its link-layer header. Packets longer than the maximum length
+/// set when opening the source are truncated.
+#[cfg(all(feature = "af_packet", target_os = "linux"))]
+pub struct AfPacketSource {
+    fd:      libc::c_int,
+    buf_len: usize,
+}
+
+#[cfg(all(feature = "af_packet", target_os = "linux"))]
+impl AfPacketSource {
+
+    /// Open a raw socket receiving the packets of interface `interface`, truncated to 65535
+    /// bytes.
+    pub fn open(interface: &str) -> io::Result<AfPacketSource> {
+        AfPacketSource::with_max_len(interface, 65535)
+    }
+
+    /// Open a raw socket receiving the packets of interface `interface`, truncated to `max_len`
+    /// bytes.
+    pub fn with_max_len(interface: &str, max_len: usize) -> io::Result<AfPacketSource> {
+        use std::ffi::CString;
+        use std::mem;
+
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        unsafe {
+            let ifindex = libc::if_nametoindex(name.as_ptr());
+            if ifindex == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as libc::c_int);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Close the socket on error.
+            let source = AfPacketSource { fd, buf_len: max_len };
+            let mut addr: libc::sockaddr_ll = mem::zeroed();
+            addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+            addr.sll_protocol = protocol;
+            addr.sll_ifindex = ifindex as libc::c_int;
+            let res = libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                                 mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t);
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(source)
+        }
+    }
+}
+
+#[cfg(all(feature = "af_packet", target_os = "linux"))]
+impl PacketSource for AfPacketSource {
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        use std::time::SystemTime;
+
+        let mut data = vec![0u8; self.buf_len];
+        // With MSG_TRUNC, the length of the packet before truncation is returned.
+        let len = unsafe {
+            libc::recv(self.fd, data.as_mut_ptr() as *mut libc::c_void, data.len(), libc::MSG_TRUNC)
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let orig_len = len as usize;
+        data.truncate(orig_len.min(self.buf_len));
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok();
+        Ok(Some(Packet { data, orig_len, timestamp }))
+    }
+}
+
+#[cfg(all(feature = "af_packet", target_os = "linux"))]
+impl Drop for AfPacketSource {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module runs corpora of test vectors against the interpreter and the JIT-compiler, so that
+//! users can maintain their own conformance suites and check them against new releases of rbpf.
+//!
+//! A test vector is a text file with extension `.data`, in the format of the test files of uBPF.
+//! The file is made of sections, each starting with a line `-- <name>`; lines starting with `#`
+//! are comments, and blank lines are ignored:
+//!
+//! * `-- asm`: the program, in the syntax of `assembler::assemble()`.
+//! * `-- raw`: the program, as bytes in hexadecimal separated by whitespace. Exactly one of `asm`
+//!   and `raw` must be present.
+//! * `-- mem`: the packet data the program runs on, as bytes in hexadecimal separated by
+//!   whitespace, as with `EbpfVmRaw`: R1 points to the packet data. Optional, the packet data is
+//!   empty by default.
+//! * `-- result`: the value the program is expected to return, in decimal or, with prefix `0x`,
+//!   in hexadecimal.
+//! * `-- error`: the error the program is expected to fail with, either when verified or when run:
+//!   the message of the error (see `error::EbpfError`) must contain this text. Exactly one of
+//!   `result` and `error` must be present.
+//!
+//! Programs expected to return a value are run with the interpreter and with the JIT-compiler,
+//! the latter catching faults (see `EbpfVmMbuff::set_catch_faults()`). Programs expected to fail
+//! are only run with the interpreter, since JIT-compiled programs do not check their accesses to
+//! memory.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::conformance::{Runner, TestVector};
+//!
+//! // Loads the second byte of the packet.
+//! let vector = TestVector::parse("ldxb", "
+//! -- asm
+//! ldxb r0, [r1+1]
+//! exit
+//! -- mem
+//! aa bb cc
+//! -- result
+//! 0xbb
+//! ").unwrap();
+//!
+//! let report = Runner::new().run(&[vector]);
+//! assert!(report.is_ok());
+//! assert_eq!(report.passed, 1);
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use assembler;
+use ebpf;
+use verifier;
+use EbpfVmRaw;
+
+/// A test vector: a program, the packet data it runs on, and the outcome expected. See the module
+/// documentation for the format of the files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    /// The name of the vector, the name of its file without the extension when loaded with
+    /// `load_dir()`.
+    pub name:     String,
+    /// The bytecode of the program.
+    pub prog:     Vec<u8>,
+    /// The packet data the program runs on.
+    pub mem:      Vec<u8>,
+    /// The value the program is expected to return, or the text expected in the message of its
+    /// error.
+    pub expected: Result<u64, String>,
+}
+
+fn invalid(name: &str, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{}: {}", name, msg))
+}
+
+fn parse_bytes(name: &str, text: &str) -> Result<Vec<u8>, Error> {
+    text.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| invalid(name, &format!("invalid byte {:?}", byte))))
+        .collect()
+}
+
+impl TestVector {
+
+    /// Parse a test vector named `name` from `text`, in the format described in the module
+    /// documentation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the text does not follow the format, or if the
+    /// program does not assemble.
+    pub fn parse(name: &str, text: &str) -> Result<TestVector, Error> {
+        let mut sections: HashMap<&str, String> = HashMap::new();
+        let mut current = None;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix("--") {
+                let section = section.trim();
+                if !["asm", "raw", "mem", "result", "error"].contains(&section) {
+                    return Err(invalid(name, &format!("unknown section {:?}", section)));
+                }
+                if sections.insert(section, String::new()).is_some() {
+                    return Err(invalid(name, &format!("duplicate section {:?}", section)));
+                }
+                current = Some(section);
+                continue;
+            }
+            match current {
+                Some(section) => {
+                    let content = sections.get_mut(section).unwrap();
+                    content.push_str(line);
+                    content.push('\n');
+                },
+                None => return Err(invalid(name, "content before the first section")),
+            }
+        }
+
+        let prog = match (sections.get("asm"), sections.get("raw")) {
+            (Some(asm), None) => assembler::assemble(asm).map_err(|err| invalid(name, &err))?,
+            (None, Some(raw)) => parse_bytes(name, raw)?,
+            _ => return Err(invalid(name, "expected exactly one of sections \"asm\" and \"raw\"")),
+        };
+        let mem = match sections.get("mem") {
+            Some(mem) => parse_bytes(name, mem)?,
+            None      => vec![],
+        };
+        let expected = match (sections.get("result"), sections.get("error")) {
+            (Some(result), None) => {
+                let result = result.trim();
+                let value = match result.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None      => result.parse(),
+                };
+                Ok(value.map_err(|_| invalid(name, &format!("invalid result {:?}", result)))?)
+            },
+            (None, Some(error)) => Err(error.trim().to_string()),
+            _ => return Err(invalid(name, "expected exactly one of sections \"result\" and \"error\"")),
+        };
+        Ok(TestVector { name: name.to_string(), prog, mem, expected })
+    }
+}
+
+/// Load the test vectors of the files with extension `.data` in directory `dir`, sorted by name.
+///
+/// # Errors
+///
+/// Returns the errors of the file system, and those of `TestVector::parse()`.
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<TestVector>, Error> {
+    let mut vectors = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "data") {
+            continue;
+        }
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        vectors.push(TestVector::parse(&name, &fs::read_to_string(&path)?)?);
+    }
+    vectors.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(vectors)
+}
+
+/// The engine running a test vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// The interpreter.
+    Interpreter,
+    /// The JIT-compiler.
+    Jit,
+}
+
+/// A test vector whose outcome differs from the expected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// The name of the vector.
+    pub name:     String,
+    /// The engine that ran the vector.
+    pub engine:   Engine,
+    /// The outcome expected, as in `TestVector::expected`.
+    pub expected: Result<u64, String>,
+    /// The value returned by the program, or the message of its error.
+    pub actual:   Result<u64, String>,
+}
+
+/// The results of the test vectors run by a `Runner`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of vectors that passed with all the engines that ran them.
+    pub passed:   usize,
+    /// The failures, in the order of the vectors.
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// Whether all the vectors passed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs test vectors with the interpreter and the JIT-compiler, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    helpers: HashMap<u32, ebpf::Helper>,
+    jit:     bool,
+}
+
+impl Default for Runner {
+    fn default() -> Runner {
+        Runner { helpers: HashMap::new(), jit: true }
+    }
+}
+
+impl Runner {
+
+    /// Create a runner, running the vectors with the interpreter and the JIT-compiler, with no
+    /// helpers.
+    pub fn new() -> Runner {
+        Runner::default()
+    }
+
+    /// Register a helper for the programs of the vectors, see `EbpfVmMbuff::register_helper()`.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Enable or disable the runs with the JIT-compiler. They are enabled by default.
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit = enabled;
+    }
+
+    // The outcome of `vector` with `engine`, the messages of errors as expected by the vectors.
+    fn outcome(&self, vector: &TestVector, engine: Engine) -> Result<u64, String> {
+        verifier::try_check(&vector.prog).map_err(|err| err.to_string())?;
+        let mut mem = vector.mem.clone();
+        let mut vm = EbpfVmRaw::new(&vector.prog);
+        for (&key, &function) in self.helpers.iter() {
+            vm.register_helper(key, function);
+        }
+        match engine {
+            Engine::Interpreter => {
+                // The interpreter panics on the instructions it does not implement.
+                match panic::catch_unwind(AssertUnwindSafe(|| vm.try_prog_exec(&mut mem))) {
+                    Ok(res)   => res.map_err(|err| err.to_string()),
+                    Err(cause) => Err(cause.downcast_ref::<&str>().map(|msg| msg.to_string())
+                                      .or_else(|| cause.downcast_ref::<String>().cloned())
+                                      .unwrap_or_else(|| "interpreter panicked".to_string())),
+                }
+            },
+            Engine::Jit => {
+                vm.set_catch_faults(true);
+                vm.try_jit_compile().map_err(|err| err.to_string())?;
+                vm.try_prog_exec_jit(&mut mem).map_err(|err| err.to_string())
+            },
+        }
+    }
+
+    // The failures of `vector`, with each engine running it.
+    fn run_vector(&self, vector: &TestVector) -> Vec<Failure> {
+        let engines: &[Engine] = match (&vector.expected, self.jit) {
+            (Ok(_), true) => &[Engine::Interpreter, Engine::Jit],
+            _             => &[Engine::Interpreter],
+        };
+        engines.iter().filter_map(|&engine| {
+            let actual = self.outcome(vector, engine);
+            let passed = match (&vector.expected, &actual) {
+                (Ok(expected), Ok(actual))   => expected == actual,
+                (Err(expected), Err(actual)) => actual.contains(expected.as_str()),
+                _                            => false,
+            };
+            match passed {
+                true  => None,
+                false => Some(Failure { name: vector.name.clone(), engine, expected: vector.expected.clone(), actual }),
+            }
+        }).collect()
+    }
+
+    /// Run `vectors`, and report the failures.
+    pub fn run(&self, vectors: &[TestVector]) -> Report {
+        let mut report = Report::default();
+        for vector in vectors {
+            let failures = self.run_vector(vector);
+            if failures.is_empty() {
+                report.passed += 1;
+            }
+            report.failures.extend(failures);
+        }
+        report
+    }
+
+    /// Load the test vectors of directory `dir` with `load_dir()`, and run them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `load_dir()`.
+    pub fn run_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Report, Error> {
+        Ok(self.run(&load_dir(dir)?))
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module estimates the cost of the executions of programs, in CPU cycles, so that
+//! candidate implementations of a filter can be compared without running them on real hardware.
+//!
+//! A `CostModel` gives an estimate of the cycles taken by each instruction. `DefaultCostModel`
+//! assigns a cost to each class of instructions (`InsnClass`), which can be changed, as well as the
+//! cost of calls to given helpers. A `CostTracer` is a tracer (see module `trace`) adding up the
+//! costs of the instructions executed by the programs it traces, in a `CostReport`.
+//!
+//! The estimates do not model the pipeline or the caches of the processor: they are meant to
+//! compare programs with each other, rather than to predict their actual run time.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::cost::CostTracer;
+//!
+//! // Both return the first byte of the packet multiplied by 4.
+//! let mul = vec![
+//!     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+//!     0x27, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mul64 r0, 4
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//! let shift = vec![
+//!     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+//!     0x67, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // lsh64 r0, 2
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! let cost = |prog: &Vec<u8>| {
+//!     let mut packets: Vec<Vec<u8>> = (0..16).map(|b| vec![b]).collect();
+//!     let vm = rbpf::EbpfVmRaw::new(prog);
+//!     let mut tracer = CostTracer::new();
+//!     for packet in packets.iter_mut() {
+//!         vm.prog_exec_trace(packet, &mut tracer);
+//!     }
+//!     assert_eq!(tracer.report().runs, 16);
+//!     tracer.report().cycles_per_run()
+//! };
+//! assert!(cost(&shift) < cost(&mul));
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+
+use ebpf;
+use trace::{TraceEntry, Tracer};
+
+/// A class of instructions, with a common cost in `DefaultCostModel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InsnClass {
+    /// Arithmetic and logic operations, other than multiplications and divisions, and byte
+    /// swaps. Default cost: 1 cycle.
+    Alu,
+    /// Multiplications. Default cost: 3 cycles.
+    Mul,
+    /// Divisions and modulo operations. Default cost: 25 cycles.
+    Div,
+    /// Loads from memory, including the legacy packet loads. Default cost: 4 cycles.
+    Load,
+    /// Loads of 64-bit immediates (`lddw`). Default cost: 1 cycle.
+    LoadImm,
+    /// Stores to memory. Default cost: 1 cycle.
+    Store,
+    /// Atomic operations on memory. Default cost: 20 cycles.
+    Atomic,
+    /// Jumps, conditional or not. Default cost: 1 cycle.
+    Jump,
+    /// Calls to helpers and tail calls. Default cost: 10 cycles.
+    Call,
+    /// Exits. Default cost: 1 cycle.
+    Exit,
+}
+
+impl InsnClass {
+    /// The class of instruction `insn`.
+    pub fn of(insn: &ebpf::Insn) -> InsnClass {
+        match insn.opc & ebpf::BPF_CLS_MASK {
+            ebpf::BPF_ALU | ebpf::BPF_ALU64 => match insn.opc & ebpf::BPF_ALU_OP_MASK {
+                ebpf::BPF_MUL                 => InsnClass::Mul,
+                ebpf::BPF_DIV | ebpf::BPF_MOD => InsnClass::Div,
+                _                             => InsnClass::Alu,
+            },
+            ebpf::BPF_LD if insn.opc == ebpf::LD_DW_IMM => InsnClass::LoadImm,
+            ebpf::BPF_LD | ebpf::BPF_LDX => InsnClass::Load,
+            ebpf::BPF_STX if insn.opc & 0xe0 == ebpf::BPF_XADD => InsnClass::Atomic,
+            ebpf::BPF_ST | ebpf::BPF_STX => InsnClass::Store,
+            _ => match insn.opc {
+                ebpf::CALL => InsnClass::Call,
+                ebpf::EXIT => InsnClass::Exit,
+                _          => InsnClass::Jump,
+            },
+        }
+    }
+}
+
+/// A model of the cost of instructions, in CPU cycles.
+pub trait CostModel {
+    /// The estimated number of cycles taken by the execution of `insn`.
+    fn cycles(&self, insn: &ebpf::Insn) -> u64;
+}
+
+/// The default cost model: a cost for each class of instructions, and for the calls to given
+/// helpers. See `InsnClass` for the default costs.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::cost::{CostModel, DefaultCostModel, InsnClass};
+/// use rbpf::ebpf;
+///
+/// let mut model = DefaultCostModel::new();
+/// model.set_class_cost(InsnClass::Div, 40);
+/// model.set_helper_cost(1, 50);
+///
+/// let div = ebpf::Insn { opc: ebpf::DIV64_IMM, dst: 0, src: 0, off: 0, imm: 3 };
+/// let call = ebpf::Insn { opc: ebpf::CALL, dst: 0, src: 0, off: 0, imm: 1 };
+/// assert_eq!(model.cycles(&div), 40);
+/// assert_eq!(model.cycles(&call), 50);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultCostModel {
+    classes: HashMap<InsnClass, u64>,
+    helpers: HashMap<u32, u64>,
+}
+
+impl Default for DefaultCostModel {
+    fn default() -> DefaultCostModel {
+        let classes = [
+            (InsnClass::Alu, 1), (InsnClass::Mul, 3), (InsnClass::Div, 25), (InsnClass::Load, 4),
+            (InsnClass::LoadImm, 1), (InsnClass::Store, 1), (InsnClass::Atomic, 20),
+            (InsnClass::Jump, 1), (InsnClass::Call, 10), (InsnClass::Exit, 1),
+        ];
+        DefaultCostModel { classes: classes.iter().cloned().collect(), helpers: HashMap::new() }
+    }
+}
+
+impl DefaultCostModel {
+
+    /// Create a model with the default costs.
+    pub fn new() -> DefaultCostModel {
+        DefaultCostModel::default()
+    }
+
+    /// The cost of the instructions of class `class`.
+    pub fn class_cost(&self, class: InsnClass) -> u64 {
+        self.classes[&class]
+    }
+
+    /// Set the cost of the instructions of class `class`.
+    pub fn set_class_cost(&mut self, class: InsnClass, cycles: u64) {
+        self.classes.insert(class, cycles);
+    }
+
+    /// Set the cost of a call to the helper of key `key`, including the execution of the helper,
+    /// instead of the cost of class `InsnClass::Call`.
+    pub fn set_helper_cost(&mut self, key: u32, cycles: u64) {
+        self.helpers.insert(key, cycles);
+    }
+}
+
+impl CostModel for DefaultCostModel {
+    fn cycles(&self, insn: &ebpf::Insn) -> u64 {
+        if insn.opc == ebpf::CALL && insn.src == 0 {
+            if let Some(&cycles) = self.helpers.get(&(insn.imm as u32)) {
+                return cycles;
+            }
+        }
+        self.class_cost(InsnClass::of(insn))
+    }
+}
+
+/// The estimated cost of the executions traced by a `CostTracer`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CostReport {
+    /// The number of executions of programs, counted when they exit.
+    pub runs:     u64,
+    /// The number of instructions executed.
+    pub insns:    u64,
+    /// The estimated number of cycles taken by the instructions executed.
+    pub cycles:   u64,
+    /// The cycles taken by the instructions of each class.
+    pub by_class: BTreeMap<InsnClass, u64>,
+    /// The cycles taken by the instructions at each index in the program, to find the hot spots
+    /// of the program. After a tail call, the indexes are those of the program jumped to.
+    pub by_insn:  Vec<u64>,
+}
+
+impl CostReport {
+    /// The average estimated number of cycles taken by an execution, or 0 if there was none.
+    pub fn cycles_per_run(&self) -> f64 {
+        match self.runs {
+            0    => 0.0,
+            runs => self.cycles as f64 / runs as f64,
+        }
+    }
+}
+
+/// A tracer estimating the cost of the executions it traces with a cost model, see the module
+/// documentation.
+#[derive(Debug, Clone, Default)]
+pub struct CostTracer<M: CostModel = DefaultCostModel> {
+    model:  M,
+    report: CostReport,
+}
+
+impl CostTracer<DefaultCostModel> {
+
+    /// Create a tracer with the default cost model.
+    pub fn new() -> CostTracer<DefaultCostModel> {
+        CostTracer::with_model(DefaultCostModel::new())
+    }
+}
+
+impl<M: CostModel> CostTracer<M> {
+
+    /// Create a tracer with cost model `model`.
+    pub fn with_model(model: M) -> CostTracer<M> {
+        CostTracer { model, report: CostReport::default() }
+    }
+
+    /// The cost model of the tracer.
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// The estimated cost of the executions traced so far.
+    pub fn report(&self) -> &CostReport {
+        &self.report
+    }
+
+    /// Forget the executions traced so far.
+    pub fn reset(&mut self) {
+        self.report = CostReport::default();
+    }
+}
+
+impl<M: CostModel> Tracer for CostTracer<M> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        let insn = &entry.insn;
+        let cycles = self.model.cycles(insn);
+        self.report.insns += 1;
+        self.report.cycles += cycles;
+        *self.report.by_class.entry(InsnClass::of(insn)).or_insert(0) += cycles;
+        if self.report.by_insn.len() <= entry.insn_ptr {
+            self.report.by_insn.resize(entry.insn_ptr + 1, 0);
+        }
+        self.report.by_insn[entry.insn_ptr] += cycles;
+        if insn.opc == ebpf::EXIT {
+            self.report.runs += 1;
+        }
+    }
+}
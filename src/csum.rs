@@ -0,0 +1,119 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module computes Internet checksums (RFC 1071): the ones' complement sums used by the
+//! headers of IPv4, TCP, UDP or ICMP packets. It is used by the interpreter to run helper
+//! `bpf_csum_diff()`, see `helpers::BPF_CSUM_DIFF_IDX`, and lets the application check the
+//! checksums of the packets rewritten by a program, for example to test NAT programs.
+//!
+//! As in the Linux kernel, data is summed as 16-bit words in the byte order of the host: the
+//! folded checksum returned by `Checksum::finish()` is stored as is in the packet, with
+//! `u16::to_ne_bytes()` or a 16-bit store from the program. Sums are kept unfolded on 32 bits,
+//! like the `__wsum` values of the kernel; two unfolded sums may differ and still fold to the same
+//! checksum, so only folded checksums should be compared with the ones computed by the kernel.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::csum::{self, Checksum};
+//!
+//! // An IPv4 header, with its checksum at offset 10.
+//! let mut header = [
+//!     0x45, 0x00, 0x00, 0x54, 0x00, 0x00, 0x40, 0x00, 0x40, 0x01, 0x00, 0x00,
+//!     0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+//! ];
+//! let check = Checksum::new(0).add(&header).finish();
+//! header[10..12].copy_from_slice(&check.to_ne_bytes());
+//! assert!(Checksum::new(0).add(&header).finish() == 0);
+//!
+//! // Rewrite the destination address, and update the checksum from the difference.
+//! let old_check = u16::from_ne_bytes([header[10], header[11]]);
+//! let diff = csum::csum_diff(&header[16..20], &[10, 0, 0, 1], !old_check as u32);
+//! header[16..20].copy_from_slice(&[10, 0, 0, 1]);
+//! header[10..12].copy_from_slice(&csum::fold(diff).to_ne_bytes());
+//! assert!(Checksum::new(0).add(&header).finish() == 0);
+//! ```
+
+/// An incremental Internet checksum: the ones' complement sum of the data added so far.
+///
+/// Data can be added in chunks of any length: a chunk following a chunk of odd length is summed
+/// as if the two were contiguous.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksum {
+    sum: u32,
+    odd: bool,
+}
+
+impl Checksum {
+
+    /// Create a new checksum, starting from the unfolded sum `seed`: 0 for a new checksum, or
+    /// the sum of a previous computation, for example a pseudo-header.
+    pub fn new(seed: u32) -> Checksum {
+        Checksum { sum: seed, odd: false }
+    }
+
+    /// Add `data` to the sum.
+    pub fn add(&mut self, data: &[u8]) -> &mut Checksum {
+        let mut sum = 0u64;
+        for word in data.chunks(2) {
+            sum += u16::from_ne_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u64;
+        }
+        let mut sum = fold64(sum);
+        if self.odd {
+            // Shift the bytes of the chunk by one position: in ones' complement arithmetic, this
+            // amounts to a rotation of the sum.
+            sum = sum.rotate_right(8);
+        }
+        self.sum = add32(self.sum, sum);
+        self.odd ^= data.len() % 2 == 1;
+        self
+    }
+
+    /// Subtract `data` from the sum, by adding its ones' complement.
+    pub fn sub(&mut self, data: &[u8]) -> &mut Checksum {
+        let complement: Vec<u8> = data.iter().map(|b| !b).collect();
+        self.add(&complement)
+    }
+
+    /// Return the unfolded 32-bit sum, as returned by `bpf_csum_diff()`.
+    pub fn sum(&self) -> u32 {
+        self.sum
+    }
+
+    /// Return the folded checksum: the ones' complement of the 16-bit sum. This is the value to
+    /// store in the checksum field of a header, or 0 if the data summed includes a valid checksum.
+    pub fn finish(&self) -> u16 {
+        fold(self.sum)
+    }
+}
+
+// Adds two 32-bit ones' complement sums.
+fn add32(a: u32, b: u32) -> u32 {
+    let (sum, carry) = a.overflowing_add(b);
+    sum + carry as u32
+}
+
+// Folds a 64-bit sum of 16-bit words into a 32-bit ones' complement sum.
+fn fold64(sum: u64) -> u32 {
+    add32(sum as u32, (sum >> 32) as u32)
+}
+
+/// Fold the unfolded 32-bit sum `sum` into a 16-bit checksum, and return its ones' complement, as
+/// function `csum_fold()` of the kernel.
+pub fn fold(sum: u32) -> u16 {
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+/// Compute the difference between the bytes `from` and `to`, added to the unfolded sum `seed`, as
+/// helper `bpf_csum_diff()`: the ones' complement sum of `to`, minus the one of `from`. Passing the
+/// ones' complement of the checksum of a header as `seed`, and folding the result with `fold()`,
+/// gives the checksum of the header once `from` is replaced by `to`.
+pub fn csum_diff(from: &[u8], to: &[u8], seed: u32) -> u32 {
+    Checksum::new(seed).sub(from).add(to).sum()
+}
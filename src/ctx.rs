@@ -0,0 +1,323 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module rewrites the accesses of a program to its context, like the Linux kernel does
+//! when loading programs (the "xlated" program it produces).
+//!
+//! Programs compiled for the kernel access the fields of the context structure they receive,
+//! such as `struct __sk_buff`, with the offsets and sizes of the structure exposed to user space.
+//! The kernel converts these accesses into accesses to its internal structures. In rbpf, the
+//! context is the metadata buffer, whose layout is chosen by the user: `convert_ctx_access()`
+//! rewrites the loads and stores to the context so that they match the layout of the buffer,
+//! given as a `CtxLayout`.
+//!
+//! It also defines the `Context` trait, implemented by the types that programs run by an
+//! `EbpfVmCtx` can receive as their context, and the `CtxBytes` trait, to write the fields of
+//! metadata buffers built by hand without casting raw pointers.
+//!
+//! # Examples
+//!
+//! The program below was compiled with clang from the program in the documentation of
+//! `EbpfVmFixedMbuff`. It reads the 32-bit fields `data` and `data_end` of `struct __sk_buff`,
+//! at offsets 0x4c and 0x50, and the VM stores 64-bit pointers at offsets 0x40 and 0x50 of the
+//! metadata buffer instead.
+//!
+//! ```
+//! use rbpf::ctx::{self, CtxLayout};
+//!
+//! let prog = vec![
+//!     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+//!     0x61, 0x12, 0x4c, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1+0x4c] (skb->data)
+//!     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+//!     0x61, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r1, [r1+0x50] (skb->data_end)
+//!     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 1 instruction
+//!     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! let layout = CtxLayout::new()
+//!     .field(0x4c, 4, 0x40, 8)
+//!     .field(0x50, 4, 0x50, 8);
+//! let prog = ctx::convert_ctx_access(&prog, &layout);
+//!
+//! // The loads now read 64-bit pointers, at the offsets used by the VM.
+//! assert_eq!(&prog[8..16], &[0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00]);
+//! assert_eq!(&prog[24..32], &[0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00]);
+//!
+//! let mut mem = vec![0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27];
+//! let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+//! assert_eq!(vm.prog_exec(&mut mem), 0x27);
+//! ```
+
+use analysis;
+use ebpf;
+use error::EbpfError;
+use PtRegs;
+
+/// A type that can be handed to programs as their context, by an `EbpfVmCtx`. The program reads
+/// and writes the bytes of the value, in the byte order of the host.
+///
+/// # Safety
+///
+/// The type must have a defined layout (`#[repr(C)]` for structures), no padding bytes, and
+/// any sequence of bytes must be a valid value: integers, arrays and structures of integers
+/// qualify, but not references, `bool` or most enums. Programs may write any bytes to it.
+///
+/// # Examples
+///
+/// ```
+/// #[repr(C)]
+/// struct Counters {
+///     packets: u64,
+///     bytes:   u64,
+/// }
+///
+/// unsafe impl rbpf::ctx::Context for Counters {}
+/// ```
+pub unsafe trait Context: Sized {}
+
+unsafe impl Context for u8 {}
+unsafe impl Context for u16 {}
+unsafe impl Context for u32 {}
+unsafe impl Context for u64 {}
+unsafe impl Context for i8 {}
+unsafe impl Context for i16 {}
+unsafe impl Context for i32 {}
+unsafe impl Context for i64 {}
+unsafe impl<T: Context, const N: usize> Context for [T; N] {}
+unsafe impl Context for PtRegs {}
+
+/// Little-endian accesses to the fields of context structures and metadata buffers built by hand,
+/// instead of casts of raw pointers. Offsets are in bytes, and need not be aligned.
+///
+/// # Panics
+///
+/// The methods panic if the field does not fit in the buffer.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ctx::CtxBytes;
+///
+/// let prog = vec![
+///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff at offset 8 into R1.
+///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let mut mem = vec![0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd];
+///
+/// // Store the pointers to packet data start and end in the metadata buffer.
+/// let mut mbuff = vec![0u8; 32];
+/// mbuff.write_u64_le(8, mem.as_ptr() as u64);
+/// mbuff.write_u64_le(24, mem.as_ptr() as u64 + mem.len() as u64);
+/// assert_eq!(mbuff.read_u64_le(24) - mbuff.read_u64_le(8), 6);
+///
+/// let vm = rbpf::EbpfVmMbuff::new(&prog);
+/// assert_eq!(vm.prog_exec(&mut mem, &mut mbuff), 0x2211);
+/// assert_eq!(mem.read_u16_le(2), 0x2211);
+/// ```
+pub trait CtxBytes {
+    /// Read the byte at `offset`.
+    fn read_u8(&self, offset: usize) -> u8;
+    /// Read the 16-bit integer at `offset`.
+    fn read_u16_le(&self, offset: usize) -> u16;
+    /// Read the 32-bit integer at `offset`.
+    fn read_u32_le(&self, offset: usize) -> u32;
+    /// Read the 64-bit integer at `offset`.
+    fn read_u64_le(&self, offset: usize) -> u64;
+    /// Write `value` to the byte at `offset`.
+    fn write_u8(&mut self, offset: usize, value: u8);
+    /// Write `value` as a 16-bit integer at `offset`.
+    fn write_u16_le(&mut self, offset: usize, value: u16);
+    /// Write `value` as a 32-bit integer at `offset`.
+    fn write_u32_le(&mut self, offset: usize, value: u32);
+    /// Write `value` as a 64-bit integer at `offset`, for example a pointer to packet data.
+    fn write_u64_le(&mut self, offset: usize, value: u64);
+}
+
+fn field<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    let mut field = [0; N];
+    field.copy_from_slice(&bytes[offset..offset + N]);
+    field
+}
+
+impl CtxBytes for [u8] {
+    fn read_u8(&self, offset: usize) -> u8 {
+        self[offset]
+    }
+
+    fn read_u16_le(&self, offset: usize) -> u16 {
+        u16::from_le_bytes(field(self, offset))
+    }
+
+    fn read_u32_le(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(field(self, offset))
+    }
+
+    fn read_u64_le(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(field(self, offset))
+    }
+
+    fn write_u8(&mut self, offset: usize, value: u8) {
+        self[offset] = value;
+    }
+
+    fn write_u16_le(&mut self, offset: usize, value: u16) {
+        self[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u32_le(&mut self, offset: usize, value: u32) {
+        self[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64_le(&mut self, offset: usize, value: u64) {
+        self[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// A field of the context, as seen by the program, and where it is found in the metadata buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtxField {
+    /// Offset of the field in the context structure the program was compiled for.
+    pub offset:        i64,
+    /// Size of the field in the context structure the program was compiled for.
+    pub size:          usize,
+    /// Offset of the field in the metadata buffer.
+    pub target_offset: i64,
+    /// Size of the field in the metadata buffer.
+    pub target_size:   usize,
+    /// Whether the program may write to the field.
+    pub writable:      bool,
+}
+
+/// The layout of the context: the list of fields the program may access, built with `field()`
+/// and `writable_field()`. Accesses to the context out of these fields are rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CtxLayout {
+    fields: Vec<CtxField>,
+}
+
+fn size_code(size: usize) -> u8 {
+    match size {
+        1 => ebpf::BPF_B,
+        2 => ebpf::BPF_H,
+        4 => ebpf::BPF_W,
+        8 => ebpf::BPF_DW,
+        _ => panic!("Error: invalid context field size {:?}, expected 1, 2, 4 or 8", size),
+    }
+}
+
+impl CtxLayout {
+
+    /// Create a layout with no field.
+    pub fn new() -> CtxLayout {
+        CtxLayout::default()
+    }
+
+    /// Add a read-only field, of `size` bytes at `offset` in the context structure the program
+    /// was compiled for, and of `target_size` bytes at `target_offset` in the metadata buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the sizes is not 1, 2, 4 or 8.
+    pub fn field(self, offset: i64, size: usize, target_offset: i64, target_size: usize) -> CtxLayout {
+        self.add(CtxField { offset, size, target_offset, target_size, writable: false })
+    }
+
+    /// Add a field the program may read and write, see `field()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the sizes is not 1, 2, 4 or 8.
+    pub fn writable_field(self, offset: i64, size: usize, target_offset: i64, target_size: usize) -> CtxLayout {
+        self.add(CtxField { offset, size, target_offset, target_size, writable: true })
+    }
+
+    fn add(mut self, field: CtxField) -> CtxLayout {
+        size_code(field.size);
+        size_code(field.target_size);
+        self.fields.push(field);
+        self
+    }
+
+    /// The fields of the layout, in the order they were added.
+    pub fn fields(&self) -> &[CtxField] {
+        &self.fields
+    }
+}
+
+/// Rewrite the accesses of `prog` to its context according to `layout`, see the module
+/// documentation.
+///
+/// # Panics
+///
+/// Panics with the message of the error returned by `try_convert_ctx_access()`, if any.
+pub fn convert_ctx_access(prog: &[u8], layout: &CtxLayout) -> Vec<u8> {
+    match try_convert_ctx_access(prog, layout) {
+        Ok(prog) => prog,
+        Err(e)   => panic!("{}", e),
+    }
+}
+
+/// Rewrite the accesses of `prog` to its context according to `layout`, see the module
+/// documentation.
+///
+/// The context accesses are found as in `analysis::dependencies()`. An access covering a whole
+/// field gets the offset and the size of the field in the metadata buffer. An access to a part of
+/// a field is only possible if the field keeps the same size, and has its offset shifted.
+///
+/// # Errors
+///
+/// Returns an `EbpfError::VerifierError` if the program accesses the context out of the fields
+/// of the layout, or writes to a read-only field, or if the analysis loses track of the pointer
+/// to the context.
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn try_convert_ctx_access(prog: &[u8], layout: &CtxLayout) -> Result<Vec<u8>, EbpfError> {
+    let error = |msg: String| Err(EbpfError::VerifierError(format!("[Verifier] Error: {}", msg)));
+    let (accesses, ctx_unknown) = analysis::ctx_accesses(prog);
+    if ctx_unknown {
+        return error("cannot follow the pointer to the context to convert context accesses".to_string());
+    }
+
+    let mut xlated = prog.to_vec();
+    for (ptr, access) in accesses {
+        let mut insn = ebpf::get_insn(prog, ptr);
+        let (start, end) = (access.offset, access.offset + access.size as i64);
+        let field = match layout.fields.iter().find(|f| f.offset <= start && end <= f.offset + f.size as i64) {
+            Some(field) => field,
+            None        => return error(format!("invalid context access at offset {:#x} of size {:?} (insn #{:?})",
+                                               access.offset, access.size, ptr)),
+        };
+        if access.write && !field.writable {
+            return error(format!("write to read-only context field at offset {:#x} (insn #{:?})",
+                                 field.offset, ptr));
+        }
+        let whole = start == field.offset && access.size == field.size;
+        let resized = field.target_size != field.size;
+        // Atomic operations and sign-extending loads are not resized, there is no 64-bit form of
+        // the latter.
+        let plain = insn.opc & 0xe0 == ebpf::BPF_MEM;
+        if resized && !(whole && plain) {
+            return error(format!("cannot convert context access at offset {:#x} of size {:?} (insn #{:?})",
+                                 access.offset, access.size, ptr));
+        }
+
+        let off = insn.off as i64 + field.target_offset - field.offset;
+        insn.off = match off {
+            o if o >= i16::MIN as i64 && o <= i16::MAX as i64 => o as i16,
+            _ => return error(format!("converted context offset {:#x} out of range (insn #{:?})", off, ptr)),
+        };
+        if resized {
+            insn.opc = (insn.opc & !0x18) | size_code(field.target_size);
+        }
+        xlated[ptr * ebpf::INSN_SIZE..(ptr + 1) * ebpf::INSN_SIZE].copy_from_slice(&insn.to_array());
+    }
+    Ok(xlated)
+}
@@ -0,0 +1,247 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module translates eBPF bytecode into human-readable assembly.
+//!
+//! The syntax is close to the one used by the Linux kernel and by uBPF: the mnemonic of the
+//! operation, followed by the destination and source operands, for example `add64 r1, 0x2`,
+//! `ldxh r0, [r1+0x2]`, `jeq r1, r2, +0x3` or `exit`.
+
+use btf::LineInfo;
+use ebpf;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A high-level representation of an eBPF instruction, as produced by the disassembler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HLInsn {
+    /// Index of the instruction in the program. `lddw` occupies two slots: the instruction
+    /// following it has an index greater by two.
+    pub ptr:  usize,
+    /// Operation code.
+    pub opc:  u8,
+    /// Mnemonic of the operation, for example `add64`.
+    pub name: String,
+    /// Full assembly for the instruction, for example `add64 r1, 0x2`.
+    pub desc: String,
+    /// Destination register operand.
+    pub dst:  u8,
+    /// Source register operand.
+    pub src:  u8,
+    /// Offset operand.
+    pub off:  i16,
+    /// Immediate value operand. For `lddw`, the 64-bit value spread over both slots.
+    pub imm:  i64,
+}
+
+fn alu_imm_str(name: &str, insn: &ebpf::Insn) -> String {
+    format!("{} r{}, {:#x}", name, insn.dst, insn.imm)
+}
+
+fn alu_reg_str(name: &str, insn: &ebpf::Insn) -> String {
+    format!("{} r{}, r{}", name, insn.dst, insn.src)
+}
+
+fn mem_str(reg: u8, off: i16) -> String {
+    match off {
+        0          => format!("[r{}]", reg),
+        o if o < 0 => format!("[r{}-{:#x}]", reg, -(o as i32)),
+        o          => format!("[r{}+{:#x}]", reg, o),
+    }
+}
+
+fn jmp_off_str(off: i32) -> String {
+    match off {
+        o if o < 0 => format!("-{:#x}", -(o as i64)),
+        o          => format!("+{:#x}", o),
+    }
+}
+
+/// Return the mnemonic and the assembly for instruction `insn`. `next_imm` is the immediate of
+/// the following slot, only used for `lddw`.
+fn describe(insn: &ebpf::Insn, next_imm: i32) -> (&'static str, String) {
+    use ebpf::OperandKind;
+
+    let info = match ebpf::opcode_info(insn.opc) {
+        Some(info) => info,
+        None       => return ("unknown", format!("unknown opcode {:#04x}", insn.opc)),
+    };
+    let name = match insn.opc {
+        // Signed divisions and modulos, and sign-extending moves (ISA v4)
+        ebpf::DIV32_IMM  | ebpf::DIV32_REG if insn.off == 1 => "sdiv32",
+        ebpf::MOD32_IMM  | ebpf::MOD32_REG if insn.off == 1 => "smod32",
+        ebpf::DIV64_IMM  | ebpf::DIV64_REG if insn.off == 1 => "sdiv64",
+        ebpf::MOD64_IMM  | ebpf::MOD64_REG if insn.off == 1 => "smod64",
+        ebpf::MOV32_REG  if insn.off != 0   => "movsx32",
+        ebpf::MOV64_REG  if insn.off != 0   => "movsx64",
+        _                                   => info.mnemonic,
+    };
+
+    let desc = match info.operands {
+        OperandKind::None      => name.to_string(),
+        OperandKind::Dst       => format!("{} r{}", name, insn.dst),
+        OperandKind::DstWidth  => format!("{}{} r{}", name, insn.imm, insn.dst),
+        OperandKind::DstImm    => alu_imm_str(name, insn),
+        OperandKind::DstSrc if name.starts_with("movsx") =>
+            format!("{} r{}, r{}, {}", name, insn.dst, insn.src, insn.off),
+        OperandKind::DstSrc    => alu_reg_str(name, insn),
+        OperandKind::DstImm64  => format!("{} r{}, {:#x}", name, insn.dst, lddw_imm(insn.imm, next_imm)),
+        OperandKind::DstMem    => format!("{} r{}, {}", name, insn.dst, mem_str(insn.src, insn.off)),
+        OperandKind::MemImm    => format!("{} {}, {:#x}", name, mem_str(insn.dst, insn.off), insn.imm),
+        OperandKind::MemSrc    => format!("{} {}, r{}", name, mem_str(insn.dst, insn.off), insn.src),
+        OperandKind::Imm       => format!("{} {:#x}", name, insn.imm),
+        OperandKind::SrcImm    => format!("{} r{}, {:#x}", name, insn.src, insn.imm),
+        // `ja32` has its offset in the immediate.
+        OperandKind::Off if insn.opc == ebpf::JA32 => format!("{} {}", name, jmp_off_str(insn.imm)),
+        OperandKind::Off       => format!("{} {}", name, jmp_off_str(insn.off as i32)),
+        OperandKind::DstImmOff =>
+            format!("{} r{}, {:#x}, {}", name, insn.dst, insn.imm, jmp_off_str(insn.off as i32)),
+        OperandKind::DstSrcOff =>
+            format!("{} r{}, r{}, {}", name, insn.dst, insn.src, jmp_off_str(insn.off as i32)),
+    };
+
+    (name, desc)
+}
+
+fn lddw_imm(imm: i32, next_imm: i32) -> u64 {
+    (imm as u32 as u64) | ((next_imm as u32 as u64) << 32)
+}
+
+/// Disassemble an eBPF program into a list of high-level instructions.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::disassembler;
+///
+/// let prog = vec![
+///     0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55,
+///     0x00, 0x00, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11,
+///     0x69, 0x12, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+/// ];
+/// let insns = disassembler::to_insn_vec(&prog);
+///
+/// assert_eq!(insns.len(), 3);
+/// assert_eq!(insns[0].desc, "lddw r0, 0x1122334455667788");
+/// assert_eq!(insns[1].ptr, 2);
+/// assert_eq!(insns[1].desc, "ldxh r2, [r1-0x2]");
+/// assert_eq!(insns[2].name, "exit");
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn to_insn_vec(prog: &[u8]) -> Vec<HLInsn> {
+    if !prog.len().is_multiple_of(ebpf::INSN_SIZE) {
+        panic!("Error: eBPF program length must be a multiple of {:?} octets",
+               ebpf::INSN_SIZE);
+    }
+
+    let mut res = vec![];
+    let mut insn_ptr = 0;
+    while insn_ptr * ebpf::INSN_SIZE < prog.len() {
+        let insn = ebpf::get_insn(prog, insn_ptr);
+        let mut imm = insn.imm as i64;
+        let mut len = 1;
+        let mut next_imm = 0;
+        if insn.opc == ebpf::LD_DW_IMM && (insn_ptr + 2) * ebpf::INSN_SIZE <= prog.len() {
+            next_imm = ebpf::get_insn(prog, insn_ptr + 1).imm;
+            imm = lddw_imm(insn.imm, next_imm) as i64;
+            len = 2;
+        }
+        let (name, desc) = describe(&insn, next_imm);
+        res.push(HLInsn {
+            ptr:  insn_ptr,
+            opc:  insn.opc,
+            name: name.to_string(),
+            desc,
+            dst:  insn.dst,
+            src:  insn.src,
+            off:  insn.off,
+            imm,
+        });
+        insn_ptr += len;
+    }
+    res
+}
+
+/// Disassemble an eBPF program, and print the result on the standard output, one instruction
+/// per line.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::disassembler;
+///
+/// let prog = vec![
+///     0x07, 0x01, 0x00, 0x00, 0x05, 0x06, 0x00, 0x00,
+///     0xb7, 0x02, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00,
+///     0xbf, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0xdc, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+/// ];
+/// disassembler::disassemble(&prog);
+/// ```
+///
+/// This will produce the following output:
+///
+/// ```test
+/// add64 r1, 0x605
+/// mov64 r2, 0x32
+/// mov64 r1, r2
+/// be16 r1
+/// exit
+/// ```
+pub fn disassemble(prog: &[u8]) {
+    for insn in to_insn_vec(prog) {
+        println!("{}", insn.desc);
+    }
+}
+
+/// Disassemble an eBPF program into human-readable instructions and prints it to standard output,
+/// with a label before the first instruction of each function named by `info`, as read from the
+/// symbol table or the BTF data of an object file.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::btf::LineInfo;
+/// use rbpf::disassembler;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+/// ];
+/// let mut info = LineInfo::new();
+/// info.insert_function(0, "allow");
+/// info.insert_function(2, "drop");
+/// disassembler::disassemble_with_symbols(&prog, &info);
+/// ```
+///
+/// This will produce the following output:
+///
+/// ```test
+/// allow:
+/// mov64 r0, 0x1
+/// exit
+/// drop:
+/// mov64 r0, 0x2
+/// exit
+/// ```
+pub fn disassemble_with_symbols(prog: &[u8], info: &LineInfo) {
+    for insn in to_insn_vec(prog) {
+        if let Some((_, name)) = info.functions().iter().find(|&&(idx, _)| idx == insn.ptr) {
+            println!("{}:", name);
+        }
+        println!("{}", insn.desc);
+    }
+}
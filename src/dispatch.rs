@@ -0,0 +1,131 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides a dispatcher acting on the verdicts of filter programs: the application
+//! registers an action for each value, or range of values, the program may return, and the
+//! dispatcher runs the program over a packet and calls the matching action.
+//!
+//! Actions are closures receiving the value returned by the program and the packet, as modified
+//! by the program, for example to drop it, or to push it to the queue given by the verdict.
+
+use std::ops::RangeInclusive;
+
+use error::EbpfError;
+use EbpfVmRaw;
+
+type Action<'a> = Box<dyn FnMut(u64, &mut [u8]) + 'a>;
+
+/// A VM running a filter program over packets, and dispatching them to actions depending on the
+/// value returned by the program, see the module documentation.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::RefCell;
+/// use rbpf::dispatch::Dispatcher;
+///
+/// // Drops (returns 0) packets whose first byte is 0, and sends the others to the queue given by
+/// // their first byte (returns it).
+/// let prog = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let dropped = RefCell::new(0);
+/// let queues = RefCell::new(vec![vec![]; 4]);
+///
+/// let mut dispatcher = Dispatcher::new(rbpf::EbpfVmRaw::new(&prog));
+/// dispatcher.on(0, |_, _| *dropped.borrow_mut() += 1);
+/// dispatcher.on_range(1..=3, |queue, packet| queues.borrow_mut()[queue as usize].push(packet.to_vec()));
+///
+/// for packet in [[0, 0xaa], [2, 0xbb], [3, 0xcc], [2, 0xdd]].iter_mut() {
+///     dispatcher.exec_and_dispatch(packet);
+/// }
+/// // No action for this verdict: the packet is ignored.
+/// assert_eq!(dispatcher.exec_and_dispatch(&mut [7]), 7);
+///
+/// drop(dispatcher);
+/// assert_eq!(dropped.into_inner(), 1);
+/// assert_eq!(queues.into_inner()[2], vec![vec![2, 0xbb], vec![2, 0xdd]]);
+/// ```
+pub struct Dispatcher<'a> {
+    vm:      EbpfVmRaw<'a>,
+    actions: Vec<(RangeInclusive<u64>, Action<'a>)>,
+    default: Option<Action<'a>>,
+}
+
+impl<'a> Dispatcher<'a> {
+
+    /// Create a dispatcher running the program loaded in `vm`, with no actions.
+    pub fn new(vm: EbpfVmRaw<'a>) -> Dispatcher<'a> {
+        Dispatcher {
+            vm,
+            actions: vec![],
+            default: None,
+        }
+    }
+
+    /// Return the VM running the program.
+    pub fn vm(&self) -> &EbpfVmRaw<'a> {
+        &self.vm
+    }
+
+    /// Return the VM running the program, for example to register helpers or maps.
+    pub fn vm_mut(&mut self) -> &mut EbpfVmRaw<'a> {
+        &mut self.vm
+    }
+
+    /// Register `action` for the packets on which the program returns `ret`. Actions are matched
+    /// in the order they were registered: if several actions match a value, only the first one
+    /// runs.
+    pub fn on<F: FnMut(u64, &mut [u8]) + 'a>(&mut self, ret: u64, action: F) {
+        self.on_range(ret..=ret, action);
+    }
+
+    /// Register `action` for the packets on which the program returns a value in `range`. See
+    /// `on()`.
+    pub fn on_range<F: FnMut(u64, &mut [u8]) + 'a>(&mut self, range: RangeInclusive<u64>, action: F) {
+        self.actions.push((range, Box::new(action)));
+    }
+
+    /// Register `action` for the packets on which the program returns a value matched by no other
+    /// action. Without a default action, such packets are ignored.
+    pub fn otherwise<F: FnMut(u64, &mut [u8]) + 'a>(&mut self, action: F) {
+        self.default = Some(Box::new(action));
+    }
+
+    /// Return `true` if an action, possibly the default one, is registered for `ret`.
+    pub fn handles(&self, ret: u64) -> bool {
+        self.default.is_some() || self.actions.iter().any(|(range, _)| range.contains(&ret))
+    }
+
+    /// Run the program over `packet`, call the action registered for the value it returned, and
+    /// return this value.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same execution errors as `EbpfVmRaw::prog_exec()`.
+    pub fn exec_and_dispatch(&mut self, packet: &mut [u8]) -> u64 {
+        match self.try_exec_and_dispatch(packet) {
+            Ok(ret)  => ret,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Run the program over `packet` and dispatch it, in the same way as `exec_and_dispatch()`,
+    /// but return runtime errors instead of panicking. No action is called on errors.
+    pub fn try_exec_and_dispatch(&mut self, packet: &mut [u8]) -> Result<u64, EbpfError> {
+        let ret = self.vm.parent.try_prog_exec(packet, &mut [])?;
+        let action = self.actions.iter_mut().find(|(range, _)| range.contains(&ret))
+            .map(|(_, action)| action)
+            .or(self.default.as_mut());
+        if let Some(action) = action {
+            action(ret, packet);
+        }
+        Ok(ret)
+    }
+}
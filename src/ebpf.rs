@@ -8,25 +8,48 @@
 //! This module contains all the definitions related to eBPF.
 //!
 //! The number of bytes in an instruction, the maximum number of instructions in a program, and
-//! also all operation codes are defined here as constants.
+//! also all operation codes are defined here as constants. `OPCODES` lists the operation codes
+//! with their mnemonics and operands, and predicates such as `is_jump()` classify them.
 //!
-//! The structure for an instruction used by this crate, as well as the function to extract it from
-//! a program, is also defined in the module.
+//! The structure for an instruction used by this crate, as well as the functions to extract it from
+//! a program (`get_insn()`, or `InsnIter` to walk a whole program) and to encode it back, are also
+//! defined in the module, as well as the actions returned by XDP programs and tc classifiers,
+//! `XdpAction` and `TcAction`.
 //!
 //! To learn more about these instructions, see the Linux kernel documentation:
 //! <https://www.kernel.org/doc/Documentation/networking/filter.txt>, or for a shorter version of
 //! the list of the operation codes: <https://github.com/iovisor/bpf-docs/blob/master/eBPF.md>
 
-use std;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use error::{EbpfError, HelperError};
 
 /// Maximum number of instructions in an eBPF program.
 pub const PROG_MAX_INSNS: usize = 4096;
+/// Default maximum estimated complexity of the verification of a program, see
+/// `verifier::complexity()`, as the number of instructions the verifier of the Linux kernel
+/// processes at most.
+pub const MAX_COMPLEXITY: usize = 1_000_000;
 /// Size of an eBPF instructions, in bytes.
 pub const INSN_SIZE: usize = 8;
 /// Maximum size of an eBPF program, in bytes.
 pub const PROG_MAX_SIZE: usize = PROG_MAX_INSNS * INSN_SIZE;
 /// Stack for the eBPF stack, in bytes.
 pub const STACK_SIZE: usize = 512;
+/// Pattern filling the stack, and the padding of the metadata buffer of `EbpfVmFixedMbuff`, when
+/// memory poisoning is enabled, see `EbpfVmMbuff::set_memory_poisoning()`.
+pub const POISON_BYTE: u8 = 0xa5;
+/// Maximum number of tail calls that can be chained during one execution, as in the Linux kernel.
+pub const MAX_TAIL_CALL_CNT: usize = 33;
+/// Default maximum number of executions nested on a thread, by helpers running programs
+/// themselves, as the number of call frames in the Linux kernel. See
+/// `EbpfVmMbuff::set_max_call_depth()`.
+pub const MAX_CALL_DEPTH: usize = 8;
+/// Maximum number of instructions an execution may run under `SecurityProfile::Strict`, as the
+/// number of instructions the Linux kernel verifier processes at most. See
+/// `EbpfVmMbuff::set_insn_limit()`.
+pub const STRICT_INSN_LIMIT: u64 = 1_000_000;
 
 // eBPF op codes.
 // See also https://www.kernel.org/doc/Documentation/networking/filter.txt
@@ -44,7 +67,8 @@ pub const BPF_STX   : u8 = 0x03;
 pub const BPF_ALU   : u8 = 0x04;
 /// BPF operation class: jump.
 pub const BPF_JMP   : u8 = 0x05;
-// [ class 6 unused, reserved for future use ]
+/// BPF operation class: jump, comparing the lower 32 bits of the operands (ISA v3).
+pub const BPF_JMP32 : u8 = 0x06;
 /// BPF operation class: 64 bits arithmetic operation.
 pub const BPF_ALU64 : u8 = 0x07;
 
@@ -74,7 +98,8 @@ pub const BPF_ABS   : u8 = 0x20;
 pub const BPF_IND   : u8 = 0x40;
 /// BPF mode modifier: load from / store to memory.
 pub const BPF_MEM   : u8 = 0x60;
-// [ 0x80 reserved ]
+/// BPF mode modifier: sign-extending load from memory (ISA v4).
+pub const BPF_MEMSX : u8 = 0x80;
 // [ 0xa0 reserved ]
 /// BPF mode modifier: exclusive add.
 pub const BPF_XADD  : u8 = 0xc0;
@@ -143,6 +168,14 @@ pub const BPF_JSGE  : u8 = 0x70;
 pub const BPF_CALL  : u8 = 0x80;
 /// BPF JMP operation code: return from program.
 pub const BPF_EXIT  : u8 = 0x90;
+/// BPF JMP operation code: jump if lower than (ISA v2).
+pub const BPF_JLT   : u8 = 0xa0;
+/// BPF JMP operation code: jump if lower or equal (ISA v2).
+pub const BPF_JLE   : u8 = 0xb0;
+/// BPF JMP operation code: jump if lower than (signed, ISA v2).
+pub const BPF_JSLT  : u8 = 0xc0;
+/// BPF JMP operation code: jump if lower or equal (signed, ISA v2).
+pub const BPF_JSLE  : u8 = 0xd0;
 
 // Op codes
 // (Following operation names are not “official”, but may be proper to rbpf; Linux kernel only
@@ -175,6 +208,12 @@ pub const LD_H_REG   : u8 = BPF_LDX   | BPF_MEM | BPF_H;
 pub const LD_W_REG   : u8 = BPF_LDX   | BPF_MEM | BPF_W;
 /// BPF opcode: `ldxdw dst, [src + off]` /// `dst = (src + off) as u64`.
 pub const LD_DW_REG  : u8 = BPF_LDX   | BPF_MEM | BPF_DW;
+/// BPF opcode: `ldxsb dst, [src + off]` /// `dst = (src + off) as i8 as u64` (ISA v4).
+pub const LD_B_SX    : u8 = BPF_LDX   | BPF_MEMSX | BPF_B;
+/// BPF opcode: `ldxsh dst, [src + off]` /// `dst = (src + off) as i16 as u64` (ISA v4).
+pub const LD_H_SX    : u8 = BPF_LDX   | BPF_MEMSX | BPF_H;
+/// BPF opcode: `ldxsw dst, [src + off]` /// `dst = (src + off) as i32 as u64` (ISA v4).
+pub const LD_W_SX    : u8 = BPF_LDX   | BPF_MEMSX | BPF_W;
 /// BPF opcode: `stb [dst + off], imm` /// `(dst + offset) as u8 = imm`.
 pub const ST_B_IMM   : u8 = BPF_ST    | BPF_MEM | BPF_B;
 /// BPF opcode: `sth [dst + off], imm` /// `(dst + offset) as u16 = imm`.
@@ -256,6 +295,8 @@ pub const ARSH32_REG : u8 = BPF_ALU   | BPF_X   | BPF_ARSH;
 pub const LE         : u8 = BPF_ALU   | BPF_K   | BPF_END;
 /// BPF opcode: `be dst` /// `dst = htobe<imm>(dst), with imm in {16, 32, 64}`.
 pub const BE         : u8 = BPF_ALU   | BPF_X   | BPF_END;
+/// BPF opcode: `bswap dst` /// `dst = bswap<imm>(dst), with imm in {16, 32, 64}` (ISA v4).
+pub const BSWAP      : u8 = BPF_ALU64 | BPF_K   | BPF_END;
 
 /// BPF opcode: `add64 dst, imm` /// `dst += imm`.
 pub const ADD64_IMM  : u8 = BPF_ALU64 | BPF_K   | BPF_ADD;
@@ -342,6 +383,27 @@ pub const JSGT_REG   : u8 = BPF_JMP   | BPF_X   | BPF_JSGT;
 pub const JSGE_IMM   : u8 = BPF_JMP   | BPF_K   | BPF_JSGE;
 /// BPF opcode: `jsge dst, src, +off` /// `PC += off if dst >= src (signed)`.
 pub const JSGE_REG   : u8 = BPF_JMP   | BPF_X   | BPF_JSGE;
+/// BPF opcode: `jlt dst, imm, +off` /// `PC += off if dst < imm` (ISA v2).
+pub const JLT_IMM    : u8 = BPF_JMP   | BPF_K   | BPF_JLT;
+/// BPF opcode: `jlt dst, src, +off` /// `PC += off if dst < src` (ISA v2).
+pub const JLT_REG    : u8 = BPF_JMP   | BPF_X   | BPF_JLT;
+/// BPF opcode: `jle dst, imm, +off` /// `PC += off if dst <= imm` (ISA v2).
+pub const JLE_IMM    : u8 = BPF_JMP   | BPF_K   | BPF_JLE;
+/// BPF opcode: `jle dst, src, +off` /// `PC += off if dst <= src` (ISA v2).
+pub const JLE_REG    : u8 = BPF_JMP   | BPF_X   | BPF_JLE;
+/// BPF opcode: `jslt dst, imm, +off` /// `PC += off if dst < imm (signed)` (ISA v2).
+pub const JSLT_IMM   : u8 = BPF_JMP   | BPF_K   | BPF_JSLT;
+/// BPF opcode: `jslt dst, src, +off` /// `PC += off if dst < src (signed)` (ISA v2).
+pub const JSLT_REG   : u8 = BPF_JMP   | BPF_X   | BPF_JSLT;
+/// BPF opcode: `jsle dst, imm, +off` /// `PC += off if dst <= imm (signed)` (ISA v2).
+pub const JSLE_IMM   : u8 = BPF_JMP   | BPF_K   | BPF_JSLE;
+/// BPF opcode: `jsle dst, src, +off` /// `PC += off if dst <= src (signed)` (ISA v2).
+pub const JSLE_REG   : u8 = BPF_JMP   | BPF_X   | BPF_JSLE;
+/// BPF opcode: `ja32 +imm` /// `PC += imm` (ISA v4).
+///
+/// The other instructions of class `BPF_JMP32` are the conditional jumps of class `BPF_JMP`,
+/// comparing the lower 32 bits of the operands (ISA v3).
+pub const JA32       : u8 = BPF_JMP32 | BPF_JA;
 
 /// BPF opcode: `call imm` /// helper function call to helper with key `imm`.
 pub const CALL       : u8 = BPF_JMP   | BPF_CALL;
@@ -356,12 +418,471 @@ pub const BPF_CLS_MASK    : u8 = 0x07;
 /// Mask to extract the arithmetic operation code from an instruction operation code.
 pub const BPF_ALU_OP_MASK : u8 = 0xf0;
 
+/// Value of the source register of a `lddw` instruction, indicating that its immediate is the
+/// identifier of a map (`BPF_PSEUDO_MAP_FD` in Linux kernel). Programs produced by libbpf
+/// reference maps this way, with the file descriptor of the map: in rbpf, this is the identifier
+/// returned by `register_map()`, loaded as is into the register, and taken by the map helpers as
+/// the handle of the map. The immediate of the second half of the instruction must be 0.
+pub const BPF_PSEUDO_MAP_FD : u8 = 1;
+
+/// Value of the source register of a `lddw` instruction, indicating that its immediate is the
+/// identifier of an array map, and the immediate of its second half an offset in the value of the
+/// first entry of the map (`BPF_PSEUDO_MAP_VALUE` in Linux kernel). The instruction loads the
+/// address of the value, plus the offset, into the register. Programs produced by libbpf access
+/// their global variables this way, see `btf::Globals`.
+pub const BPF_PSEUDO_MAP_VALUE : u8 = 2;
+
+/// Value of the source register of a `lddw` instruction, indicating that it loads a reference to
+/// the subprogram at the relative offset given by its immediate (`BPF_PSEUDO_FUNC` in Linux
+/// kernel), to pass it as a callback to a helper, such as `bpf_for_each_map_elem()`. In rbpf, the
+/// reference is the index of the first instruction of the subprogram. The immediate of the second
+/// half of the instruction must be 0.
+pub const BPF_PSEUDO_FUNC : u8 = 4;
+
+/// Value of the source register of a `call` instruction, indicating a call to a subprogram (a
+/// BPF-to-BPF call) at the relative offset given by its immediate, rather than to a helper
+/// (`BPF_PSEUDO_CALL` in Linux kernel).
+pub const BPF_PSEUDO_CALL : u8 = 1;
+
+/// Prototype of an eBPF helper function: five `u64` arguments, and a `u64` as a return value.
+pub type Helper = fn (u64, u64, u64, u64, u64) -> u64;
+
+/// Prototype of a fallible eBPF helper function, see `EbpfVmMbuff::register_fallible_helper()`:
+/// five `u64` arguments, and a `u64` as a return value, or an error aborting the execution.
+pub type FallibleHelper = fn (u64, u64, u64, u64, u64) -> Result<u64, HelperError>;
+
+/// Semantics of divisions and modulos by a register holding zero. Divisions by an immediate zero
+/// are always rejected by the verifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DivByZero {
+    /// Abort the program. The interpreter reports an error (or panics), while JIT-compiled
+    /// programs return `u64::MAX`. This is the default.
+    #[default]
+    Error,
+    /// Follow the Linux kernel: a division sets the destination register to zero, and a modulo
+    /// leaves it unchanged (apart from the upper 32 bits, cleared by 32-bit operations).
+    Kernel,
+}
+
+/// Presets of the settings of a VM, so that hosts running programs supplied by several tenants
+/// need not know every setting, see `EbpfVmMbuff::set_security_profile()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SecurityProfile {
+    /// The default settings of the VMs.
+    #[default]
+    Default,
+    /// Hardened settings, for programs that the host does not trust:
+    ///
+    /// * Programs may only call the helpers enabled on the VM when the profile is selected, see
+    ///   `verifier::HelperAllowList`.
+    /// * The JIT-compiler blinds constants, runs programs on a guarded stack, and catches their
+    ///   faults, see `EbpfVmMbuff::set_jit_hardening()`, `EbpfVmMbuff::set_stack_guard()` and
+    ///   `EbpfVmMbuff::set_catch_faults()`.
+    /// * The interpreter rejects the loads of stack never written, see
+    ///   `EbpfVmMbuff::set_memory_poisoning()`.
+    /// * Divisions by zero follow the kernel, so that the interpreter and JIT-compiled programs
+    ///   return the same values, see `DivByZero::Kernel`.
+    /// * Programs cannot run from within helpers, see `EbpfVmMbuff::set_max_call_depth()`.
+    /// * Executions run at most `STRICT_INSN_LIMIT` instructions, and the JIT-compiler only
+    ///   compiles the programs proven to stay within this limit, see
+    ///   `EbpfVmMbuff::set_insn_limit()`.
+    /// * Packet data is read-only, and the JIT-compiler only compiles the programs proven to
+    ///   store to the stack only, see `EbpfVmMbuff::set_read_only_packet()`.
+    ///
+    /// The profile does not isolate programs further: they still run on the addresses of the
+    /// host, since the VMs have no guest address space, and nothing makes their executions
+    /// deterministic, as the helpers registered by the host may not be.
+    Strict,
+}
+
+/// Versions of the eBPF instruction set, as defined by the Linux kernel (`-mcpu=v1` to `v4` for
+/// clang). Each version adds instructions to the previous one:
+///
+/// * v2: jumps `jlt`, `jle`, `jslt` and `jsle`.
+/// * v3: 32-bit jumps, class `BPF_JMP32`.
+/// * v4: signed divisions and modulos (`BPF_DIV` and `BPF_MOD` with offset 1), sign-extending
+///   moves (`BPF_MOV` from a register with offset 8, 16 or 32), sign-extending loads
+///   (`BPF_MEMSX`), unconditional byte swaps (`BSWAP`) and jumps with a 32-bit offset (`JA32`).
+///
+/// The verifier only accepts the instructions of the version selected for a VM, v4 by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum IsaVersion {
+    /// Original instruction set.
+    V1,
+    /// Adds jumps on lower or equal conditions.
+    V2,
+    /// Adds 32-bit jumps.
+    V3,
+    /// Adds signed divisions, sign extensions, byte swaps and long jumps.
+    #[default]
+    V4,
+}
+
+/// Actions returned by XDP programs (`enum xdp_action` in Linux kernel).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XdpAction {
+    /// `XDP_ABORTED` (0): drop the packet, signaling an error.
+    Aborted,
+    /// `XDP_DROP` (1): drop the packet.
+    Drop,
+    /// `XDP_PASS` (2): pass the packet to the network stack.
+    Pass,
+    /// `XDP_TX` (3): send the packet back through the interface it came from.
+    Tx,
+    /// `XDP_REDIRECT` (4): redirect the packet to another interface, CPU or socket.
+    Redirect,
+}
+
+impl XdpAction {
+    /// Convert the value returned by a program into an action. As in the kernel, only the lower 32
+    /// bits of the value are considered. Returns an `EbpfError::InvalidReturnValue` error if they
+    /// do not match an action.
+    pub fn from_ret(ret: u64) -> Result<XdpAction, EbpfError> {
+        match ret as u32 {
+            0 => Ok(XdpAction::Aborted),
+            1 => Ok(XdpAction::Drop),
+            2 => Ok(XdpAction::Pass),
+            3 => Ok(XdpAction::Tx),
+            4 => Ok(XdpAction::Redirect),
+            _ => Err(EbpfError::InvalidReturnValue { ret, prog_type: "XDP" }),
+        }
+    }
+
+    /// The value of the action in the kernel.
+    pub fn value(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Actions returned by tc classifiers in direct-action mode (`TC_ACT_*` in Linux kernel).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcAction {
+    /// `TC_ACT_UNSPEC` (-1): use the default action of the qdisc.
+    Unspec,
+    /// `TC_ACT_OK` (0): pass the packet.
+    Ok,
+    /// `TC_ACT_RECLASSIFY` (1): restart the classification.
+    Reclassify,
+    /// `TC_ACT_SHOT` (2): drop the packet.
+    Shot,
+    /// `TC_ACT_PIPE` (3): run the next action.
+    Pipe,
+    /// `TC_ACT_STOLEN` (4): consume the packet.
+    Stolen,
+    /// `TC_ACT_QUEUED` (5): consume the packet, queued for later processing.
+    Queued,
+    /// `TC_ACT_REPEAT` (6): run the action again.
+    Repeat,
+    /// `TC_ACT_REDIRECT` (7): redirect the packet to another interface.
+    Redirect,
+    /// `TC_ACT_TRAP` (8): drop the packet, and hand it to the CPU in hardware offloads.
+    Trap,
+}
+
+impl TcAction {
+    /// Convert the value returned by a program into an action. As in the kernel, only the lower 32
+    /// bits of the value are considered, as a signed integer. Returns an
+    /// `EbpfError::InvalidReturnValue` error if they do not match an action.
+    pub fn from_ret(ret: u64) -> Result<TcAction, EbpfError> {
+        match ret as u32 as i32 {
+            -1 => Ok(TcAction::Unspec),
+            0  => Ok(TcAction::Ok),
+            1  => Ok(TcAction::Reclassify),
+            2  => Ok(TcAction::Shot),
+            3  => Ok(TcAction::Pipe),
+            4  => Ok(TcAction::Stolen),
+            5  => Ok(TcAction::Queued),
+            6  => Ok(TcAction::Repeat),
+            7  => Ok(TcAction::Redirect),
+            8  => Ok(TcAction::Trap),
+            _  => Err(EbpfError::InvalidReturnValue { ret, prog_type: "tc" }),
+        }
+    }
+
+    /// The value of the action in the kernel.
+    pub fn value(self) -> i32 {
+        self as i32 - 1
+    }
+}
+
+/// The operands of an instruction, in the order of its assembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperandKind {
+    /// No operand: `exit`.
+    None,
+    /// Destination register: `neg64 r1`.
+    Dst,
+    /// Destination register, with the width of the operation in bits in the immediate, appended
+    /// to the mnemonic: `be16 r1`.
+    DstWidth,
+    /// Destination register and immediate: `add64 r1, 0x2`.
+    DstImm,
+    /// Destination and source registers: `add64 r1, r2`.
+    DstSrc,
+    /// Destination register and 64-bit immediate, over two instruction slots: `lddw r1, 0x2`.
+    DstImm64,
+    /// Destination register, and memory at source register plus offset: `ldxw r1, [r2+0x4]`.
+    DstMem,
+    /// Memory at destination register plus offset, and immediate: `stw [r1+0x4], 0x2`.
+    MemImm,
+    /// Memory at destination register plus offset, and source register: `stxw [r1+0x4], r2`.
+    MemSrc,
+    /// Immediate: `call 0x1`, `ldabsw 0x4`.
+    Imm,
+    /// Source register and immediate: `ldindw r1, 0x4`.
+    SrcImm,
+    /// Jump offset: `ja +0x2`. For `ja32`, the offset is the immediate.
+    Off,
+    /// Destination register, immediate and jump offset: `jeq r1, 0x2, +0x3`.
+    DstImmOff,
+    /// Destination and source registers, and jump offset: `jeq r1, r2, +0x3`.
+    DstSrcOff,
+}
+
+/// Metadata of an operation code, see `OPCODES`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpcodeInfo {
+    /// The operation code.
+    pub opc:      u8,
+    /// Mnemonic of the operation, as used by the assembler and the disassembler. Signed divisions
+    /// and modulos, and sign-extending moves, are selected by the offset of the instruction: they
+    /// share the operation codes of `div`, `mod` and `mov`.
+    pub mnemonic: &'static str,
+    /// The operands of the instruction.
+    pub operands: OperandKind,
+    /// First version of the instruction set with this operation code.
+    pub isa:      IsaVersion,
+}
+
+const fn op(opc: u8, mnemonic: &'static str, operands: OperandKind, isa: IsaVersion) -> OpcodeInfo {
+    OpcodeInfo { opc, mnemonic, operands, isa }
+}
+
+/// The operation codes supported by this crate, with their metadata, in increasing class order.
+/// The assembler, the disassembler and the verifier all rely on this table.
+pub static OPCODES: &[OpcodeInfo] = &[
+    // BPF_LD class
+    op(LD_ABS_B,                      "ldabsb",    OperandKind::Imm,       IsaVersion::V1),
+    op(LD_ABS_H,                      "ldabsh",    OperandKind::Imm,       IsaVersion::V1),
+    op(LD_ABS_W,                      "ldabsw",    OperandKind::Imm,       IsaVersion::V1),
+    op(LD_ABS_DW,                     "ldabsdw",   OperandKind::Imm,       IsaVersion::V1),
+    op(LD_IND_B,                      "ldindb",    OperandKind::SrcImm,    IsaVersion::V1),
+    op(LD_IND_H,                      "ldindh",    OperandKind::SrcImm,    IsaVersion::V1),
+    op(LD_IND_W,                      "ldindw",    OperandKind::SrcImm,    IsaVersion::V1),
+    op(LD_IND_DW,                     "ldinddw",   OperandKind::SrcImm,    IsaVersion::V1),
+    op(LD_DW_IMM,                     "lddw",      OperandKind::DstImm64,  IsaVersion::V1),
+
+    // BPF_LDX class
+    op(LD_B_REG,                      "ldxb",      OperandKind::DstMem,    IsaVersion::V1),
+    op(LD_H_REG,                      "ldxh",      OperandKind::DstMem,    IsaVersion::V1),
+    op(LD_W_REG,                      "ldxw",      OperandKind::DstMem,    IsaVersion::V1),
+    op(LD_DW_REG,                     "ldxdw",     OperandKind::DstMem,    IsaVersion::V1),
+    op(LD_B_SX,                       "ldxsb",     OperandKind::DstMem,    IsaVersion::V4),
+    op(LD_H_SX,                       "ldxsh",     OperandKind::DstMem,    IsaVersion::V4),
+    op(LD_W_SX,                       "ldxsw",     OperandKind::DstMem,    IsaVersion::V4),
+
+    // BPF_ST class
+    op(ST_B_IMM,                      "stb",       OperandKind::MemImm,    IsaVersion::V1),
+    op(ST_H_IMM,                      "sth",       OperandKind::MemImm,    IsaVersion::V1),
+    op(ST_W_IMM,                      "stw",       OperandKind::MemImm,    IsaVersion::V1),
+    op(ST_DW_IMM,                     "stdw",      OperandKind::MemImm,    IsaVersion::V1),
+
+    // BPF_STX class
+    op(ST_B_REG,                      "stxb",      OperandKind::MemSrc,    IsaVersion::V1),
+    op(ST_H_REG,                      "stxh",      OperandKind::MemSrc,    IsaVersion::V1),
+    op(ST_W_REG,                      "stxw",      OperandKind::MemSrc,    IsaVersion::V1),
+    op(ST_DW_REG,                     "stxdw",     OperandKind::MemSrc,    IsaVersion::V1),
+    op(ST_W_XADD,                     "stxxaddw",  OperandKind::MemSrc,    IsaVersion::V1),
+    op(ST_DW_XADD,                    "stxxadddw", OperandKind::MemSrc,    IsaVersion::V1),
+
+    // BPF_ALU class
+    op(ADD32_IMM,                     "add32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(ADD32_REG,                     "add32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(SUB32_IMM,                     "sub32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(SUB32_REG,                     "sub32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(MUL32_IMM,                     "mul32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MUL32_REG,                     "mul32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(DIV32_IMM,                     "div32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(DIV32_REG,                     "div32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(OR32_IMM,                      "or32",      OperandKind::DstImm,    IsaVersion::V1),
+    op(OR32_REG,                      "or32",      OperandKind::DstSrc,    IsaVersion::V1),
+    op(AND32_IMM,                     "and32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(AND32_REG,                     "and32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(LSH32_IMM,                     "lsh32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(LSH32_REG,                     "lsh32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(RSH32_IMM,                     "rsh32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(RSH32_REG,                     "rsh32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(NEG32,                         "neg32",     OperandKind::Dst,       IsaVersion::V1),
+    op(MOD32_IMM,                     "mod32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MOD32_REG,                     "mod32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(XOR32_IMM,                     "xor32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(XOR32_REG,                     "xor32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(MOV32_IMM,                     "mov32",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MOV32_REG,                     "mov32",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(ARSH32_IMM,                    "arsh32",    OperandKind::DstImm,    IsaVersion::V1),
+    op(ARSH32_REG,                    "arsh32",    OperandKind::DstSrc,    IsaVersion::V1),
+    op(LE,                            "le",        OperandKind::DstWidth,  IsaVersion::V1),
+    op(BE,                            "be",        OperandKind::DstWidth,  IsaVersion::V1),
+
+    // BPF_ALU64 class
+    op(ADD64_IMM,                     "add64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(ADD64_REG,                     "add64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(SUB64_IMM,                     "sub64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(SUB64_REG,                     "sub64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(MUL64_IMM,                     "mul64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MUL64_REG,                     "mul64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(DIV64_IMM,                     "div64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(DIV64_REG,                     "div64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(OR64_IMM,                      "or64",      OperandKind::DstImm,    IsaVersion::V1),
+    op(OR64_REG,                      "or64",      OperandKind::DstSrc,    IsaVersion::V1),
+    op(AND64_IMM,                     "and64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(AND64_REG,                     "and64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(LSH64_IMM,                     "lsh64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(LSH64_REG,                     "lsh64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(RSH64_IMM,                     "rsh64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(RSH64_REG,                     "rsh64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(NEG64,                         "neg64",     OperandKind::Dst,       IsaVersion::V1),
+    op(MOD64_IMM,                     "mod64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MOD64_REG,                     "mod64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(XOR64_IMM,                     "xor64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(XOR64_REG,                     "xor64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(MOV64_IMM,                     "mov64",     OperandKind::DstImm,    IsaVersion::V1),
+    op(MOV64_REG,                     "mov64",     OperandKind::DstSrc,    IsaVersion::V1),
+    op(ARSH64_IMM,                    "arsh64",    OperandKind::DstImm,    IsaVersion::V1),
+    op(ARSH64_REG,                    "arsh64",    OperandKind::DstSrc,    IsaVersion::V1),
+    op(BSWAP,                         "bswap",     OperandKind::DstWidth,  IsaVersion::V4),
+
+    // BPF_JMP class
+    op(JA,                            "ja",        OperandKind::Off,       IsaVersion::V1),
+    op(JEQ_IMM,                       "jeq",       OperandKind::DstImmOff, IsaVersion::V1),
+    op(JEQ_REG,                       "jeq",       OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JGT_IMM,                       "jgt",       OperandKind::DstImmOff, IsaVersion::V1),
+    op(JGT_REG,                       "jgt",       OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JGE_IMM,                       "jge",       OperandKind::DstImmOff, IsaVersion::V1),
+    op(JGE_REG,                       "jge",       OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JSET_IMM,                      "jset",      OperandKind::DstImmOff, IsaVersion::V1),
+    op(JSET_REG,                      "jset",      OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JNE_IMM,                       "jne",       OperandKind::DstImmOff, IsaVersion::V1),
+    op(JNE_REG,                       "jne",       OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JSGT_IMM,                      "jsgt",      OperandKind::DstImmOff, IsaVersion::V1),
+    op(JSGT_REG,                      "jsgt",      OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JSGE_IMM,                      "jsge",      OperandKind::DstImmOff, IsaVersion::V1),
+    op(JSGE_REG,                      "jsge",      OperandKind::DstSrcOff, IsaVersion::V1),
+    op(JLT_IMM,                       "jlt",       OperandKind::DstImmOff, IsaVersion::V2),
+    op(JLT_REG,                       "jlt",       OperandKind::DstSrcOff, IsaVersion::V2),
+    op(JLE_IMM,                       "jle",       OperandKind::DstImmOff, IsaVersion::V2),
+    op(JLE_REG,                       "jle",       OperandKind::DstSrcOff, IsaVersion::V2),
+    op(JSLT_IMM,                      "jslt",      OperandKind::DstImmOff, IsaVersion::V2),
+    op(JSLT_REG,                      "jslt",      OperandKind::DstSrcOff, IsaVersion::V2),
+    op(JSLE_IMM,                      "jsle",      OperandKind::DstImmOff, IsaVersion::V2),
+    op(JSLE_REG,                      "jsle",      OperandKind::DstSrcOff, IsaVersion::V2),
+    op(CALL,                          "call",      OperandKind::Imm,       IsaVersion::V1),
+    op(TAIL_CALL,                     "tail_call", OperandKind::None,      IsaVersion::V1),
+    op(EXIT,                          "exit",      OperandKind::None,      IsaVersion::V1),
+
+    // BPF_JMP32 class
+    op(JA32,                          "ja32",      OperandKind::Off,       IsaVersion::V4),
+    op(BPF_JMP32 | BPF_K | BPF_JEQ,   "jeq32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JEQ,   "jeq32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JGT,   "jgt32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JGT,   "jgt32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JGE,   "jge32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JGE,   "jge32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JSET,  "jset32",    OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JSET,  "jset32",    OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JNE,   "jne32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JNE,   "jne32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JSGT,  "jsgt32",    OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JSGT,  "jsgt32",    OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JSGE,  "jsge32",    OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JSGE,  "jsge32",    OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JLT,   "jlt32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JLT,   "jlt32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JLE,   "jle32",     OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JLE,   "jle32",     OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JSLT,  "jslt32",    OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JSLT,  "jslt32",    OperandKind::DstSrcOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_K | BPF_JSLE,  "jsle32",    OperandKind::DstImmOff, IsaVersion::V3),
+    op(BPF_JMP32 | BPF_X | BPF_JSLE,  "jsle32",    OperandKind::DstSrcOff, IsaVersion::V3),
+];
+
+/// Return the metadata of operation code `opc`, or `None` if it is not a known operation code.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf::{self, OperandKind};
+///
+/// let info = ebpf::opcode_info(ebpf::JEQ_IMM).unwrap();
+/// assert_eq!(info.mnemonic, "jeq");
+/// assert_eq!(info.operands, OperandKind::DstImmOff);
+/// assert!(ebpf::opcode_info(0xff).is_none());
+/// ```
+pub fn opcode_info(opc: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.opc == opc)
+}
+
+/// Return the class of operation code `opc`, for example `BPF_ALU64`.
+pub fn class(opc: u8) -> u8 {
+    opc & BPF_CLS_MASK
+}
+
+/// Return the operands of the instructions with operation code `opc`, or `None` if it is not a
+/// known operation code.
+pub fn operand_kind(opc: u8) -> Option<OperandKind> {
+    opcode_info(opc).map(|info| info.operands)
+}
+
+/// Return `true` if `opc` is a known operation code loading from memory: the `ldx*`
+/// instructions, and the legacy packet loads. `lddw` loads an immediate, and is not one of them.
+pub fn is_load(opc: u8) -> bool {
+    opcode_info(opc).is_some() && match class(opc) {
+        BPF_LDX => true,
+        BPF_LD  => opc != LD_DW_IMM,
+        _       => false,
+    }
+}
+
+/// Return `true` if `opc` is a known operation code storing to memory, atomic operations included.
+pub fn is_store(opc: u8) -> bool {
+    opcode_info(opc).is_some() && (class(opc) == BPF_ST || class(opc) == BPF_STX)
+}
+
+/// Return `true` if `opc` is a known arithmetic or bitwise operation code, of class `BPF_ALU` or
+/// `BPF_ALU64`.
+pub fn is_alu(opc: u8) -> bool {
+    opcode_info(opc).is_some() && (class(opc) == BPF_ALU || class(opc) == BPF_ALU64)
+}
+
+/// Return `true` if `opc` is a known jump operation code, conditional or not. Calls and `exit`
+/// are not jumps.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf;
+///
+/// assert!(ebpf::is_jump(ebpf::JA) && ebpf::is_jump(ebpf::JSGT_REG));
+/// assert!(!ebpf::is_jump(ebpf::CALL) && !ebpf::is_jump(ebpf::EXIT));
+/// assert!(!ebpf::is_conditional_jump(ebpf::JA32));
+/// ```
+pub fn is_jump(opc: u8) -> bool {
+    matches!(operand_kind(opc), Some(OperandKind::Off)) || is_conditional_jump(opc)
+}
+
+/// Return `true` if `opc` is a known conditional jump operation code.
+pub fn is_conditional_jump(opc: u8) -> bool {
+    matches!(operand_kind(opc), Some(OperandKind::DstImmOff | OperandKind::DstSrcOff))
+}
+
 /// An eBPF instruction.
 ///
 /// See <https://www.kernel.org/doc/Documentation/networking/filter.txt> for the Linux kernel
 /// documentation about eBPF, or <https://github.com/iovisor/bpf-docs/blob/master/eBPF.md> for a
 /// more concise version.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Insn {
     /// Operation code.
     pub opc: u8,
@@ -375,6 +896,112 @@ pub struct Insn {
     pub imm: i32,
 }
 
+impl Insn {
+    /// Encode the instruction into the 8 bytes used in programs: the reverse of `get_insn()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::ebpf;
+    ///
+    /// let insn = ebpf::Insn { opc: ebpf::ADD64_IMM, dst: 1, src: 0, off: 0, imm: 0x10 };
+    /// let bytes = insn.to_array();
+    /// assert_eq!(bytes, [0x07, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00]);
+    /// assert_eq!(ebpf::get_insn(&bytes, 0), insn);
+    /// ```
+    pub fn to_array(&self) -> [u8; INSN_SIZE] {
+        let off = self.off.to_le_bytes();
+        let imm = self.imm.to_le_bytes();
+        [self.opc, (self.src << 4) | (self.dst & 0x0f), off[0], off[1], imm[0], imm[1], imm[2], imm[3]]
+    }
+
+    /// Encode the instruction into the 8 bytes used in programs, as a vector, which can be
+    /// appended to a program with `extend()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::ebpf;
+    ///
+    /// let mut prog = vec![];
+    /// prog.extend(ebpf::Insn { opc: ebpf::MOV64_IMM, dst: 0, src: 0, off: 0, imm: 1 }.to_vec());
+    /// prog.extend(ebpf::Insn { opc: ebpf::EXIT, dst: 0, src: 0, off: 0, imm: 0 }.to_vec());
+    /// assert_eq!(prog, vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    /// ]);
+    /// ```
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.to_array().to_vec()
+    }
+}
+
+/// An iterator over the instructions of an eBPF program, in the order of the program. There is
+/// one instruction per slot of 8 bytes: `lddw` yields two instructions, the second one holding the
+/// upper 32 bits of the immediate.
+///
+/// # Examples
+///
+/// Walking a program, and rewriting it with the instructions changed:
+///
+/// ```
+/// use rbpf::ebpf;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(ebpf::InsnIter::new(&prog).len(), 3);
+///
+/// // Replace additions with subtractions.
+/// let rewritten: Vec<u8> = ebpf::InsnIter::new(&prog).flat_map(|mut insn| {
+///     if insn.opc == ebpf::ADD64_IMM {
+///         insn.opc = ebpf::SUB64_IMM;
+///     }
+///     insn.to_array()
+/// }).collect();
+/// assert_eq!(ebpf::get_insn(&rewritten, 1).opc, ebpf::SUB64_IMM);
+/// ```
+#[derive(Debug, Clone)]
+pub struct InsnIter<'a> {
+    prog: &'a [u8],
+    idx:  usize,
+}
+
+impl<'a> InsnIter<'a> {
+    /// Create an iterator over the instructions of `prog`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of the program is not a multiple of the size of an instruction.
+    pub fn new(prog: &'a [u8]) -> InsnIter<'a> {
+        if !prog.len().is_multiple_of(INSN_SIZE) {
+            panic!("Error: eBPF program length must be a multiple of {:?} octets", INSN_SIZE);
+        }
+        InsnIter { prog, idx: 0 }
+    }
+}
+
+impl Iterator for InsnIter<'_> {
+    type Item = Insn;
+
+    fn next(&mut self) -> Option<Insn> {
+        if self.idx * INSN_SIZE >= self.prog.len() {
+            return None;
+        }
+        self.idx += 1;
+        Some(get_insn(self.prog, self.idx - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.prog.len() / INSN_SIZE - self.idx;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for InsnIter<'_> {}
+
 /// Get the instruction at `idx` of an eBPF program. `idx` is the index (number) of the
 /// instruction (not a byte offset). The first instruction has index 0.
 ///
@@ -407,24 +1034,23 @@ pub struct Insn {
 ///     ];
 /// let insn = ebpf::get_insn(&prog, 1);
 /// ```
-pub fn get_insn(prog: &std::vec::Vec<u8>, idx: usize) -> Insn {
+pub fn get_insn(prog: &[u8], idx: usize) -> Insn {
     // This guard should not be needed in most cases, since the verifier already checks the program
     // size, and indexes should be fine in the interpreter/JIT. But this function is publicly
-    // available and user can call it with any `idx`, so we have to check anyway.
-    if (idx + 1) * INSN_SIZE > prog.len() {
-        panic!("Error: cannot reach instruction at index {:?} in program containing {:?} bytes",
-               idx, prog.len());
-    }
-    let insn = Insn {
-        opc:  prog[INSN_SIZE * idx],
-        dst:  prog[INSN_SIZE * idx + 1] & 0x0f,
-        src: (prog[INSN_SIZE * idx + 1] & 0xf0) >> 4,
-        off: unsafe {
-            let x = prog.as_ptr().offset((INSN_SIZE * idx + 2) as isize) as *const i16; *x
-        },
-        imm: unsafe {
-            let x = prog.as_ptr().offset((INSN_SIZE * idx + 4) as isize) as *const i32; *x
-        },
+    // available and user can call it with any `idx`, so we have to check anyway. The offset of the
+    // instruction is computed without overflowing, for huge values of `idx`.
+    let start = idx.checked_mul(INSN_SIZE);
+    let bytes = match start.and_then(|start| prog.get(start..start.checked_add(INSN_SIZE)?)) {
+        Some(bytes) => bytes,
+        None        => panic!("Error: cannot reach instruction at index {:?} in program containing {:?} bytes",
+                              idx, prog.len()),
     };
-    insn
+    // Programs are little-endian, whatever the endianness of the host.
+    Insn {
+        opc:  bytes[0],
+        dst:  bytes[1] & 0x0f,
+        src: (bytes[1] & 0xf0) >> 4,
+        off:  i16::from_le_bytes([bytes[2], bytes[3]]),
+        imm:  i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+    }
 }
@@ -0,0 +1,187 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module checks whether two eBPF programs behave the same, by running them on the same
+//! inputs and comparing the results.
+//!
+//! This is useful when refactoring or optimizing a program, or when changing the compiler or the
+//! generator producing it: the old and the new versions of the program can be run over a corpus
+//! of packets, or over pseudo-random inputs, with `EquivChecker`. The first input for which the
+//! programs return different values, or leave the packet in a different state, is reported along
+//! with the first instruction at which the two executions took different paths.
+//!
+//! Programs are interpreted, with the input as packet data and no metadata buffer (as with
+//! `EbpfVmRaw`): R1 points to the input.
+
+use std::collections::HashMap;
+
+use ebpf;
+use interpreter;
+use trace::{TraceEntry, TraceLog, Tracer};
+use verifier;
+
+/// A difference between the executions of two programs on the same input, as found by
+/// `EquivChecker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The input on which the programs differ.
+    pub input:   Vec<u8>,
+    /// The values returned by the first and by the second program.
+    pub results: (u64, u64),
+    /// The contents of the input after each program ran, since programs can modify it.
+    pub outputs: (Vec<u8>, Vec<u8>),
+    /// Index, in the sequence of instructions executed by each program, of the first instruction
+    /// that differs between the two executions.
+    pub step:    usize,
+    /// The instructions executed by the first and by the second program at `step`. `None` for a
+    /// program that had already exited.
+    pub insns:   (Option<TraceEntry>, Option<TraceEntry>),
+}
+
+/// Runs two programs on the same inputs, and reports the first input on which they differ.
+///
+/// Both programs use the same helpers.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::equivalence::EquivChecker;
+///
+/// // Both return the first byte of the input multiplied by 4.
+/// let prog1 = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x27, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mul64 r0, 4
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let prog2 = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x67, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // lsh64 r0, 2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// // Returns the first byte of the input multiplied by 4, or 0 if it is 0xff.
+/// let prog3 = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x15, 0x00, 0x02, 0x00, 0xff, 0x00, 0x00, 0x00, // jeq r0, 0xff, +2
+///     0x67, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // lsh64 r0, 2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let checker = EquivChecker::new(&prog1, &prog2);
+/// assert!(checker.check_random(4, 1000, 0x2a).is_none());
+///
+/// let checker = EquivChecker::new(&prog2, &prog3);
+/// let corpus = (0..=255).map(|b| vec![b]);
+/// let divergence = checker.check_inputs(corpus).unwrap();
+/// assert_eq!(divergence.input, vec![0xff]);
+/// assert_eq!(divergence.results, (0x3fc, 0));
+/// // The programs run different instructions after the first one.
+/// assert_eq!(divergence.step, 1);
+/// assert_eq!(divergence.insns.0.unwrap().insn.opc, rbpf::ebpf::LSH64_IMM);
+/// assert_eq!(divergence.insns.1.unwrap().insn.opc, rbpf::ebpf::JEQ_IMM);
+/// ```
+pub struct EquivChecker<'a> {
+    progs:   (&'a [u8], &'a [u8]),
+    helpers: HashMap<u32, ebpf::Helper>,
+}
+
+// A xorshift64* generator, for reproducible pseudo-random inputs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl<'a> EquivChecker<'a> {
+
+    /// Create a checker comparing programs `prog_a` and `prog_b`.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in one of the programs.
+    pub fn new(prog_a: &'a [u8], prog_b: &'a [u8]) -> EquivChecker<'a> {
+        verifier::check(&prog_a.to_vec());
+        verifier::check(&prog_b.to_vec());
+        EquivChecker {
+            progs:   (prog_a, prog_b),
+            helpers: HashMap::new(),
+        }
+    }
+
+    /// Register a helper function, available to both programs. See
+    /// `EbpfVmMbuff::register_helper()`.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Run both programs on each of the `inputs`, in order, and return the first divergence
+    /// found, if any.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same execution errors as `EbpfVmRaw::prog_exec()`, for each program.
+    pub fn check_inputs<I: IntoIterator<Item = Vec<u8>>>(&self, inputs: I) -> Option<Divergence> {
+        inputs.into_iter().filter_map(|input| self.compare(input)).next()
+    }
+
+    /// Run both programs on `count` pseudo-random inputs of `input_len` bytes, and return the
+    /// first divergence found, if any. The inputs only depend on `seed`, so that a divergence can
+    /// be reproduced.
+    ///
+    /// About a quarter of the bytes of the inputs are zeroes, the other ones are drawn uniformly.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same execution errors as `EbpfVmRaw::prog_exec()`, for each program.
+    pub fn check_random(&self, input_len: usize, count: usize, seed: u64) -> Option<Divergence> {
+        // The state of the generator must not be zero.
+        let mut rng = Rng(seed | 1);
+        let inputs = (0..count).map(|_| (0..input_len).map(|_| match rng.next() {
+            r if r % 4 == 0 => 0,
+            r               => (r >> 8) as u8,
+        }).collect());
+        self.check_inputs(inputs)
+    }
+
+    fn run<'b>(&'b self, prog: &'b [u8], input: &[u8], tracer: Option<&'b mut dyn Tracer>) -> (u64, Vec<u8>) {
+        let mem = input.to_vec();
+        let options = interpreter::Options { tracer, ..Default::default() };
+        let res = interpreter::execute_program(prog, &mem, &[], &self.helpers, options);
+        (res, mem)
+    }
+
+    fn compare(&self, input: Vec<u8>) -> Option<Divergence> {
+        let (res_a, out_a) = self.run(self.progs.0, &input, None);
+        let (res_b, out_b) = self.run(self.progs.1, &input, None);
+        if res_a == res_b && out_a == out_b {
+            return None;
+        }
+
+        // Run again, tracing the executions, to find where they start to differ.
+        let (mut log_a, mut log_b) = (TraceLog::new(), TraceLog::new());
+        self.run(self.progs.0, &input, Some(&mut log_a));
+        self.run(self.progs.1, &input, Some(&mut log_b));
+        let (trace_a, trace_b) = (log_a.entries(), log_b.entries());
+        let step = trace_a.iter().zip(trace_b.iter())
+            .position(|(a, b)| a.insn_ptr != b.insn_ptr || a.insn != b.insn)
+            .unwrap_or_else(|| trace_a.len().min(trace_b.len()));
+
+        Some(Divergence {
+            input,
+            results: (res_a, res_b),
+            outputs: (out_a, out_b),
+            step,
+            insns:   (trace_a.get(step).cloned(), trace_b.get(step).cloned()),
+        })
+    }
+}
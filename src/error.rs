@@ -0,0 +1,373 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module defines the errors returned by the fallible versions of the functions of the
+//! crate, such as `verifier::try_check()` or `EbpfVmMbuff::try_prog_exec()`.
+//!
+//! The other functions panic instead, with the message given by the `Display` implementation of
+//! the error, completed with source locations when line information is attached to the VM, and
+//! with the faulty instruction and the instructions preceding it (see `InsnContext`).
+
+use std::error::Error;
+use std::fmt;
+
+use btf::LineInfo;
+use disassembler;
+use ebpf;
+use trace::AccessKind;
+use ProgramMeta;
+
+/// An error found when verifying, compiling or running an eBPF program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EbpfError {
+    /// The verifier rejected the program. The message describes the first error found.
+    VerifierError(String),
+    /// The program divided by zero, at instruction `pc`.
+    DivideByZero {
+        /// Index of the instruction.
+        pc: usize,
+    },
+    /// The program accessed memory out of the bounds of the metadata buffer, packet data and
+    /// stack, with a load or store, or through a helper run by the interpreter.
+    OutOfBounds {
+        /// Index of the instruction.
+        pc:          usize,
+        /// Whether the memory was read or written.
+        kind:        AccessKind,
+        /// Address of the first byte accessed.
+        addr:        u64,
+        /// Number of bytes accessed.
+        len:         usize,
+        /// Addresses and sizes of the memory areas available to the program.
+        region_info: String,
+    },
+    /// The program jumped out of the program, or reached its end without exiting. The verifier
+    /// rejects such programs, but programs may be run without being verified.
+    JumpOutOfBounds {
+        /// Index of the instruction.
+        pc:     usize,
+        /// Index of the instruction the program would execute next.
+        target: i64,
+    },
+    /// The program ran an instruction that the interpreter does not support: one of the legacy
+    /// packet loads `LD_ABS` and `LD_IND`, an atomic add (`XADD`), the tail call opcode, a
+    /// BPF-to-BPF call, a byte swap of an invalid width, or an unknown opcode. The verifier
+    /// rejects such programs, but programs may be run without being verified.
+    UnsupportedInstruction {
+        /// Index of the instruction.
+        pc:  usize,
+        /// Operation code of the instruction.
+        opc: u8,
+    },
+    /// The program called a helper function that is not registered.
+    UnknownHelper {
+        /// Key of the helper.
+        key: u32,
+    },
+    /// The program referenced a map that is not registered, with a `lddw` instruction, see
+    /// `ebpf::BPF_PSEUDO_MAP_FD`, or the value of a map that is not an array map, see
+    /// `ebpf::BPF_PSEUDO_MAP_VALUE`.
+    UnknownMap {
+        /// Index of the instruction.
+        pc: usize,
+        /// Identifier of the map.
+        id: u32,
+    },
+    /// A helper registered with `EbpfVmMbuff::register_fallible_helper()` returned an error,
+    /// aborting the execution.
+    HelperFailed {
+        /// Index of the instruction.
+        pc:    usize,
+        /// Key of the helper.
+        key:   u32,
+        /// The error returned by the helper.
+        error: HelperError,
+    },
+    /// The helper hook of the VM denied a call to a helper, see `helpers::HookVerdict::Deny`.
+    HelperDenied {
+        /// Index of the instruction.
+        pc:  usize,
+        /// Key of the helper.
+        key: u32,
+    },
+    /// The JIT-compiler could not compile the program.
+    JitError(String),
+    /// The execution exceeded a limit set on the VM. The message describes the limit.
+    ExceededLimit(String),
+    /// The JIT-compiled program raised a memory or arithmetic fault, caught because the VM was
+    /// set to do so with `EbpfVmMbuff::set_catch_faults()`.
+    MemoryFault {
+        /// Address of the faulty native instruction.
+        native_pc: usize,
+        /// Index of the eBPF instruction it was compiled from.
+        ebpf_pc:   usize,
+    },
+    /// The program returned a value that is not a valid action for its type, see
+    /// `ebpf::XdpAction` and `ebpf::TcAction`.
+    InvalidReturnValue {
+        /// The value returned by the program.
+        ret:       u64,
+        /// The type of the program: "XDP" or "tc".
+        prog_type: &'static str,
+    },
+    /// An execution suspended by `EbpfVmMbuff::try_prog_exec_metered()` was resumed by another VM,
+    /// or on other packet data or another metadata buffer than it started on. The message
+    /// describes the mismatch. The suspended execution is dropped.
+    InvalidResume(String),
+    /// The execution of the program would nest too many executions on the current thread, see
+    /// `EbpfVmMbuff::set_max_call_depth()`. The program did not run.
+    CallDepthExceeded {
+        /// The maximum number of nested executions.
+        limit: usize,
+    },
+    /// The program loaded bytes of the stack that it never wrote, detected because memory
+    /// poisoning is enabled, see `EbpfVmMbuff::set_memory_poisoning()`.
+    UninitializedRead {
+        /// Index of the instruction.
+        pc:  usize,
+        /// Offset of the first byte loaded from the frame pointer, R10.
+        off: i64,
+        /// Number of bytes loaded.
+        len: usize,
+    },
+    /// The analysis of the program by the verifier ran out of its budget, or was cancelled, see
+    /// `verifier::check_bounds_within()`. The program was neither accepted nor rejected.
+    VerificationTimedOut {
+        /// Number of steps of the analysis completed, see `verifier::VerifyBudget::max_steps`.
+        steps:     u64,
+        /// Whether the analysis was cancelled, rather than out of time or steps.
+        cancelled: bool,
+    },
+    /// An error of a program run by a VM with a name or tags, see `EbpfVmMbuff::set_name()`. The
+    /// message of the error is prefixed with them.
+    InProgram {
+        /// The name and tags of the program.
+        program: ProgramMeta,
+        /// The error itself.
+        error:   Box<EbpfError>,
+    },
+}
+
+/// An error returned by a helper registered with `EbpfVmMbuff::register_fallible_helper()`, to
+/// abort the execution of the program. The VM returns it in `EbpfError::HelperFailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HelperError {
+    /// The message of the error.
+    pub message: String,
+}
+
+impl HelperError {
+    /// Create an error with message `message`.
+    pub fn new(message: &str) -> HelperError {
+        HelperError { message: message.to_string() }
+    }
+}
+
+impl fmt::Display for HelperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for HelperError {}
+
+/// The number of instructions preceding the faulty instruction in an `InsnContext`.
+pub const CONTEXT_INSNS: usize = 3;
+
+/// The instruction at which an error occurred, decoded, and the instructions preceding it in the
+/// program, see `EbpfError::insn_context()`.
+///
+/// It is displayed as the disassembled instructions, one per line, the faulty instruction last
+/// with its fields:
+///
+/// ```text
+///     #1: mov64 r2, 0x0
+///     #2: add64 r0, 0x1
+///  => #3: div64 r0, r2 (opc 0x3f, dst r0, src r2, off 0, imm 0x0)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsnContext {
+    /// Index of the faulty instruction.
+    pub pc:       usize,
+    /// The faulty instruction.
+    pub insn:     ebpf::Insn,
+    /// The faulty instruction, disassembled.
+    pub desc:     String,
+    /// The instructions preceding the faulty one in the program, up to `CONTEXT_INSNS`, with
+    /// their indices, disassembled. These are not necessarily the instructions executed before
+    /// it, if it is the target of a jump.
+    pub previous: Vec<(usize, String)>,
+}
+
+impl fmt::Display for InsnContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(pc, ref desc) in self.previous.iter() {
+            writeln!(f, "    #{}: {}", pc, desc)?;
+        }
+        let insn = &self.insn;
+        write!(f, " => #{}: {} (opc {:#04x}, dst r{}, src r{}, off {}, imm {:#x})",
+               self.pc, self.desc, insn.opc, insn.dst, insn.src, insn.off, insn.imm)
+    }
+}
+
+impl EbpfError {
+    /// The index of the instruction at which the error occurred, if known.
+    pub fn pc(&self) -> Option<usize> {
+        match *self {
+            EbpfError::DivideByZero { pc } |
+            EbpfError::OutOfBounds { pc, .. } |
+            EbpfError::JumpOutOfBounds { pc, .. } |
+            EbpfError::UnsupportedInstruction { pc, .. } |
+            EbpfError::UnknownMap { pc, .. } |
+            EbpfError::HelperFailed { pc, .. } |
+            EbpfError::HelperDenied { pc, .. } |
+            EbpfError::MemoryFault { ebpf_pc: pc, .. } |
+            EbpfError::UninitializedRead { pc, .. } => Some(pc),
+            EbpfError::InProgram { ref error, .. } => error.pc(),
+            _ => None,
+        }
+    }
+
+    /// The instruction of `prog` at which the error occurred, decoded, and the instructions
+    /// preceding it, if the error has an index of instruction within the program. `prog` must
+    /// be the program that raised the error: after a tail call, the index refers to the program
+    /// jumped to.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+    ///     0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+    ///     0x3f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r2
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    /// let err = vm.try_prog_exec().unwrap_err();
+    /// assert_eq!(err.to_string(), "Error: division by 0 (insn #2)");
+    ///
+    /// let context = err.insn_context(&prog).unwrap();
+    /// assert_eq!(context.insn.opc, rbpf::ebpf::DIV64_REG);
+    /// assert_eq!(context.desc, "div64 r0, r2");
+    /// assert_eq!(context.previous, vec![(0, "mov64 r2, 0x0".to_string()), (1, "add64 r0, 0x1".to_string())]);
+    /// ```
+    pub fn insn_context(&self, prog: &[u8]) -> Option<InsnContext> {
+        let pc = self.pc()?;
+        if (pc + 1) * ebpf::INSN_SIZE > prog.len() || !prog.len().is_multiple_of(ebpf::INSN_SIZE) {
+            return None;
+        }
+        let insns = disassembler::to_insn_vec(prog);
+        let idx = insns.iter().position(|insn| insn.ptr >= pc).unwrap_or(insns.len());
+        let desc = match insns.get(idx) {
+            Some(insn) if insn.ptr == pc => insn.desc.clone(),
+            // The second half of a `lddw` instruction.
+            _                            => "(second half of lddw)".to_string(),
+        };
+        let previous = insns[idx.saturating_sub(CONTEXT_INSNS)..idx].iter()
+            .map(|insn| (insn.ptr, insn.desc.clone()))
+            .collect();
+        Some(InsnContext { pc, insn: ebpf::get_insn(prog, pc), desc, previous })
+    }
+
+    // The message of the error as with `message()`, followed by the faulty instruction of `prog`
+    // and the instructions preceding it, if known. This is the message of the panics of the VMs.
+    pub(crate) fn report(&self, prog: &[u8], line_info: Option<&LineInfo>) -> String {
+        match self.insn_context(prog) {
+            Some(context) => format!("{}\n{}", self.message(line_info), context),
+            None          => self.message(line_info),
+        }
+    }
+
+    // The error, attributed to `program` unless it has neither a name nor tags.
+    pub(crate) fn in_program(self, program: &ProgramMeta) -> EbpfError {
+        match program.is_empty() {
+            true  => self,
+            false => EbpfError::InProgram { program: program.clone(), error: Box::new(self) },
+        }
+    }
+
+    // The message of the error, with the function and the source location of the instruction if
+    // `line_info` provides them.
+    pub(crate) fn message(&self, line_info: Option<&LineInfo>) -> String {
+        let source = |pc: usize| {
+            let mut source = String::new();
+            if let Some(func) = line_info.and_then(|info| info.function(pc)) {
+                source.push_str(&format!(" in {}", func));
+            }
+            if let Some(loc) = line_info.and_then(|info| info.lookup(pc)) {
+                source.push_str(&format!(" at {}", loc));
+            }
+            source
+        };
+        let location = |pc: usize| format!("insn #{:?}{}", pc, source(pc));
+        match *self {
+            EbpfError::VerifierError(ref msg) => msg.clone(),
+            EbpfError::DivideByZero { pc } => format!("Error: division by 0 ({})", location(pc)),
+            EbpfError::OutOfBounds { pc, kind, addr, len, ref region_info } => {
+                // As in uBPF, the instruction number reported is the one following the faulty
+                // instruction; the source location is that of the faulty instruction itself.
+                let source = source(pc);
+                let access = match kind {
+                    AccessKind::Load  => "load",
+                    AccessKind::Store => "store",
+                };
+                format!("Error: out of bounds memory {} (insn #{:?}{}), addr {:#x}, size {:?}\n{}",
+                        access, pc + 1, source, addr, len, region_info)
+            },
+            EbpfError::JumpOutOfBounds { pc, target } => {
+                format!("Error: jump out of code to #{:?} ({})", target, location(pc))
+            },
+            EbpfError::UnsupportedInstruction { pc, opc } => {
+                format!("Error: unsupported instruction, opcode {:#04x} ({})", opc, location(pc))
+            },
+            EbpfError::UnknownHelper { key } => {
+                format!("Error: unknown helper function (id: {:#x})", key)
+            },
+            EbpfError::UnknownMap { pc, id } => {
+                format!("Error: reference to unknown map {} ({})", id, location(pc))
+            },
+            EbpfError::HelperFailed { pc, key, ref error } => {
+                format!("Error: helper function {:#x} failed: {} ({})", key, error, location(pc))
+            },
+            EbpfError::HelperDenied { pc, key } => {
+                format!("Error: call to helper function {:#x} denied by hook ({})", key, location(pc))
+            },
+            EbpfError::JitError(ref msg) => msg.clone(),
+            EbpfError::ExceededLimit(ref msg) => msg.clone(),
+            EbpfError::MemoryFault { native_pc, ebpf_pc } => {
+                format!("Error: memory fault in JIT-compiled program at {:#x} ({})", native_pc, location(ebpf_pc))
+            },
+            EbpfError::InvalidReturnValue { ret, prog_type } => {
+                format!("Error: program returned {:#x}, which is not a valid {} action", ret, prog_type)
+            },
+            EbpfError::InvalidResume(ref msg) => msg.clone(),
+            EbpfError::CallDepthExceeded { limit } => {
+                format!("Error: exceeded the maximum call depth of {} nested executions", limit)
+            },
+            EbpfError::UninitializedRead { pc, off, len } => {
+                format!("Error: load of uninitialized stack at r10{:+} ({}), size {:?}", off, location(pc), len)
+            },
+            EbpfError::VerificationTimedOut { steps, cancelled: true } => {
+                format!("[Verifier] Error: verification cancelled after {} steps", steps)
+            },
+            EbpfError::VerificationTimedOut { steps, cancelled: false } => {
+                format!("[Verifier] Error: verification ran out of its budget after {} steps", steps)
+            },
+            EbpfError::InProgram { ref program, ref error } => {
+                format!("[{}] {}", program, error.message(line_info))
+            },
+        }
+    }
+}
+
+impl fmt::Display for EbpfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message(None))
+    }
+}
+
+impl Error for EbpfError {}
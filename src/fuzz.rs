@@ -0,0 +1,436 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module generates random, valid eBPF programs for structured fuzzing, from the raw input
+//! of a fuzzer decoded with the `arbitrary` crate. It is only available with the `fuzz` feature.
+//!
+//! Fuzzing the VM with raw bytes mostly exercises the verifier, since nearly all random programs
+//! are rejected. The programs generated here pass the simple verifier, and they can be run to
+//! completion without errors by the interpreter, on packet data of the configured length:
+//!
+//! * Jumps only go forward, so that programs always terminate.
+//! * Loads and stores only access the packet data (through R1, which is never modified) and the
+//!   first bytes of the stack (through R10), in bounds.
+//! * Divisions and modulos by a register make sure that the divisor is not zero. Shifts by an
+//!   immediate stay below the width of the operation, as the kernel requires, while shifts by a
+//!   register use any amount, masked at runtime.
+//! * All registers, and the stack area in use, are initialized at the start of the program.
+//! * R1 and R10 are never used as operands of arithmetic instructions, comparisons or stores, so
+//!   that the result of a program does not depend on the address of the memory it runs on.
+//!
+//! Any crash, or any difference between the interpreter and the JIT-compiler, on such a program
+//! is a bug. `shrink()` then reduces the program to a smaller one with the same issue.
+//!
+//! With `cargo fuzz`, a fuzz target comparing the interpreter with the JIT-compiler could be:
+//!
+//! ```text
+//! fuzz_target!(|input: rbpf::fuzz::ValidProgram| {
+//!     let mut mem = vec![0xaa; rbpf::fuzz::DEFAULT_MEM_LEN];
+//!     let mut vm = rbpf::EbpfVmRaw::new(&input.prog);
+//!     let expected = vm.prog_exec(&mut mem.clone());
+//!     vm.jit_compile();
+//!     assert_eq!(vm.prog_exec_jit(&mut mem), expected);
+//! });
+//! ```
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use ebpf;
+use ebpf::Insn;
+
+/// Length of the packet data that the programs generated by `ValidProgram` expect.
+pub const DEFAULT_MEM_LEN: usize = 64;
+
+// Size of the stack area used by generated programs, initialized by the prologue.
+const STACK_USED: i16 = 64;
+
+// Registers holding scalar values, that generated instructions can freely read and write.
+const SCALAR_REGS: [u8; 9] = [0, 2, 3, 4, 5, 6, 7, 8, 9];
+
+const ALU_OPS: [u8; 11] = [ebpf::BPF_ADD, ebpf::BPF_SUB, ebpf::BPF_MUL, ebpf::BPF_DIV, ebpf::BPF_OR,
+                           ebpf::BPF_AND, ebpf::BPF_LSH, ebpf::BPF_RSH, ebpf::BPF_MOD,
+                           ebpf::BPF_XOR, ebpf::BPF_ARSH];
+const JMP_OPS: [u8; 7] = [ebpf::BPF_JEQ, ebpf::BPF_JGT, ebpf::BPF_JGE, ebpf::BPF_JSET,
+                          ebpf::BPF_JNE, ebpf::BPF_JSGT, ebpf::BPF_JSGE];
+const SIZES: [(u8, i16); 4] = [(ebpf::BPF_B, 1), (ebpf::BPF_H, 2), (ebpf::BPF_W, 4),
+                               (ebpf::BPF_DW, 8)];
+
+/// A valid program, generated with the default settings of `ProgramGenerator`. Its loads and
+/// stores expect packet data of `DEFAULT_MEM_LEN` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidProgram {
+    /// The bytecode of the program.
+    pub prog: Vec<u8>,
+}
+
+impl<'a> Arbitrary<'a> for ValidProgram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<ValidProgram> {
+        let prog = ProgramGenerator::new().generate(u)?;
+        Ok(ValidProgram { prog })
+    }
+}
+
+/// Generator of valid eBPF programs, see the documentation of the module.
+///
+/// # Examples
+///
+/// ```
+/// extern crate arbitrary;
+/// extern crate rbpf;
+///
+/// use arbitrary::Unstructured;
+/// use rbpf::fuzz::ProgramGenerator;
+///
+/// // Use the bytes provided by the fuzzer instead.
+/// let data: Vec<u8> = (0..1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+///
+/// let generator = ProgramGenerator::new().max_insns(32).mem_len(16);
+/// let prog = generator.generate(&mut Unstructured::new(&data)).unwrap();
+///
+/// rbpf::verifier::check(&prog);
+/// let mut mem = vec![0x2a; 16];
+/// let vm = rbpf::EbpfVmRaw::new(&prog);
+/// vm.prog_exec(&mut mem);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProgramGenerator {
+    max_insns: usize,
+    mem_len:   usize,
+    helpers:   Vec<u32>,
+}
+
+impl Default for ProgramGenerator {
+    fn default() -> ProgramGenerator {
+        ProgramGenerator { max_insns: 64, mem_len: DEFAULT_MEM_LEN, helpers: vec![] }
+    }
+}
+
+// An instruction of the program being generated, before jump offsets are resolved. Most
+// operations are made of a single instruction, with some exceptions: `lddw` takes two slots, and
+// divisions by a register are preceded by an instruction adjusting the register.
+enum Op {
+    Insns(Vec<Insn>),
+    // A jump to the operation of the given index, always forward.
+    Jump(Insn, usize),
+}
+
+impl ProgramGenerator {
+
+    /// Create a generator with default settings: programs of at most 64 operations (an operation
+    /// takes up to two instructions), running on packet data of `DEFAULT_MEM_LEN` bytes, and not
+    /// calling helpers.
+    pub fn new() -> ProgramGenerator {
+        ProgramGenerator::default()
+    }
+
+    /// Set the maximum number of operations in generated programs, not counting the prologue
+    /// initializing registers and stack, and the final `exit`. It is capped so that programs never exceed
+    /// `ebpf::PROG_MAX_INSNS` instructions.
+    pub fn max_insns(mut self, max_insns: usize) -> ProgramGenerator {
+        self.max_insns = max_insns.min((ebpf::PROG_MAX_INSNS - 16) / 2);
+        self
+    }
+
+    /// Set the length of the packet data the programs will run on. Programs do not access packet
+    /// data if `mem_len` is 0. Lengths above 32767 bytes are capped, since offsets are signed
+    /// 16-bit values.
+    pub fn mem_len(mut self, mem_len: usize) -> ProgramGenerator {
+        self.mem_len = mem_len.min(i16::MAX as usize);
+        self
+    }
+
+    /// Let generated programs call the helpers with the given keys. They must be registered in
+    /// the VM running the programs, and accept any arguments.
+    pub fn helpers(mut self, keys: &[u32]) -> ProgramGenerator {
+        self.helpers = keys.to_vec();
+        self
+    }
+
+    /// Generate a program from `u`. Once the data in `u` is exhausted, the program ends.
+    pub fn generate(&self, u: &mut Unstructured) -> arbitrary::Result<Vec<u8>> {
+        // Reading uninitialized registers or stack is not valid: the JIT-compiler does not reset
+        // them, unlike the interpreter.
+        let mut prologue: Vec<Insn> = (1..=STACK_USED / 8)
+            .map(|i| insn(ebpf::ST_DW_IMM, 10, 0, -8 * i, 0))
+            .collect();
+        for reg in SCALAR_REGS.iter() {
+            prologue.push(insn(ebpf::MOV64_IMM, *reg, 0, 0, self.gen_imm(u)?));
+        }
+        let len = u.int_in_range(0..=self.max_insns)?;
+        let mut ops = Vec::with_capacity(len);
+        for i in 0..len {
+            if u.is_empty() {
+                break;
+            }
+            ops.push(self.gen_op(u, i, len)?);
+        }
+        Ok(assemble(prologue, &ops))
+    }
+
+    fn gen_op(&self, u: &mut Unstructured, index: usize, len: usize) -> arbitrary::Result<Op> {
+        let dst = *u.choose(&SCALAR_REGS)?;
+        let src = *u.choose(&SCALAR_REGS)?;
+        let op = match u.int_in_range(0..=13)? {
+            0 ..= 3 => {
+                let class = *u.choose(&[ebpf::BPF_ALU, ebpf::BPF_ALU64])?;
+                let width = if class == ebpf::BPF_ALU { 32 } else { 64 };
+                let alu_op = *u.choose(&ALU_OPS)?;
+                let by_reg: bool = u.arbitrary()?;
+                let reg_op = insn(class | ebpf::BPF_X | alu_op, dst, src, 0, 0);
+                let imm = match alu_op {
+                    ebpf::BPF_LSH | ebpf::BPF_RSH | ebpf::BPF_ARSH => u.int_in_range(0..=width - 1)?,
+                    // The verifier rejects multiplications by an immediate 0 too.
+                    ebpf::BPF_MUL | ebpf::BPF_DIV | ebpf::BPF_MOD => match self.gen_imm(u)? {
+                        0   => 1,
+                        imm => imm,
+                    },
+                    _ => self.gen_imm(u)?,
+                };
+                match alu_op {
+                    // Make sure the divisor is not zero, in 32 bits as in 64 bits.
+                    ebpf::BPF_DIV | ebpf::BPF_MOD if by_reg => {
+                        Op::Insns(vec![insn(ebpf::OR64_IMM, src, 0, 0, 1), reg_op])
+                    },
+                    _ if by_reg => Op::Insns(vec![reg_op]),
+                    _ => Op::Insns(vec![insn(class | ebpf::BPF_K | alu_op, dst, 0, 0, imm)]),
+                }
+            },
+            4 => {
+                let opc = *u.choose(&[ebpf::MOV32_IMM, ebpf::MOV32_REG, ebpf::MOV64_IMM,
+                                      ebpf::MOV64_REG, ebpf::NEG32, ebpf::NEG64])?;
+                let imm = self.gen_imm(u)?;
+                match opc {
+                    ebpf::MOV32_REG | ebpf::MOV64_REG => Op::Insns(vec![insn(opc, dst, src, 0, 0)]),
+                    ebpf::NEG32 | ebpf::NEG64 => Op::Insns(vec![insn(opc, dst, 0, 0, 0)]),
+                    _ => Op::Insns(vec![insn(opc, dst, 0, 0, imm)]),
+                }
+            },
+            5 => {
+                let opc = *u.choose(&[ebpf::LE, ebpf::BE])?;
+                let imm = *u.choose(&[16, 32, 64])?;
+                Op::Insns(vec![insn(opc, dst, 0, 0, imm)])
+            },
+            6 => {
+                let value: u64 = u.arbitrary()?;
+                Op::Insns(vec![insn(ebpf::LD_DW_IMM, dst, 0, 0, value as i32),
+                               insn(0, 0, 0, 0, (value >> 32) as i32)])
+            },
+            7 | 8 => {
+                let (base, off, size) = self.gen_access(u)?;
+                Op::Insns(vec![insn(ebpf::BPF_LDX | ebpf::BPF_MEM | size, dst, base, off, 0)])
+            },
+            9 => {
+                let (base, off, size) = self.gen_access(u)?;
+                match u.arbitrary()? {
+                    true  => Op::Insns(vec![insn(ebpf::BPF_STX | ebpf::BPF_MEM | size, base, src,
+                                                 off, 0)]),
+                    false => Op::Insns(vec![insn(ebpf::BPF_ST | ebpf::BPF_MEM | size, base, 0,
+                                                 off, self.gen_imm(u)?)]),
+                }
+            },
+            10 ..= 12 => {
+                // Any operation after this one, or the final `exit`.
+                let target = u.int_in_range(index + 1..=len)?;
+                let jmp_op = *u.choose(&JMP_OPS)?;
+                match u.int_in_range(0..=2)? {
+                    0 => Op::Jump(insn(ebpf::JA, 0, 0, 0, 0), target),
+                    1 => Op::Jump(insn(ebpf::BPF_JMP | ebpf::BPF_X | jmp_op, dst, src, 0, 0),
+                                  target),
+                    _ => Op::Jump(insn(ebpf::BPF_JMP | ebpf::BPF_K | jmp_op, dst, 0, 0,
+                                       self.gen_imm(u)?), target),
+                }
+            },
+            _ => match self.helpers.is_empty() {
+                true  => Op::Insns(vec![insn(ebpf::EXIT, 0, 0, 0, 0)]),
+                false => {
+                    let key = *u.choose(&self.helpers)?;
+                    Op::Insns(vec![insn(ebpf::CALL, 0, 0, 0, key as i32)])
+                },
+            },
+        };
+        Ok(op)
+    }
+
+    // Immediate values, biased towards the edge cases.
+    fn gen_imm(&self, u: &mut Unstructured) -> arbitrary::Result<i32> {
+        match u.int_in_range(0..=3)? {
+            0 => u.choose(&[0, 1, -1, i32::MIN, i32::MAX, 0xff, 0xffff]).copied(),
+            1 => u.int_in_range(-16..=16),
+            _ => u.arbitrary(),
+        }
+    }
+
+    // Base register, offset and size of an in-bounds access to packet data or to the stack.
+    fn gen_access(&self, u: &mut Unstructured) -> arbitrary::Result<(u8, i16, u8)> {
+        let (size, len) = *u.choose(&SIZES)?;
+        if self.mem_len >= len as usize && u.arbitrary()? {
+            let off = u.int_in_range(0..=self.mem_len as i16 - len)?;
+            Ok((1, off, size))
+        } else {
+            let off = u.int_in_range(-STACK_USED..=-len)?;
+            Ok((10, off, size))
+        }
+    }
+}
+
+fn insn(opc: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn { opc, dst, src, off, imm }
+}
+
+// Lay out the operations after the prologue, followed by `exit`, and resolve jump offsets.
+fn assemble(prologue: Vec<Insn>, ops: &[Op]) -> Vec<u8> {
+    let mut insns = prologue;
+    // Index of the first instruction of each operation, then of the final `exit`.
+    let mut starts = Vec::with_capacity(ops.len() + 1);
+    let mut pos = insns.len();
+    for op in ops {
+        starts.push(pos);
+        pos += match *op {
+            Op::Insns(ref v) => v.len(),
+            Op::Jump(..)     => 1,
+        };
+    }
+    starts.push(pos);
+    for op in ops {
+        match *op {
+            Op::Insns(ref v) => insns.extend_from_slice(v),
+            Op::Jump(jmp, target) => {
+                // The target may be missing if the input ended early: jump to `exit` instead.
+                let off = starts[target.min(ops.len())] - (insns.len() + 1);
+                insns.push(Insn { off: off as i16, ..jmp });
+            },
+        }
+    }
+    insns.push(insn(ebpf::EXIT, 0, 0, 0, 0));
+    insns.iter().flat_map(|insn| insn.to_array().to_vec()).collect()
+}
+
+/// Reduce a program for which `is_failing` returns `true`, for example because it makes a VM
+/// crash, to a smaller program for which `is_failing` still returns `true`.
+///
+/// The program is reduced by removing instructions, adjusting the offsets of jumps over them so
+/// that it remains valid, then by setting the immediate values of the remaining instructions to
+/// zero. The program must pass the simple verifier, and end with `exit`, which is kept. Jumps
+/// keep their direction: if the program has no backward jumps, the reduced programs passed to
+/// `is_failing` always terminate.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf;
+/// use rbpf::fuzz;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r0, 5
+///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+///     0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+///     0x3f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// // Reduce while the program has a division by a register.
+/// let shrunk = fuzz::shrink(&prog, |p| {
+///     (0..p.len() / ebpf::INSN_SIZE).any(|i| ebpf::get_insn(p, i).opc == ebpf::DIV64_REG)
+/// });
+/// assert_eq!(shrunk, vec![
+///     0x3f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ]);
+/// ```
+pub fn shrink<F: FnMut(&[u8]) -> bool>(prog: &[u8], mut is_failing: F) -> Vec<u8> {
+    let mut insns: Vec<Insn> = (0..prog.len() / ebpf::INSN_SIZE)
+        .map(|i| ebpf::get_insn(prog, i))
+        .collect();
+    let encode = |insns: &[Insn]| -> Vec<u8> {
+        insns.iter().flat_map(|insn| insn.to_array().to_vec()).collect()
+    };
+
+    // Remove chunks of instructions, from half of the program down to single instructions.
+    let mut chunk = insns.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        let mut removed = false;
+        while start + 1 < insns.len() {
+            // Keep the final `exit`, and do not split `lddw`.
+            let mut end = (start + chunk).min(insns.len() - 1);
+            if end > start && insns[end - 1].opc == ebpf::LD_DW_IMM {
+                end += 1;
+            }
+            if start > 0 && insns[start - 1].opc == ebpf::LD_DW_IMM || end >= insns.len() {
+                start += 1;
+                continue;
+            }
+            let candidate = remove_insns(&insns, start, end);
+            if is_failing(&encode(&candidate)) {
+                insns = candidate;
+                removed = true;
+            } else {
+                start = end;
+            }
+        }
+        if !removed {
+            chunk /= 2;
+        }
+    }
+
+    // Simplify immediate values, except for the ones that the verifier requires to be non-zero.
+    for i in 0..insns.len() {
+        let opc = insns[i].opc;
+        let class = opc & ebpf::BPF_CLS_MASK;
+        let alu_op = opc & ebpf::BPF_ALU_OP_MASK;
+        let nonzero = (class == ebpf::BPF_ALU || class == ebpf::BPF_ALU64) &&
+            opc & ebpf::BPF_X == 0 &&
+            (alu_op == ebpf::BPF_MUL || alu_op == ebpf::BPF_DIV || alu_op == ebpf::BPF_MOD);
+        if insns[i].imm == 0 || nonzero || opc == ebpf::CALL || opc == ebpf::LE || opc == ebpf::BE {
+            continue;
+        }
+        let mut candidate = insns.clone();
+        candidate[i].imm = 0;
+        if is_failing(&encode(&candidate)) {
+            insns = candidate;
+        }
+    }
+    encode(&insns)
+}
+
+// Remove instructions `start..end`, and adjust the offsets of the jumps over them. Jumps to a
+// removed instruction go to the first instruction after the removed ones.
+fn remove_insns(insns: &[Insn], start: usize, end: usize) -> Vec<Insn> {
+    let mut res = Vec::with_capacity(insns.len() - (end - start));
+    for (i, insn) in insns.iter().enumerate() {
+        if i >= start && i < end {
+            continue;
+        }
+        let mut insn = *insn;
+        let class = insn.opc & ebpf::BPF_CLS_MASK;
+        let is_jump = (class == ebpf::BPF_JMP || class == ebpf::BPF_JMP32) &&
+            insn.opc != ebpf::CALL && insn.opc != ebpf::EXIT;
+        // The second half of `lddw` has opcode 0, it is not a jump.
+        if is_jump && !(i > 0 && insns[i - 1].opc == ebpf::LD_DW_IMM) {
+            let (from, start, end) = (i as isize, start as isize, end as isize);
+            // `gotol` has its offset in the immediate.
+            let off = match insn.opc {
+                ebpf::JA32 => insn.imm as isize,
+                _          => insn.off as isize,
+            };
+            let to = from + 1 + off;
+            // Skip the removed instructions between the jump and its target.
+            let delta = if from < start && to > start {
+                -(to.min(end) - start)
+            } else if from >= end && to < end {
+                end - to.max(start)
+            } else {
+                0
+            };
+            match insn.opc {
+                ebpf::JA32 => insn.imm += delta as i32,
+                _          => insn.off += delta as i16,
+            }
+        }
+        res.push(insn);
+    }
+    res
+}
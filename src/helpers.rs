@@ -19,12 +19,98 @@
 //! The prototype for helpers is always the same: five `u64` as arguments, and a `u64` as a return
 //! value. Hence some helpers have unused arguments, or return a 0 value in all cases, in order to
 //! respect this convention.
+//!
+//! Bounds-checked `memcpy()`, `memset()`, `memcmp()`, `bpf_strtol()`, `bpf_strtoul()` and
+//! `bpf_csum_diff()` helpers are also available; they are run by the interpreter, see
+//! `MEMCPY_IDX`. So are the helpers for multi-buffer packets, see `BPF_XDP_GET_BUFF_LEN_IDX`, the
+//! helper returning the scratch storage of the VM, see `GET_SCRATCH_IDX`, the helpers reading the
+//! configuration store of the VM, see `CONFIG_LOOKUP_IDX`, and the map helpers, see
+//! `BPF_MAP_LOOKUP_ELEM_IDX`, `BPF_REDIRECT_MAP_IDX` and `BPF_FOR_EACH_MAP_ELEM_IDX`.
+//!
+//! This module also defines `HelperHook`, a trait for auditing, and possibly forbidding, the calls
+//! made to helpers by a program at runtime, and `EventSink`, a trait for the consumers of the
+//! events output by programs.
 
+use std::sync::mpsc;
 use std::u64;
 
+use libc;
+
 // Helpers associated to kernel helpers
 // See also linux/include/uapi/linux/bpf.h in Linux kernel sources.
 
+// bpf_tail_call()
+
+/// Index of helper `bpf_tail_call()` in Linux kernel. This helper has no implementation in this
+/// module: tail calls are handled by the interpreter itself, when running programs from a
+/// `registry::ProgramRegistry`.
+pub const BPF_TAIL_CALL_IDX: u32 = 12;
+
+// bpf_probe_read(), bpf_probe_read_user(), bpf_probe_read_kernel()
+
+/// Index of helper `bpf_probe_read(dst, size, unsafe_ptr)` in Linux kernel. This helper, and the
+/// two following ones, have no implementation in this module: they are run by the interpreter,
+/// once memory regions have been registered on the VM with `register_probe_region()`, in order to
+/// test tracing programs with snapshots of memory.
+///
+/// The helper copies `size` bytes from address `unsafe_ptr` to `dst`, and returns 0. If the
+/// source is not entirely contained in a registered region, or in the memory of the program
+/// (packet data, metadata buffer or stack), it clears `dst` and returns `-EFAULT` instead. The
+/// destination must be in the memory of the program, or the interpreter panics.
+pub const BPF_PROBE_READ_IDX: u32 = 4;
+
+/// Index of helper `bpf_probe_read_user(dst, size, unsafe_ptr)` in Linux kernel. Same as
+/// `bpf_probe_read()`, see `BPF_PROBE_READ_IDX`, but only reads from regions registered as user
+/// memory.
+pub const BPF_PROBE_READ_USER_IDX: u32 = 112;
+
+/// Index of helper `bpf_probe_read_kernel(dst, size, unsafe_ptr)` in Linux kernel. Same as
+/// `bpf_probe_read()`, see `BPF_PROBE_READ_IDX`, but does not read from regions registered as
+/// user memory.
+pub const BPF_PROBE_READ_KERNEL_IDX: u32 = 113;
+
+/// Error code returned by the `bpf_probe_read*()` helpers when the source address is invalid,
+/// as a signed integer.
+pub const EFAULT: i64 = 14;
+
+/// Address space of a memory region registered for the `bpf_probe_read*()` helpers, see
+/// `BPF_PROBE_READ_IDX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    /// User memory, readable with `bpf_probe_read()` and `bpf_probe_read_user()`.
+    User,
+    /// Kernel memory, readable with `bpf_probe_read()` and `bpf_probe_read_kernel()`.
+    Kernel,
+}
+
+// bpf_ktime_get_ns()
+
+/// Index of helper `bpf_ktime_get_ns()` in Linux kernel, equivalent to `bpf_ktime_get_ns()` in
+/// this module.
+pub const BPF_KTIME_GET_NS_IDX: u32 = 5;
+
+/// Return the time elapsed since an arbitrary point in the past, in nanoseconds, read from the
+/// monotonic clock of the system, as helper `bpf_ktime_get_ns()` in Linux kernel. All arguments
+/// are unused.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::helpers;
+///
+/// let t1 = helpers::bpf_ktime_get_ns(0, 0, 0, 0, 0);
+/// let t2 = helpers::bpf_ktime_get_ns(0, 0, 0, 0, 0);
+/// assert!(t2 >= t1);
+/// ```
+#[allow(unused_variables)]
+pub fn bpf_ktime_get_ns (unused1: u64, unused2: u64, unused3: u64, unused4: u64, unused5: u64) -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    (ts.tv_sec as u64).wrapping_mul(1_000_000_000).wrapping_add(ts.tv_nsec as u64)
+}
+
 // bpf_trace_printk()
 
 /// Index of helper `bpf_trace_printk()`, equivalent to `bpf_trace_printf()`, in Linux kernel, see
@@ -196,3 +282,468 @@ pub fn strcmp (arg1: u64, arg2: u64, arg3: u64, unused4: u64, unused5: u64) -> u
         }
     }
 }
+
+/// Built-in helpers that the JIT-compiler replaces with inline machine code, when enabled on a VM
+/// with `set_intrinsics()`. A call is inlined when the function registered for its key is the
+/// helper of this module, whatever the key. The inline code produces the same results as the
+/// helper, but saves the cost of a function call; `bpf_ktime_get_ns()` still calls
+/// `clock_gettime()`, but directly from the program. The interpreter is not affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Intrinsics {
+    /// Inline `sqrti()`.
+    pub sqrti:        bool,
+    /// Inline `gather_bytes()`.
+    pub gather_bytes: bool,
+    /// Inline `bpf_ktime_get_ns()`.
+    pub ktime_get_ns: bool,
+}
+
+impl Intrinsics {
+    /// All the available intrinsics.
+    pub fn all() -> Intrinsics {
+        Intrinsics { sqrti: true, gather_bytes: true, ktime_get_ns: true }
+    }
+}
+
+// Bounds-checked memory helpers
+//
+// These helpers have no implementation in this module either: since they must check that the
+// memory they access belongs to the program (packet data, metadata buffer or stack), they are
+// run by the interpreter itself, once enabled on a VM with `register_mem_helpers()`. As for
+// other accesses, out of bounds accesses make the interpreter panic. JIT-compiled programs cannot
+// call them.
+
+/// Index of helper `memcpy(dst, src, len)`, specific to rbpf. Copies `len` bytes from `src` to
+/// `dst`, the areas may overlap. Returns 0.
+pub const MEMCPY_IDX: u32 = 0x7f00_0001;
+
+/// Index of helper `memset(dst, c, len)`, specific to rbpf. Sets `len` bytes at `dst` to the lowest
+/// byte of `c`. Returns 0.
+pub const MEMSET_IDX: u32 = 0x7f00_0002;
+
+/// Index of helper `memcmp(a, b, len)`, specific to rbpf. Compares the `len` bytes at `a` and `b`,
+/// and returns 0 if they are equal, or else the difference between the first two bytes that
+/// differ, as a signed integer: negative if the byte from `a` is the smallest.
+pub const MEMCMP_IDX: u32 = 0x7f00_0003;
+
+/// Index of helper `get_scratch()`, specific to rbpf. Returns the address of the scratch storage
+/// of the VM, which keeps its contents from one execution of the program to the next, or 0 if
+/// the VM has none. Its size is the one passed to `set_scratch()`. Like the memory helpers, it is
+/// run by the interpreter, once a region is set on the VM with `set_scratch()`, and the program
+/// may then load from and store to the region. JIT-compiled programs cannot call it.
+pub const GET_SCRATCH_IDX: u32 = 0x7f00_0004;
+
+/// Index of helper `config_lookup(key, key_len)`, specific to rbpf. Looks up the key made of the
+/// `key_len` bytes at `key`, without any trailing null byte, in the configuration store of the
+/// VM, and returns the address of its value, or 0 if the store has no such key. The program may
+/// then load from the value, but not store to it. Like the memory helpers, it is run by the
+/// interpreter, once the VM has a configuration store, see `EbpfVmMbuff::set_config()`.
+/// JIT-compiled programs cannot call it.
+pub const CONFIG_LOOKUP_IDX: u32 = 0x7f00_0005;
+
+/// Index of helper `config_value_len(key, key_len)`, specific to rbpf. Returns the length of the
+/// value of the key in the configuration store of the VM, or `-ENOENT` if the store has no such
+/// key. See `CONFIG_LOOKUP_IDX`.
+pub const CONFIG_VALUE_LEN_IDX: u32 = 0x7f00_0006;
+
+/// Index of helper `bpf_strtol(buf, buf_len, flags, res)` in Linux kernel. Parses the signed
+/// integer at the start of the `buf_len` bytes at `buf`, or of the null-terminated string they
+/// contain, and stores it as a 64-bit value at `res`. As in the kernel:
+///
+/// * The lowest five bits of `flags` are the base: 8, 10 or 16, or 0 to detect it from a `0x`
+///   (hexadecimal) or `0` (octal) prefix. Other bits must be cleared.
+/// * Leading whitespaces are skipped, then an optional `-` sign; at most 63 characters are
+///   parsed after them.
+/// * The helper returns the number of characters consumed, or `-EINVAL` if the flags are invalid
+///   or no digit is found, and `-ERANGE` on overflow. `res` is not written on error.
+pub const BPF_STRTOL_IDX: u32 = 105;
+
+/// Index of helper `bpf_strtoul(buf, buf_len, flags, res)` in Linux kernel. Same as
+/// `bpf_strtol()`, see `BPF_STRTOL_IDX`, for unsigned integers: the helper returns `-EINVAL` if
+/// the number is negative.
+pub const BPF_STRTOUL_IDX: u32 = 106;
+
+/// Index of helper `bpf_csum_diff(from, from_size, to, to_size, seed)` in Linux kernel. Returns
+/// the ones' complement sum of the `to_size` bytes at `to`, minus the one of the `from_size` bytes
+/// at `from`, added to `seed`, as a 32-bit unfolded sum (see module `csum`). The sizes must be
+/// multiples of 4, and add up to at most 512 bytes, else the helper returns `-EINVAL`; either of
+/// them may be 0, to compute the sum of the other area only.
+pub const BPF_CSUM_DIFF_IDX: u32 = 28;
+
+/// Error code returned by `bpf_strtol()`, `bpf_strtoul()` and `bpf_csum_diff()` for invalid
+/// arguments, as a signed integer.
+pub const EINVAL: i64 = 22;
+
+/// Error code returned by `bpf_strtol()` and `bpf_strtoul()` when the result overflows, as a
+/// signed integer.
+pub const ERANGE: i64 = 34;
+
+// Maps
+
+/// Index of helper `bpf_map_lookup_elem(map, key)` in Linux kernel. This helper, and the other
+/// map helpers, have no implementation in this module: they are run by the interpreter, once a
+/// map is registered on the VM with `register_map()`, see module `maps`. `map` is the identifier
+/// returned by `register_map()`, and `key` must belong to the memory of the program.
+///
+/// The helper returns the address of the value associated with `key`, which the program may then
+/// read and write, or 0 if there is no such entry or map.
+pub const BPF_MAP_LOOKUP_ELEM_IDX: u32 = 1;
+
+/// Index of helper `bpf_map_update_elem(map, key, value, flags)` in Linux kernel. Associates the
+/// value at `value` with `key`, see `maps::Map::update()`. Returns 0, or the error code of the
+/// update, as a negative integer. See `BPF_MAP_LOOKUP_ELEM_IDX`.
+pub const BPF_MAP_UPDATE_ELEM_IDX: u32 = 2;
+
+/// Index of helper `bpf_map_delete_elem(map, key)` in Linux kernel. Removes the entry of `key`,
+/// see `maps::Map::delete()`. Returns 0, or the error code of the deletion, as a negative
+/// integer. See `BPF_MAP_LOOKUP_ELEM_IDX`.
+pub const BPF_MAP_DELETE_ELEM_IDX: u32 = 3;
+
+/// Index of helper `bpf_redirect_map(map, key, flags)` in Linux kernel. Requests the redirect of
+/// the packet to the target at index `key`, a 32-bit integer, of `map`, a map of type
+/// `maps::MapType::DevMap`. See `BPF_MAP_LOOKUP_ELEM_IDX`.
+///
+/// The helper returns `XDP_REDIRECT` if the entry exists, and records the redirect, returned to
+/// the application by `EbpfVmMbuff::prog_exec_xdp_redirect()`; a later call replaces it. If there
+/// is no such entry, it returns the action in the lowest two bits of `flags`, usually
+/// `XDP_PASS` or `XDP_DROP`. It returns `XDP_ABORTED` if the map is not a map of redirect targets,
+/// or if other bits of `flags` are set.
+pub const BPF_REDIRECT_MAP_IDX: u32 = 51;
+
+/// Index of helper `bpf_for_each_map_elem(map, callback, ctx, flags)` in Linux kernel. Calls
+/// `callback`, a subprogram of the program loaded with `lddw` (see `ebpf::BPF_PSEUDO_FUNC`), on
+/// each element of `map`, as `callback(map, key, value, ctx)`: `key` and `value` point to the key
+/// and the value of the element, which the callback may update in place, and `ctx` is passed as
+/// is, usually a pointer to the stack of the caller. The callback returns 0 to continue with the
+/// next element, or 1 to stop. See `BPF_MAP_LOOKUP_ELEM_IDX`.
+///
+/// The elements of arrays are visited in the order of their indexes, those of hash maps in no
+/// particular order; the keys are taken when the iteration starts, and the elements deleted in the
+/// meantime are skipped. Each call of the callback runs on a stack of its own, and callbacks may
+/// iterate over maps in turn, with at most `ebpf::MAX_CALL_DEPTH` iterations nested.
+///
+/// The helper returns the number of elements on which the callback was called, or `-EINVAL` if
+/// `flags` is not 0, if there is no such map, or if too many iterations are nested.
+pub const BPF_FOR_EACH_MAP_ELEM_IDX: u32 = 164;
+
+/// Error code returned by `bpf_map_update_elem()` when the map is full, as a signed integer.
+pub const E2BIG: i64 = 7;
+
+/// Error code returned by `bpf_map_update_elem()` when the entry exists and flag
+/// `maps::BPF_NOEXIST` is passed, as a signed integer.
+pub const EEXIST: i64 = 17;
+
+// Event output
+
+/// Index of helper `bpf_perf_event_output(ctx, map, flags, data, size)` in Linux kernel. This
+/// helper has no implementation in this module: it is run by the interpreter, once an
+/// `EventSink` is attached to the VM with `set_event_sink()`.
+///
+/// The helper copies the `size` bytes at `data`, which must belong to the memory of the program
+/// (packet data, metadata buffer or stack), and passes them to the sink as an event. The `ctx`,
+/// `map` and `flags` arguments are ignored. The helper returns 0, or the error returned by the
+/// sink.
+pub const BPF_PERF_EVENT_OUTPUT_IDX: u32 = 25;
+
+/// Error code returned by `bpf_perf_event_output()` when the consumer has gone, and by the map
+/// helpers when there is no such entry, as a signed integer.
+pub const ENOENT: i64 = 2;
+
+/// Error code returned by `bpf_perf_event_output()` when the consumer cannot accept more events,
+/// as a signed integer.
+pub const ENOSPC: i64 = 28;
+
+// Multi-buffer packets
+
+/// Index of helper `bpf_xdp_get_buff_len(ctx)` in Linux kernel. This helper, and the other
+/// helpers for multi-buffer packets, have no implementation in this module: they are run by the
+/// interpreter, when a program is run on a packet made of several fragments, for example with
+/// `EbpfVmMbuff::prog_exec_frags()`. Packet data is then the first fragment.
+///
+/// The helper returns the total length of the packet, all fragments included. The `ctx` argument
+/// is ignored.
+pub const BPF_XDP_GET_BUFF_LEN_IDX: u32 = 188;
+
+/// Index of helper `bpf_xdp_load_bytes(ctx, offset, buf, len)` in Linux kernel. Copies the `len`
+/// bytes at `offset` in the packet, possibly spanning several fragments, to `buf`, which must
+/// belong to the memory of the program. Returns 0, or `-EINVAL` if `len` is 0 or if the bytes are
+/// out of the packet. See `BPF_XDP_GET_BUFF_LEN_IDX`.
+pub const BPF_XDP_LOAD_BYTES_IDX: u32 = 189;
+
+/// Index of helper `bpf_xdp_store_bytes(ctx, offset, buf, len)` in Linux kernel. Same as
+/// `bpf_xdp_load_bytes()`, see `BPF_XDP_LOAD_BYTES_IDX`, copying the bytes from `buf` to the
+/// packet.
+pub const BPF_XDP_STORE_BYTES_IDX: u32 = 190;
+
+// Resizable packets
+
+/// Index of helper `bpf_xdp_adjust_head(ctx, delta)` in Linux kernel. This helper, and the other
+/// helpers resizing packets, have no implementation in this module: they are run by the
+/// interpreter, when a program is run on a `PacketBuffer` with
+/// `EbpfVmFixedMbuff::prog_exec_resizable()`. They move the start or the end of packet data within
+/// the buffer, and update the pointers to packet data stored in the metadata buffer: the pointers
+/// loaded by the program before the call are no longer valid.
+///
+/// The helper moves the start of packet data by `delta` bytes, a signed 32-bit integer: a negative
+/// `delta` grows the packet, to push an encapsulation header for example. Returns 0, or `-EINVAL`
+/// if the buffer has not enough headroom, or if the packet would be shorter than an Ethernet
+/// header.
+pub const BPF_XDP_ADJUST_HEAD_IDX: u32 = 44;
+
+/// Index of helper `bpf_skb_adjust_room(skb, len_diff, mode, flags)` in Linux kernel. Inserts
+/// `len_diff` zeroed bytes, a signed 32-bit integer, after the Ethernet header if `mode` is
+/// `BPF_ADJ_ROOM_MAC`, or after the IPv4 or IPv6 header following it if `mode` is
+/// `BPF_ADJ_ROOM_NET`, by moving the headers towards the start of the buffer. A negative
+/// `len_diff` removes bytes instead. The `flags`, describing encapsulations to the kernel, are
+/// ignored. Returns 0, or `-EINVAL` if the mode is unknown, if the packet has no such network
+/// header, or if the buffer has not enough headroom. See `BPF_XDP_ADJUST_HEAD_IDX`.
+pub const BPF_SKB_ADJUST_ROOM_IDX: u32 = 50;
+
+/// Index of helper `bpf_xdp_adjust_tail(ctx, delta)` in Linux kernel. Moves the end of packet data
+/// by `delta` bytes, a signed 32-bit integer; the bytes added to the packet are zeroed. Returns 0,
+/// or `-EINVAL` if the buffer has not enough tailroom, or if the packet would be shorter than an
+/// Ethernet header. See `BPF_XDP_ADJUST_HEAD_IDX`.
+pub const BPF_XDP_ADJUST_TAIL_IDX: u32 = 65;
+
+/// Error code returned by `bpf_xdp_store_bytes()` and by the helpers resizing packets when packet
+/// data is read-only, see `EbpfVmMbuff::set_read_only_packet()`, as a signed integer.
+pub const EPERM: i64 = 1;
+
+/// Mode of `bpf_skb_adjust_room()`, resizing the packet after its network header.
+pub const BPF_ADJ_ROOM_NET: u64 = 0;
+
+/// Mode of `bpf_skb_adjust_room()`, resizing the packet after its Ethernet header.
+pub const BPF_ADJ_ROOM_MAC: u64 = 1;
+
+/// A consumer for the events output by a program with `bpf_perf_event_output()`, see
+/// `BPF_PERF_EVENT_OUTPUT_IDX`.
+///
+/// The trait is implemented for the senders of `std::sync::mpsc` channels, so that the events
+/// can be received by another thread of the application in real time: `Sender<Vec<u8>>`, and
+/// `SyncSender<Vec<u8>>` for bounded channels, which drops the events when they are full. It is
+/// also implemented for closures.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::mpsc;
+///
+/// // Outputs the first two bytes of packet data as an event.
+/// let prog = vec![
+///     0xbf, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r1
+///     0xb7, 0x05, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r5, 2
+///     0x85, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, // call bpf_perf_event_output
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let (sender, receiver) = mpsc::channel();
+/// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+/// vm.set_event_sink(Box::new(sender));
+///
+/// assert_eq!(vm.prog_exec(&mut vec![0x11, 0x22, 0x33]), 0);
+/// assert_eq!(receiver.try_recv(), Ok(vec![0x11, 0x22]));
+/// ```
+pub trait EventSink {
+    /// Consume the event `data`. Returns the error code for `bpf_perf_event_output()` to return,
+    /// as a positive integer, if the event cannot be consumed.
+    fn output(&self, data: &[u8]) -> Result<(), i64>;
+}
+
+impl EventSink for mpsc::Sender<Vec<u8>> {
+    fn output(&self, data: &[u8]) -> Result<(), i64> {
+        self.send(data.to_vec()).map_err(|_| ENOENT)
+    }
+}
+
+impl EventSink for mpsc::SyncSender<Vec<u8>> {
+    fn output(&self, data: &[u8]) -> Result<(), i64> {
+        self.try_send(data.to_vec()).map_err(|err| match err {
+            mpsc::TrySendError::Full(_)         => ENOSPC,
+            mpsc::TrySendError::Disconnected(_) => ENOENT,
+        })
+    }
+}
+
+impl<F: Fn(&[u8]) -> Result<(), i64>> EventSink for F {
+    fn output(&self, data: &[u8]) -> Result<(), i64> {
+        self(data)
+    }
+}
+
+// Auditing helper calls
+
+/// Decision taken by a `HelperHook` before a helper function is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookVerdict {
+    /// Call the helper function.
+    Allow,
+    /// Do not call the helper function, and resume execution as if it had returned the given
+    /// value.
+    Skip(u64),
+    /// Do not call the helper function, and abort the execution of the program.
+    Deny,
+}
+
+/// A hook for auditing the calls to helper functions made by an eBPF program.
+///
+/// Once attached to a VM with `set_helper_hook()`, the hook is invoked by the interpreter on every
+/// `call` instruction, with the key of the helper and the values of the five argument registers R1
+/// to R5. This includes calls to the helpers run by the interpreter itself, such as the map
+/// helpers or tail calls, and calls to unknown helpers. It can log the call, or veto it. If the
+/// helper is called and returns to the program, the hook is invoked again with its return value.
+///
+/// JIT-compiled programs call helpers directly, and cannot be audited: VMs with a helper hook
+/// refuse to run them.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use rbpf::helpers::{HelperHook, HookVerdict};
+///
+/// // Counts the calls to helpers, and forbids calls to `bpf_trace_printf()`.
+/// struct Audit {
+///     calls: Cell<u32>,
+/// }
+///
+/// impl HelperHook for Audit {
+///     fn before_call(&self, key: u32, _args: &[u64; 5]) -> HookVerdict {
+///         self.calls.set(self.calls.get() + 1);
+///         match key {
+///             rbpf::helpers::BPF_TRACE_PRINTK_IDX => HookVerdict::Skip(0),
+///             _                                   => HookVerdict::Allow,
+///         }
+///     }
+/// }
+///
+/// let prog = vec![
+///     0x85, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // call 6 (bpf_trace_printf)
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let audit = Audit { calls: Cell::new(0) };
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// vm.register_helper(rbpf::helpers::BPF_TRACE_PRINTK_IDX, rbpf::helpers::bpf_trace_printf);
+/// vm.set_helper_hook(Box::new(&audit));
+///
+/// // Nothing is printed.
+/// assert_eq!(vm.prog_exec(), 0);
+/// assert_eq!(audit.calls.get(), 1);
+/// ```
+pub trait HelperHook {
+    /// Called before helper `key` is called with arguments `args`. The verdict decides whether
+    /// the helper is actually called.
+    fn before_call(&self, key: u32, args: &[u64; 5]) -> HookVerdict;
+
+    /// Called after helper `key` has been called with arguments `args`, and returned `ret`. Does
+    /// nothing by default.
+    #[allow(unused_variables)]
+    fn after_call(&self, key: u32, args: &[u64; 5], ret: u64) {}
+}
+
+impl<H: HelperHook + ?Sized> HelperHook for &H {
+    fn before_call(&self, key: u32, args: &[u64; 5]) -> HookVerdict {
+        (**self).before_call(key, args)
+    }
+
+    fn after_call(&self, key: u32, args: &[u64; 5], ret: u64) {
+        (**self).after_call(key, args, ret)
+    }
+}
+
+/// The arguments of a helper taking them in a frame, in the memory of the program, rather than
+/// in registers R1 to R5, for helpers needing more than five arguments or richer data. Such
+/// helpers are registered with `EbpfVmMbuff::register_frame_helper()`: the program passes the
+/// address of the frame in R1, and the interpreter reads it into the typed arguments of the
+/// helper, after checking that the `SIZE` bytes at this address belong to the memory of the
+/// program. Frame helpers are not available to JIT-compiled programs.
+///
+/// The trait is implemented for arrays of `u64`, read in the byte order of the host, as the
+/// program stores them.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::helpers::ArgFrame;
+///
+/// // A frame holding a 32-bit address, a 16-bit port and a 16-bit protocol.
+/// struct Flow {
+///     addr:  u32,
+///     port:  u16,
+///     proto: u16,
+/// }
+///
+/// impl ArgFrame for Flow {
+///     const SIZE: usize = 8;
+///
+///     fn from_frame(frame: &[u8]) -> Flow {
+///         Flow {
+///             addr:  u32::from_ne_bytes([frame[0], frame[1], frame[2], frame[3]]),
+///             port:  u16::from_ne_bytes([frame[4], frame[5]]),
+///             proto: u16::from_ne_bytes([frame[6], frame[7]]),
+///         }
+///     }
+/// }
+///
+/// // Returns 1 for TCP flows to port 80.
+/// fn is_http(flow: Flow) -> u64 {
+///     (flow.proto == 6 && flow.port == 80) as u64
+/// }
+///
+/// let prog = vec![
+///     0x62, 0x0a, 0xf8, 0xff, 0x0a, 0x00, 0x00, 0x01, // stw [r10-8], 0x0100000a
+///     0x6a, 0x0a, 0xfc, 0xff, 0x50, 0x00, 0x00, 0x00, // sth [r10-4], 80
+///     0x6a, 0x0a, 0xfe, 0xff, 0x06, 0x00, 0x00, 0x00, // sth [r10-2], 6
+///     0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+///     0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// vm.register_frame_helper(1, is_http);
+/// assert_eq!(vm.prog_exec(), 1);
+/// ```
+pub trait ArgFrame: Sized + 'static {
+    /// The size of the frame, in bytes.
+    const SIZE: usize;
+
+    /// Read the arguments from `frame`, the `SIZE` bytes of the frame.
+    fn from_frame(frame: &[u8]) -> Self;
+}
+
+impl<const N: usize> ArgFrame for [u64; N] {
+    const SIZE: usize = N * 8;
+
+    fn from_frame(frame: &[u8]) -> [u64; N] {
+        let mut args = [0; N];
+        for (arg, bytes) in args.iter_mut().zip(frame.chunks_exact(8)) {
+            let mut arg_bytes = [0; 8];
+            arg_bytes.copy_from_slice(bytes);
+            *arg = u64::from_ne_bytes(arg_bytes);
+        }
+        args
+    }
+}
+
+type FrameCall = Box<dyn Fn(&[u8]) -> u64 + Send + Sync>;
+
+// A helper registered with `EbpfVmMbuff::register_frame_helper()`, reading its arguments from a
+// frame of `size` bytes.
+pub(crate) struct FrameHelper {
+    pub size: usize,
+    call:     FrameCall,
+}
+
+impl FrameHelper {
+    pub fn new<T: ArgFrame>(function: fn(T) -> u64) -> FrameHelper {
+        FrameHelper {
+            size: T::SIZE,
+            call: Box::new(move |frame| function(T::from_frame(frame))),
+        }
+    }
+
+    pub fn call(&self, frame: &[u8]) -> u64 {
+        (self.call)(frame)
+    }
+}
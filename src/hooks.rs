@@ -0,0 +1,239 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides hook points, to which applications attach chains of eBPF programs.
+//!
+//! An application defines named hook points, for example one for each stage of its packet
+//! processing. Programs are attached to a hook with a priority, and invoking the hook runs them in
+//! order of priority, lowest first, on the same context. The policy of the hook decides, from the
+//! value returned by each program, whether the next one runs, in the same way as chains of
+//! programs in the Linux kernel:
+//!
+//! * Chains of tc classifiers carry on while programs return `TC_ACT_UNSPEC` (-1), and stop at the
+//!   first program returning another action: this is `ChainPolicy::ContinueOn(-1i64 as u64)`.
+//! * The dispatcher of libxdp only runs the next XDP program if the current one returns
+//!   `XDP_PASS` (2): this is `ChainPolicy::ContinueOn(2)`.
+//!
+//! All the programs of a chain see the changes made to the context by the previous ones. Programs
+//! attached to hooks are interpreted; they cannot be JIT-compiled.
+
+use std::collections::HashMap;
+
+use ebpf;
+use interpreter;
+use verifier;
+
+/// Decides, from the value returned by a program of a chain, whether the next program runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainPolicy {
+    /// Run all the programs of the chain.
+    RunAll,
+    /// Run the next program only if the current one returned this value.
+    ContinueOn(u64),
+    /// Stop the chain as soon as a program returns this value.
+    AbortOn(u64),
+}
+
+impl ChainPolicy {
+    fn carries_on(&self, ret: u64) -> bool {
+        match *self {
+            ChainPolicy::RunAll        => true,
+            ChainPolicy::ContinueOn(v) => ret == v,
+            ChainPolicy::AbortOn(v)    => ret != v,
+        }
+    }
+}
+
+/// The outcome of invoking a hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainResult {
+    /// The value returned by the last program run, or the default value of the hook if no program
+    /// is attached.
+    pub ret:        u64,
+    /// The number of programs run.
+    pub run:        usize,
+    /// The name of the program that stopped the chain, if the policy of the hook stopped it before
+    /// its last program.
+    pub stopped_by: Option<String>,
+}
+
+struct Attached {
+    name:     String,
+    priority: i32,
+    prog:     Vec<u8>,
+}
+
+struct HookPoint {
+    policy:   ChainPolicy,
+    default:  u64,
+    programs: Vec<Attached>,
+}
+
+/// A set of named hook points, and of the programs attached to them. All programs share the same
+/// set of helpers.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::hooks::{ChainPolicy, Hooks};
+///
+/// // Drops (returns 1) packets whose first byte is 0, passes (returns 2) the others.
+/// let filter = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+///     0x55, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r2, 0, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// // Increments the second byte of the packet, and passes it.
+/// let counter = vec![
+///     0x71, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1+1]
+///     0x07, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r2, 1
+///     0x73, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r2
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// // As with XDP programs, carry on while programs pass the packet.
+/// let mut hooks = Hooks::new();
+/// hooks.define("ingress", ChainPolicy::ContinueOn(2), 2);
+/// hooks.attach("ingress", "counter", 20, counter);
+/// hooks.attach("ingress", "filter", 10, filter);
+/// assert_eq!(hooks.attached("ingress"), vec!["filter", "counter"]);
+///
+/// let mut packet = vec![0x2a, 0x00];
+/// let res = hooks.invoke("ingress", &mut packet, &mut []);
+/// assert_eq!((res.ret, res.run, res.stopped_by), (2, 2, None));
+/// assert_eq!(packet[1], 1);
+///
+/// // The filter drops this one, so the counter does not run.
+/// let mut packet = vec![0x00, 0x00];
+/// let res = hooks.invoke("ingress", &mut packet, &mut []);
+/// assert_eq!((res.ret, res.run), (1, 1));
+/// assert_eq!(res.stopped_by, Some("filter".to_string()));
+/// assert_eq!(packet[1], 0);
+/// ```
+#[derive(Default)]
+pub struct Hooks {
+    hooks:   HashMap<String, HookPoint>,
+    helpers: HashMap<u32, ebpf::Helper>,
+}
+
+impl Hooks {
+
+    /// Create a new set, with no hook points.
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    /// Define a hook point named `hook`, with the given `policy`. Invoking the hook returns
+    /// `default` when no program is attached. If the hook was already defined, its policy and
+    /// default value are replaced, and the programs attached to it remain.
+    pub fn define(&mut self, hook: &str, policy: ChainPolicy, default: u64) {
+        let point = self.hooks.entry(hook.to_string()).or_insert(HookPoint {
+            policy,
+            default,
+            programs: vec![],
+        });
+        point.policy = policy;
+        point.default = default;
+    }
+
+    /// Remove the hook point named `hook`, along with the programs attached to it. Returns `true`
+    /// if it existed.
+    pub fn remove(&mut self, hook: &str) -> bool {
+        self.hooks.remove(hook).is_some()
+    }
+
+    /// Return `true` if a hook point named `hook` is defined.
+    pub fn is_defined(&self, hook: &str) -> bool {
+        self.hooks.contains_key(hook)
+    }
+
+    /// Attach a program to `hook` under the given `name`, with the given `priority`: programs with
+    /// a lower priority run first, and programs with the same priority run in the order they were
+    /// attached. If a program was already attached to the hook under the same name, it is
+    /// replaced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no hook point named `hook` is defined. The simple verifier may also panic if it
+    /// finds errors in the eBPF program.
+    pub fn attach(&mut self, hook: &str, name: &str, priority: i32, prog: Vec<u8>) {
+        verifier::check(&prog);
+        let point = self.get_mut(hook);
+        point.programs.retain(|p| p.name != name);
+        let pos = point.programs.iter().position(|p| p.priority > priority)
+            .unwrap_or(point.programs.len());
+        point.programs.insert(pos, Attached { name: name.to_string(), priority, prog });
+    }
+
+    /// Detach the program attached to `hook` under `name`, and return it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no hook point named `hook` is defined.
+    pub fn detach(&mut self, hook: &str, name: &str) -> Option<Vec<u8>> {
+        let point = self.get_mut(hook);
+        let pos = point.programs.iter().position(|p| p.name == name)?;
+        Some(point.programs.remove(pos).prog)
+    }
+
+    /// Return the names of the programs attached to `hook`, in the order they run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no hook point named `hook` is defined.
+    pub fn attached(&self, hook: &str) -> Vec<&str> {
+        self.get(hook).programs.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    /// Register a built-in or user-defined helper function, available to all programs attached to
+    /// the hooks of this set. See `EbpfVmMbuff::register_helper()`.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Run the chain of programs attached to `hook`, with the given packet data and metadata
+    /// buffer. As for `EbpfVmMbuff::prog_exec()`, R1 points to the metadata buffer if it is not
+    /// empty, or to packet data otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no hook point named `hook` is defined. Also panics on the same execution errors
+    /// as `EbpfVmMbuff::prog_exec()`.
+    pub fn invoke(&self, hook: &str, mem: &mut [u8], mbuff: &mut [u8]) -> ChainResult {
+        let point = self.get(hook);
+        let mut res = ChainResult { ret: point.default, run: 0, stopped_by: None };
+        for (i, attached) in point.programs.iter().enumerate() {
+            res.ret = interpreter::execute_program(&attached.prog, mem, mbuff, &self.helpers,
+                                                   interpreter::Options::default());
+            res.run += 1;
+            if !point.policy.carries_on(res.ret) {
+                if i + 1 < point.programs.len() {
+                    res.stopped_by = Some(attached.name.clone());
+                }
+                break;
+            }
+        }
+        res
+    }
+
+    fn get(&self, hook: &str) -> &HookPoint {
+        match self.hooks.get(hook) {
+            Some(point) => point,
+            None        => panic!("Error: no hook point named \"{}\"", hook),
+        }
+    }
+
+    fn get_mut(&mut self, hook: &str) -> &mut HookPoint {
+        match self.hooks.get_mut(hook) {
+            Some(point) => point,
+            None        => panic!("Error: no hook point named \"{}\"", hook),
+        }
+    }
+}
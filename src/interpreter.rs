@@ -0,0 +1,1320 @@
+// Derived from uBPF <https://github.com/iovisor/ubpf>
+// Copyright 2015 Big Switch Networks, Inc
+//      (uBPF: VM architecture, parts of the interpreter, originally in C)
+// Copyright 2016 Quentin Monnet <quentin.monnet@6wind.com>
+//      (Translation to Rust, MetaBuff/multiple classes addition, hashmaps for helpers)
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ebpf;
+use csum;
+use helpers::{AddressSpace, BPF_ADJ_ROOM_MAC, BPF_ADJ_ROOM_NET, BPF_CSUM_DIFF_IDX, BPF_FOR_EACH_MAP_ELEM_IDX,
+              BPF_MAP_DELETE_ELEM_IDX, BPF_MAP_LOOKUP_ELEM_IDX, BPF_MAP_UPDATE_ELEM_IDX,
+              BPF_PERF_EVENT_OUTPUT_IDX, BPF_PROBE_READ_IDX, BPF_PROBE_READ_KERNEL_IDX,
+              BPF_PROBE_READ_USER_IDX, BPF_REDIRECT_MAP_IDX, BPF_SKB_ADJUST_ROOM_IDX, BPF_STRTOL_IDX,
+              BPF_STRTOUL_IDX, BPF_TAIL_CALL_IDX, BPF_XDP_ADJUST_HEAD_IDX, BPF_XDP_ADJUST_TAIL_IDX,
+              BPF_XDP_GET_BUFF_LEN_IDX, BPF_XDP_LOAD_BYTES_IDX, BPF_XDP_STORE_BYTES_IDX, CONFIG_LOOKUP_IDX,
+              CONFIG_VALUE_LEN_IDX, EFAULT, EINVAL, ENOENT, EPERM, ERANGE, HelperHook, EventSink, FrameHelper,
+              GET_SCRATCH_IDX, HookVerdict, MEMCMP_IDX, MEMCPY_IDX, MEMSET_IDX};
+use btf::LineInfo;
+use error::EbpfError;
+use maps::{Map, MapType, Redirect, ValuePtr};
+use trace::{AccessKind, Tracer, TraceEntry};
+use ProgramMeta;
+
+// Resolves a tail call: receives the identifier of a program array and an index in this array, and
+// returns the bytecode of the program to jump to, if any.
+pub type TailCallResolver<'a> = dyn Fn(u32, u32) -> Option<&'a [u8]> + 'a;
+
+// The memory areas a program can access: the metadata buffer, packet data, the stack, and for
+// multi-buffer packets, the fragments following the first one (which is packet data), which
+// `bpf_xdp_store_bytes()` writes through the pointers taken from mutable slices. Also the
+// scratch storage of the VM, the map values returned by lookups so far, and the values of the
+// configuration store, which are read-only. Packet data moves when the program resizes the packet.
+// With `read_only_packet`, packet data and the fragments are read-only as well.
+#[derive(Clone, Copy)]
+struct Areas<'m> {
+    mbuff: &'m [u8],
+    mem:   &'m Cell<&'m [u8]>,
+    stack: &'m [u8],
+    frags: &'m [*mut [u8]],
+    scratch: &'m [u8],
+    values:  &'m RefCell<Vec<ValuePtr>>,
+    config:  Option<&'m HashMap<String, Vec<u8>>>,
+    read_only_packet: bool,
+}
+
+impl<'m> Areas<'m> {
+    // The addresses and lengths of the areas, other than map values and configuration values.
+    fn iter(&self) -> impl Iterator<Item = (u64, usize)> + 'm {
+        IntoIterator::into_iter([self.mbuff, self.mem.get(), self.stack, self.scratch])
+            .map(|area| (area.as_ptr() as u64, area.len()))
+            .chain(self.frags.iter().map(|&frag| (frag as *mut u8 as u64, frag.len())))
+    }
+
+    // Whether `len` bytes at `addr` are in one of the areas.
+    fn contain(&self, addr: u64, len: usize) -> bool {
+        // Lengths passed to helpers can be large enough to overflow.
+        let end = addr.saturating_add(len as u64);
+        self.iter().any(|(start, len)| start <= addr && end <= start + len as u64) ||
+            self.values.borrow().iter().any(|value| value.addr <= addr && end <= value.addr + value.len as u64)
+    }
+
+    fn check(&self, addr: u64, len: usize, kind: AccessKind, pc: usize) -> Result<(), EbpfError> {
+        let writable = kind == AccessKind::Load || !self.read_only_packet || !self.packet_overlap(addr, len);
+        if writable && (self.contain(addr, len) || (kind == AccessKind::Load && self.config_contain(addr, len))) {
+            return Ok(());
+        }
+        let mut region_info = format!("mbuff: {:#x}/{:#x}, mem: {:#x}/{:#x}, stack: {:#x}/{:#x}",
+                                      self.mbuff.as_ptr() as u64, self.mbuff.len(),
+                                      self.mem.get().as_ptr() as u64, self.mem.get().len(),
+                                      self.stack.as_ptr() as u64, self.stack.len());
+        for &frag in self.frags {
+            region_info += &format!(", frag: {:#x}/{:#x}", frag as *mut u8 as u64, frag.len());
+        }
+        if !self.scratch.is_empty() {
+            region_info += &format!(", scratch: {:#x}/{:#x}", self.scratch.as_ptr() as u64, self.scratch.len());
+        }
+        for value in self.values.borrow().iter() {
+            region_info += &format!(", map value: {:#x}/{:#x}", value.addr, value.len);
+        }
+        if self.read_only_packet {
+            region_info += ", packet read-only";
+        }
+        Err(EbpfError::OutOfBounds { pc, kind, addr, len, region_info })
+    }
+
+    // Whether some of the `len` bytes at `addr` are in packet data or in a fragment.
+    fn packet_overlap(&self, addr: u64, len: usize) -> bool {
+        let end = addr.saturating_add(len as u64);
+        std::iter::once((self.mem.get().as_ptr() as u64, self.mem.get().len()))
+            .chain(self.frags.iter().map(|&frag| (frag as *mut u8 as u64, frag.len())))
+            .any(|(start, len)| addr < start + len as u64 && start < end)
+    }
+
+    // Whether `len` bytes at `addr` are in one of the values of the configuration store.
+    fn config_contain(&self, addr: u64, len: usize) -> bool {
+        let end = addr.saturating_add(len as u64);
+        self.config.into_iter().flat_map(|config| config.values())
+            .any(|value| value.as_ptr() as u64 <= addr && end <= value.as_ptr() as u64 + value.len() as u64)
+    }
+
+    // Total length of a multi-buffer packet.
+    fn packet_len(&self) -> usize {
+        self.mem.get().len() + self.frags.iter().map(|frag| frag.len()).sum::<usize>()
+    }
+}
+
+// Parses an integer as `bpf_strtol()` and `bpf_strtoul()` do in the kernel (see
+// `__bpf_strtoull()` in kernel/bpf/helpers.c). Returns the absolute value, whether it is
+// negative, and the number of characters consumed, or an error code.
+fn strtoull(buf: &[u8], flags: u64) -> Result<(u64, bool, usize), i64> {
+    const BASE_MASK: u64 = 0x1f;
+    const MAX_LEN: usize = 63;
+    let is_space = |c: u8| c == b' ' || (b'\t'..=b'\r').contains(&c);
+
+    let mut base = (flags & BASE_MASK) as u32;
+    if flags & !BASE_MASK != 0 || ![0, 8, 10, 16].contains(&base) || buf.is_empty() {
+        return Err(EINVAL);
+    }
+    let mut consumed = buf.iter().take_while(|&&c| is_space(c)).count();
+    let negative = buf.get(consumed) == Some(&b'-');
+    if negative {
+        consumed += 1;
+    }
+    if consumed == buf.len() {
+        return Err(EINVAL);
+    }
+    let rest = &buf[consumed..buf.len().min(consumed + MAX_LEN)];
+    let rest = &rest[..rest.iter().position(|&c| c == 0).unwrap_or(rest.len())];
+
+    let has_prefix = rest.len() >= 2 && rest[0] == b'0' && rest[1].eq_ignore_ascii_case(&b'x');
+    if base == 0 {
+        base = match rest.first() {
+            Some(b'0') if has_prefix && rest.get(2).is_some_and(|c| c.is_ascii_hexdigit()) => 16,
+            Some(b'0') => 8,
+            _          => 10,
+        };
+    }
+    let mut digits = rest;
+    if base == 16 && has_prefix {
+        digits = &rest[2..];
+        consumed += 2;
+    }
+
+    let (mut value, mut overflow, mut len) = (0u64, false, 0);
+    for &c in digits {
+        let digit = match (c as char).to_digit(16) {
+            Some(d) if d < base => d as u64,
+            _                   => break,
+        };
+        match value.checked_mul(base as u64).and_then(|v| v.checked_add(digit)) {
+            Some(v) => value = v,
+            None    => overflow = true,
+        }
+        len += 1;
+    }
+    if overflow {
+        return Err(ERANGE);
+    }
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    Ok((value, negative, consumed + len))
+}
+
+// Runs one of the bounds-checked memory helpers, called by instruction `pc`, on arguments `args`.
+fn mem_helper(key: u32, args: &[u64], pc: usize, areas: Areas) -> Result<u64, EbpfError> {
+    let check = |addr: u64, len: usize, kind: AccessKind| match len {
+        0 => Ok(()),
+        _ => areas.check(addr, len, kind, pc),
+    };
+    if key == BPF_STRTOL_IDX || key == BPF_STRTOUL_IDX {
+        let len = args[1] as usize;
+        check(args[0], len, AccessKind::Load)?;
+        check(args[3], 8, AccessKind::Store)?;
+        let buf = match len {
+            0 => &[],
+            _ => unsafe { std::slice::from_raw_parts(args[0] as *const u8, len) },
+        };
+        let res = strtoull(buf, args[2]).and_then(|(value, negative, consumed)| {
+            let value = match (key, negative) {
+                (BPF_STRTOUL_IDX, true)                         => return Err(EINVAL),
+                (BPF_STRTOUL_IDX, false)                        => value,
+                (_, true) if value.wrapping_neg() as i64 > 0    => return Err(ERANGE),
+                (_, true)                                       => value.wrapping_neg(),
+                (_, false) if (value as i64) < 0                => return Err(ERANGE),
+                (_, false)                                      => value,
+            };
+            Ok((value, consumed))
+        });
+        return match res {
+            Ok((value, consumed)) => {
+                unsafe { (args[3] as *mut u64).write_unaligned(value) };
+                Ok(consumed as u64)
+            },
+            Err(err) => Ok(-err as u64),
+        };
+    }
+    if key == BPF_CSUM_DIFF_IDX {
+        let (from_size, to_size) = (args[1], args[3]);
+        if (from_size | to_size) % 4 != 0 || from_size.saturating_add(to_size) > 512 {
+            return Ok(-EINVAL as u64);
+        }
+        check(args[0], from_size as usize, AccessKind::Load)?;
+        check(args[2], to_size as usize, AccessKind::Load)?;
+        let area = |addr: u64, len: u64| match len {
+            0 => &[][..],
+            _ => unsafe { std::slice::from_raw_parts(addr as *const u8, len as usize) },
+        };
+        let sum = csum::csum_diff(area(args[0], from_size), area(args[2], to_size), args[4] as u32);
+        return Ok(sum as u64);
+    }
+
+    let len = args[2] as usize;
+    if len == 0 {
+        return Ok(0);
+    }
+    let res = match key {
+        MEMCPY_IDX => {
+            check(args[0], len, AccessKind::Store)?;
+            check(args[1], len, AccessKind::Load)?;
+            unsafe { std::ptr::copy(args[1] as *const u8, args[0] as *mut u8, len) };
+            0
+        },
+        MEMSET_IDX => {
+            check(args[0], len, AccessKind::Store)?;
+            unsafe { std::ptr::write_bytes(args[0] as *mut u8, args[1] as u8, len) };
+            0
+        },
+        _          => {
+            check(args[0], len, AccessKind::Load)?;
+            check(args[1], len, AccessKind::Load)?;
+            let (a, b) = unsafe {
+                (std::slice::from_raw_parts(args[0] as *const u8, len),
+                 std::slice::from_raw_parts(args[1] as *const u8, len))
+            };
+            match a.iter().zip(b.iter()).find(|&(x, y)| x != y) {
+                Some((&x, &y)) => (x as i64 - y as i64) as u64,
+                None           => 0,
+            }
+        },
+    };
+    Ok(res)
+}
+
+// A memory region readable with the `bpf_probe_read*()` helpers: its address space, the address
+// at which programs see it, and its contents.
+pub type ProbeRegion<'a> = (AddressSpace, u64, &'a [u8]);
+
+// Runs one of the `bpf_probe_read*()` helpers, called by instruction `pc`, on arguments `args`.
+fn probe_read(key: u32, args: &[u64], pc: usize, regions: &[ProbeRegion],
+              areas: Areas) -> Result<u64, EbpfError> {
+    let (dst, len, src) = (args[0], args[1] as usize, args[2]);
+    if len == 0 {
+        return Ok(0);
+    }
+    areas.check(dst, len, AccessKind::Store, pc)?;
+
+    let end = src.saturating_add(len as u64);
+    let contains = |start: u64, size: usize| start <= src && end <= start.saturating_add(size as u64);
+    let region = regions.iter().find(|&&(space, start, data)| {
+        let readable = match space {
+            AddressSpace::User   => key != BPF_PROBE_READ_KERNEL_IDX,
+            AddressSpace::Kernel => key != BPF_PROBE_READ_USER_IDX,
+        };
+        readable && contains(start, data.len())
+    });
+    if let Some(&(_, start, data)) = region {
+        let offset = (src - start) as usize;
+        unsafe { std::ptr::copy(data[offset..offset + len].as_ptr(), dst as *mut u8, len) };
+        return Ok(0);
+    }
+    // The memory of the program is kernel memory.
+    let own = areas.contain(src, len);
+    if own && key != BPF_PROBE_READ_USER_IDX {
+        unsafe { std::ptr::copy(src as *const u8, dst as *mut u8, len) };
+        return Ok(0);
+    }
+    unsafe { std::ptr::write_bytes(dst as *mut u8, 0, len) };
+    Ok(-EFAULT as u64)
+}
+
+// Runs one of the helpers for multi-buffer packets, called by instruction `pc`, on arguments
+// `args`. The packet is made of packet data followed by the fragments of `areas`.
+fn xdp_helper(key: u32, args: &[u64], pc: usize, areas: Areas) -> Result<u64, EbpfError> {
+    if key == BPF_XDP_GET_BUFF_LEN_IDX {
+        return Ok(areas.packet_len() as u64);
+    }
+    let (offset, buf, len) = (args[1] as u32 as usize, args[2], args[3] as usize);
+    let kind = match key {
+        BPF_XDP_LOAD_BYTES_IDX => AccessKind::Store,
+        _                      => AccessKind::Load,
+    };
+    if len == 0 || offset.saturating_add(len) > areas.packet_len() {
+        return Ok(-EINVAL as u64);
+    }
+    if key == BPF_XDP_STORE_BYTES_IDX && areas.read_only_packet {
+        return Ok(-EPERM as u64);
+    }
+    areas.check(buf, len, kind, pc)?;
+
+    // Copy the bytes from or to each segment overlapping the range.
+    let mem = areas.mem.get();
+    let segs = std::iter::once((mem.as_ptr() as *mut u8, mem.len()))
+        .chain(areas.frags.iter().map(|&frag| (frag as *mut u8, frag.len())));
+    let (mut start, mut copied) = (0, 0);
+    for (seg_ptr, seg_len) in segs {
+        let from = offset + copied;
+        if copied < len && from < start + seg_len {
+            let n = (start + seg_len - from).min(len - copied);
+            let buf_ptr = (buf + copied as u64) as *mut u8;
+            unsafe {
+                let seg_ptr = seg_ptr.add(from - start);
+                match key {
+                    BPF_XDP_LOAD_BYTES_IDX => std::ptr::copy(seg_ptr, buf_ptr, n),
+                    _                      => std::ptr::copy(buf_ptr, seg_ptr, n),
+                }
+            }
+            copied += n;
+        }
+        start += seg_len;
+    }
+    Ok(0)
+}
+
+// A packet that the program can resize within its buffer, see `PacketBuffer`. Packet data is
+// `buffer[window.0..window.1]`, and the pointers to its start and end are stored at
+// `data_offsets` in the metadata buffer.
+#[derive(Clone, Copy)]
+pub struct ResizablePacket<'b> {
+    pub buffer:       &'b [u8],
+    pub window:       &'b Cell<(usize, usize)>,
+    pub data_offsets: (usize, usize),
+}
+
+// Length of an Ethernet header, the minimum length of resized packets.
+const ETH_HLEN: usize = 14;
+
+// The length of the headers that `bpf_skb_adjust_room()` keeps before the room, in mode `mode`.
+fn room_offset(data: &[u8], mode: u64) -> Option<usize> {
+    let offset = match (mode, data.get(12..14)) {
+        (BPF_ADJ_ROOM_MAC, _)                     => ETH_HLEN,
+        (BPF_ADJ_ROOM_NET, Some(&[0x08, 0x00])) => ETH_HLEN + (*data.get(ETH_HLEN)? & 0xf) as usize * 4,
+        (BPF_ADJ_ROOM_NET, Some(&[0x86, 0xdd])) => ETH_HLEN + 40,
+        _                                         => return None,
+    };
+    Some(offset).filter(|&offset| offset <= data.len())
+}
+
+// Runs one of the helpers resizing packets on arguments `args`: moves packet data within the
+// buffer of `packet`, and updates the areas and the pointers of the metadata buffer.
+fn adjust_helper<'m>(key: u32, args: &[u64], packet: &ResizablePacket<'m>, areas: Areas<'m>) -> u64 {
+    if areas.read_only_packet {
+        return -EPERM as u64;
+    }
+    let (start, end) = packet.window.get();
+    let delta = args[1] as i32 as i64;
+    let headers = match key {
+        BPF_SKB_ADJUST_ROOM_IDX => match room_offset(&packet.buffer[start..end], args[2]) {
+            Some(offset) => offset,
+            None         => return -EINVAL as u64,
+        },
+        _ => 0,
+    };
+    let (new_start, new_end) = match key {
+        BPF_XDP_ADJUST_HEAD_IDX => (start as i64 + delta, end as i64),
+        BPF_XDP_ADJUST_TAIL_IDX => (start as i64, end as i64 + delta),
+        _                       => (start as i64 - delta, end as i64),
+    };
+    if new_start < 0 || new_end > packet.buffer.len() as i64 ||
+       new_end - new_start < ETH_HLEN.max(headers) as i64 {
+        return -EINVAL as u64;
+    }
+    let (new_start, new_end) = (new_start as usize, new_end as usize);
+    let base = packet.buffer.as_ptr() as *mut u8;
+    unsafe {
+        if key == BPF_SKB_ADJUST_ROOM_IDX {
+            std::ptr::copy(base.add(start), base.add(new_start), headers);
+            if new_start < start {
+                std::ptr::write_bytes(base.add(new_start + headers), 0, start - new_start);
+            }
+        } else if new_end > end {
+            std::ptr::write_bytes(base.add(end), 0, new_end - end);
+        }
+        let (data, data_end) = packet.data_offsets;
+        (areas.mbuff.as_ptr().add(data) as *mut u64).write_unaligned(base.add(new_start) as u64);
+        (areas.mbuff.as_ptr().add(data_end) as *mut u64).write_unaligned(base.add(new_end) as u64);
+    }
+    packet.window.set((new_start, new_end));
+    areas.mem.set(&packet.buffer[new_start..new_end]);
+    0
+}
+
+// Runs one of the helpers reading the configuration store `config`, called by instruction `pc`,
+// on arguments `args`.
+fn config_helper(key: u32, args: &[u64], pc: usize, config: &HashMap<String, Vec<u8>>, areas: Areas)
+                 -> Result<u64, EbpfError> {
+    let (config_key, key_len) = (args[0], args[1] as usize);
+    let bytes: &[u8] = match key_len {
+        0 => &[],
+        _ => {
+            areas.check(config_key, key_len, AccessKind::Load, pc)?;
+            unsafe { std::slice::from_raw_parts(config_key as *const u8, key_len) }
+        },
+    };
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    let value = std::str::from_utf8(bytes).ok().and_then(|config_key| config.get(config_key));
+    Ok(match (key, value) {
+        (CONFIG_LOOKUP_IDX, Some(value)) => value.as_ptr() as u64,
+        (CONFIG_LOOKUP_IDX, None)        => 0,
+        (_, Some(value))                 => value.len() as u64,
+        (_, None)                        => -ENOENT as u64,
+    })
+}
+
+// Runs one of the map helpers, called by instruction `pc`, on arguments `args`. The values
+// returned by lookups are added to the areas, so that the program can access them.
+fn map_helper(key: u32, args: &[u64], pc: usize, maps: &[Arc<Map>], areas: Areas) -> Result<u64, EbpfError> {
+    let map = match maps.get(args[0] as usize) {
+        Some(map) => map,
+        None if key == BPF_MAP_LOOKUP_ELEM_IDX => return Ok(0),
+        None      => return Ok(-EINVAL as u64),
+    };
+    let read = |addr: u64, len: usize| -> Result<&[u8], EbpfError> {
+        areas.check(addr, len, AccessKind::Load, pc)?;
+        Ok(unsafe { std::slice::from_raw_parts(addr as *const u8, len) })
+    };
+    // Keys and values are read in place, so that lookups do not allocate.
+    let map_key = read(args[1], map.key_size())?;
+    let res = match key {
+        BPF_MAP_LOOKUP_ELEM_IDX => {
+            return Ok(match map.lookup_ptr(map_key) {
+                Some(value) => {
+                    let addr = value.addr;
+                    let mut values = areas.values.borrow_mut();
+                    if !values.iter().any(|v| v.addr == addr) {
+                        values.push(value);
+                    }
+                    addr
+                },
+                None => 0,
+            });
+        },
+        BPF_MAP_UPDATE_ELEM_IDX => map.update(map_key, read(args[2], map.value_size())?, args[3]),
+        _                       => map.delete(map_key),
+    };
+    Ok(match res {
+        Ok(())   => 0,
+        Err(err) => -err as u64,
+    })
+}
+
+// Runs `bpf_redirect_map()`, see `helpers::BPF_REDIRECT_MAP_IDX`.
+fn redirect_map(args: &[u64], maps: &[Arc<Map>], redirect: Option<&Cell<Option<Redirect>>>) -> u64 {
+    // Actions that the program may request when the target is absent.
+    const ACTION_MASK: u64 = 0x3;
+    let (map, key, flags) = (args[0] as u32, args[1] as u32, args[2]);
+    match maps.get(map as usize) {
+        Some(target) if target.map_type() == MapType::DevMap && flags & !ACTION_MASK == 0 => {
+            if target.lookup(&key.to_ne_bytes()).is_none() {
+                return flags;
+            }
+            if let Some(redirect) = redirect {
+                redirect.set(Some(Redirect { map, key, flags }));
+            }
+            ebpf::XdpAction::Redirect.value() as u64
+        },
+        _ => ebpf::XdpAction::Aborted.value() as u64,
+    }
+}
+
+// An iteration of `bpf_for_each_map_elem()` in progress, see `helpers::BPF_FOR_EACH_MAP_ELEM_IDX`:
+// the callback runs on the elements in turn, then the caller resumes with the number of elements
+// visited in R0.
+struct Callback {
+    // The arguments of the helper, and the registers and next instruction of the caller.
+    args:     [u64; 5],
+    reg:      [u64; 11],
+    insn_ptr: usize,
+    map:      Arc<Map>,
+    // The first instruction of the callback.
+    target:   usize,
+    // The keys of the elements left, taken when the iteration started.
+    keys:     std::vec::IntoIter<Vec<u8>>,
+    count:    u64,
+    // The addresses of the key passed to the callback and of its stack, lent to the program as
+    // map values for the duration of the iteration.
+    key:      u64,
+    stack:    u64,
+}
+
+impl Callback {
+    fn new(args: [u64; 5], reg: [u64; 11], insn_ptr: usize, map: &Arc<Map>, areas: Areas) -> Callback {
+        let key = ValuePtr::zeroed(map.key_size());
+        let stack = ValuePtr::zeroed(ebpf::STACK_SIZE);
+        let callback = Callback {
+            args, reg, insn_ptr, map: map.clone(), target: args[1] as usize,
+            keys: map.iter().map(|(key, _)| key).collect::<Vec<_>>().into_iter(), count: 0,
+            key: key.addr, stack: stack.addr,
+        };
+        areas.values.borrow_mut().extend(vec![key, stack]);
+        callback
+    }
+
+    // Prepares the call of the callback on the next element still in the map, if any.
+    fn next(&mut self, reg: &mut [u64; 11], areas: Areas) -> bool {
+        for key in self.keys.by_ref() {
+            let value = match self.map.lookup_ptr(&key) {
+                Some(value) => value,
+                None        => continue,
+            };
+            unsafe { std::ptr::copy_nonoverlapping(key.as_ptr(), self.key as *mut u8, key.len()) };
+            *reg = [0, self.args[0], self.key, value.addr, self.args[2], 0, 0, 0, 0, 0,
+                    self.stack + ebpf::STACK_SIZE as u64];
+            let mut values = areas.values.borrow_mut();
+            if !values.iter().any(|v| v.addr == value.addr) {
+                values.push(value);
+            }
+            self.count += 1;
+            return true;
+        }
+        false
+    }
+
+    // Ends the iteration: returns the registers of the caller, with the result in R0.
+    fn finish(&self, areas: Areas) -> [u64; 11] {
+        areas.values.borrow_mut().retain(|value| value.addr != self.key && value.addr != self.stack);
+        let mut reg = self.reg;
+        reg[0] = self.count;
+        reg
+    }
+}
+
+// Sign-extends the lowest `bits` bits of `value`, for sign-extending moves and loads, if `bits` is
+// 8, 16 or 32.
+fn sign_extend(value: u64, bits: i16) -> Option<u64> {
+    match bits {
+        8  => Some(value as i8  as u64),
+        16 => Some(value as i16 as u64),
+        32 => Some(value as i32 as u64),
+        _  => None,
+    }
+}
+
+// Signed division or modulo of `dst` by `src`, in 32 or 64 bits depending on the class of `opc`.
+// Returns `None` if `src` is zero. As in the kernel, dividing the lowest signed integer by -1
+// does not overflow: the division returns the dividend and the modulo returns 0.
+fn signed_div_mod(opc: u8, dst: u64, src: u64) -> Option<u64> {
+    let div = opc & ebpf::BPF_ALU_OP_MASK == ebpf::BPF_DIV;
+    match opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ALU => match (div, src as i32) {
+            (_, 0)        => None,
+            (true, src)   => Some((dst as i32).wrapping_div(src) as u32 as u64),
+            (false, src)  => Some((dst as i32).wrapping_rem(src) as u32 as u64),
+        },
+        _             => match (div, src as i64) {
+            (_, 0)        => None,
+            (true, src)   => Some((dst as i64).wrapping_div(src) as u64),
+            (false, src)  => Some((dst as i64).wrapping_rem(src) as u64),
+        },
+    }
+}
+
+// Index of the instruction `off` instructions after the one following `pc`, for a program of `len`
+// instructions. The verifier rejects jumps out of the program, but programs may be run without
+// being verified.
+fn jump_target(pc: usize, off: i64, len: usize) -> Result<usize, EbpfError> {
+    let target = pc as i64 + 1 + off;
+    match target >= 0 && (target as usize) < len {
+        true  => Ok(target as usize),
+        false => Err(EbpfError::JumpOutOfBounds { pc, target }),
+    }
+}
+
+// Whether a jump of class `BPF_JMP32` with operation code `opc` is taken, for operands `a` and `b`,
+// if `opc` is a conditional jump.
+fn jmp32_taken(opc: u8, a: u32, b: u32) -> Option<bool> {
+    Some(match opc & ebpf::BPF_ALU_OP_MASK {
+        ebpf::BPF_JEQ  => a == b,
+        ebpf::BPF_JGT  => a >  b,
+        ebpf::BPF_JGE  => a >= b,
+        ebpf::BPF_JSET => a &  b != 0,
+        ebpf::BPF_JNE  => a != b,
+        ebpf::BPF_JSGT => (a as i32) >  b as i32,
+        ebpf::BPF_JSGE => (a as i32) >= b as i32,
+        ebpf::BPF_JLT  => a <  b,
+        ebpf::BPF_JLE  => a <= b,
+        ebpf::BPF_JSLT => (a as i32) <  b as i32,
+        ebpf::BPF_JSLE => (a as i32) <= b as i32,
+        _              => return None,
+    })
+}
+
+// Optional features of the interpreter, all disabled by default.
+#[derive(Default)]
+pub struct Options<'a, 'b> {
+    pub helper_hook: Option<&'b dyn HelperHook>,
+    // Run these helpers taking their arguments in a frame, see `helpers::ArgFrame`.
+    pub frame_helpers: Option<&'b HashMap<u32, FrameHelper>>,
+    // Run these helpers returning errors that abort the execution.
+    pub fallible_helpers: Option<&'b HashMap<u32, ebpf::FallibleHelper>>,
+    pub tail_calls:  Option<&'b TailCallResolver<'a>>,
+    pub tracer:      Option<&'b mut dyn Tracer>,
+    pub line_info:   Option<&'b LineInfo>,
+    // Run the bounds-checked memory helpers, see `helpers::MEMCPY_IDX`.
+    pub mem_helpers: bool,
+    // Run the `bpf_probe_read*()` helpers over these regions, unless empty.
+    pub probe_regions: &'b [ProbeRegion<'a>],
+    // Run `bpf_perf_event_output()`, passing the events to this sink.
+    pub event_sink:  Option<&'b dyn EventSink>,
+    // Run the helpers for multi-buffer packets, with these fragments following packet data, taken
+    // from mutable slices that outlive the execution.
+    pub frags:       Option<&'b [*mut [u8]]>,
+    // Run `get_scratch()`, returning this region, kept by the VM between executions.
+    pub scratch:     Option<&'b RefCell<Vec<u8>>>,
+    // Run the map helpers, on these maps, unless empty.
+    pub maps:        &'b [Arc<Map>],
+    // Record the redirect requested with `bpf_redirect_map()` here.
+    pub redirect:    Option<&'b Cell<Option<Redirect>>>,
+    pub div_by_zero: ebpf::DivByZero,
+    // Skip the runtime checks of the loads and stores at the indexes set in this slice, proven in
+    // bounds with `verifier::check_bounds()`.
+    pub proven_accesses: &'b [bool],
+    // Attribute errors and traces to this program, see `EbpfVmMbuff::set_name()`.
+    pub program:     Option<&'b ProgramMeta>,
+    // Maximum number of nested executions, `ebpf::MAX_CALL_DEPTH` if not set.
+    pub max_call_depth: Option<usize>,
+    // Fill the stack with `ebpf::POISON_BYTE`, and reject the loads of bytes of the stack never
+    // written, see `EbpfVmMbuff::set_memory_poisoning()`.
+    pub poison:      bool,
+    // Run the helpers reading the configuration store, on this store.
+    pub config:      Option<&'b HashMap<String, Vec<u8>>>,
+    // Run the helpers resizing packets, on this packet, see `EbpfVmFixedMbuff::prog_exec_resizable()`.
+    pub packet:      Option<ResizablePacket<'b>>,
+    // Count the instructions executed against this fuel, and suspend the execution when it runs
+    // out, see `EbpfVmMbuff::try_prog_exec_metered()`.
+    pub metering:    Option<&'b Metering<'a>>,
+    // Abort the execution once it has run this number of instructions, see
+    // `EbpfVmMbuff::set_insn_limit()`.
+    pub insn_limit:  Option<u64>,
+    // Reject the stores to packet data, see `EbpfVmMbuff::set_read_only_packet()`.
+    pub read_only_packet: bool,
+}
+
+// The state of an execution, kept between the instructions: it can be suspended, and resumed
+// later on the same memory. The stack does not move, since registers may point to it.
+pub struct ExecState<'a> {
+    reg:           [u64; 11],
+    stack:         Vec<u8>,
+    // When the stack is poisoned, the bytes of the stack written so far.
+    written:       Option<Vec<Cell<bool>>>,
+    values:        RefCell<Vec<ValuePtr>>,
+    // The program running, after tail calls.
+    prog:          &'a [u8],
+    insn_ptr:      usize,
+    tail_call_cnt: usize,
+    // The iterations of `bpf_for_each_map_elem()` running, innermost last.
+    callbacks:     Vec<Callback>,
+    // The number of instructions executed so far, counted with an instruction limit only.
+    insns:         u64,
+    // The addresses and lengths of the packet data and the metadata buffer.
+    memory:        [(usize, usize); 2],
+}
+
+impl<'a> ExecState<'a> {
+    fn new(prog: &'a [u8], mem: &[u8], mbuff: &[u8], poison: bool) -> ExecState<'a> {
+        let stack = vec![if poison { ebpf::POISON_BYTE } else { 0 }; ebpf::STACK_SIZE];
+        // R1 points to beginning of memory area, R10 to stack
+        let mut reg: [u64;11] = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, stack.as_ptr() as u64 + stack.len() as u64
+        ];
+        if !mbuff.is_empty() {
+            reg[1] = mbuff.as_ptr() as u64;
+        }
+        else if !mem.is_empty() {
+            reg[1] = mem.as_ptr() as u64;
+        }
+        // The verifier may prove accesses in bounds, not that they read initialized bytes: loads
+        // are checked even then.
+        let written = match poison {
+            true  => Some(vec![Cell::new(false); ebpf::STACK_SIZE]),
+            false => None,
+        };
+        ExecState {
+            reg, stack, written, values: RefCell::new(vec![]), prog, insn_ptr: 0, tail_call_cnt: 0, callbacks: vec![],
+            insns: 0,
+            memory: [(mem.as_ptr() as usize, mem.len()), (mbuff.as_ptr() as usize, mbuff.len())],
+        }
+    }
+
+    // The index of the next instruction to execute.
+    pub fn insn_ptr(&self) -> usize {
+        self.insn_ptr
+    }
+
+    // Whether the execution ran on `mem` and `mbuff`. Empty buffers have no address to check.
+    fn runs_on(&self, mem: &[u8], mbuff: &[u8]) -> bool {
+        [mem, mbuff].iter().zip(self.memory.iter())
+            .all(|(buf, &(addr, len))| buf.len() == len && (len == 0 || buf.as_ptr() as usize == addr))
+    }
+}
+
+// The fuel of an execution, in instructions, and its state once suspended: the state is taken
+// back to resume the execution.
+pub struct Metering<'a> {
+    pub fuel:  Cell<u64>,
+    pub state: RefCell<Option<ExecState<'a>>>,
+}
+
+thread_local! {
+    // Number of executions running on the thread: helpers may run programs themselves.
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Leaves the execution entered with `enter()` when dropped, even if a helper panics.
+pub struct CallDepthGuard(());
+
+impl Drop for CallDepthGuard {
+    fn drop(&mut self) {
+        CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+// Enters a new execution on the current thread, unless `limit` executions are already running.
+pub fn enter(limit: usize) -> Result<CallDepthGuard, EbpfError> {
+    CALL_DEPTH.with(|depth| match depth.get() {
+        running if running >= limit => Err(EbpfError::CallDepthExceeded { limit }),
+        running                      => {
+            depth.set(running + 1);
+            Ok(CallDepthGuard(()))
+        },
+    })
+}
+
+// Interprets the program, panicking on errors.
+pub fn execute_program<'a>(prog: &'a [u8], mem: &[u8], mbuff: &[u8],
+                           helpers: &HashMap<u32, ebpf::Helper>, options: Options<'a, '_>) -> u64 {
+    let line_info = options.line_info;
+    match try_execute_program(prog, mem, mbuff, helpers, options) {
+        Ok(res)  => res,
+        Err(err) => panic!("{}", err.report(prog, line_info)),
+    }
+}
+
+pub fn try_execute_program<'a>(prog: &'a [u8], mem: &[u8], mbuff: &[u8],
+                               helpers: &HashMap<u32, ebpf::Helper>, mut options: Options<'a, '_>)
+                               -> Result<u64, EbpfError> {
+    let program = options.program;
+    let in_program = |err: EbpfError| match program {
+        Some(program) => err.in_program(program),
+        None          => err,
+    };
+    let _depth = enter(options.max_call_depth.unwrap_or(ebpf::MAX_CALL_DEPTH)).map_err(in_program)?;
+    let metering = options.metering;
+    let mut state = match metering.and_then(|metering| metering.state.borrow_mut().take()) {
+        Some(state) => {
+            if !state.runs_on(mem, mbuff) {
+                let msg = "Error: execution resumed on other packet data or metadata buffer than it started on";
+                return Err(in_program(EbpfError::InvalidResume(msg.to_string())));
+            }
+            state
+        },
+        None        => {
+            if let (Some(program), Some(tracer)) = (program, options.tracer.as_mut()) {
+                tracer.start_program(program);
+            }
+            ExecState::new(prog, mem, mbuff, options.poison)
+        },
+    };
+    match run(&mut state, mem, mbuff, helpers, options).map_err(in_program)? {
+        Some(res) => Ok(res),
+        // Out of fuel: the caller finds the state in `metering`.
+        None      => {
+            *metering.unwrap().state.borrow_mut() = Some(state);
+            Ok(0)
+        },
+    }
+}
+
+// Runs the program from `state` until it exits, or, with metering, until it runs out of fuel:
+// the state is then that of the execution suspended, and `None` is returned.
+fn run<'a>(state: &mut ExecState<'a>, mem: &[u8], mbuff: &[u8], helpers: &HashMap<u32, ebpf::Helper>,
+           options: Options<'a, '_>) -> Result<Option<u64>, EbpfError> {
+    let Options { helper_hook, frame_helpers, fallible_helpers, tail_calls, mut tracer, line_info: _, mem_helpers, probe_regions,
+                  event_sink, frags, scratch, maps, redirect, div_by_zero, proven_accesses, program: _,
+                  max_call_depth: _, poison: _, config, packet, metering, insn_limit,
+                  read_only_packet } = options;
+    let strict_div = div_by_zero == ebpf::DivByZero::Error;
+    const U32MAX: u64 = u32::MAX as u64;
+
+    let stack = &state.stack;
+    let mut reg = state.reg;
+
+    // The region is borrowed for the whole execution, programs write to it through the pointer
+    // returned by `get_scratch()`.
+    let mut scratch = scratch.map(|region| region.borrow_mut());
+    let scratch_ptr = scratch.as_mut().map_or(0, |region| region.as_mut_ptr() as u64);
+    let mem = Cell::new(mem);
+    let areas = Areas { mbuff, mem: &mem, stack, frags: frags.unwrap_or(&[]),
+                        scratch: scratch.as_ref().map_or(&[], |region| &region[..]), values: &state.values,
+                        config, read_only_packet };
+    let written = &state.written;
+    let stack_top = stack.as_ptr() as u64 + stack.len() as u64;
+    let stack_bytes = |addr: u64, len: usize| {
+        let start = addr.max(stack.as_ptr() as u64);
+        let end = addr.saturating_add(len as u64).min(stack_top);
+        (start - stack.as_ptr() as u64) as usize..end.saturating_sub(stack.as_ptr() as u64) as usize
+    };
+    // The proofs are about the program loaded, they do not hold after a tail call.
+    let proven_accesses = Cell::new(if state.tail_call_cnt == 0 { proven_accesses } else { &[] });
+    let check_mem_load = | addr: u64, len: usize, pc: usize | {
+        if proven_accesses.get().get(pc) != Some(&true) {
+            areas.check(addr, len, AccessKind::Load, pc)?;
+        }
+        match written {
+            Some(ref written) if stack_bytes(addr, len).any(|i| !written[i].get()) => {
+                Err(EbpfError::UninitializedRead { pc, off: addr.wrapping_sub(stack_top) as i64, len })
+            },
+            _ => Ok(()),
+        }
+    };
+    // The accesses proven in bounds are to the stack or to a context of known size, which is never
+    // packet data: the stores skipping their checks cannot write to read-only packet data.
+    let check_mem_store = | addr: u64, len: usize, pc: usize | {
+        if proven_accesses.get().get(pc) != Some(&true) {
+            areas.check(addr, len, AccessKind::Store, pc)?;
+        }
+        if let Some(ref written) = written {
+            stack_bytes(addr, len).for_each(|i| written[i].set(true));
+        }
+        Ok(())
+    };
+
+    // Loop on instructions
+    let mut prog = state.prog;
+    let mut tail_call_cnt = state.tail_call_cnt;
+    let mut insn_ptr = state.insn_ptr;
+    let mut pc = 0;
+    while insn_ptr * ebpf::INSN_SIZE < prog.len() {
+        if let Some(metering) = metering {
+            if metering.fuel.get() == 0 {
+                state.reg = reg;
+                state.prog = prog;
+                state.insn_ptr = insn_ptr;
+                state.tail_call_cnt = tail_call_cnt;
+                return Ok(None);
+            }
+            metering.fuel.set(metering.fuel.get() - 1);
+        }
+        if let Some(limit) = insn_limit {
+            if state.insns == limit {
+                return Err(EbpfError::ExceededLimit(format!(
+                    "Error: exceeded the limit of {} instructions (insn #{})", limit, insn_ptr)));
+            }
+            state.insns += 1;
+        }
+        let insn = ebpf::get_insn(prog, insn_ptr);
+        pc = insn_ptr;
+        let prog_len = prog.len() / ebpf::INSN_SIZE;
+        let jump = |off: i64| jump_target(pc, off, prog_len);
+        // The verifier rejects the instructions not supported, but programs may be run without
+        // being verified.
+        let unsupported = || EbpfError::UnsupportedInstruction { pc, opc: insn.opc };
+        let regs_before = reg;
+        insn_ptr += 1;
+        let _dst    = insn.dst as usize;
+        let _src    = insn.src as usize;
+
+        match insn.opc {
+
+            // BPF_LD class
+            ebpf::LD_ABS_B   => return Err(unsupported()),
+            ebpf::LD_ABS_H   => return Err(unsupported()),
+            ebpf::LD_ABS_W   => return Err(unsupported()),
+            ebpf::LD_ABS_DW  => return Err(unsupported()),
+            ebpf::LD_IND_B   => return Err(unsupported()),
+            ebpf::LD_IND_H   => return Err(unsupported()),
+            ebpf::LD_IND_W   => return Err(unsupported()),
+            ebpf::LD_IND_DW  => return Err(unsupported()),
+
+            // BPF_LDX class
+            ebpf::LD_DW_IMM  => {
+                let next_insn = ebpf::get_insn(prog, insn_ptr);
+                insn_ptr += 1;
+                if insn.src == ebpf::BPF_PSEUDO_MAP_FD && insn.imm as u32 as usize >= maps.len() {
+                    return Err(EbpfError::UnknownMap { pc, id: insn.imm as u32 });
+                }
+                if insn.src == ebpf::BPF_PSEUDO_FUNC {
+                    reg[_dst] = (pc as i64 + 1 + insn.imm as i64) as u64;
+                } else if insn.src != ebpf::BPF_PSEUDO_MAP_VALUE {
+                    reg[_dst] = ((insn.imm as u32) as u64) + ((next_insn.imm as u64) << 32);
+                } else {
+                    // The value is added to the areas, as for lookups, see `map_helper()`.
+                    let value = maps.get(insn.imm as u32 as usize)
+                        .filter(|map| map.map_type() == MapType::Array)
+                        .and_then(|map| map.lookup_ptr(&0u32.to_ne_bytes()));
+                    let value = match value {
+                        Some(value) => value,
+                        None        => return Err(EbpfError::UnknownMap { pc, id: insn.imm as u32 }),
+                    };
+                    reg[_dst] = value.addr + next_insn.imm as u32 as u64;
+                    let mut values = areas.values.borrow_mut();
+                    if !values.iter().any(|v| v.addr == value.addr) {
+                        values.push(value);
+                    }
+                }
+            },
+            ebpf::LD_B_REG   => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize);
+                check_mem_load(x as u64, 1, pc)?;
+                x.read_unaligned() as u64
+            },
+            ebpf::LD_H_REG   => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u16;
+                check_mem_load(x as u64, 2, pc)?;
+                x.read_unaligned() as u64
+            },
+            ebpf::LD_W_REG   => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u32;
+                check_mem_load(x as u64, 4, pc)?;
+                x.read_unaligned() as u64
+            },
+            ebpf::LD_DW_REG  => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u64;
+                check_mem_load(x as u64, 8, pc)?;
+                x.read_unaligned()
+            },
+            ebpf::LD_B_SX    => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const i8;
+                check_mem_load(x as u64, 1, pc)?;
+                x.read_unaligned() as u64
+            },
+            ebpf::LD_H_SX    => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const i16;
+                check_mem_load(x as u64, 2, pc)?;
+                x.read_unaligned() as u64
+            },
+            ebpf::LD_W_SX    => reg[_dst] = unsafe {
+                let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const i32;
+                check_mem_load(x as u64, 4, pc)?;
+                x.read_unaligned() as u64
+            },
+
+            // BPF_ST class
+            ebpf::ST_B_IMM   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u8;
+                check_mem_store(x as u64, 1, pc)?;
+                x.write_unaligned(insn.imm as u8);
+            },
+            ebpf::ST_H_IMM   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u16;
+                check_mem_store(x as u64, 2, pc)?;
+                x.write_unaligned(insn.imm as u16);
+            },
+            ebpf::ST_W_IMM   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u32;
+                check_mem_store(x as u64, 4, pc)?;
+                x.write_unaligned(insn.imm as u32);
+            },
+            ebpf::ST_DW_IMM  => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u64;
+                check_mem_store(x as u64, 8, pc)?;
+                x.write_unaligned(insn.imm as u64);
+            },
+
+            // BPF_STX class
+            ebpf::ST_B_REG   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u8;
+                check_mem_store(x as u64, 1, pc)?;
+                x.write_unaligned(reg[_src] as u8);
+            },
+            ebpf::ST_H_REG   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u16;
+                check_mem_store(x as u64, 2, pc)?;
+                x.write_unaligned(reg[_src] as u16);
+            },
+            ebpf::ST_W_REG   => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u32;
+                check_mem_store(x as u64, 4, pc)?;
+                x.write_unaligned(reg[_src] as u32);
+            },
+            ebpf::ST_DW_REG  => unsafe {
+                let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u64;
+                check_mem_store(x as u64, 8, pc)?;
+                x.write_unaligned(reg[_src]);
+            },
+            ebpf::ST_W_XADD  => return Err(unsupported()),
+            ebpf::ST_DW_XADD => return Err(unsupported()),
+
+            // Signed divisions and modulos, and sign-extending moves (ISA v4), with a non-zero
+            // offset
+            ebpf::DIV32_IMM | ebpf::DIV32_REG | ebpf::MOD32_IMM | ebpf::MOD32_REG |
+            ebpf::DIV64_IMM | ebpf::DIV64_REG | ebpf::MOD64_IMM | ebpf::MOD64_REG if insn.off == 1 => {
+                let src = match insn.opc & ebpf::BPF_X {
+                    0 => insn.imm as u64,
+                    _ => reg[_src],
+                };
+                reg[_dst] = match signed_div_mod(insn.opc, reg[_dst], src) {
+                    Some(res) => res,
+                    None if strict_div => return Err(EbpfError::DivideByZero { pc }),
+                    None => match (insn.opc & ebpf::BPF_ALU_OP_MASK, insn.opc & ebpf::BPF_CLS_MASK) {
+                        (ebpf::BPF_DIV, _)         => 0,
+                        (_, ebpf::BPF_ALU)         => reg[_dst] as u32 as u64,
+                        (_, _)                     => reg[_dst],
+                    },
+                };
+            },
+            ebpf::MOV32_REG if insn.off != 0 => reg[_dst] = sign_extend(reg[_src], insn.off).ok_or_else(unsupported)? as u32 as u64,
+            ebpf::MOV64_REG if insn.off != 0 => reg[_dst] = sign_extend(reg[_src], insn.off).ok_or_else(unsupported)?,
+
+            // BPF_ALU class
+            // As in the kernel, operations only consider the lower 32 bits of their operands, and
+            // zero the upper 32 bits of the destination register.
+            ebpf::ADD32_IMM  => reg[_dst] = (reg[_dst] as u32).wrapping_add(insn.imm  as u32) as u64,
+            ebpf::ADD32_REG  => reg[_dst] = (reg[_dst] as u32).wrapping_add(reg[_src] as u32) as u64,
+            ebpf::SUB32_IMM  => reg[_dst] = (reg[_dst] as u32).wrapping_sub(insn.imm  as u32) as u64,
+            ebpf::SUB32_REG  => reg[_dst] = (reg[_dst] as u32).wrapping_sub(reg[_src] as u32) as u64,
+            ebpf::MUL32_IMM  => reg[_dst] = (reg[_dst] as u32).wrapping_mul(insn.imm  as u32) as u64,
+            ebpf::MUL32_REG  => reg[_dst] = (reg[_dst] as u32).wrapping_mul(reg[_src] as u32) as u64,
+            ebpf::DIV32_IMM  => reg[_dst] = (reg[_dst] as u32 / insn.imm              as u32) as u64,
+            ebpf::DIV32_REG  => {
+                if reg[_src] as u32 == 0 && strict_div {
+                    return Err(EbpfError::DivideByZero { pc });
+                }
+                reg[_dst] = (reg[_dst] as u32).checked_div(reg[_src] as u32).unwrap_or(0) as u64;
+            },
+            ebpf::OR32_IMM   =>   reg[_dst] = (reg[_dst] as u32             | insn.imm  as u32) as u64,
+            ebpf::OR32_REG   =>   reg[_dst] = (reg[_dst] as u32             | reg[_src] as u32) as u64,
+            ebpf::AND32_IMM  =>   reg[_dst] = (reg[_dst] as u32             & insn.imm  as u32) as u64,
+            ebpf::AND32_REG  =>   reg[_dst] = (reg[_dst] as u32             & reg[_src] as u32) as u64,
+            ebpf::LSH32_IMM  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shl(insn.imm  as u32) as u64,
+            ebpf::LSH32_REG  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shl(reg[_src] as u32) as u64,
+            ebpf::RSH32_IMM  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shr(insn.imm  as u32) as u64,
+            ebpf::RSH32_REG  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shr(reg[_src] as u32) as u64,
+            ebpf::NEG32      => { reg[_dst] = (reg[_dst] as i32).wrapping_neg()                 as u64; reg[_dst] &= U32MAX; },
+            ebpf::MOD32_IMM  =>   reg[_dst] = (reg[_dst] as u32             % insn.imm  as u32) as u64,
+            ebpf::MOD32_REG  => {
+                if reg[_src] as u32 == 0 && strict_div {
+                    return Err(EbpfError::DivideByZero { pc });
+                }
+                let dst = reg[_dst] as u32;
+                reg[_dst] = dst.checked_rem(reg[_src] as u32).unwrap_or(dst) as u64;
+            },
+            ebpf::XOR32_IMM  =>   reg[_dst] = (reg[_dst] as u32             ^ insn.imm  as u32) as u64,
+            ebpf::XOR32_REG  =>   reg[_dst] = (reg[_dst] as u32             ^ reg[_src] as u32) as u64,
+            ebpf::MOV32_IMM  =>   reg[_dst] = insn.imm                              as u32  as u64,
+            ebpf::MOV32_REG  =>   reg[_dst] = (reg[_src] as u32)                                as u64,
+            ebpf::ARSH32_IMM => { reg[_dst] = (reg[_dst] as i32).wrapping_shr(insn.imm  as u32) as u64; reg[_dst] &= U32MAX; },
+            ebpf::ARSH32_REG => { reg[_dst] = (reg[_dst] as i32).wrapping_shr(reg[_src] as u32) as u64; reg[_dst] &= U32MAX; },
+            ebpf::LE         => {
+                reg[_dst] = match insn.imm {
+                    16 => (reg[_dst] as u16).to_le() as u64,
+                    32 => (reg[_dst] as u32).to_le() as u64,
+                    64 =>  reg[_dst].to_le(),
+                    _  => return Err(unsupported()),
+                };
+            },
+            ebpf::BE         => {
+                reg[_dst] = match insn.imm {
+                    16 => (reg[_dst] as u16).to_be() as u64,
+                    32 => (reg[_dst] as u32).to_be() as u64,
+                    64 =>  reg[_dst].to_be(),
+                    _  => return Err(unsupported()),
+                };
+            },
+
+            // BPF_ALU64 class
+            ebpf::ADD64_IMM  => reg[_dst] = reg[_dst].wrapping_add(insn.imm as u64),
+            ebpf::ADD64_REG  => reg[_dst] = reg[_dst].wrapping_add(reg[_src]),
+            ebpf::SUB64_IMM  => reg[_dst] = reg[_dst].wrapping_sub(insn.imm as u64),
+            ebpf::SUB64_REG  => reg[_dst] = reg[_dst].wrapping_sub(reg[_src]),
+            ebpf::MUL64_IMM  => reg[_dst] = reg[_dst].wrapping_mul(insn.imm as u64),
+            ebpf::MUL64_REG  => reg[_dst] = reg[_dst].wrapping_mul(reg[_src]),
+            ebpf::DIV64_IMM  => reg[_dst]                       /= insn.imm as u64,
+            ebpf::DIV64_REG  => {
+                if reg[_src] == 0 && strict_div {
+                    return Err(EbpfError::DivideByZero { pc });
+                }
+                reg[_dst] = reg[_dst].checked_div(reg[_src]).unwrap_or(0);
+            },
+            ebpf::OR64_IMM   => reg[_dst] |=  insn.imm as u64,
+            ebpf::OR64_REG   => reg[_dst] |=  reg[_src],
+            ebpf::AND64_IMM  => reg[_dst] &=  insn.imm as u64,
+            ebpf::AND64_REG  => reg[_dst] &=  reg[_src],
+            ebpf::LSH64_IMM  => reg[_dst] = reg[_dst].wrapping_shl(insn.imm as u32),
+            ebpf::LSH64_REG  => reg[_dst] = reg[_dst].wrapping_shl(reg[_src] as u32),
+            ebpf::RSH64_IMM  => reg[_dst] = reg[_dst].wrapping_shr(insn.imm as u32),
+            ebpf::RSH64_REG  => reg[_dst] = reg[_dst].wrapping_shr(reg[_src] as u32),
+            ebpf::NEG64      => reg[_dst] = (reg[_dst] as i64).wrapping_neg() as u64,
+            ebpf::MOD64_IMM  => reg[_dst] %=  insn.imm as u64,
+            ebpf::MOD64_REG  => {
+                if reg[_src] == 0 && strict_div {
+                    return Err(EbpfError::DivideByZero { pc });
+                }
+                reg[_dst] = reg[_dst].checked_rem(reg[_src]).unwrap_or(reg[_dst]);
+            },
+            ebpf::XOR64_IMM  => reg[_dst] ^= insn.imm  as u64,
+            ebpf::XOR64_REG  => reg[_dst] ^= reg[_src],
+            ebpf::MOV64_IMM  => reg[_dst] =  insn.imm  as u64,
+            ebpf::MOV64_REG  => reg[_dst] =  reg[_src],
+            ebpf::ARSH64_IMM => reg[_dst] = (reg[_dst] as i64).wrapping_shr(insn.imm as u32) as u64,
+            ebpf::ARSH64_REG => reg[_dst] = (reg[_dst] as i64).wrapping_shr(reg[_src] as u32) as u64,
+            ebpf::BSWAP      => {
+                reg[_dst] = match insn.imm {
+                    16 => (reg[_dst] as u16).swap_bytes() as u64,
+                    32 => (reg[_dst] as u32).swap_bytes() as u64,
+                    64 =>  reg[_dst].swap_bytes(),
+                    _  => return Err(unsupported()),
+                };
+            },
+
+            // BPF_JMP class
+            // TODO: check this actually works as expected for signed / unsigned ops
+            ebpf::JA         =>                                           insn_ptr = jump(insn.off as i64)?,
+            ebpf::JEQ_IMM    => if reg[_dst] == insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JEQ_REG    => if reg[_dst] == reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JGT_IMM    => if reg[_dst] >  insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JGT_REG    => if reg[_dst] >  reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JGE_IMM    => if reg[_dst] >= insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JGE_REG    => if reg[_dst] >= reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSET_IMM   => if reg[_dst] &  insn.imm as u64 != 0    { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSET_REG   => if reg[_dst] &  reg[_src]       != 0    { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JNE_IMM    => if reg[_dst] != insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JNE_REG    => if reg[_dst] != reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSGT_IMM   => if reg[_dst] as i64 >  insn.imm  as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSGT_REG   => if reg[_dst] as i64 >  reg[_src] as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSGE_IMM   => if reg[_dst] as i64 >= insn.imm  as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSGE_REG   => if reg[_dst] as i64 >= reg[_src] as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JLT_IMM    => if reg[_dst] <  insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JLT_REG    => if reg[_dst] <  reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JLE_IMM    => if reg[_dst] <= insn.imm as u64         { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JLE_REG    => if reg[_dst] <= reg[_src]               { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSLT_IMM   => if (reg[_dst] as i64) <  insn.imm  as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSLT_REG   => if (reg[_dst] as i64) <  reg[_src] as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSLE_IMM   => if reg[_dst] as i64 <= insn.imm  as i64 { insn_ptr = jump(insn.off as i64)?; },
+            ebpf::JSLE_REG   => if reg[_dst] as i64 <= reg[_src] as i64 { insn_ptr = jump(insn.off as i64)?; },
+            // Do not delegate the check to the verifier, since registered functions can be
+            // changed after the program has been verified. The hook audits every call, including
+            // those to the helpers run by the interpreter itself.
+            // BPF-to-BPF calls are rejected by the verifier, and must not run as calls to helpers.
+            ebpf::CALL if insn.src != 0 => return Err(unsupported()),
+            ebpf::CALL       => {
+                let key = insn.imm as u32;
+                let args = [reg[1], reg[2], reg[3], reg[4], reg[5]];
+                let allowed = match helper_hook.map_or(HookVerdict::Allow, |hook| hook.before_call(key, &args)) {
+                    HookVerdict::Allow     => true,
+                    HookVerdict::Skip(ret) => { reg[0] = ret; false },
+                    HookVerdict::Deny      => return Err(EbpfError::HelperDenied { pc, key }),
+                };
+                let mut returns = allowed;
+                match tail_calls {
+                    _ if !allowed => (),
+                    // Tail calls are only available when a resolver for program arrays is
+                    // provided, for example when running from a `ProgramRegistry`. On success, the
+                    // new program starts from its first instruction with the same context in R1
+                    // and the same stack. On failure, execution resumes with the next
+                    // instruction, as in the kernel. Callbacks cannot tail-call.
+                    Some(resolve) if key == BPF_TAIL_CALL_IDX => {
+                        if tail_call_cnt < ebpf::MAX_TAIL_CALL_CNT && state.callbacks.is_empty() {
+                            if let Some(next_prog) = resolve(args[1] as u32, args[2] as u32) {
+                                prog = next_prog;
+                                proven_accesses.set(&[]);
+                                insn_ptr = 0;
+                                tail_call_cnt += 1;
+                                returns = false;
+                            }
+                        }
+                    },
+                    _ if mem_helpers && [MEMCPY_IDX, MEMSET_IDX, MEMCMP_IDX, BPF_STRTOL_IDX, BPF_STRTOUL_IDX,
+                                         BPF_CSUM_DIFF_IDX].contains(&key) => {
+                        reg[0] = mem_helper(key, &args, pc, areas)?;
+                    },
+                    _ if !probe_regions.is_empty() &&
+                         [BPF_PROBE_READ_IDX, BPF_PROBE_READ_USER_IDX, BPF_PROBE_READ_KERNEL_IDX].contains(&key) => {
+                        reg[0] = probe_read(key, &[args[0], args[1], args[2]], pc, probe_regions, areas)?;
+                    },
+                    _ if frags.is_some() &&
+                         [BPF_XDP_GET_BUFF_LEN_IDX, BPF_XDP_LOAD_BYTES_IDX, BPF_XDP_STORE_BYTES_IDX].contains(&key) => {
+                        reg[0] = xdp_helper(key, &[args[0], args[1], args[2], args[3]], pc, areas)?;
+                    },
+                    _ if packet.is_some() &&
+                         [BPF_XDP_ADJUST_HEAD_IDX, BPF_XDP_ADJUST_TAIL_IDX, BPF_SKB_ADJUST_ROOM_IDX].contains(&key) => {
+                        reg[0] = adjust_helper(key, &[args[0], args[1], args[2], args[3]], packet.as_ref().unwrap(), areas);
+                    },
+                    _ if config.is_some() && [CONFIG_LOOKUP_IDX, CONFIG_VALUE_LEN_IDX].contains(&key) => {
+                        reg[0] = config_helper(key, &[args[0], args[1]], pc, config.unwrap(), areas)?;
+                    },
+                    _ if scratch_ptr != 0 && key == GET_SCRATCH_IDX => reg[0] = scratch_ptr,
+                    _ if !maps.is_empty() &&
+                         [BPF_MAP_LOOKUP_ELEM_IDX, BPF_MAP_UPDATE_ELEM_IDX, BPF_MAP_DELETE_ELEM_IDX].contains(&key) => {
+                        reg[0] = map_helper(key, &[args[0], args[1], args[2], args[3]], pc, maps, areas)?;
+                    },
+                    _ if !maps.is_empty() && key == BPF_REDIRECT_MAP_IDX => {
+                        reg[0] = redirect_map(&[args[0], args[1], args[2]], maps, redirect);
+                    },
+                    // The callback starts on the first element, and returns to the caller from
+                    // the last one, see `ebpf::EXIT` below.
+                    _ if !maps.is_empty() && key == BPF_FOR_EACH_MAP_ELEM_IDX => {
+                        match maps.get(args[0] as usize) {
+                            Some(map) if args[3] == 0 && state.callbacks.len() < ebpf::MAX_CALL_DEPTH => {
+                                if args[1] >= prog_len as u64 {
+                                    return Err(EbpfError::JumpOutOfBounds { pc, target: args[1] as i64 });
+                                }
+                                let mut callback = Callback::new(args, reg, insn_ptr, map, areas);
+                                if callback.next(&mut reg, areas) {
+                                    insn_ptr = callback.target;
+                                    state.callbacks.push(callback);
+                                    returns = false;
+                                } else {
+                                    reg = callback.finish(areas);
+                                }
+                            },
+                            _ => reg[0] = -EINVAL as u64,
+                        }
+                    },
+                    _ if event_sink.is_some() && key == BPF_PERF_EVENT_OUTPUT_IDX => {
+                        let (data, size) = (args[3], args[4] as usize);
+                        let event: &[u8] = match size {
+                            0 => &[],
+                            _ => {
+                                areas.check(data, size, AccessKind::Load, pc)?;
+                                unsafe { std::slice::from_raw_parts(data as *const u8, size) }
+                            },
+                        };
+                        reg[0] = match event_sink.unwrap().output(event) {
+                            Ok(())   => 0,
+                            Err(err) => -err as u64,
+                        };
+                    },
+                    _ => {
+                        let frame_helper = frame_helpers.and_then(|frame_helpers| frame_helpers.get(&key));
+                        let fallible_helper = fallible_helpers.and_then(|fallible_helpers| fallible_helpers.get(&key));
+                        reg[0] = match frame_helper {
+                            // The frame is in the memory of the program, at the address in R1.
+                            Some(helper) => helper.call(match helper.size {
+                                0    => &[],
+                                size => {
+                                    areas.check(args[0], size, AccessKind::Load, pc)?;
+                                    unsafe { std::slice::from_raw_parts(args[0] as *const u8, size) }
+                                },
+                            }),
+                            None         => match fallible_helper {
+                                Some(function) => function(args[0], args[1], args[2], args[3], args[4])
+                                    .map_err(|error| EbpfError::HelperFailed { pc, key, error })?,
+                                None           => match helpers.get(&key) {
+                                    Some(function) => function(args[0], args[1], args[2], args[3], args[4]),
+                                    None           => return Err(EbpfError::UnknownHelper { key }),
+                                },
+                            },
+                        };
+                    },
+                }
+                // A successful tail call does not return to the program.
+                if let (true, Some(hook)) = (returns, helper_hook) {
+                    hook.after_call(key, &args, reg[0]);
+                }
+            },
+            ebpf::TAIL_CALL  => return Err(unsupported()),
+            // A callback of `bpf_for_each_map_elem()` runs on the next element, unless it
+            // returned 1 to stop the iteration, else the caller resumes.
+            ebpf::EXIT if !state.callbacks.is_empty() => {
+                let callback = state.callbacks.last_mut().unwrap();
+                if reg[0] == 0 && callback.next(&mut reg, areas) {
+                    insn_ptr = callback.target;
+                } else {
+                    let callback = state.callbacks.pop().unwrap();
+                    reg = callback.finish(areas);
+                    insn_ptr = callback.insn_ptr;
+                    if let Some(hook) = helper_hook {
+                        hook.after_call(BPF_FOR_EACH_MAP_ELEM_IDX, &callback.args, reg[0]);
+                    }
+                }
+            },
+            ebpf::EXIT       => {
+                if let Some(ref mut tracer) = tracer {
+                    tracer.trace(&TraceEntry::new(pc, insn, &regs_before, &reg));
+                }
+                return Ok(Some(reg[0]));
+            },
+
+            // BPF_JMP32 class
+            ebpf::JA32       => insn_ptr = jump(insn.imm as i64)?,
+            _ if insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP32 => {
+                let src = match insn.opc & ebpf::BPF_X {
+                    0 => insn.imm as u32,
+                    _ => reg[_src] as u32,
+                };
+                if jmp32_taken(insn.opc, reg[_dst] as u32, src).ok_or_else(unsupported)? {
+                    insn_ptr = jump(insn.off as i64)?;
+                }
+            },
+
+            _                => return Err(unsupported()),
+        }
+
+        // Helpers write to the stack without the checks above: the bytes they changed are
+        // considered written. Those they set to the pattern itself are not detected.
+        if let (ebpf::CALL, Some(ref written)) = (insn.opc, &written) {
+            for (byte, written) in stack.iter().zip(written) {
+                if *byte != ebpf::POISON_BYTE {
+                    written.set(true);
+                }
+            }
+        }
+
+        if let Some(ref mut tracer) = tracer {
+            tracer.trace(&TraceEntry::new(pc, insn, &regs_before, &reg));
+        }
+    }
+
+    // The last instruction was not an exit.
+    Err(EbpfError::JumpOutOfBounds { pc, target: insn_ptr as i64 })
+}
@@ -11,23 +11,39 @@
 
 use std;
 use std::mem;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::fmt::{Error, Formatter};
 use std::ops::{Index, IndexMut};
 
+use analysis;
+use disassembler;
 use ebpf;
+use error::EbpfError;
+use helpers::{self, Intrinsics};
+use maps::Map;
+use verifier;
 
 extern crate libc;
 
 extern {
     fn memset(s: *mut libc::c_void, c: libc::uint32_t, n: libc::size_t) -> *mut libc::c_void;
 }
-const PAGE_SIZE: usize = 4096;
+
+// The size of the pages of the host, and of the huge pages backing the code of the programs
+// compiled with `huge_pages`.
+static PAGE_SIZE: LazyLock<usize> = LazyLock::new(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize });
+const HUGE_PAGE_SIZE: usize = 2 << 20;
 
 // Special values for target_pc in struct Jump
 const TARGET_OFFSET: isize = ebpf::PROG_MAX_INSNS as isize;
 const TARGET_PC_EXIT:         isize = TARGET_OFFSET + 1;
 const TARGET_PC_DIV_BY_ZERO:  isize = TARGET_OFFSET + 2;
+const TARGET_PC_FAULT:        isize = TARGET_OFFSET + 3;
 
 enum OperandSize {
     S8  = 8,
@@ -48,8 +64,8 @@ const RDI: u8 = 7;
 const R8:  u8 = 8;
 const R9:  u8 = 9;
 //const R10: u8 = 10;
-//const R11: u8 = 11;
-//const R12: u8 = 12;
+const R11: u8 = 11;
+const R12: u8 = 12;
 const R13: u8 = 13;
 const R14: u8 = 14;
 const R15: u8 = 15;
@@ -78,10 +94,12 @@ fn map_register(r: u8) -> u8 {
 macro_rules! emit_bytes {
     ( $jit:ident, $data:tt, $t:ty ) => {{
         let size = mem::size_of::<$t>() as usize;
-        assert!($jit.offset + size <= $jit.contents.len());
+        if $jit.offset + size > $jit.contents.len() {
+            $jit.contents.resize($jit.offset + size, 0);
+        }
         unsafe {
-            let mut ptr = $jit.contents.as_ptr().offset($jit.offset as isize) as *mut $t;
-            *ptr = $data as $t;
+            let ptr = $jit.contents.as_mut_ptr().add($jit.offset) as *mut $t;
+            ptr.write_unaligned($data as $t);
         }
         $jit.offset += size;
     }}
@@ -249,6 +267,21 @@ fn emit_jmp (jit: &mut JitMemory, target_pc: isize) {
     emit_jump_offset(jit, target_pc);
 }
 
+// Short jump within the code generated for one instruction, with an 8-bit offset patched with
+// `patch_short_jump()`. Returns the location following the jump.
+#[inline]
+fn emit_short_jump (jit: &mut JitMemory, code: u8) -> usize {
+    emit1(jit, code);
+    emit1(jit, 0);
+    jit.offset
+}
+
+// Makes the short jump preceding location `loc` target the current location.
+#[inline]
+fn patch_short_jump (jit: &mut JitMemory, loc: usize) {
+    jit[loc - 1] = (jit.offset - loc) as u8;
+}
+
 #[inline]
 fn set_anchor(jit: &mut JitMemory, target: isize) {
     jit.special_targets.insert(target, jit.offset);
@@ -283,6 +316,29 @@ fn emit_load (jit: &mut JitMemory, size: OperandSize, src: u8, dst: u8, offset:
     emit_modrm_and_displacement(jit, dst, src, offset);
 }
 
+// Load [src + offset] into dst, sign-extended (ISA v4)
+#[inline]
+fn emit_load_sx (jit: &mut JitMemory, size: OperandSize, src: u8, dst: u8, offset: i32) {
+    emit_basic_rex(jit, 1, dst, src);
+    match size {
+        OperandSize::S8  => {
+            // movsx
+            emit1(jit, 0x0f);
+            emit1(jit, 0xbe);
+        },
+        OperandSize::S16 => {
+            // movsx
+            emit1(jit, 0x0f);
+            emit1(jit, 0xbf);
+        },
+        _                => {
+            // movsxd
+            emit1(jit, 0x63);
+        },
+    }
+    emit_modrm_and_displacement(jit, dst, src, offset);
+}
+
 // Load sign-extended immediate into register
 #[inline]
 fn emit_load_imm (jit: &mut JitMemory, dst: u8, imm: i64) {
@@ -356,24 +412,64 @@ fn emit_call (jit: &mut JitMemory, target: i64) {
     emit1(jit, 0xd0);
 }
 
-fn muldivmod(jit: &mut JitMemory, pc: u16, opc: u8, src: u8, dst: u8, imm: i32) {
+// Condition code of the x86 conditional jump for the conditional jump `opc`, after a comparison of
+// dst with src (or a test, for `jset`).
+fn jcc_code(opc: u8) -> u8 {
+    match opc & ebpf::BPF_ALU_OP_MASK {
+        ebpf::BPF_JEQ  => 0x84,
+        ebpf::BPF_JGT  => 0x87,
+        ebpf::BPF_JGE  => 0x83,
+        ebpf::BPF_JSET => 0x85,
+        ebpf::BPF_JNE  => 0x85,
+        ebpf::BPF_JSGT => 0x8f,
+        ebpf::BPF_JSGE => 0x8d,
+        ebpf::BPF_JLT  => 0x82,
+        ebpf::BPF_JLE  => 0x86,
+        ebpf::BPF_JSLT => 0x8c,
+        ebpf::BPF_JSLE => 0x8e,
+        _              => unreachable!(),
+    }
+}
+
+fn muldivmod(jit: &mut JitMemory, pc: u16, opc: u8, src: u8, dst: u8, imm: i32,
+             div_by_zero: ebpf::DivByZero) {
     let mul = (opc & ebpf::BPF_ALU_OP_MASK) == (ebpf::MUL32_IMM & ebpf::BPF_ALU_OP_MASK);
     let div = (opc & ebpf::BPF_ALU_OP_MASK) == (ebpf::DIV32_IMM & ebpf::BPF_ALU_OP_MASK);
     let modrm = (opc & ebpf::BPF_ALU_OP_MASK) == (ebpf::MOD32_IMM & ebpf::BPF_ALU_OP_MASK);
     let is64 = (opc & ebpf::BPF_CLS_MASK) == ebpf::BPF_ALU64;
 
-    if div || modrm {
-        emit_load_imm(jit, RCX, pc as i64);
-
+    // Divisions by an immediate were checked by the verifier, only the register can be zero.
+    let check_zero = (div || modrm) && opc & ebpf::BPF_X != 0;
+    // With kernel semantics, offset of the short jump skipping the operation, to patch below.
+    let mut skip_loc = None;
+    if check_zero {
         // test src,src
-        if is64 {
-            emit_alu64(jit, 0x85, src, src);
-        } else {
-            emit_alu32(jit, 0x85, src, src);
+        match is64 {
+            true  => emit_alu64(jit, 0x85, src, src),
+            false => emit_alu32(jit, 0x85, src, src),
         }
 
-        // jz div_by_zero
-        emit_jcc(jit, 0x84, TARGET_PC_DIV_BY_ZERO);
+        match div_by_zero {
+            ebpf::DivByZero::Error => {
+                emit_load_imm(jit, RCX, pc as i64);
+                // jz div_by_zero
+                emit_jcc(jit, 0x84, TARGET_PC_DIV_BY_ZERO);
+            },
+            ebpf::DivByZero::Kernel => {
+                // jnz over the result for a zero divisor
+                let nonzero_loc = emit_short_jump(jit, 0x75);
+                if div {
+                    // xor dst,dst
+                    emit_alu32(jit, 0x31, dst, dst);
+                } else if !is64 {
+                    // mov dst32,dst32, clearing the upper bits
+                    emit_alu32(jit, 0x89, dst, dst);
+                }
+                // jmp over the operation
+                skip_loc = Some(emit_short_jump(jit, 0xeb));
+                patch_short_jump(jit, nonzero_loc);
+            },
+        }
     }
 
     if dst != RAX {
@@ -414,6 +510,396 @@ fn muldivmod(jit: &mut JitMemory, pc: u16, opc: u8, src: u8, dst: u8, imm: i32)
         }
         emit_pop(jit, RAX);
     }
+
+    if let Some(loc) = skip_loc {
+        patch_short_jump(jit, loc);
+    }
+}
+
+// Signed division or modulo (ISA v4). Dividing the lowest signed integer by -1 would fault on
+// x86, so a divisor of -1 is handled separately: the division negates dst, the modulo clears it.
+fn sdivmod(jit: &mut JitMemory, pc: u16, opc: u8, src: u8, dst: u8, imm: i32,
+           div_by_zero: ebpf::DivByZero) {
+    let div = (opc & ebpf::BPF_ALU_OP_MASK) == ebpf::BPF_DIV;
+    let is64 = (opc & ebpf::BPF_CLS_MASK) == ebpf::BPF_ALU64;
+    let by_reg = opc & ebpf::BPF_X != 0;
+    let alu = |jit: &mut JitMemory, op: u8, src: u8, dst: u8| match is64 {
+        true  => emit_alu64(jit, op, src, dst),
+        false => emit_alu32(jit, op, src, dst),
+    };
+    // Result for a divisor of -1: neg dst, or xor dst,dst
+    let minus_one = |jit: &mut JitMemory| match div {
+        true  => alu(jit, 0xf7, 3, dst),
+        false => emit_alu32(jit, 0x31, dst, dst),
+    };
+
+    if !by_reg && imm == -1 {
+        minus_one(jit);
+        return;
+    }
+    // The verifier rejects all divisions and modulos by an immediate 0.
+    debug_assert!(by_reg || imm != 0, "division by an immediate 0 (insn #{})", pc);
+
+    let mut end_locs = vec![];
+    if by_reg {
+        // test src,src
+        alu(jit, 0x85, src, src);
+        match div_by_zero {
+            ebpf::DivByZero::Error => {
+                emit_load_imm(jit, RCX, pc as i64);
+                emit_jcc(jit, 0x84, TARGET_PC_DIV_BY_ZERO);
+            },
+            ebpf::DivByZero::Kernel => {
+                let nonzero_loc = emit_short_jump(jit, 0x75);
+                if div {
+                    emit_alu32(jit, 0x31, dst, dst);
+                } else if !is64 {
+                    emit_alu32(jit, 0x89, dst, dst);
+                }
+                end_locs.push(emit_short_jump(jit, 0xeb));
+                patch_short_jump(jit, nonzero_loc);
+            },
+        }
+
+        // cmp src,-1
+        match is64 {
+            true  => emit_alu64_imm8(jit, 0x83, 7, src, -1),
+            false => emit_alu32_imm8(jit, 0x83, 7, src, -1),
+        }
+        let not_minus_one_loc = emit_short_jump(jit, 0x75);
+        minus_one(jit);
+        end_locs.push(emit_short_jump(jit, 0xeb));
+        patch_short_jump(jit, not_minus_one_loc);
+    }
+
+    if dst != RAX {
+        emit_push(jit, RAX);
+    }
+    if dst != RDX {
+        emit_push(jit, RDX);
+    }
+    match by_reg {
+        true  => emit_mov(jit, src, RCX),
+        false => emit_load_imm(jit, RCX, imm as i64),
+    }
+    emit_mov(jit, dst, RAX);
+
+    // cqo or cdq, sign-extending rax into rdx
+    if is64 {
+        emit_rex(jit, 1, 0, 0, 0);
+    }
+    emit1(jit, 0x99);
+
+    // idiv %ecx
+    alu(jit, 0xf7, 7, RCX);
+
+    let res = if div { RAX } else { RDX };
+    if dst != res {
+        emit_mov(jit, res, dst);
+    }
+    if dst != RDX {
+        emit_pop(jit, RDX);
+    }
+    if dst != RAX {
+        emit_pop(jit, RAX);
+    }
+
+    for loc in end_locs {
+        patch_short_jump(jit, loc);
+    }
+}
+
+// Sign-extending move of the lowest `bits` bits of src into dst (ISA v4), in 32 or 64 bits.
+fn emit_movsx(jit: &mut JitMemory, is64: bool, bits: i16, src: u8, dst: u8) {
+    let w = if is64 { 1 } else { 0 };
+    match bits {
+        8  => {
+            // movsx; the REX prefix selects the low byte of rsi and rdi, instead of dh and bh
+            emit_rex(jit, w, (dst >> 3) & 1, 0, (src >> 3) & 1);
+            emit1(jit, 0x0f);
+            emit1(jit, 0xbe);
+        },
+        16 => {
+            // movsx
+            emit_basic_rex(jit, w, dst, src);
+            emit1(jit, 0x0f);
+            emit1(jit, 0xbf);
+        },
+        _  => {
+            // movsxd
+            emit_basic_rex(jit, 1, dst, src);
+            emit1(jit, 0x63);
+        },
+    }
+    emit_modrm_reg2reg(jit, dst, src);
+}
+
+// Conversion of the signed 64-bit integer in `src` to a double in xmm0 (cvtsi2sd).
+fn emit_cvtsi2sd_xmm0(jit: &mut JitMemory, src: u8) {
+    emit1(jit, 0xf2);
+    emit_basic_rex(jit, 1, 0, src);
+    emit1(jit, 0x0f);
+    emit1(jit, 0x2a);
+    emit_modrm_reg2reg(jit, 0, src);
+}
+
+// Inline code for helper `sqrti()`: `(R1 as f64).sqrt() as u64` into R0.
+fn emit_sqrti(jit: &mut JitMemory) {
+    emit_mov(jit, map_register(1), RAX);
+    emit_alu64(jit, 0x85, RAX, RAX);                // test rax, rax
+    let big = emit_short_jump(jit, 0x78);           // js
+    emit_cvtsi2sd_xmm0(jit, RAX);
+    let done = emit_short_jump(jit, 0xeb);          // jmp
+    // Above 2^63, convert half of the value, keeping the lowest bit for the rounding, and double
+    // the result.
+    patch_short_jump(jit, big);
+    emit_mov(jit, RAX, RCX);
+    emit_alu64(jit, 0xd1, 5, RCX);                  // shr rcx, 1
+    emit_alu64_imm32(jit, 0x81, 4, RAX, 1);         // and rax, 1
+    emit_alu64(jit, 0x09, RAX, RCX);                // or rcx, rax
+    emit_cvtsi2sd_xmm0(jit, RCX);
+    emit4(jit, 0xc0580ff2);                         // addsd xmm0, xmm0
+    patch_short_jump(jit, done);
+    emit4(jit, 0xc0510ff2);                         // sqrtsd xmm0, xmm0
+    emit1(jit, 0xf2);                               // cvttsd2si rax, xmm0
+    emit4(jit, 0xc02c0f48);
+}
+
+// Inline code for helper `gather_bytes()`: R1 to R5 shifted and or'ed into R0.
+fn emit_gather_bytes(jit: &mut JitMemory) {
+    emit_mov(jit, map_register(1), RAX);
+    for r in 2..6 {
+        emit_alu64_imm8(jit, 0xc1, 4, RAX, 8);      // shl rax, 8
+        emit_alu64(jit, 0x09, map_register(r), RAX); // or rax, r
+    }
+}
+
+// Inline code for helper `bpf_ktime_get_ns()`: a direct call to `clock_gettime()`, with the
+// `struct timespec` on the native stack, kept aligned on 16 bytes.
+fn emit_ktime_get_ns(jit: &mut JitMemory) {
+    emit_alu64_imm32(jit, 0x81, 5, RSP, 16);        // sub rsp, 16
+    emit_load_imm(jit, RDI, libc::CLOCK_MONOTONIC as i64);
+    emit_mov(jit, RSP, RSI);
+    let clock_gettime: unsafe extern "C" fn(libc::clockid_t, *mut libc::timespec) -> libc::c_int =
+        libc::clock_gettime;
+    emit_call(jit, clock_gettime as usize as i64);
+    emit_mov(jit, RSP, RCX);
+    emit_load(jit, OperandSize::S64, RCX, RAX, 0);  // tv_sec
+    emit_alu64_imm32(jit, 0x69, RAX, RAX, 1_000_000_000); // imul rax, rax, 1000000000
+    emit_load(jit, OperandSize::S64, RCX, RCX, 8);  // tv_nsec
+    emit_alu64(jit, 0x01, RCX, RAX);                // add rax, rcx
+    emit_alu64_imm32(jit, 0x81, 0, RSP, 16);        // add rsp, 16
+}
+
+// Inline code for `bpf_map_lookup_elem()` in array `map`: the address of the value for the
+// 32-bit key pointed to by R2, or null if the key is out of range.
+fn emit_array_lookup(jit: &mut JitMemory, map: &Map, values: u64) {
+    emit_load(jit, OperandSize::S32, map_register(2), RAX, 0);
+    emit_cmp_imm32(jit, RAX, map.max_entries() as i32);
+    let out_of_range = emit_short_jump(jit, 0x73);  // jae
+    emit_alu64_imm32(jit, 0x69, RAX, RAX, map.value_size() as i32); // imul rax, rax, value_size
+    emit_load_imm(jit, RCX, values as i64);
+    emit_alu64(jit, 0x01, RCX, RAX);                // add rax, rcx
+    let done = emit_short_jump(jit, 0xeb);          // jmp
+    patch_short_jump(jit, out_of_range);
+    emit_alu32(jit, 0x31, RAX, RAX);                // xor eax, eax
+    patch_short_jump(jit, done);
+}
+
+// Emits inline code in place of a call to `helper`, if it is a built-in helper selected in
+// `intrinsics`. Returns `false` if the helper must be called instead.
+fn emit_intrinsic(jit: &mut JitMemory, intrinsics: Intrinsics, helper: ebpf::Helper) -> bool {
+    let is = |builtin: ebpf::Helper| helper as usize == builtin as usize;
+    if intrinsics.sqrti && is(helpers::sqrti) {
+        emit_sqrti(jit);
+    } else if intrinsics.gather_bytes && is(helpers::gather_bytes) {
+        emit_gather_bytes(jit);
+    } else if intrinsics.ktime_get_ns && is(helpers::bpf_ktime_get_ns) {
+        emit_ktime_get_ns(jit);
+    } else {
+        return false;
+    }
+    true
+}
+
+// Constant blinding
+//
+// When hardening is enabled, the immediates of the program are not copied as is into the machine
+// code, where a program could choose them to form native instructions, reached by jumping into
+// the middle of the code (JIT spraying). Each immediate is xored with a random key when compiling,
+// and xored again with the key at run time into R11, which the operation then uses instead of the
+// immediate. Shifts and byte swaps, whose immediates fit in a single byte, are not blinded.
+
+// A random key to blind an immediate with. The standard library draws the keys of its hashers
+// from the random number generator of the system.
+fn blinding_key() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Whether the immediate of `opc` is blinded by `emit_blinded()`.
+fn is_blinded(opc: u8) -> bool {
+    let op = opc & ebpf::BPF_ALU_OP_MASK;
+    let imm = opc & ebpf::BPF_X == ebpf::BPF_K;
+    match opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ST => true,
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => {
+            imm && !matches!(op, ebpf::BPF_LSH | ebpf::BPF_RSH | ebpf::BPF_ARSH | ebpf::BPF_NEG |
+                                 ebpf::BPF_END)
+        },
+        ebpf::BPF_JMP | ebpf::BPF_JMP32 => imm && ebpf::is_conditional_jump(opc),
+        _ => false,
+    }
+}
+
+// Load the sign-extended immediate `imm` into R11, blinded.
+fn emit_blinded_imm32(jit: &mut JitMemory, imm: i32) {
+    let key = blinding_key() as i32;
+    // mov r11, imm ^ key; xor r11, key
+    emit_alu64_imm32(jit, 0xc7, 0, R11, imm ^ key);
+    emit_alu64_imm32(jit, 0x81, 6, R11, key);
+}
+
+// Load the 64-bit immediate `imm` of a `lddw` into `dst`, blinded.
+fn emit_blinded_load_imm(jit: &mut JitMemory, dst: u8, imm: i64) {
+    let key = blinding_key() as i64;
+    emit_load_imm(jit, dst, imm ^ key);
+    emit_load_imm(jit, R11, key);
+    // xor dst, r11
+    emit_alu64(jit, 0x31, R11, dst);
+}
+
+// Emit instruction `insn`, whose immediate is blinded (see `is_blinded()`), with the operation by
+// register on R11 instead of the immediate.
+fn emit_blinded(jit: &mut JitMemory, pc: usize, insn: &ebpf::Insn, dst: u8, target_pc: isize,
+                div_by_zero: ebpf::DivByZero) {
+    let class = insn.opc & ebpf::BPF_CLS_MASK;
+    let is64 = class == ebpf::BPF_ALU64 || class == ebpf::BPF_JMP;
+    let alu = |jit: &mut JitMemory, op: u8, src: u8, dst: u8| match is64 {
+        true  => emit_alu64(jit, op, src, dst),
+        false => emit_alu32(jit, op, src, dst),
+    };
+    emit_blinded_imm32(jit, insn.imm);
+    match class {
+        ebpf::BPF_ST => {
+            let size = match insn.opc & 0x18 {
+                ebpf::BPF_B => OperandSize::S8,
+                ebpf::BPF_H => OperandSize::S16,
+                ebpf::BPF_W => OperandSize::S32,
+                _           => OperandSize::S64,
+            };
+            emit_store(jit, size, R11, dst, insn.off as i32);
+        },
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => match insn.opc & ebpf::BPF_ALU_OP_MASK {
+            ebpf::BPF_MUL | ebpf::BPF_DIV | ebpf::BPF_MOD if insn.off == 1 =>
+                sdivmod(jit, pc as u16, insn.opc | ebpf::BPF_X, R11, dst, 0, div_by_zero),
+            ebpf::BPF_MUL | ebpf::BPF_DIV | ebpf::BPF_MOD =>
+                muldivmod(jit, pc as u16, insn.opc | ebpf::BPF_X, R11, dst, 0, div_by_zero),
+            ebpf::BPF_ADD => alu(jit, 0x01, R11, dst),
+            ebpf::BPF_SUB => alu(jit, 0x29, R11, dst),
+            ebpf::BPF_OR  => alu(jit, 0x09, R11, dst),
+            ebpf::BPF_AND => alu(jit, 0x21, R11, dst),
+            ebpf::BPF_XOR => alu(jit, 0x31, R11, dst),
+            ebpf::BPF_MOV => alu(jit, 0x89, R11, dst),
+            _             => unreachable!(),
+        },
+        _ => {
+            match insn.opc & ebpf::BPF_ALU_OP_MASK {
+                // test
+                ebpf::BPF_JSET => alu(jit, 0x85, R11, dst),
+                // cmp
+                _              => alu(jit, 0x39, R11, dst),
+            }
+            emit_jcc(jit, jcc_code(insn.opc), target_pc);
+        },
+    }
+}
+
+// The ranges of instructions of the basic blocks of `prog`, in the order given by `layout`, the
+// indices of their first instructions, see `profile::Profile::layout()`.
+fn block_ranges(prog: &[u8], layout: &[usize]) -> Result<std::vec::Vec<(usize, usize)>, EbpfError> {
+    let blocks = analysis::basic_blocks(prog);
+    let mut ranges = std::vec::Vec::with_capacity(blocks.len());
+    let mut placed = vec![false; blocks.len()];
+    for &start in layout {
+        match blocks.iter().position(|block| block.start == start) {
+            Some(b) if !placed[b] => {
+                placed[b] = true;
+                ranges.push((start, blocks[b].end));
+            },
+            _ => break,
+        }
+    }
+    if ranges.len() != blocks.len() || ranges.first().is_some_and(|range| range.0 != 0) {
+        return Err(EbpfError::JitError("[JIT] Error: invalid layout of the basic blocks".to_string()));
+    }
+    Ok(ranges)
+}
+
+// Register allocation
+//
+// eBPF registers are mapped to x86 registers once and for all (see `REGISTER_MAP`), so the moves
+// of the program are all emitted as moves between registers. Before compiling, the sources of the
+// instructions reading a copy of a register made earlier in the same basic block are rewritten to
+// read the original register, with `propagate_copies()`. The copies, and the other arithmetic
+// operations, whose results are never read (see `analysis::dead_writes()`) are then not emitted,
+// so that the chains of moves that compilers produce when shuffling arguments collapse.
+
+// Whether `opc` only writes its destination register, without faulting nor jumping to the handler
+// of divisions by zero: such instructions need not be emitted when their result is never read.
+fn is_pure(opc: u8) -> bool {
+    let op = opc & ebpf::BPF_ALU_OP_MASK;
+    match opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => match op {
+            ebpf::BPF_DIV | ebpf::BPF_MOD => false,
+            ebpf::BPF_NEG                 => opc & ebpf::BPF_X == ebpf::BPF_K,
+            ebpf::BPF_END                 => matches!(opc, ebpf::LE | ebpf::BE | ebpf::BSWAP),
+            _                             => op <= ebpf::BPF_ARSH,
+        },
+        _ => false,
+    }
+}
+
+// Whether the source register of `opc` is only read, and holds a value rather than the type of an
+// immediate or of a call: atomic operations may write their source register.
+fn reads_src(opc: u8) -> bool {
+    let op = opc & ebpf::BPF_ALU_OP_MASK;
+    match opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => opc & ebpf::BPF_X != 0 && op != ebpf::BPF_END,
+        ebpf::BPF_LDX                   => true,
+        ebpf::BPF_STX                   => opc & 0xe0 != ebpf::BPF_XADD,
+        ebpf::BPF_JMP | ebpf::BPF_JMP32 => opc & ebpf::BPF_X != 0 && ebpf::is_conditional_jump(opc),
+        _                               => false,
+    }
+}
+
+// Rewrites the instructions of `prog` reading a register that holds a copy of another one, made
+// by a `mov64` earlier in the same basic block, to read the original register instead. The copy
+// is then often dead, and not emitted.
+fn propagate_copies(prog: &[u8]) -> std::vec::Vec<u8> {
+    let mut prog = prog.to_vec();
+    let mut insns = disassembler::to_insn_vec(&prog).into_iter().peekable();
+    for block in analysis::basic_blocks(&prog) {
+        // For each register, the register it is a copy of.
+        let mut copies: [Option<u8>; 11] = [None; 11];
+        while let Some(mut insn) = insns.next_if(|insn| insn.ptr < block.end) {
+            if reads_src(insn.opc) {
+                if let Some(&Some(orig)) = copies.get(insn.src as usize) {
+                    insn.src = orig;
+                    prog[insn.ptr * ebpf::INSN_SIZE + 1] = orig << 4 | insn.dst;
+                }
+            }
+            let written = analysis::may_defs(&insn);
+            for (reg, copy) in copies.iter_mut().enumerate() {
+                if written & (1 << reg) != 0 || copy.is_some_and(|orig| written & (1 << orig) != 0) {
+                    *copy = None;
+                }
+            }
+            if insn.opc == ebpf::MOV64_REG && insn.off == 0 && insn.dst != insn.src && insn.dst < 10 {
+                copies[insn.dst as usize] = Some(insn.src);
+            }
+        }
+    }
+    prog
 }
 
 #[derive(Debug)]
@@ -422,42 +908,64 @@ struct Jump {
     target_pc:  isize,
 }
 
-struct JitMemory<'a> {
-    contents:        &'a mut [u8],
+// The code being compiled, in a growing buffer, copied into executable memory once complete, see
+// `JitCode::new()`. The code only holds offsets relative to its start, and may be moved.
+struct JitMemory {
+    contents:        std::vec::Vec<u8>,
     offset:          usize,
     pc_locs:         std::vec::Vec<usize>,
     special_targets: HashMap<isize, usize>,
     jumps:           std::vec::Vec<Jump>,
+    intrinsics:      Intrinsics,
+    maps:            std::vec::Vec<Arc<Map>>,
+    harden:          bool,
 }
 
-impl<'a> JitMemory<'a> {
-    fn new(num_pages: usize) -> JitMemory<'a> {
-        let contents: &mut[u8];
-        unsafe {
-            let size = num_pages * PAGE_SIZE;
-            let mut raw: *mut libc::c_void = mem::uninitialized();
-            libc::posix_memalign(&mut raw, PAGE_SIZE, size);
-            libc::mprotect(raw, size, libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE);
-            memset(raw, 0xc3, size);  // for now, prepopulate with 'RET' calls
-            contents = std::slice::from_raw_parts_mut(mem::transmute(raw), num_pages * PAGE_SIZE);
-        }
-
+impl JitMemory {
+    fn new(capacity: usize) -> JitMemory {
         JitMemory {
-            contents:        contents,
+            contents:        std::vec::Vec::with_capacity(capacity),
             offset:          0,
             pc_locs:         vec![],
             jumps:           vec![],
             special_targets: HashMap::new(),
+            intrinsics:      Intrinsics::default(),
+            maps:            vec![],
+            harden:          false,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn jit_compile(&mut self, prog: &std::vec::Vec<u8>, use_mbuff: bool, update_data_ptr: bool,
-                   helpers: &HashMap<u32, fn (u64, u64, u64, u64, u64) -> u64>) {
+                   helpers: &HashMap<u32, ebpf::Helper>,
+                   div_by_zero: ebpf::DivByZero, frame_pointer: Option<u64>,
+                   layout: Option<&[usize]>)
+                   -> Result<(), EbpfError> {
+        // Lookups in array maps are inlined when the map is known at compile time, that is, when
+        // R1 holds the same map on all paths to the call. Sizes too large for the immediates of
+        // the inline code are left to the helpers.
+        let maps = self.maps.clone();
+        let array_lookups: HashMap<usize, (&Map, u64)> = analysis::map_lookups(prog).into_iter()
+            .filter_map(|(ptr, map)| {
+                let map = &**maps.get(map as usize)?;
+                let fits = map.max_entries() <= i32::MAX as usize && map.value_size() <= i32::MAX as usize;
+                match (map.array_values(), fits) {
+                    (Some(values), true) => Some((ptr, (map, values))),
+                    _                    => None,
+                }
+            })
+            .collect();
+
         emit_push(self, RBP);
         emit_push(self, RBX);
         emit_push(self, R13);
         emit_push(self, R14);
         emit_push(self, R15);
+        emit_push(self, R12);
+        // Keep the stack aligned on 16 bytes for the calls to helpers
+        emit_alu64_imm32(self, 0x81, 5, RSP, 8);
+        // Save the stack pointer in R12, restored by the landing pad for faults
+        emit_mov(self, RSP, R12);
 
         // RDI: mbuff
         // RSI: mbuff_len
@@ -484,7 +992,7 @@ impl<'a> JitMemory<'a> {
                 emit_alu64(self, 0x01, RDI, R8);                // add mbuff to mem_offset in R8
                 emit_store(self, OperandSize::S64, RDX, R8, 0); // set mem at mbuff + mem_offset
                 // Store mem_end at mbuff + mem_end_offset. Trash R9.
-                emit_load(self, OperandSize::S64, RDX, R8, 0);  // load mem into R8
+                emit_mov(self, RDX, R8);                        // copy mem into R8
                 emit_alu64(self, 0x01, RCX, R8);                // add mem_len to mem (= mem_end)
                 emit_alu64(self, 0x01, RDI, R9);                // add mbuff to mem_end_offset
                 emit_store(self, OperandSize::S64, R8, R9, 0);  // store mem_end
@@ -496,17 +1004,43 @@ impl<'a> JitMemory<'a> {
             }
         }
 
-        // Copy stack pointer to R10
-        emit_mov(self, RSP, map_register(10));
+        match frame_pointer {
+            // Use the stack given by the caller
+            Some(fp) => emit_load_imm(self, map_register(10), fp as i64),
+            None     => {
+                // Copy stack pointer to R10
+                emit_mov(self, RSP, map_register(10));
 
-        // Allocate stack space
-        emit_alu64_imm32(self, 0x81, 5, RSP, ebpf::STACK_SIZE as i32);
+                // Allocate stack space
+                emit_alu64_imm32(self, 0x81, 5, RSP, ebpf::STACK_SIZE as i32);
+            },
+        }
+
+        let prog = &propagate_copies(prog);
+        let dead = analysis::dead_writes(prog);
 
         self.pc_locs = vec![0; prog.len() / ebpf::INSN_SIZE + 1];
 
-        let mut insn_ptr:usize = 0;
-        while insn_ptr * ebpf::INSN_SIZE < prog.len() {
+        // The ranges of instructions to emit, in order: the basic blocks in the order of the
+        // layout, or the whole program.
+        let ranges = match layout {
+            Some(layout) => block_ranges(prog, layout)?,
+            None         => vec![(0, prog.len() / ebpf::INSN_SIZE)],
+        };
+
+        let mut block = 0;
+        let mut insn_ptr:usize = ranges.first().map_or(0, |range| range.0);
+        let mut last_opc = 0;
+        while block < ranges.len() {
+            if insn_ptr >= ranges[block].1 {
+                let next = ranges.get(block + 1).map_or(prog.len() / ebpf::INSN_SIZE, |range| range.0);
+                self.link_block(ranges[block].1, next, last_opc);
+                block += 1;
+                insn_ptr = ranges.get(block).map_or(0, |range| range.0);
+                continue;
+            }
             let insn = ebpf::get_insn(prog, insn_ptr);
+            last_opc = insn.opc;
 
             self.pc_locs[insn_ptr] = self.offset;
 
@@ -514,6 +1048,17 @@ impl<'a> JitMemory<'a> {
             let src = map_register(insn.src);
             let target_pc = insn_ptr as isize + insn.off as isize + 1;
 
+            if dead.contains(&insn_ptr) && is_pure(insn.opc) {
+                insn_ptr += 1;
+                continue;
+            }
+
+            if self.harden && is_blinded(insn.opc) {
+                emit_blinded(self, insn_ptr, &insn, dst, target_pc, div_by_zero);
+                insn_ptr += 1;
+                continue;
+            }
+
             match insn.opc {
 
                 // BPF_LD class
@@ -530,8 +1075,23 @@ impl<'a> JitMemory<'a> {
                 ebpf::LD_DW_IMM  => {
                     insn_ptr += 1;
                     let second_part = ebpf::get_insn(prog, insn_ptr).imm as u64;
-                    let imm = (insn.imm as u32) as u64 | second_part.wrapping_shl(32);
-                    emit_load_imm(self, dst, imm as i64);
+                    let mut imm = (insn.imm as u32) as u64 | second_part.wrapping_shl(32);
+                    // The values of array maps do not move, their addresses are loaded as is.
+                    if insn.src == ebpf::BPF_PSEUDO_MAP_VALUE {
+                        let offset = second_part as u32 as u64;
+                        imm = match maps.get(insn.imm as u32 as usize)
+                            .filter(|map| offset < map.value_size() as u64)
+                            .and_then(|map| map.array_values()) {
+                            Some(values) => values + offset,
+                            None         => return Err(EbpfError::JitError(
+                                format!("[JIT] Error: invalid reference to the value of map {} (insn #{:?})",
+                                        insn.imm as u32, insn_ptr - 1))),
+                        };
+                    }
+                    match self.harden {
+                        true  => emit_blinded_load_imm(self, dst, imm as i64),
+                        false => emit_load_imm(self, dst, imm as i64),
+                    }
                 },
                 ebpf::LD_B_REG   =>
                     emit_load(self, OperandSize::S8,  src, dst, insn.off as i32),
@@ -541,6 +1101,12 @@ impl<'a> JitMemory<'a> {
                     emit_load(self, OperandSize::S32, src, dst, insn.off as i32),
                 ebpf::LD_DW_REG  =>
                     emit_load(self, OperandSize::S64, src, dst, insn.off as i32),
+                ebpf::LD_B_SX    =>
+                    emit_load_sx(self, OperandSize::S8,  src, dst, insn.off as i32),
+                ebpf::LD_H_SX    =>
+                    emit_load_sx(self, OperandSize::S16, src, dst, insn.off as i32),
+                ebpf::LD_W_SX    =>
+                    emit_load_sx(self, OperandSize::S32, src, dst, insn.off as i32),
 
                 // BPF_ST class
                 ebpf::ST_B_IMM   =>
@@ -564,6 +1130,14 @@ impl<'a> JitMemory<'a> {
                 ebpf::ST_W_XADD  => unimplemented!(),
                 ebpf::ST_DW_XADD => unimplemented!(),
 
+                // Signed divisions and modulos, and sign-extending moves (ISA v4)
+                ebpf::DIV32_IMM | ebpf::DIV32_REG | ebpf::MOD32_IMM | ebpf::MOD32_REG |
+                    ebpf::DIV64_IMM | ebpf::DIV64_REG |
+                    ebpf::MOD64_IMM | ebpf::MOD64_REG if insn.off == 1 =>
+                    sdivmod(self, insn_ptr as u16, insn.opc, src, dst, insn.imm, div_by_zero),
+                ebpf::MOV32_REG if insn.off != 0 => emit_movsx(self, false, insn.off, src, dst),
+                ebpf::MOV64_REG if insn.off != 0 => emit_movsx(self, true, insn.off, src, dst),
+
                 // BPF_ALU class
                 ebpf::ADD32_IMM  => emit_alu32_imm32(self, 0x81, 0, dst, insn.imm),
                 ebpf::ADD32_REG  => emit_alu32(self, 0x01, src, dst),
@@ -572,7 +1146,7 @@ impl<'a> JitMemory<'a> {
                 ebpf::MUL32_IMM | ebpf::MUL32_REG |
                     ebpf::DIV32_IMM | ebpf::DIV32_REG |
                     ebpf::MOD32_IMM | ebpf::MOD32_REG =>
-                    muldivmod(self, insn_ptr as u16, insn.opc, src, dst, insn.imm),
+                    muldivmod(self, insn_ptr as u16, insn.opc, src, dst, insn.imm, div_by_zero),
                 ebpf::OR32_IMM   => emit_alu32_imm32(self, 0x81, 1, dst, insn.imm),
                 ebpf::OR32_REG   => emit_alu32(self, 0x09, src, dst),
                 ebpf::AND32_IMM  => emit_alu32_imm32(self, 0x81, 4, dst, insn.imm),
@@ -591,14 +1165,21 @@ impl<'a> JitMemory<'a> {
                 ebpf::XOR32_IMM  => emit_alu32_imm32(self, 0x81, 6, dst, insn.imm),
                 ebpf::XOR32_REG  => emit_alu32(self, 0x31, src, dst),
                 ebpf::MOV32_IMM  => emit_alu32_imm32(self, 0xc7, 0, dst, insn.imm),
-                ebpf::MOV32_REG  => emit_mov(self, src, dst),
+                ebpf::MOV32_REG  => emit_alu32(self, 0x89, src, dst),
                 ebpf::ARSH32_IMM => emit_alu32_imm8(self, 0xc1, 7, dst, insn.imm as i8),
                 ebpf::ARSH32_REG => {
                     emit_mov(self, src, RCX);
                     emit_alu32(self, 0xd3, 7, dst);
                 },
-                ebpf::LE         => {}, // No-op
-                ebpf::BE         => {
+                // No byte swap on x86, but the upper bits are cleared, as in the interpreter
+                ebpf::LE         => match insn.imm {
+                    // and
+                    16 => emit_alu32_imm32(self, 0x81, 4, dst, 0xffff),
+                    // mov dst32, dst32
+                    32 => emit_alu32(self, 0x89, dst, dst),
+                    _  => {},
+                },
+                ebpf::BE | ebpf::BSWAP => {
                     match insn.imm {
                         16 => {
                             // rol
@@ -626,7 +1207,7 @@ impl<'a> JitMemory<'a> {
                 ebpf::MUL64_IMM | ebpf::MUL64_REG |
                     ebpf::DIV64_IMM | ebpf::DIV64_REG |
                     ebpf::MOD64_IMM | ebpf::MOD64_REG  =>
-                    muldivmod(self, insn_ptr as u16, insn.opc, src, dst, insn.imm),
+                    muldivmod(self, insn_ptr as u16, insn.opc, src, dst, insn.imm, div_by_zero),
                 ebpf::OR64_IMM   => emit_alu64_imm32(self, 0x81, 1, dst, insn.imm),
                 ebpf::OR64_REG   => emit_alu64(self, 0x09, src, dst),
                 ebpf::AND64_IMM  => emit_alu64_imm32(self, 0x81, 4, dst, insn.imm),
@@ -645,6 +1226,7 @@ impl<'a> JitMemory<'a> {
                 ebpf::XOR64_IMM  => emit_alu64_imm32(self, 0x81, 6, dst, insn.imm),
                 ebpf::XOR64_REG  => emit_alu64(self, 0x31, src, dst),
                 ebpf::MOV64_IMM  => emit_load_imm(self, dst, insn.imm as i64),
+                ebpf::MOV64_REG if src == dst => (),
                 ebpf::MOV64_REG  => emit_mov(self, src, dst),
                 ebpf::ARSH64_IMM => emit_alu64_imm8(self, 0xc1, 7, dst, insn.imm as i8),
                 ebpf::ARSH64_REG => {
@@ -710,29 +1292,66 @@ impl<'a> JitMemory<'a> {
                     emit_cmp(self, src, dst);
                     emit_jcc(self, 0x8d, target_pc);
                 },
+                ebpf::JLT_IMM | ebpf::JLE_IMM | ebpf::JSLT_IMM | ebpf::JSLE_IMM => {
+                    emit_cmp_imm32(self, dst, insn.imm);
+                    emit_jcc(self, jcc_code(insn.opc), target_pc);
+                },
+                ebpf::JLT_REG | ebpf::JLE_REG | ebpf::JSLT_REG | ebpf::JSLE_REG => {
+                    emit_cmp(self, src, dst);
+                    emit_jcc(self, jcc_code(insn.opc), target_pc);
+                },
+                ebpf::CALL if array_lookups.contains_key(&insn_ptr) => {
+                    let (map, values) = array_lookups[&insn_ptr];
+                    emit_array_lookup(self, map, values);
+                },
+                ebpf::CALL if insn.src != 0 => {
+                    return Err(EbpfError::JitError(
+                        format!("[JIT] Error: BPF-to-BPF calls are not supported (insn #{:?})", insn_ptr)));
+                },
                 ebpf::CALL       => {
                     // For JIT, helpers in use MUST be registered at compile time. They can be
                     // updated later, but not created after compiling (we need the address of the
                     // helper function in the JIT-compiled program).
-                    if let Some(_) = helpers.get(&(insn.imm as u32)) {
-                        // We reserve RCX for shifts
-                        emit_mov(self, R9, RCX);
-                        emit_call(self, helpers[&(insn.imm as u32)] as i64);
+                    if let Some(&helper) = helpers.get(&(insn.imm as u32)) {
+                        let intrinsics = self.intrinsics;
+                        if !emit_intrinsic(self, intrinsics, helper) {
+                            // We reserve RCX for shifts
+                            emit_mov(self, R9, RCX);
+                            emit_call(self, helper as i64);
+                        }
                     } else {
-                        panic!("[JIT] Error: unknown helper function (id: {:#x})",
-                               insn.imm as u32);
+                        return Err(EbpfError::JitError(
+                            format!("[JIT] Error: unknown helper function (id: {:#x})",
+                                    insn.imm as u32)));
                     };
                 },
                 ebpf::TAIL_CALL  => { unimplemented!() },
                 ebpf::EXIT       => {
-                    if insn_ptr != prog.len() / ebpf::INSN_SIZE - 1 {
+                    // The last instruction emitted falls through into the epilogue.
+                    if block + 1 < ranges.len() || insn_ptr + 1 != ranges[block].1 {
                         emit_jmp(self, TARGET_PC_EXIT);
                     };
                 },
 
+                // BPF_JMP32 class
+                ebpf::JA32       => emit_jmp(self, insn_ptr as isize + insn.imm as isize + 1),
+                _ if insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP32 => {
+                    let op = insn.opc & ebpf::BPF_ALU_OP_MASK;
+                    match (op, insn.opc & ebpf::BPF_X) {
+                        // test
+                        (ebpf::BPF_JSET, 0) => emit_alu32_imm32(self, 0xf7, 0, dst, insn.imm),
+                        (ebpf::BPF_JSET, _) => emit_alu32(self, 0x85, src, dst),
+                        // cmp
+                        (_, 0)              => emit_alu32_imm32(self, 0x81, 7, dst, insn.imm),
+                        (_, _)              => emit_alu32(self, 0x39, src, dst),
+                    }
+                    emit_jcc(self, jcc_code(insn.opc), target_pc);
+                },
+
                 _                => {
-                    panic!("[JIT] Error: unknown eBPF opcode {:#2x} (insn #{:?})",
-                           insn.opc, insn_ptr);
+                    return Err(EbpfError::JitError(
+                        format!("[JIT] Error: unknown eBPF opcode {:#2x} (insn #{:?})",
+                                insn.opc, insn_ptr)));
                 },
             }
 
@@ -748,8 +1367,16 @@ impl<'a> JitMemory<'a> {
         }
 
         // Deallocate stack space
-        emit_alu64_imm32(self, 0x81, 0, RSP, ebpf::STACK_SIZE as i32);
+        if frame_pointer.is_none() {
+            emit_alu64_imm32(self, 0x81, 0, RSP, ebpf::STACK_SIZE as i32);
+        }
 
+        // Landing pad for faults, see `run()`: the stack pointer is restored from R12
+        set_anchor(self, TARGET_PC_FAULT);
+        emit_mov(self, R12, RSP);
+
+        emit_alu64_imm32(self, 0x81, 0, RSP, 8);
+        emit_pop(self, R12);
         emit_pop(self, R15);
         emit_pop(self, R14);
         emit_pop(self, R13);
@@ -777,6 +1404,31 @@ impl<'a> JitMemory<'a> {
         emit_call(self, log as i64);
         emit_load_imm(self, map_register(0), -1);
         emit_jmp(self, TARGET_PC_EXIT);
+
+        Ok(())
+    }
+
+    // Links the block ending before instruction `end`, whose last instruction has opcode `opc`, to
+    // the block emitted after it, starting at instruction `next` (the end of the program for the
+    // last block): the block falls through to the following instruction of the program if it is
+    // next, or jumps to it. If the next block is the target of the final conditional jump of the
+    // block, the condition is inverted instead.
+    fn link_block(&mut self, end: usize, next: usize, opc: u8) {
+        if next == end {
+            return;
+        }
+        match opc {
+            ebpf::EXIT | ebpf::JA | ebpf::JA32 => (),
+            _ if ebpf::is_conditional_jump(opc) &&
+                 self.jumps.last().map(|jump| jump.target_pc) == Some(next as isize) => {
+                // The condition codes of x86 come in pairs, differing by their lowest bit.
+                let jump = self.jumps.last_mut().unwrap();
+                jump.target_pc = end as isize;
+                let loc = jump.offset_loc - 1;
+                self[loc] ^= 1;
+            },
+            _ => emit_jmp(self, end as isize),
+        }
     }
 
     fn resolve_jumps(&mut self)
@@ -792,7 +1444,7 @@ impl<'a> JitMemory<'a> {
                 let offset_loc = jump.offset_loc as i32 + std::mem::size_of::<i32>() as i32;
                 let rel = &(target_loc as i32 - offset_loc) as *const i32;
 
-                let offset_ptr = self.contents.as_ptr().offset(jump.offset_loc as isize);
+                let offset_ptr = self.contents.as_mut_ptr().offset(jump.offset_loc as isize);
 
                 libc::memcpy(offset_ptr as *mut libc::c_void, rel as *const libc::c_void,
                              std::mem::size_of::<i32>());
@@ -801,7 +1453,206 @@ impl<'a> JitMemory<'a> {
     }
 } // struct JitMemory
 
-impl<'a> Index<usize> for JitMemory<'a> {
+// Faults of the compiled programs, caught by `run()`.
+//
+// The compiled programs are registered with the location of their landing pad, which returns from
+// the program after restoring the stack pointer saved in R12 by the prologue. When a program run
+// by `run()` faults, the signal handler records the address of the faulty instruction, and resumes
+// the program at its landing pad. Other faults are passed to the handler installed before ours.
+
+struct CodeInfo {
+    size:    usize,
+    len:     usize,
+    landing: usize,
+    pc_locs: std::vec::Vec<usize>,
+    // Number of compilations that returned this code, see `compile()`.
+    users:   usize,
+    // The generation of the code in the cache of `compile()`, 0 if it is not cached.
+    generation: u64,
+    // Number of runs of the code in progress, and time of its last run, for the eviction of the
+    // least recently used code from the cache.
+    running:    usize,
+    last_used:  u64,
+}
+
+// Compiled programs, by address.
+static CODE_INFO: Mutex<BTreeMap<usize, CodeInfo>> = Mutex::new(BTreeMap::new());
+
+// The program running on a thread, and the fault it raised, if caught. The bounds of its code and
+// the address to resume at after a fault are only read by the handler of faults.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(all(target_os = "linux", target_arch = "x86_64")), allow(dead_code))]
+struct Running {
+    start:   usize,
+    end:     usize,
+    landing: usize,
+    fault:   Option<usize>,
+}
+
+thread_local! {
+    // The program run by `run()` on this thread.
+    static RUNNING: Cell<Option<Running>> = const { Cell::new(None) };
+}
+
+// Restores the program run before, for programs run from helpers.
+struct RunningGuard(Option<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(self.0));
+    }
+}
+
+// The handler reads and sets the instruction pointer in the saved context of the thread, whose
+// layout depends on the architecture.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod fault_handler {
+    use std::sync::{Once, OnceLock};
+
+    use super::libc;
+    use super::RUNNING;
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGFPE];
+
+    // The actions installed before ours, in the order of `SIGNALS`.
+    static PREVIOUS: [OnceLock<libc::sigaction>; 2] = [OnceLock::new(), OnceLock::new()];
+
+    pub fn install() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| unsafe {
+            for (i, &sig) in SIGNALS.iter().enumerate() {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handle_fault as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(sig, &action, &mut previous);
+                let _ = PREVIOUS[i].set(previous);
+            }
+        });
+    }
+
+    extern "C" fn handle_fault(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+        let uc = ctx as *mut libc::ucontext_t;
+        let rip = unsafe { &mut (*uc).uc_mcontext.gregs[libc::REG_RIP as usize] };
+        let caught = RUNNING.try_with(|running| match running.get() {
+            Some(mut run) if run.fault.is_none() && run.start <= *rip as usize && (*rip as usize) < run.end => {
+                run.fault = Some(*rip as usize);
+                running.set(Some(run));
+                *rip = run.landing as libc::greg_t;
+                true
+            },
+            _ => false,
+        });
+        if caught == Ok(true) {
+            return;
+        }
+
+        let previous = match SIGNALS.iter().position(|&s| s == sig).and_then(|i| PREVIOUS[i].get()) {
+            Some(previous) => previous,
+            None           => return,
+        };
+        unsafe {
+            match previous.sa_sigaction {
+                // Restore the default action, run when the faulty instruction is run again.
+                libc::SIG_DFL | libc::SIG_IGN => {
+                    let mut action: libc::sigaction = std::mem::zeroed();
+                    action.sa_sigaction = libc::SIG_DFL;
+                    libc::sigaction(sig, &action, std::ptr::null_mut());
+                },
+                handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                    let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                        std::mem::transmute(handler);
+                    handler(sig, info, ctx);
+                },
+                handler => {
+                    let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+                    handler(sig);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod fault_handler {
+    // Faults are not caught on other systems and architectures.
+    pub fn install() {}
+}
+
+// The size of the machine code of the compiled program `jit`, if it is a compiled program that
+// was not evicted from the cache.
+pub fn code_size(jit: Compiled) -> Option<usize> {
+    CODE_INFO.lock().unwrap().get(&(jit.entry as usize))
+        .filter(|info| info.generation == jit.generation)
+        .map(|info| info.len)
+}
+
+// Whether the compiled program `jit` was returned by several compilations, see `compile()`.
+pub fn code_shared(jit: Compiled) -> bool {
+    CODE_INFO.lock().unwrap().get(&(jit.entry as usize))
+        .is_some_and(|info| info.generation == jit.generation && info.users > 1)
+}
+
+// Marks the compiled program `jit` as running until the guard is dropped, so that it is not
+// evicted from the cache meanwhile. Returns `None` if the program was evicted: its code is freed,
+// and its address may hold another program.
+pub fn acquire(jit: Compiled) -> Option<CodeGuard> {
+    if jit.generation == 0 {
+        return Some(CodeGuard(None));
+    }
+    let mut code_info = CODE_INFO.lock().unwrap();
+    let info = code_info.get_mut(&(jit.entry as usize)).filter(|info| info.generation == jit.generation)?;
+    info.running += 1;
+    info.last_used = CLOCK.fetch_add(1, Ordering::Relaxed);
+    Some(CodeGuard(Some(jit.entry as usize)))
+}
+
+// A run of a cached program in progress, see `acquire()`.
+pub struct CodeGuard(Option<usize>);
+
+impl Drop for CodeGuard {
+    fn drop(&mut self) {
+        if let Some(start) = self.0 {
+            if let Some(info) = CODE_INFO.lock().unwrap().get_mut(&start) {
+                info.running -= 1;
+            }
+        }
+    }
+}
+
+// Runs the compiled program `jit` with `call`. With `catch_faults`, the memory faults (SIGSEGV)
+// and arithmetic faults (SIGFPE) raised by the instructions of the program are returned as
+// `EbpfError::MemoryFault`; faults raised in helpers are not.
+pub fn run<F: FnOnce(JitFn) -> u64>(jit: JitFn, catch_faults: bool, call: F) -> Result<u64, EbpfError> {
+    let start = jit as usize;
+    let code = CODE_INFO.lock().unwrap().get(&start).map(|info| (start + info.size, start + info.landing));
+    let (end, landing) = match code {
+        Some(code) if catch_faults => code,
+        _ => return Ok(call(jit)),
+    };
+    fault_handler::install();
+    let run = Running { start, end, landing, fault: None };
+    let guard = RunningGuard(RUNNING.with(|running| running.replace(Some(run))));
+    let res = call(jit);
+    let fault = RUNNING.with(|running| running.get()).and_then(|run| run.fault);
+    drop(guard);
+
+    match fault {
+        None            => Ok(res),
+        Some(native_pc) => {
+            let offset = native_pc - start;
+            // The basic blocks may not be laid out in the order of the program.
+            let ebpf_pc = CODE_INFO.lock().unwrap().get(&start).and_then(|info| {
+                info.pc_locs.iter().enumerate().filter(|&(_, &loc)| loc <= offset)
+                    .max_by_key(|&(_, &loc)| loc).map(|(pc, _)| pc)
+            }).unwrap_or(0);
+            Err(EbpfError::MemoryFault { native_pc, ebpf_pc })
+        },
+    }
+}
+
+impl Index<usize> for JitMemory {
     type Output = u8;
 
     fn index(&self, _index: usize) -> &u8 {
@@ -809,16 +1660,16 @@ impl<'a> Index<usize> for JitMemory<'a> {
     }
 }
 
-impl<'a> IndexMut<usize> for JitMemory<'a> {
+impl IndexMut<usize> for JitMemory {
     fn index_mut(&mut self, _index: usize) -> &mut u8 {
         &mut self.contents[_index]
     }
 }
 
-impl<'a> std::fmt::Debug for JitMemory<'a> {
+impl std::fmt::Debug for JitMemory {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         fmt.write_str("JIT contents: [")?;
-        for i in self.contents as &[u8] {
+        for i in &self.contents {
             fmt.write_fmt(format_args!(" {:#04x},", i))?;
         };
         fmt.write_str(" ] | ")?;
@@ -831,17 +1682,280 @@ impl<'a> std::fmt::Debug for JitMemory<'a> {
     }
 }
 
-// In the end, this is the only thing we export
-pub fn compile(prog: &std::vec::Vec<u8>,
-               helpers: &HashMap<u32, fn (u64, u64, u64, u64, u64) -> u64>,
-               use_mbuff: bool, update_data_ptr: bool)
-    -> (fn(*mut u8, usize, *mut u8, usize, usize, usize) -> u64) {
-
-    // TODO: check how long the page must be to be sure to support an eBPF program of maximum
-    // possible length
-    let mut jit = JitMemory::new(1);
-    jit.jit_compile(prog, use_mbuff, update_data_ptr, helpers);
+// Signature of the compiled programs: mbuff, mbuff_len, mem, mem_len, mem_offset and
+// mem_end_offset.
+pub type JitFn = fn(*mut u8, usize, *mut u8, usize, usize, usize) -> u64;
+
+// The executable memory holding a compiled program, freed when dropped, with the maps whose
+// values are accessed directly by the program. The memory is made of whole pages, not shared with
+// other allocations.
+pub struct JitCode {
+    ptr:  *mut u8,
+    size: usize,
+    _maps: std::vec::Vec<Arc<Map>>,
+}
+
+// The code is not modified once compiled, and runs on the stack of the calling thread.
+unsafe impl Send for JitCode {}
+unsafe impl Sync for JitCode {}
+
+impl JitCode {
+    // Copies `code` into executable memory. With `huge_pages`, the memory is aligned to, and made
+    // of, huge pages, which the kernel is advised to use for it where supported: programs run
+    // often then take a single entry of the iTLB.
+    fn new(code: &[u8], maps: &[Arc<Map>], huge_pages: bool) -> JitCode {
+        let page_size = match huge_pages {
+            true  => HUGE_PAGE_SIZE,
+            false => *PAGE_SIZE,
+        };
+        let size = code.len().div_ceil(page_size) * page_size;
+        unsafe {
+            let mut raw: *mut libc::c_void = std::ptr::null_mut();
+            if libc::posix_memalign(&mut raw, page_size, size) != 0 {
+                panic!("Error: cannot allocate the executable memory of the program");
+            }
+            #[cfg(target_os = "linux")]
+            if huge_pages {
+                libc::madvise(raw, size, libc::MADV_HUGEPAGE);
+            }
+            memset(raw, 0xc3, size);  // fill the rest of the pages with 'RET' calls
+            libc::memcpy(raw, code.as_ptr() as *const libc::c_void, code.len());
+            libc::mprotect(raw, size, libc::PROT_EXEC | libc::PROT_READ);
+            JitCode { ptr: raw as *mut u8, size, _maps: maps.to_vec() }
+        }
+    }
+
+    pub fn entry(&self) -> JitFn {
+        unsafe { mem::transmute::<*const u8, JitFn>(self.ptr) }
+    }
+}
+
+impl Drop for JitCode {
+    fn drop(&mut self) {
+        CODE_INFO.lock().unwrap().remove(&(self.ptr as usize));
+        unsafe {
+            libc::mprotect(self.ptr as *mut libc::c_void, self.size, libc::PROT_READ | libc::PROT_WRITE);
+            libc::free(self.ptr as *mut libc::c_void);
+        }
+    }
+}
+
+// The stack of programs JIT-compiled with `EbpfVmMbuff::set_stack_guard()`: a page mapped between
+// two inaccessible guard pages.
+pub struct GuardedStack {
+    base: *mut u8,
+    size: usize,
+}
+
+impl GuardedStack {
+    pub fn new() -> GuardedStack {
+        let size = 3 * *PAGE_SIZE;
+        unsafe {
+            let base = libc::mmap(std::ptr::null_mut(), size, libc::PROT_NONE,
+                                  libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+            if base == libc::MAP_FAILED {
+                panic!("Error: cannot map the stack of the program");
+            }
+            libc::mprotect(base.add(*PAGE_SIZE), *PAGE_SIZE, libc::PROT_READ | libc::PROT_WRITE);
+            GuardedStack { base: base as *mut u8, size }
+        }
+    }
+
+    // The initial value of R10. The stack starts at the lower guard page, so that the accesses
+    // past its bottom fault.
+    pub fn frame_pointer(&self) -> u64 {
+        (self.base as usize + *PAGE_SIZE + ebpf::STACK_SIZE) as u64
+    }
+}
+
+impl Drop for GuardedStack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+// Compiles the program into executable memory owned by the caller. With a `frame_pointer`, the
+// program uses the stack ending at this address instead of allocating its stack on the native
+// stack. Array maps looked up by the program must be in `maps`, with the indices used by the
+// program, to be inlined. With `harden`, the immediates of the program are blinded. With
+// `huge_pages`, the code is backed by huge pages, see `JitCode::new()`. With a `layout`, the basic
+// blocks of the program are emitted in its order.
+#[allow(clippy::too_many_arguments)]
+pub fn compile_code(prog: &std::vec::Vec<u8>,
+                    helpers: &HashMap<u32, ebpf::Helper>, maps: &[Arc<Map>],
+                    use_mbuff: bool, update_data_ptr: bool, div_by_zero: ebpf::DivByZero,
+                    frame_pointer: Option<u64>, intrinsics: Intrinsics, harden: bool,
+                    huge_pages: bool, layout: Option<&[usize]>)
+    -> Result<JitCode, EbpfError> {
+
+    verifier::check_maps(prog, maps.len())?;
+
+    // Most instructions compile to a few native instructions, the buffer grows for the others.
+    let mut jit = JitMemory::new(prog.len() * 2);
+    jit.intrinsics = intrinsics;
+    jit.maps = maps.to_vec();
+    jit.harden = harden;
+    jit.jit_compile(prog, use_mbuff, update_data_ptr, helpers, div_by_zero, frame_pointer, layout)?;
     jit.resolve_jumps();
+    let code = JitCode::new(&jit.contents[..jit.offset], maps, huge_pages);
+    CODE_INFO.lock().unwrap().insert(code.ptr as usize, CodeInfo {
+        size:    code.size,
+        len:     jit.offset,
+        landing: jit.special_targets[&TARGET_PC_FAULT],
+        pc_locs: jit.pc_locs[..prog.len() / ebpf::INSN_SIZE].to_vec(),
+        users:   1,
+        generation: 0,
+        running:    0,
+        last_used:  0,
+    });
+    Ok(code)
+}
+
+// What the code generated by `compile()` depends on. The helpers and maps are identified by
+// their addresses: the maps are kept alive by the code, as long as it is cached.
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+    prog:            std::vec::Vec<u8>,
+    helpers:         std::vec::Vec<(u32, usize)>,
+    maps:            std::vec::Vec<usize>,
+    use_mbuff:       bool,
+    update_data_ptr: bool,
+    div_by_zero:     ebpf::DivByZero,
+    frame_pointer:   Option<u64>,
+    intrinsics:      Intrinsics,
+    harden:          bool,
+    huge_pages:      bool,
+    layout:          Option<std::vec::Vec<usize>>,
+}
+
+// Programs compiled by `compile()`, and the limit of the executable memory they use, see
+// `set_code_budget()`.
+#[derive(Default)]
+struct CodeCache {
+    code:   HashMap<CacheKey, JitCode>,
+    budget: Option<usize>,
+}
+
+static CODE_CACHE: LazyLock<Mutex<CodeCache>> = LazyLock::new(Default::default);
+
+// The last generation given to a cached program, and the clock ordering the runs of programs.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+// A program compiled by `compile()`: its entry point, and its generation in the cache, which
+// tells whether it was evicted since, see `acquire()`. Programs with generation 0 are not cached,
+// and never evicted.
+#[derive(Clone, Copy)]
+pub struct Compiled {
+    pub entry:  JitFn,
+    generation: u64,
+}
+
+impl Compiled {
+    pub fn uncached(entry: JitFn) -> Compiled {
+        Compiled { entry, generation: 0 }
+    }
+}
 
-    unsafe { mem::transmute(jit.contents.as_ptr()) }
+// Compiles the program into executable memory kept in a cache. Compiling the same program again,
+// with the same helpers, maps and options, returns the same code, so that the VMs loading the same
+// program share it. The code is kept until the end of the process, unless the cache exceeds its
+// budget, see `set_code_budget()`.
+#[allow(clippy::too_many_arguments)]
+pub fn compile(prog: &std::vec::Vec<u8>,
+               helpers: &HashMap<u32, ebpf::Helper>, maps: &[Arc<Map>],
+               use_mbuff: bool, update_data_ptr: bool, div_by_zero: ebpf::DivByZero,
+               frame_pointer: Option<u64>, intrinsics: Intrinsics, harden: bool,
+               huge_pages: bool, layout: Option<&[usize]>)
+    -> Result<Compiled, EbpfError> {
+    let mut helper_addrs: std::vec::Vec<_> = helpers.iter()
+        .map(|(&key, &helper)| (key, helper as usize))
+        .collect();
+    helper_addrs.sort_unstable();
+    let key = CacheKey {
+        prog: prog.clone(),
+        helpers: helper_addrs,
+        maps: maps.iter().map(|map| Arc::as_ptr(map) as usize).collect(),
+        use_mbuff,
+        update_data_ptr,
+        div_by_zero,
+        frame_pointer,
+        intrinsics,
+        harden,
+        huge_pages,
+        layout: layout.map(|layout| layout.to_vec()),
+    };
+    if let Some(compiled) = cached(&CODE_CACHE.lock().unwrap(), &key) {
+        return Ok(compiled);
+    }
+    let code = compile_code(prog, helpers, maps, use_mbuff, update_data_ptr, div_by_zero,
+                            frame_pointer, intrinsics, harden, huge_pages, layout)?;
+    let mut cache = CODE_CACHE.lock().unwrap();
+    // Concurrent compilations of the same program all succeed, the first one is cached.
+    if let Some(compiled) = cached(&cache, &key) {
+        return Ok(compiled);
+    }
+    let compiled = Compiled { entry: code.entry(), generation: GENERATION.fetch_add(1, Ordering::Relaxed) + 1 };
+    if let Some(info) = CODE_INFO.lock().unwrap().get_mut(&(compiled.entry as usize)) {
+        info.generation = compiled.generation;
+        info.last_used = CLOCK.fetch_add(1, Ordering::Relaxed);
+    }
+    cache.code.insert(key, code);
+    evict(&mut cache);
+    Ok(compiled)
+}
+
+// Returns the program cached for `key`, if any, counting one more compilation returning it.
+fn cached(cache: &CodeCache, key: &CacheKey) -> Option<Compiled> {
+    let entry = cache.code.get(key)?.entry();
+    let mut code_info = CODE_INFO.lock().unwrap();
+    let info = code_info.get_mut(&(entry as usize))?;
+    info.users += 1;
+    Some(Compiled { entry, generation: info.generation })
+}
+
+// Limits the executable memory used by the programs cached by `compile()` to `budget` bytes, or
+// removes the limit. While the cache exceeds its budget, the least recently run programs are
+// evicted, and their code freed; programs running at that time are kept. VMs check that their
+// program was not evicted with `acquire()` before running it.
+pub fn set_code_budget(budget: Option<usize>) {
+    let mut cache = CODE_CACHE.lock().unwrap();
+    cache.budget = budget;
+    evict(&mut cache);
+}
+
+// The executable memory used by the programs cached by `compile()`, in bytes.
+pub fn code_usage() -> usize {
+    let cache = CODE_CACHE.lock().unwrap();
+    cache.code.values().map(|code| code.size).sum()
+}
+
+// Evicts the least recently run programs from the cache while it exceeds its budget.
+fn evict(cache: &mut CodeCache) {
+    let budget = match cache.budget {
+        Some(budget) => budget,
+        None         => return,
+    };
+    let mut usage: usize = cache.code.values().map(|code| code.size).sum();
+    let mut candidates: std::vec::Vec<_> = {
+        let code_info = CODE_INFO.lock().unwrap();
+        cache.code.values()
+            .filter_map(|code| code_info.get(&(code.ptr as usize)).map(|info| (info, code)))
+            .filter(|(info, _)| info.running == 0)
+            .map(|(info, code)| (info.last_used, code.ptr as usize, code.size))
+            .collect()
+    };
+    candidates.sort_unstable();
+    let mut evicted = std::collections::HashSet::new();
+    for (_, start, size) in candidates {
+        if usage <= budget {
+            break;
+        }
+        evicted.insert(start);
+        usage -= size;
+    }
+    // Dropping the code frees it.
+    cache.code.retain(|_, code| !evicted.contains(&(code.ptr as usize)));
 }
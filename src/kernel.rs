@@ -0,0 +1,235 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module compares the results of rbpf with those of the Linux kernel, for a given program:
+//! the program is loaded into the kernel with the `bpf()` system call, run by the kernel on each
+//! input of a corpus with command `BPF_PROG_TEST_RUN`, and run by the interpreter of rbpf on the
+//! same input. Users validate in this way that rbpf runs their programs as the kernel does.
+//! Available with feature `kernel`, on Linux. Loading programs into the kernel requires the
+//! `CAP_BPF` capability, or `CAP_SYS_ADMIN` on older kernels.
+//!
+//! Programs are loaded as XDP programs (`BPF_PROG_TYPE_XDP`), and receive the input as packet
+//! data, through the fields `data` and `data_end` of `struct xdp_md`, at offsets 0 and 4 of the
+//! context. For rbpf, the accesses to these fields are rewritten with `ctx::convert_ctx_access()`
+//! to read the 64-bit pointers of a metadata buffer, as with `EbpfVmFixedMbuff::new(prog, 0, 8)`;
+//! programs accessing the other fields of the context are rejected. Programs cannot use maps. The
+//! kernel runs its own helpers, and the interpreter the helpers registered with
+//! `KernelChecker::register_helper()`, which should behave the same. The kernel requires inputs
+//! holding at least an Ethernet header, that is 14 bytes.
+//!
+//! The results compared are the values returned, truncated to 32 bits as the kernel returns them,
+//! and the contents of the packet after the run.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind};
+use std::mem;
+
+use ctx::{self, CtxLayout};
+use ebpf;
+use error::EbpfError;
+use interpreter;
+
+// Commands of the `bpf()` system call.
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+
+const BPF_PROG_TYPE_XDP: u32 = 6;
+
+// The size of the log of the kernel verifier, returned when a program is rejected.
+const LOG_SIZE: usize = 64 * 1024;
+
+// The room left in output buffers for programs growing the packet.
+const OUTPUT_HEADROOM: usize = 256;
+
+// The attributes of command `BPF_PROG_LOAD`, the first ones of `union bpf_attr`. The kernel
+// accepts larger attributes if the bytes it does not know are zeroes.
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type:    u32,
+    insn_cnt:     u32,
+    insns:        u64,
+    license:      u64,
+    log_level:    u32,
+    log_size:     u32,
+    log_buf:      u64,
+    kern_version: u32,
+    prog_flags:   u32,
+}
+
+// The attributes of command `BPF_PROG_TEST_RUN`.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd:       u32,
+    retval:        u32,
+    data_size_in:  u32,
+    data_size_out: u32,
+    data_in:       u64,
+    data_out:      u64,
+    repeat:        u32,
+    duration:      u32,
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> Result<libc::c_long, Error> {
+    let res = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, mem::size_of::<T>()) };
+    match res {
+        -1  => Err(Error::last_os_error()),
+        res => Ok(res),
+    }
+}
+
+/// A difference between the results of rbpf and of the kernel on the same input, as found by
+/// `KernelChecker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The input on which the results differ.
+    pub input:   Vec<u8>,
+    /// The value returned by the program run by rbpf, truncated to 32 bits, or the error it met.
+    pub rbpf:    Result<u32, EbpfError>,
+    /// The value returned by the program run by the kernel.
+    pub kernel:  u32,
+    /// The contents of the packet after the run by rbpf and by the kernel.
+    pub outputs: (Vec<u8>, Vec<u8>),
+}
+
+/// A program loaded into the kernel, run by the kernel and by rbpf to compare their results. See
+/// the module documentation.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rbpf::kernel::KernelChecker;
+///
+/// // Returns XDP_DROP if the first byte of the packet is 0xff, and XDP_PASS otherwise.
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+///     0x61, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1] (ctx->data)
+///     0x61, 0x13, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r3, [r1+4] (ctx->data_end)
+///     0xbf, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r2
+///     0x07, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r4, 1
+///     0x2d, 0x34, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jgt r4, r3, +3
+///     0x71, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r2]
+///     0x55, 0x02, 0x01, 0x00, 0xff, 0x00, 0x00, 0x00, // jne r2, 0xff, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let checker = KernelChecker::load(&prog).unwrap();
+/// let corpus = (0..=255).map(|b| { let mut packet = vec![0; 64]; packet[0] = b; packet });
+/// assert_eq!(checker.check_inputs(corpus).unwrap(), None);
+/// ```
+pub struct KernelChecker {
+    prog:    Vec<u8>,
+    fd:      libc::c_int,
+    helpers: HashMap<u32, ebpf::Helper>,
+}
+
+impl KernelChecker {
+
+    /// Load `prog` into the kernel, and prepare it for rbpf.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the program accesses the context out of the
+    /// fields `data` and `data_end`, the error of the system call if the kernel rejects the
+    /// program, with the log of its verifier, or an error of kind `PermissionDenied` if the
+    /// process is not allowed to load programs.
+    pub fn load(prog: &[u8]) -> Result<KernelChecker, Error> {
+        let layout = CtxLayout::new()
+            .field(0, 4, 0, 8)
+            .field(4, 4, 8, 8);
+        let converted = ctx::try_convert_ctx_access(prog, &layout)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+
+        let mut log = vec![0u8; LOG_SIZE];
+        let license = b"GPL\0";
+        let mut attr = ProgLoadAttr {
+            prog_type: BPF_PROG_TYPE_XDP,
+            insn_cnt:  (prog.len() / ebpf::INSN_SIZE) as u32,
+            insns:     prog.as_ptr() as u64,
+            license:   license.as_ptr() as u64,
+            log_level: 1,
+            log_size:  LOG_SIZE as u32,
+            log_buf:   log.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        let fd = bpf(BPF_PROG_LOAD, &mut attr).map_err(|err| {
+            let log = CStr::from_bytes_until_nul(&log).map(|log| log.to_string_lossy()).unwrap_or_default();
+            match log.is_empty() {
+                true  => err,
+                false => Error::new(err.kind(), format!("{}\n{}", err, log)),
+            }
+        })?;
+        Ok(KernelChecker { prog: converted, fd: fd as libc::c_int, helpers: HashMap::new() })
+    }
+
+    /// Register a helper function for rbpf, see `EbpfVmMbuff::register_helper()`. The kernel runs
+    /// its own helper of the same key.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Run the program in the kernel on `input`, and return the value it returned, and the
+    /// contents of the packet after the run.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the system call, for example of kind `InvalidInput` if the input is
+    /// shorter than an Ethernet header.
+    pub fn run_kernel(&self, input: &[u8]) -> Result<(u32, Vec<u8>), Error> {
+        let mut output = vec![0u8; input.len() + OUTPUT_HEADROOM];
+        let mut attr = TestRunAttr {
+            prog_fd:       self.fd as u32,
+            data_size_in:  input.len() as u32,
+            data_size_out: output.len() as u32,
+            data_in:       input.as_ptr() as u64,
+            data_out:      output.as_mut_ptr() as u64,
+            repeat:        1,
+            ..Default::default()
+        };
+        bpf(BPF_PROG_TEST_RUN, &mut attr)?;
+        output.truncate(attr.data_size_out as usize);
+        Ok((attr.retval, output))
+    }
+
+    /// Run the program with the interpreter of rbpf on `input`, and return the value it returned,
+    /// truncated to 32 bits, or the error it met, and the contents of the packet after the run.
+    pub fn run_rbpf(&self, input: &[u8]) -> (Result<u32, EbpfError>, Vec<u8>) {
+        let mem = input.to_vec();
+        let mut mbuff = (mem.as_ptr() as u64).to_le_bytes().to_vec();
+        mbuff.extend_from_slice(&(mem.as_ptr() as u64 + mem.len() as u64).to_le_bytes());
+        let res = interpreter::try_execute_program(&self.prog, &mem, &mbuff, &self.helpers,
+                                                   Default::default());
+        (res.map(|ret| ret as u32), mem)
+    }
+
+    /// Run the program in the kernel and with rbpf on each of the `inputs`, in order, and return
+    /// the first mismatch found, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `run_kernel()`.
+    pub fn check_inputs<I: IntoIterator<Item = Vec<u8>>>(&self, inputs: I)
+        -> Result<Option<Mismatch>, Error> {
+        for input in inputs {
+            let (kernel, kernel_output) = self.run_kernel(&input)?;
+            let (rbpf, rbpf_output) = self.run_rbpf(&input);
+            if rbpf != Ok(kernel) || rbpf_output != kernel_output {
+                return Ok(Some(Mismatch { input, rbpf, kernel, outputs: (rbpf_output, kernel_output) }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for KernelChecker {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
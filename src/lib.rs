@@ -15,15 +15,59 @@
 
 #![warn(missing_docs)]
 
-use std::u32;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use ctx::CtxBytes;
+use error::EbpfError;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 
 extern crate libc;
+#[cfg(feature = "fuzz")]
+extern crate arbitrary;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+pub mod analysis;
+pub mod assembler;
+pub mod batch;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod btf;
+pub mod capture;
+pub mod conformance;
+pub mod cost;
+pub mod csum;
+pub mod ctx;
+pub mod disassembler;
+pub mod dispatch;
 pub mod ebpf;
+pub mod equivalence;
+pub mod error;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 pub mod helpers;
-mod verifier;
+pub mod hooks;
+#[cfg(all(feature = "kernel", target_os = "linux"))]
+pub mod kernel;
+pub mod maps;
+pub mod minimize;
+pub mod packet;
+pub mod pin;
+pub mod profile;
+pub mod program;
+pub mod registry;
+pub mod shm;
+pub mod symbolic;
+pub mod testing;
+pub mod trace;
+pub mod verifier;
+mod interpreter;
 mod jit;
+mod tnum;
 
 // A metadata buffer with two offset indications. It can be used in one kind of eBPF VM to simulate
 // the use of a metadata buffer each time the program is executed, without the user having to
@@ -35,12 +79,162 @@ struct MetaBuff {
     buffer:          std::vec::Vec<u8>,
 }
 
+impl MetaBuff {
+    // Store the pointers to the start and the end of `mem` in the buffer.
+    fn write_pointers(&mut self, mem: &[u8]) {
+        self.buffer.write_u64_le(self.data_offset, mem.as_ptr() as u64);
+        self.buffer.write_u64_le(self.data_end_offset, mem.as_ptr() as u64 + mem.len() as u64);
+    }
+
+    // The layout of the VMs using this buffer.
+    fn layout(&self) -> MemoryLayout {
+        MemoryLayout::FixedMbuff {
+            buffer_len:      self.buffer.len(),
+            data_offset:     self.data_offset,
+            data_end_offset: self.data_end_offset,
+        }
+    }
+}
+
+// How the programs of a kind of VM receive packet data, which the code generated by the
+// JIT-compiler depends on. All the VMs run their programs with an `EbpfVmMbuff`, holding the
+// layout of the VM wrapping it, and differ only by the arguments of their execution functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemoryLayout {
+    // R1 points to a metadata buffer given by the caller, as with `EbpfVmMbuff`, or to a context
+    // structure.
+    Mbuff,
+    // R1 points to a metadata buffer of `buffer_len` bytes, into which the VM stores the pointers
+    // to packet data at the offsets given, as with `EbpfVmFixedMbuff`.
+    FixedMbuff { buffer_len: usize, data_offset: usize, data_end_offset: usize },
+    // R1 points to packet data, as with `EbpfVmRaw` and `EbpfVmNoData`.
+    Raw,
+}
+
+impl MemoryLayout {
+    // Arguments `use_mbuff` and `update_data_ptr` of `jit::compile()`.
+    fn jit_args(self) -> (bool, bool) {
+        match self {
+            MemoryLayout::Mbuff             => (true, false),
+            MemoryLayout::FixedMbuff { .. } => (true, true),
+            MemoryLayout::Raw               => (false, false),
+        }
+    }
+}
+
+/// A description of the packet about to be processed by an `EbpfVmFixedMbuff`, handed to the
+/// hook registered with `EbpfVmFixedMbuff::set_mbuff_hook()` so that it can fill the metadata
+/// buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketInfo<'p> {
+    /// Packet data. For multi-buffer packets, this is the first fragment only.
+    pub data:     &'p [u8],
+    /// Total length of the packet, in bytes, including all fragments.
+    pub len:      usize,
+    /// Index of the interface the packet is processed on, as set with
+    /// `EbpfVmFixedMbuff::set_ifindex()`.
+    pub ifindex:  u32,
+    /// The VLAN tag control information of the packet, if it starts with an Ethernet header
+    /// carrying an 802.1Q or 802.1ad tag.
+    pub vlan_tci: Option<u16>,
+}
+
+/// A hook filling the metadata buffer of an `EbpfVmFixedMbuff` before each packet is processed,
+/// see `EbpfVmFixedMbuff::set_mbuff_hook()`.
+pub type MbuffHook<'a> = dyn FnMut(&mut [u8], &PacketInfo) + 'a;
+
+impl<'p> PacketInfo<'p> {
+    fn new(data: &'p [u8], len: usize, ifindex: u32) -> PacketInfo<'p> {
+        let vlan_tci = match data.get(12..16) {
+            Some(&[0x81, 0x00, hi, lo]) | Some(&[0x88, 0xa8, hi, lo]) => Some(u16::from_be_bytes([hi, lo])),
+            _ => None,
+        };
+        PacketInfo { data, len, ifindex, vlan_tci }
+    }
+}
+
+/// A packet stored in a buffer with room before and after packet data, so that the programs run
+/// with `EbpfVmFixedMbuff::prog_exec_resizable()` can grow or shrink it, with helpers such as
+/// `bpf_xdp_adjust_head()` (see `helpers::BPF_XDP_ADJUST_HEAD_IDX`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketBuffer {
+    buffer: Vec<u8>,
+    start:  usize,
+    end:    usize,
+}
+
+impl PacketBuffer {
+
+    /// Create a buffer holding packet data `data`, preceded by `headroom` bytes and followed by
+    /// `tailroom` bytes, zeroed. The kernel reserves 256 bytes of headroom for XDP programs.
+    pub fn new(data: &[u8], headroom: usize, tailroom: usize) -> PacketBuffer {
+        let mut buffer = vec![0; headroom + data.len() + tailroom];
+        buffer[headroom..headroom + data.len()].copy_from_slice(data);
+        PacketBuffer { buffer, start: headroom, end: headroom + data.len() }
+    }
+
+    /// Packet data.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    /// Packet data, for modification.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.start..self.end]
+    }
+
+    /// The number of bytes of the buffer before packet data.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// The number of bytes of the buffer after packet data.
+    pub fn tailroom(&self) -> usize {
+        self.buffer.len() - self.end
+    }
+}
+
+/// An execution suspended when it ran out of fuel, see `EbpfVmMbuff::try_prog_exec_metered()`:
+/// its registers, its stack, and the next instruction to run. It is resumed with
+/// `EbpfVmMbuff::try_resume()`, by the VM that started it, on the same packet data and metadata
+/// buffer, which must not move in the meantime since the registers may point to them.
+pub struct Continuation<'a> {
+    state: Box<interpreter::ExecState<'a>>,
+    // The address of the program of the VM that started the execution.
+    vm:    usize,
+}
+
+impl Continuation<'_> {
+    /// The index of the next instruction to run, in the program running: after a tail call, this
+    /// is the program jumped to.
+    pub fn insn_ptr(&self) -> usize {
+        self.state.insn_ptr()
+    }
+}
+
+impl std::fmt::Debug for Continuation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Continuation").field("insn_ptr", &self.insn_ptr()).finish()
+    }
+}
+
+/// The outcome of an execution with a budget of fuel, see `EbpfVmMbuff::try_prog_exec_metered()`.
+#[derive(Debug)]
+pub enum Metered<'a> {
+    /// The program exited, returning this value.
+    Done(u64),
+    /// The program ran out of fuel before exiting: the execution can be resumed.
+    Suspended(Continuation<'a>),
+}
+
 /// A virtual machine to run eBPF program. This kind of VM is used for programs expecting to work
 /// on a metadata buffer containing pointers to packet data.
 ///
 /// # Examples
 ///
 /// ```
+/// use rbpf::ctx::CtxBytes;
+///
 /// let prog = vec![
 ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff at offset 8 into R1.
 ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
@@ -53,12 +247,8 @@ struct MetaBuff {
 /// // Just for the example we create our metadata buffer from scratch, and we store the pointers
 /// // to packet data start and end in it.
 /// let mut mbuff = vec![0u8; 32];
-/// unsafe {
-///     let mut data     = mbuff.as_ptr().offset(8)  as *mut u64;
-///     let mut data_end = mbuff.as_ptr().offset(24) as *mut u64;
-///     *data     = mem.as_ptr() as u64;
-///     *data_end = mem.as_ptr() as u64 + mem.len() as u64;
-/// }
+/// mbuff.write_u64_le(8, mem.as_ptr() as u64);
+/// mbuff.write_u64_le(24, mem.as_ptr() as u64 + mem.len() as u64);
 ///
 /// // Instantiate a VM.
 /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
@@ -69,8 +259,130 @@ struct MetaBuff {
 /// ```
 pub struct EbpfVmMbuff<'a> {
     prog:    &'a std::vec::Vec<u8>,
-    jit:     (fn (*mut u8, usize, *mut u8, usize, usize, usize) -> u64),
-    helpers: HashMap<u32, fn (u64, u64, u64, u64, u64) -> u64>,
+    jit:     jit::Compiled,
+    jit_async: OnceLock<AsyncJit>,
+    // How the program receives packet data, for the JIT-compiler.
+    layout:  MemoryLayout,
+    jit_threshold: Option<u64>,
+    interpreted_runs: AtomicU64,
+    jit_stack: Option<jit::GuardedStack>,
+    catch_faults: bool,
+    helpers: HashMap<u32, ebpf::Helper>,
+    helper_hook: Option<Box<dyn helpers::HelperHook + 'a>>,
+    frame_helpers: HashMap<u32, helpers::FrameHelper>,
+    fallible_helpers: HashMap<u32, ebpf::FallibleHelper>,
+    line_info: Option<btf::LineInfo>,
+    insn_policy: Option<Box<dyn verifier::InsnPolicy + 'a>>,
+    prog_limits: verifier::ProgLimits,
+    mem_helpers: bool,
+    probe_regions: Vec<interpreter::ProbeRegion<'a>>,
+    event_sink: Option<Box<dyn helpers::EventSink + 'a>>,
+    scratch: Option<RefCell<Vec<u8>>>,
+    config: HashMap<String, Vec<u8>>,
+    maps: Vec<Arc<maps::Map>>,
+    div_by_zero: ebpf::DivByZero,
+    memory_poisoning: bool,
+    intrinsics: helpers::Intrinsics,
+    jit_harden: bool,
+    jit_huge_pages: bool,
+    jit_profile: Option<profile::Profile>,
+    isa: ebpf::IsaVersion,
+    max_call_depth: usize,
+    insn_limit: Option<u64>,
+    read_only_packet: bool,
+    // Size of the context of the programs, if known when loading them, see `set_ctx_len()`.
+    ctx_len: Option<usize>,
+    // The loads and stores of the program that the interpreter does not check, see
+    // `mark_proven_accesses()`.
+    proven_accesses: Vec<bool>,
+    // The verified program the loaded program comes from, if loaded with `from_program()`.
+    program: Option<&'a program::Program>,
+    meta: ProgramMeta,
+}
+
+/// The resources used by a virtual machine, as returned by the `stats()` function of the VMs, for
+/// example to enforce quotas on programs supplied by several users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmStats {
+    /// Size of the loaded program, in bytes.
+    pub prog_size:          usize,
+    /// Size of the machine code of the program, in bytes, if it was JIT-compiled, and not evicted
+    /// since, see `set_jit_code_budget()`.
+    pub jit_code_size:      Option<usize>,
+    /// Whether the machine code was also returned by other compilations of the same program,
+    /// see `EbpfVmMbuff::jit_compile()`. The code is counted in full by each VM sharing it.
+    pub jit_code_shared:    bool,
+    /// Size of the stack of the program, in bytes.
+    pub stack_size:         usize,
+    /// Size of the metadata buffer managed by the VM, in bytes, for `EbpfVmFixedMbuff`.
+    pub mbuff_size:         usize,
+    /// Size of the scratch storage, in bytes, see `EbpfVmMbuff::set_scratch()`.
+    pub scratch_size:       usize,
+    /// Number of regions registered with `register_probe_region()`.
+    pub probe_regions:      usize,
+    /// Total size of the regions registered with `register_probe_region()`, in bytes.
+    pub probe_regions_size: usize,
+    /// Number of registered maps.
+    pub maps:               usize,
+    /// Memory used by the entries of the registered maps, in bytes, see
+    /// `maps::Map::memory_usage()`.
+    pub map_memory:         usize,
+}
+
+/// The name and tags attached to a VM with `set_name()` and `set_tag()`, to tell which program an
+/// error or a trace comes from, for example on a host running the programs of several customers.
+/// The errors of named VMs are wrapped in `EbpfError::InProgram`, and tracers are told the
+/// program they trace with `Tracer::start_program()`.
+///
+/// It is displayed as the name followed by the tags, as in `filter-42 customer=acme`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramMeta {
+    /// Name or identifier of the program, empty if not set.
+    pub name: String,
+    /// Key-value tags, in the order they were first set.
+    pub tags: Vec<(String, String)>,
+}
+
+impl ProgramMeta {
+    /// Whether neither a name nor tags are set.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_empty() && self.tags.is_empty()
+    }
+
+    /// The value of tag `key`, if set.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn set_tag(&mut self, key: &str, value: &str) {
+        match self.tags.iter_mut().find(|(k, _)| k == key) {
+            Some(tag) => tag.1 = value.to_string(),
+            None      => self.tags.push((key.to_string(), value.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for ProgramMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            let sep = if i == 0 && self.name.is_empty() { "" } else { " " };
+            write!(f, "{}{}={}", sep, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+// A JIT compilation running in a background thread, see `EbpfVmMbuff::jit_compile_async()`.
+struct AsyncJit {
+    result: Arc<OnceLock<Result<jit::Compiled, EbpfError>>>,
+    thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+#[allow(unused_variables)]
+fn no_jit(foo: *mut u8, foo_len: usize, bar: *mut u8, bar_len: usize,
+          nodata_offset: usize, nodata_end_offset: usize) -> u64 {
+    panic!("Error: program has not been JIT-compiled");
 }
 
 // Runs on packet data, with a metadata buffer
@@ -97,25 +409,85 @@ impl<'a> EbpfVmMbuff<'a> {
     /// ```
     pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmMbuff<'a> {
         verifier::check(prog);
+        EbpfVmMbuff::load(prog, None)
+    }
 
-        #[allow(unused_variables)]
-        fn no_jit(foo: *mut u8, foo_len: usize, bar: *mut u8, bar_len: usize,
-                  nodata_offset: usize, nodata_end_offset: usize) -> u64 {
-            panic!("Error: program has not been JIT-compiled");
-        }
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. The verifier does not run again, and the VM uses the version of the instruction
+    /// set the program was verified against. See module `program`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::program::{Program, ProgramConfig};
+    ///
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let program = Program::verify(prog, ProgramConfig::default()).unwrap();
+    ///
+    /// // Instantiate two VMs.
+    /// let vm1 = rbpf::EbpfVmMbuff::from_program(&program);
+    /// let vm2 = rbpf::EbpfVmMbuff::from_program(&program);
+    /// ```
+    pub fn from_program(program: &'a program::Program) -> EbpfVmMbuff<'a> {
+        let mut vm = EbpfVmMbuff::load(&program.bytes, Some(program));
+        vm.isa = program.config().isa;
+        vm
+    }
 
-        EbpfVmMbuff {
+    // Creates a VM running `prog`, which comes from `program` if set, without verifying it.
+    fn load(prog: &'a std::vec::Vec<u8>, program: Option<&'a program::Program>) -> EbpfVmMbuff<'a> {
+        let mut vm = EbpfVmMbuff {
             prog:    prog,
-            jit:     no_jit,
+            jit:     jit::Compiled::uncached(no_jit),
+            jit_async: OnceLock::new(),
+            layout:  MemoryLayout::Mbuff,
+            jit_threshold: None,
+            interpreted_runs: AtomicU64::new(0),
+            jit_stack: None,
+            catch_faults: false,
             helpers: HashMap::new(),
-        }
+            helper_hook: None,
+            frame_helpers: HashMap::new(),
+            fallible_helpers: HashMap::new(),
+            line_info: None,
+            insn_policy: None,
+            prog_limits: verifier::ProgLimits::default(),
+            mem_helpers: false,
+            probe_regions: vec![],
+            event_sink: None,
+            scratch: None,
+            config: HashMap::new(),
+            maps: vec![],
+            div_by_zero: ebpf::DivByZero::Error,
+            memory_poisoning: false,
+            intrinsics: helpers::Intrinsics::default(),
+            jit_harden: false,
+            jit_huge_pages: false,
+            jit_profile: None,
+            isa: ebpf::IsaVersion::default(),
+            max_call_depth: ebpf::MAX_CALL_DEPTH,
+            insn_limit: None,
+            read_only_packet: false,
+            ctx_len: None,
+            proven_accesses: vec![],
+            program,
+            meta: ProgramMeta::default(),
+        };
+        vm.mark_proven_accesses();
+        vm
     }
 
     /// Load a new eBPF program into the virtual machine instance.
     ///
     /// # Panics
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any. Panics before running the verifier
+    /// if the program exceeds the limits of the VM, see `set_prog_limits()`.
     ///
     /// # Examples
     ///
@@ -135,8 +507,40 @@ impl<'a> EbpfVmMbuff<'a> {
     /// vm.set_prog(&prog2);
     /// ```
     pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
-        verifier::check(prog);
+        if let Err(err) = verifier::check_limits(prog, &self.prog_limits) {
+            panic!("{}", err);
+        }
+        if let Err(err) = verifier::try_check_isa(prog, self.isa) {
+            panic!("{}", err);
+        }
+        if let Some(ref policy) = self.insn_policy {
+            if let Err(err) = verifier::check_policy(prog, &**policy) {
+                panic!("{}", err);
+            }
+        }
         self.prog = prog;
+        self.program = None;
+        self.jit_async = OnceLock::new();
+        *self.interpreted_runs.get_mut() = 0;
+        self.mark_proven_accesses();
+    }
+
+    // Set the size of the context passed in R1 to all executions of the program, for the VMs
+    // that know it.
+    fn set_ctx_len(&mut self, ctx_len: Option<usize>) {
+        self.ctx_len = ctx_len;
+        self.mark_proven_accesses();
+    }
+
+    // Record the loads and stores of the program that `verifier::check_bounds()` proves in bounds
+    // of the stack, or of a context of `ctx_len` bytes: the interpreter skips their runtime
+    // checks. Nothing is recorded if the analysis finds an error. Programs loaded with
+    // `from_program()` share the results of the analysis.
+    fn mark_proven_accesses(&mut self) {
+        self.proven_accesses = match self.program {
+            Some(program) => program.proven_accesses(self.ctx_len),
+            None          => program::proven_accesses(self.prog, self.ctx_len),
+        };
     }
 
     /// Register a built-in or user-defined helper function in order to use it later from within
@@ -175,1202 +579,3931 @@ impl<'a> EbpfVmMbuff<'a> {
     /// vm.register_helper(6, helpers::bpf_trace_printf);
     /// ```
     pub fn register_helper(&mut self, key: u32, function: fn (u64, u64, u64, u64, u64) -> u64) {
+        self.frame_helpers.remove(&key);
+        self.fallible_helpers.remove(&key);
         self.helpers.insert(key, function);
     }
 
-    /// Execute the program loaded, with the given packet data and metadata buffer.
-    ///
-    /// If the program is made to be compatible with Linux kernel, it is expected to load the
-    /// address of the beginning and of the end of the memory area used for packet data from the
-    /// metadata buffer, at some appointed offsets. It is up to the user to ensure that these
-    /// pointers are correctly stored in the buffer.
+    /// Register a built-in or user-defined helper function taking its arguments in a frame, in
+    /// the memory of the program: the program passes the address of the frame in R1, and the
+    /// interpreter reads the frame into the typed arguments of the helper, of type `A`. This lets
+    /// helpers take more than five arguments, or structures. It replaces any helper registered
+    /// under the same key. See `helpers::ArgFrame`.
     ///
-    /// # Panics
-    ///
-    /// This function is currently expected to panic if it encounters any error during the program
-    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
-    /// in the future (we could raise errors instead).
+    /// Frame helpers are not available to JIT-compiled programs. The helper hook attached to the
+    /// VM, if any, receives the values of registers R1 to R5 as arguments.
     ///
     /// # Examples
     ///
     /// ```
+    /// // Sums its six arguments.
+    /// fn sum6(args: [u64; 6]) -> u64 {
+    ///     args.iter().sum()
+    /// }
+    ///
+    /// // The frame is packet data, at the address in R1.
     /// let prog = vec![
-    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
-    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
-    /// ];
+    /// let mut frame: Vec<u8> = (1..7u64).flat_map(|arg| arg.to_ne_bytes().to_vec()).collect();
     ///
-    /// // Just for the example we create our metadata buffer from scratch, and we store the
-    /// // pointers to packet data start and end in it.
-    /// let mut mbuff = vec![0u8; 32];
-    /// unsafe {
-    ///     let mut data     = mbuff.as_ptr().offset(8)  as *mut u64;
-    ///     let mut data_end = mbuff.as_ptr().offset(24) as *mut u64;
-    ///     *data     = mem.as_ptr() as u64;
-    ///     *data_end = mem.as_ptr() as u64 + mem.len() as u64;
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_frame_helper(1, sum6);
+    /// assert_eq!(vm.prog_exec(&mut frame, &mut vec![]), 21);
+    /// ```
+    pub fn register_frame_helper<A: helpers::ArgFrame>(&mut self, key: u32, function: fn(A) -> u64) {
+        self.helpers.remove(&key);
+        self.fallible_helpers.remove(&key);
+        self.frame_helpers.insert(key, helpers::FrameHelper::new(function));
+    }
+
+    /// Register a user-defined helper function returning an error to abort the execution of the
+    /// program, rather than panicking. The execution then fails with `EbpfError::HelperFailed`,
+    /// holding the index of the call instruction, the key of the helper and its error. It
+    /// replaces any helper registered under the same key.
+    ///
+    /// Fallible helpers are not available to JIT-compiled programs. The helper hook attached to
+    /// the VM, if any, is not notified of the calls that failed with `after_call()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::error::{EbpfError, HelperError};
+    ///
+    /// // Divides its first argument by its second one.
+    /// fn checked_div(a: u64, b: u64, _c: u64, _d: u64, _e: u64) -> Result<u64, HelperError> {
+    ///     a.checked_div(b).ok_or_else(|| HelperError::new("division by zero in helper"))
     /// }
     ///
-    /// // Instantiate a VM.
+    /// // Divides 12 by the first byte of the packet.
+    /// let prog = vec![
+    ///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+    ///     0xb7, 0x01, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // mov64 r1, 12
+    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
     /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_fallible_helper(1, checked_div);
+    /// assert_eq!(vm.try_prog_exec(&mut [4], &mut []), Ok(3));
     ///
-    /// // Provide both a reference to the packet data, and to the metadata buffer.
-    /// let res = vm.prog_exec(&mut mem, &mut mbuff);
-    /// assert_eq!(res, 0x2211);
+    /// let err = vm.try_prog_exec(&mut [0], &mut []).unwrap_err();
+    /// assert_eq!(err, EbpfError::HelperFailed { pc: 2, key: 1, error: HelperError::new("division by zero in helper") });
+    /// assert_eq!(err.to_string(), "Error: helper function 0x1 failed: division by zero in helper (insn #2)");
     /// ```
-    pub fn prog_exec(&self, mem: &mut std::vec::Vec<u8>, mbuff: &'a mut std::vec::Vec<u8>) -> u64 {
-        const U32MAX: u64 = u32::MAX as u64;
-
-        let stack = vec![0u8;ebpf::STACK_SIZE];
-
-        // R1 points to beginning of memory area, R10 to stack
-        let mut reg: [u64;11] = [
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, stack.as_ptr() as u64 + stack.len() as u64
-        ];
-        if mbuff.len() > 0 {
-            reg[1] = mbuff.as_ptr() as u64;
-        }
-        else if mem.len() > 0 {
-            reg[1] = mem.as_ptr() as u64;
-        }
-
-        let check_mem_load = | addr: u64, len: usize, insn_ptr: usize | {
-            EbpfVmMbuff::check_mem(addr, len, "load", insn_ptr, &mbuff, &mem, &stack);
-        };
-        let check_mem_store = | addr: u64, len: usize, insn_ptr: usize | {
-            EbpfVmMbuff::check_mem(addr, len, "store", insn_ptr, &mbuff, &mem, &stack);
-        };
-
-        // Loop on instructions
-        let mut insn_ptr:usize = 0;
-        while insn_ptr * ebpf::INSN_SIZE < self.prog.len() {
-            let insn = ebpf::get_insn(self.prog, insn_ptr);
-            insn_ptr += 1;
-            let _dst    = insn.dst as usize;
-            let _src    = insn.src as usize;
-
-            match insn.opc {
-
-                // BPF_LD class
-                ebpf::LD_ABS_B   => unimplemented!(),
-                ebpf::LD_ABS_H   => unimplemented!(),
-                ebpf::LD_ABS_W   => unimplemented!(),
-                ebpf::LD_ABS_DW  => unimplemented!(),
-                ebpf::LD_IND_B   => unimplemented!(),
-                ebpf::LD_IND_H   => unimplemented!(),
-                ebpf::LD_IND_W   => unimplemented!(),
-                ebpf::LD_IND_DW  => unimplemented!(),
-
-                // BPF_LDX class
-                ebpf::LD_DW_IMM  => {
-                    let next_insn = ebpf::get_insn(self.prog, insn_ptr);
-                    insn_ptr += 1;
-                    reg[_dst] = ((insn.imm as u32) as u64) + ((next_insn.imm as u64) << 32);
-                },
-                ebpf::LD_B_REG   => reg[_dst] = unsafe {
-                    let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u8;
-                    check_mem_load(x as u64, 1, insn_ptr);
-                    *x as u64
-                },
-                ebpf::LD_H_REG   => reg[_dst] = unsafe {
-                    let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u16;
-                    check_mem_load(x as u64, 2, insn_ptr);
-                    *x as u64
-                },
-                ebpf::LD_W_REG   => reg[_dst] = unsafe {
-                    let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u32;
-                    check_mem_load(x as u64, 4, insn_ptr);
-                    *x as u64
-                },
-                ebpf::LD_DW_REG  => reg[_dst] = unsafe {
-                    let x = (reg[_src] as *const u8).offset(insn.off as isize) as *const u64;
-                    check_mem_load(x as u64, 8, insn_ptr);
-                    *x as u64
-                },
-
-                // BPF_ST class
-                ebpf::ST_B_IMM   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u8;
-                    check_mem_store(x as u64, 1, insn_ptr);
-                    *x = insn.imm as u8;
-                },
-                ebpf::ST_H_IMM   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u16;
-                    check_mem_store(x as u64, 2, insn_ptr);
-                    *x = insn.imm as u16;
-                },
-                ebpf::ST_W_IMM   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u32;
-                    check_mem_store(x as u64, 4, insn_ptr);
-                    *x = insn.imm as u32;
-                },
-                ebpf::ST_DW_IMM  => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u64;
-                    check_mem_store(x as u64, 8, insn_ptr);
-                    *x = insn.imm as u64;
-                },
-
-                // BPF_STX class
-                ebpf::ST_B_REG   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u8;
-                    check_mem_store(x as u64, 1, insn_ptr);
-                    *x = reg[_src] as u8;
-                },
-                ebpf::ST_H_REG   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u16;
-                    check_mem_store(x as u64, 2, insn_ptr);
-                    *x = reg[_src] as u16;
-                },
-                ebpf::ST_W_REG   => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u32;
-                    check_mem_store(x as u64, 4, insn_ptr);
-                    *x = reg[_src] as u32;
-                },
-                ebpf::ST_DW_REG  => unsafe {
-                    let x = (reg[_dst] as *const u8).offset(insn.off as isize) as *mut u64;
-                    check_mem_store(x as u64, 8, insn_ptr);
-                    *x = reg[_src] as u64;
-                },
-                ebpf::ST_W_XADD  => unimplemented!(),
-                ebpf::ST_DW_XADD => unimplemented!(),
-
-                // BPF_ALU class
-                // TODO Check how overflow works in kernel. Should we &= U32MAX all src register value
-                // before we do the operation?
-                // Cf ((0x11 << 32) - (0x1 << 32)) as u32 VS ((0x11 << 32) as u32 - (0x1 << 32) as u32
-                ebpf::ADD32_IMM  => reg[_dst] = (reg[_dst] as i32).wrapping_add(insn.imm)         as u64, //((reg[_dst] & U32MAX) + insn.imm  as u64)     & U32MAX,
-                ebpf::ADD32_REG  => reg[_dst] = (reg[_dst] as i32).wrapping_add(reg[_src] as i32) as u64, //((reg[_dst] & U32MAX) + (reg[_src] & U32MAX)) & U32MAX,
-                ebpf::SUB32_IMM  => reg[_dst] = (reg[_dst] as i32).wrapping_sub(insn.imm)         as u64,
-                ebpf::SUB32_REG  => reg[_dst] = (reg[_dst] as i32).wrapping_sub(reg[_src] as i32) as u64,
-                ebpf::MUL32_IMM  => reg[_dst] = (reg[_dst] as i32).wrapping_mul(insn.imm)         as u64,
-                ebpf::MUL32_REG  => reg[_dst] = (reg[_dst] as i32).wrapping_mul(reg[_src] as i32) as u64,
-                ebpf::DIV32_IMM  => reg[_dst] = (reg[_dst] as u32 / insn.imm              as u32) as u64,
-                ebpf::DIV32_REG  => {
-                    if reg[_src] == 0 {
-                        panic!("Error: division by 0");
-                    }
-                    reg[_dst] = (reg[_dst] as u32 / reg[_src] as u32) as u64;
-                },
-                ebpf::OR32_IMM   =>   reg[_dst] = (reg[_dst] as u32             | insn.imm  as u32) as u64,
-                ebpf::OR32_REG   =>   reg[_dst] = (reg[_dst] as u32             | reg[_src] as u32) as u64,
-                ebpf::AND32_IMM  =>   reg[_dst] = (reg[_dst] as u32             & insn.imm  as u32) as u64,
-                ebpf::AND32_REG  =>   reg[_dst] = (reg[_dst] as u32             & reg[_src] as u32) as u64,
-                ebpf::LSH32_IMM  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shl(insn.imm  as u32) as u64,
-                ebpf::LSH32_REG  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shl(reg[_src] as u32) as u64,
-                ebpf::RSH32_IMM  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shr(insn.imm  as u32) as u64,
-                ebpf::RSH32_REG  =>   reg[_dst] = (reg[_dst] as u32).wrapping_shr(reg[_src] as u32) as u64,
-                ebpf::NEG32      => { reg[_dst] = (reg[_dst] as i32).wrapping_neg()                 as u64; reg[_dst] &= U32MAX; },
-                ebpf::MOD32_IMM  =>   reg[_dst] = (reg[_dst] as u32             % insn.imm  as u32) as u64,
-                ebpf::MOD32_REG  => {
-                    if reg[_src] == 0 {
-                        panic!("Error: division by 0");
-                    }
-                    reg[_dst] = (reg[_dst] as u32 % reg[_src] as u32) as u64;
-                },
-                ebpf::XOR32_IMM  =>   reg[_dst] = (reg[_dst] as u32             ^ insn.imm  as u32) as u64,
-                ebpf::XOR32_REG  =>   reg[_dst] = (reg[_dst] as u32             ^ reg[_src] as u32) as u64,
-                ebpf::MOV32_IMM  =>   reg[_dst] = insn.imm                                          as u64,
-                ebpf::MOV32_REG  =>   reg[_dst] = (reg[_src] as u32)                                as u64,
-                ebpf::ARSH32_IMM => { reg[_dst] = (reg[_dst] as i32).wrapping_shr(insn.imm  as u32) as u64; reg[_dst] &= U32MAX; },
-                ebpf::ARSH32_REG => { reg[_dst] = (reg[_dst] as i32).wrapping_shr(reg[_src] as u32) as u64; reg[_dst] &= U32MAX; },
-                ebpf::LE         => {
-                    reg[_dst] = match insn.imm {
-                        16 => (reg[_dst] as u16).to_le() as u64,
-                        32 => (reg[_dst] as u32).to_le() as u64,
-                        64 =>  reg[_dst].to_le(),
-                        _  => unreachable!(),
-                    };
-                },
-                ebpf::BE         => {
-                    reg[_dst] = match insn.imm {
-                        16 => (reg[_dst] as u16).to_be() as u64,
-                        32 => (reg[_dst] as u32).to_be() as u64,
-                        64 =>  reg[_dst].to_be(),
-                        _  => unreachable!(),
-                    };
-                },
-
-                // BPF_ALU64 class
-                ebpf::ADD64_IMM  => reg[_dst] = reg[_dst].wrapping_add(insn.imm as u64),
-                ebpf::ADD64_REG  => reg[_dst] = reg[_dst].wrapping_add(reg[_src]),
-                ebpf::SUB64_IMM  => reg[_dst] = reg[_dst].wrapping_sub(insn.imm as u64),
-                ebpf::SUB64_REG  => reg[_dst] = reg[_dst].wrapping_sub(reg[_src]),
-                ebpf::MUL64_IMM  => reg[_dst] = reg[_dst].wrapping_mul(insn.imm as u64),
-                ebpf::MUL64_REG  => reg[_dst] = reg[_dst].wrapping_mul(reg[_src]),
-                ebpf::DIV64_IMM  => reg[_dst]                       /= insn.imm as u64,
-                ebpf::DIV64_REG  => {
-                    if reg[_src] == 0 {
-                        panic!("Error: division by 0");
-                    }
-                    reg[_dst] /= reg[_src];
-                },
-                ebpf::OR64_IMM   => reg[_dst] |=  insn.imm as u64,
-                ebpf::OR64_REG   => reg[_dst] |=  reg[_src],
-                ebpf::AND64_IMM  => reg[_dst] &=  insn.imm as u64,
-                ebpf::AND64_REG  => reg[_dst] &=  reg[_src],
-                ebpf::LSH64_IMM  => reg[_dst] <<= insn.imm as u64,
-                ebpf::LSH64_REG  => reg[_dst] <<= reg[_src],
-                ebpf::RSH64_IMM  => reg[_dst] >>= insn.imm as u64,
-                ebpf::RSH64_REG  => reg[_dst] >>= reg[_src],
-                ebpf::NEG64      => reg[_dst] = -(reg[_dst] as i64) as u64,
-                ebpf::MOD64_IMM  => reg[_dst] %=  insn.imm as u64,
-                ebpf::MOD64_REG  => {
-                    if reg[_src] == 0 {
-                        panic!("Error: division by 0");
-                    }
-                    reg[_dst] %= reg[_src];
-                },
-                ebpf::XOR64_IMM  => reg[_dst] ^= insn.imm  as u64,
-                ebpf::XOR64_REG  => reg[_dst] ^= reg[_src],
-                ebpf::MOV64_IMM  => reg[_dst] =  insn.imm  as u64,
-                ebpf::MOV64_REG  => reg[_dst] =  reg[_src],
-                ebpf::ARSH64_IMM => reg[_dst] = (reg[_dst] as i64 >> insn.imm)  as u64,
-                ebpf::ARSH64_REG => reg[_dst] = (reg[_dst] as i64 >> reg[_src]) as u64,
-
-                // BPF_JMP class
-                // TODO: check this actually works as expected for signed / unsigned ops
-                ebpf::JA         =>                                           insn_ptr = (insn_ptr as i16 + insn.off) as usize,
-                ebpf::JEQ_IMM    => if reg[_dst] == insn.imm as u64         { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JEQ_REG    => if reg[_dst] == reg[_src]               { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JGT_IMM    => if reg[_dst] >  insn.imm as u64         { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JGT_REG    => if reg[_dst] >  reg[_src]               { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JGE_IMM    => if reg[_dst] >= insn.imm as u64         { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JGE_REG    => if reg[_dst] >= reg[_src]               { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSET_IMM   => if reg[_dst] &  insn.imm as u64 != 0    { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSET_REG   => if reg[_dst] &  reg[_src]       != 0    { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JNE_IMM    => if reg[_dst] != insn.imm as u64         { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JNE_REG    => if reg[_dst] != reg[_src]               { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSGT_IMM   => if reg[_dst] as i64 >  insn.imm  as i64 { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSGT_REG   => if reg[_dst] as i64 >  reg[_src] as i64 { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSGE_IMM   => if reg[_dst] as i64 >= insn.imm  as i64 { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                ebpf::JSGE_REG   => if reg[_dst] as i64 >= reg[_src] as i64 { insn_ptr = (insn_ptr as i16 + insn.off) as usize; },
-                // Do not delegate the check to the verifier, since registered functions can be
-                // changed after the program has been verified.
-                ebpf::CALL       => if let Some(function) = self.helpers.get(&(insn.imm as u32)) {
-                    reg[0] = function(reg[1], reg[2], reg[3], reg[4], reg[5]);
-                } else {
-                    panic!("Error: unknown helper function (id: {:#x})", insn.imm as u32);
-                },
-                ebpf::TAIL_CALL  => unimplemented!(),
-                ebpf::EXIT       => return reg[0],
-
-                _                => unreachable!()
-            }
-        }
-
-        return 0;
+    pub fn register_fallible_helper(&mut self, key: u32, function: ebpf::FallibleHelper) {
+        self.helpers.remove(&key);
+        self.frame_helpers.remove(&key);
+        self.fallible_helpers.insert(key, function);
     }
 
-    fn check_mem(addr: u64, len: usize, access_type: &str, insn_ptr: usize,
-                 mbuff: &std::vec::Vec<u8>, mem: &std::vec::Vec<u8>, stack: &std::vec::Vec<u8>) {
-        if mbuff.as_ptr() as u64 <= addr && addr + len as u64 <= mbuff.as_ptr() as u64 + mbuff.len() as u64 {
-            return
-        }
-        if mem.as_ptr() as u64 <= addr && addr + len as u64 <= mem.as_ptr() as u64 + mem.len() as u64 {
-            return
-        }
-        if stack.as_ptr() as u64 <= addr && addr + len as u64 <= stack.as_ptr() as u64 + stack.len() as u64 {
-            return
-        }
-
-        panic!(
-            "Error: out of bounds memory {} (insn #{:?}), addr {:#x}, size {:?}\nmbuff: {:#x}/{:#x}, mem: {:#x}/{:#x}, stack: {:#x}/{:#x}",
-            access_type, insn_ptr, addr, len,
-            mbuff.as_ptr() as u64, mbuff.len(),
-            mem.as_ptr() as u64, mem.len(),
-            stack.as_ptr() as u64, stack.len()
-        );
+    /// Make the bounds-checked memory helpers `memcpy()`, `memset()` and `memcmp()` available to
+    /// the program, under keys `helpers::MEMCPY_IDX`, `helpers::MEMSET_IDX` and
+    /// `helpers::MEMCMP_IDX`, as well as helpers `bpf_strtol()` and `bpf_strtoul()` (see
+    /// `helpers::BPF_STRTOL_IDX`) and `bpf_csum_diff()` (see `helpers::BPF_CSUM_DIFF_IDX`). These
+    /// helpers are run by the interpreter, which checks that the
+    /// memory they access belongs to the packet data, the metadata buffer or the stack, and panics
+    /// otherwise. They take precedence over helpers registered with the same keys, and are not
+    /// available to JIT-compiled programs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // Copies the first four bytes of packet data to the stack, and returns them.
+    /// let prog = vec![
+    ///     0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+    ///     0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+    ///     0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+    ///     0xbf, 0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r6
+    ///     0xb7, 0x03, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r3, 4
+    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x7f, // call memcpy
+    ///     0x61, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r10-8]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![0x11, 0x22, 0x33, 0x44];
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_mem_helpers();
+    ///
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut vec![]), 0x44332211);
+    /// ```
+    pub fn register_mem_helpers(&mut self) {
+        self.mem_helpers = true;
     }
 
-    /// JIT-compile the loaded program. No argument required for this.
+    /// Register a region of memory readable by the program with helpers `bpf_probe_read()`,
+    /// `bpf_probe_read_user()` and `bpf_probe_read_kernel()`, at address `addr` in address space
+    /// `space`: the helpers read `data` when passed addresses starting from `addr`. This makes it
+    /// possible to test tracing programs with snapshots of user or kernel memory.
     ///
-    /// If using helper functions, be sure to register them into the VM before calling this
-    /// function.
+    /// Once a region is registered, the interpreter runs these helpers itself, and they only read
+    /// from registered regions and from the memory of the program. They return an error instead
+    /// of faulting on other addresses. See `helpers::BPF_PROBE_READ_IDX`. They are not available
+    /// to JIT-compiled programs.
     ///
-    /// # Panics
+    /// # Examples
     ///
-    /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
-    /// unknown eBPF operation code.
+    /// ```
+    /// use rbpf::helpers::{self, AddressSpace};
+    ///
+    /// // Reads a 64-bit value at the address found in the first 8 bytes of packet data, and
+    /// // returns it, or 0 if the value cannot be read.
+    /// let prog = vec![
+    ///     0x79, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r3, [r1]
+    ///     0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+    ///     0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+    ///     0xb7, 0x02, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov64 r2, 8
+    ///     0x85, 0x00, 0x00, 0x00, 0x71, 0x00, 0x00, 0x00, // call bpf_probe_read_kernel
+    ///     0x79, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-8]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let snapshot = vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_probe_region(AddressSpace::Kernel, 0xffff_8880_0000_1000, &snapshot);
+    ///
+    /// let mut mem = 0xffff_8880_0000_1000u64.to_le_bytes().to_vec();
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut vec![]), 0x8877665544332211);
+    ///
+    /// let mut mem = 0xffff_8880_0000_1004u64.to_le_bytes().to_vec();
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut vec![]), 0);
+    /// ```
+    pub fn register_probe_region(&mut self, space: helpers::AddressSpace, addr: u64, data: &'a [u8]) {
+        self.probe_regions.push((space, addr, data));
+    }
+
+    /// Attach a consumer to the VM, receiving the events output by the program with helper
+    /// `bpf_perf_event_output()`. It replaces any consumer previously attached. Once a consumer
+    /// is attached, the interpreter runs this helper itself, see
+    /// `helpers::BPF_PERF_EVENT_OUTPUT_IDX`. It is not available to JIT-compiled programs.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::sync::mpsc;
+    ///
+    /// // Outputs the first four bytes of packet data as an event.
     /// let prog = vec![
-    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
-    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0xbf, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r1
+    ///     0xb7, 0x05, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r5, 4
+    ///     0x85, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, // call bpf_perf_event_output
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
+    /// let mut mem = vec![0x11, 0x22, 0x33, 0x44];
     ///
-    /// // Instantiate a VM.
+    /// let (sender, receiver) = mpsc::channel();
     /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_event_sink(Box::new(sender));
     ///
-    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut vec![]), 0);
+    /// assert_eq!(receiver.try_recv(), Ok(vec![0x11, 0x22, 0x33, 0x44]));
     /// ```
-    pub fn jit_compile(&mut self) {
-        self.jit = jit::compile(&self.prog, &self.helpers, true, false);
+    pub fn set_event_sink(&mut self, sink: Box<dyn helpers::EventSink + 'a>) {
+        self.event_sink = Some(sink);
     }
 
-    /// Execute the previously JIT-compiled program, with the given packet data and metadata
-    /// buffer, in a manner very similar to `prog_exec()`.
+    /// Give the VM a scratch storage of `size` bytes, initialized with zeroes, that the program
+    /// may use to keep data from one execution to the next. The program gets the address of the
+    /// region from helper `get_scratch()`, run by the interpreter, see
+    /// `helpers::GET_SCRATCH_IDX`, and then loads from and stores to it as to the other areas of
+    /// memory available to it. It replaces any region previously set; a size of 0 removes it.
+    /// The region is not available to JIT-compiled programs.
     ///
-    /// If the program is made to be compatible with Linux kernel, it is expected to load the
-    /// address of the beginning and of the end of the memory area used for packet data from the
-    /// metadata buffer, at some appointed offsets. It is up to the user to ensure that these
-    /// pointers are correctly stored in the buffer.
+    /// # Examples
     ///
-    /// # Panics
+    /// ```
+    /// // Increments a counter kept in the scratch storage, and returns its new value.
+    /// let prog = vec![
+    ///     0x85, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x7f, // call get_scratch
+    ///     0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+    ///     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+    ///     0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
     ///
-    /// This function panics if an error occurs during the execution of the program.
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_scratch(8);
     ///
-    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
-    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
-    /// very bad (program may segfault). It may be wise to check that the program works with the
-    /// interpreter before running the JIT-compiled version of it.
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 1);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 2);
+    /// assert_eq!(vm.scratch_mut().unwrap(), &[2, 0, 0, 0, 0, 0, 0, 0]);
+    /// ```
+    pub fn set_scratch(&mut self, size: usize) {
+        self.scratch = match size {
+            0 => None,
+            _ => Some(RefCell::new(vec![0; size])),
+        };
+    }
+
+    /// The contents of the scratch storage of the VM, if any, to be read or changed between
+    /// executions of the program. See `set_scratch()`.
+    pub fn scratch_mut(&mut self) -> Option<&mut [u8]> {
+        self.scratch.as_mut().map(|region| &mut region.get_mut()[..])
+    }
+
+    /// Give the VM a configuration store, mapping keys to values, that the program reads to
+    /// adapt its behavior without the host having to set up maps. The program gets the address
+    /// of the value of a key from helper `config_lookup()`, and its length from helper
+    /// `config_value_len()`, both run by the interpreter, see `helpers::CONFIG_LOOKUP_IDX`. The
+    /// program may load from the values, but not store to them. It replaces any store previously
+    /// set; an empty store removes it. The store is not available to JIT-compiled programs.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::collections::HashMap;
+    ///
+    /// // Returns the first byte of the value of key "ttl", or 0 if there is no such key.
     /// let prog = vec![
-    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into r1.
-    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x62, 0x0a, 0xfc, 0xff, 0x74, 0x74, 0x6c, 0x00, // stw [r10-4], "ttl"
+    ///     0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+    ///     0x07, 0x01, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r1, -4
+    ///     0xb7, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r2, 4
+    ///     0x85, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x7f, // call config_lookup
+    ///     0x15, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +1
+    ///     0x71, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r0]
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
-    /// ];
     ///
-    /// // Just for the example we create our metadata buffer from scratch, and we store the
-    /// // pointers to packet data start and end in it.
-    /// let mut mbuff = vec![0u8; 32];
-    /// unsafe {
-    ///     let mut data     = mbuff.as_ptr().offset(8)  as *mut u64;
-    ///     let mut data_end = mbuff.as_ptr().offset(24) as *mut u64;
-    ///     *data     = mem.as_ptr() as u64;
-    ///     *data_end = mem.as_ptr() as u64 + mem.len() as u64;
-    /// }
+    /// let mut config = HashMap::new();
+    /// config.insert("ttl".to_string(), vec![64]);
     ///
-    /// // Instantiate a VM.
     /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_config(config);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 64);
     ///
-    /// vm.jit_compile();
+    /// vm.config_mut().insert("ttl".to_string(), vec![32]);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 32);
     ///
-    /// // Provide both a reference to the packet data, and to the metadata buffer.
-    /// let res = vm.prog_exec_jit(&mut mem, &mut mbuff);
-    /// assert_eq!(res, 0x2211);
+    /// vm.config_mut().remove("ttl");
+    /// vm.config_mut().insert("hops".to_string(), vec![8]);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 0);
     /// ```
-    pub fn prog_exec_jit(&self, mem: &mut std::vec::Vec<u8>, mbuff: &'a mut std::vec::Vec<u8>) -> u64 {
-        // If packet data is empty, do not send the address of an empty vector; send a null
-        // pointer (zero value) as first argument instead, as this is uBPF's behavior (empty
-        // packet should not happen in the kernel; anyway the verifier would prevent the use of
-        // uninitialized registers). See `mul_loop` test.
-        let mem_ptr = match mem.len() {
-            0 => 0 as *mut u8,
-            _ => mem.as_ptr() as *mut u8
-        };
-        // The last two arguments are not used in this function. They would be used if there was a
-        // need to indicate to the JIT at which offset in the mbuff mem_ptr and mem_ptr + mem.len()
-        // should be stored; this is what happens with struct EbpfVmFixedMbuff.
-        (self.jit)(mbuff.as_ptr() as *mut u8, mbuff.len(), mem_ptr, mem.len(), 0, 0)
+    pub fn set_config(&mut self, config: HashMap<String, Vec<u8>>) {
+        self.config = config;
     }
-}
 
-/// A virtual machine to run eBPF program. This kind of VM is used for programs expecting to work
-/// on a metadata buffer containing pointers to packet data, but it internally handles the buffer
-/// so as to save the effort to manually handle the metadata buffer for the user.
-///
-/// This struct implements a static internal buffer that is passed to the program. The user has to
-/// indicate the offset values at which the eBPF program expects to find the start and the end of
-/// packet data in the buffer. On calling the `prog_exec()` or `prog_exec_jit()` functions, the
-/// struct automatically updates the addresses in this static buffer, at the appointed offsets, for
-/// the start and the end of the packet data the program is called upon.
-///
-/// # Examples
-///
-/// This was compiled with clang from the following program, in C:
-///
-/// ```c
-/// #include <linux/bpf.h>
-/// #include "path/to/linux/samples/bpf/bpf_helpers.h"
-///
-/// SEC(".classifier")
-/// int classifier(struct __sk_buff *skb)
-/// {
-///   void *data = (void *)(long)skb->data;
-///   void *data_end = (void *)(long)skb->data_end;
-///
-///   // Check program is long enough.
-///   if (data + 5 > data_end)
-///     return 0;
-///
-///   return *((char *)data + 5);
-/// }
-/// ```
-///
-/// Some small modifications have been brought to have it work, see comments.
-///
-/// ```
-/// let prog = vec![
-///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-///     // Here opcode 0x61 had to be replace by 0x79 so as to load a 8-bytes long address.
-///     // Also, offset 0x4c had to be replace with e.g. 0x40 so as to prevent the two pointers
-///     // from overlapping in the buffer.
-///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load pointer to mem from r1[0x40] to r2
-///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-///     // Here opcode 0x61 had to be replace by 0x79 so as to load a 8-bytes long address.
-///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load ptr to mem_end from r1[0x50] to r1
-///     0x2d, 0x12, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
-///     0x67, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, // r0 >>= 56
-///     0xc7, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, // r0 <<= 56 (arsh) extend byte sign to u64
-///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-/// ];
-/// let mut mem1 = vec![
-///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
-/// ];
-/// let mut mem2 = vec![
-///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
-/// ];
-///
-/// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
-/// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
-///
-/// // Provide only a reference to the packet data. We do not manage the metadata buffer.
-/// let res = vm.prog_exec(&mut mem1);
-/// assert_eq!(res, 0xffffffffffffffdd);
-///
-/// let res = vm.prog_exec(&mut mem2);
-/// assert_eq!(res, 0x27);
-/// ```
-pub struct EbpfVmFixedMbuff<'a> {
-    parent: EbpfVmMbuff<'a>,
-    mbuff:  MetaBuff,
-}
+    /// The configuration store of the VM, to be read or changed between executions of the
+    /// program. See `set_config()`.
+    pub fn config_mut(&mut self) -> &mut HashMap<String, Vec<u8>> {
+        &mut self.config
+    }
 
-impl<'a> EbpfVmFixedMbuff<'a> {
+    /// Register a map, that the program accesses with the map helpers, run by the interpreter,
+    /// see `helpers::BPF_MAP_LOOKUP_ELEM_IDX`. Returns the identifier of the map, to be passed
+    /// by the program to the map helpers: maps are numbered from 0 in the order they are
+    /// registered. Maps can be shared by several VMs, possibly on different threads, see module
+    /// `maps`. JIT-compiled programs only support lookups in array maps, inlined by the
+    /// JIT-compiler when the map is known at compile time: register the maps before compiling.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rbpf::maps::{self, Map, MapType};
+    ///
+    /// // Stores the 32-bit value at the start of packet data under the key that follows it.
+    /// let prog = vec![
+    ///     0xbf, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r1 (value)
+    ///     0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+    ///     0x07, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // add64 r2, 4 (key)
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+    ///     0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, 0 (BPF_ANY)
+    ///     0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call bpf_map_update_elem
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![0x11, 0x22, 0x33, 0x44, 0xaa, 0xbb];
+    ///
+    /// let map = Arc::new(Map::new(MapType::Hash, 2, 4, 16));
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// assert_eq!(vm.register_map(map.clone()), 0);
+    ///
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut vec![]), 0);
+    /// assert_eq!(map.lookup(&[0xaa, 0xbb]), Some(vec![0x11, 0x22, 0x33, 0x44]));
+    /// assert_eq!(map.update(&[0xaa, 0xbb], &[0; 4], maps::BPF_NOEXIST), Err(rbpf::helpers::EEXIST));
+    /// ```
+    pub fn register_map(&mut self, map: Arc<maps::Map>) -> u32 {
+        self.maps.push(map);
+        (self.maps.len() - 1) as u32
+    }
 
-    /// Create a new virtual machine instance, and load an eBPF program into that instance.
-    /// When attempting to load the program, it passes through a simple verifier.
+    /// Return the maps registered on the VM, indexed by their identifiers, for the application
+    /// to read the entries maintained by the program, see `maps::Map::iter()`.
     ///
-    /// # Panics
+    /// # Examples
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// ```
+    /// use std::sync::Arc;
+    /// use rbpf::maps::{Map, MapType};
+    ///
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// let id = vm.register_map(Arc::new(Map::new(MapType::Array, 4, 8, 16)));
+    /// assert_eq!(vm.maps()[id as usize].iter().count(), 16);
+    /// ```
+    pub fn maps(&self) -> &[Arc<maps::Map>] {
+        &self.maps
+    }
+
+    /// Report the resources used by the VM: the sizes of the program, of its JIT-compiled code
+    /// and of its stack, and the memory attached to the VM with `set_scratch()`,
+    /// `register_probe_region()` and `register_map()`. Maps shared between VMs are counted in
+    /// full by each of them.
     ///
     /// # Examples
     ///
     /// ```
+    /// use std::sync::Arc;
+    /// use rbpf::maps::{Map, MapType};
+    ///
     /// let prog = vec![
     ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_scratch(64);
+    /// vm.register_map(Arc::new(Map::new(MapType::Array, 4, 8, 16)));
+    ///
+    /// let stats = vm.stats();
+    /// assert_eq!(stats.prog_size, 16);
+    /// assert_eq!(stats.jit_code_size, None);
+    /// assert_eq!(stats.stack_size, 512);
+    /// assert_eq!(stats.scratch_size, 64);
+    /// assert_eq!((stats.maps, stats.map_memory), (1, 128));
     /// ```
-    pub fn new(prog: &'a std::vec::Vec<u8>, data_offset: usize, data_end_offset: usize) -> EbpfVmFixedMbuff<'a> {
-        let parent = EbpfVmMbuff::new(prog);
-        let get_buff_len = | x: usize, y: usize | if x >= y { x + 8 } else { y + 8 };
-        let buffer = vec![0u8; get_buff_len(data_offset, data_end_offset)];
-        let mbuff = MetaBuff {
-            data_offset:     data_offset,
-            data_end_offset: data_end_offset,
-            buffer:          buffer,
+    pub fn stats(&self) -> VmStats {
+        let async_jit = self.jit_async.get().and_then(|jit| jit.result.get());
+        let jit = match async_jit {
+            Some(&Ok(compiled)) => compiled,
+            _                   => self.jit,
         };
-        EbpfVmFixedMbuff {
-            parent: parent,
-            mbuff:  mbuff,
+        VmStats {
+            prog_size:          self.prog.len(),
+            jit_code_size:      jit::code_size(jit),
+            jit_code_shared:    jit::code_shared(jit),
+            stack_size:         ebpf::STACK_SIZE,
+            mbuff_size:         0,
+            scratch_size:       self.scratch.as_ref().map_or(0, |region| region.borrow().len()),
+            probe_regions:      self.probe_regions.len(),
+            probe_regions_size: self.probe_regions.iter().map(|&(_, _, data)| data.len()).sum(),
+            maps:               self.maps.len(),
+            map_memory:         self.maps.iter().map(|map| map.memory_usage()).sum(),
         }
     }
 
-    /// Load a new eBPF program into the virtual machine instance.
+    /// Name the program of the VM, for example with the identifier of the customer who supplied
+    /// it. Errors then carry the name, see `ProgramMeta`.
     ///
-    /// At the same time, load new offsets for storing pointers to start and end of packet data in
-    /// the internal metadata buffer.
+    /// # Examples
     ///
-    /// # Panics
+    /// ```
+    /// use rbpf::error::EbpfError;
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+    ///     0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_name("filter-42");
+    /// vm.set_tag("customer", "acme");
+    ///
+    /// let err = vm.try_prog_exec(&mut [], &mut []).unwrap_err();
+    /// assert_eq!(err.to_string(), "[filter-42 customer=acme] Error: division by 0 (insn #2)");
+    /// match err {
+    ///     EbpfError::InProgram { program, error } => {
+    ///         assert_eq!(program.tag("customer"), Some("acme"));
+    ///         assert_eq!(*error, EbpfError::DivideByZero { pc: 2 });
+    ///     },
+    ///     _ => panic!("unexpected error"),
+    /// }
+    /// ```
+    pub fn set_name(&mut self, name: &str) {
+        self.meta.name = name.to_string();
+    }
+
+    /// Attach tag `key` with `value` to the program of the VM, replacing the previous value of
+    /// the tag. See `set_name()`.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.meta.set_tag(key, value);
+    }
+
+    /// The name and tags attached to the VM.
+    pub fn meta(&self) -> &ProgramMeta {
+        &self.meta
+    }
+
+    /// Attach a hook to the VM, invoked by the interpreter on every call to a helper function with
+    /// the key of the helper and its arguments, and then with its return value. The hook can log
+    /// the calls, or veto them. It replaces any hook previously attached. See
+    /// `helpers::HelperHook`.
+    ///
+    /// Since JIT-compiled programs call helpers directly, the VM refuses to run a JIT-compiled
+    /// program once a hook is attached.
     ///
     /// # Examples
     ///
     /// ```
-    /// let prog1 = vec![
-    ///     0xb7, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-    /// ];
-    /// let prog2 = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
-    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-    /// ];
+    /// use rbpf::helpers::{HelperHook, HookVerdict};
+    ///
+    /// // Only allows calls to helper 1.
+    /// struct OnlyHelper1;
+    /// impl HelperHook for OnlyHelper1 {
+    ///     fn before_call(&self, key: u32, _args: &[u64; 5]) -> HookVerdict {
+    ///         if key == 1 { HookVerdict::Allow } else { HookVerdict::Deny }
+    ///     }
+    /// }
     ///
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27,
+    /// let prog = vec![
+    ///     0xb7, 0x01, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // mov r1, 9
+    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call helper with key 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog1, 0, 0);
-    /// vm.set_prog(&prog2, 0x40, 0x50);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_helper(1, rbpf::helpers::sqrti);
+    /// vm.set_helper_hook(Box::new(OnlyHelper1));
     ///
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 0x27);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 3);
     /// ```
-    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>, data_offset: usize, data_end_offset: usize) {
-        let get_buff_len = | x: usize, y: usize | if x >= y { x + 8 } else { y + 8 };
-        let buffer = vec![0u8; get_buff_len(data_offset, data_end_offset)];
-        self.mbuff.buffer = buffer;
-        self.mbuff.data_offset = data_offset;
-        self.mbuff.data_end_offset = data_end_offset;
-        self.parent.set_prog(prog)
+    pub fn set_helper_hook(&mut self, hook: Box<dyn helpers::HelperHook + 'a>) {
+        self.helper_hook = Some(hook);
     }
 
-    /// Register a built-in or user-defined helper function in order to use it later from within
-    /// the eBPF program. The helper is registered into a hashmap, so the `key` can be any `u32`.
+    /// Attach an instruction policy to the VM, restricting the instructions allowed in programs.
+    /// The policy checks the program currently loaded, and then every program loaded with
+    /// `set_prog()`. It replaces any policy previously attached. See `verifier::InsnPolicy`.
     ///
-    /// If using JIT-compiled eBPF programs, be sure to register all helpers before compiling the
-    /// program. You should be able to change registered helpers after compiling, but not to add
-    /// new ones (i.e. with new keys).
+    /// # Panics
+    ///
+    /// Panics if the policy rejects the program currently loaded.
     ///
     /// # Examples
     ///
-    /// ```
-    /// use rbpf::helpers;
+    /// ```should_panic
+    /// use rbpf::ebpf;
+    /// use rbpf::verifier::DenyList;
     ///
-    /// // This program was compiled with clang, from a C program containing the following single
-    /// // instruction: `return bpf_trace_printk("foo %c %c %c\n", 10, 1, 2, 3);`
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 6 instructions
-    ///     0x71, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r1
-    ///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r2, 0
-    ///     0xb7, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r3, 0
-    ///     0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r4, 0
-    ///     0xb7, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r5, 0
+    ///     0xb7, 0x01, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // mov r1, 9
     ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call helper with key 1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x09,
-    /// ];
-    ///
-    /// // Instantiate a VM.
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
-    ///
-    /// // Register a helper. This helper will store the result of the square root of r1 into r0.
-    /// vm.register_helper(1, helpers::sqrti);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
     ///
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 3);
+    /// // Panics with "[Verifier] Error: opcode 0x85 denied by policy (insn #1)".
+    /// vm.set_insn_policy(Box::new(DenyList::new().deny_opcode(ebpf::CALL)));
     /// ```
-    pub fn register_helper(&mut self, key: u32, function: fn (u64, u64, u64, u64, u64) -> u64) {
-        self.parent.register_helper(key, function);
+    pub fn set_insn_policy(&mut self, policy: Box<dyn verifier::InsnPolicy + 'a>) {
+        if let Err(err) = verifier::check_policy(self.prog, &*policy) {
+            panic!("{}", err);
+        }
+        self.insn_policy = Some(policy);
     }
 
-    /// Execute the program loaded, with the given packet data.
-    ///
-    /// If the program is made to be compatible with Linux kernel, it is expected to load the
-    /// address of the beginning and of the end of the memory area used for packet data from some
-    /// metadata buffer, which in the case of this VM is handled internally. The offsets at which
-    /// the addresses should be placed should have be set at the creation of the VM.
+    /// Set the limits on the size and estimated verification complexity of the programs loaded
+    /// with `set_prog()`, checked before running the verifier. By default, programs have at
+    /// most `ebpf::PROG_MAX_INSNS` instructions and a complexity of at most
+    /// `ebpf::MAX_COMPLEXITY`. See `verifier::ProgLimits`.
     ///
     /// # Panics
     ///
-    /// This function is currently expected to panic if it encounters any error during the program
-    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
-    /// in the future (we could raise errors instead).
+    /// Panics if the program currently loaded exceeds the limits.
     ///
     /// # Examples
     ///
-    /// ```
-    /// let prog = vec![
+    /// ```should_panic
+    /// use rbpf::verifier::ProgLimits;
+    ///
+    /// let prog1 = vec![
     ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// let prog2 = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog1);
+    /// vm.set_prog_limits(ProgLimits { max_insns: 2, ..ProgLimits::default() });
     ///
-    /// // Provide only a reference to the packet data. We do not manage the metadata buffer.
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 0xdd);
+    /// // Panics with "[Verifier] Error: program of 3 instructions exceeds the limit of 2
+    /// // instructions".
+    /// vm.set_prog(&prog2);
     /// ```
-    pub fn prog_exec(&mut self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
-        let l = self.mbuff.buffer.len();
-        // Can this ever happen? Probably not, should be ensured at mbuff creation.
-        if self.mbuff.data_offset + 8 > l || self.mbuff.data_end_offset + 8 > l {
-            panic!("Error: buffer too small ({:?}), cannot use data_offset {:?} and data_end_offset {:?}",
-            l, self.mbuff.data_offset, self.mbuff.data_end_offset);
+    pub fn set_prog_limits(&mut self, limits: verifier::ProgLimits) {
+        if let Err(err) = verifier::check_limits(self.prog, &limits) {
+            panic!("{}", err);
         }
-        unsafe {
-            let mut data     = self.mbuff.buffer.as_ptr().offset(self.mbuff.data_offset as isize)     as *mut u64;
-            let mut data_end = self.mbuff.buffer.as_ptr().offset(self.mbuff.data_end_offset as isize) as *mut u64;
-            *data     = mem.as_ptr() as u64;
-            *data_end = mem.as_ptr() as u64 + mem.len() as u64;
-        }
-        self.parent.prog_exec(mem, &mut self.mbuff.buffer)
+        self.prog_limits = limits;
     }
 
-    /// JIT-compile the loaded program. No argument required for this.
+    /// Attach line information to the VM, mapping the instructions of the loaded program to
+    /// source locations. When the interpreter encounters an error, the message then reports the
+    /// source location of the faulty instruction in addition to its number. See
+    /// `btf::LineInfo`.
     ///
-    /// If using helper functions, be sure to register them into the VM before calling this
-    /// function.
+    /// The line information is kept when loading a new program with `set_prog()`: it should be
+    /// replaced as well.
     ///
-    /// # Panics
+    /// # Examples
     ///
-    /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
-    /// unknown eBPF operation code.
+    /// ```should_panic
+    /// use rbpf::btf::{LineInfo, SourceLocation};
+    ///
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+    ///     0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // Usually obtained with `LineInfo::parse()`.
+    /// let mut info = LineInfo::new();
+    /// info.insert(2, SourceLocation { file: "filter.c".to_string(), line: 42, col: 0 });
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_line_info(info);
+    ///
+    /// // Panics with "Error: division by 0 (insn #2 at filter.c:42)".
+    /// vm.prog_exec(&mut vec![], &mut vec![]);
+    /// ```
+    pub fn set_line_info(&mut self, info: btf::LineInfo) {
+        self.line_info = Some(info);
+    }
+
+    /// Select the semantics of divisions and modulos by zero, see `ebpf::DivByZero`. By default,
+    /// they abort the program.
+    ///
+    /// The JIT-compiler uses the semantics selected when compiling: this function should be called
+    /// before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use rbpf::ebpf::DivByZero;
+    ///
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0xb7, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov r0, 7
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+    ///     0x9f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mod r0, r1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_div_by_zero(DivByZero::Kernel);
     ///
-    /// vm.jit_compile();
+    /// // The modulo by zero leaves r0 unchanged.
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 7);
     /// ```
-    pub fn jit_compile(&mut self) {
-        self.parent.jit = jit::compile(&self.parent.prog, &self.parent.helpers, true, true);
+    pub fn set_div_by_zero(&mut self, semantics: ebpf::DivByZero) {
+        self.div_by_zero = semantics;
     }
 
-    /// Execute the previously JIT-compiled program, with the given packet data, in a manner very
-    /// similar to `prog_exec()`.
-    ///
-    /// If the program is made to be compatible with Linux kernel, it is expected to load the
-    /// address of the beginning and of the end of the memory area used for packet data from some
-    /// metadata buffer, which in the case of this VM is handled internally. The offsets at which
-    /// the addresses should be placed should have be set at the creation of the VM.
-    ///
-    /// # Panics
+    /// Enable or disable memory poisoning, a debug mode of the interpreter catching the programs
+    /// that read their stack before writing it. The simple verifier does not track the contents
+    /// of the stack, and such programs run with whatever the stack happens to contain, while the
+    /// verifier of the kernel rejects them.
     ///
-    /// This function panics if an error occurs during the execution of the program.
+    /// When poisoning is enabled, the stack is filled with `ebpf::POISON_BYTE` before each
+    /// execution, and the interpreter records the bytes of the stack written by the program and
+    /// by helpers. Loading a byte that was never written aborts the program with
+    /// `EbpfError::UninitializedRead`. `EbpfVmFixedMbuff` also fills the bytes of its metadata
+    /// buffer not set by the VM with the pattern. Bytes that helpers set to the pattern itself are
+    /// still considered uninitialized.
     ///
-    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
-    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
-    /// very bad (program may segfault). It may be wise to check that the program works with the
-    /// interpreter before running the JIT-compiled version of it.
+    /// Only the interpreter checks the loads: the programs run by `prog_exec()` are not
+    /// JIT-compiled in the background while poisoning is enabled.
     ///
     /// # Examples
     ///
     /// ```
+    /// use rbpf::error::EbpfError;
+    ///
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
-    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
-    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
-    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
-    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x7a, 0x0a, 0xf8, 0xff, 0x01, 0x00, 0x00, 0x00, // stdw [r10-8], 1
+    ///     0x79, 0xa0, 0xf0, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-16]
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
-    /// ];
     ///
-    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
-    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// assert_eq!(vm.try_prog_exec(&mut vec![], &mut vec![]), Ok(0));
     ///
-    /// vm.jit_compile();
+    /// vm.set_memory_poisoning(true);
+    /// assert_eq!(vm.try_prog_exec(&mut vec![], &mut vec![]),
+    ///            Err(EbpfError::UninitializedRead { pc: 1, off: -16, len: 8 }));
+    /// ```
+    pub fn set_memory_poisoning(&mut self, poison: bool) {
+        self.memory_poisoning = poison;
+    }
+
+    /// Select the built-in helpers that the JIT-compiler inlines, see `helpers::Intrinsics`. By
+    /// default, all helpers are called.
     ///
-    /// // Provide only a reference to the packet data. We do not manage the metadata buffer.
-    /// let res = vm.prog_exec_jit(&mut mem);
-    /// assert_eq!(res, 0xdd);
-    /// ```
-    // This struct redefines the `prog_exec_jit()` function, in order to pass the offsets
-    // associated with the fixed mbuff.
-    pub fn prog_exec_jit(&mut self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
-        // If packet data is empty, do not send the address of an empty vector; send a null
-        // pointer (zero value) as first argument instead, as this is uBPF's behavior (empty
-        // packet should not happen in the kernel; anyway the verifier would prevent the use of
-        // uninitialized registers). See `mul_loop` test.
-        let mem_ptr = match mem.len() {
-            0 => 0 as *mut u8,
-            _ => mem.as_ptr() as *mut u8
-        };
-        (self.parent.jit)(self.mbuff.buffer.as_ptr() as *mut u8, self.mbuff.buffer.len(),
-                          mem_ptr, mem.len(), self.mbuff.data_offset, self.mbuff.data_end_offset)
-    }
-}
-
-/// A virtual machine to run eBPF program. This kind of VM is used for programs expecting to work
-/// directly on the memory area representing packet data.
-///
-/// # Examples
-///
-/// ```
-/// let prog = vec![
-///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
-///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-/// ];
-/// let mut mem = vec![
-///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
-/// ];
-///
-/// // Instantiate a VM.
-/// let vm = rbpf::EbpfVmRaw::new(&prog);
-///
-/// // Provide only a reference to the packet data.
-/// let res = vm.prog_exec(&mut mem);
-/// assert_eq!(res, 0x22cc);
-/// ```
-pub struct EbpfVmRaw<'a> {
-    parent: EbpfVmMbuff<'a>,
-}
-
-impl<'a> EbpfVmRaw<'a> {
-
-    /// Create a new virtual machine instance, and load an eBPF program into that instance.
-    /// When attempting to load the program, it passes through a simple verifier.
-    ///
-    /// # Panics
-    ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// The JIT-compiler uses the intrinsics selected when compiling: this function should be
+    /// called before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use rbpf::helpers::{self, Intrinsics};
+    ///
     /// let prog = vec![
-    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0xb7, 0x01, 0x00, 0x00, 0x51, 0x00, 0x00, 0x00, // mov r1, 81
+    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call sqrti
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// // Instantiate a VM.
-    /// let vm = rbpf::EbpfVmRaw::new(&prog);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_helper(1, helpers::sqrti);
+    /// vm.set_intrinsics(Intrinsics { sqrti: true, ..Default::default() });
+    ///
+    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec_jit(&mut vec![], &mut vec![]), 9);
     /// ```
-    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmRaw<'a> {
-        let parent = EbpfVmMbuff::new(prog);
-        EbpfVmRaw {
-            parent: parent,
-        }
+    pub fn set_intrinsics(&mut self, intrinsics: helpers::Intrinsics) {
+        self.intrinsics = intrinsics;
     }
 
-    /// Load a new eBPF program into the virtual machine instance.
-    ///
-    /// # Panics
+    /// Enable or disable the hardening of the JIT-compiled code, disabled by default. With
+    /// hardening, the JIT-compiler blinds the immediates of the program, as the Linux kernel does
+    /// with `bpf_jit_harden`: they are not copied as is into the machine code, but xored with a
+    /// random key, and restored at run time. This prevents untrusted programs from choosing
+    /// sequences of bytes in executable memory (JIT spraying), for a slightly larger and slower
+    /// code. Immediates of a single byte, such as the ones of shifts, are not blinded.
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// As with `set_intrinsics()`, this function should be called before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
-    /// let prog1 = vec![
-    ///     0xb7, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-    /// ];
-    /// let prog2 = vec![
-    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x90, 0x90, 0x90, 0x90, // mov r0, 0x90909090
+    ///     0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add r0, 1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27,
-    /// ];
-    ///
-    /// let mut vm = rbpf::EbpfVmRaw::new(&prog1);
-    /// vm.set_prog(&prog2);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.jit_compile();
+    /// let size = vm.stats().jit_code_size.unwrap();
     ///
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 0x22cc);
+    /// vm.set_jit_hardening(true);
+    /// vm.jit_compile();
+    /// assert!(vm.stats().jit_code_size.unwrap() > size);
+    /// assert_eq!(vm.prog_exec_jit(&mut vec![], &mut vec![]), 0xffffffff90909091);
     /// ```
-    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
-        self.parent.set_prog(prog)
+    pub fn set_jit_hardening(&mut self, enabled: bool) {
+        self.jit_harden = enabled;
     }
 
-    /// Register a built-in or user-defined helper function in order to use it later from within
-    /// the eBPF program. The helper is registered into a hashmap, so the `key` can be any `u32`.
+    /// Enable or disable the backing of the JIT-compiled code with huge pages, disabled by
+    /// default. The code of each program is then aligned to, and made of, huge pages of 2 MiB,
+    /// which the kernel is advised to use for it, on Linux with transparent huge pages enabled.
+    /// A few very hot programs then take a single entry of the instruction TLB each, at the cost
+    /// of 2 MiB of memory each, counted by `jit_code_usage()`. Without it, the code is made of the
+    /// pages of the host.
     ///
-    /// If using JIT-compiled eBPF programs, be sure to register all helpers before compiling the
-    /// program. You should be able to change registered helpers after compiling, but not to add
-    /// new ones (i.e. with new keys).
+    /// As with `set_intrinsics()`, this function should be called before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
-    /// use rbpf::helpers;
-    ///
     /// let prog = vec![
-    ///     0x79, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, r1[0x00]
-    ///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r2, 0
-    ///     0xb7, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r3, 0
-    ///     0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r4, 0
-    ///     0xb7, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r5, 0
-    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call helper with key 1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov r0, 42
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut mem = vec![
-    ///     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01
-    /// ];
-    ///
-    /// // Instantiate a VM.
-    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
-    ///
-    /// // Register a helper. This helper will store the result of the square root of r1 into r0.
-    /// vm.register_helper(1, helpers::sqrti);
-    ///
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 0x10000000);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_jit_huge_pages(true);
+    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec_jit(&mut vec![], &mut vec![]), 42);
     /// ```
-    pub fn register_helper(&mut self, key: u32, function: fn (u64, u64, u64, u64, u64) -> u64) {
-        self.parent.register_helper(key, function);
+    pub fn set_jit_huge_pages(&mut self, enabled: bool) {
+        self.jit_huge_pages = enabled;
     }
 
-    /// Execute the program loaded, with the given packet data.
+    /// Give the JIT-compiler a profile of the executions of the program, collected by the
+    /// interpreter with `prog_exec_trace()`, or remove it. The JIT-compiler then lays out the
+    /// code of the program following the profile: the hot paths are contiguous, the likely
+    /// successor of each conditional jump follows it, and the code that never ran is moved to the
+    /// end, see `profile::Profile::layout()`. The profile does not change the results of the
+    /// program.
     ///
-    /// # Panics
-    ///
-    /// This function is currently expected to panic if it encounters any error during the program
-    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
-    /// in the future (we could raise errors instead).
+    /// As with `set_intrinsics()`, this function should be called before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use rbpf::profile::Profile;
+    ///
     /// let prog = vec![
-    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+    ///     0x55, 0x02, 0x01, 0x00, 0x2a, 0x00, 0x00, 0x00, // jne r2, 42, +1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
+    /// let mut mem = vec![7];
+    /// let mut mbuff = vec![0u8; 16];
+    /// mbuff[8..16].copy_from_slice(&(mem.as_ptr() as u64).to_ne_bytes());
     ///
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
-    /// ];
-    ///
-    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// let mut profile = Profile::new();
+    /// assert_eq!(vm.prog_exec_trace(&mut mem, &mut mbuff, &mut profile), 0);
+    /// assert_eq!(profile.taken(3), 1);
     ///
-    /// let res = vm.prog_exec(&mut mem);
-    /// assert_eq!(res, 0x22cc);
+    /// vm.set_jit_profile(Some(profile));
+    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec_jit(&mut mem, &mut mbuff), 0);
     /// ```
-    pub fn prog_exec(&self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
-        let mut mbuff = vec![];
-        self.parent.prog_exec(mem, &mut mbuff)
+    pub fn set_jit_profile(&mut self, profile: Option<profile::Profile>) {
+        self.jit_profile = profile;
     }
 
-    /// JIT-compile the loaded program. No argument required for this.
-    ///
-    /// If using helper functions, be sure to register them into the VM before calling this
-    /// function.
+    // The layout of the basic blocks of the program for the JIT-compiler, see `set_jit_profile()`.
+    fn jit_layout(&self) -> Option<Vec<usize>> {
+        self.jit_profile.as_ref().map(|profile| profile.layout(self.prog))
+    }
+
+    /// Select the version of the instruction set that programs may use, see `ebpf::IsaVersion`.
+    /// The verifier checks the program currently loaded against it, and then every program loaded
+    /// with `set_prog()`. By default, all versions up to v4 are accepted.
     ///
     /// # Panics
     ///
-    /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
-    /// unknown eBPF operation code.
+    /// Panics if the program currently loaded uses instructions unavailable in version `isa`.
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```should_panic
+    /// use rbpf::ebpf::IsaVersion;
+    ///
     /// let prog = vec![
-    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x16, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq32 r1, 0, +1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
     ///
-    /// vm.jit_compile();
+    /// // Panics with "[Verifier] Error: opcode 0x16 requires ISA V3 (insn #0)".
+    /// vm.set_isa_version(IsaVersion::V2);
     /// ```
-    pub fn jit_compile(&mut self) {
-        self.parent.jit = jit::compile(&self.parent.prog, &self.parent.helpers, false, false);
+    pub fn set_isa_version(&mut self, isa: ebpf::IsaVersion) {
+        if let Err(err) = verifier::try_check_isa(self.prog, isa) {
+            panic!("{}", err);
+        }
+        self.isa = isa;
     }
 
-    /// Execute the previously JIT-compiled program, with the given packet data, in a manner very
-    /// similar to `prog_exec()`.
-    ///
-    /// # Panics
-    ///
-    /// This function panics if an error occurs during the execution of the program.
+    /// Set the maximum number of executions of programs nested on the current thread, when
+    /// helpers run programs themselves, for example to call back into the application, counting
+    /// the execution of this VM. With 1, the program cannot run from within a helper. The default
+    /// is `ebpf::MAX_CALL_DEPTH`.
+    ///
+    /// The executions of all VMs running on the thread are counted, and the limit of the VM about
+    /// to run its program applies. Beyond it, the program does not run, and the execution fails
+    /// with `EbpfError::CallDepthExceeded`, instead of exhausting the stack of the host.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Set the maximum number of instructions an execution of the program may run, or `None` for
+    /// no limit, the default. The instructions of the programs jumped to with tail calls, and of
+    /// the callbacks of `bpf_for_each_map_elem()`, are counted as well. Beyond the limit, the
+    /// interpreter aborts the execution with `EbpfError::ExceededLimit`.
     ///
-    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
-    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
-    /// very bad (program may segfault). It may be wise to check that the program works with the
-    /// interpreter before running the JIT-compiled version of it.
+    /// JIT-compiled programs do not count their instructions: under a limit, the JIT-compiler
+    /// only compiles the programs that `analysis::worst_case()` proves to run at most `limit`
+    /// instructions, and fails for the others. Set the limit before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use rbpf::error::EbpfError;
+    ///
+    /// // Runs about two million instructions.
     /// let prog = vec![
-    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
-    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
-    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+    ///     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+    ///     0xa5, 0x01, 0xfe, 0xff, 0x40, 0x42, 0x0f, 0x00, // jlt r1, 1000000, -2
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut mem = vec![
-    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
-    /// ];
-    ///
-    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
-    ///
-    /// vm.jit_compile();
-    ///
-    /// let res = vm.prog_exec_jit(&mut mem);
-    /// assert_eq!(res, 0x22cc);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_insn_limit(Some(1000));
+    /// assert!(vm.try_jit_compile().is_err());
+    /// assert_eq!(vm.try_prog_exec(&mut [], &mut []),
+    ///            Err(EbpfError::ExceededLimit("Error: exceeded the limit of 1000 instructions (insn #2)".to_string())));
     /// ```
-    pub fn prog_exec_jit(&self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
-        let mut mbuff = vec![];
-        self.parent.prog_exec_jit(mem, &mut mbuff)
+    pub fn set_insn_limit(&mut self, limit: Option<u64>) {
+        self.insn_limit = limit;
     }
-}
-
-/// A virtual machine to run eBPF program. This kind of VM is used for programs that do not work
-/// with any memory area—no metadata buffer, no packet data either.
-///
-/// # Examples
-///
-/// ```
-/// let prog = vec![
-///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
-///     0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r1, 1
-///     0xb7, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov r2, 2
-///     0xb7, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov r3, 3
-///     0xb7, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov r4, 4
-///     0xb7, 0x05, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov r5, 5
-///     0xb7, 0x06, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // mov r6, 6
-///     0xb7, 0x07, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov r7, 7
-///     0xb7, 0x08, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov r8, 8
-///     0x4f, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // or r0, r5
-///     0x47, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x00, // or r0, 0xa0
-///     0x57, 0x00, 0x00, 0x00, 0xa3, 0x00, 0x00, 0x00, // and r0, 0xa3
-///     0xb7, 0x09, 0x00, 0x00, 0x91, 0x00, 0x00, 0x00, // mov r9, 0x91
-///     0x5f, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // and r0, r9
-///     0x67, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // lsh r0, 32
-///     0x67, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, // lsh r0, 22
-///     0x6f, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lsh r0, r8
-///     0x77, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // rsh r0, 32
-///     0x77, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, // rsh r0, 19
-///     0x7f, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // rsh r0, r7
-///     0xa7, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // xor r0, 0x03
-///     0xaf, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xor r0, r2
-///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-/// ];
-///
-/// // Instantiate a VM.
-/// let vm = rbpf::EbpfVmNoData::new(&prog);
-///
-/// // Provide only a reference to the packet data.
-/// let res = vm.prog_exec();
-/// assert_eq!(res, 0x11);
-/// ```
-pub struct EbpfVmNoData<'a> {
-    parent: EbpfVmRaw<'a>,
-}
-
-impl<'a> EbpfVmNoData<'a> {
 
-    /// Create a new virtual machine instance, and load an eBPF program into that instance.
-    /// When attempting to load the program, it passes through a simple verifier.
-    ///
-    /// # Panics
+    /// Make packet data read-only for the program, or writable again, the default. The
+    /// interpreter then rejects the stores to packet data, and to the fragments of multi-buffer
+    /// packets, with `EbpfError::OutOfBounds`, as well as the writes of the bounds-checked memory
+    /// helpers; `bpf_xdp_store_bytes()` and the helpers resizing packets return `-EPERM` (see
+    /// `helpers::EPERM`). Packet data is the `mem` area given to the VMs, which is also the context
+    /// of the program for `EbpfVmRaw`. The helpers registered on the VM are not restricted.
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// JIT-compiled programs do not check their stores: with read-only packet data, the
+    /// JIT-compiler only compiles the programs whose stores `verifier::check_bounds()` finds to
+    /// all be to the stack, and fails for the others. Set it before `jit_compile()`.
     ///
     /// # Examples
     ///
     /// ```
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x72, 0x01, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r1], 0x2a
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
+    /// let mut mem = vec![0u8; 4];
     ///
-    /// // Instantiate a VM.
-    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    /// vm.set_read_only_packet(true);
+    /// assert!(vm.try_jit_compile().is_err());
+    /// assert!(vm.try_prog_exec(&mut mem).is_err());
+    /// assert_eq!(mem, vec![0u8; 4]);
     /// ```
-    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmNoData<'a> {
-        let parent = EbpfVmRaw::new(prog);
-        EbpfVmNoData {
-            parent: parent,
-        }
+    pub fn set_read_only_packet(&mut self, read_only: bool) {
+        self.read_only_packet = read_only;
     }
 
-    /// Load a new eBPF program into the virtual machine instance.
+    /// Apply the settings of a security profile, see `ebpf::SecurityProfile`, replacing the
+    /// individual settings it covers; `SecurityProfile::Default` restores their default values,
+    /// and removes any instruction policy. The settings can still be changed individually
+    /// afterwards.
+    ///
+    /// With `SecurityProfile::Strict`, programs may only call the helpers registered on the VM,
+    /// and the built-in helpers enabled on it, at the time the profile is selected: select it
+    /// once the helpers are registered. As when setting the JIT hardening or the stack guard, the
+    /// JIT-compiled program is discarded. Under the strict profile, the JIT-compiler fails for the
+    /// programs it cannot prove to respect the instruction limit and read-only packet data.
     ///
     /// # Panics
     ///
-    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    /// Panics if the program currently loaded calls other helpers, under the strict profile.
     ///
     /// # Examples
     ///
     /// ```
-    /// let prog1 = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
-    /// ];
-    /// let prog2 = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    /// use rbpf::ebpf::SecurityProfile;
+    ///
+    /// // Divides 7 by zero.
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov r0, 7
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+    ///     0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, r1
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut vm = rbpf::EbpfVmNoData::new(&prog1);
-    ///
-    /// let res = vm.prog_exec();
-    /// assert_eq!(res, 0x2211);
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_security_profile(SecurityProfile::Strict);
+    /// assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 0);
     ///
-    /// vm.set_prog(&prog2);
+    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec_jit(&mut vec![], &mut vec![]), 0);
+    /// ```
+    pub fn set_security_profile(&mut self, profile: ebpf::SecurityProfile) {
+        let strict = profile == ebpf::SecurityProfile::Strict;
+        self.insn_policy = None;
+        if strict {
+            let policy = self.enabled_helpers().into_iter().fold(verifier::HelperAllowList::new(), |policy, key| {
+                policy.allow(key)
+            });
+            self.set_insn_policy(Box::new(policy));
+        }
+        self.set_jit_hardening(strict);
+        self.set_stack_guard(strict);
+        self.set_catch_faults(strict);
+        self.set_memory_poisoning(strict);
+        self.set_div_by_zero(if strict { ebpf::DivByZero::Kernel } else { ebpf::DivByZero::Error });
+        self.set_max_call_depth(if strict { 1 } else { ebpf::MAX_CALL_DEPTH });
+        self.set_insn_limit(if strict { Some(ebpf::STRICT_INSN_LIMIT) } else { None });
+        self.set_read_only_packet(strict);
+    }
+
+    // The keys of the helpers that programs can call: those registered, and the built-in helpers
+    // enabled on the VM, in increasing order. The helpers for multi-buffer and resizable packets
+    // are enabled by the functions running programs on such packets, and tail calls by the
+    // registries running programs, so they are always included.
+    fn enabled_helpers(&self) -> Vec<u32> {
+        let mut keys: Vec<u32> = self.helpers.keys().chain(self.frame_helpers.keys())
+            .chain(self.fallible_helpers.keys())
+            .cloned()
+            .collect();
+        keys.extend_from_slice(&[helpers::BPF_TAIL_CALL_IDX, helpers::BPF_XDP_GET_BUFF_LEN_IDX,
+                                 helpers::BPF_XDP_LOAD_BYTES_IDX, helpers::BPF_XDP_STORE_BYTES_IDX,
+                                 helpers::BPF_XDP_ADJUST_HEAD_IDX, helpers::BPF_XDP_ADJUST_TAIL_IDX,
+                                 helpers::BPF_SKB_ADJUST_ROOM_IDX]);
+        if self.mem_helpers {
+            keys.extend_from_slice(&[helpers::MEMCPY_IDX, helpers::MEMSET_IDX, helpers::MEMCMP_IDX,
+                                     helpers::BPF_STRTOL_IDX, helpers::BPF_STRTOUL_IDX, helpers::BPF_CSUM_DIFF_IDX]);
+        }
+        if !self.probe_regions.is_empty() {
+            keys.extend_from_slice(&[helpers::BPF_PROBE_READ_IDX, helpers::BPF_PROBE_READ_USER_IDX,
+                                     helpers::BPF_PROBE_READ_KERNEL_IDX]);
+        }
+        if self.event_sink.is_some() {
+            keys.push(helpers::BPF_PERF_EVENT_OUTPUT_IDX);
+        }
+        if self.scratch.is_some() {
+            keys.push(helpers::GET_SCRATCH_IDX);
+        }
+        if !self.config.is_empty() {
+            keys.extend_from_slice(&[helpers::CONFIG_LOOKUP_IDX, helpers::CONFIG_VALUE_LEN_IDX]);
+        }
+        if !self.maps.is_empty() {
+            keys.extend_from_slice(&[helpers::BPF_MAP_LOOKUP_ELEM_IDX, helpers::BPF_MAP_UPDATE_ELEM_IDX,
+                                     helpers::BPF_MAP_DELETE_ELEM_IDX, helpers::BPF_REDIRECT_MAP_IDX,
+                                     helpers::BPF_FOR_EACH_MAP_ELEM_IDX]);
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    /// Execute the program loaded, with the given packet data and metadata buffer.
+    ///
+    /// If the program is made to be compatible with Linux kernel, it is expected to load the
+    /// address of the beginning and of the end of the memory area used for packet data from the
+    /// metadata buffer, at some appointed offsets. It is up to the user to ensure that these
+    /// pointers are correctly stored in the buffer.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
+    /// in the future (we could raise errors instead).
+    ///
+    /// # Examples
     ///
-    /// let res = vm.prog_exec();
-    /// assert_eq!(res, 0x1122);
     /// ```
-    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
-        self.parent.set_prog(prog)
+    /// use rbpf::ctx::CtxBytes;
+    ///
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// // Just for the example we create our metadata buffer from scratch, and we store the
+    /// // pointers to packet data start and end in it.
+    /// let mut mbuff = vec![0u8; 32];
+    /// mbuff.write_u64_le(8, mem.as_ptr() as u64);
+    /// mbuff.write_u64_le(24, mem.as_ptr() as u64 + mem.len() as u64);
+    ///
+    /// // Instantiate a VM.
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// // Provide both a reference to the packet data, and to the metadata buffer.
+    /// let res = vm.prog_exec(&mut mem, &mut mbuff);
+    /// assert_eq!(res, 0x2211);
+    /// ```
+    pub fn prog_exec(&self, mem: &mut std::vec::Vec<u8>, mbuff: &'a mut std::vec::Vec<u8>) -> u64 {
+        if let Some(jit) = self.async_jit() {
+            return self.exec_jit(jit, mem, mbuff);
+        }
+        interpreter::execute_program(self.prog, mem, mbuff, &self.helpers, self.interpreter_options(None))
     }
 
-    /// Register a built-in or user-defined helper function in order to use it later from within
-    /// the eBPF program. The helper is registered into a hashmap, so the `key` can be any `u32`.
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. The message of the error does not include the source location
+    /// obtained from line information, but the index of the faulty instruction is available
+    /// with `EbpfError::pc()`.
     ///
-    /// If using JIT-compiled eBPF programs, be sure to register all helpers before compiling the
-    /// program. You should be able to change registered helpers after compiling, but not to add
-    /// new ones (i.e. with new keys).
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::error::EbpfError;
+    ///
+    /// let prog = vec![
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+    ///     0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// match vm.try_prog_exec(&mut [], &mut []) {
+    ///     Err(EbpfError::DivideByZero { pc }) => assert_eq!(pc, 1),
+    ///     _ => panic!("expected a division by zero"),
+    /// }
+    /// ```
+    pub fn try_prog_exec(&self, mem: &mut [u8], mbuff: &'a mut [u8]) -> Result<u64, EbpfError> {
+        interpreter::try_execute_program(self.prog, mem, mbuff, &self.helpers, self.interpreter_options(None))
+    }
+
+    /// Execute the program loaded with the interpreter, as `try_prog_exec()` does, with a budget
+    /// of `fuel` instructions: if the program has not exited when the fuel runs out, the
+    /// execution stops before the next instruction, and a continuation is returned, to resume it
+    /// later with `try_resume()` and more fuel. A host can run many programs on one thread in
+    /// turn in this way, each with a slice of fuel, without a long program delaying the others.
+    ///
+    /// The `lddw` instructions take one unit of fuel, as the other instructions. Helpers run
+    /// to completion, whatever their cost.
     ///
     /// # Examples
     ///
     /// ```
-    /// use rbpf::helpers;
+    /// use rbpf::Metered;
     ///
+    /// // Sums the integers from 1 to 10.
     /// let prog = vec![
-    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // mov r1, 0x010000000
-    ///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r2, 0
-    ///     0xb7, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r3, 0
-    ///     0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r4, 0
-    ///     0xb7, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r5, 0
-    ///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call helper with key 1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+    ///     0xb7, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r1, 10
+    ///     0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+    ///     0x17, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r1, 1
+    ///     0x55, 0x01, 0xfd, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -3
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// // The program runs 33 instructions: refill the fuel 10 instructions at a time.
+    /// let mut outcome = vm.try_prog_exec_metered(&mut [], &mut [], 10).unwrap();
+    /// let mut slices = 1;
+    /// let res = loop {
+    ///     match outcome {
+    ///         Metered::Done(res)                => break res,
+    ///         Metered::Suspended(continuation) => {
+    ///             outcome = vm.try_resume(continuation, &mut [], &mut [], 10).unwrap();
+    ///             slices += 1;
+    ///         },
+    ///     }
+    /// };
+    /// assert_eq!(res, 55);
+    /// assert_eq!(slices, 4);
+    /// ```
+    pub fn try_prog_exec_metered(&self, mem: &mut [u8], mbuff: &mut [u8], fuel: u64)
+                                 -> Result<Metered<'a>, EbpfError> {
+        self.exec_metered(interpreter::Metering { fuel: Cell::new(fuel), state: RefCell::new(None) }, mem, mbuff)
+    }
+
+    /// Resume the execution suspended in `continuation`, with a budget of `fuel` more
+    /// instructions, see `try_prog_exec_metered()`. The execution may be suspended again.
+    ///
+    /// # Errors
+    ///
+    /// Returns `EbpfError::InvalidResume`, dropping the execution, if it was not started by this
+    /// VM, or if `mem` and `mbuff` are not the packet data and the metadata buffer it started on,
+    /// at the same addresses.
+    pub fn try_resume(&self, continuation: Continuation<'a>, mem: &mut [u8], mbuff: &mut [u8], fuel: u64)
+                      -> Result<Metered<'a>, EbpfError> {
+        if continuation.vm != self.prog.as_ptr() as usize {
+            return Err(EbpfError::InvalidResume("Error: execution resumed by another VM".to_string()));
+        }
+        let metering = interpreter::Metering { fuel: Cell::new(fuel), state: RefCell::new(Some(*continuation.state)) };
+        self.exec_metered(metering, mem, mbuff)
+    }
+
+    fn exec_metered(&self, metering: interpreter::Metering<'a>, mem: &mut [u8], mbuff: &mut [u8])
+                    -> Result<Metered<'a>, EbpfError> {
+        let options = interpreter::Options { metering: Some(&metering), ..self.interpreter_options(None) };
+        let res = interpreter::try_execute_program(self.prog, mem, mbuff, &self.helpers, options)?;
+        Ok(match metering.state.into_inner() {
+            Some(state) => Metered::Suspended(Continuation { state: Box::new(state), vm: self.prog.as_ptr() as usize }),
+            None        => Metered::Done(res),
+        })
+    }
+
+    /// Execute the loaded XDP program, in the same way as `prog_exec()`, and return the value in
+    /// R0 as an XDP action.
     ///
-    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    /// # Panics
     ///
-    /// // Register a helper. This helper will store the result of the square root of r1 into r0.
-    /// vm.register_helper(1, helpers::sqrti);
+    /// Panics on the same errors as `try_prog_exec_xdp()`.
+    ///
+    /// # Examples
     ///
-    /// let res = vm.prog_exec();
-    /// assert_eq!(res, 0x1000);
     /// ```
-    pub fn register_helper(&mut self, key: u32, function: fn (u64, u64, u64, u64, u64) -> u64) {
-        self.parent.register_helper(key, function);
+    /// use rbpf::ebpf::XdpAction;
+    ///
+    /// // Drops packets whose first byte is 0, passes the others.
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+    ///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+    ///     0x55, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r2, 0, +1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// assert_eq!(vm.prog_exec_xdp(&mut [0x00], &mut []), XdpAction::Drop);
+    /// assert_eq!(vm.prog_exec_xdp(&mut [0x2a], &mut []), XdpAction::Pass);
+    /// ```
+    pub fn prog_exec_xdp(&self, mem: &mut [u8], mbuff: &'a mut [u8]) -> ebpf::XdpAction {
+        match self.try_prog_exec_xdp(mem, mbuff) {
+            Ok(action) => action,
+            Err(err)   => panic!("{}", err),
+        }
     }
 
-    /// JIT-compile the loaded program. No argument required for this.
+    /// Execute the loaded XDP program, in the same way as `prog_exec_xdp()`, but return runtime
+    /// errors instead of panicking, as well as an `EbpfError::InvalidReturnValue` error if the
+    /// value in R0 is not an XDP action. See `ebpf::XdpAction::from_ret()`.
+    pub fn try_prog_exec_xdp(&self, mem: &mut [u8], mbuff: &'a mut [u8]) -> Result<ebpf::XdpAction, EbpfError> {
+        self.try_prog_exec(mem, mbuff).and_then(ebpf::XdpAction::from_ret)
+    }
+
+    /// Execute the loaded XDP program, in the same way as `prog_exec_xdp()`, and return the XDP
+    /// action along with the redirect requested by the program with `bpf_redirect_map()`, if it
+    /// returned `XdpAction::Redirect`. The VM does not perform the redirect: the application
+    /// forwards the packet to the target found in the map. See `helpers::BPF_REDIRECT_MAP_IDX`.
+    /// The program is interpreted, even if it was JIT-compiled.
     ///
-    /// If using helper functions, be sure to register them into the VM before calling this
-    /// function.
+    /// # Panics
+    ///
+    /// Panics on the same errors as `try_prog_exec_xdp()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rbpf::ebpf::XdpAction;
+    /// use rbpf::maps::{self, Map, MapType, Redirect};
+    ///
+    /// // Redirects packets to the port given by their first byte, or drops them.
+    /// let prog = vec![
+    ///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+    ///     0xb7, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r3, XDP_DROP
+    ///     0x85, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00, // call bpf_redirect_map
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // Port 1 is interface 42.
+    /// let ports = Arc::new(Map::new(MapType::DevMap, 4, 4, 8));
+    /// ports.update(&1u32.to_le_bytes(), &42u32.to_le_bytes(), maps::BPF_ANY).unwrap();
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.register_map(ports.clone());
+    ///
+    /// let redirect = Redirect { map: 0, key: 1, flags: 1 };
+    /// assert_eq!(vm.prog_exec_xdp_redirect(&mut [1], &mut []), (XdpAction::Redirect, Some(redirect)));
+    /// assert_eq!(vm.prog_exec_xdp_redirect(&mut [2], &mut []), (XdpAction::Drop, None));
+    /// assert_eq!(ports.lookup(&redirect.key.to_le_bytes()), Some(42u32.to_le_bytes().to_vec()));
+    /// ```
+    pub fn prog_exec_xdp_redirect(&self, mem: &mut [u8], mbuff: &'a mut [u8])
+                                  -> (ebpf::XdpAction, Option<maps::Redirect>) {
+        match self.try_prog_exec_xdp_redirect(mem, mbuff) {
+            Ok(res)  => res,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Execute the loaded XDP program, in the same way as `prog_exec_xdp_redirect()`, but return
+    /// runtime errors instead of panicking, as well as an `EbpfError::InvalidReturnValue` error if
+    /// the value in R0 is not an XDP action.
+    pub fn try_prog_exec_xdp_redirect(&self, mem: &mut [u8], mbuff: &'a mut [u8])
+                                      -> Result<(ebpf::XdpAction, Option<maps::Redirect>), EbpfError> {
+        let redirect = Cell::new(None);
+        let options = interpreter::Options { redirect: Some(&redirect), ..self.interpreter_options(None) };
+        let action = interpreter::try_execute_program(self.prog, mem, mbuff, &self.helpers, options)
+            .and_then(ebpf::XdpAction::from_ret)?;
+        Ok(match action {
+            ebpf::XdpAction::Redirect => (action, redirect.get()),
+            _                         => (action, None),
+        })
+    }
+
+    /// Execute the loaded tc classifier, in the same way as `prog_exec()`, and return the value in
+    /// R0 as a tc action.
     ///
     /// # Panics
     ///
-    /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
-    /// unknown eBPF operation code.
+    /// Panics on the same errors as `try_prog_exec_tc()`.
+    pub fn prog_exec_tc(&self, mem: &mut [u8], mbuff: &'a mut [u8]) -> ebpf::TcAction {
+        match self.try_prog_exec_tc(mem, mbuff) {
+            Ok(action) => action,
+            Err(err)   => panic!("{}", err),
+        }
+    }
+
+    /// Execute the loaded tc classifier, in the same way as `prog_exec_tc()`, but return runtime
+    /// errors instead of panicking, as well as an `EbpfError::InvalidReturnValue` error if the
+    /// value in R0 is not a tc action. See `ebpf::TcAction::from_ret()`.
+    pub fn try_prog_exec_tc(&self, mem: &mut [u8], mbuff: &'a mut [u8]) -> Result<ebpf::TcAction, EbpfError> {
+        self.try_prog_exec(mem, mbuff).and_then(ebpf::TcAction::from_ret)
+    }
+
+    /// Run the program `repeat` times, or once if `repeat` is 0, on a copy of packet data
+    /// `data_in` and of metadata buffer `ctx_in`, as command `BPF_PROG_TEST_RUN` of the `bpf()`
+    /// system call of the Linux kernel does, and return the value returned by the last run, the
+    /// average duration of a run, and the packet data and metadata buffer after the last run. As
+    /// in the kernel, all runs use the same copies, so that the changes of a run are seen by the
+    /// following ones. The program is interpreted, even if it was JIT-compiled.
+    ///
+    /// The pointers to packet data in the metadata buffer are those of the caller; programs
+    /// reading them from the metadata buffer are run with `EbpfVmFixedMbuff::test_run()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the first run failing, as `try_prog_exec()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// // Increments the first byte of the metadata buffer, and returns it.
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+    ///     0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+    ///     0x73, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1], r0
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// let run = vm.test_run(&[10, 20], &[7, 0], 5).unwrap();
+    /// assert_eq!(run.retval, 12);
+    /// assert_eq!(run.data_out, vec![10, 20]);
+    /// assert_eq!(run.ctx_out, vec![12, 0]);
+    /// ```
+    pub fn test_run(&self, data_in: &[u8], ctx_in: &[u8], repeat: u32) -> Result<testing::TestRun, EbpfError> {
+        self.test_run_buffers(data_in.to_vec(), ctx_in.to_vec(), repeat)
+    }
+
+    // Runs the program as `test_run()`, on buffers `data` and `ctx`.
+    fn test_run_buffers(&self, data: std::vec::Vec<u8>, ctx: std::vec::Vec<u8>, repeat: u32)
+                        -> Result<testing::TestRun, EbpfError> {
+        let runs = repeat.max(1);
+        let mut retval = 0;
+        let start = Instant::now();
+        for _ in 0..runs {
+            retval = interpreter::try_execute_program(self.prog, &data, &ctx, &self.helpers,
+                                                      self.interpreter_options(None))?;
+        }
+        let duration = start.elapsed().as_nanos() / runs as u128;
+        Ok(testing::TestRun {
+            retval:   retval as u32,
+            duration: duration.min(u32::MAX as u128) as u32,
+            data_out: data,
+            ctx_out:  ctx,
+        })
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See the `trace` module for the available tracers.
     ///
+    /// # Panics
+    ///
+    /// Panics on the same errors as `prog_exec()`. The instruction causing the error is not
+    /// recorded, but those executed before it are.
+    ///
+    /// # Examples
     ///
-    /// vm.jit_compile();
     /// ```
-    pub fn jit_compile(&mut self) {
-        self.parent.jit_compile();
+    /// use rbpf::ctx::CtxBytes;
+    ///
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// let mut mbuff = vec![0u8; 32];
+    /// mbuff.write_u64_le(8, mem.as_ptr() as u64);
+    /// mbuff.write_u64_le(24, mem.as_ptr() as u64 + mem.len() as u64);
+    ///
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// let mut log = rbpf::trace::TraceLog::new();
+    /// let res = vm.prog_exec_trace(&mut mem, &mut mbuff, &mut log);
+    /// assert_eq!(res, 0x2211);
+    ///
+    /// // The second instruction loaded two bytes from packet data.
+    /// let access = log.entries()[1].mem_access.unwrap();
+    /// assert_eq!(access.addr, mem.as_ptr() as u64 + 2);
+    /// assert_eq!(access.len, 2);
+    /// ```
+    pub fn prog_exec_trace(&self, mem: &mut [u8], mbuff: &'a mut [u8],
+                           tracer: &mut dyn trace::Tracer) -> u64 {
+        interpreter::execute_program(self.prog, mem, mbuff, &self.helpers, self.interpreter_options(Some(tracer)))
     }
 
-    /// Execute the program loaded, without providing pointers to any memory area whatsoever.
+    /// Execute the program loaded on a multi-buffer packet, made of several non-contiguous
+    /// fragments, as XDP programs may receive in the kernel. The first fragment is packet data,
+    /// all the fragments are accessible to the program, and it can read or write bytes anywhere
+    /// in the packet with helpers `bpf_xdp_load_bytes()` and `bpf_xdp_store_bytes()`, or get its
+    /// total length with `bpf_xdp_get_buff_len()`: see `helpers::BPF_XDP_GET_BUFF_LEN_IDX`.
     ///
     /// # Panics
     ///
-    /// This function is currently expected to panic if it encounters any error during the program
-    /// execution, such as memory accesses or division by zero attempts. This may be changed in the
-    /// future (we could raise errors instead).
+    /// Panics on the same errors as `prog_exec()`.
     ///
     /// # Examples
     ///
     /// ```
+    /// // Copy the two bytes at offset 3 in the packet to the stack, and return them.
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0xb7, 0x02, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r2, 3
+    ///     0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+    ///     0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+    ///     0xb7, 0x04, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r4, 2
+    ///     0x85, 0x00, 0x00, 0x00, 0xbd, 0x00, 0x00, 0x00, // call bpf_xdp_load_bytes
+    ///     0x69, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxh r0, [r10-8]
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
+    /// let mut frag1 = vec![0x00, 0x11, 0x22, 0x33];
+    /// let mut frag2 = vec![0x44, 0x55];
     ///
-    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
     ///
-    /// // For this kind of VM, the `prog_exec()` function needs no argument.
-    /// let res = vm.prog_exec();
-    /// assert_eq!(res, 0x1122);
+    /// let res = vm.prog_exec_frags(&mut [&mut frag1, &mut frag2], &mut []);
+    /// assert_eq!(res, 0x4433);
     /// ```
-    pub fn prog_exec(&self) -> u64 {
-        self.parent.prog_exec(&mut vec![])
+    pub fn prog_exec_frags(&self, frags: &mut [&mut [u8]], mbuff: &mut [u8]) -> u64 {
+        match self.try_prog_exec_frags(frags, mbuff) {
+            Ok(res) => res,
+            Err(e)  => panic!("{}", e.report(self.prog, self.line_info.as_ref())),
+        }
     }
 
-    /// Execute the previously JIT-compiled program, without providing pointers to any memory area
-    /// whatsoever, in a manner very similar to `prog_exec()`.
+    /// Execute the program loaded on a multi-buffer packet, in the same way as
+    /// `prog_exec_frags()`, but return runtime errors instead of panicking.
+    pub fn try_prog_exec_frags(&self, frags: &mut [&mut [u8]], mbuff: &mut [u8]) -> Result<u64, EbpfError> {
+        self.try_exec_segments(frags.iter_mut().map(|frag| &mut **frag as *mut [u8]).collect(), mbuff)
+    }
+
+    /// Execute the program loaded on packet data scattered over several buffers, such as the
+    /// slices filled by a vectored read, or the two halves of a ring buffer, without copying
+    /// them into a contiguous buffer. The buffers are handled as the fragments of a multi-buffer
+    /// packet: see `prog_exec_frags()`.
     ///
     /// # Panics
     ///
-    /// This function panics if an error occurs during the execution of the program.
+    /// Panics on the same errors as `prog_exec()`.
     ///
-    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
-    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
-    /// very bad (program may segfault). It may be wise to check that the program works with the
-    /// interpreter before running the JIT-compiled version of it.
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::IoSliceMut;
+    ///
+    /// // Return the total length of packet data.
+    /// let prog = vec![
+    ///     0x85, 0x00, 0x00, 0x00, 0xbc, 0x00, 0x00, 0x00, // call bpf_xdp_get_buff_len
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let (mut head, mut tail) = ([0u8; 7], [0u8; 5]);
+    ///
+    /// let vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// let mut iov = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)];
+    /// assert_eq!(vm.prog_exec_iov(&mut iov, &mut []), 12);
+    /// ```
+    pub fn prog_exec_iov(&self, iov: &mut [std::io::IoSliceMut], mbuff: &mut [u8]) -> u64 {
+        match self.try_prog_exec_iov(iov, mbuff) {
+            Ok(res) => res,
+            Err(e)  => panic!("{}", e.report(self.prog, self.line_info.as_ref())),
+        }
+    }
+
+    /// Execute the program loaded on packet data scattered over several buffers, in the same way
+    /// as `prog_exec_iov()`, but return runtime errors instead of panicking.
+    pub fn try_prog_exec_iov(&self, iov: &mut [std::io::IoSliceMut], mbuff: &mut [u8]) -> Result<u64, EbpfError> {
+        self.try_exec_segments(iov.iter_mut().map(|seg| &mut **seg as *mut [u8]).collect(), mbuff)
+    }
+
+    // Runs the program on a packet made of `segs`, the first one being packet data. The segments
+    // are borrowed mutably by the caller for the duration of the execution.
+    fn try_exec_segments(&self, segs: Vec<*mut [u8]>, mbuff: &mut [u8]) -> Result<u64, EbpfError> {
+        let (mem, rest) = match segs.split_first() {
+            Some((&mem, rest)) => (unsafe { &*mem }, rest),
+            None               => (&[][..], &[][..]),
+        };
+        let options = interpreter::Options { frags: Some(rest), ..self.interpreter_options(None) };
+        interpreter::try_execute_program(self.prog, mem, mbuff, &self.helpers, options)
+    }
+
+    /// JIT-compile the loaded program. No argument required for this.
+    ///
+    /// If using helper functions, be sure to register them into the VM before calling this
+    /// function.
+    ///
+    /// The machine code is cached for the whole process: VMs compiling the same program, with
+    /// the same helpers, maps and options, share the code compiled by the first one, which saves
+    /// compilation time and memory when the same program is loaded many times, for example as a
+    /// filter for each connection. `stats()` tells whether the code is shared. The memory used by
+    /// the cache can be limited with `set_jit_code_budget()`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
+    /// unknown eBPF operation code.
     ///
     /// # Examples
     ///
     /// ```
     /// let prog = vec![
-    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
-    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
     ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
     /// ];
     ///
-    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    /// // Instantiate a VM.
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
     ///
     /// vm.jit_compile();
+    /// ```
+    pub fn jit_compile(&mut self) {
+        if let Err(err) = self.try_jit_compile() {
+            panic!("{}", err);
+        }
+    }
+
+    /// JIT-compile the loaded program, in the same way as `jit_compile()`, but return an
+    /// `EbpfError::JitError` instead of panicking if the program cannot be compiled.
+    ///
+    /// # Examples
     ///
-    /// let res = vm.prog_exec_jit();
-    /// assert_eq!(res, 0x1122);
     /// ```
-    pub fn prog_exec_jit(&self) -> u64 {
-        self.parent.prog_exec_jit(&mut vec![])
+    /// use rbpf::error::EbpfError;
+    ///
+    /// let prog = vec![
+    ///     0x85, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, // call 0x3f
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // No helper registered for key 0x3f.
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// assert_eq!(vm.try_jit_compile(),
+    ///            Err(EbpfError::JitError("[JIT] Error: unknown helper function (id: 0x3f)".to_string())));
+    /// ```
+    pub fn try_jit_compile(&mut self) -> Result<(), EbpfError> {
+        self.check_jit_settings().map_err(|err| err.in_program(&self.meta))?;
+        let (use_mbuff, update_data_ptr) = self.layout.jit_args();
+        self.jit = jit::compile(self.prog, &self.helpers, &self.maps, use_mbuff, update_data_ptr,
+                                self.div_by_zero, self.frame_pointer(), self.intrinsics,
+                                self.jit_harden, self.jit_huge_pages, self.jit_layout().as_deref())
+            .map_err(|err| err.in_program(&self.meta))?;
+        Ok(())
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, a handle independent from the VM,
+    /// that can be sent to and run by other threads. Helpers must be registered before calling
+    /// this function.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the program cannot be compiled, see `try_jit_program()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let program = Arc::new(rbpf::EbpfVmMbuff::new(&prog).jit_program());
+    ///
+    /// let worker = {
+    ///     let program = program.clone();
+    ///     thread::spawn(move || {
+    ///         let mut mem = vec![0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd];
+    ///         let mut mbuff = vec![0u8; 32];
+    ///         mbuff[8..16].copy_from_slice(&(mem.as_ptr() as u64).to_ne_bytes());
+    ///         program.prog_exec(&mut mem, &mut mbuff)
+    ///     })
+    /// };
+    /// assert_eq!(worker.join().unwrap(), 0x2211);
+    /// ```
+    pub fn jit_program(&self) -> JitProgram {
+        match self.try_jit_program() {
+            Ok(program) => program,
+            Err(err)    => panic!("{}", err),
+        }
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, in the same way as `jit_program()`,
+    /// but return an `EbpfError::JitError` instead of panicking if the program cannot be
+    /// compiled.
+    pub fn try_jit_program(&self) -> Result<JitProgram, EbpfError> {
+        self.check_jit_settings()?;
+        let (use_mbuff, update_data_ptr) = self.layout.jit_args();
+        let code = jit::compile_code(self.prog, &self.helpers, &self.maps, use_mbuff, update_data_ptr,
+                                     self.div_by_zero, None, self.intrinsics, self.jit_harden,
+                                     self.jit_huge_pages, self.jit_layout().as_deref())?;
+        Ok(JitProgram { code, layout: self.layout })
+    }
+
+    /// JIT-compile the loaded program in a background thread, while `prog_exec()` keeps
+    /// interpreting it. As soon as the compilation succeeds, `prog_exec()` switches to the
+    /// JIT-compiled program, so that latency-sensitive applications can start running the
+    /// program immediately and benefit from the JIT-compiler once it is done.
+    ///
+    /// The switch does not happen while features run by the interpreter only are enabled on the
+    /// VM, such as a helper hook, memory helpers, probe regions, an event sink, a scratch storage,
+    /// a configuration store or maps, since the JIT-compiled program would not behave the same. `try_prog_exec()` and
+    /// `prog_exec_trace()` always interpret the program. Remember that JIT-compiled programs do
+    /// not check memory accesses, see `prog_exec_jit()`.
+    ///
+    /// Helpers must be registered before calling this function. If the compilation fails, the VM
+    /// keeps interpreting the program, and `wait_jit_compile()` returns the error. Loading a new
+    /// program with `set_prog()` cancels the switch. See also `set_jit_threshold()`, to start the
+    /// compilation once the program has run a number of times.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    /// let mut mbuff = vec![0u8; 32];
+    /// mbuff[8..16].copy_from_slice(&(mem.as_ptr() as u64).to_ne_bytes());
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.jit_compile_async();
+    ///
+    /// // Interpreted, unless the compilation is already done.
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut mbuff), 0x2211);
+    ///
+    /// vm.wait_jit_compile().unwrap();
+    /// assert!(vm.jit_ready());
+    /// assert_eq!(vm.prog_exec(&mut mem, &mut mbuff), 0x2211);
+    /// ```
+    pub fn jit_compile_async(&mut self) {
+        self.jit_async = OnceLock::from(self.spawn_jit_compile());
+    }
+
+    fn spawn_jit_compile(&self) -> AsyncJit {
+        let (prog, helpers, div_by_zero) = (self.prog.clone(), self.helpers.clone(), self.div_by_zero);
+        let (intrinsics, harden, maps) = (self.intrinsics, self.jit_harden, self.maps.clone());
+        let (huge_pages, layout) = (self.jit_huge_pages, self.jit_layout());
+        let (use_mbuff, update_data_ptr) = self.layout.jit_args();
+        let frame_pointer = self.frame_pointer();
+        let checked = self.check_jit_settings();
+        let result = Arc::new(OnceLock::new());
+        let compiled = result.clone();
+        let thread = std::thread::spawn(move || {
+            let _ = compiled.set(checked.and_then(|()| {
+                jit::compile(&prog, &helpers, &maps, use_mbuff, update_data_ptr, div_by_zero,
+                             frame_pointer, intrinsics, harden, huge_pages, layout.as_deref())
+            }));
+        });
+        AsyncJit { result, thread: Mutex::new(Some(thread)) }
+    }
+
+    // Check that the JIT-compiled program respects the settings that it does not enforce at run
+    // time, see `set_insn_limit()` and `set_read_only_packet()`.
+    fn check_jit_settings(&self) -> Result<(), EbpfError> {
+        if let Some(limit) = self.insn_limit {
+            let worst = analysis::worst_case(self.prog, &HashMap::new())
+                .map_err(|err| EbpfError::JitError(format!("[JIT] Error: {}, under an instruction limit", err)))?;
+            if worst.insns > limit {
+                return Err(EbpfError::JitError(format!(
+                    "[JIT] Error: program may execute {} instructions, more than the limit of {}",
+                    worst.insns, limit)));
+            }
+        }
+        if self.read_only_packet {
+            let stack_stores = verifier::check_bounds(self.prog, self.ctx_len).is_ok_and(|report| {
+                report.accesses.iter().all(|access| !access.write || access.region == verifier::Region::Stack)
+            });
+            if !stack_stores {
+                return Err(EbpfError::JitError(
+                    "[JIT] Error: program may store to packet data, which is read-only".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the compilation started with `jit_compile_async()`, or after crossing the
+    /// threshold set with `set_jit_threshold()`, has succeeded.
+    pub fn jit_ready(&self) -> bool {
+        self.jit_async.get().is_some_and(|jit| matches!(jit.result.get(), Some(Ok(_))))
+    }
+
+    /// Make `prog_exec()` start JIT-compiling the program in the background, as with
+    /// `jit_compile_async()`, once it has interpreted the program `runs` times; or never, with
+    /// `None`, the default. Callers do not have to choose between the interpreter and the
+    /// JIT-compiler upfront: programs run only a few times are interpreted, and the ones run
+    /// often are JIT-compiled. The runs are counted from the loading of the program, see
+    /// `interpreted_runs()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov r0, 0x2a
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    /// vm.set_jit_threshold(Some(100));
+    ///
+    /// for _ in 0..100 {
+    ///     assert_eq!(vm.prog_exec(&mut vec![], &mut vec![]), 0x2a);
+    /// }
+    /// assert_eq!(vm.interpreted_runs(), 100);
+    ///
+    /// // The compilation started with the 100th run.
+    /// vm.wait_jit_compile().unwrap();
+    /// assert!(vm.jit_ready());
+    /// ```
+    pub fn set_jit_threshold(&mut self, runs: Option<u64>) {
+        self.jit_threshold = runs;
+    }
+
+    /// The number of times `prog_exec()` interpreted the program since it was loaded.
+    pub fn interpreted_runs(&self) -> u64 {
+        self.interpreted_runs.load(Ordering::Relaxed)
+    }
+
+    /// Run the JIT-compiled program on a stack mapped between two inaccessible guard pages,
+    /// instead of on the stack of the calling thread, or stop doing so. With the guard, a program
+    /// writing past the bottom of its stack faults at once, instead of silently corrupting the
+    /// memory next to it; accesses above the frame pointer fault once past the page holding the
+    /// stack. The interpreter checks the accesses to the stack anyway, and is not affected.
+    ///
+    /// Changing the setting discards the JIT-compiled program (including one compiled in the
+    /// background), which must be compiled again. Handles returned by `jit_program()`, that
+    /// several threads may run at the same time, always use the stack of the calling thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x01, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r1, 42
+    ///     0x7b, 0x1a, 0x00, 0xfe, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-0x200], r1
+    ///     0x79, 0xa0, 0x00, 0xfe, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-0x200]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    /// vm.set_stack_guard(true);
+    /// vm.jit_compile();
+    /// assert_eq!(vm.prog_exec_jit(), 42);
+    /// ```
+    pub fn set_stack_guard(&mut self, enabled: bool) {
+        self.jit_stack = match enabled {
+            true  => Some(jit::GuardedStack::new()),
+            false => None,
+        };
+        // The compiled programs use the previous stack.
+        self.jit = jit::Compiled::uncached(no_jit);
+        self.jit_async = OnceLock::new();
+    }
+
+    fn frame_pointer(&self) -> Option<u64> {
+        self.jit_stack.as_ref().map(|stack| stack.frame_pointer())
+    }
+
+    /// Catch the memory faults (`SIGSEGV`) and arithmetic faults (`SIGFPE`) raised by the
+    /// JIT-compiled program, instead of letting them kill the process. `try_prog_exec_jit()` then
+    /// returns them as an `EbpfError::MemoryFault`, with the address of the faulty native
+    /// instruction and the index of the eBPF instruction it was compiled from, and the other
+    /// functions running the JIT-compiled program panic. The signal handler is installed on the
+    /// first run, and passes the faults raised out of the JIT-compiled programs, including in
+    /// helpers, to the handler installed before it. Only available on Linux on x86_64: on other
+    /// systems and architectures, faults are not caught.
+    ///
+    /// Faults only occur on accesses to unmapped memory, or to the guard pages of the stack with
+    /// `set_stack_guard()`; other invalid accesses silently read or corrupt memory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::error::EbpfError;
+    ///
+    /// let prog = vec![
+    ///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+    ///     0x79, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    /// vm.set_catch_faults(true);
+    /// vm.jit_compile();
+    /// assert!(matches!(vm.try_prog_exec_jit(), Err(EbpfError::MemoryFault { ebpf_pc: 1, .. })));
+    /// ```
+    pub fn set_catch_faults(&mut self, enabled: bool) {
+        self.catch_faults = enabled;
+    }
+
+    /// Wait for the compilation started with `jit_compile_async()` to complete. The compiled
+    /// program is then also run by `prog_exec_jit()`. Returns immediately if no compilation was
+    /// started.
+    ///
+    /// # Errors
+    ///
+    /// Returns the `EbpfError::JitError` of the compilation, if it failed.
+    pub fn wait_jit_compile(&mut self) -> Result<(), EbpfError> {
+        let jit = match self.jit_async.get_mut() {
+            Some(jit) => jit,
+            None      => return Ok(()),
+        };
+        if let Some(thread) = jit.thread.get_mut().unwrap().take() {
+            thread.join().unwrap_or_else(|payload| std::panic::resume_unwind(payload));
+        }
+        match jit.result.get() {
+            Some(Ok(compiled)) => {
+                self.jit = *compiled;
+                Ok(())
+            },
+            Some(Err(err)) => Err(err.clone()),
+            None           => unreachable!(),
+        }
+    }
+
+    // Called by `prog_exec()`: the program compiled in the background, if the compilation has
+    // succeeded and no feature run by the interpreter only is enabled. Otherwise the program is
+    // interpreted: counts the run, and starts the compilation if the threshold is crossed.
+    fn async_jit(&self) -> Option<jit::Compiled> {
+        let interpreter_only = self.helper_hook.is_some() || self.mem_helpers || !self.probe_regions.is_empty() ||
+            self.event_sink.is_some() || self.scratch.is_some() || !self.config.is_empty() || !self.maps.is_empty() ||
+            self.memory_poisoning;
+        if let Some(&Ok(compiled)) = self.jit_async.get().and_then(|jit| jit.result.get()) {
+            if !interpreter_only {
+                return Some(compiled);
+            }
+        }
+        let runs = self.interpreted_runs.fetch_add(1, Ordering::Relaxed) + 1;
+        if !interpreter_only && self.jit_threshold.is_some_and(|threshold| runs >= threshold) {
+            self.jit_async.get_or_init(|| self.spawn_jit_compile());
+        }
+        None
+    }
+
+    /// Execute the previously JIT-compiled program, with the given packet data and metadata
+    /// buffer, in a manner very similar to `prog_exec()`.
+    ///
+    /// If the program is made to be compatible with Linux kernel, it is expected to load the
+    /// address of the beginning and of the end of the memory area used for packet data from the
+    /// metadata buffer, at some appointed offsets. It is up to the user to ensure that these
+    /// pointers are correctly stored in the buffer.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::ctx::CtxBytes;
+    ///
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into r1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// // Just for the example we create our metadata buffer from scratch, and we store the
+    /// // pointers to packet data start and end in it.
+    /// let mut mbuff = vec![0u8; 32];
+    /// mbuff.write_u64_le(8, mem.as_ptr() as u64);
+    /// mbuff.write_u64_le(24, mem.as_ptr() as u64 + mem.len() as u64);
+    ///
+    /// // Instantiate a VM.
+    /// let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    ///
+    /// vm.jit_compile();
+    ///
+    /// // Provide both a reference to the packet data, and to the metadata buffer.
+    /// let res = vm.prog_exec_jit(&mut mem, &mut mbuff);
+    /// assert_eq!(res, 0x2211);
+    /// ```
+    pub fn prog_exec_jit(&self, mem: &mut std::vec::Vec<u8>, mbuff: &'a mut std::vec::Vec<u8>) -> u64 {
+        self.exec_jit(self.jit, mem, mbuff)
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking.
+    pub fn try_prog_exec_jit(&self, mem: &mut [u8], mbuff: &mut [u8]) -> Result<u64, EbpfError> {
+        self.try_exec_jit(self.jit, mem, mbuff)
+    }
+
+    fn exec_jit(&self, jit: jit::Compiled, mem: &mut [u8], mbuff: &mut [u8]) -> u64 {
+        match self.try_exec_jit(jit, mem, mbuff) {
+            Ok(res) => res,
+            Err(e)  => panic!("{}", e.report(self.prog, self.line_info.as_ref())),
+        }
+    }
+
+    fn try_exec_jit(&self, jit: jit::Compiled, mem: &mut [u8], mbuff: &mut [u8]) -> Result<u64, EbpfError> {
+        if self.helper_hook.is_some() {
+            panic!("Error: cannot run a JIT-compiled program with a helper hook attached");
+        }
+        let _code = match jit::acquire(jit) {
+            Some(code) => code,
+            // Evicted from the cache of compiled programs, see `set_jit_code_budget()`
+            None       => {
+                return interpreter::try_execute_program(self.prog, mem, mbuff, &self.helpers,
+                                                        self.interpreter_options(None));
+            },
+        };
+        let _depth = interpreter::enter(self.max_call_depth).map_err(|err| err.in_program(&self.meta))?;
+        jit::run(jit.entry, self.catch_faults, |jit| JitFunction::new(jit, 0, 0).call(mem, mbuff))
+            .map_err(|err| err.in_program(&self.meta))
+    }
+
+    fn interpreter_options<'b>(&'b self, tracer: Option<&'b mut dyn trace::Tracer>) -> interpreter::Options<'a, 'b> {
+        interpreter::Options {
+            helper_hook: self.helper_hook.as_deref(),
+            frame_helpers: Some(&self.frame_helpers),
+            fallible_helpers: Some(&self.fallible_helpers),
+            tail_calls:  None,
+            tracer,
+            line_info:   self.line_info.as_ref(),
+            mem_helpers: self.mem_helpers,
+            probe_regions: &self.probe_regions,
+            event_sink:  self.event_sink.as_deref(),
+            frags:       None,
+            scratch:     self.scratch.as_ref(),
+            config:      Some(&self.config).filter(|config| !config.is_empty()),
+            maps:        &self.maps,
+            redirect:    None,
+            div_by_zero: self.div_by_zero,
+            proven_accesses: &self.proven_accesses,
+            program:     Some(&self.meta).filter(|meta| !meta.is_empty()),
+            max_call_depth: Some(self.max_call_depth),
+            poison:      self.memory_poisoning,
+            packet:      None,
+            metering:    None,
+            insn_limit:  self.insn_limit,
+            read_only_packet: self.read_only_packet,
+        }
+    }
+}
+
+// The methods shared by all the VMs wrapping an `EbpfVmMbuff`, which set up the execution of
+// the program and do not depend on how it receives its data: they are forwarded to the
+// `EbpfVmMbuff` in field `parent`, whose memory layout tells the JIT-compiler how to compile the
+// program. Expanded in the `impl` block of each VM, with the lifetime of the VM as argument.
+// `EbpfVmFixedMbuff`, which accounts for its metadata buffer in `stats()` and fills it according
+// to the settings, passes `own_settings` as well, and defines the methods of the settings itself.
+macro_rules! vm_wrapper_methods {
+    ($a:lifetime) => {
+        /// Report the resources used by the VM. See `EbpfVmMbuff::stats()`.
+        pub fn stats(&self) -> VmStats {
+            self.parent.stats()
+        }
+
+        /// Enable or disable memory poisoning in the interpreter, to catch the loads of bytes of the
+        /// stack never written. See `EbpfVmMbuff::set_memory_poisoning()`.
+        pub fn set_memory_poisoning(&mut self, poison: bool) {
+            self.parent.set_memory_poisoning(poison);
+        }
+
+        /// Apply the settings of a security profile. See `EbpfVmMbuff::set_security_profile()`.
+        pub fn set_security_profile(&mut self, profile: ebpf::SecurityProfile) {
+            self.parent.set_security_profile(profile);
+        }
+
+        vm_wrapper_methods!($a, own_settings);
+    };
+    ($a:lifetime, own_settings) => {
+        /// Register a built-in or user-defined helper function in order to use it later from within
+        /// the eBPF program. See `EbpfVmMbuff::register_helper()`.
+        pub fn register_helper(&mut self, key: u32, function: fn (u64, u64, u64, u64, u64) -> u64) {
+            self.parent.register_helper(key, function);
+        }
+
+        /// Register a helper function taking its arguments in a frame, in the memory of the program.
+        /// See `EbpfVmMbuff::register_frame_helper()`.
+        pub fn register_frame_helper<A: helpers::ArgFrame>(&mut self, key: u32, function: fn(A) -> u64) {
+            self.parent.register_frame_helper(key, function);
+        }
+
+        /// Register a user-defined helper function returning an error to abort the execution of the
+        /// program. See `EbpfVmMbuff::register_fallible_helper()`.
+        pub fn register_fallible_helper(&mut self, key: u32, function: ebpf::FallibleHelper) {
+            self.parent.register_fallible_helper(key, function);
+        }
+
+        /// Make the bounds-checked memory helpers available to the program. See
+        /// `EbpfVmMbuff::register_mem_helpers()`.
+        pub fn register_mem_helpers(&mut self) {
+            self.parent.register_mem_helpers();
+        }
+
+        /// Register a region of memory readable by the program with the `bpf_probe_read*()` helpers.
+        /// See `EbpfVmMbuff::register_probe_region()`.
+        pub fn register_probe_region(&mut self, space: helpers::AddressSpace, addr: u64, data: &$a [u8]) {
+            self.parent.register_probe_region(space, addr, data);
+        }
+
+        /// Attach a consumer to the VM, receiving the events output by the program with helper
+        /// `bpf_perf_event_output()`. See `EbpfVmMbuff::set_event_sink()`.
+        pub fn set_event_sink(&mut self, sink: Box<dyn helpers::EventSink + $a>) {
+            self.parent.set_event_sink(sink);
+        }
+
+        /// Give the VM a scratch storage of `size` bytes, kept between executions of the program.
+        /// See `EbpfVmMbuff::set_scratch()`.
+        pub fn set_scratch(&mut self, size: usize) {
+            self.parent.set_scratch(size);
+        }
+
+        /// The contents of the scratch storage of the VM, if any. See `EbpfVmMbuff::scratch_mut()`.
+        pub fn scratch_mut(&mut self) -> Option<&mut [u8]> {
+            self.parent.scratch_mut()
+        }
+
+        /// Give the VM a configuration store, that the program reads with helpers run by the
+        /// interpreter. See `EbpfVmMbuff::set_config()`.
+        pub fn set_config(&mut self, config: HashMap<String, Vec<u8>>) {
+            self.parent.set_config(config);
+        }
+
+        /// The configuration store of the VM. See `EbpfVmMbuff::config_mut()`.
+        pub fn config_mut(&mut self) -> &mut HashMap<String, Vec<u8>> {
+            self.parent.config_mut()
+        }
+
+        /// Register a map, that the program accesses with the map helpers, and return its
+        /// identifier. See `EbpfVmMbuff::register_map()`.
+        pub fn register_map(&mut self, map: Arc<maps::Map>) -> u32 {
+            self.parent.register_map(map)
+        }
+
+        /// Return the maps registered on the VM, indexed by their identifiers. See
+        /// `EbpfVmMbuff::maps()`.
+        pub fn maps(&self) -> &[Arc<maps::Map>] {
+            self.parent.maps()
+        }
+
+        /// Name the program of the VM. See `EbpfVmMbuff::set_name()`.
+        pub fn set_name(&mut self, name: &str) {
+            self.parent.set_name(name);
+        }
+
+        /// Attach a tag to the program of the VM. See `EbpfVmMbuff::set_tag()`.
+        pub fn set_tag(&mut self, key: &str, value: &str) {
+            self.parent.set_tag(key, value);
+        }
+
+        /// The name and tags attached to the VM. See `EbpfVmMbuff::meta()`.
+        pub fn meta(&self) -> &ProgramMeta {
+            self.parent.meta()
+        }
+
+        /// Attach a hook to the VM, invoked by the interpreter on every call to a helper function.
+        /// See `EbpfVmMbuff::set_helper_hook()`.
+        pub fn set_helper_hook(&mut self, hook: Box<dyn helpers::HelperHook + $a>) {
+            self.parent.set_helper_hook(hook);
+        }
+
+        /// Attach an instruction policy to the VM, restricting the instructions allowed in programs.
+        /// See `EbpfVmMbuff::set_insn_policy()`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the policy rejects the program currently loaded.
+        pub fn set_insn_policy(&mut self, policy: Box<dyn verifier::InsnPolicy + $a>) {
+            self.parent.set_insn_policy(policy);
+        }
+
+        /// Set the limits on the size and estimated verification complexity of the programs loaded
+        /// with `set_prog()`. See `EbpfVmMbuff::set_prog_limits()`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the program currently loaded exceeds the limits.
+        pub fn set_prog_limits(&mut self, limits: verifier::ProgLimits) {
+            self.parent.set_prog_limits(limits);
+        }
+
+        /// Attach line information to the VM, so that runtime errors report source locations. See
+        /// `EbpfVmMbuff::set_line_info()`.
+        pub fn set_line_info(&mut self, info: btf::LineInfo) {
+            self.parent.set_line_info(info);
+        }
+
+        /// Select the semantics of divisions and modulos by zero, before JIT-compiling the program.
+        /// See `EbpfVmMbuff::set_div_by_zero()`.
+        pub fn set_div_by_zero(&mut self, semantics: ebpf::DivByZero) {
+            self.parent.set_div_by_zero(semantics);
+        }
+
+        /// Select the built-in helpers that the JIT-compiler inlines, before JIT-compiling the
+        /// program. See `EbpfVmMbuff::set_intrinsics()`.
+        pub fn set_intrinsics(&mut self, intrinsics: helpers::Intrinsics) {
+            self.parent.set_intrinsics(intrinsics);
+        }
+
+        /// Enable or disable the hardening of the JIT-compiled code, before JIT-compiling the
+        /// program. See `EbpfVmMbuff::set_jit_hardening()`.
+        pub fn set_jit_hardening(&mut self, enabled: bool) {
+            self.parent.set_jit_hardening(enabled);
+        }
+
+        /// Enable or disable the backing of the JIT-compiled code with huge pages, before
+        /// JIT-compiling the program. See `EbpfVmMbuff::set_jit_huge_pages()`.
+        pub fn set_jit_huge_pages(&mut self, enabled: bool) {
+            self.parent.set_jit_huge_pages(enabled);
+        }
+
+        /// Give the JIT-compiler a profile of the executions of the program, or remove it, before
+        /// JIT-compiling the program. See `EbpfVmMbuff::set_jit_profile()`.
+        pub fn set_jit_profile(&mut self, profile: Option<profile::Profile>) {
+            self.parent.set_jit_profile(profile);
+        }
+
+        /// Select the version of the instruction set that programs may use. See
+        /// `EbpfVmMbuff::set_isa_version()`.
+        pub fn set_isa_version(&mut self, isa: ebpf::IsaVersion) {
+            self.parent.set_isa_version(isa);
+        }
+
+        /// Set the maximum number of executions of programs nested on the current thread. See
+        /// `EbpfVmMbuff::set_max_call_depth()`.
+        pub fn set_max_call_depth(&mut self, depth: usize) {
+            self.parent.set_max_call_depth(depth);
+        }
+
+        /// Set the maximum number of instructions an execution of the program may run. See
+        /// `EbpfVmMbuff::set_insn_limit()`.
+        pub fn set_insn_limit(&mut self, limit: Option<u64>) {
+            self.parent.set_insn_limit(limit);
+        }
+
+        /// Make packet data read-only for the program, or writable again. See
+        /// `EbpfVmMbuff::set_read_only_packet()`.
+        pub fn set_read_only_packet(&mut self, read_only: bool) {
+            self.parent.set_read_only_packet(read_only);
+        }
+
+        /// JIT-compile the loaded program. See `EbpfVmMbuff::jit_compile()`.
+        ///
+        /// # Panics
+        ///
+        /// This function panics if an error occurs during JIT-compiling, such as the occurrence of an
+        /// unknown eBPF operation code.
+        pub fn jit_compile(&mut self) {
+            self.parent.jit_compile();
+        }
+
+        /// JIT-compile the loaded program, returning an error instead of panicking if it cannot be
+        /// compiled. See `EbpfVmMbuff::try_jit_compile()`.
+        pub fn try_jit_compile(&mut self) -> Result<(), EbpfError> {
+            self.parent.try_jit_compile()
+        }
+
+        /// JIT-compile the loaded program in a background thread, while `prog_exec()` keeps
+        /// interpreting it until the compilation succeeds. See `EbpfVmMbuff::jit_compile_async()`.
+        pub fn jit_compile_async(&mut self) {
+            self.parent.jit_compile_async();
+        }
+
+        /// Whether the compilation started with `jit_compile_async()` has succeeded. See
+        /// `EbpfVmMbuff::jit_ready()`.
+        pub fn jit_ready(&self) -> bool {
+            self.parent.jit_ready()
+        }
+
+        /// Wait for the compilation started with `jit_compile_async()` to complete. See
+        /// `EbpfVmMbuff::wait_jit_compile()`.
+        pub fn wait_jit_compile(&mut self) -> Result<(), EbpfError> {
+            self.parent.wait_jit_compile()
+        }
+
+        /// Make `prog_exec()` start JIT-compiling the program in the background once it has
+        /// interpreted it `runs` times. See `EbpfVmMbuff::set_jit_threshold()`.
+        pub fn set_jit_threshold(&mut self, runs: Option<u64>) {
+            self.parent.set_jit_threshold(runs);
+        }
+
+        /// The number of times `prog_exec()` interpreted the program since it was loaded. See
+        /// `EbpfVmMbuff::interpreted_runs()`.
+        pub fn interpreted_runs(&self) -> u64 {
+            self.parent.interpreted_runs()
+        }
+
+        /// Run the JIT-compiled program on a stack mapped between two inaccessible guard pages, or
+        /// stop doing so. See `EbpfVmMbuff::set_stack_guard()`.
+        pub fn set_stack_guard(&mut self, enabled: bool) {
+            self.parent.set_stack_guard(enabled)
+        }
+
+        /// Catch the faults raised by the JIT-compiled program, instead of letting them kill the
+        /// process. See `EbpfVmMbuff::set_catch_faults()`.
+        pub fn set_catch_faults(&mut self, enabled: bool) {
+            self.parent.set_catch_faults(enabled)
+        }
+    };
+}
+
+/// A virtual machine to run eBPF program. This kind of VM is used for programs expecting to work
+/// on a metadata buffer containing pointers to packet data, but it internally handles the buffer
+/// so as to save the effort to manually handle the metadata buffer for the user.
+///
+/// This struct implements a static internal buffer that is passed to the program. The user has to
+/// indicate the offset values at which the eBPF program expects to find the start and the end of
+/// packet data in the buffer. On calling the `prog_exec()` or `prog_exec_jit()` functions, the
+/// struct automatically updates the addresses in this static buffer, at the appointed offsets, for
+/// the start and the end of the packet data the program is called upon.
+///
+/// # Examples
+///
+/// This was compiled with clang from the following program, in C:
+///
+/// ```c
+/// #include <linux/bpf.h>
+/// #include "path/to/linux/samples/bpf/bpf_helpers.h"
+///
+/// SEC(".classifier")
+/// int classifier(struct __sk_buff *skb)
+/// {
+///   void *data = (void *)(long)skb->data;
+///   void *data_end = (void *)(long)skb->data_end;
+///
+///   // Check program is long enough.
+///   if (data + 5 > data_end)
+///     return 0;
+///
+///   return *((char *)data + 5);
+/// }
+/// ```
+///
+/// Some small modifications have been brought to have it work, see comments. Instead of patching
+/// the program by hand, `ctx::convert_ctx_access()` can rewrite such accesses to the context.
+///
+/// ```
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+///     // Here opcode 0x61 had to be replace by 0x79 so as to load a 8-bytes long address.
+///     // Also, offset 0x4c had to be replace with e.g. 0x40 so as to prevent the two pointers
+///     // from overlapping in the buffer.
+///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load pointer to mem from r1[0x40] to r2
+///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+///     // Here opcode 0x61 had to be replace by 0x79 so as to load a 8-bytes long address.
+///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load ptr to mem_end from r1[0x50] to r1
+///     0x2d, 0x12, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+///     0x67, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, // r0 >>= 56
+///     0xc7, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, // r0 <<= 56 (arsh) extend byte sign to u64
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let mut mem1 = vec![
+///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+/// ];
+/// let mut mem2 = vec![
+///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
+/// ];
+///
+/// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
+/// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+///
+/// // Provide only a reference to the packet data. We do not manage the metadata buffer.
+/// let res = vm.prog_exec(&mut mem1);
+/// assert_eq!(res, 0xffffffffffffffdd);
+///
+/// let res = vm.prog_exec(&mut mem2);
+/// assert_eq!(res, 0x27);
+/// ```
+pub struct EbpfVmFixedMbuff<'a> {
+    parent:     EbpfVmMbuff<'a>,
+    mbuff:      MetaBuff,
+    mbuff_hook: Option<Box<MbuffHook<'a>>>,
+    ifindex:    u32,
+}
+
+impl<'a> EbpfVmFixedMbuff<'a> {
+
+    /// Create a new virtual machine instance, and load an eBPF program into that instance.
+    /// When attempting to load the program, it passes through a simple verifier.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
+    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
+    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// ```
+    pub fn new(prog: &'a std::vec::Vec<u8>, data_offset: usize, data_end_offset: usize) -> EbpfVmFixedMbuff<'a> {
+        EbpfVmFixedMbuff::with_parent(EbpfVmMbuff::new(prog), data_offset, data_end_offset)
+    }
+
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. See `EbpfVmMbuff::from_program()`.
+    pub fn from_program(program: &'a program::Program, data_offset: usize,
+                        data_end_offset: usize) -> EbpfVmFixedMbuff<'a> {
+        EbpfVmFixedMbuff::with_parent(EbpfVmMbuff::from_program(program), data_offset, data_end_offset)
+    }
+
+    fn with_parent(parent: EbpfVmMbuff<'a>, data_offset: usize, data_end_offset: usize) -> EbpfVmFixedMbuff<'a> {
+        let mbuff = MetaBuff {
+            data_offset:     0,
+            data_end_offset: 0,
+            buffer:          vec![],
+        };
+        let mut vm = EbpfVmFixedMbuff {
+            parent,
+            mbuff,
+            mbuff_hook: None,
+            ifindex:    0,
+        };
+        vm.reset_mbuff(data_offset, data_end_offset);
+        vm
+    }
+
+    // Replace the metadata buffer with a new one, for pointers to packet data at the given
+    // offsets.
+    fn reset_mbuff(&mut self, data_offset: usize, data_end_offset: usize) {
+        let buff_len = std::cmp::max(data_offset, data_end_offset) + 8;
+        self.mbuff.buffer = vec![self.mbuff_padding(); buff_len];
+        self.mbuff.data_offset = data_offset;
+        self.mbuff.data_end_offset = data_end_offset;
+        self.parent.set_ctx_len(Some(buff_len));
+        self.parent.layout = self.mbuff.layout();
+    }
+
+    /// Load a new eBPF program into the virtual machine instance.
+    ///
+    /// At the same time, load new offsets for storing pointers to start and end of packet data in
+    /// the internal metadata buffer.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog1 = vec![
+    ///     0xb7, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let prog2 = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
+    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
+    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27,
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog1, 0, 0);
+    /// vm.set_prog(&prog2, 0x40, 0x50);
+    ///
+    /// let res = vm.prog_exec(&mut mem);
+    /// assert_eq!(res, 0x27);
+    /// ```
+    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>, data_offset: usize, data_end_offset: usize) {
+        self.reset_mbuff(data_offset, data_end_offset);
+        self.parent.set_prog(prog)
+    }
+
+    /// Change the offsets for storing pointers to start and end of packet data in the internal
+    /// metadata buffer, without reloading nor verifying the program again. The buffer grows if
+    /// needed, and keeps its contents.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x79, 0x12, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x10] to r2
+    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+    ///     0x79, 0x11, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x18] to r1
+    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem1 = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27,
+    /// ];
+    /// let mut mem2 = mem1.clone();
+    ///
+    /// // The program does not find the pointers to packet data where it expects them.
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// assert_eq!(vm.prog_exec(&mut mem1), 0);
+    ///
+    /// vm.set_mbuff_offsets(0x10, 0x18);
+    /// assert_eq!(vm.prog_exec(&mut mem2), 0x27);
+    /// ```
+    pub fn set_mbuff_offsets(&mut self, data_offset: usize, data_end_offset: usize) {
+        let buff_len = std::cmp::max(data_offset, data_end_offset) + 8;
+        if self.mbuff.buffer.len() < buff_len {
+            let padding = self.mbuff_padding();
+            self.mbuff.buffer.resize(buff_len, padding);
+            self.parent.set_ctx_len(Some(buff_len));
+        }
+        self.mbuff.data_offset = data_offset;
+        self.mbuff.data_end_offset = data_end_offset;
+        self.parent.layout = self.mbuff.layout();
+    }
+
+    vm_wrapper_methods!('a, own_settings);
+
+    /// Report the resources used by the VM, including its metadata buffer. See
+    /// `EbpfVmMbuff::stats()`.
+    pub fn stats(&self) -> VmStats {
+        VmStats { mbuff_size: self.mbuff.buffer.len(), ..self.parent.stats() }
+    }
+
+    /// Enable or disable memory poisoning in the interpreter, to catch the loads of bytes of the
+    /// stack never written. See `EbpfVmMbuff::set_memory_poisoning()`.
+    ///
+    /// The bytes of the metadata buffer, other than the pointers to packet data set before each
+    /// execution, are filled with `ebpf::POISON_BYTE` when poisoning is enabled, and with zeroes
+    /// when it is disabled.
+    pub fn set_memory_poisoning(&mut self, poison: bool) {
+        self.parent.set_memory_poisoning(poison);
+        let padding = self.mbuff_padding();
+        self.mbuff.buffer.iter_mut().for_each(|byte| *byte = padding);
+    }
+
+    // The value of the bytes of the metadata buffer not set by the VM.
+    fn mbuff_padding(&self) -> u8 {
+        match self.parent.memory_poisoning {
+            true  => ebpf::POISON_BYTE,
+            false => 0,
+        }
+    }
+
+    /// Apply the settings of a security profile. See `EbpfVmMbuff::set_security_profile()`. The
+    /// metadata buffer is refilled as with `set_memory_poisoning()`.
+    pub fn set_security_profile(&mut self, profile: ebpf::SecurityProfile) {
+        self.parent.set_security_profile(profile);
+        self.set_memory_poisoning(profile == ebpf::SecurityProfile::Strict);
+    }
+
+    /// Register a closure filling the metadata buffer before each execution of the program,
+    /// with the JIT-compiled program as well as with the interpreter.
+    ///
+    /// The closure receives the metadata buffer and a description of the packet, from which it
+    /// can derive additional fields such as the packet length, the interface index or the VLAN
+    /// tag. The pointers to the start and the end of packet data are written after the closure
+    /// returns, so the closure cannot overwrite them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x61, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r1] (packet length)
+    ///     0x69, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r1, [r1+8] (vlan tci)
+    ///     0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x11, // destination, source MAC
+    ///     0x22, 0x33, 0x44, 0x55, 0x81, 0x00, 0x00, 0x2a, // 802.1Q tag, VLAN 42
+    ///     0x08, 0x00                                      // EtherType
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    /// vm.set_mbuff_hook(Box::new(|mbuff: &mut [u8], info: &rbpf::PacketInfo| {
+    ///     mbuff[0..4].copy_from_slice(&(info.len as u32).to_le_bytes());
+    ///     mbuff[8..10].copy_from_slice(&info.vlan_tci.unwrap_or(0).to_le_bytes());
+    /// }));
+    ///
+    /// assert_eq!(vm.prog_exec(&mut mem), 18 + 42);
+    /// ```
+    pub fn set_mbuff_hook(&mut self, hook: Box<MbuffHook<'a>>) {
+        self.mbuff_hook = Some(hook);
+    }
+
+    /// Set the interface index reported to the hook registered with `set_mbuff_hook()`. It
+    /// defaults to 0.
+    pub fn set_ifindex(&mut self, ifindex: u32) {
+        self.ifindex = ifindex;
+    }
+
+    /// Execute the program loaded, with the given packet data.
+    ///
+    /// If the program is made to be compatible with Linux kernel, it is expected to load the
+    /// address of the beginning and of the end of the memory area used for packet data from some
+    /// metadata buffer, which in the case of this VM is handled internally. The offsets at which
+    /// the addresses should be placed should have be set at the creation of the VM.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
+    /// in the future (we could raise errors instead).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
+    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
+    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    ///
+    /// // Provide only a reference to the packet data. We do not manage the metadata buffer.
+    /// let res = vm.prog_exec(&mut mem);
+    /// assert_eq!(res, 0xdd);
+    /// ```
+    #[allow(clippy::ptr_arg)] // Same signature as `EbpfVmMbuff::prog_exec()`.
+    pub fn prog_exec(&mut self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
+        if let Some(jit) = self.parent.async_jit() {
+            return self.exec_jit(jit, mem);
+        }
+        self.update_mbuff_pointers(mem, mem.len());
+        interpreter::execute_program(self.parent.prog, mem, &self.mbuff.buffer, &self.parent.helpers,
+                                     self.parent.interpreter_options(None))
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. See `EbpfVmMbuff::try_prog_exec()`.
+    pub fn try_prog_exec(&mut self, mem: &'a mut [u8]) -> Result<u64, EbpfError> {
+        self.update_mbuff_pointers(mem, mem.len());
+        self.parent.try_prog_exec(mem, &mut self.mbuff.buffer)
+    }
+
+    /// Execute the loaded XDP program, and return the value in R0 as an XDP action. See
+    /// `EbpfVmMbuff::prog_exec_xdp()`.
+    pub fn prog_exec_xdp(&mut self, mem: &'a mut [u8]) -> ebpf::XdpAction {
+        match self.try_prog_exec_xdp(mem) {
+            Ok(action) => action,
+            Err(err)   => panic!("{}", err),
+        }
+    }
+
+    /// Execute the loaded XDP program, and return the value in R0 as an XDP action, or an error.
+    /// See `EbpfVmMbuff::try_prog_exec_xdp()`.
+    pub fn try_prog_exec_xdp(&mut self, mem: &'a mut [u8]) -> Result<ebpf::XdpAction, EbpfError> {
+        self.try_prog_exec(mem).and_then(ebpf::XdpAction::from_ret)
+    }
+
+    /// Execute the loaded XDP program, and return the XDP action along with the redirect
+    /// requested by the program, if any. See `EbpfVmMbuff::prog_exec_xdp_redirect()`.
+    pub fn prog_exec_xdp_redirect(&mut self, mem: &'a mut [u8]) -> (ebpf::XdpAction, Option<maps::Redirect>) {
+        match self.try_prog_exec_xdp_redirect(mem) {
+            Ok(res)  => res,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Execute the loaded XDP program, and return the XDP action along with the redirect
+    /// requested by the program, if any, or an error. See
+    /// `EbpfVmMbuff::try_prog_exec_xdp_redirect()`.
+    pub fn try_prog_exec_xdp_redirect(&mut self, mem: &'a mut [u8])
+                                      -> Result<(ebpf::XdpAction, Option<maps::Redirect>), EbpfError> {
+        self.update_mbuff_pointers(mem, mem.len());
+        self.parent.try_prog_exec_xdp_redirect(mem, &mut self.mbuff.buffer)
+    }
+
+    /// Execute the loaded tc classifier, and return the value in R0 as a tc action. See
+    /// `EbpfVmMbuff::prog_exec_tc()`.
+    pub fn prog_exec_tc(&mut self, mem: &'a mut [u8]) -> ebpf::TcAction {
+        match self.try_prog_exec_tc(mem) {
+            Ok(action) => action,
+            Err(err)   => panic!("{}", err),
+        }
+    }
+
+    /// Execute the loaded tc classifier, and return the value in R0 as a tc action, or an error.
+    /// See `EbpfVmMbuff::try_prog_exec_tc()`.
+    pub fn try_prog_exec_tc(&mut self, mem: &'a mut [u8]) -> Result<ebpf::TcAction, EbpfError> {
+        self.try_prog_exec(mem).and_then(ebpf::TcAction::from_ret)
+    }
+
+    /// Run the program as command `BPF_PROG_TEST_RUN` of the kernel does, see
+    /// `EbpfVmMbuff::test_run()`. The metadata buffer is a copy of the buffer of the VM, starting
+    /// with `ctx_in`, in which the pointers to the start and end of a copy of `data_in` are
+    /// stored at the offsets of the VM. In the metadata buffer returned, they are replaced with
+    /// their offsets in packet data, 0 and the length of the data, as the kernel does for
+    /// `struct xdp_md`. The metadata buffer hook is not run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // Returns the length of the packet.
+    /// let prog = vec![
+    ///     0x79, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r1] (data)
+    ///     0x79, 0x10, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+8] (data_end)
+    ///     0x1f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // sub64 r0, r2
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    /// let run = vm.test_run(&[0; 60], &[], 1).unwrap();
+    /// assert_eq!(run.retval, 60);
+    /// assert_eq!(&run.ctx_out[8..16], &60u64.to_ne_bytes());
+    /// ```
+    pub fn test_run(&self, data_in: &[u8], ctx_in: &[u8], repeat: u32) -> Result<testing::TestRun, EbpfError> {
+        let data = data_in.to_vec();
+        let mut ctx = self.mbuff.buffer.clone();
+        if ctx.len() < ctx_in.len() {
+            ctx.resize(ctx_in.len(), 0);
+        }
+        ctx[..ctx_in.len()].copy_from_slice(ctx_in);
+        let (data_offset, data_end_offset) = (self.mbuff.data_offset, self.mbuff.data_end_offset);
+        let start = data.as_ptr() as u64;
+        ctx[data_offset..data_offset + 8].copy_from_slice(&start.to_ne_bytes());
+        ctx[data_end_offset..data_end_offset + 8].copy_from_slice(&(start + data.len() as u64).to_ne_bytes());
+        let mut run = self.parent.test_run_buffers(data, ctx, repeat)?;
+        let len = run.data_out.len() as u64;
+        run.ctx_out[data_offset..data_offset + 8].copy_from_slice(&0u64.to_ne_bytes());
+        run.ctx_out[data_end_offset..data_end_offset + 8].copy_from_slice(&len.to_ne_bytes());
+        Ok(run)
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See `EbpfVmMbuff::prog_exec_trace()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r1
+    ///     0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    ///
+    /// let mut log = rbpf::trace::TraceLog::new();
+    /// assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), 0xbb);
+    /// assert_eq!(log.entries().len(), 3);
+    /// ```
+    pub fn prog_exec_trace(&mut self, mem: &'a mut [u8], tracer: &mut dyn trace::Tracer) -> u64 {
+        self.update_mbuff_pointers(mem, mem.len());
+        self.parent.prog_exec_trace(mem, &mut self.mbuff.buffer, tracer)
+    }
+
+    /// Execute the program loaded on a multi-buffer packet, made of several non-contiguous
+    /// fragments. The pointers to the start and the end of packet data stored in the metadata
+    /// buffer are those of the first fragment. See `EbpfVmMbuff::prog_exec_frags()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // Return the total length of the packet.
+    /// let prog = vec![
+    ///     0x85, 0x00, 0x00, 0x00, 0xbc, 0x00, 0x00, 0x00, // call bpf_xdp_get_buff_len
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut frag1 = vec![0xaa; 16];
+    /// let mut frag2 = vec![0xbb; 5];
+    ///
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    ///
+    /// assert_eq!(vm.prog_exec_frags(&mut [&mut frag1, &mut frag2]), 21);
+    /// ```
+    pub fn prog_exec_frags(&mut self, frags: &mut [&mut [u8]]) -> u64 {
+        let len = frags.iter().map(|frag| frag.len()).sum();
+        self.update_mbuff_pointers(frags.first().map_or(&[], |frag| &**frag), len);
+        self.parent.prog_exec_frags(frags, &mut self.mbuff.buffer)
+    }
+
+    /// Execute the program loaded on a multi-buffer packet, in the same way as
+    /// `prog_exec_frags()`, but return runtime errors instead of panicking.
+    pub fn try_prog_exec_frags(&mut self, frags: &mut [&mut [u8]]) -> Result<u64, EbpfError> {
+        let len = frags.iter().map(|frag| frag.len()).sum();
+        self.update_mbuff_pointers(frags.first().map_or(&[], |frag| &**frag), len);
+        self.parent.try_prog_exec_frags(frags, &mut self.mbuff.buffer)
+    }
+
+    /// Execute the program loaded on a packet that it can resize within its buffer, with the
+    /// helpers `bpf_xdp_adjust_head()`, `bpf_xdp_adjust_tail()` and `bpf_skb_adjust_room()` (see
+    /// `helpers::BPF_XDP_ADJUST_HEAD_IDX`), to test encapsulation programs for example. The
+    /// helpers update the pointers to the start and the end of packet data in the metadata
+    /// buffer, and the interpreter checks the accesses of the program against the packet data
+    /// resized. On return, the data of `packet` is the packet data left by the program.
+    ///
+    /// The program is always interpreted, even if it was JIT-compiled.
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same errors as `try_prog_exec_resizable()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // Pushes a 4-byte header, and returns the length of the packet.
+    /// let prog = vec![
+    ///     0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+    ///     0xb7, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // mov64 r2, -4
+    ///     0x85, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, // call bpf_xdp_adjust_head
+    ///     0x79, 0x62, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r6+0x40] (data)
+    ///     0x79, 0x60, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r6+0x50] (data_end)
+    ///     0x62, 0x02, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11, // stw [r2], 0x11223344
+    ///     0x1f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // sub64 r0, r2
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut packet = rbpf::PacketBuffer::new(&[0xaa; 60], 16, 0);
+    ///
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    ///
+    /// assert_eq!(vm.prog_exec_resizable(&mut packet), 64);
+    /// assert_eq!(&packet.data()[..5], &[0x44, 0x33, 0x22, 0x11, 0xaa]);
+    /// assert_eq!(packet.headroom(), 12);
+    /// ```
+    pub fn prog_exec_resizable(&mut self, packet: &mut PacketBuffer) -> u64 {
+        match self.try_prog_exec_resizable(packet) {
+            Ok(res)  => res,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Execute the program loaded on a packet that it can resize, in the same way as
+    /// `prog_exec_resizable()`, but return runtime errors instead of panicking. The packet keeps
+    /// the size it had when the error occurred.
+    pub fn try_prog_exec_resizable(&mut self, packet: &mut PacketBuffer) -> Result<u64, EbpfError> {
+        let window = Cell::new((packet.start, packet.end));
+        let res = {
+            let mem = &packet.buffer[packet.start..packet.end];
+            self.update_mbuff_pointers(mem, mem.len());
+            let resizable = interpreter::ResizablePacket {
+                buffer:       &packet.buffer,
+                window:       &window,
+                data_offsets: (self.mbuff.data_offset, self.mbuff.data_end_offset),
+            };
+            // The bounds proven by the verifier do not hold once the packet is resized.
+            let options = interpreter::Options {
+                packet:          Some(resizable),
+                proven_accesses: &[],
+                ..self.parent.interpreter_options(None)
+            };
+            interpreter::try_execute_program(self.parent.prog, mem, &self.mbuff.buffer, &self.parent.helpers, options)
+        };
+        let (start, end) = window.get();
+        packet.start = start;
+        packet.end = end;
+        res
+    }
+
+    fn run_mbuff_hook(&mut self, mem: &[u8], len: usize) {
+        if let Some(ref mut hook) = self.mbuff_hook {
+            hook(&mut self.mbuff.buffer, &PacketInfo::new(mem, len, self.ifindex));
+        }
+    }
+
+    fn update_mbuff_pointers(&mut self, mem: &[u8], len: usize) {
+        self.run_mbuff_hook(mem, len);
+        let l = self.mbuff.buffer.len();
+        // Can this ever happen? Probably not, should be ensured at mbuff creation.
+        if self.mbuff.data_offset + 8 > l || self.mbuff.data_end_offset + 8 > l {
+            panic!("Error: buffer too small ({:?}), cannot use data_offset {:?} and data_end_offset {:?}",
+            l, self.mbuff.data_offset, self.mbuff.data_end_offset);
+        }
+        self.mbuff.write_pointers(mem);
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, a handle independent from the VM.
+    /// The handle keeps the offsets of the pointers to packet data, and runs the program on a new
+    /// metadata buffer at each call. See `EbpfVmMbuff::jit_program()`.
+    pub fn jit_program(&self) -> JitProgram {
+        match self.try_jit_program() {
+            Ok(program) => program,
+            Err(err)    => panic!("{}", err),
+        }
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, returning an error instead of
+    /// panicking if it cannot be compiled. See `EbpfVmMbuff::try_jit_program()`.
+    pub fn try_jit_program(&self) -> Result<JitProgram, EbpfError> {
+        self.parent.try_jit_program()
+    }
+
+    /// Execute the previously JIT-compiled program, with the given packet data, in a manner very
+    /// similar to `prog_exec()`.
+    ///
+    /// If the program is made to be compatible with Linux kernel, it is expected to load the
+    /// address of the beginning and of the end of the memory area used for packet data from some
+    /// metadata buffer, which in the case of this VM is handled internally. The offsets at which
+    /// the addresses should be placed should have be set at the creation of the VM.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem from r1[0x40] to r2
+    ///     0x07, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // add r2, 5
+    ///     0x79, 0x11, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // load mem_end from r1[0x50] to r1
+    ///     0x2d, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // if r2 > r1 skip 3 instructions
+    ///     0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // load r2 (= *(mem + 5)) into r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// // Instantiate a VM. Note that we provide the start and end offsets for mem pointers.
+    /// let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    ///
+    /// vm.jit_compile();
+    ///
+    /// // Provide only a reference to the packet data. We do not manage the metadata buffer.
+    /// let res = vm.prog_exec_jit(&mut mem);
+    /// assert_eq!(res, 0xdd);
+    /// ```
+    // This struct redefines the `prog_exec_jit()` function, in order to pass the offsets
+    // associated with the fixed mbuff.
+    pub fn prog_exec_jit(&mut self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
+        self.exec_jit(self.parent.jit, mem)
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking. See
+    /// `EbpfVmMbuff::try_prog_exec_jit()`.
+    pub fn try_prog_exec_jit(&mut self, mem: &mut [u8]) -> Result<u64, EbpfError> {
+        self.try_exec_jit(self.parent.jit, mem)
+    }
+
+    fn exec_jit(&mut self, jit: jit::Compiled, mem: &mut [u8]) -> u64 {
+        match self.try_exec_jit(jit, mem) {
+            Ok(res) => res,
+            Err(e)  => panic!("{}", e.report(self.parent.prog, self.parent.line_info.as_ref())),
+        }
+    }
+
+    fn try_exec_jit(&mut self, jit: jit::Compiled, mem: &mut [u8]) -> Result<u64, EbpfError> {
+        if self.parent.helper_hook.is_some() {
+            panic!("Error: cannot run a JIT-compiled program with a helper hook attached");
+        }
+        let _code = match jit::acquire(jit) {
+            Some(code) => code,
+            // Evicted from the cache of compiled programs, see `set_jit_code_budget()`
+            None       => {
+                self.update_mbuff_pointers(mem, mem.len());
+                return interpreter::try_execute_program(self.parent.prog, mem, &self.mbuff.buffer,
+                                                        &self.parent.helpers,
+                                                        self.parent.interpreter_options(None));
+            },
+        };
+        // The JIT-compiled program writes the pointers to packet data into the metadata buffer
+        // itself.
+        self.run_mbuff_hook(mem, mem.len());
+        let _depth = interpreter::enter(self.parent.max_call_depth)
+            .map_err(|err| err.in_program(&self.parent.meta))?;
+        let (data_offset, data_end_offset) = (self.mbuff.data_offset, self.mbuff.data_end_offset);
+        let buffer = &mut self.mbuff.buffer;
+        jit::run(jit.entry, self.parent.catch_faults, |jit| {
+            JitFunction::new(jit, data_offset, data_end_offset).call(mem, buffer)
+        }).map_err(|err| err.in_program(&self.parent.meta))
+    }
+}
+
+/// A virtual machine to run eBPF program. This kind of VM is used for programs expecting to work
+/// directly on the memory area representing packet data.
+///
+/// # Examples
+///
+/// ```
+/// let prog = vec![
+///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
+///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let mut mem = vec![
+///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+/// ];
+///
+/// // Instantiate a VM.
+/// let vm = rbpf::EbpfVmRaw::new(&prog);
+///
+/// // Provide only a reference to the packet data.
+/// let res = vm.prog_exec(&mut mem);
+/// assert_eq!(res, 0x22cc);
+/// ```
+pub struct EbpfVmRaw<'a> {
+    parent: EbpfVmMbuff<'a>,
+}
+
+impl<'a> EbpfVmRaw<'a> {
+
+    /// Create a new virtual machine instance, and load an eBPF program into that instance.
+    /// When attempting to load the program, it passes through a simple verifier.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // Instantiate a VM.
+    /// let vm = rbpf::EbpfVmRaw::new(&prog);
+    /// ```
+    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmRaw<'a> {
+        EbpfVmRaw::with_parent(EbpfVmMbuff::new(prog))
+    }
+
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. See `EbpfVmMbuff::from_program()`.
+    pub fn from_program(program: &'a program::Program) -> EbpfVmRaw<'a> {
+        EbpfVmRaw::with_parent(EbpfVmMbuff::from_program(program))
+    }
+
+    fn with_parent(mut parent: EbpfVmMbuff<'a>) -> EbpfVmRaw<'a> {
+        parent.layout = MemoryLayout::Raw;
+        EbpfVmRaw {
+            parent: parent,
+        }
+    }
+
+    /// Load a new eBPF program into the virtual machine instance.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog1 = vec![
+    ///     0xb7, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let prog2 = vec![
+    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27,
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmRaw::new(&prog1);
+    /// vm.set_prog(&prog2);
+    ///
+    /// let res = vm.prog_exec(&mut mem);
+    /// assert_eq!(res, 0x22cc);
+    /// ```
+    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
+        self.parent.set_prog(prog)
+    }
+
+    vm_wrapper_methods!('a);
+
+    /// Execute the program loaded, with the given packet data.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as out of bounds accesses or division by zero attempts. This may be changed
+    /// in the future (we could raise errors instead).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    ///
+    /// let res = vm.prog_exec(&mut mem);
+    /// assert_eq!(res, 0x22cc);
+    /// ```
+    pub fn prog_exec(&self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
+        let mut mbuff = vec![];
+        self.parent.prog_exec(mem, &mut mbuff)
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. See `EbpfVmMbuff::try_prog_exec()`.
+    pub fn try_prog_exec(&self, mem: &'a mut [u8]) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec(mem, &mut [])
+    }
+
+    /// Execute the program loaded with the interpreter, with a budget of `fuel` instructions. See
+    /// `EbpfVmMbuff::try_prog_exec_metered()`.
+    pub fn try_prog_exec_metered(&self, mem: &mut [u8], fuel: u64) -> Result<Metered<'a>, EbpfError> {
+        self.parent.try_prog_exec_metered(mem, &mut [], fuel)
+    }
+
+    /// Resume the execution suspended in `continuation`, with a budget of `fuel` more
+    /// instructions, on the same packet data. See `EbpfVmMbuff::try_resume()`.
+    pub fn try_resume(&self, continuation: Continuation<'a>, mem: &mut [u8], fuel: u64)
+                      -> Result<Metered<'a>, EbpfError> {
+        self.parent.try_resume(continuation, mem, &mut [], fuel)
+    }
+
+    /// Execute the loaded XDP program, and return the value in R0 as an XDP action. See
+    /// `EbpfVmMbuff::prog_exec_xdp()`.
+    pub fn prog_exec_xdp(&self, mem: &'a mut [u8]) -> ebpf::XdpAction {
+        self.parent.prog_exec_xdp(mem, &mut [])
+    }
+
+    /// Execute the loaded XDP program, and return the value in R0 as an XDP action, or an error.
+    /// See `EbpfVmMbuff::try_prog_exec_xdp()`.
+    pub fn try_prog_exec_xdp(&self, mem: &'a mut [u8]) -> Result<ebpf::XdpAction, EbpfError> {
+        self.parent.try_prog_exec_xdp(mem, &mut [])
+    }
+
+    /// Execute the loaded XDP program, and return the XDP action along with the redirect
+    /// requested by the program, if any. See `EbpfVmMbuff::prog_exec_xdp_redirect()`.
+    pub fn prog_exec_xdp_redirect(&self, mem: &'a mut [u8]) -> (ebpf::XdpAction, Option<maps::Redirect>) {
+        self.parent.prog_exec_xdp_redirect(mem, &mut [])
+    }
+
+    /// Execute the loaded XDP program, and return the XDP action along with the redirect
+    /// requested by the program, if any, or an error. See
+    /// `EbpfVmMbuff::try_prog_exec_xdp_redirect()`.
+    pub fn try_prog_exec_xdp_redirect(&self, mem: &'a mut [u8])
+                                      -> Result<(ebpf::XdpAction, Option<maps::Redirect>), EbpfError> {
+        self.parent.try_prog_exec_xdp_redirect(mem, &mut [])
+    }
+
+    /// Execute the loaded tc classifier, and return the value in R0 as a tc action. See
+    /// `EbpfVmMbuff::prog_exec_tc()`.
+    pub fn prog_exec_tc(&self, mem: &'a mut [u8]) -> ebpf::TcAction {
+        self.parent.prog_exec_tc(mem, &mut [])
+    }
+
+    /// Execute the loaded tc classifier, and return the value in R0 as a tc action, or an error.
+    /// See `EbpfVmMbuff::try_prog_exec_tc()`.
+    pub fn try_prog_exec_tc(&self, mem: &'a mut [u8]) -> Result<ebpf::TcAction, EbpfError> {
+        self.parent.try_prog_exec_tc(mem, &mut [])
+    }
+
+    /// Run the program on packet data `data_in` as command `BPF_PROG_TEST_RUN` of the kernel
+    /// does, with no context, see `EbpfVmMbuff::test_run()`.
+    pub fn test_run(&self, data_in: &[u8], repeat: u32) -> Result<testing::TestRun, EbpfError> {
+        self.parent.test_run(data_in, &[], repeat)
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See `EbpfVmMbuff::prog_exec_trace()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0xdd
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmRaw::new(&prog);
+    ///
+    /// let mut tracer = rbpf::trace::TextWriter::new(Vec::new());
+    /// assert_eq!(vm.prog_exec_trace(&mut mem, &mut tracer), 0xcc);
+    ///
+    /// let text = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    /// assert_eq!(text.lines().count(), 3);
+    /// ```
+    pub fn prog_exec_trace(&self, mem: &'a mut [u8], tracer: &mut dyn trace::Tracer) -> u64 {
+        let mut mbuff = vec![];
+        self.parent.prog_exec_trace(mem, &mut mbuff, tracer)
+    }
+
+    /// Execute the program loaded on packet data scattered over several buffers, without copying
+    /// them into a contiguous buffer. R1 points to the first buffer. See
+    /// `EbpfVmMbuff::prog_exec_iov()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::IoSliceMut;
+    ///
+    /// // Return the byte at offset 3 of packet data, read with `bpf_xdp_load_bytes()`.
+    /// let prog = vec![
+    ///     0xb7, 0x02, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r2, 3
+    ///     0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+    ///     0x07, 0x03, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // add64 r3, -1
+    ///     0xb7, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r4, 1
+    ///     0x85, 0x00, 0x00, 0x00, 0xbd, 0x00, 0x00, 0x00, // call bpf_xdp_load_bytes
+    ///     0x71, 0xa0, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r10-1]
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let (mut head, mut tail) = ([0x11, 0x22], [0x33, 0x44]);
+    ///
+    /// let vm = rbpf::EbpfVmRaw::new(&prog);
+    ///
+    /// let mut iov = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)];
+    /// assert_eq!(vm.prog_exec_iov(&mut iov), 0x44);
+    /// ```
+    pub fn prog_exec_iov(&self, iov: &mut [std::io::IoSliceMut]) -> u64 {
+        self.parent.prog_exec_iov(iov, &mut [])
+    }
+
+    /// Execute the program loaded on packet data scattered over several buffers, in the same way
+    /// as `prog_exec_iov()`, but return runtime errors instead of panicking.
+    pub fn try_prog_exec_iov(&self, iov: &mut [std::io::IoSliceMut]) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec_iov(iov, &mut [])
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, a handle independent from the VM. See
+    /// `EbpfVmMbuff::jit_program()`.
+    pub fn jit_program(&self) -> JitProgram {
+        match self.try_jit_program() {
+            Ok(program) => program,
+            Err(err)    => panic!("{}", err),
+        }
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, returning an error instead of
+    /// panicking if it cannot be compiled. See `EbpfVmMbuff::try_jit_program()`.
+    pub fn try_jit_program(&self) -> Result<JitProgram, EbpfError> {
+        self.parent.try_jit_program()
+    }
+
+    /// Execute the previously JIT-compiled program, with the given packet data, in a manner very
+    /// similar to `prog_exec()`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x71, 0x11, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1[0x04], r1
+    ///     0x07, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00, // add r1, 0x22
+    ///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut mem = vec![
+    ///     0xaa, 0xbb, 0x11, 0x22, 0xcc, 0x27
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    ///
+    /// vm.jit_compile();
+    ///
+    /// let res = vm.prog_exec_jit(&mut mem);
+    /// assert_eq!(res, 0x22cc);
+    /// ```
+    pub fn prog_exec_jit(&self, mem: &'a mut std::vec::Vec<u8>) -> u64 {
+        let mut mbuff = vec![];
+        self.parent.prog_exec_jit(mem, &mut mbuff)
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking. See
+    /// `EbpfVmMbuff::try_prog_exec_jit()`.
+    pub fn try_prog_exec_jit(&self, mem: &mut [u8]) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec_jit(mem, &mut [])
+    }
+}
+
+/// A virtual machine to run eBPF program. This kind of VM is used for programs that do not work
+/// with any memory area—no metadata buffer, no packet data either.
+///
+/// # Examples
+///
+/// ```
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+///     0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r1, 1
+///     0xb7, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov r2, 2
+///     0xb7, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov r3, 3
+///     0xb7, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov r4, 4
+///     0xb7, 0x05, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov r5, 5
+///     0xb7, 0x06, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // mov r6, 6
+///     0xb7, 0x07, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov r7, 7
+///     0xb7, 0x08, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov r8, 8
+///     0x4f, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // or r0, r5
+///     0x47, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x00, // or r0, 0xa0
+///     0x57, 0x00, 0x00, 0x00, 0xa3, 0x00, 0x00, 0x00, // and r0, 0xa3
+///     0xb7, 0x09, 0x00, 0x00, 0x91, 0x00, 0x00, 0x00, // mov r9, 0x91
+///     0x5f, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // and r0, r9
+///     0x67, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // lsh r0, 32
+///     0x67, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, // lsh r0, 22
+///     0x6f, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lsh r0, r8
+///     0x77, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // rsh r0, 32
+///     0x77, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, // rsh r0, 19
+///     0x7f, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // rsh r0, r7
+///     0xa7, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // xor r0, 0x03
+///     0xaf, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // xor r0, r2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// // Instantiate a VM.
+/// let vm = rbpf::EbpfVmNoData::new(&prog);
+///
+/// // Provide only a reference to the packet data.
+/// let res = vm.prog_exec();
+/// assert_eq!(res, 0x11);
+/// ```
+pub struct EbpfVmNoData<'a> {
+    parent: EbpfVmRaw<'a>,
+}
+
+impl<'a> EbpfVmNoData<'a> {
+
+    /// Create a new virtual machine instance, and load an eBPF program into that instance.
+    /// When attempting to load the program, it passes through a simple verifier.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// // Instantiate a VM.
+    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    /// ```
+    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmNoData<'a> {
+        let parent = EbpfVmRaw::new(prog);
+        EbpfVmNoData {
+            parent: parent,
+        }
+    }
+
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. See `EbpfVmMbuff::from_program()`.
+    pub fn from_program(program: &'a program::Program) -> EbpfVmNoData<'a> {
+        EbpfVmNoData { parent: EbpfVmRaw::from_program(program) }
+    }
+
+    /// Load a new eBPF program into the virtual machine instance.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog1 = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let prog2 = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmNoData::new(&prog1);
+    ///
+    /// let res = vm.prog_exec();
+    /// assert_eq!(res, 0x2211);
+    ///
+    /// vm.set_prog(&prog2);
+    ///
+    /// let res = vm.prog_exec();
+    /// assert_eq!(res, 0x1122);
+    /// ```
+    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
+        self.parent.set_prog(prog)
+    }
+
+    vm_wrapper_methods!('a);
+
+    /// JIT-compile the loaded program into a `JitProgram`, a handle independent from the VM, to
+    /// be run with empty packet data and metadata buffer. See `EbpfVmMbuff::jit_program()`.
+    pub fn jit_program(&self) -> JitProgram {
+        self.parent.jit_program()
+    }
+
+    /// JIT-compile the loaded program into a `JitProgram`, returning an error instead of
+    /// panicking if it cannot be compiled. See `EbpfVmMbuff::try_jit_program()`.
+    pub fn try_jit_program(&self) -> Result<JitProgram, EbpfError> {
+        self.parent.try_jit_program()
+    }
+
+    /// Execute the program loaded, without providing pointers to any memory area whatsoever.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as memory accesses or division by zero attempts. This may be changed in the
+    /// future (we could raise errors instead).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    ///
+    /// // For this kind of VM, the `prog_exec()` function needs no argument.
+    /// let res = vm.prog_exec();
+    /// assert_eq!(res, 0x1122);
+    /// ```
+    pub fn prog_exec(&self) -> u64 {
+        self.parent.prog_exec(&mut vec![])
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. See `EbpfVmMbuff::try_prog_exec()`.
+    pub fn try_prog_exec(&self) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec(&mut [])
+    }
+
+    /// Execute the program loaded with the interpreter, with a budget of `fuel` instructions. See
+    /// `EbpfVmMbuff::try_prog_exec_metered()`.
+    pub fn try_prog_exec_metered(&self, fuel: u64) -> Result<Metered<'a>, EbpfError> {
+        self.parent.try_prog_exec_metered(&mut [], fuel)
+    }
+
+    /// Resume the execution suspended in `continuation`, with a budget of `fuel` more
+    /// instructions. See `EbpfVmMbuff::try_resume()`.
+    pub fn try_resume(&self, continuation: Continuation<'a>, fuel: u64) -> Result<Metered<'a>, EbpfError> {
+        self.parent.try_resume(continuation, &mut [], fuel)
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See `EbpfVmMbuff::prog_exec_trace()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    ///
+    /// let mut log = rbpf::trace::TraceLog::new();
+    /// assert_eq!(vm.prog_exec_trace(&mut log), 0x1122);
+    /// assert_eq!(log.entries()[1].reg_deltas[0].new, 0x1122);
+    /// ```
+    pub fn prog_exec_trace(&self, tracer: &mut dyn trace::Tracer) -> u64 {
+        self.parent.prog_exec_trace(&mut [], tracer)
+    }
+
+    /// Execute the previously JIT-compiled program, without providing pointers to any memory area
+    /// whatsoever, in a manner very similar to `prog_exec()`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x11, 0x22, 0x00, 0x00, // mov r0, 0x2211
+    ///     0xdc, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    ///
+    /// vm.jit_compile();
+    ///
+    /// let res = vm.prog_exec_jit();
+    /// assert_eq!(res, 0x1122);
+    /// ```
+    pub fn prog_exec_jit(&self) -> u64 {
+        self.parent.prog_exec_jit(&mut vec![])
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking. See
+    /// `EbpfVmMbuff::try_prog_exec_jit()`.
+    pub fn try_prog_exec_jit(&self) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec_jit(&mut [])
+    }
+}
+
+/// Limit the executable memory used by the programs JIT-compiled with the `jit_compile()`
+/// functions of the VMs to `budget` bytes, or remove the limit with `None`, the default.
+///
+/// The limit is global: it bounds the memory used by all the VMs of the process, for example on
+/// hosts running the programs of many users. When the compiled programs exceed it, the least
+/// recently run ones are evicted, and their code freed; the VMs running an evicted program fall
+/// back to the interpreter, as if it had never been compiled, until they compile it again. A
+/// program compiled alone over the budget is evicted at once. Programs running at the time of the
+/// eviction are kept until the next one. Programs compiled with `jit_program()` are owned by their
+/// `JitProgram` handle, and not counted.
+///
+/// # Examples
+///
+/// ```
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov r0, 42
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// vm.jit_compile();
+/// assert!(vm.stats().jit_code_size.is_some());
+///
+/// // The program is evicted, and interpreted.
+/// rbpf::set_jit_code_budget(Some(0));
+/// assert_eq!(rbpf::jit_code_usage(), 0);
+/// assert_eq!(vm.stats().jit_code_size, None);
+/// assert_eq!(vm.prog_exec_jit(), 42);
+/// # rbpf::set_jit_code_budget(None);
+/// ```
+pub fn set_jit_code_budget(budget: Option<usize>) {
+    jit::set_code_budget(budget);
+}
+
+/// Return the executable memory used by the programs JIT-compiled with the `jit_compile()`
+/// functions of the VMs, in bytes, as limited by `set_jit_code_budget()`. This counts whole pages,
+/// and each program once, even if it is shared by several VMs.
+pub fn jit_code_usage() -> usize {
+    jit::code_usage()
+}
+
+/// What this build of the crate supports, as returned by `capabilities()`, so that tools can
+/// select the programs and the bytecode they send to an application running rbpf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Capabilities {
+    /// The version of the crate.
+    pub version:     &'static str,
+    /// The latest version of the instruction set supported, the default of the VMs. Older
+    /// versions can be selected with `EbpfVmMbuff::set_isa_version()`.
+    pub isa_version: ebpf::IsaVersion,
+    /// The operation codes supported by the verifier, the interpreter and the JIT-compiler, in
+    /// increasing order. See `ebpf::OPCODES` for their metadata.
+    pub opcodes:     Vec<u8>,
+    /// The architectures the JIT-compiler generates code for, named as in
+    /// `std::env::consts::ARCH`: `x86_64` on x86-64 hosts, none on the others.
+    pub jit_targets: Vec<&'static str>,
+    /// Whether programs can use maps, see module `maps`.
+    pub maps:        bool,
+    /// Whether line information in the BTF format can be attached to programs, see module `btf`.
+    pub btf:         bool,
+    /// Whether programs can be loaded from ELF object files. The crate takes bytecode only: the
+    /// application extracts it from the sections of object files itself.
+    pub elf:         bool,
+    /// The optional features of the crate enabled in this build, as named in `Cargo.toml`, for
+    /// example `pcap`. The features restricted to some systems are only listed on those.
+    pub features:    Vec<&'static str>,
+}
+
+/// Report what this build of the crate supports: the instruction set, the targets of the
+/// JIT-compiler and the optional features.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf;
+///
+/// let caps = rbpf::capabilities();
+/// assert_eq!(caps.isa_version, ebpf::IsaVersion::V4);
+/// assert!(caps.opcodes.contains(&ebpf::ADD64_IMM));
+/// assert!(caps.maps && !caps.elf);
+/// if cfg!(target_arch = "x86_64") {
+///     assert_eq!(caps.jit_targets, vec!["x86_64"]);
+/// }
+/// ```
+pub fn capabilities() -> Capabilities {
+    let features = [
+        ("af_packet", cfg!(all(feature = "af_packet", target_os = "linux"))),
+        ("capi",      cfg!(feature = "capi")),
+        ("fuzz",      cfg!(feature = "fuzz")),
+        ("kernel",    cfg!(all(feature = "kernel", target_os = "linux"))),
+        ("pcap",      cfg!(feature = "pcap")),
+        ("serde",     cfg!(feature = "serde")),
+    ];
+    let mut opcodes: Vec<u8> = ebpf::OPCODES.iter().map(|info| info.opc).collect();
+    opcodes.sort_unstable();
+    opcodes.dedup();
+    Capabilities {
+        version:     env!("CARGO_PKG_VERSION"),
+        isa_version: ebpf::IsaVersion::V4,
+        opcodes,
+        jit_targets: if cfg!(target_arch = "x86_64") { vec!["x86_64"] } else { vec![] },
+        maps:        true,
+        btf:         true,
+        elf:         false,
+        features:    features.iter().filter(|&&(_, enabled)| enabled).map(|&(name, _)| name).collect(),
+    }
+}
+
+/// A JIT-compiled program, independent from the VM that compiled it, and obtained with the
+/// `jit_program()` function of the VMs.
+///
+/// The handle owns the executable memory of the program, released when the handle is dropped,
+/// and is `Send` and `Sync`: the program runs on the stack of the calling thread, so several
+/// threads can run it at the same time, for example by sharing the handle in an `Arc`. The
+/// helpers are compiled into the program, as the functions registered on the VM at compilation
+/// time.
+///
+/// **WARNING:** as with `EbpfVmMbuff::prog_exec_jit()`, there is no runtime check for memory
+/// accesses, and no helper hook is run.
+pub struct JitProgram {
+    code:   jit::JitCode,
+    // The layout of the VM the program was compiled by.
+    layout: MemoryLayout,
+}
+
+impl JitProgram {
+
+    /// Run the program on packet data `mem`, and on the metadata buffer `mbuff` for programs
+    /// compiled by an `EbpfVmMbuff`. Programs compiled by other VMs ignore `mbuff`: those from an
+    /// `EbpfVmFixedMbuff` receive a new metadata buffer holding the pointers to `mem`, and those
+    /// from an `EbpfVmRaw` receive the address of `mem` in R1.
+    pub fn prog_exec(&self, mem: &mut [u8], mbuff: &mut [u8]) -> u64 {
+        match self.layout {
+            MemoryLayout::Mbuff => self.function().call(mem, mbuff),
+            MemoryLayout::FixedMbuff { buffer_len, .. } => {
+                self.function().call(mem, &mut vec![0u8; buffer_len])
+            },
+            MemoryLayout::Raw => self.function().call(mem, &mut []),
+        }
+    }
+
+    /// Return the entry point of the program, to call it directly, for example from the
+    /// scheduler of an application running many programs. Contrary to `prog_exec()`, the metadata
+    /// buffer is always passed as is: for programs compiled by an `EbpfVmFixedMbuff`, the caller
+    /// provides a buffer of the size given to the VM, into which the program writes the pointers
+    /// to packet data.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // Load mem from mbuff into R1.
+    ///     0x69, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldhx r1[2], r0
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let program = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50).jit_program();
+    /// let function = program.function();
+    ///
+    /// let mut buffer = vec![0u8; 0x58];
+    /// assert_eq!(function.call(&mut [0xaa, 0xbb, 0x11, 0x22], &mut buffer), 0x2211);
+    /// assert_eq!(function.call(&mut [0xaa, 0xbb, 0x33, 0x44], &mut buffer), 0x4433);
+    /// ```
+    pub fn function(&self) -> JitFunction<'_> {
+        match self.layout {
+            MemoryLayout::FixedMbuff { data_offset, data_end_offset, .. } => {
+                JitFunction::new(self.code.entry(), data_offset, data_end_offset)
+            },
+            MemoryLayout::Mbuff | MemoryLayout::Raw => JitFunction::new(self.code.entry(), 0, 0),
+        }
+    }
+
+    /// Return the size of the machine code of the program, in bytes. The executable memory
+    /// holding it is rounded up to whole pages, see `EbpfVmMbuff::set_jit_huge_pages()`.
+    pub fn code_size(&self) -> usize {
+        jit::code_size(jit::Compiled::uncached(self.code.entry())).unwrap_or(0)
+    }
+}
+
+/// The entry point of a JIT-compiled program, obtained with `JitProgram::function()` and valid as
+/// long as the `JitProgram` it was obtained from. It is `Copy`, `Send` and `Sync`, so that
+/// applications can store it in their own structures, and call it from any thread.
+///
+/// **WARNING:** as with `EbpfVmMbuff::prog_exec_jit()`, there is no runtime check for memory
+/// accesses, no helper hook is run, and the faults of the program are not caught.
+#[derive(Clone, Copy)]
+pub struct JitFunction<'a> {
+    entry:           jit::JitFn,
+    data_offset:     usize,
+    data_end_offset: usize,
+    _code:           std::marker::PhantomData<&'a jit::JitCode>,
+}
+
+impl<'a> JitFunction<'a> {
+
+    fn new(entry: jit::JitFn, data_offset: usize, data_end_offset: usize) -> JitFunction<'a> {
+        JitFunction { entry, data_offset, data_end_offset, _code: std::marker::PhantomData }
+    }
+
+    /// Run the program on packet data `mem` and on the metadata buffer `mbuff`, and return the
+    /// value left in R0. Programs compiled by an `EbpfVmRaw` ignore `mbuff`, and those compiled
+    /// by an `EbpfVmFixedMbuff` store the pointers to `mem` into it.
+    ///
+    /// If packet data is empty, the program does not receive the address of an empty buffer but
+    /// a null pointer, as uBPF does: empty packets should not happen in the kernel, and the
+    /// verifier would prevent the use of uninitialized registers anyway. See `mul_loop` test.
+    pub fn call(&self, mem: &mut [u8], mbuff: &mut [u8]) -> u64 {
+        let mem_ptr = match mem.len() {
+            0 => std::ptr::null_mut(),
+            _ => mem.as_mut_ptr(),
+        };
+        (self.entry)(mbuff.as_mut_ptr(), mbuff.len(), mem_ptr, mem.len(),
+                     self.data_offset, self.data_end_offset)
+    }
+}
+
+/// The registers of a task, as stored by the Linux kernel on x86_64 in `struct pt_regs`, the
+/// context of kprobe and uprobe programs. The fields are in the order of the kernel structure,
+/// each of them taking 8 bytes: `di`, the first argument of the probed function, is at offset
+/// 0x70, and `ax`, holding its return value, at offset 0x50.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+#[allow(missing_docs)]
+pub struct PtRegs {
+    pub r15:      u64,
+    pub r14:      u64,
+    pub r13:      u64,
+    pub r12:      u64,
+    pub bp:       u64,
+    pub bx:       u64,
+    pub r11:      u64,
+    pub r10:      u64,
+    pub r9:       u64,
+    pub r8:       u64,
+    pub ax:       u64,
+    pub cx:       u64,
+    pub dx:       u64,
+    pub si:       u64,
+    pub di:       u64,
+    pub orig_ax:  u64,
+    pub ip:       u64,
+    pub cs:       u64,
+    pub flags:    u64,
+    pub sp:       u64,
+    pub ss:       u64,
+}
+
+impl PtRegs {
+
+    /// Size of `struct pt_regs`, in bytes.
+    pub const SIZE: usize = 21 * 8;
+
+    /// Build the registers from a dump of `struct pt_regs`, in the byte order of the host, as
+    /// recorded for example by a probe copying its context.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` holds less than `PtRegs::SIZE` bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut dump = vec![0u8; rbpf::PtRegs::SIZE];
+    /// dump[0x70..0x78].copy_from_slice(&42u64.to_ne_bytes());
+    ///
+    /// assert_eq!(rbpf::PtRegs::from_bytes(&dump).di, 42);
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> PtRegs {
+        if bytes.len() < PtRegs::SIZE {
+            panic!("Error: register dump too small ({:?} bytes), expected {:?} bytes",
+                   bytes.len(), PtRegs::SIZE);
+        }
+        let mut words = [0u64; 21];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(chunk);
+            *word = u64::from_ne_bytes(b);
+        }
+        let [r15, r14, r13, r12, bp, bx, r11, r10, r9, r8, ax, cx, dx, si, di, orig_ax, ip, cs, flags, sp, ss] = words;
+        PtRegs { r15, r14, r13, r12, bp, bx, r11, r10, r9, r8, ax, cx, dx, si, di, orig_ax, ip, cs, flags, sp, ss }
+    }
+
+    /// Encode the registers as `struct pt_regs`, in the byte order of the host: the reverse of
+    /// `from_bytes()`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.r15, self.r14, self.r13, self.r12, self.bp, self.bx, self.r11, self.r10, self.r9,
+         self.r8, self.ax, self.cx, self.dx, self.si, self.di, self.orig_ax, self.ip, self.cs,
+         self.flags, self.sp, self.ss].iter().flat_map(|w| w.to_ne_bytes()).collect()
+    }
+}
+
+/// A virtual machine to run eBPF program. This kind of VM is used for tracing programs, such as
+/// kprobe or uprobe programs, receiving a pointer to the registers of the probed task in R1. The
+/// registers are given as a `PtRegs` snapshot, and copied to a buffer at each run: the program
+/// may modify them, without affecting the snapshot.
+///
+/// Memory pointed to by the registers can be made available to the `bpf_probe_read*()` helpers
+/// with `register_probe_region()`.
+///
+/// # Examples
+///
+/// ```
+/// // Return the sum of the first two arguments of the probed function.
+/// let prog = vec![
+///     0x79, 0x10, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+0x70] (di)
+///     0x79, 0x12, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r1+0x68] (si)
+///     0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add r0, r2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let vm = rbpf::EbpfVmTracing::new(&prog);
+///
+/// let regs = rbpf::PtRegs { di: 0x1100, si: 0x22, ..Default::default() };
+/// assert_eq!(vm.prog_exec(&regs), 0x1122);
+/// ```
+pub struct EbpfVmTracing<'a> {
+    parent: EbpfVmMbuff<'a>,
+}
+
+impl<'a> EbpfVmTracing<'a> {
+
+    /// Create a new virtual machine instance, and load an eBPF program into that instance.
+    /// When attempting to load the program, it passes through a simple verifier.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmTracing<'a> {
+        EbpfVmTracing::with_parent(EbpfVmMbuff::new(prog))
+    }
+
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. See `EbpfVmMbuff::from_program()`.
+    pub fn from_program(program: &'a program::Program) -> EbpfVmTracing<'a> {
+        EbpfVmTracing::with_parent(EbpfVmMbuff::from_program(program))
+    }
+
+    fn with_parent(mut parent: EbpfVmMbuff<'a>) -> EbpfVmTracing<'a> {
+        parent.set_ctx_len(Some(std::mem::size_of::<PtRegs>()));
+        EbpfVmTracing { parent }
+    }
+
+    /// Load a new eBPF program into the virtual machine instance.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any.
+    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
+        self.parent.set_prog(prog)
+    }
+
+    vm_wrapper_methods!('a);
+
+    /// Execute the program loaded, with a pointer to a copy of `regs` in R1.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as out of bounds accesses or division by zero attempts.
+    pub fn prog_exec(&self, regs: &PtRegs) -> u64 {
+        self.parent.prog_exec(&mut vec![], &mut regs.to_bytes())
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. See `EbpfVmMbuff::try_prog_exec()`.
+    pub fn try_prog_exec(&self, regs: &PtRegs) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec(&mut [], &mut regs.to_bytes())
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See `EbpfVmMbuff::prog_exec_trace()`.
+    pub fn prog_exec_trace(&self, regs: &PtRegs, tracer: &mut dyn trace::Tracer) -> u64 {
+        self.parent.prog_exec_trace(&mut [], &mut regs.to_bytes(), tracer)
+    }
+
+    /// Execute the previously JIT-compiled program, with a pointer to a copy of `regs` in R1, in
+    /// a manner very similar to `prog_exec()`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    pub fn prog_exec_jit(&self, regs: &PtRegs) -> u64 {
+        self.parent.prog_exec_jit(&mut vec![], &mut regs.to_bytes())
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking. See
+    /// `EbpfVmMbuff::try_prog_exec_jit()`.
+    pub fn try_prog_exec_jit(&self, regs: &PtRegs) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec_jit(&mut [], &mut regs.to_bytes())
+    }
+}
+
+/// A virtual machine to run eBPF program. This kind of VM is used for programs receiving in R1
+/// the address of a context of type `T`, such as a structure defined for a custom program type.
+/// The context is given by reference at each run, and the program may read and modify it, with
+/// bounds checks in the interpreter. See `ctx::Context` for the types allowed.
+///
+/// # Examples
+///
+/// ```
+/// #[repr(C)]
+/// struct Flow {
+///     packets: u32,
+///     bytes:   u32,
+///     average: u32,
+/// }
+///
+/// unsafe impl rbpf::ctx::Context for Flow {}
+///
+/// // Compute the average size of packets, and return 1 if it exceeds 1000 bytes.
+/// let prog = vec![
+///     0x61, 0x12, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1+4]
+///     0x61, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r3, [r1]
+///     0x3c, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div32 r2, r3
+///     0x63, 0x21, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // stxw [r1+8], r2
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+///     0xb6, 0x02, 0x01, 0x00, 0xe8, 0x03, 0x00, 0x00, // jle32 r2, 1000, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let vm = rbpf::EbpfVmCtx::<Flow>::new(&prog);
+///
+/// let mut flow = Flow { packets: 4, bytes: 6000, average: 0 };
+/// assert_eq!(vm.prog_exec(&mut flow), 1);
+/// assert_eq!(flow.average, 1500);
+/// ```
+pub struct EbpfVmCtx<'a, T: ctx::Context> {
+    parent: EbpfVmMbuff<'a>,
+    ctx:    std::marker::PhantomData<fn (&mut T)>,
+}
+
+impl<'a, T: ctx::Context> EbpfVmCtx<'a, T> {
+
+    /// Create a new virtual machine instance, and load an eBPF program into that instance.
+    /// When attempting to load the program, it passes through a simple verifier.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    pub fn new(prog: &'a std::vec::Vec<u8>) -> EbpfVmCtx<'a, T> {
+        EbpfVmCtx::with_parent(EbpfVmMbuff::new(prog))
+    }
+
+    /// Create a new virtual machine instance, and load a program already verified into that
+    /// instance. See `EbpfVmMbuff::from_program()`.
+    pub fn from_program(program: &'a program::Program) -> EbpfVmCtx<'a, T> {
+        EbpfVmCtx::with_parent(EbpfVmMbuff::from_program(program))
+    }
+
+    fn with_parent(mut parent: EbpfVmMbuff<'a>) -> EbpfVmCtx<'a, T> {
+        // The interpreter does not pass an empty context in R1.
+        if std::mem::size_of::<T>() > 0 {
+            parent.set_ctx_len(Some(std::mem::size_of::<T>()));
+        }
+        EbpfVmCtx { parent, ctx: std::marker::PhantomData }
+    }
+
+    /// Load a new eBPF program into the virtual machine instance.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time, as well
+    /// as the instruction policy attached to the VM, if any.
+    pub fn set_prog(&mut self, prog: &'a std::vec::Vec<u8>) {
+        self.parent.set_prog(prog)
+    }
+
+    vm_wrapper_methods!('a);
+
+    // The bytes of the context, passed to the program as the metadata buffer.
+    fn ctx_bytes(ctx: &mut T) -> &mut [u8] {
+        // `ctx::Context` guarantees that any bytes written by the program form a valid `T`.
+        unsafe { std::slice::from_raw_parts_mut(ctx as *mut T as *mut u8, std::mem::size_of::<T>()) }
+    }
+
+    /// Execute the program loaded, with the address of `ctx` in R1.
+    ///
+    /// # Panics
+    ///
+    /// This function is currently expected to panic if it encounters any error during the program
+    /// execution, such as out of bounds accesses or division by zero attempts.
+    pub fn prog_exec(&self, ctx: &mut T) -> u64 {
+        if let Some(jit) = self.parent.async_jit() {
+            return self.exec_jit(jit, ctx);
+        }
+        match self.try_prog_exec(ctx) {
+            Ok(res) => res,
+            Err(e)  => panic!("{}", e.report(self.parent.prog, self.parent.line_info.as_ref())),
+        }
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, but return runtime errors
+    /// instead of panicking. See `EbpfVmMbuff::try_prog_exec()`.
+    pub fn try_prog_exec(&self, ctx: &mut T) -> Result<u64, EbpfError> {
+        self.parent.try_prog_exec(&mut [], Self::ctx_bytes(ctx))
+    }
+
+    /// Execute the program loaded, in the same way as `prog_exec()`, while handing a record of
+    /// each executed instruction to `tracer`. See `EbpfVmMbuff::prog_exec_trace()`.
+    pub fn prog_exec_trace(&self, ctx: &mut T, tracer: &mut dyn trace::Tracer) -> u64 {
+        self.parent.prog_exec_trace(&mut [], Self::ctx_bytes(ctx), tracer)
+    }
+
+    /// Execute the previously JIT-compiled program, with the address of `ctx` in R1, in a manner
+    /// very similar to `prog_exec()`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if an error occurs during the execution of the program, or if a
+    /// helper hook is attached to the VM.
+    ///
+    /// **WARNING:** JIT-compiled assembly code is not safe, in particular there is no runtime
+    /// check for memory access; so if the eBPF program attempts erroneous accesses, this may end
+    /// very bad (program may segfault). It may be wise to check that the program works with the
+    /// interpreter before running the JIT-compiled version of it.
+    pub fn prog_exec_jit(&self, ctx: &mut T) -> u64 {
+        self.exec_jit(self.parent.jit, ctx)
+    }
+
+    /// Execute the previously JIT-compiled program, in the same way as `prog_exec_jit()`, but
+    /// return the faults caught with `set_catch_faults()` instead of panicking. See
+    /// `EbpfVmMbuff::try_prog_exec_jit()`.
+    pub fn try_prog_exec_jit(&self, ctx: &mut T) -> Result<u64, EbpfError> {
+        self.parent.try_exec_jit(self.parent.jit, &mut [], Self::ctx_bytes(ctx))
+    }
+
+    fn exec_jit(&self, jit: jit::Compiled, ctx: &mut T) -> u64 {
+        self.parent.exec_jit(jit, &mut [], Self::ctx_bytes(ctx))
     }
 }
@@ -0,0 +1,798 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module implements eBPF maps: key/value stores shared by programs and the application, and
+//! by the programs run by several VMs, possibly on different threads.
+//!
+//! A map is created with `Map::new()`, shared as an `Arc<Map>`, and registered on each VM that
+//! uses it with `register_map()`, which returns the identifier the program passes to the map
+//! helpers. Once a map is registered, the interpreter runs helpers `bpf_map_lookup_elem()`,
+//! `bpf_map_update_elem()` and `bpf_map_delete_elem()` itself, see
+//! `helpers::BPF_MAP_LOOKUP_ELEM_IDX`, and `bpf_for_each_map_elem()`, which calls a subprogram of
+//! the program on each element, see `helpers::BPF_FOR_EACH_MAP_ELEM_IDX`.
+//!
+//! JIT-compiled programs only support lookups in array maps, when the map passed in R1 is the
+//! same on all paths to the call: the JIT-compiler replaces the call with the computation of the
+//! address of the value, after checking the key against the number of entries. Maps must be
+//! registered before compiling, and the other map helpers are not available.
+//!
+//! Maps of type `MapType::DevMap` hold the targets to which XDP programs redirect packets with
+//! helper `bpf_redirect_map()`, see `helpers::BPF_REDIRECT_MAP_IDX`. The redirect is not
+//! performed by the VM: it is returned to the application as a `Redirect`, with the action of the
+//! program, by `EbpfVmMbuff::prog_exec_xdp_redirect()`.
+//!
+//! Maps are shared with other processes by pinning them to a directory, see module `pin`: the
+//! values of array maps are then held in a file mapped in memory. Maps created in shared memory
+//! segments, including hash maps, are shared with their entries, see module `shm`.
+//!
+//! # Consistency
+//!
+//! `Map` is `Send` and `Sync`, and all its operations take `&self`:
+//!
+//! * Hash maps are split into shards, each protected by a read-write lock, so that operations on
+//!   keys of different shards do not contend. A lookup holds the read lock of its shard only long
+//!   enough to take a reference on the value.
+//! * Updating an entry of a hash map replaces its value with a new one, as deleting it removes
+//!   it: a program holding a pointer to the previous value, returned by a lookup, keeps accessing
+//!   the previous value, which remains valid until the program exits, as with RCU in the kernel.
+//! * LPM tries are protected by a single read-write lock: lookups walk the trie under the read
+//!   lock, and updates and deletions replace or remove values under the write lock, as in hash
+//!   maps.
+//! * Array maps have a fixed storage: lookups take no lock, and updates copy the value in place.
+//! * Programs load from and store to map values without synchronization. As in the kernel, the
+//!   concurrent updates of a value, by programs or by `update()` on an array map, are not atomic:
+//!   a reader may see a partially updated value, and concurrent increments may be lost.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::thread;
+//! use rbpf::maps::{Map, MapType};
+//!
+//! // Increments the 64-bit counter of the array map at the index found in packet data.
+//! let prog = vec![
+//!     0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1 (key)
+//!     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+//!     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+//!     0x15, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +3
+//!     0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+//!     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+//!     0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! let map = Arc::new(Map::new(MapType::Array, 4, 8, 2));
+//! let workers: Vec<_> = (0..2u32).map(|index| {
+//!     let (prog, map) = (prog.clone(), map.clone());
+//!     thread::spawn(move || {
+//!         let mut vm = rbpf::EbpfVmRaw::new(&prog);
+//!         vm.register_map(map);
+//!         for _ in 0..10 {
+//!             vm.prog_exec(&mut index.to_le_bytes().to_vec());
+//!         }
+//!     })
+//! }).collect();
+//! for worker in workers {
+//!     worker.join().unwrap();
+//! }
+//!
+//! assert_eq!(map.lookup(&1u32.to_le_bytes()), Some(10u64.to_le_bytes().to_vec()));
+//! ```
+//!
+//! # Introspection
+//!
+//! The application reads the maps maintained by programs with `Map::iter()`, which returns a
+//! copy of the entries, `Map::lookup_batch()` and `Map::clear()`. The maps registered on a VM are
+//! returned by `EbpfVmMbuff::maps()`. Like the other operations, these are not atomic with regard
+//! to the programs running concurrently: `iter()` copies one shard of a hash map at a time, and
+//! may miss the entries moved between shards while it runs.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use helpers::{E2BIG, EEXIST, EINVAL, ENOENT};
+use shm::Table;
+
+/// Flag for `bpf_map_update_elem()`: create the entry or update it.
+pub const BPF_ANY: u64 = 0;
+/// Flag for `bpf_map_update_elem()`: only create the entry, if it does not exist.
+pub const BPF_NOEXIST: u64 = 1;
+/// Flag for `bpf_map_update_elem()`: only update the entry, if it exists.
+pub const BPF_EXIST: u64 = 2;
+
+// Number of shards of hash maps.
+const SHARDS: usize = 16;
+
+// Maximum size of the data of the keys of LPM tries, in bytes, as in the kernel
+// (`LPM_DATA_SIZE_MAX`): the trie has one level per bit of the data.
+const LPM_DATA_SIZE_MAX: usize = 256;
+
+/// The kind of a map, as in the Linux kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapType {
+    /// A hash table, `BPF_MAP_TYPE_HASH`: up to `max_entries` entries with arbitrary keys.
+    Hash,
+    /// An array, `BPF_MAP_TYPE_ARRAY`: `max_entries` values, all present and initialized with
+    /// zeroes, indexed by 32-bit keys. Entries cannot be deleted.
+    Array,
+    /// A map of redirect targets, `BPF_MAP_TYPE_DEVMAP`: up to `max_entries` entries, indexed by
+    /// 32-bit keys, whose values describe the targets to the application, for example the index
+    /// of a network interface. Entries may be absent, as in hash maps.
+    DevMap,
+    /// A longest-prefix-match trie, `BPF_MAP_TYPE_LPM_TRIE`, for example for IP routing tables.
+    /// Keys are made of a prefix length in bits, as a 32-bit little-endian integer, followed by
+    /// the data, of up to 256 bytes, for example an IPv4 address for keys of 8 bytes. Entries are
+    /// associated with a prefix of the data, the bits beyond the prefix length being ignored; a
+    /// lookup returns the entry of the longest prefix matching the first bits of the key, up to
+    /// its prefix length.
+    ///
+    /// The entries are held in a binary trie with one level per bit of the prefixes: a lookup
+    /// follows the bits of the key from the root, and returns the last entry found on the way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::maps::{self, Map, MapType};
+    ///
+    /// fn key(prefixlen: u32, addr: [u8; 4]) -> Vec<u8> {
+    ///     let mut key = prefixlen.to_le_bytes().to_vec();
+    ///     key.extend_from_slice(&addr);
+    ///     key
+    /// }
+    ///
+    /// let routes = Map::new(MapType::LpmTrie, 8, 1, 16);
+    /// routes.update(&key(8, [10, 0, 0, 0]), &[1], maps::BPF_ANY).unwrap();
+    /// routes.update(&key(24, [10, 1, 2, 0]), &[2], maps::BPF_ANY).unwrap();
+    ///
+    /// assert_eq!(routes.lookup(&key(32, [10, 1, 2, 3])), Some(vec![2]));
+    /// assert_eq!(routes.lookup(&key(32, [10, 1, 3, 3])), Some(vec![1]));
+    /// assert_eq!(routes.lookup(&key(16, [10, 1, 2, 3])), Some(vec![1]));
+    /// assert_eq!(routes.lookup(&key(32, [192, 168, 0, 1])), None);
+    /// ```
+    LpmTrie,
+}
+
+impl MapType {
+    // The identifier of the map type in the Linux kernel.
+    pub(crate) fn kernel_id(self) -> u32 {
+        match self {
+            MapType::Hash    => 1,
+            MapType::Array   => 2,
+            MapType::LpmTrie => 11,
+            MapType::DevMap  => 14,
+        }
+    }
+
+    // The map type of identifier `id` in the Linux kernel, if supported.
+    pub(crate) fn from_kernel_id(id: u32) -> Option<MapType> {
+        match id {
+            1  => Some(MapType::Hash),
+            2  => Some(MapType::Array),
+            11 => Some(MapType::LpmTrie),
+            14 => Some(MapType::DevMap),
+            _  => None,
+        }
+    }
+}
+
+/// The redirect requested by a program with helper `bpf_redirect_map()`, see
+/// `helpers::BPF_REDIRECT_MAP_IDX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirect {
+    /// The identifier of the map of targets, as returned by `register_map()`.
+    pub map:   u32,
+    /// The index of the target in the map.
+    pub key:   u32,
+    /// The flags passed to the helper.
+    pub flags: u64,
+}
+
+type Value = Arc<[AtomicU8]>;
+type Shard = RwLock<HashMap<Vec<u8>, Value>>;
+
+enum Storage {
+    Hash {
+        shards: Vec<Shard>,
+        len:    AtomicUsize,
+    },
+    Trie {
+        root: RwLock<Node>,
+        len:  AtomicUsize,
+    },
+    Array(ArrayValues),
+    // A hash map held in a shared memory segment.
+    Shared(Table),
+}
+
+// A node of an LPM trie, for a prefix of the data of the keys: the entry of the prefix, if any,
+// with its key, and the nodes of the prefixes one bit longer, by the value of their last bit.
+#[derive(Default)]
+struct Node {
+    entry:    Option<(Vec<u8>, Value)>,
+    children: [Option<Box<Node>>; 2],
+}
+
+impl Node {
+    // The node of the prefix of the first `len` bits of `data`, if any.
+    fn get(&self, data: &[u8], len: usize) -> Option<&Node> {
+        (0..len).try_fold(self, |node, bit| node.children[bit_at(data, bit)].as_deref())
+    }
+
+    // The node of the prefix of the first `len` bits of `data`, created if needed.
+    fn get_or_insert(&mut self, data: &[u8], len: usize) -> &mut Node {
+        (0..len).fold(self, |node, bit| {
+            node.children[bit_at(data, bit)].get_or_insert_with(Default::default)
+        })
+    }
+
+    // Removes the entry of the prefix of the first `len` bits of `data`, and the nodes left with
+    // no entry below them: the chain of nodes below the last node of the path that holds an
+    // entry, or a subtrie off the path.
+    fn remove(&mut self, data: &[u8], len: usize) -> Option<Value> {
+        let mut cut = 0;
+        let mut node = &*self;
+        for bit in 0..len {
+            if node.entry.is_some() || node.children[1 - bit_at(data, bit)].is_some() {
+                cut = bit;
+            }
+            node = node.children[bit_at(data, bit)].as_deref()?;
+        }
+        node.entry.as_ref()?;
+        if node.children.iter().any(Option::is_some) {
+            cut = len;
+        }
+
+        let kept = (0..cut).try_fold(self, |node, bit| node.children[bit_at(data, bit)].as_deref_mut())?;
+        if cut == len {
+            return kept.entry.take().map(|(_, value)| value);
+        }
+        let mut chain = kept.children[bit_at(data, cut)].take()?;
+        let mut node = &mut *chain;
+        for bit in cut + 1..len {
+            node = node.children[bit_at(data, bit)].as_deref_mut()?;
+        }
+        node.entry.take().map(|(_, value)| value)
+    }
+
+    // Appends copies of the entries of the subtrie of the node to `entries`, shorter prefixes
+    // first.
+    fn copy_entries(&self, entries: &mut Vec<(Vec<u8>, Vec<u8>)>) {
+        let mut nodes = vec![self];
+        while let Some(node) = nodes.pop() {
+            if let Some((ref key, ref value)) = node.entry {
+                entries.push((key.clone(), read_value(value)));
+            }
+            nodes.extend(node.children.iter().rev().flatten().map(|child| &**child));
+        }
+    }
+}
+
+// The trie has one level per bit of the data of the keys: its nodes are dropped iteratively, rather
+// than recursively on the stack of the thread.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut nodes: Vec<Box<Node>> = self.children.iter_mut().filter_map(Option::take).collect();
+        while let Some(mut node) = nodes.pop() {
+            nodes.extend(node.children.iter_mut().filter_map(Option::take));
+        }
+    }
+}
+
+// The values of an array map: in memory, or in a file mapped in memory with `mmap()`, shared with
+// the other processes mapping it, see module `pin`.
+pub(crate) enum ArrayValues {
+    Heap(Box<[AtomicU8]>),
+    Mapped {
+        // The mapping, unmapped when the values are dropped.
+        addr:    *mut libc::c_void,
+        map_len: usize,
+        // The position of the values in the mapping.
+        offset:  usize,
+        len:     usize,
+    },
+}
+
+// The mapping is only accessed through atomic bytes.
+unsafe impl Send for ArrayValues {}
+unsafe impl Sync for ArrayValues {}
+
+impl Deref for ArrayValues {
+    type Target = [AtomicU8];
+
+    fn deref(&self) -> &[AtomicU8] {
+        match *self {
+            ArrayValues::Heap(ref values) => values,
+            ArrayValues::Mapped { addr, offset, len, .. } => unsafe {
+                std::slice::from_raw_parts((addr as *const AtomicU8).add(offset), len)
+            },
+        }
+    }
+}
+
+impl Drop for ArrayValues {
+    fn drop(&mut self) {
+        if let ArrayValues::Mapped { addr, map_len, .. } = *self {
+            unsafe {
+                libc::munmap(addr, map_len);
+            }
+        }
+    }
+}
+
+/// An iterator over copies of the entries of a map, returned by `Map::iter()`: pairs of a key and
+/// a value.
+#[derive(Debug)]
+pub struct Iter {
+    entries: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Iterator for Iter {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Iter {}
+
+// A value returned to a program by a lookup: its address and its size. For hash maps, the
+// reference keeps the value alive until the program exits, even if the entry is updated or
+// deleted in the meantime.
+pub(crate) struct ValuePtr {
+    pub addr: u64,
+    pub len:  usize,
+    _value:   Option<Value>,
+}
+
+impl ValuePtr {
+    // A buffer of `len` bytes set to zero, in no map, lent to a program as a value: the interpreter
+    // passes the keys of the elements to the callbacks of `bpf_for_each_map_elem()` this way, and
+    // runs them on such a stack.
+    pub(crate) fn zeroed(len: usize) -> ValuePtr {
+        let value = new_value(&vec![0; len]);
+        ValuePtr { addr: value.as_ptr() as u64, len, _value: Some(value) }
+    }
+}
+
+/// An eBPF map, see the module documentation.
+pub struct Map {
+    map_type:    MapType,
+    key_size:    usize,
+    value_size:  usize,
+    max_entries: usize,
+    storage:     Storage,
+}
+
+fn new_value(bytes: &[u8]) -> Value {
+    bytes.iter().map(|&b| AtomicU8::new(b)).collect()
+}
+
+// The shard of a hash map holding `key`.
+fn shard<'s>(shards: &'s [Shard], key: &[u8]) -> &'s Shard {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    &shards[hasher.finish() as usize % shards.len()]
+}
+
+fn read_value(value: &[AtomicU8]) -> Vec<u8> {
+    value.iter().map(|b| b.load(Ordering::Relaxed)).collect()
+}
+
+// The prefix length of a key of an LPM trie.
+fn prefixlen(key: &[u8]) -> u32 {
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&key[..4]);
+    u32::from_le_bytes(len)
+}
+
+// The bit of index `bit` of `data`, most significant bits first, as the bits of the prefixes of
+// an LPM trie.
+fn bit_at(data: &[u8], bit: usize) -> usize {
+    (data[bit / 8] >> (7 - bit % 8) & 1) as usize
+}
+
+// The key of an LPM trie for the first `len` bits of the data of `key`, with the other bits
+// cleared.
+fn lpm_key(key: &[u8], len: u32) -> Vec<u8> {
+    let mut lpm_key = len.to_le_bytes().to_vec();
+    lpm_key.extend(key[4..].iter().enumerate().map(|(i, &byte)| {
+        match (len as usize).saturating_sub(i * 8) {
+            0            => 0,
+            bits @ 1..=7 => byte & !(0xff >> bits),
+            _            => byte,
+        }
+    }));
+    lpm_key
+}
+
+impl Map {
+
+    /// Create a map of type `map_type`, for keys of `key_size` bytes and values of `value_size`
+    /// bytes, holding at most `max_entries` entries.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the sizes or the number of entries is 0, if the keys of an array map or
+    /// of a map of redirect targets are not 4-byte long, or if the keys of an LPM trie hold no
+    /// data or more than 256 bytes of data.
+    pub fn new(map_type: MapType, key_size: usize, value_size: usize, max_entries: usize) -> Map {
+        if let Err(msg) = Map::check(map_type, key_size, value_size, max_entries) {
+            panic!("{}", msg);
+        }
+        let storage = match map_type {
+            MapType::Hash | MapType::DevMap => Storage::Hash {
+                shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+                len:    AtomicUsize::new(0),
+            },
+            MapType::LpmTrie => Storage::Trie {
+                root: RwLock::new(Node::default()),
+                len:  AtomicUsize::new(0),
+            },
+            MapType::Array => {
+                let values = (0..value_size * max_entries).map(|_| AtomicU8::new(0)).collect();
+                Storage::Array(ArrayValues::Heap(values))
+            },
+        };
+        Map { map_type, key_size, value_size, max_entries, storage }
+    }
+
+    // Checks the definition of a map, as `new()` does, returning the message to panic with.
+    pub(crate) fn check(map_type: MapType, key_size: usize, value_size: usize, max_entries: usize)
+        -> Result<(), String> {
+        if key_size == 0 || value_size == 0 || max_entries == 0 {
+            return Err(format!("Error: invalid map with key size {:?}, value size {:?} and {:?} entries",
+                               key_size, value_size, max_entries));
+        }
+        match map_type {
+            MapType::DevMap if key_size != 4 => {
+                Err(format!("Error: invalid key size {:?} for map of redirect targets, expected 4", key_size))
+            },
+            MapType::LpmTrie if key_size <= 4 || key_size > 4 + LPM_DATA_SIZE_MAX => {
+                Err(format!("Error: invalid key size {:?} for LPM trie, expected more than 4 and at most {}",
+                            key_size, 4 + LPM_DATA_SIZE_MAX))
+            },
+            MapType::Array if key_size != 4 => {
+                Err(format!("Error: invalid key size {:?} for array map, expected 4", key_size))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Creates an array map holding its values in `values`, of `value_size * max_entries` bytes.
+    pub(crate) fn with_array_values(value_size: usize, max_entries: usize, values: ArrayValues) -> Map {
+        Map { map_type: MapType::Array, key_size: 4, value_size, max_entries, storage: Storage::Array(values) }
+    }
+
+    // Creates a map stored as a hash table, holding its entries in the shared memory segment of
+    // `table`.
+    pub(crate) fn with_shared_table(map_type: MapType, key_size: usize, value_size: usize,
+                                    max_entries: usize, table: Table) -> Map {
+        Map { map_type, key_size, value_size, max_entries, storage: Storage::Shared(table) }
+    }
+
+    /// The type of the map.
+    pub fn map_type(&self) -> MapType {
+        self.map_type
+    }
+
+    /// The size of the keys of the map.
+    pub fn key_size(&self) -> usize {
+        self.key_size
+    }
+
+    /// The size of the values of the map.
+    pub fn value_size(&self) -> usize {
+        self.value_size
+    }
+
+    /// The maximum number of entries of the map.
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// The number of entries of the map. Arrays always have `max_entries()` entries.
+    pub fn len(&self) -> usize {
+        match self.storage {
+            Storage::Hash { ref len, .. } | Storage::Trie { ref len, .. } => len.load(Ordering::Relaxed),
+            Storage::Array(_)          => self.max_entries,
+            Storage::Shared(ref table) => table.len(),
+        }
+    }
+
+    /// Whether the map has no entry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The memory used by the entries of the map, in bytes: the keys and values of the entries
+    /// of hash maps, and all the values of arrays.
+    pub fn memory_usage(&self) -> usize {
+        match self.storage {
+            Storage::Hash { .. } | Storage::Trie { .. } | Storage::Shared(_) => {
+                self.len() * (self.key_size + self.value_size)
+            },
+            Storage::Array(ref values) => values.len(),
+        }
+    }
+
+    // The index of `key` in an array map or a map of redirect targets, if it is in range. Indexes
+    // are in the byte order of the host, as the programs store them.
+    fn index(&self, key: &[u8]) -> Option<usize> {
+        let mut index = [0u8; 4];
+        index.copy_from_slice(key);
+        let index = u32::from_ne_bytes(index) as usize;
+        match index < self.max_entries {
+            true  => Some(index),
+            false => None,
+        }
+    }
+
+    pub(crate) fn lookup_ptr(&self, key: &[u8]) -> Option<ValuePtr> {
+        if key.len() != self.key_size {
+            return None;
+        }
+        match self.storage {
+            Storage::Hash { ref shards, .. } => {
+                let value = shard(shards, key).read().unwrap().get(key).cloned()?;
+                Some(ValuePtr { addr: value.as_ptr() as u64, len: value.len(), _value: Some(value) })
+            },
+            Storage::Trie { ref root, .. } => {
+                let value = self.trie_lookup(root, key)?;
+                Some(ValuePtr { addr: value.as_ptr() as u64, len: value.len(), _value: Some(value) })
+            },
+            Storage::Array(ref values) => {
+                let start = self.index(key)? * self.value_size;
+                let addr = values[start..].as_ptr() as u64;
+                Some(ValuePtr { addr, len: self.value_size, _value: None })
+            },
+            Storage::Shared(ref table) => {
+                let addr = table.lookup_addr(key)?;
+                Some(ValuePtr { addr, len: self.value_size, _value: None })
+            },
+        }
+    }
+
+    // The value of the longest prefix of `key` in an LPM trie: the last entry on the path of the
+    // bits of the data of `key`, up to its prefix length.
+    fn trie_lookup(&self, root: &RwLock<Node>, key: &[u8]) -> Option<Value> {
+        let data = &key[4..];
+        let root = root.read().unwrap();
+        let mut node = &*root;
+        let mut found = node.entry.as_ref();
+        for bit in 0..(prefixlen(key) as usize).min(data.len() * 8) {
+            node = match node.children[bit_at(data, bit)] {
+                Some(ref child) => child,
+                None            => break,
+            };
+            found = node.entry.as_ref().or(found);
+        }
+        found.map(|(_, value)| value.clone())
+    }
+
+    // The prefix length of `key`, for an LPM trie, or `None` if the prefix is longer than the
+    // data.
+    fn trie_prefixlen(&self, key: &[u8]) -> Option<usize> {
+        match prefixlen(key) as usize {
+            len if len > (self.key_size - 4) * 8 => None,
+            len => Some(len),
+        }
+    }
+
+    // The address of the values of an array map, which are never moved: the value for index `i`
+    // is at offset `i * value_size()`.
+    pub(crate) fn array_values(&self) -> Option<u64> {
+        match self.storage {
+            Storage::Array(ref values) => Some(values.as_ptr() as u64),
+            Storage::Hash { .. } | Storage::Trie { .. } | Storage::Shared(_) => None,
+        }
+    }
+
+    /// A copy of the value associated with `key`, if any.
+    pub fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if key.len() != self.key_size {
+            return None;
+        }
+        match self.storage {
+            Storage::Hash { ref shards, .. } => {
+                shard(shards, key).read().unwrap().get(key).map(|value| read_value(value))
+            },
+            Storage::Trie { ref root, .. } => self.trie_lookup(root, key).map(|value| read_value(&value)),
+            Storage::Array(ref values) => {
+                let start = self.index(key)? * self.value_size;
+                Some(read_value(&values[start..start + self.value_size]))
+            },
+            Storage::Shared(ref table) => table.lookup(key),
+        }
+    }
+
+    /// A copy of the values associated with each of `keys`, in the same order, as `lookup()`.
+    pub fn lookup_batch(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        keys.iter().map(|key| self.lookup(key)).collect()
+    }
+
+    /// An iterator over copies of the entries of the map, taken when it is called: the keys and
+    /// values of hash maps, in no particular order, and all the values of arrays, in the order of
+    /// their indexes, with the indexes as keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::maps::{self, Map, MapType};
+    ///
+    /// let map = Map::new(MapType::Hash, 2, 1, 8);
+    /// map.update(&[0, 1], &[10], maps::BPF_ANY).unwrap();
+    /// map.update(&[0, 2], &[20], maps::BPF_ANY).unwrap();
+    ///
+    /// let mut entries: Vec<_> = map.iter().collect();
+    /// entries.sort();
+    /// assert_eq!(entries, vec![(vec![0, 1], vec![10]), (vec![0, 2], vec![20])]);
+    ///
+    /// let array = Map::new(MapType::Array, 4, 1, 2);
+    /// assert_eq!(array.iter().map(|(_, value)| value[0]).sum::<u8>(), 0);
+    ///
+    /// map.clear();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn iter(&self) -> Iter {
+        let entries: Vec<_> = match self.storage {
+            Storage::Hash { ref shards, .. } => {
+                shards.iter().flat_map(|shard| {
+                    shard.read().unwrap().iter()
+                        .map(|(key, value)| (key.clone(), read_value(value)))
+                        .collect::<Vec<_>>()
+                }).collect()
+            },
+            Storage::Trie { ref root, .. } => {
+                let mut entries = vec![];
+                root.read().unwrap().copy_entries(&mut entries);
+                entries
+            },
+            Storage::Array(ref values) => {
+                values.chunks(self.value_size).enumerate()
+                    .map(|(index, value)| ((index as u32).to_ne_bytes().to_vec(), read_value(value)))
+                    .collect()
+            },
+            Storage::Shared(ref table) => table.entries(),
+        };
+        Iter { entries: entries.into_iter() }
+    }
+
+    /// Remove all the entries of a hash map, or set all the values of an array to zero.
+    pub fn clear(&self) {
+        match self.storage {
+            Storage::Hash { ref shards, ref len } => {
+                for shard in shards {
+                    let mut shard = shard.write().unwrap();
+                    len.fetch_sub(shard.len(), Ordering::Relaxed);
+                    shard.clear();
+                }
+            },
+            Storage::Trie { ref root, ref len } => {
+                let mut root = root.write().unwrap();
+                *root = Node::default();
+                len.store(0, Ordering::Relaxed);
+            },
+            Storage::Array(ref values) => {
+                for byte in values.iter() {
+                    byte.store(0, Ordering::Relaxed);
+                }
+            },
+            Storage::Shared(ref table) => table.clear(),
+        }
+    }
+
+    /// Associate `value` with `key`, as helper `bpf_map_update_elem()` does: `flags` is one of
+    /// `BPF_ANY`, `BPF_NOEXIST` and `BPF_EXIST`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error code for the helper to return, as a positive integer: `EINVAL` if the
+    /// sizes of the key or value, the prefix length of a key of an LPM trie, or the flags, are
+    /// invalid, `EEXIST` or `ENOENT` if the entry exists, or does not, against `flags`, and
+    /// `E2BIG` if the map is full or the index of an array or map of redirect targets out of
+    /// range.
+    pub fn update(&self, key: &[u8], value: &[u8], flags: u64) -> Result<(), i64> {
+        if key.len() != self.key_size || value.len() != self.value_size || flags > BPF_EXIST {
+            return Err(EINVAL);
+        }
+        if self.map_type == MapType::DevMap && self.index(key).is_none() {
+            return Err(E2BIG);
+        }
+        match self.storage {
+            Storage::Hash { ref shards, ref len } => {
+                let mut shard = shard(shards, key).write().unwrap();
+                match (shard.contains_key(key), flags) {
+                    (true, BPF_NOEXIST) => return Err(EEXIST),
+                    (false, BPF_EXIST)  => return Err(ENOENT),
+                    (false, _) => {
+                        if len.fetch_add(1, Ordering::Relaxed) >= self.max_entries {
+                            len.fetch_sub(1, Ordering::Relaxed);
+                            return Err(E2BIG);
+                        }
+                    },
+                    (true, _) => (),
+                }
+                shard.insert(key.to_vec(), new_value(value));
+            },
+            Storage::Trie { ref root, ref len } => {
+                let prefixlen = self.trie_prefixlen(key).ok_or(EINVAL)?;
+                let mut root = root.write().unwrap();
+                let exists = root.get(&key[4..], prefixlen).and_then(|node| node.entry.as_ref()).is_some();
+                match (exists, flags) {
+                    (true, BPF_NOEXIST) => return Err(EEXIST),
+                    (false, BPF_EXIST)  => return Err(ENOENT),
+                    (false, _) => {
+                        if len.load(Ordering::Relaxed) >= self.max_entries {
+                            return Err(E2BIG);
+                        }
+                        len.fetch_add(1, Ordering::Relaxed);
+                    },
+                    (true, _) => (),
+                }
+                let node = root.get_or_insert(&key[4..], prefixlen);
+                node.entry = Some((lpm_key(key, prefixlen as u32), new_value(value)));
+            },
+            Storage::Array(ref values) => {
+                let start = self.index(key).ok_or(E2BIG)? * self.value_size;
+                if flags == BPF_NOEXIST {
+                    return Err(EEXIST);
+                }
+                for (dst, &src) in values[start..start + self.value_size].iter().zip(value) {
+                    dst.store(src, Ordering::Relaxed);
+                }
+            },
+            Storage::Shared(ref table) => table.update(key, value, flags, self.max_entries)?,
+        }
+        Ok(())
+    }
+
+    /// Remove the entry of `key`, as helper `bpf_map_delete_elem()` does.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error code for the helper to return, as a positive integer: `ENOENT` if there
+    /// is no such entry, and `EINVAL` for array maps, or if the size of the key, or the prefix
+    /// length of a key of an LPM trie, is invalid.
+    pub fn delete(&self, key: &[u8]) -> Result<(), i64> {
+        if key.len() != self.key_size {
+            return Err(EINVAL);
+        }
+        match self.storage {
+            Storage::Hash { ref shards, ref len } => {
+                match shard(shards, key).write().unwrap().remove(key) {
+                    Some(_) => {
+                        len.fetch_sub(1, Ordering::Relaxed);
+                        Ok(())
+                    },
+                    None    => Err(ENOENT),
+                }
+            },
+            Storage::Trie { ref root, ref len } => {
+                let prefixlen = self.trie_prefixlen(key).ok_or(EINVAL)?;
+                match root.write().unwrap().remove(&key[4..], prefixlen) {
+                    Some(_) => {
+                        len.fetch_sub(1, Ordering::Relaxed);
+                        Ok(())
+                    },
+                    None    => Err(ENOENT),
+                }
+            },
+            Storage::Array(_) => Err(EINVAL),
+            Storage::Shared(ref table) => table.delete(key),
+        }
+    }
+}
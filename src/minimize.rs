@@ -0,0 +1,185 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module reduces eBPF programs triggering an issue to small reproducers, to be attached to
+//! bug reports.
+//!
+//! The issue is described by a predicate on programs, such as `jit_mismatch()` (the interpreter
+//! and the JIT-compiler disagree) or `verifier_panics()`. `minimize()` then removes chunks of
+//! instructions, in the manner of delta debugging, and neutralizes the remaining ones, as long as
+//! the predicate holds. Jumps over removed instructions are adjusted, so that the programs
+//! reduced keep their control flow.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::error::EbpfError;
+//! use rbpf::minimize;
+//!
+//! let prog = vec![
+//!     0xb7, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r0, 5
+//!     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+//!     0x15, 0x00, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, // jeq r0, 3, +1
+//!     0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+//!     0x3f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r2
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//! // Reduce while the program divides by zero.
+//! let reduced = minimize::minimize(&prog, |p| {
+//!     let p = p.to_vec();
+//!     rbpf::verifier::try_check(&p).is_ok() &&
+//!         matches!(rbpf::EbpfVmNoData::new(&p).try_prog_exec(), Err(EbpfError::DivideByZero { .. }))
+//! });
+//! assert_eq!(reduced, vec![
+//!     0x3f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r2
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ]);
+//! ```
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use analysis;
+use ebpf::{self, Insn};
+use program::splice_insns;
+use verifier;
+use EbpfVmRaw;
+
+fn encode(insns: &[Insn]) -> Vec<u8> {
+    insns.iter().flat_map(|insn| insn.to_array().to_vec()).collect()
+}
+
+// The indices of the first instructions of the units that can be removed, that is the
+// instructions other than the second halves of `lddw`, followed by the length of the program.
+fn units(insns: &[Insn]) -> Vec<usize> {
+    let mut units = vec![];
+    let mut idx = 0;
+    while idx < insns.len() {
+        units.push(idx);
+        idx += if insns[idx].opc == ebpf::LD_DW_IMM { 2 } else { 1 };
+    }
+    units.push(insns.len().min(idx));
+    units
+}
+
+// Removes chunks of instructions while `test` holds. The program is split in `n` chunks, each of
+// which is removed in turn: `n` decreases when a chunk can be removed, and doubles otherwise, down
+// to chunks of single instructions.
+fn remove_chunks<F: FnMut(&[Insn]) -> bool>(mut insns: Vec<Insn>, test: &mut F) -> Vec<Insn> {
+    let mut n = 2;
+    loop {
+        let units = units(&insns);
+        let count = units.len() - 1;
+        if count == 0 {
+            return insns;
+        }
+        let n_chunks = n.min(count);
+        let candidate = (0..n_chunks)
+            .map(|k| units[k * count / n_chunks]..units[(k + 1) * count / n_chunks])
+            .filter_map(|range| splice_insns(&insns, range, &[]).ok())
+            .find(|candidate| test(candidate));
+        match candidate {
+            Some(candidate) => {
+                insns = candidate;
+                n = n_chunks.saturating_sub(1).max(2);
+            },
+            None if n_chunks == count => return insns,
+            None => n = (2 * n_chunks).min(count),
+        }
+    }
+}
+
+// The simpler instructions that can replace `insn`: conditional jumps become unconditional, or
+// no-ops, and immediate values become zero.
+fn simplifications(insn: &Insn) -> Vec<Insn> {
+    let ja = |off: i16| Insn { opc: ebpf::JA, dst: 0, src: 0, off, imm: 0 };
+    if ebpf::is_conditional_jump(insn.opc) {
+        vec![ja(insn.off), ja(0)]
+    } else if insn.imm != 0 && ![ebpf::CALL, ebpf::LD_DW_IMM, ebpf::LE, ebpf::BE].contains(&insn.opc) {
+        vec![Insn { imm: 0, ..*insn }]
+    } else {
+        vec![]
+    }
+}
+
+/// Reduce `prog` to a smaller program for which `predicate` still holds, for example a program
+/// triggering the same bug. Chunks of instructions are removed while the predicate holds, then
+/// conditional jumps are made unconditional or replaced with no-ops, and immediate values set to
+/// zero, one instruction at a time; the two steps are repeated until none of them changes the
+/// program.
+///
+/// The programs passed to the predicate may not be accepted by the verifier, and their loops may
+/// no longer terminate: the predicate should check them first, as `jit_mismatch()` does. If the
+/// predicate does not hold for `prog`, it is returned unchanged.
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn minimize<F: FnMut(&[u8]) -> bool>(prog: &[u8], mut predicate: F) -> Vec<u8> {
+    if !predicate(prog) {
+        return prog.to_vec();
+    }
+    let mut insns: Vec<Insn> = (0..prog.len() / ebpf::INSN_SIZE)
+        .map(|i| ebpf::get_insn(prog, i))
+        .collect();
+    let mut test = |insns: &[Insn]| predicate(&encode(insns));
+    loop {
+        let size = insns.len();
+        insns = remove_chunks(insns, &mut test);
+        let len = insns.len();
+        let mut simplified = false;
+        for idx in units(&insns).into_iter().filter(|&idx| idx < len) {
+            for insn in simplifications(&insns[idx]) {
+                let mut candidate = insns.clone();
+                candidate[idx] = insn;
+                if test(&candidate) {
+                    insns = candidate;
+                    simplified = true;
+                    break;
+                }
+            }
+        }
+        if !simplified && len == size {
+            return encode(&insns);
+        }
+    }
+}
+
+/// A predicate for `minimize()`: whether the interpreter and the JIT-compiler return different
+/// values, or leave the packet in different states, when running the program on `input` as packet
+/// data, as with `EbpfVmRaw`. Faults of the JIT-compiled program count as differences.
+///
+/// The predicate does not hold for the programs rejected by the verifier, the programs with loops
+/// that `analysis::worst_case()` cannot bound, the programs that the interpreter fails to run,
+/// and the programs that the JIT-compiler fails to compile.
+pub fn jit_mismatch(input: &[u8]) -> impl FnMut(&[u8]) -> bool {
+    let input = input.to_vec();
+    move |prog| {
+        let prog = prog.to_vec();
+        if verifier::try_check(&prog).is_err() || analysis::worst_case(&prog, &HashMap::new()).is_err() {
+            return false;
+        }
+        let (mut interpreted_mem, mut jit_mem) = (input.clone(), input.clone());
+        let mut vm = EbpfVmRaw::new(&prog);
+        vm.set_catch_faults(true);
+        let interpreted = match panic::catch_unwind(AssertUnwindSafe(|| vm.try_prog_exec(&mut interpreted_mem))) {
+            Ok(Ok(res)) => res,
+            _           => return false,
+        };
+        if vm.try_jit_compile().is_err() {
+            return false;
+        }
+        vm.try_prog_exec_jit(&mut jit_mem) != Ok(interpreted) || interpreted_mem != jit_mem
+    }
+}
+
+/// A predicate for `minimize()`: whether the simple verifier panics on the program, instead of
+/// accepting or rejecting it. The panics are still reported by the panic hook.
+pub fn verifier_panics(prog: &[u8]) -> bool {
+    let prog = prog.to_vec();
+    panic::catch_unwind(|| verifier::try_check(&prog)).is_err()
+}
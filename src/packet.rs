@@ -0,0 +1,271 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module builds packets to test filters with, instead of hand-crafted hexadecimal dumps:
+//! `PacketBuilder` stacks an Ethernet header, an optional VLAN tag, an IPv4 or IPv6 header, and a
+//! TCP or UDP header over a payload, and fills in the lengths, the protocol fields and the
+//! checksums.
+//!
+//! Multi-byte fields are stored in network byte order, and checksums are computed with
+//! `csum::Checksum`, including the pseudo-header of TCP and UDP.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::packet::PacketBuilder;
+//!
+//! // Returns 1 if the packet is a UDP packet over IPv4, and 0 otherwise.
+//! let prog = rbpf::assembler::assemble("
+//!     mov64 r0, 0
+//!     ldxh r2, [r1+12]
+//!     jne r2, 0x0008, +3
+//!     ldxb r2, [r1+23]
+//!     jne r2, 17, +1
+//!     mov64 r0, 1
+//!     exit
+//! ").unwrap();
+//!
+//! let mut udp = PacketBuilder::new()
+//!     .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+//!     .ipv4([192, 168, 0, 1], [192, 168, 0, 2])
+//!     .udp(4000, 53)
+//!     .payload(b"query")
+//!     .build();
+//! assert_eq!(udp.len(), 14 + 20 + 8 + 5);
+//!
+//! let mut tcp = PacketBuilder::new()
+//!     .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+//!     .ipv4([192, 168, 0, 1], [192, 168, 0, 2])
+//!     .tcp(4000, 80, rbpf::packet::TCP_SYN)
+//!     .build();
+//!
+//! let vm = rbpf::EbpfVmRaw::new(&prog);
+//! assert_eq!(vm.prog_exec(&mut udp), 1);
+//! assert_eq!(vm.prog_exec(&mut tcp), 0);
+//! ```
+
+use std::convert::TryFrom;
+
+use csum::Checksum;
+
+/// Ethertype of IPv4.
+pub const ETH_P_IP: u16 = 0x0800;
+/// Ethertype of IPv6.
+pub const ETH_P_IPV6: u16 = 0x86dd;
+/// Ethertype of 802.1Q VLAN tags.
+pub const ETH_P_8021Q: u16 = 0x8100;
+/// IP protocol number of TCP.
+pub const IPPROTO_TCP: u8 = 6;
+/// IP protocol number of UDP.
+pub const IPPROTO_UDP: u8 = 17;
+/// IP protocol number meaning that no header follows, used when the packet has no TCP or UDP
+/// header.
+pub const IPPROTO_NONE: u8 = 59;
+
+/// TCP flag FIN.
+pub const TCP_FIN: u8 = 0x01;
+/// TCP flag SYN.
+pub const TCP_SYN: u8 = 0x02;
+/// TCP flag RST.
+pub const TCP_RST: u8 = 0x04;
+/// TCP flag PSH.
+pub const TCP_PSH: u8 = 0x08;
+/// TCP flag ACK.
+pub const TCP_ACK: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Ip {
+    V4 { src: [u8; 4], dst: [u8; 4] },
+    V6 { src: [u8; 16], dst: [u8; 16] },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Tcp { src: u16, dst: u16, flags: u8 },
+    Udp { src: u16, dst: u16 },
+}
+
+/// A builder of packets, see the module documentation. Each header is optional, but a TCP or UDP
+/// header requires an IP header, and a VLAN tag an Ethernet header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketBuilder {
+    ethernet:  Option<([u8; 6], [u8; 6])>,
+    vlan:      Option<u16>,
+    ip:        Option<Ip>,
+    ttl:       u8,
+    transport: Option<Transport>,
+    payload:   Vec<u8>,
+}
+
+impl Default for PacketBuilder {
+    fn default() -> PacketBuilder {
+        PacketBuilder { ethernet: None, vlan: None, ip: None, ttl: 64, transport: None, payload: vec![] }
+    }
+}
+
+impl PacketBuilder {
+
+    /// Create a builder of an empty packet.
+    pub fn new() -> PacketBuilder {
+        PacketBuilder::default()
+    }
+
+    /// Add an Ethernet header, from MAC address `src` to MAC address `dst`.
+    pub fn ethernet(mut self, src: [u8; 6], dst: [u8; 6]) -> PacketBuilder {
+        self.ethernet = Some((src, dst));
+        self
+    }
+
+    /// Add an 802.1Q VLAN tag to the Ethernet header, with tag control information `tci`: the
+    /// priority in the upper 3 bits, and the VLAN identifier in the lower 12 bits.
+    pub fn vlan(mut self, tci: u16) -> PacketBuilder {
+        self.vlan = Some(tci);
+        self
+    }
+
+    /// Add an IPv4 header, from address `src` to address `dst`.
+    pub fn ipv4(mut self, src: [u8; 4], dst: [u8; 4]) -> PacketBuilder {
+        self.ip = Some(Ip::V4 { src, dst });
+        self
+    }
+
+    /// Add an IPv6 header, from address `src` to address `dst`.
+    pub fn ipv6(mut self, src: [u8; 16], dst: [u8; 16]) -> PacketBuilder {
+        self.ip = Some(Ip::V6 { src, dst });
+        self
+    }
+
+    /// Set the time to live of the IPv4 header, or the hop limit of the IPv6 header. 64 by
+    /// default.
+    pub fn ttl(mut self, ttl: u8) -> PacketBuilder {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Add a TCP header, from port `src` to port `dst`, with flags `flags`, such as `TCP_SYN`.
+    /// The sequence and acknowledgment numbers are zero, and the window is 65535.
+    pub fn tcp(mut self, src: u16, dst: u16, flags: u8) -> PacketBuilder {
+        self.transport = Some(Transport::Tcp { src, dst, flags });
+        self
+    }
+
+    /// Add a UDP header, from port `src` to port `dst`.
+    pub fn udp(mut self, src: u16, dst: u16) -> PacketBuilder {
+        self.transport = Some(Transport::Udp { src, dst });
+        self
+    }
+
+    /// Set the payload following the headers.
+    pub fn payload(mut self, payload: &[u8]) -> PacketBuilder {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    // The TCP or UDP header, with a zero checksum, followed by the payload.
+    fn transport_segment(&self) -> Vec<u8> {
+        let mut segment = vec![];
+        match self.transport {
+            Some(Transport::Tcp { src, dst, flags }) => {
+                segment.extend_from_slice(&src.to_be_bytes());
+                segment.extend_from_slice(&dst.to_be_bytes());
+                segment.extend_from_slice(&[0; 8]);
+                // Data offset of 5 words, no options.
+                segment.extend_from_slice(&[0x50, flags]);
+                segment.extend_from_slice(&u16::MAX.to_be_bytes());
+                segment.extend_from_slice(&[0; 4]);
+            },
+            Some(Transport::Udp { src, dst }) => {
+                segment.extend_from_slice(&src.to_be_bytes());
+                segment.extend_from_slice(&dst.to_be_bytes());
+                segment.extend_from_slice(&((8 + self.payload.len()) as u16).to_be_bytes());
+                segment.extend_from_slice(&[0; 2]);
+            },
+            None => (),
+        }
+        segment.extend_from_slice(&self.payload);
+        segment
+    }
+
+    /// Build the packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the packet has a TCP or UDP header without an IP header, or a VLAN tag without
+    /// an Ethernet header, or if it is too long for the length fields of its headers.
+    pub fn build(&self) -> Vec<u8> {
+        assert!(self.transport.is_none() || self.ip.is_some(), "TCP and UDP headers require an IP header");
+        assert!(self.vlan.is_none() || self.ethernet.is_some(), "VLAN tags require an Ethernet header");
+        let (protocol, csum_offset) = match self.transport {
+            Some(Transport::Tcp { .. }) => (IPPROTO_TCP, 16),
+            Some(Transport::Udp { .. }) => (IPPROTO_UDP, 6),
+            None                        => (IPPROTO_NONE, 0),
+        };
+
+        let mut segment = self.transport_segment();
+        let len = segment.len();
+        let mut ip_header = vec![];
+        let mut pseudo_header = vec![];
+        match self.ip {
+            Some(Ip::V4 { src, dst }) => {
+                let total_len = u16::try_from(20 + len).expect("packet too long for IPv4");
+                ip_header.extend_from_slice(&[0x45, 0]);
+                ip_header.extend_from_slice(&total_len.to_be_bytes());
+                // Identification 0, flag "don't fragment".
+                ip_header.extend_from_slice(&[0, 0, 0x40, 0, self.ttl, protocol, 0, 0]);
+                ip_header.extend_from_slice(&src);
+                ip_header.extend_from_slice(&dst);
+                let check = Checksum::new(0).add(&ip_header).finish();
+                ip_header[10..12].copy_from_slice(&check.to_ne_bytes());
+                pseudo_header.extend_from_slice(&src);
+                pseudo_header.extend_from_slice(&dst);
+                pseudo_header.extend_from_slice(&[0, protocol]);
+                pseudo_header.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            Some(Ip::V6 { src, dst }) => {
+                let payload_len = u16::try_from(len).expect("packet too long for IPv6");
+                ip_header.extend_from_slice(&[0x60, 0, 0, 0]);
+                ip_header.extend_from_slice(&payload_len.to_be_bytes());
+                ip_header.extend_from_slice(&[protocol, self.ttl]);
+                ip_header.extend_from_slice(&src);
+                ip_header.extend_from_slice(&dst);
+                pseudo_header.extend_from_slice(&src);
+                pseudo_header.extend_from_slice(&dst);
+                pseudo_header.extend_from_slice(&(len as u32).to_be_bytes());
+                pseudo_header.extend_from_slice(&[0, 0, 0, protocol]);
+            },
+            None => (),
+        }
+        if self.transport.is_some() {
+            let mut check = Checksum::new(0).add(&pseudo_header).add(&segment).finish();
+            // A zero UDP checksum means that the checksum is not computed.
+            if check == 0 && protocol == IPPROTO_UDP {
+                check = 0xffff;
+            }
+            segment[csum_offset..csum_offset + 2].copy_from_slice(&check.to_ne_bytes());
+        }
+
+        let mut packet = vec![];
+        if let Some((src, dst)) = self.ethernet {
+            packet.extend_from_slice(&dst);
+            packet.extend_from_slice(&src);
+            if let Some(tci) = self.vlan {
+                packet.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+                packet.extend_from_slice(&tci.to_be_bytes());
+            }
+            let ethertype = match self.ip {
+                Some(Ip::V4 { .. }) => ETH_P_IP,
+                Some(Ip::V6 { .. }) => ETH_P_IPV6,
+                // The length of the payload, as in IEEE 802.3 frames.
+                None                => len as u16,
+            };
+            packet.extend_from_slice(&ethertype.to_be_bytes());
+        }
+        packet.extend_from_slice(&ip_header);
+        packet.extend_from_slice(&segment);
+        packet
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module pins programs and maps to a directory, as the Linux kernel does to the BPF file
+//! system (bpffs): a pinned object outlives the process that created it, and other processes open
+//! it by its name.
+//!
+//! A `PinDir` holds one file for each pinned object, named after it. Pinning fails if the name is
+//! already used, as in the kernel; `PinDir::unpin()` removes the file, and the objects opened from
+//! it remain valid. Files are written under a temporary name starting with a dot, then linked to
+//! their name, so that other processes never open a partial file.
+//!
+//! The maps created with `PinDir::create_map()`, or opened with `PinDir::open_map()`, are shared
+//! with the other processes opening them when they are array maps: their values are held in the
+//! pinned file, mapped in memory in each process with `mmap()`. The consistency of the values is
+//! that of the values of an array map shared between threads, see module `maps`. Other maps are
+//! pinned as a copy of their entries, taken by `PinDir::pin_map()`: opening them returns a new map
+//! holding these entries, and the updates of a process are not seen by the others.
+//!
+//! # Format
+//!
+//! All integers are little-endian. A pinned program is made of:
+//!
+//! * the magic bytes `RBPP` (4 bytes), and the version of the format, 1 (1 byte),
+//! * the version of the instruction set of the program (1 byte, 1 for `ebpf::IsaVersion::V1` to 4
+//!   for `ebpf::IsaVersion::V4`), followed by 2 bytes set to zero,
+//! * the size of the bytecode, in bytes (4 bytes), and the bytecode.
+//!
+//! A pinned map is made of a header of 64 bytes, followed by its contents:
+//!
+//! * the magic bytes `RBPM` (4 bytes), and the version of the format, 1 (1 byte), followed by 3
+//!   bytes set to zero,
+//! * the type of the map, as its identifier in the Linux kernel (4 bytes, for example 1 for
+//!   `BPF_MAP_TYPE_HASH` and 2 for `BPF_MAP_TYPE_ARRAY`),
+//! * the size of the keys, the size of the values, and the maximum number of entries (4 bytes
+//!   each),
+//! * the number of entries (4 bytes), followed by bytes set to zero up to the end of the header,
+//! * for array maps, all the values, in the order of their indexes; for other maps, the entries,
+//!   each made of a key followed by its value.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::maps::MapType;
+//! use rbpf::pin::PinDir;
+//! use rbpf::program::{Program, ProgramConfig};
+//!
+//! // Increments the 64-bit counter at index 0 of the array map.
+//! let prog = vec![
+//!     0x62, 0x0a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stw [r10-4], 0
+//!     0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+//!     0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+//!     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+//!     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+//!     0x15, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +3
+//!     0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+//!     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+//!     0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! let path = std::env::temp_dir().join(format!("rbpf-pin-doc-{}", std::process::id()));
+//! let dir = PinDir::open(&path).unwrap();
+//! dir.pin_program("counter", &Program::verify(prog, ProgramConfig::default()).unwrap()).unwrap();
+//! let counters = dir.create_map("counters", MapType::Array, 4, 8, 1).unwrap();
+//!
+//! // Another process opens the program and the map by their names.
+//! let program = dir.open_program("counter").unwrap();
+//! let mut vm = rbpf::EbpfVmNoData::from_program(&program);
+//! vm.register_map(dir.open_map("counters").unwrap());
+//! vm.prog_exec();
+//! vm.prog_exec();
+//!
+//! assert_eq!(counters.lookup(&0u32.to_le_bytes()), Some(2u64.to_le_bytes().to_vec()));
+//! assert_eq!(dir.names().unwrap(), vec!["counter", "counters"]);
+//! # std::fs::remove_dir_all(&path).unwrap();
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+use ebpf::IsaVersion;
+use maps::{self, ArrayValues, Map, MapType};
+use program::{Program, ProgramConfig};
+
+const PROGRAM_MAGIC: &[u8; 4] = b"RBPP";
+const MAP_MAGIC: &[u8; 4] = b"RBPM";
+const VERSION: u8 = 1;
+
+// The size of the header of pinned maps.
+const MAP_HEADER_SIZE: usize = 64;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32, Error> {
+    match data.get(off..off + 4) {
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        None    => Err(invalid("truncated pinned object")),
+    }
+}
+
+fn isa_id(isa: IsaVersion) -> u8 {
+    match isa {
+        IsaVersion::V1 => 1,
+        IsaVersion::V2 => 2,
+        IsaVersion::V3 => 3,
+        IsaVersion::V4 => 4,
+    }
+}
+
+// The header of a pinned map.
+struct MapHeader {
+    map_type:    MapType,
+    key_size:    usize,
+    value_size:  usize,
+    max_entries: usize,
+    len:         usize,
+}
+
+impl MapHeader {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = MAP_MAGIC.to_vec();
+        header.extend_from_slice(&[VERSION, 0, 0, 0]);
+        for x in &[self.map_type.kernel_id(), self.key_size as u32, self.value_size as u32,
+                   self.max_entries as u32, self.len as u32] {
+            header.extend_from_slice(&x.to_le_bytes());
+        }
+        header.resize(MAP_HEADER_SIZE, 0);
+        header
+    }
+
+    fn parse(data: &[u8]) -> Result<MapHeader, Error> {
+        if data.len() < MAP_HEADER_SIZE || &data[..4] != MAP_MAGIC {
+            return Err(invalid("not a pinned map"));
+        }
+        if data[4] != VERSION {
+            return Err(invalid(&format!("unsupported version {} of pinned map", data[4])));
+        }
+        let map_type = MapType::from_kernel_id(read_u32(data, 8)?)
+            .ok_or_else(|| invalid("unsupported type of pinned map"))?;
+        let header = MapHeader {
+            map_type,
+            key_size:    read_u32(data, 12)? as usize,
+            value_size:  read_u32(data, 16)? as usize,
+            max_entries: read_u32(data, 20)? as usize,
+            len:         read_u32(data, 24)? as usize,
+        };
+        Map::check(map_type, header.key_size, header.value_size, header.max_entries)
+            .map_err(|msg| invalid(&msg))?;
+        Ok(header)
+    }
+
+    // The size of the contents of the map following the header.
+    fn contents_size(&self) -> usize {
+        match self.map_type {
+            MapType::Array => self.value_size * self.max_entries,
+            _              => self.len * (self.key_size + self.value_size),
+        }
+    }
+}
+
+// Maps the `len` bytes of `file` in memory, shared with the other processes mapping it, and
+// returns the values of the array map it holds.
+fn map_values(file: &File, header: &MapHeader, len: usize) -> Result<ArrayValues, Error> {
+    let addr = unsafe {
+        libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                   file.as_raw_fd(), 0)
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    Ok(ArrayValues::Mapped {
+        addr,
+        map_len: len,
+        offset:  MAP_HEADER_SIZE,
+        len:     header.contents_size(),
+    })
+}
+
+/// A directory holding pinned programs and maps, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct PinDir {
+    path: PathBuf,
+}
+
+impl PinDir {
+
+    /// Open the directory at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PinDir, Error> {
+        fs::create_dir_all(&path)?;
+        Ok(PinDir { path: path.as_ref().to_path_buf() })
+    }
+
+    /// The path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The path of the file of object `name`.
+    fn object_path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid pin name {:?}", name)));
+        }
+        Ok(self.path.join(name))
+    }
+
+    // Creates the file of object `name` under a temporary name, then links it to its name once
+    // `write` filled it; `write` returns the value to return.
+    fn create<T, F>(&self, name: &str, write: F) -> Result<T, Error>
+        where F: FnOnce(&mut File) -> Result<T, Error> {
+        let path = self.object_path(name)?;
+        if path.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} is already pinned", name)));
+        }
+        let tmp_path = self.path.join(format!(".{}.{}", name, std::process::id()));
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&tmp_path)?;
+        let res = write(&mut file).and_then(|value| {
+            file.sync_all()?;
+            fs::hard_link(&tmp_path, &path)?;
+            Ok(value)
+        });
+        fs::remove_file(&tmp_path)?;
+        res
+    }
+
+    /// Pin `program` under `name`, with the version of the instruction set it was verified
+    /// against.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if an object is already pinned under `name`, of
+    /// kind `InvalidInput` if the name is empty, starts with a dot or holds a slash, or the error
+    /// of the file system.
+    pub fn pin_program(&self, name: &str, program: &Program) -> Result<(), Error> {
+        self.create(name, |file| {
+            let bytes = program.bytes();
+            let mut data = PROGRAM_MAGIC.to_vec();
+            data.extend_from_slice(&[VERSION, isa_id(program.config().isa), 0, 0]);
+            data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            data.extend_from_slice(bytes);
+            file.write_all(&data)
+        })
+    }
+
+    /// Open the program pinned under `name`, and verify it again, see `Program::verify()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if no object is pinned under `name`, of kind
+    /// `InvalidData` if the object is not a valid program or fails the verification, or the error
+    /// of the file system.
+    pub fn open_program(&self, name: &str) -> Result<Program, Error> {
+        let mut data = vec![];
+        File::open(self.object_path(name)?)?.read_to_end(&mut data)?;
+        if data.len() < 12 || &data[..4] != PROGRAM_MAGIC {
+            return Err(invalid("not a pinned program"));
+        }
+        if data[4] != VERSION {
+            return Err(invalid(&format!("unsupported version {} of pinned program", data[4])));
+        }
+        let isa = match data[5] {
+            1 => IsaVersion::V1,
+            2 => IsaVersion::V2,
+            3 => IsaVersion::V3,
+            4 => IsaVersion::V4,
+            v => return Err(invalid(&format!("unsupported instruction set version {}", v))),
+        };
+        let len = read_u32(&data, 8)? as usize;
+        let bytes = match data.get(12..12 + len) {
+            Some(bytes) if data.len() == 12 + len => bytes.to_vec(),
+            _                                     => return Err(invalid("truncated pinned program")),
+        };
+        Program::verify(bytes, ProgramConfig { isa }).map_err(|err| invalid(&err.to_string()))
+    }
+
+    /// Create a map, as `Map::new()` does, and pin it under `name`. The values of an array map
+    /// are held in the pinned file, shared with the processes opening the map; other maps are
+    /// pinned empty, as `pin_map()` does.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the definition of the map is invalid, see
+    /// `Map::new()`, and the errors of `pin_map()`.
+    pub fn create_map(&self, name: &str, map_type: MapType, key_size: usize, value_size: usize,
+                      max_entries: usize) -> Result<Arc<Map>, Error> {
+        Map::check(map_type, key_size, value_size, max_entries)
+            .map_err(|msg| Error::new(ErrorKind::InvalidInput, msg))?;
+        if map_type != MapType::Array {
+            let map = Arc::new(Map::new(map_type, key_size, value_size, max_entries));
+            self.pin_map(name, &map)?;
+            return Ok(map);
+        }
+        let header = MapHeader { map_type, key_size, value_size, max_entries, len: max_entries };
+        self.create(name, |file| {
+            file.write_all(&header.to_bytes())?;
+            let len = MAP_HEADER_SIZE + header.contents_size();
+            file.set_len(len as u64)?;
+            let values = map_values(file, &header, len)?;
+            Ok(Arc::new(Map::with_array_values(value_size, max_entries, values)))
+        })
+    }
+
+    /// Pin a copy of the entries of `map` under `name`, see `Map::iter()`. The processes opening
+    /// an array map pinned this way share it, but not with the processes using `map`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `AlreadyExists` if an object is already pinned under `name`, of
+    /// kind `InvalidInput` if the name is empty, starts with a dot or holds a slash, or the error
+    /// of the file system.
+    pub fn pin_map(&self, name: &str, map: &Map) -> Result<(), Error> {
+        let entries: Vec<_> = map.iter().collect();
+        let header = MapHeader {
+            map_type:    map.map_type(),
+            key_size:    map.key_size(),
+            value_size:  map.value_size(),
+            max_entries: map.max_entries(),
+            len:         entries.len(),
+        };
+        self.create(name, |file| {
+            let mut data = header.to_bytes();
+            for (key, value) in entries {
+                if header.map_type != MapType::Array {
+                    data.extend_from_slice(&key);
+                }
+                data.extend_from_slice(&value);
+            }
+            file.write_all(&data)
+        })
+    }
+
+    /// Open the map pinned under `name`. An array map is mapped in memory, and shared with the
+    /// other processes opening it; other maps are created with a copy of the entries pinned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if no object is pinned under `name`, of kind
+    /// `InvalidData` if the object is not a valid map, or the error of the file system.
+    pub fn open_map(&self, name: &str) -> Result<Arc<Map>, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(self.object_path(name)?)?;
+        let mut header = [0u8; MAP_HEADER_SIZE];
+        file.read_exact(&mut header).map_err(|_| invalid("not a pinned map"))?;
+        let header = MapHeader::parse(&header)?;
+        let len = MAP_HEADER_SIZE + header.contents_size();
+        if file.metadata()?.len() != len as u64 {
+            return Err(invalid("truncated pinned map"));
+        }
+        if header.map_type == MapType::Array {
+            let values = map_values(&file, &header, len)?;
+            return Ok(Arc::new(Map::with_array_values(header.value_size, header.max_entries, values)));
+        }
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        let map = Map::new(header.map_type, header.key_size, header.value_size, header.max_entries);
+        for entry in contents.chunks(header.key_size + header.value_size) {
+            let (key, value) = entry.split_at(header.key_size);
+            map.update(key, value, maps::BPF_NOEXIST)
+                .map_err(|_| invalid("invalid entry in pinned map"))?;
+        }
+        Ok(Arc::new(map))
+    }
+
+    /// Remove the object pinned under `name`. The programs and maps opened from it remain valid.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `NotFound` if no object is pinned under `name`, or the error of
+    /// the file system.
+    pub fn unpin(&self, name: &str) -> Result<(), Error> {
+        fs::remove_file(self.object_path(name)?)
+    }
+
+    /// The names of the objects pinned to the directory, sorted.
+    pub fn names(&self) -> Result<Vec<String>, Error> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
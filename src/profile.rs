@@ -0,0 +1,169 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module collects profiles of the executions of programs by the interpreter, to guide the
+//! JIT-compiler: how many times each instruction and each opcode ran, and how many times each
+//! conditional jump was taken.
+//!
+//! Given a profile with `EbpfVmMbuff::set_jit_profile()`, the JIT-compiler emits the basic
+//! blocks of the program in the order of `Profile::layout()`: the hot paths are contiguous, the
+//! likely successor of each conditional jump follows it, and the blocks that never ran are moved
+//! to the end of the code. Large classifier programs make a better use of the instruction cache in
+//! this way.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::profile::Profile;
+//!
+//! // Returns 2 if the first byte of the packet is 0, and 1 otherwise.
+//! let prog = vec![
+//!     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+//!     0x15, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r2, 0, +2
+//!     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+//!     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! // Profile the program with the interpreter.
+//! let mut profile = Profile::new();
+//! let mut packets = vec![vec![0u8]; 10];
+//! packets.push(vec![1]);
+//! let vm = rbpf::EbpfVmRaw::new(&prog);
+//! for packet in packets.iter_mut() {
+//!     vm.prog_exec_trace(packet, &mut profile);
+//! }
+//! assert_eq!(profile.runs(), 11);
+//! assert_eq!(profile.taken(1), 10);
+//!
+//! // The jump is usually taken: its target is laid out right after it.
+//! assert_eq!(profile.layout(&prog), vec![0, 4, 2]);
+//!
+//! let (mut zero, mut one) = (vec![0u8], vec![1u8]);
+//! let mut vm = rbpf::EbpfVmRaw::new(&prog);
+//! vm.set_jit_profile(Some(profile));
+//! vm.jit_compile();
+//! assert_eq!(vm.prog_exec_jit(&mut zero), 2);
+//! assert_eq!(vm.prog_exec_jit(&mut one), 1);
+//! ```
+
+use std::cmp::Reverse;
+
+use analysis;
+use ebpf;
+use trace::{TraceEntry, Tracer};
+use ProgramMeta;
+
+/// A profile of the executions of programs, collected as a tracer. See the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    executions: Vec<u64>,
+    taken:      Vec<u64>,
+    opcodes:    Vec<u64>,
+    runs:       u64,
+    // The conditional jump traced last, and its target.
+    pending:    Option<(usize, usize)>,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile { executions: vec![], taken: vec![], opcodes: vec![0; 256], runs: 0, pending: None }
+    }
+}
+
+fn count(counts: &mut Vec<u64>, index: usize) {
+    if counts.len() <= index {
+        counts.resize(index + 1, 0);
+    }
+    counts[index] += 1;
+}
+
+impl Profile {
+
+    /// Create an empty profile.
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// The number of executions of programs, counted when they exit.
+    pub fn runs(&self) -> u64 {
+        self.runs
+    }
+
+    /// The number of times the instruction at index `insn_ptr` ran.
+    pub fn executions(&self, insn_ptr: usize) -> u64 {
+        self.executions.get(insn_ptr).cloned().unwrap_or(0)
+    }
+
+    /// The number of times the conditional jump at index `insn_ptr` was taken.
+    pub fn taken(&self, insn_ptr: usize) -> u64 {
+        self.taken.get(insn_ptr).cloned().unwrap_or(0)
+    }
+
+    /// The number of times instructions of opcode `opc` ran.
+    pub fn opcode_count(&self, opc: u8) -> u64 {
+        self.opcodes[opc as usize]
+    }
+
+    /// The order in which the JIT-compiler emits the basic blocks of `prog` with this profile, as
+    /// the indices of their first instructions (see `analysis::basic_blocks()`). Starting with
+    /// the first block, each block is followed by its most frequent successor not placed yet, or,
+    /// when it has none that ran, by the most executed block not placed yet. The blocks that
+    /// never ran keep the order of the program, at the end.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of the program is not a multiple of the size of an instruction.
+    pub fn layout(&self, prog: &[u8]) -> Vec<usize> {
+        let blocks = analysis::basic_blocks(prog);
+        let index = |start: Option<usize>| start.and_then(|s| blocks.iter().position(|b| b.start == s));
+        let mut placed = vec![false; blocks.len()];
+        let mut order = vec![];
+        let mut current = if blocks.is_empty() { None } else { Some(0) };
+        while let Some(b) = current {
+            placed[b] = true;
+            order.push(blocks[b].start);
+            let runs = self.executions(blocks[b].start);
+            let taken = self.taken(blocks[b].end - 1);
+            let edges = [(index(blocks[b].branch), taken),
+                         (index(blocks[b].fallthrough), runs.saturating_sub(taken))];
+            let next = edges.iter()
+                .filter_map(|&(succ, count)| succ.filter(|&s| !placed[s]).map(|s| (s, count)))
+                .max_by_key(|&(_, count)| count);
+            current = match next {
+                Some((succ, count)) if count > 0 => Some(succ),
+                _ => (0..blocks.len()).filter(|&b| !placed[b])
+                    .min_by_key(|&b| (Reverse(self.executions(blocks[b].start)), b)),
+            };
+        }
+        order
+    }
+}
+
+impl Tracer for Profile {
+    fn trace(&mut self, entry: &TraceEntry) {
+        let insn_ptr = entry.insn_ptr;
+        if let Some((jump, target)) = self.pending.take() {
+            if insn_ptr == target && target != jump + 1 {
+                count(&mut self.taken, jump);
+            }
+        }
+        count(&mut self.executions, insn_ptr);
+        self.opcodes[entry.insn.opc as usize] += 1;
+        if entry.insn.opc == ebpf::EXIT {
+            self.runs += 1;
+        } else if ebpf::is_conditional_jump(entry.insn.opc) {
+            let target = insn_ptr as isize + 1 + entry.insn.off as isize;
+            self.pending = Some((insn_ptr, target as usize));
+        }
+    }
+
+    fn start_program(&mut self, _program: &ProgramMeta) {
+        self.pending = None;
+    }
+}
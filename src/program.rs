@@ -0,0 +1,272 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module defines `Program`, a program that already passed the verifier, to be loaded into
+//! many VMs.
+//!
+//! Creating a VM with `new()` runs the verifier on the program, then the bounds analysis that
+//! lets the interpreter skip the checks of the accesses proven in bounds (see
+//! `verifier::check_bounds()`). A host creating a VM for each connection or each customer of the
+//! same program verifies it once with `Program::verify()`, and creates the VMs with
+//! `from_program()`: they share the results of the verification, and the analysis is run at
+//! most once for each size of context. JIT-compiling the program on several VMs also compiles it
+//! once, see `EbpfVmMbuff::jit_compile()`.
+//!
+//! A verified program can be rewritten in place with `Program::patch()` and `Program::splice()`,
+//! for example to update the constants of a filter, such as a threshold, without rebuilding it.
+//! The program is verified again after each change, and left unchanged if the verification fails.
+//! VMs borrow the program, so the ones running it must be dropped before it is patched, and
+//! created again afterwards.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use ebpf::{self, Insn};
+use error::EbpfError;
+use verifier;
+
+/// The options of the verification of a `Program`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgramConfig {
+    /// The version of the instruction set the program is checked against, see
+    /// `EbpfVmMbuff::set_isa_version()`. VMs created from the program use the same version.
+    pub isa: ebpf::IsaVersion,
+}
+
+/// A verified program, see the module documentation.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::program::{Program, ProgramConfig};
+///
+/// let prog = vec![
+///     0x71, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+2]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let program = Program::verify(prog, ProgramConfig::default()).unwrap();
+///
+/// let vms: Vec<_> = (0..4).map(|_| rbpf::EbpfVmRaw::from_program(&program)).collect();
+/// for (i, vm) in vms.iter().enumerate() {
+///     assert_eq!(vm.prog_exec(&mut vec![0, 0, i as u8]), i as u64);
+/// }
+///
+/// // Programs are verified when created.
+/// assert!(Program::verify(vec![0x06, 0, 0, 0, 0, 0, 0, 0], ProgramConfig::default()).is_err());
+/// ```
+#[derive(Debug)]
+pub struct Program {
+    pub(crate) bytes: Vec<u8>,
+    config:           ProgramConfig,
+    // The loads and stores proven in bounds, for each size of context they were computed for.
+    proven_accesses:  Mutex<HashMap<Option<usize>, Vec<bool>>>,
+}
+
+impl Program {
+
+    /// Run the verifier on `bytes`, with the options of `config`, and return the verified
+    /// program, or the error found by the verifier.
+    pub fn verify(bytes: Vec<u8>, config: ProgramConfig) -> Result<Program, EbpfError> {
+        verifier::try_check_isa(&bytes, config.isa)?;
+        Ok(Program {
+            bytes,
+            config,
+            proven_accesses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The bytes of the program.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The options the program was verified with.
+    pub fn config(&self) -> &ProgramConfig {
+        &self.config
+    }
+
+    /// Replace instruction `insn_idx` of the program with `insn`, and verify the program again.
+    /// The offset of a jump is relative to its own position, as in the program: it is stored as
+    /// is. Both halves of a `lddw` instruction are patched separately, the second one holding the
+    /// upper 32 bits of the immediate.
+    ///
+    /// If the verifier rejects the patched program, its error is returned and the program is left
+    /// unchanged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `insn_idx` is not the index of an instruction of the program.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::ebpf;
+    /// use rbpf::program::{Program, ProgramConfig};
+    ///
+    /// // Returns 1 if the first byte of packet data is above 10, 0 otherwise.
+    /// let prog = vec![
+    ///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+    ///     0x25, 0x02, 0x01, 0x00, 0x0a, 0x00, 0x00, 0x00, // jgt r2, 10, +1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut program = Program::verify(prog, ProgramConfig::default()).unwrap();
+    /// assert_eq!(rbpf::EbpfVmRaw::from_program(&program).prog_exec(&mut vec![20]), 1);
+    ///
+    /// // Raise the threshold to 30.
+    /// let mut insn = ebpf::get_insn(program.bytes(), 2);
+    /// insn.imm = 30;
+    /// program.patch(2, insn).unwrap();
+    /// assert_eq!(rbpf::EbpfVmRaw::from_program(&program).prog_exec(&mut vec![20]), 0);
+    ///
+    /// // Jumping out of the program is rejected.
+    /// insn.off = 10;
+    /// assert!(program.patch(2, insn).is_err());
+    /// assert_eq!(ebpf::get_insn(program.bytes(), 2).imm, 30);
+    /// ```
+    pub fn patch(&mut self, insn_idx: usize, insn: Insn) -> Result<(), EbpfError> {
+        let len = self.bytes.len() / ebpf::INSN_SIZE;
+        if insn_idx >= len {
+            panic!("Error: cannot patch instruction #{:?} in program containing {:?} instructions",
+                   insn_idx, len);
+        }
+        let mut bytes = self.bytes.clone();
+        let start = insn_idx * ebpf::INSN_SIZE;
+        bytes[start..start + ebpf::INSN_SIZE].copy_from_slice(&insn.to_array());
+        self.update(bytes)
+    }
+
+    /// Replace instructions `range` of the program with `insns`, and verify the program again.
+    /// The jumps and BPF-to-BPF calls of the rest of the program are adjusted, so that they keep
+    /// going to the same instructions: the ones going to a replaced instruction go to the first
+    /// instruction of `insns`, and the ones going to the instruction following `range` still go
+    /// to it. With an empty `range`, `insns` are inserted before instruction `range.start`, and
+    /// only run by the instructions falling through to them. The jumps of `insns` are stored as
+    /// is.
+    ///
+    /// If an adjusted jump no longer fits in its offset, or if the verifier rejects the new
+    /// program, the error is returned and the program is left unchanged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is not a range of instructions of the program.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rbpf::ebpf::{self, Insn};
+    /// use rbpf::program::{Program, ProgramConfig};
+    ///
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+    ///     0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +1
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+    ///     0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    /// let mut program = Program::verify(prog, ProgramConfig::default()).unwrap();
+    ///
+    /// // Replace the unreachable instruction with two, the jump skips both.
+    /// let mov = Insn { opc: ebpf::MOV64_IMM, dst: 0, src: 0, off: 0, imm: 5 };
+    /// program.splice(2..3, &[mov, mov]).unwrap();
+    /// assert_eq!(ebpf::get_insn(program.bytes(), 1).off, 2);
+    /// assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 2);
+    /// ```
+    pub fn splice(&mut self, range: Range<usize>, insns: &[Insn]) -> Result<(), EbpfError> {
+        let len = self.bytes.len() / ebpf::INSN_SIZE;
+        if range.start > range.end || range.end > len {
+            panic!("Error: cannot splice instructions {:?} in program containing {:?} instructions",
+                   range, len);
+        }
+        let prog: Vec<Insn> = ebpf::InsnIter::new(&self.bytes).collect();
+        let bytes = splice_insns(&prog, range, insns)
+            .map_err(EbpfError::VerifierError)?
+            .iter()
+            .flat_map(|insn| insn.to_array())
+            .collect();
+        self.update(bytes)
+    }
+
+    // Verifies `bytes` with the options of the program, and replaces the program with them.
+    fn update(&mut self, bytes: Vec<u8>) -> Result<(), EbpfError> {
+        verifier::try_check_isa(&bytes, self.config.isa)?;
+        self.bytes = bytes;
+        self.proven_accesses.get_mut().unwrap_or_else(|err| err.into_inner()).clear();
+        Ok(())
+    }
+
+    // The loads and stores of the program proven in bounds with a context of `ctx_len` bytes,
+    // computed on first use.
+    pub(crate) fn proven_accesses(&self, ctx_len: Option<usize>) -> Vec<bool> {
+        let mut cache = self.proven_accesses.lock().unwrap_or_else(|err| err.into_inner());
+        cache.entry(ctx_len).or_insert_with(|| proven_accesses(&self.bytes, ctx_len)).clone()
+    }
+}
+
+// Returns, for each instruction of `prog`, whether it is a load or store that
+// `verifier::check_bounds()` proves in bounds of the stack, or of a context of `ctx_len` bytes.
+// Nothing is recorded if the analysis finds an error.
+pub(crate) fn proven_accesses(prog: &[u8], ctx_len: Option<usize>) -> Vec<bool> {
+    match verifier::check_bounds(prog, ctx_len) {
+        Ok(report) => {
+            let mut proven = vec![false; prog.len() / ebpf::INSN_SIZE];
+            for access in report.accesses.iter().filter(|access| access.proven) {
+                proven[access.insn_ptr] = true;
+            }
+            proven
+        },
+        Err(_) => vec![],
+    }
+}
+
+// Returns `prog` with instructions `range` replaced with `insns`, and the offsets of the jumps and
+// BPF-to-BPF calls of the other instructions adjusted, see `Program::splice()`.
+pub(crate) fn splice_insns(prog: &[Insn], range: Range<usize>, insns: &[Insn]) -> Result<Vec<Insn>, String> {
+    let (start, end) = (range.start as i64, range.end as i64);
+    let growth = insns.len() as i64 - (end - start);
+    // The new index of the instruction at `idx`, or of the replacement of removed instructions.
+    let new_idx = |idx: i64| match idx {
+        _ if idx < start => idx,
+        _ if idx < end   => start,
+        _                => idx + growth,
+    };
+    let mut res = Vec::with_capacity((prog.len() as i64 + growth) as usize);
+    res.extend_from_slice(&prog[..range.start]);
+    res.extend_from_slice(insns);
+    res.extend_from_slice(&prog[range.end..]);
+    for (idx, insn) in prog.iter().enumerate() {
+        let idx = idx as i64;
+        if idx >= start && idx < end {
+            continue;
+        }
+        let is_jump = ebpf::is_jump(insn.opc);
+        let is_subprog_call = insn.opc == ebpf::CALL && insn.src == ebpf::BPF_PSEUDO_CALL;
+        // The second half of `lddw` has opcode 0, it is not a jump.
+        let lddw_half = idx > 0 && prog[idx as usize - 1].opc == ebpf::LD_DW_IMM;
+        if !(is_jump || is_subprog_call) || lddw_half {
+            continue;
+        }
+        // `gotol` and calls have their offset in the immediate.
+        let in_imm = insn.opc == ebpf::JA32 || is_subprog_call;
+        let off = if in_imm { insn.imm as i64 } else { insn.off as i64 };
+        let from = new_idx(idx);
+        let new_off = new_idx(idx + 1 + off) - from - 1;
+        let new_insn = &mut res[from as usize];
+        if in_imm {
+            new_insn.imm = new_off as i32;
+        } else if new_off >= i16::MIN as i64 && new_off <= i16::MAX as i64 {
+            new_insn.off = new_off as i16;
+        } else {
+            return Err(format!("[Verifier] Error: jump offset {} out of range after splice (insn #{})",
+                               new_off, from));
+        }
+    }
+    Ok(res)
+}
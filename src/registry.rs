@@ -0,0 +1,176 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides a registry for running several eBPF programs together.
+//!
+//! Programs are loaded into the registry under a name, and all of them share the same set of
+//! helpers. Programs can jump to one another with tail calls (helper `bpf_tail_call()` in the
+//! Linux kernel, see `helpers::BPF_TAIL_CALL_IDX`): the registry holds program arrays, which are
+//! tables of references to the names of loaded programs, and that the tail calls use to find the
+//! program to jump to. This makes it possible to model multi-program pipelines, such as a parser
+//! tail-calling a classifier, itself tail-calling an action.
+//!
+//! As in the kernel, a tail call takes three arguments: the context (R1), the identifier of the
+//! program array (R2), and the index in this array of the program to jump to (R3). If the tail
+//! call succeeds, the new program starts with the same context and never returns to the caller. If
+//! it fails (no such array, empty slot, or too many chained tail calls), the caller resumes with
+//! the instruction following the call.
+//!
+//! Programs run in a registry are interpreted; they cannot be JIT-compiled.
+
+use std::collections::HashMap;
+
+use ebpf;
+use interpreter;
+use verifier;
+
+/// A set of named eBPF programs sharing the same helpers, and that can tail-call one another
+/// through program arrays.
+///
+/// # Examples
+///
+/// ```
+/// // Tail-calls the program at index 1 in program array 0, then returns 1 if the tail call fails.
+/// let parser = vec![
+///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r2, 0
+///     0xb7, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r3, 1
+///     0x85, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // call 12 (tail call)
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// // Returns the first byte of packet data.
+/// let classifier = vec![
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let mut registry = rbpf::registry::ProgramRegistry::new();
+/// registry.load("parser", parser);
+/// registry.load("classifier", classifier);
+/// registry.create_prog_array(0, 4);
+/// registry.set_tail_call(0, 1, "classifier");
+///
+/// let mut mem = vec![0x2a, 0x00];
+/// assert_eq!(registry.prog_exec("parser", &mut mem, &mut []), 0x2a);
+///
+/// // Once the slot is cleared, the tail call fails and the parser returns 1.
+/// registry.clear_tail_call(0, 1);
+/// assert_eq!(registry.prog_exec("parser", &mut mem, &mut []), 1);
+/// ```
+#[derive(Default)]
+pub struct ProgramRegistry {
+    programs:    HashMap<String, Vec<u8>>,
+    prog_arrays: HashMap<u32, Vec<Option<String>>>,
+    helpers:     HashMap<u32, ebpf::Helper>,
+}
+
+impl ProgramRegistry {
+
+    /// Create a new, empty registry.
+    pub fn new() -> ProgramRegistry {
+        ProgramRegistry::default()
+    }
+
+    /// Load an eBPF program into the registry under the given `name`. If a program was already
+    /// loaded under this name, it is replaced; program arrays referencing this name will then jump
+    /// to the new version of the program.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the eBPF program at load time.
+    pub fn load(&mut self, name: &str, prog: Vec<u8>) {
+        verifier::check(&prog);
+        self.programs.insert(name.to_string(), prog);
+    }
+
+    /// Remove the program loaded under `name` from the registry, and return it. Program array
+    /// slots referencing this name are left in place, but tail calls through them fail until a
+    /// program is loaded again under the same name.
+    pub fn unload(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.programs.remove(name)
+    }
+
+    /// Return the bytecode of the program loaded under `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.programs.get(name).map(|p| p.as_slice())
+    }
+
+    /// Register a built-in or user-defined helper function, available to all programs of the
+    /// registry. See `EbpfVmMbuff::register_helper()`.
+    ///
+    /// Note that the key `helpers::BPF_TAIL_CALL_IDX` is reserved for tail calls in the registry:
+    /// a helper registered with this key will never be called.
+    pub fn register_helper(&mut self, key: u32, function: ebpf::Helper) {
+        self.helpers.insert(key, function);
+    }
+
+    /// Create a program array with identifier `id`, and `max_entries` empty slots. If an array
+    /// already existed with the same identifier, it is replaced.
+    pub fn create_prog_array(&mut self, id: u32, max_entries: u32) {
+        self.prog_arrays.insert(id, vec![None; max_entries as usize]);
+    }
+
+    /// Make slot `index` of program array `array_id` point to the program loaded under `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such program array, if `index` is out of the bounds of the array, or
+    /// if no program is loaded under `name`.
+    pub fn set_tail_call(&mut self, array_id: u32, index: u32, name: &str) {
+        if !self.programs.contains_key(name) {
+            panic!("Error: no program named \"{}\" in registry", name);
+        }
+        *self.get_slot(array_id, index) = Some(name.to_string());
+    }
+
+    /// Empty slot `index` of program array `array_id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such program array, or if `index` is out of the bounds of the array.
+    pub fn clear_tail_call(&mut self, array_id: u32, index: u32) {
+        *self.get_slot(array_id, index) = None;
+    }
+
+    /// Interpret the program loaded under `name`, with the given packet data and metadata buffer,
+    /// following tail calls to other programs of the registry. As for `EbpfVmMbuff::prog_exec()`,
+    /// R1 points to the metadata buffer if it is not empty, or to packet data otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no program is loaded under `name`. Also panics on the same execution errors as
+    /// `EbpfVmMbuff::prog_exec()`.
+    pub fn prog_exec(&self, name: &str, mem: &mut [u8], mbuff: &mut [u8]) -> u64 {
+        let prog = match self.programs.get(name) {
+            Some(prog) => prog,
+            None       => panic!("Error: no program named \"{}\" in registry", name),
+        };
+        let resolve = |array_id: u32, index: u32| self.resolve_tail_call(array_id, index);
+        let options = interpreter::Options { tail_calls: Some(&resolve), ..Default::default() };
+        interpreter::execute_program(prog, mem, mbuff, &self.helpers, options)
+    }
+
+    fn resolve_tail_call(&self, array_id: u32, index: u32) -> Option<&[u8]> {
+        self.prog_arrays.get(&array_id)
+            .and_then(|array| array.get(index as usize))
+            .and_then(|slot| slot.as_ref())
+            .and_then(|name| self.get(name))
+    }
+
+    fn get_slot(&mut self, array_id: u32, index: u32) -> &mut Option<String> {
+        let array = match self.prog_arrays.get_mut(&array_id) {
+            Some(array) => array,
+            None        => panic!("Error: no program array with id {:?} in registry", array_id),
+        };
+        let len = array.len();
+        match array.get_mut(index as usize) {
+            Some(slot) => slot,
+            None       => panic!("Error: index {:?} out of bounds for program array {:?} ({:?} entries)",
+                                 index, array_id, len),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module holds maps in shared memory segments, so that several processes use the same maps:
+//! for example, a collector process reading the counters maintained by programs running in a
+//! separate dataplane process.
+//!
+//! A shared map is created in a POSIX shared memory segment with `create()`, and opened by other
+//! processes with `open()`, by the name of the segment, until it is removed with `unlink()`. On
+//! Linux, `create_memfd()` creates it in an anonymous file instead, whose descriptor is passed to
+//! the other processes, for example inherited by a child process or sent over a Unix socket, which
+//! open the map with `open_file()`. The maps returned are used as any other map: registered on VMs,
+//! and read and updated with the methods of `maps::Map`.
+//!
+//! Array maps, hash maps and maps of redirect targets can be shared. Unlike the maps pinned with
+//! module `pin`, the entries of shared hash maps are held in the segment, and shared too.
+//!
+//! # Layout
+//!
+//! Integers are in the byte order of the host, and the segment is only shared between processes of
+//! the same host. The segment starts with a header of 64 bytes:
+//!
+//! * the magic bytes `RBPS` (4 bytes), and the version of the layout, 1 (1 byte), followed by 3
+//!   bytes set to zero,
+//! * the type of the map, as its identifier in the Linux kernel (4 bytes, 1 for
+//!   `BPF_MAP_TYPE_HASH`, 2 for `BPF_MAP_TYPE_ARRAY` and 14 for `BPF_MAP_TYPE_DEVMAP`),
+//! * the size of the keys, the size of the values, and the maximum number of entries (4 bytes
+//!   each),
+//! * the number of slots, and the size of a slot (4 bytes each),
+//! * the lock (4 bytes), and the number of entries (4 bytes), followed by bytes set to zero up to
+//!   the end of the header.
+//!
+//! The slots follow the header. The slots of an array map are its values, in the order of their
+//! indexes. Hash maps are open-addressing hash tables, whose number of slots is the smallest power
+//! of two holding twice the maximum number of entries. A slot is made of its state (4 bytes, 0 if
+//! it never held an entry, 1 if it holds one, 2 if its entry was deleted), 4 bytes of padding, the
+//! key, and the value, both padded to a multiple of 8 bytes. An entry is held in the first slot
+//! holding no entry after the slot of index the FNV-1a 64-bit hash of its key, modulo the number of
+//! slots, looking through the following slots in order and wrapping around at the end.
+//!
+//! # Locking protocol
+//!
+//! The operations changing the entries of a hash map (updates, deletions and clearing) hold the
+//! lock of the segment: they set it from 0 to 1 with an atomic compare-and-exchange operation,
+//! retrying until it succeeds, and set it back to 0 once done. Lookups take no lock: an entry is
+//! written before its state is set to 1, with release ordering, and lookups read the state with
+//! acquire ordering. As in array maps, updating an entry copies its value in place, and values are
+//! loaded from and stored to without synchronization: a reader may see a partially updated value.
+//! The slot of a deleted entry may be reused by a later update, so that a program holding a
+//! pointer to the value of a deleted entry may see the value of another entry.
+//!
+//! A process terminated while holding the lock leaves it held, and the other processes wait for
+//! it indefinitely: the map must then be created again.
+//!
+//! # Examples
+//!
+//! ```
+//! use rbpf::maps::MapType;
+//! use rbpf::shm;
+//!
+//! // Increments the 64-bit counter of the hash map for the key found in packet data.
+//! let prog = vec![
+//!     0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1 (key)
+//!     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+//!     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+//!     0x15, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +3
+//!     0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+//!     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+//!     0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! // The dataplane process creates the map, and its entries.
+//! let name = format!("/rbpf-shm-doc-{}", std::process::id());
+//! let counters = shm::create(&name, MapType::Hash, 4, 8, 64).unwrap();
+//! counters.update(&80u32.to_le_bytes(), &0u64.to_le_bytes(), rbpf::maps::BPF_ANY).unwrap();
+//! let mut vm = rbpf::EbpfVmRaw::new(&prog);
+//! vm.register_map(counters);
+//! for _ in 0..3 {
+//!     vm.prog_exec(&mut 80u32.to_le_bytes().to_vec());
+//! }
+//!
+//! // The collector process opens it by its name.
+//! let collected = shm::open(&name).unwrap();
+//! assert_eq!(collected.lookup(&80u32.to_le_bytes()), Some(3u64.to_le_bytes().to_vec()));
+//! shm::unlink(&name).unwrap();
+//! ```
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use helpers::{E2BIG, EEXIST, ENOENT};
+use maps::{self, ArrayValues, Map, MapType};
+
+const MAGIC: &[u8; 4] = b"RBPS";
+const VERSION: u8 = 1;
+
+// The size of the header of the segment, and the offsets of its fields.
+const HEADER_SIZE: usize = 64;
+const MAP_TYPE_OFF: usize = 8;
+const KEY_SIZE_OFF: usize = 12;
+const VALUE_SIZE_OFF: usize = 16;
+const MAX_ENTRIES_OFF: usize = 20;
+const SLOTS_OFF: usize = 24;
+const SLOT_SIZE_OFF: usize = 28;
+const LOCK_OFF: usize = 32;
+const LEN_OFF: usize = 36;
+
+// The states of the slots of hash maps.
+const SLOT_EMPTY: u32 = 0;
+const SLOT_USED: u32 = 1;
+const SLOT_DELETED: u32 = 2;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn round_up(size: usize) -> usize {
+    size.div_ceil(8) * 8
+}
+
+// The FNV-1a 64-bit hash of `key`, which does not depend on the process computing it.
+fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// The number and the size of the slots of a map, see the module documentation.
+fn slots(map_type: MapType, key_size: usize, value_size: usize, max_entries: usize) -> (usize, usize) {
+    match map_type {
+        MapType::Array => (max_entries, value_size),
+        _              => ((2 * max_entries).next_power_of_two(), 8 + round_up(key_size) + round_up(value_size)),
+    }
+}
+
+// A mapping of a segment in memory, unmapped when dropped.
+struct Segment {
+    addr: *mut libc::c_void,
+    len:  usize,
+}
+
+impl Segment {
+    fn map(file: &File, len: usize) -> Result<Segment, Error> {
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                       file.as_raw_fd(), 0)
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(Segment { addr, len })
+    }
+
+    fn u32_at(&self, off: usize) -> &AtomicU32 {
+        unsafe { &*((self.addr as *const u8).add(off) as *const AtomicU32) }
+    }
+
+    fn bytes(&self, off: usize, len: usize) -> &[AtomicU8] {
+        unsafe { std::slice::from_raw_parts((self.addr as *const AtomicU8).add(off), len) }
+    }
+
+    // The values of an array map held in the segment, which then takes over the mapping.
+    fn into_array_values(self, len: usize) -> ArrayValues {
+        let values = ArrayValues::Mapped { addr: self.addr, map_len: self.len, offset: HEADER_SIZE, len };
+        std::mem::forget(self);
+        values
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+// The hash table of a hash map held in a segment, see the module documentation.
+pub(crate) struct Table {
+    segment:    Segment,
+    key_size:   usize,
+    value_size: usize,
+    slots:      usize,
+    slot_size:  usize,
+}
+
+// The segment is only accessed through atomic integers.
+unsafe impl Send for Table {}
+unsafe impl Sync for Table {}
+
+// The lock of a segment, held until dropped.
+struct Lock<'t>(&'t AtomicU32);
+
+impl<'t> Drop for Lock<'t> {
+    fn drop(&mut self) {
+        self.0.store(0, Ordering::Release);
+    }
+}
+
+impl Table {
+    fn lock(&self) -> Lock<'_> {
+        let lock = self.segment.u32_at(LOCK_OFF);
+        while lock.compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now();
+        }
+        Lock(lock)
+    }
+
+    fn slot_off(&self, slot: usize) -> usize {
+        HEADER_SIZE + slot * self.slot_size
+    }
+
+    fn state(&self, slot: usize) -> &AtomicU32 {
+        self.segment.u32_at(self.slot_off(slot))
+    }
+
+    fn key(&self, slot: usize) -> &[AtomicU8] {
+        self.segment.bytes(self.slot_off(slot) + 8, self.key_size)
+    }
+
+    fn value(&self, slot: usize) -> &[AtomicU8] {
+        self.segment.bytes(self.slot_off(slot) + 8 + round_up(self.key_size), self.value_size)
+    }
+
+    fn len_field(&self) -> &AtomicU32 {
+        self.segment.u32_at(LEN_OFF)
+    }
+
+    // The slots to look through for `key`, in order.
+    fn probe(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let (start, slots) = (fnv1a(key) as usize, self.slots);
+        (0..slots).map(move |i| (start + i) & (slots - 1))
+    }
+
+    // The slot holding `key`, if any.
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        for slot in self.probe(key) {
+            match self.state(slot).load(Ordering::Acquire) {
+                SLOT_EMPTY => return None,
+                SLOT_USED if self.key(slot).iter().zip(key).all(|(a, &b)| a.load(Ordering::Relaxed) == b) => {
+                    return Some(slot);
+                },
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len_field().load(Ordering::Relaxed) as usize
+    }
+
+    pub(crate) fn lookup_addr(&self, key: &[u8]) -> Option<u64> {
+        self.find(key).map(|slot| self.value(slot).as_ptr() as u64)
+    }
+
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.find(key).map(|slot| read(self.value(slot)))
+    }
+
+    pub(crate) fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..self.slots)
+            .filter(|&slot| self.state(slot).load(Ordering::Acquire) == SLOT_USED)
+            .map(|slot| (read(self.key(slot)), read(self.value(slot))))
+            .collect()
+    }
+
+    pub(crate) fn clear(&self) {
+        let _lock = self.lock();
+        for slot in 0..self.slots {
+            self.state(slot).store(SLOT_EMPTY, Ordering::Release);
+        }
+        self.len_field().store(0, Ordering::Relaxed);
+    }
+
+    // Associates `value` with `key`, as `Map::update()`, once the sizes and flags are checked.
+    pub(crate) fn update(&self, key: &[u8], value: &[u8], flags: u64, max_entries: usize)
+        -> Result<(), i64> {
+        let _lock = self.lock();
+        if let Some(slot) = self.find(key) {
+            if flags == maps::BPF_NOEXIST {
+                return Err(EEXIST);
+            }
+            write(self.value(slot), value);
+            return Ok(());
+        }
+        if flags == maps::BPF_EXIST {
+            return Err(ENOENT);
+        }
+        if self.len() >= max_entries {
+            return Err(E2BIG);
+        }
+        let slot = self.probe(key)
+            .find(|&slot| self.state(slot).load(Ordering::Relaxed) != SLOT_USED)
+            .ok_or(E2BIG)?;
+        write(self.key(slot), key);
+        write(self.value(slot), value);
+        self.state(slot).store(SLOT_USED, Ordering::Release);
+        self.len_field().fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<(), i64> {
+        let _lock = self.lock();
+        let slot = self.find(key).ok_or(ENOENT)?;
+        self.state(slot).store(SLOT_DELETED, Ordering::Release);
+        self.len_field().fetch_sub(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn read(bytes: &[AtomicU8]) -> Vec<u8> {
+    bytes.iter().map(|b| b.load(Ordering::Relaxed)).collect()
+}
+
+fn write(bytes: &[AtomicU8], src: &[u8]) {
+    for (dst, &src) in bytes.iter().zip(src) {
+        dst.store(src, Ordering::Relaxed);
+    }
+}
+
+// Creates the map held in `file`, whose contents are those of a new segment if `create` is set.
+fn map_file(file: &File, create: Option<(MapType, usize, usize, usize)>) -> Result<Arc<Map>, Error> {
+    if let Some((map_type, key_size, value_size, max_entries)) = create {
+        if map_type == MapType::LpmTrie {
+            return Err(Error::new(ErrorKind::InvalidInput, "LPM tries cannot be shared"));
+        }
+        Map::check(map_type, key_size, value_size, max_entries)
+            .map_err(|msg| Error::new(ErrorKind::InvalidInput, msg))?;
+        let (slots, slot_size) = slots(map_type, key_size, value_size, max_entries);
+        file.set_len((HEADER_SIZE + slots * slot_size) as u64)?;
+        let segment = Segment::map(file, HEADER_SIZE + slots * slot_size)?;
+        write(segment.bytes(0, 4), MAGIC);
+        write(segment.bytes(4, 1), &[VERSION]);
+        for &(off, x) in &[(MAP_TYPE_OFF, map_type.kernel_id()), (KEY_SIZE_OFF, key_size as u32),
+                           (VALUE_SIZE_OFF, value_size as u32), (MAX_ENTRIES_OFF, max_entries as u32),
+                           (SLOTS_OFF, slots as u32), (SLOT_SIZE_OFF, slot_size as u32)] {
+            segment.u32_at(off).store(x, Ordering::Relaxed);
+        }
+    }
+
+    let len = file.metadata()?.len() as usize;
+    if len < HEADER_SIZE {
+        return Err(invalid("not a shared map"));
+    }
+    let segment = Segment::map(file, len)?;
+    if read(segment.bytes(0, 4)) != MAGIC {
+        return Err(invalid("not a shared map"));
+    }
+    if read(segment.bytes(4, 1))[0] != VERSION {
+        return Err(invalid("unsupported version of shared map"));
+    }
+    let field = |off| segment.u32_at(off).load(Ordering::Relaxed) as usize;
+    let map_type = match MapType::from_kernel_id(field(MAP_TYPE_OFF) as u32) {
+        Some(MapType::LpmTrie) | None => return Err(invalid("unsupported type of shared map")),
+        Some(map_type)                => map_type,
+    };
+    let (key_size, value_size, max_entries) = (field(KEY_SIZE_OFF), field(VALUE_SIZE_OFF), field(MAX_ENTRIES_OFF));
+    Map::check(map_type, key_size, value_size, max_entries).map_err(|msg| invalid(&msg))?;
+    let (slots, slot_size) = slots(map_type, key_size, value_size, max_entries);
+    if field(SLOTS_OFF) != slots || field(SLOT_SIZE_OFF) != slot_size || len < HEADER_SIZE + slots * slot_size {
+        return Err(invalid("invalid layout of shared map"));
+    }
+    let map = match map_type {
+        MapType::Array => {
+            Map::with_array_values(value_size, max_entries, segment.into_array_values(slots * slot_size))
+        },
+        _ => {
+            let table = Table { segment, key_size, value_size, slots, slot_size };
+            Map::with_shared_table(map_type, key_size, value_size, max_entries, table)
+        },
+    };
+    Ok(Arc::new(map))
+}
+
+fn shm_name(name: &str) -> Result<CString, Error> {
+    CString::new(name).map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid segment name"))
+}
+
+fn shm_open(name: &str, flags: libc::c_int) -> Result<File, Error> {
+    let fd = unsafe { libc::shm_open(shm_name(name)?.as_ptr(), flags, 0o600) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Create a map, as `Map::new()` does, in a new POSIX shared memory segment named `name`. As for
+/// `shm_open()`, the name starts with a slash, and holds no other slash.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidInput` if the definition of the map is invalid, see
+/// `Map::new()`, or for LPM tries, of kind `AlreadyExists` if the segment exists, or the error of
+/// the system.
+pub fn create(name: &str, map_type: MapType, key_size: usize, value_size: usize, max_entries: usize)
+    -> Result<Arc<Map>, Error> {
+    let file = shm_open(name, libc::O_RDWR | libc::O_CREAT | libc::O_EXCL)?;
+    map_file(&file, Some((map_type, key_size, value_size, max_entries))).inspect_err(|_| {
+        let _ = unlink(name);
+    })
+}
+
+/// Open the map held in the POSIX shared memory segment named `name`, created with `create()`.
+///
+/// # Errors
+///
+/// Returns an error of kind `NotFound` if there is no such segment, of kind `InvalidData` if the
+/// segment does not hold a valid map, or the error of the system.
+pub fn open(name: &str) -> Result<Arc<Map>, Error> {
+    map_file(&shm_open(name, libc::O_RDWR)?, None)
+}
+
+/// Remove the POSIX shared memory segment named `name`. The maps opened from it remain valid.
+pub fn unlink(name: &str) -> Result<(), Error> {
+    if unsafe { libc::shm_unlink(shm_name(name)?.as_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create a map, as `Map::new()` does, in a new anonymous file created with `memfd_create()`, and
+/// return it with the file, whose descriptor is passed to the processes opening the map with
+/// `open_file()`. `name` is the name of the file, for debugging purposes.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidInput` if the definition of the map is invalid, see
+/// `Map::new()`, or for LPM tries, or the error of the system.
+#[cfg(target_os = "linux")]
+pub fn create_memfd(name: &str, map_type: MapType, key_size: usize, value_size: usize,
+                    max_entries: usize) -> Result<(Arc<Map>, File), Error> {
+    let fd = unsafe { libc::memfd_create(shm_name(name)?.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    let map = map_file(&file, Some((map_type, key_size, value_size, max_entries)))?;
+    Ok((map, file))
+}
+
+/// Open the map held in `file`, created with `create_memfd()`, or a file holding a POSIX shared
+/// memory segment created with `create()`, opened for reading and writing.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the file does not hold a valid map, or the error of
+/// the system.
+pub fn open_file(file: &File) -> Result<Arc<Map>, Error> {
+    map_file(file, None)
+}
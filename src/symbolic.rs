@@ -0,0 +1,637 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module generates packets exercising the branches of an eBPF program, to be used as test
+//! inputs for the program.
+//!
+//! The program is executed on concrete packets, while keeping track of how the values of the
+//! registers derive from the bytes of the packet (concolic execution). Each conditional jump
+//! depending on the packet yields a constraint on its bytes. To exercise the other direction of
+//! a jump, the generator looks for a packet satisfying the constraints of the jumps leading to it,
+//! and the negation of the constraint of the jump itself: it tries the constants found in the
+//! constraint, written in big or little endian, then all values of the bytes involved when there
+//! is only one of them, and finally pseudo-random values. Every packet produced is run again to
+//! check that it does exercise the new branches, so all reported coverage is real. Exploration is
+//! bounded by a number of executions, and each execution by a number of instructions.
+//!
+//! Programs are run as with `EbpfVmRaw`: R1 points to the packet, and the context has no other
+//! content. Helpers are not called: calls to helpers return 0. Programs accessing memory through
+//! pointers other than R1 and R10 (and values derived from them with additions and
+//! subtractions) are not supported; their execution stops at such accesses.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+
+use ebpf;
+use verifier;
+
+/// A packet produced by `InputGenerator`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedInput {
+    /// The packet.
+    pub input:        Vec<u8>,
+    /// The value returned by the program on this packet, or a description of the error that
+    /// stopped its execution: out of bounds access, division by zero, unsupported instruction or
+    /// memory access, or too many instructions executed.
+    pub result:       Result<u64, String>,
+    /// The branches exercised by this packet, and by none of the packets before it: pairs of the
+    /// index of a conditional jump and of a boolean, `true` if the jump is taken.
+    pub new_branches: Vec<(usize, bool)>,
+}
+
+/// The packets produced by `InputGenerator::generate()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeneratedInputs {
+    /// Packets exercising new branches, in the order they were found. The first one is the
+    /// packet full of zeroes.
+    pub inputs:    Vec<GeneratedInput>,
+    /// Branches of the conditional jumps reached by the packets, that none of them exercised.
+    /// They may be infeasible, or beyond the bounds of the exploration.
+    pub uncovered: BTreeSet<(usize, bool)>,
+}
+
+/// Generates packets exercising the branches of a program.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::symbolic::InputGenerator;
+///
+/// // Returns 1 for IPv4 packets with TCP, 2 for other IPv4 packets, 0 otherwise.
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x69, 0x12, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r2, [r1+12]
+///     0x55, 0x02, 0x04, 0x00, 0x08, 0x00, 0x00, 0x00, // jne r2, 0x8, +4
+///     0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+///     0x71, 0x12, 0x17, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1+23]
+///     0x55, 0x02, 0x01, 0x00, 0x06, 0x00, 0x00, 0x00, // jne r2, 0x6, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let generated = InputGenerator::new(&prog, 64).generate();
+/// assert!(generated.uncovered.is_empty());
+///
+/// let mut results: Vec<u64> = generated.inputs.iter().map(|i| *i.result.as_ref().unwrap()).collect();
+/// results.sort();
+/// assert_eq!(results, vec![0, 1, 2]);
+/// ```
+pub struct InputGenerator<'a> {
+    prog:      &'a [u8],
+    input_len: usize,
+    max_steps: usize,
+    max_runs:  usize,
+}
+
+// An expression computing a value from the bytes of the packet.
+#[derive(Debug)]
+enum Expr {
+    Const(u64),
+    // The byte at this offset in the packet.
+    Byte(usize),
+    // A little-endian load of these bytes.
+    Load(Vec<Rc<Expr>>),
+    // Byte number `.1` of the value, starting from the least significant one.
+    ByteOf(Rc<Expr>, usize),
+    // An ALU operation, with opcode `.0` (with `BPF_X`, except for `mov32` with an immediate).
+    Alu(u8, Rc<Expr>, Rc<Expr>),
+    // A `le` or `be` operation, with the given size in bits.
+    End(u8, i32, Rc<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Packet,
+    Stack,
+}
+
+// The value of a register: a concrete value, or for a pointer, an offset in a region. Values
+// derived from the packet also hold the expression computing them.
+#[derive(Debug, Clone)]
+struct Val {
+    v:   u64,
+    ptr: Option<Region>,
+    sym: Option<Rc<Expr>>,
+}
+
+impl Val {
+    fn scalar(v: u64) -> Val {
+        Val { v, ptr: None, sym: None }
+    }
+
+    fn expr(&self) -> Rc<Expr> {
+        self.sym.clone().unwrap_or_else(|| Rc::new(Expr::Const(self.v)))
+    }
+}
+
+// A conditional jump depending on the packet, and the direction it took.
+struct Constraint {
+    opc:   u8,
+    lhs:   Rc<Expr>,
+    rhs:   Rc<Expr>,
+    taken: bool,
+}
+
+// The outcome of one execution.
+struct Run {
+    result:   Result<u64, String>,
+    // Conditional jumps executed, and whether they were taken, with the constraints for those
+    // depending on the packet.
+    branches: Vec<(usize, bool, Option<Constraint>)>,
+}
+
+// The ALU operations of the interpreter, with a register as source operand, or with `mov32`
+// with an immediate. Returns `None` on division by zero.
+fn alu_op(opc: u8, dst: u64, src: u64) -> Option<u64> {
+    const U32MAX: u64 = u32::MAX as u64;
+    Some(match opc {
+        ebpf::ADD32_REG  => (dst as u32).wrapping_add(src as u32) as u64,
+        ebpf::SUB32_REG  => (dst as u32).wrapping_sub(src as u32) as u64,
+        ebpf::MUL32_REG  => (dst as u32).wrapping_mul(src as u32) as u64,
+        ebpf::DIV32_REG  => (dst as u32).checked_div(src as u32)? as u64,
+        ebpf::OR32_REG   => (dst as u32 | src as u32) as u64,
+        ebpf::AND32_REG  => (dst as u32 & src as u32) as u64,
+        ebpf::LSH32_REG  => (dst as u32).wrapping_shl(src as u32) as u64,
+        ebpf::RSH32_REG  => (dst as u32).wrapping_shr(src as u32) as u64,
+        ebpf::NEG32      => (dst as i32).wrapping_neg() as u64 & U32MAX,
+        ebpf::MOD32_REG  => (dst as u32).checked_rem(src as u32)? as u64,
+        ebpf::XOR32_REG  => (dst as u32 ^ src as u32) as u64,
+        ebpf::MOV32_IMM  => src as u32 as u64,
+        ebpf::MOV32_REG  => src as u32 as u64,
+        ebpf::ARSH32_REG => (dst as i32).wrapping_shr(src as u32) as u64 & U32MAX,
+        ebpf::ADD64_REG  => dst.wrapping_add(src),
+        ebpf::SUB64_REG  => dst.wrapping_sub(src),
+        ebpf::MUL64_REG  => dst.wrapping_mul(src),
+        ebpf::DIV64_REG  => dst.checked_div(src)?,
+        ebpf::OR64_REG   => dst | src,
+        ebpf::AND64_REG  => dst & src,
+        ebpf::LSH64_REG  => dst.wrapping_shl(src as u32),
+        ebpf::RSH64_REG  => dst.wrapping_shr(src as u32),
+        ebpf::NEG64      => (dst as i64).wrapping_neg() as u64,
+        ebpf::MOD64_REG  => dst.checked_rem(src)?,
+        ebpf::XOR64_REG  => dst ^ src,
+        ebpf::MOV64_REG  => src,
+        ebpf::ARSH64_REG => (dst as i64).wrapping_shr(src as u32) as u64,
+        _                => 0,
+    })
+}
+
+fn end_op(opc: u8, size: i32, v: u64) -> u64 {
+    match (opc, size) {
+        (ebpf::LE, 16) => (v as u16).to_le() as u64,
+        (ebpf::LE, 32) => (v as u32).to_le() as u64,
+        (ebpf::LE, _)  => v.to_le(),
+        (_, 16)        => (v as u16).to_be() as u64,
+        (_, 32)        => (v as u32).to_be() as u64,
+        (_, _)         => v.to_be(),
+    }
+}
+
+fn jump_taken(opc: u8, dst: u64, src: u64) -> bool {
+    match opc & ebpf::BPF_ALU_OP_MASK {
+        ebpf::BPF_JEQ  => dst == src,
+        ebpf::BPF_JGT  => dst > src,
+        ebpf::BPF_JGE  => dst >= src,
+        ebpf::BPF_JSET => dst & src != 0,
+        ebpf::BPF_JNE  => dst != src,
+        ebpf::BPF_JSGT => dst as i64 > src as i64,
+        ebpf::BPF_JSGE => dst as i64 >= src as i64,
+        ebpf::BPF_JLT  => dst < src,
+        ebpf::BPF_JLE  => dst <= src,
+        ebpf::BPF_JSLT => (dst as i64) < src as i64,
+        ebpf::BPF_JSLE => dst as i64 <= src as i64,
+        _              => true,
+    }
+}
+
+// Evaluates `expr` on `input`. Subexpressions are shared, so they are evaluated once.
+fn eval(expr: &Rc<Expr>, input: &[u8], memo: &mut HashMap<*const Expr, u64>) -> u64 {
+    if let Some(&v) = memo.get(&Rc::as_ptr(expr)) {
+        return v;
+    }
+    let v = match **expr {
+        Expr::Const(v)            => v,
+        Expr::Byte(i)             => input.get(i).cloned().unwrap_or(0) as u64,
+        Expr::Load(ref bytes)     => bytes.iter().enumerate()
+            .fold(0, |acc, (i, b)| acc | (eval(b, input, memo) & 0xff) << (8 * i)),
+        Expr::ByteOf(ref e, i)    => (eval(e, input, memo) >> (8 * i)) & 0xff,
+        Expr::Alu(opc, ref a, ref b) => {
+            let (a, b) = (eval(a, input, memo), eval(b, input, memo));
+            alu_op(opc, a, b).unwrap_or(0)
+        },
+        Expr::End(opc, size, ref e) => end_op(opc, size, eval(e, input, memo)),
+    };
+    memo.insert(Rc::as_ptr(expr), v);
+    v
+}
+
+// Collects the offsets of the packet bytes `expr` depends on, and the constants it uses.
+fn collect(expr: &Rc<Expr>, bytes: &mut BTreeSet<usize>, consts: &mut BTreeSet<u64>,
+           seen: &mut HashSet<*const Expr>) {
+    if !seen.insert(Rc::as_ptr(expr)) {
+        return;
+    }
+    match **expr {
+        Expr::Const(v)               => { consts.insert(v); },
+        Expr::Byte(i)                => { bytes.insert(i); },
+        Expr::Load(ref bs)           => for b in bs {
+            collect(b, bytes, consts, seen);
+        },
+        Expr::ByteOf(ref e, _) | Expr::End(_, _, ref e) => collect(e, bytes, consts, seen),
+        Expr::Alu(_, ref a, ref b)   => {
+            collect(a, bytes, consts, seen);
+            collect(b, bytes, consts, seen);
+        },
+    }
+}
+
+fn load_expr(bytes: Vec<Rc<Expr>>) -> Rc<Expr> {
+    // Loading back all the bytes of a stored value gives the value itself.
+    if bytes.len() == 8 {
+        if let Expr::ByteOf(ref e, 0) = *bytes[0] {
+            let whole = bytes.iter().enumerate().all(|(i, b)| match **b {
+                Expr::ByteOf(ref f, j) => Rc::ptr_eq(e, f) && i == j,
+                _                      => false,
+            });
+            if whole {
+                return e.clone();
+            }
+        }
+    }
+    Rc::new(Expr::Load(bytes))
+}
+
+fn byte_of(expr: &Rc<Expr>, i: usize) -> Rc<Expr> {
+    match **expr {
+        Expr::Load(ref bytes) if i < bytes.len() => bytes[i].clone(),
+        _                                        => Rc::new(Expr::ByteOf(expr.clone(), i)),
+    }
+}
+
+// A xorshift64* generator, for reproducible pseudo-random values.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl<'a> InputGenerator<'a> {
+
+    /// Create a generator for packets of `input_len` bytes, for program `prog`. By default,
+    /// exploration stops after 256 executions, and each execution after 4096 instructions.
+    ///
+    /// # Panics
+    ///
+    /// The simple verifier may panic if it finds errors in the program.
+    pub fn new(prog: &'a [u8], input_len: usize) -> InputGenerator<'a> {
+        verifier::check(&prog.to_vec());
+        InputGenerator { prog, input_len, max_steps: 4096, max_runs: 256 }
+    }
+
+    /// Stop each execution after `max_steps` instructions.
+    pub fn max_steps(mut self, max_steps: usize) -> InputGenerator<'a> {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Stop exploring after `max_runs` executions of the program.
+    pub fn max_runs(mut self, max_runs: usize) -> InputGenerator<'a> {
+        self.max_runs = max_runs;
+        self
+    }
+
+    /// Explore the branches of the program, and return the packets exercising them.
+    pub fn generate(&self) -> GeneratedInputs {
+        let mut generated = GeneratedInputs::default();
+        let mut covered = BTreeSet::new();
+        let mut reached = BTreeSet::new();
+        let mut attempted = HashSet::new();
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut runs = 0;
+
+        let mut worklist = vec![vec![0u8; self.input_len]];
+        while let Some(input) = worklist.pop() {
+            if runs >= self.max_runs {
+                break;
+            }
+            runs += 1;
+            let run = self.execute(&input);
+
+            let mut new_branches = vec![];
+            for &(insn_ptr, taken, _) in &run.branches {
+                reached.insert(insn_ptr);
+                if covered.insert((insn_ptr, taken)) {
+                    new_branches.push((insn_ptr, taken));
+                }
+            }
+            if new_branches.is_empty() && !generated.inputs.is_empty() {
+                continue;
+            }
+
+            // Look for packets exercising the other direction of each jump of this path.
+            for k in 0..run.branches.len() {
+                let (insn_ptr, taken, ref constraint) = run.branches[k];
+                if covered.contains(&(insn_ptr, !taken)) || constraint.is_none() {
+                    continue;
+                }
+                if !attempted.insert((insn_ptr, !taken, k)) {
+                    continue;
+                }
+                let path: Vec<&Constraint> = run.branches[..=k].iter()
+                    .filter_map(|b| b.2.as_ref())
+                    .collect();
+                worklist.extend(solve(&input, &path, &mut rng));
+            }
+
+            generated.inputs.push(GeneratedInput { input, result: run.result, new_branches });
+        }
+
+        for insn_ptr in reached {
+            for &taken in &[true, false] {
+                if !covered.contains(&(insn_ptr, taken)) {
+                    generated.uncovered.insert((insn_ptr, taken));
+                }
+            }
+        }
+        generated
+    }
+
+    fn execute(&self, input: &[u8]) -> Run {
+        let mut branches = vec![];
+        let result = self.interpret(input, &mut branches);
+        Run { result, branches }
+    }
+
+    fn interpret(&self, input: &[u8], branches: &mut Vec<(usize, bool, Option<Constraint>)>)
+                 -> Result<u64, String> {
+        let prog = self.prog;
+        let mut packet: Vec<(u8, Option<Rc<Expr>>)> = input.iter().enumerate()
+            .map(|(i, &b)| (b, Some(Rc::new(Expr::Byte(i)))))
+            .collect();
+        let mut stack: Vec<(u8, Option<Rc<Expr>>)> = vec![(0, None); ebpf::STACK_SIZE];
+        // Pointers spilled to the stack, by offset.
+        let mut spilled: HashMap<usize, Val> = HashMap::new();
+
+        let mut reg: Vec<Val> = vec![Val::scalar(0); 11];
+        reg[1] = Val { v: 0, ptr: Some(Region::Packet), sym: None };
+        reg[10] = Val { v: ebpf::STACK_SIZE as u64, ptr: Some(Region::Stack), sym: None };
+
+        let mut insn_ptr = 0;
+        let mut steps = 0;
+        while insn_ptr * ebpf::INSN_SIZE < prog.len() {
+            steps += 1;
+            if steps > self.max_steps {
+                return Err(format!("more than {} instructions executed", self.max_steps));
+            }
+            let pc = insn_ptr;
+            let insn = ebpf::get_insn(prog, insn_ptr);
+            insn_ptr += 1;
+            let dst = insn.dst as usize;
+            let src = insn.src as usize;
+            let class = insn.opc & ebpf::BPF_CLS_MASK;
+            let size = match insn.opc & 0x18 {
+                ebpf::BPF_B => 1,
+                ebpf::BPF_H => 2,
+                ebpf::BPF_W => 4,
+                _           => 8,
+            };
+
+            // Sign-extending loads and moves, signed divisions and byte swaps (ISA v4).
+            let is_v4 = match insn.opc {
+                ebpf::LD_B_SX | ebpf::LD_H_SX | ebpf::LD_W_SX | ebpf::BSWAP => true,
+                ebpf::MOV32_REG | ebpf::MOV64_REG                           => insn.off != 0,
+                _ => (class == ebpf::BPF_ALU || class == ebpf::BPF_ALU64) && insn.off == 1 &&
+                    matches!(insn.opc & ebpf::BPF_ALU_OP_MASK, ebpf::BPF_DIV | ebpf::BPF_MOD),
+            };
+            if is_v4 {
+                return Err(format!("unsupported instruction (insn #{})", pc));
+            }
+
+            // Memory accesses.
+            if class == ebpf::BPF_LDX || class == ebpf::BPF_ST || class == ebpf::BPF_STX {
+                if insn.opc == ebpf::ST_W_XADD || insn.opc == ebpf::ST_DW_XADD {
+                    return Err(format!("unsupported instruction (insn #{})", pc));
+                }
+                let base = if class == ebpf::BPF_LDX { &reg[src] } else { &reg[dst] };
+                let region = match base.ptr {
+                    Some(region) => region,
+                    None         => return Err(format!("unsupported memory access (insn #{})", pc)),
+                };
+                let addr = (base.v as i64).wrapping_add(insn.off as i64);
+                let mem = match region {
+                    Region::Packet => &mut packet,
+                    Region::Stack  => &mut stack,
+                };
+                if addr < 0 || addr as usize + size > mem.len() {
+                    return Err(format!("out of bounds access (insn #{})", pc));
+                }
+                let addr = addr as usize;
+                if class == ebpf::BPF_LDX {
+                    reg[dst] = match spilled.get(&addr) {
+                        Some(val) if region == Region::Stack && size == 8 => val.clone(),
+                        _ => {
+                            let bytes = &mem[addr..addr + size];
+                            let v = bytes.iter().rev().fold(0, |acc, b| acc << 8 | b.0 as u64);
+                            let sym = match bytes.iter().any(|b| b.1.is_some()) {
+                                true  => Some(load_expr(bytes.iter().map(|b| match b.1 {
+                                    Some(ref e) => e.clone(),
+                                    None        => Rc::new(Expr::Const(b.0 as u64)),
+                                }).collect())),
+                                false => None,
+                            };
+                            Val { v, ptr: None, sym }
+                        },
+                    };
+                } else {
+                    let val = match class {
+                        ebpf::BPF_ST => Val::scalar(insn.imm as u64),
+                        _            => reg[src].clone(),
+                    };
+                    if region == Region::Stack {
+                        spilled.retain(|&off, _| off + 8 <= addr || addr + size <= off);
+                        if val.ptr.is_some() && size == 8 {
+                            spilled.insert(addr, val.clone());
+                        }
+                    }
+                    for (i, byte) in mem[addr..addr + size].iter_mut().enumerate() {
+                        *byte = ((val.v >> (8 * i)) as u8, val.sym.as_ref().map(|e| byte_of(e, i)));
+                    }
+                }
+                continue;
+            }
+
+            match class {
+                ebpf::BPF_LD if insn.opc == ebpf::LD_DW_IMM => {
+                    let next = ebpf::get_insn(prog, insn_ptr);
+                    insn_ptr += 1;
+                    reg[dst] = Val::scalar((insn.imm as u32) as u64 + ((next.imm as u64) << 32));
+                },
+                ebpf::BPF_ALU | ebpf::BPF_ALU64 if insn.opc == ebpf::LE || insn.opc == ebpf::BE => {
+                    let a = reg[dst].clone();
+                    reg[dst] = Val {
+                        v:   end_op(insn.opc, insn.imm, a.v),
+                        ptr: None,
+                        sym: a.sym.map(|e| Rc::new(Expr::End(insn.opc, insn.imm, e))),
+                    };
+                },
+                ebpf::BPF_ALU | ebpf::BPF_ALU64 => {
+                    let is_neg = insn.opc & ebpf::BPF_ALU_OP_MASK == ebpf::BPF_NEG;
+                    let (opc, b) = match insn.opc {
+                        ebpf::MOV32_IMM                   => (insn.opc, Val::scalar(insn.imm as u64)),
+                        _ if is_neg                       => (insn.opc, Val::scalar(0)),
+                        _ if insn.opc & ebpf::BPF_X != 0  => (insn.opc, reg[src].clone()),
+                        _                                 => (insn.opc | ebpf::BPF_X, Val::scalar(insn.imm as u64)),
+                    };
+                    let a = reg[dst].clone();
+                    let v = match alu_op(opc, a.v, b.v) {
+                        Some(v) => v,
+                        None    => return Err(format!("division by 0 (insn #{})", pc)),
+                    };
+                    let op = opc & ebpf::BPF_ALU_OP_MASK;
+                    let ptr = match (a.ptr, b.ptr, opc) {
+                        (_, Some(r), ebpf::MOV64_REG)                   => Some(r),
+                        (Some(r), None, ebpf::ADD64_REG) |
+                        (Some(r), None, ebpf::SUB64_REG) |
+                        (None, Some(r), ebpf::ADD64_REG)                => Some(r),
+                        (Some(r), Some(q), ebpf::SUB64_REG) if r == q   => None,
+                        (None, None, _)                                 => None,
+                        _ if op == ebpf::BPF_MOV && b.ptr.is_none()     => None,
+                        _ => return Err(format!("unsupported pointer arithmetic (insn #{})", pc)),
+                    };
+                    // Offsets of pointers are made concrete.
+                    let sym = match (ptr, a.sym.is_some() || b.sym.is_some()) {
+                        (None, true) if op == ebpf::BPF_MOV => b.sym.clone(),
+                        (None, true)                        => Some(Rc::new(Expr::Alu(opc, a.expr(), b.expr()))),
+                        _                                   => None,
+                    };
+                    reg[dst] = Val { v, ptr, sym };
+                },
+                ebpf::BPF_JMP => match insn.opc {
+                    ebpf::JA    => insn_ptr = (insn_ptr as isize + insn.off as isize) as usize,
+                    ebpf::CALL  => reg[0] = Val::scalar(0),
+                    ebpf::EXIT  => return Ok(reg[0].v),
+                    ebpf::TAIL_CALL => return Err(format!("unsupported instruction (insn #{})", pc)),
+                    _           => {
+                        let a = reg[dst].clone();
+                        let b = match insn.opc & ebpf::BPF_X != 0 {
+                            true  => reg[src].clone(),
+                            false => Val::scalar(insn.imm as u64),
+                        };
+                        if a.ptr != b.ptr {
+                            return Err(format!("unsupported pointer comparison (insn #{})", pc));
+                        }
+                        let taken = jump_taken(insn.opc, a.v, b.v);
+                        let constraint = match a.sym.is_some() || b.sym.is_some() {
+                            true  => Some(Constraint { opc: insn.opc, lhs: a.expr(), rhs: b.expr(), taken }),
+                            false => None,
+                        };
+                        branches.push((pc, taken, constraint));
+                        if taken {
+                            insn_ptr = (insn_ptr as isize + insn.off as isize) as usize;
+                        }
+                    },
+                },
+                _ => return Err(format!("unsupported instruction (insn #{})", pc)),
+            }
+        }
+        Err("jumped out of the program".to_string())
+    }
+}
+
+// Returns whether `input` satisfies all constraints of `path`, the last one being negated.
+fn satisfies(input: &[u8], path: &[&Constraint]) -> bool {
+    let mut memo = HashMap::new();
+    path.iter().enumerate().all(|(i, c)| {
+        let taken = jump_taken(c.opc, eval(&c.lhs, input, &mut memo), eval(&c.rhs, input, &mut memo));
+        match i + 1 == path.len() {
+            true  => taken != c.taken,
+            false => taken == c.taken,
+        }
+    })
+}
+
+// Looks for packets following the jumps of `path` except the last one, in the other direction,
+// by changing the bytes of `input` the last constraint depends on. Several packets are returned
+// when possible, since some of them may stop the execution before it reaches other branches (with
+// an out of bounds access for instance).
+fn solve(input: &[u8], path: &[&Constraint], rng: &mut Rng) -> Vec<Vec<u8>> {
+    const MAX_SOLUTIONS: usize = 4;
+    const RANDOM_TRIES: usize = 1000;
+
+    let mut solutions = BTreeSet::new();
+    let last = path[path.len() - 1];
+    let (mut bytes, mut consts) = (BTreeSet::new(), BTreeSet::new());
+    let mut seen = HashSet::new();
+    collect(&last.lhs, &mut bytes, &mut consts, &mut seen);
+    collect(&last.rhs, &mut bytes, &mut consts, &mut seen);
+    let bytes: Vec<usize> = bytes.into_iter().filter(|&b| b < input.len()).collect();
+    if bytes.is_empty() {
+        return vec![];
+    }
+    let mut candidate = input.to_vec();
+    let try_candidate = |candidate: &Vec<u8>, solutions: &mut BTreeSet<Vec<u8>>| {
+        if satisfies(candidate, path) {
+            solutions.insert(candidate.clone());
+        }
+        solutions.len() >= MAX_SOLUTIONS
+    };
+
+    // The constants of the constraint and their neighbours, written over the bytes in big and
+    // little endian.
+    let mut values = BTreeSet::new();
+    for &c in &consts {
+        for v in [c, c.wrapping_add(1), c.wrapping_sub(1), 0].iter() {
+            values.insert(*v);
+        }
+    }
+    let n = bytes.len().min(8);
+    for &v in &values {
+        for &big_endian in &[true, false] {
+            for (j, &b) in bytes.iter().take(8).enumerate() {
+                let shift = if big_endian { 8 * (n - 1 - j) } else { 8 * j };
+                candidate[b] = (v >> shift) as u8;
+            }
+            if try_candidate(&candidate, &mut solutions) {
+                return solutions.into_iter().collect();
+            }
+        }
+    }
+
+    // All values of a single byte.
+    if bytes.len() == 1 {
+        for v in 0..=255 {
+            candidate[bytes[0]] = v;
+            if try_candidate(&candidate, &mut solutions) {
+                break;
+            }
+        }
+        return solutions.into_iter().collect();
+    }
+
+    let pool: Vec<u8> = consts.iter()
+        .flat_map(|&c| (0..8).map(move |i| (c >> (8 * i)) as u8))
+        .chain([0u8, 1, 0xff].iter().cloned())
+        .collect();
+    for _ in 0..RANDOM_TRIES {
+        for &b in &bytes {
+            let r = rng.next();
+            candidate[b] = match r % 2 {
+                0 => pool[(r >> 8) as usize % pool.len()],
+                _ => (r >> 16) as u8,
+            };
+        }
+        if try_candidate(&candidate, &mut solutions) {
+            break;
+        }
+    }
+    solutions.into_iter().collect()
+}
@@ -0,0 +1,422 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module helps writing unit tests for eBPF programs: it records the calls a program makes
+//! to helpers, and captures the contents of maps, so that tests can check them after a run.
+//!
+//! * `HelperRecorder` is a `helpers::HelperHook` recording the calls the program makes to
+//!   helpers, with their arguments and the values they returned. This includes the helpers run by
+//!   the interpreter itself, such as the map helpers.
+//! * `MapSnapshot` is a copy of the entries of a map at a given time, to compare the contents of
+//!   a map before and after a run.
+//! * Macros `assert_map_contains!` and `assert_helper_called!` check a map or a snapshot, and a
+//!   recorder, and panic with the values found otherwise.
+//! * `TestRun` holds the results of `EbpfVmMbuff::test_run()`, which runs a program as command
+//!   `BPF_PROG_TEST_RUN` of the `bpf()` system call of the Linux kernel does, so that the test
+//!   suites written against the kernel run their programs with rbpf.
+//! * `RegressionTest` generates the source of a standalone test from the trace of a run, to turn
+//!   a bug reproduced with a tracer into a regression test.
+//!
+//! # Examples
+//!
+//! ```
+//! #[macro_use]
+//! extern crate rbpf;
+//!
+//! use std::sync::Arc;
+//! use rbpf::maps::{Map, MapType};
+//! use rbpf::testing::{HelperRecorder, MapSnapshot};
+//!
+//! # fn main() {
+//! // Stores the square root of 9 at index 0 of map 0.
+//! let prog = vec![
+//!     0xb7, 0x01, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // mov64 r1, 9
+//!     0x85, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // call 42 (sqrti)
+//!     0x7b, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r0
+//!     0x62, 0x0a, 0xf4, 0xff, 0x00, 0x00, 0x00, 0x00, // stw [r10-12], 0
+//!     0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+//!     0x07, 0x02, 0x00, 0x00, 0xf4, 0xff, 0xff, 0xff, // add64 r2, -12
+//!     0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+//!     0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+//!     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+//!     0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, BPF_ANY
+//!     0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call bpf_map_update_elem
+//!     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+//! ];
+//!
+//! let map = Arc::new(Map::new(MapType::Hash, 4, 8, 4));
+//! let recorder = HelperRecorder::new();
+//! let mut vm = rbpf::EbpfVmNoData::new(&prog);
+//! vm.register_helper(42, rbpf::helpers::sqrti);
+//! vm.register_map(map.clone());
+//! vm.set_helper_hook(Box::new(&recorder));
+//!
+//! let before = MapSnapshot::take(&map);
+//! vm.prog_exec();
+//!
+//! assert_helper_called!(recorder, 42, 1);
+//! assert_eq!(recorder.calls()[0].ret, 3);
+//! assert_map_contains!(map, 0u32.to_le_bytes(), 3u64.to_le_bytes());
+//! assert_eq!(before.changed_keys(&MapSnapshot::take(&map)), vec![vec![0, 0, 0, 0]]);
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use disassembler;
+use ebpf;
+use helpers::{HelperHook, HookVerdict};
+use maps::Map;
+use trace::TraceEntry;
+
+/// A call to a helper, recorded by a `HelperRecorder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HelperCall {
+    /// The key of the helper.
+    pub key:  u32,
+    /// The arguments passed to the helper, in R1 to R5.
+    pub args: [u64; 5],
+    /// The value returned by the helper.
+    pub ret:  u64,
+}
+
+/// A helper hook recording the calls to helpers, see the module documentation. The recorder
+/// allows all the calls; it is attached to a VM by reference, with
+/// `vm.set_helper_hook(Box::new(&recorder))`, so that the test can read the calls afterwards.
+#[derive(Debug, Default)]
+pub struct HelperRecorder {
+    calls: RefCell<Vec<HelperCall>>,
+}
+
+impl HelperRecorder {
+
+    /// Create a recorder, with no calls recorded.
+    pub fn new() -> HelperRecorder {
+        HelperRecorder::default()
+    }
+
+    /// The calls recorded, in the order they were made.
+    pub fn calls(&self) -> Vec<HelperCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// The calls recorded to helper `key`, in the order they were made.
+    pub fn calls_to(&self, key: u32) -> Vec<HelperCall> {
+        self.calls.borrow().iter().filter(|call| call.key == key).cloned().collect()
+    }
+
+    /// The keys of the helpers called, in the order of the calls.
+    pub fn sequence(&self) -> Vec<u32> {
+        self.calls.borrow().iter().map(|call| call.key).collect()
+    }
+
+    /// Forget the calls recorded so far.
+    pub fn clear(&self) {
+        self.calls.borrow_mut().clear();
+    }
+}
+
+impl HelperHook for HelperRecorder {
+    fn before_call(&self, _key: u32, _args: &[u64; 5]) -> HookVerdict {
+        HookVerdict::Allow
+    }
+
+    fn after_call(&self, key: u32, args: &[u64; 5], ret: u64) {
+        self.calls.borrow_mut().push(HelperCall { key, args: *args, ret });
+    }
+}
+
+/// The results of a test run of a program, see `EbpfVmMbuff::test_run()`, as returned by command
+/// `BPF_PROG_TEST_RUN` of the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestRun {
+    /// The value returned by the program on its last run, truncated to 32 bits as in the kernel.
+    pub retval:   u32,
+    /// The average duration of a run, in nanoseconds.
+    pub duration: u32,
+    /// The packet data after the last run.
+    pub data_out: Vec<u8>,
+    /// The context (the metadata buffer) after the last run.
+    pub ctx_out:  Vec<u8>,
+}
+
+/// A copy of the entries of a map, see `maps::Map::iter()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapSnapshot {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MapSnapshot {
+
+    /// Copy the entries of `map`.
+    pub fn take(map: &Map) -> MapSnapshot {
+        MapSnapshot { entries: map.iter().collect() }
+    }
+
+    /// The value associated with `key` when the snapshot was taken, if any.
+    pub fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.get(key).cloned()
+    }
+
+    /// The entries of the snapshot, sorted by key.
+    pub fn entries(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.entries
+    }
+
+    /// The keys of the entries added, removed or updated between this snapshot and `later`,
+    /// sorted.
+    pub fn changed_keys(&self, later: &MapSnapshot) -> Vec<Vec<u8>> {
+        let mut keys: Vec<_> = self.entries.keys().chain(later.entries.keys())
+            .filter(|key| self.entries.get(*key) != later.entries.get(*key))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+/// A generator of regression tests: from the trace of a run of a program with `EbpfVmRaw`, it
+/// writes the source of a standalone Rust test running the program on the same packet data, and
+/// checking the value returned, the number of instructions executed, and the final values of the
+/// registers. The test can be added to the test suite of a crate, to turn a bug reproduced with a
+/// trace into a regression test.
+///
+/// The registers holding addresses, which change from one run to another, are not checked: the
+/// generator follows the values derived from the addresses of the packet data (R1) and of the
+/// stack (R10) along the trace, through the registers and the stack. The values returned by the
+/// helpers are assumed not to be addresses.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::testing::RegressionTest;
+/// use rbpf::trace::TraceLog;
+///
+/// let prog = vec![
+///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+///     0x07, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r2, 1
+///     0x73, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r2
+///     0xbf, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// let input = vec![0x41, 0x00];
+///
+/// let mut mem = input.clone();
+/// let mut log = TraceLog::new();
+/// rbpf::EbpfVmRaw::new(&prog).prog_exec_trace(&mut mem, &mut log);
+///
+/// let test = RegressionTest::new("ldxb_add", &prog, &input)
+///     .expect_mem(&mem)
+///     .generate(log.entries());
+/// assert!(test.contains("fn ldxb_add()"));
+/// assert!(test.contains("0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]"));
+/// assert!(test.contains("assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), 0x42);"));
+/// assert!(test.contains("assert_eq!(regs[2], 0x42);"));
+/// assert!(test.contains("assert_eq!(mem, vec![0x41, 0x42]);"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegressionTest {
+    name:    String,
+    prog:    Vec<u8>,
+    mem:     Vec<u8>,
+    helpers: BTreeMap<u32, String>,
+    mem_out: Option<Vec<u8>>,
+}
+
+// Writes `bytes` as the elements of a vector, 16 bytes per line.
+fn write_bytes(src: &mut String, bytes: &[u8]) {
+    for chunk in bytes.chunks(16) {
+        let line: Vec<String> = chunk.iter().map(|b| format!("{:#04x}", b)).collect();
+        src.push_str(&format!("        {},\n", line.join(", ")));
+    }
+}
+
+// Whether each register holds a value derived from an address at the end of `trace`.
+fn address_regs(trace: &[TraceEntry]) -> [bool; 11] {
+    let mut regs = [false; 11];
+    regs[1] = true;
+    regs[10] = true;
+    // The addresses of the 8-byte slots of memory holding addresses.
+    let mut slots = HashSet::new();
+    for entry in trace {
+        let insn = &entry.insn;
+        let (dst, src) = (insn.dst as usize % 11, insn.src as usize % 11);
+        let access = entry.mem_access;
+        match insn.opc & ebpf::BPF_CLS_MASK {
+            ebpf::BPF_ALU | ebpf::BPF_ALU64 => match insn.opc & ebpf::BPF_ALU_OP_MASK {
+                ebpf::BPF_MOV => regs[dst] = insn.opc & ebpf::BPF_X != 0 && regs[src],
+                // For byte swaps, BPF_X selects the big endian.
+                ebpf::BPF_END => (),
+                _ => regs[dst] |= insn.opc & ebpf::BPF_X != 0 && regs[src],
+            },
+            ebpf::BPF_LD if insn.opc == ebpf::LD_DW_IMM => regs[dst] = insn.src != 0,
+            ebpf::BPF_LD => regs[0] = false,
+            ebpf::BPF_LDX => regs[dst] = access.is_some_and(|a| a.len == 8 && slots.contains(&a.addr)),
+            ebpf::BPF_ST => if let Some(a) = access {
+                slots.remove(&a.addr);
+            },
+            ebpf::BPF_STX if insn.opc & 0xe0 == ebpf::BPF_XADD => if let Some(a) = access {
+                let address = regs[src] || slots.contains(&a.addr);
+                if address {
+                    slots.insert(a.addr);
+                }
+                for delta in entry.reg_deltas.iter() {
+                    regs[delta.reg as usize % 11] = address;
+                }
+            },
+            ebpf::BPF_STX => if let Some(a) = access {
+                if regs[src] && a.len == 8 {
+                    slots.insert(a.addr);
+                } else {
+                    slots.remove(&a.addr);
+                }
+            },
+            _ if insn.opc == ebpf::CALL && insn.src == 0 => regs[0] = false,
+            _ => (),
+        }
+    }
+    regs
+}
+
+impl RegressionTest {
+
+    /// Create a generator of a test named `name`, which must be a valid Rust identifier, running
+    /// `prog` on packet data `mem`, the data the traced run started with.
+    pub fn new(name: &str, prog: &[u8], mem: &[u8]) -> RegressionTest {
+        RegressionTest {
+            name: name.to_string(), prog: prog.to_vec(), mem: mem.to_vec(), helpers: BTreeMap::new(), mem_out: None,
+        }
+    }
+
+    /// Register the helper of key `key` in the test, as the function at path `path`, for example
+    /// `rbpf::helpers::sqrti`. The generated test marks with a `FIXME` comment the helpers called
+    /// in the trace but not registered, unless the interpreter runs them itself.
+    pub fn helper(mut self, key: u32, path: &str) -> RegressionTest {
+        self.helpers.insert(key, path.to_string());
+        self
+    }
+
+    /// Check in the test that the packet data is `mem_out` after the run, for example the data
+    /// the traced run left.
+    pub fn expect_mem(mut self, mem_out: &[u8]) -> RegressionTest {
+        self.mem_out = Some(mem_out.to_vec());
+        self
+    }
+
+    /// Generate the source of the test, checking the outcome of the run traced in `trace`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of the program is not a multiple of the size of an instruction.
+    pub fn generate(&self, trace: &[TraceEntry]) -> String {
+        let comments: HashMap<usize, String> = disassembler::to_insn_vec(&self.prog).into_iter()
+            .map(|insn| (insn.ptr, insn.desc))
+            .collect();
+        let mut src = format!("#[test]\nfn {}() {{\n    let prog = vec![\n", self.name);
+        for (ptr, insn) in self.prog.chunks(ebpf::INSN_SIZE).enumerate() {
+            let line: Vec<String> = insn.iter().map(|b| format!("{:#04x}", b)).collect();
+            match comments.get(&ptr) {
+                Some(desc) => src.push_str(&format!("        {}, // {}\n", line.join(", "), desc)),
+                None       => src.push_str(&format!("        {},\n", line.join(", "))),
+            }
+        }
+        src.push_str("    ];\n    let mut mem: Vec<u8> = vec![\n");
+        write_bytes(&mut src, &self.mem);
+        src.push_str("    ];\n");
+
+        let mut called = BTreeSet::new();
+        for entry in trace.iter().filter(|entry| entry.insn.opc == ebpf::CALL && entry.insn.src == 0) {
+            called.insert(entry.insn.imm as u32);
+        }
+        for key in called.iter().filter(|key| !self.helpers.contains_key(key)) {
+            src.push_str(&format!("    // FIXME: register helper {:#x}, unless the interpreter runs it.\n", key));
+        }
+        let mutable = if self.helpers.is_empty() { "" } else { "mut " };
+        src.push_str(&format!("    let {}vm = rbpf::EbpfVmRaw::new(&prog);\n", mutable));
+        for (key, path) in self.helpers.iter() {
+            src.push_str(&format!("    vm.register_helper({:#x}, {});\n", key, path));
+        }
+        src.push_str("    let mut log = rbpf::trace::TraceLog::new();\n");
+
+        let mut values = [None; 11];
+        for delta in trace.iter().flat_map(|entry| entry.reg_deltas.iter()) {
+            values[delta.reg as usize % 11] = Some(delta.new);
+        }
+        let addresses = address_regs(trace);
+        match addresses[0] {
+            true  => src.push_str("    vm.prog_exec_trace(&mut mem, &mut log);\n"),
+            false => src.push_str(&format!("    assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), {:#x});\n",
+                                           values[0].unwrap_or(0))),
+        }
+        src.push_str(&format!("    assert_eq!(log.entries().len(), {});\n", trace.len()));
+        let checked: Vec<(usize, u64)> = (1..10)
+            .filter(|&reg| !addresses[reg])
+            .filter_map(|reg| values[reg].map(|value| (reg, value)))
+            .collect();
+        if !checked.is_empty() {
+            src.push_str("    let mut regs = [0u64; 11];\n");
+            src.push_str("    for delta in log.entries().iter().flat_map(|entry| entry.reg_deltas.iter()) {\n");
+            src.push_str("        regs[delta.reg as usize] = delta.new;\n    }\n");
+            for (reg, value) in checked {
+                src.push_str(&format!("    assert_eq!(regs[{}], {:#x});\n", reg, value));
+            }
+        }
+        if let Some(ref mem_out) = self.mem_out {
+            let bytes: Vec<String> = mem_out.iter().map(|b| format!("{:#04x}", b)).collect();
+            src.push_str(&format!("    assert_eq!(mem, vec![{}]);\n", bytes.join(", ")));
+        }
+        src.push_str("}\n");
+        src
+    }
+}
+
+/// Assert that a map, or a `MapSnapshot`, associates the value `$value` with the key `$key`, or
+/// holds an entry for `$key` if no value is given. Keys and values are anything that can be
+/// indexed as a byte slice, such as arrays and vectors of bytes.
+///
+/// See the documentation of module `testing`.
+#[macro_export]
+macro_rules! assert_map_contains {
+    ($map:expr, $key:expr, $value:expr) => {{
+        let (key, value): (&[u8], &[u8]) = (&$key[..], &$value[..]);
+        match $map.lookup(key) {
+            Some(ref found) if &found[..] == value => (),
+            found => panic!("assertion failed: map does not map key {:?} to {:?}, found {:?}",
+                            key, value, found),
+        }
+    }};
+    ($map:expr, $key:expr) => {{
+        let key: &[u8] = &$key[..];
+        if $map.lookup(key).is_none() {
+            panic!("assertion failed: map has no entry for key {:?}", key);
+        }
+    }};
+}
+
+/// Assert that the helper of key `$key` was called, as recorded by the
+/// `testing::HelperRecorder` `$recorder`: at least once, or exactly `$times` times if given.
+///
+/// See the documentation of module `testing`.
+#[macro_export]
+macro_rules! assert_helper_called {
+    ($recorder:expr, $key:expr, $times:expr) => {{
+        let (key, times): (u32, usize) = ($key, $times);
+        let calls = $recorder.calls_to(key).len();
+        if calls != times {
+            panic!("assertion failed: helper {} called {} times, expected {}, calls: {:?}",
+                   key, calls, times, $recorder.sequence());
+        }
+    }};
+    ($recorder:expr, $key:expr) => {{
+        let key: u32 = $key;
+        if $recorder.calls_to(key).is_empty() {
+            panic!("assertion failed: helper {} not called, calls: {:?}", key, $recorder.sequence());
+        }
+    }};
+}
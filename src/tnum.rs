@@ -0,0 +1,143 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+// Tristate numbers ("tnums"), used by the verifier to track which bits of a register are known.
+//
+// A tnum is a pair (value, mask): bits set in `mask` are unknown, the other bits are equal to the
+// corresponding bits of `value` (unknown bits are always cleared in `value`). A tnum represents
+// the set of all 64-bit integers matching the known bits. Operations on tnums return a tnum that
+// contains all the results of the operation applied to members of the operands.
+//
+// The arithmetic operations follow "Sound, Precise, and Fast Abstract Interpretation with Tristate
+// Numbers" (Vishwanathan et al., CGO 2022), which describes the algorithms used by the verifier of
+// the Linux kernel.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tnum {
+    pub value: u64,
+    pub mask:  u64,
+}
+
+impl Tnum {
+
+    pub fn constant(value: u64) -> Tnum {
+        Tnum { value, mask: 0 }
+    }
+
+    pub fn unknown() -> Tnum {
+        Tnum { value: 0, mask: u64::MAX }
+    }
+
+    // The smallest tnum containing all integers in [min, max]: bits above the highest bit that
+    // differs between `min` and `max` are known.
+    pub fn range(min: u64, max: u64) -> Tnum {
+        let bits = 64 - (min ^ max).leading_zeros();
+        if bits >= 64 {
+            return Tnum::unknown();
+        }
+        let delta = (1u64 << bits) - 1;
+        Tnum { value: min & !delta, mask: delta }
+    }
+
+    pub fn is_const(&self) -> bool {
+        self.mask == 0
+    }
+
+    pub fn min(&self) -> u64 {
+        self.value
+    }
+
+    pub fn max(&self) -> u64 {
+        self.value | self.mask
+    }
+
+    pub fn add(self, other: Tnum) -> Tnum {
+        let sm = self.mask.wrapping_add(other.mask);
+        let sv = self.value.wrapping_add(other.value);
+        let chi = sm.wrapping_add(sv) ^ sv;
+        let mu = chi | self.mask | other.mask;
+        Tnum { value: sv & !mu, mask: mu }
+    }
+
+    pub fn sub(self, other: Tnum) -> Tnum {
+        let dv = self.value.wrapping_sub(other.value);
+        let alpha = dv.wrapping_add(self.mask);
+        let beta = dv.wrapping_sub(other.mask);
+        let mu = (alpha ^ beta) | self.mask | other.mask;
+        Tnum { value: dv & !mu, mask: mu }
+    }
+
+    pub fn mul(self, other: Tnum) -> Tnum {
+        let acc_v = self.value.wrapping_mul(other.value);
+        let mut acc_m = Tnum::constant(0);
+        let (mut a, mut b) = (self, other);
+        while a.value != 0 || a.mask != 0 {
+            if a.value & 1 != 0 {
+                acc_m = acc_m.add(Tnum { value: 0, mask: b.mask });
+            } else if a.mask & 1 != 0 {
+                acc_m = acc_m.add(Tnum { value: 0, mask: b.value | b.mask });
+            }
+            a = a.rshift(1);
+            b = b.lshift(1);
+        }
+        Tnum::constant(acc_v).add(acc_m)
+    }
+
+    pub fn and(self, other: Tnum) -> Tnum {
+        let value = self.value & other.value;
+        Tnum { value, mask: self.max() & other.max() & !value }
+    }
+
+    pub fn or(self, other: Tnum) -> Tnum {
+        let value = self.value | other.value;
+        Tnum { value, mask: (self.mask | other.mask) & !value }
+    }
+
+    pub fn xor(self, other: Tnum) -> Tnum {
+        let mask = self.mask | other.mask;
+        Tnum { value: (self.value ^ other.value) & !mask, mask }
+    }
+
+    pub fn lshift(self, shift: u32) -> Tnum {
+        Tnum { value: self.value << shift, mask: self.mask << shift }
+    }
+
+    pub fn rshift(self, shift: u32) -> Tnum {
+        Tnum { value: self.value >> shift, mask: self.mask >> shift }
+    }
+
+    pub fn arshift(self, shift: u32) -> Tnum {
+        Tnum {
+            value: ((self.value as i64) >> shift) as u64,
+            mask:  ((self.mask as i64) >> shift) as u64,
+        }
+    }
+
+    // Keep the `size` lowest bytes, clear the others.
+    pub fn cast(self, size: usize) -> Tnum {
+        if size >= 8 {
+            return self;
+        }
+        let keep = (1u64 << (size * 8)) - 1;
+        Tnum { value: self.value & keep, mask: self.mask & keep }
+    }
+
+    // The integers belonging to both tnums, or `None` if there is none.
+    pub fn intersect(self, other: Tnum) -> Option<Tnum> {
+        if (self.value ^ other.value) & !self.mask & !other.mask != 0 {
+            return None;
+        }
+        let mask = self.mask & other.mask;
+        Some(Tnum { value: (self.value | other.value) & !mask, mask })
+    }
+
+    // The smallest tnum containing both tnums.
+    pub fn union(self, other: Tnum) -> Tnum {
+        let mask = self.mask | other.mask | (self.value ^ other.value);
+        Tnum { value: self.value & !mask, mask }
+    }
+}
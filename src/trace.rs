@@ -0,0 +1,668 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+//! This module provides tracers for recording the execution of eBPF programs by the interpreter.
+//!
+//! When a program is run with one of the `prog_exec_trace()` functions of the virtual machines,
+//! the interpreter hands an entry to the tracer for each executed instruction. The entry holds the
+//! instruction, the registers it modified (with their old and new values) and the memory location
+//! it accessed, if any. Tracers can keep the entries in memory (`TraceLog`), or write them out as
+//! human-readable text (`TextWriter`), JSON lines (`JsonLinesWriter`) or a compact binary format
+//! (`BinaryWriter`, read back with `read_binary()`), so that failing runs, for example in CI, can
+//! be examined afterwards without rerunning the program under a debugger. Long executions can be
+//! explored visually in chrome://tracing or Perfetto, with the events of `ChromeTraceWriter`.
+//!
+//! Helper functions are not traced themselves: a call to a helper appears as a single `call`
+//! instruction, with the change of R0 as its only effect.
+
+use std::io;
+use std::io::{Read, Write};
+
+use btf::LineInfo;
+use disassembler;
+use ebpf;
+use helpers::BPF_TAIL_CALL_IDX;
+use ProgramMeta;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Kind of a memory access performed by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AccessKind {
+    /// Memory read, by a `ldx*` instruction.
+    Load,
+    /// Memory write, by a `st*` or `stx*` instruction.
+    Store,
+}
+
+/// A memory access performed by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemAccess {
+    /// Whether the memory was read or written.
+    pub kind: AccessKind,
+    /// Address of the first byte accessed.
+    pub addr: u64,
+    /// Number of bytes accessed: 1, 2, 4 or 8.
+    pub len: usize,
+    /// Value loaded or stored, zero-extended to 64 bits.
+    pub value: u64,
+}
+
+/// A change of value of a register, caused by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RegDelta {
+    /// Register number, from 0 to 10.
+    pub reg: u8,
+    /// Value of the register before the instruction.
+    pub old: u64,
+    /// Value of the register after the instruction.
+    pub new: u64,
+}
+
+/// The record of the execution of one instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TraceEntry {
+    /// Index of the instruction in the program being run. After a tail call, this is an index in
+    /// the program that was jumped to.
+    pub insn_ptr: usize,
+    /// The instruction. For `lddw`, only the first half of the instruction is recorded.
+    pub insn: ebpf::Insn,
+    /// The registers modified by the instruction, in increasing register order.
+    pub reg_deltas: Vec<RegDelta>,
+    /// The memory access performed by the instruction, if any.
+    pub mem_access: Option<MemAccess>,
+}
+
+impl TraceEntry {
+    /// Build the entry for instruction `insn`, at index `insn_ptr`, given the values of the
+    /// registers before (`regs_before`) and after (`regs_after`) its execution. The memory access
+    /// is deduced from the instruction and the registers.
+    pub fn new(insn_ptr: usize, insn: ebpf::Insn, regs_before: &[u64], regs_after: &[u64]) -> TraceEntry {
+        let reg_deltas = regs_before.iter().zip(regs_after).enumerate()
+            .filter(|&(_, (old, new))| old != new)
+            .map(|(reg, (&old, &new))| RegDelta { reg: reg as u8, old, new })
+            .collect();
+
+        let len = match insn.opc & 0x18 {
+            ebpf::BPF_B => 1,
+            ebpf::BPF_H => 2,
+            ebpf::BPF_W => 4,
+            _           => 8,
+        };
+        let mask = if len == 8 { u64::MAX } else { (1u64 << (len * 8)) - 1 };
+        let mem_access = match insn.opc & ebpf::BPF_CLS_MASK {
+            ebpf::BPF_LDX => Some(MemAccess {
+                kind:  AccessKind::Load,
+                addr:  regs_before[insn.src as usize].wrapping_add(insn.off as u64),
+                len,
+                value: regs_after[insn.dst as usize],
+            }),
+            ebpf::BPF_ST  => Some(MemAccess {
+                kind:  AccessKind::Store,
+                addr:  regs_before[insn.dst as usize].wrapping_add(insn.off as u64),
+                len,
+                value: insn.imm as u64 & mask,
+            }),
+            ebpf::BPF_STX => Some(MemAccess {
+                kind:  AccessKind::Store,
+                addr:  regs_before[insn.dst as usize].wrapping_add(insn.off as u64),
+                len,
+                value: regs_before[insn.src as usize] & mask,
+            }),
+            _             => None,
+        };
+
+        TraceEntry { insn_ptr, insn, reg_deltas, mem_access }
+    }
+}
+
+/// A receiver for the entries produced while tracing the execution of a program.
+pub trait Tracer {
+    /// Called by the interpreter after the execution of each instruction.
+    fn trace(&mut self, entry: &TraceEntry);
+
+    /// Called by the interpreter before running a program with a name or tags, see
+    /// `EbpfVmMbuff::set_name()`. Does nothing by default.
+    fn start_program(&mut self, _program: &ProgramMeta) {}
+}
+
+/// A tracer keeping all entries in memory.
+///
+/// # Examples
+///
+/// ```
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov r0, 3
+///     0x07, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // add r0, 4
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let vm = rbpf::EbpfVmNoData::new(&prog);
+/// let mut log = rbpf::trace::TraceLog::new();
+/// assert_eq!(vm.prog_exec_trace(&mut log), 7);
+///
+/// assert_eq!(log.entries().len(), 3);
+/// assert_eq!(log.entries()[1].reg_deltas[0].old, 3);
+/// assert_eq!(log.entries()[1].reg_deltas[0].new, 7);
+/// ```
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TraceLog {
+    entries: Vec<TraceEntry>,
+}
+
+impl TraceLog {
+    /// Create a new, empty log.
+    pub fn new() -> TraceLog {
+        TraceLog::default()
+    }
+
+    /// Return the entries recorded so far, in execution order.
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    /// Remove all recorded entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Tracer for TraceLog {
+    fn trace(&mut self, entry: &TraceEntry) {
+        self.entries.push(entry.clone());
+    }
+}
+
+fn access_kind_name(kind: AccessKind) -> &'static str {
+    match kind {
+        AccessKind::Load  => "load",
+        AccessKind::Store => "store",
+    }
+}
+
+fn write_text<W: Write>(w: &mut W, entry: &TraceEntry, line_info: Option<&LineInfo>) -> io::Result<()> {
+    let insn = &entry.insn;
+    write!(w, "{:5}: opc {:#04x} dst r{} src r{} off {:+} imm {:#x}",
+           entry.insn_ptr, insn.opc, insn.dst, insn.src, insn.off, insn.imm)?;
+    for delta in &entry.reg_deltas {
+        write!(w, " | r{}: {:#x} -> {:#x}", delta.reg, delta.old, delta.new)?;
+    }
+    if let Some(ref access) = entry.mem_access {
+        write!(w, " | {} {} bytes at {:#x}: {:#x}",
+               access_kind_name(access.kind), access.len, access.addr, access.value)?;
+    }
+    let func = line_info.and_then(|info| info.function(entry.insn_ptr));
+    match (func, line_info.and_then(|info| info.lookup(entry.insn_ptr))) {
+        (Some(func), Some(loc)) => write!(w, " | {} at {}", func, loc)?,
+        (Some(func), None)      => write!(w, " | {}", func)?,
+        (None, Some(loc))       => write!(w, " | {}", loc)?,
+        (None, None)            => (),
+    }
+    writeln!(w)
+}
+
+// Register values and addresses are written as hexadecimal strings, as 64-bit integers cannot all
+// be represented exactly by JSON parsers using double-precision numbers.
+fn write_json<W: Write>(w: &mut W, entry: &TraceEntry, line_info: Option<&LineInfo>) -> io::Result<()> {
+    let insn = &entry.insn;
+    write!(w, "{{\"insn_ptr\":{},\"opc\":{},\"dst\":{},\"src\":{},\"off\":{},\"imm\":{},\"regs\":[",
+           entry.insn_ptr, insn.opc, insn.dst, insn.src, insn.off, insn.imm)?;
+    for (i, delta) in entry.reg_deltas.iter().enumerate() {
+        if i > 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "{{\"reg\":{},\"old\":\"{:#x}\",\"new\":\"{:#x}\"}}", delta.reg, delta.old, delta.new)?;
+    }
+    write!(w, "],\"mem\":")?;
+    match entry.mem_access {
+        Some(ref access) => write!(w, "{{\"kind\":\"{}\",\"addr\":\"{:#x}\",\"len\":{},\"value\":\"{:#x}\"}}",
+                                   access_kind_name(access.kind), access.addr, access.len, access.value)?,
+        None             => write!(w, "null")?,
+    }
+    if let Some(loc) = line_info.and_then(|info| info.lookup(entry.insn_ptr)) {
+        write!(w, ",\"file\":\"{}\",\"line\":{},\"col\":{}", json_escape(&loc.file), loc.line, loc.col)?;
+    }
+    if let Some(func) = line_info.and_then(|info| info.function(entry.insn_ptr)) {
+        write!(w, ",\"function\":\"{}\"", json_escape(func))?;
+    }
+    writeln!(w, "}}")
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"'                    => escaped.push_str("\\\""),
+            '\\'                   => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c                      => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Magic bytes starting a binary trace, followed by one byte for the version of the format.
+pub const BINARY_MAGIC: &[u8; 4] = b"RBTR";
+/// Version of the binary trace format written by `BinaryWriter`.
+pub const BINARY_VERSION: u8 = 1;
+
+fn write_binary<W: Write>(w: &mut W, entry: &TraceEntry, _line_info: Option<&LineInfo>) -> io::Result<()> {
+    let insn = &entry.insn;
+    let mut buf = Vec::with_capacity(32);
+    buf.extend_from_slice(&(entry.insn_ptr as u32).to_le_bytes());
+    buf.push(insn.opc);
+    buf.push(insn.src << 4 | insn.dst);
+    buf.extend_from_slice(&insn.off.to_le_bytes());
+    buf.extend_from_slice(&insn.imm.to_le_bytes());
+    buf.push(entry.reg_deltas.len() as u8);
+    for delta in &entry.reg_deltas {
+        buf.push(delta.reg);
+        buf.extend_from_slice(&delta.old.to_le_bytes());
+        buf.extend_from_slice(&delta.new.to_le_bytes());
+    }
+    match entry.mem_access {
+        Some(ref access) => {
+            buf.push(match access.kind { AccessKind::Load => 1, AccessKind::Store => 2 });
+            buf.push(access.len as u8);
+            buf.extend_from_slice(&access.addr.to_le_bytes());
+            buf.extend_from_slice(&access.value.to_le_bytes());
+        },
+        None             => buf.push(0),
+    }
+    w.write_all(&buf)
+}
+
+macro_rules! trace_writer {
+    ( $name:ident, $write_fn:ident, $doc:expr ) => {
+        #[doc=$doc]
+        ///
+        /// I/O errors do not interrupt the execution of the program: once an error occurs, the
+        /// following entries are dropped, and the error is returned by `finish()`.
+        #[derive(Debug)]
+        pub struct $name<W: Write> {
+            writer:    W,
+            error:     Option<io::Error>,
+            line_info: Option<LineInfo>,
+        }
+
+        impl<W: Write> $name<W> {
+            /// Flush the writer and return it, or return the first error that occurred while
+            /// writing the trace.
+            pub fn finish(mut self) -> io::Result<W> {
+                if let Some(err) = self.error {
+                    return Err(err);
+                }
+                self.writer.flush()?;
+                Ok(self.writer)
+            }
+        }
+
+        impl<W: Write> Tracer for $name<W> {
+            fn trace(&mut self, entry: &TraceEntry) {
+                if self.error.is_none() {
+                    if let Err(err) = $write_fn(&mut self.writer, entry, self.line_info.as_ref()) {
+                        self.error = Some(err);
+                    }
+                }
+            }
+        }
+    };
+}
+
+trace_writer!(TextWriter, write_text,
+              "A tracer writing one human-readable line per executed instruction.");
+trace_writer!(JsonLinesWriter, write_json,
+              "A tracer writing one JSON object per executed instruction, one object per line.");
+trace_writer!(BinaryWriter, write_binary,
+              "A tracer writing entries in a compact binary format, which can be decoded with \
+               `read_binary()`.");
+
+impl<W: Write> TextWriter<W> {
+    /// Create a tracer writing to `writer`.
+    pub fn new(writer: W) -> TextWriter<W> {
+        TextWriter { writer, error: None, line_info: None }
+    }
+
+    /// Append the source location of each instruction to its line, as found in `info`.
+    pub fn with_line_info(mut self, info: LineInfo) -> TextWriter<W> {
+        self.line_info = Some(info);
+        self
+    }
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    /// Create a tracer writing to `writer`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let prog = vec![
+    ///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+    ///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    /// ];
+    ///
+    /// let vm = rbpf::EbpfVmNoData::new(&prog);
+    /// let mut tracer = rbpf::trace::JsonLinesWriter::new(Vec::new());
+    /// vm.prog_exec_trace(&mut tracer);
+    ///
+    /// let json = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    /// assert_eq!(json.lines().next().unwrap(),
+    ///            "{\"insn_ptr\":0,\"opc\":183,\"dst\":0,\"src\":0,\"off\":0,\"imm\":1,\
+    ///              \"regs\":[{\"reg\":0,\"old\":\"0x0\",\"new\":\"0x1\"}],\"mem\":null}");
+    /// ```
+    pub fn new(writer: W) -> JsonLinesWriter<W> {
+        JsonLinesWriter { writer, error: None, line_info: None }
+    }
+
+    /// Add the source location of each instruction, as found in `info`, to its object (fields
+    /// `file`, `line` and `col`).
+    pub fn with_line_info(mut self, info: LineInfo) -> JsonLinesWriter<W> {
+        self.line_info = Some(info);
+        self
+    }
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Create a tracer writing to `writer`. The header of the trace (`BINARY_MAGIC` and
+    /// `BINARY_VERSION`) is written immediately.
+    ///
+    /// Each entry is then encoded as follows, with all integers in little-endian byte order:
+    ///
+    /// - the index of the instruction, on 32 bits;
+    /// - the instruction, on 8 bytes, as in eBPF bytecode;
+    /// - the number of modified registers, on 8 bits, and for each of them the register number on
+    ///   8 bits, then its old and new values on 64 bits each;
+    /// - the kind of memory access on 8 bits (0 for none, 1 for a load, 2 for a store), and if
+    ///   there is one, its length on 8 bits, then its address and value on 64 bits each.
+    pub fn new(mut writer: W) -> BinaryWriter<W> {
+        let error = writer.write_all(BINARY_MAGIC)
+            .and_then(|_| writer.write_all(&[BINARY_VERSION]))
+            .err();
+        BinaryWriter { writer, error, line_info: None }
+    }
+}
+
+/// A tracer writing the execution as events in the Trace Event Format of chrome://tracing, which
+/// can also be opened with Perfetto.
+///
+/// Each program has its own track, named with `set_program()`, where each run of the program is a
+/// span containing one span per executed instruction. Calls to helpers are in category `helper`,
+/// other instructions in category `insn`. Programs reached by a tail call get a track of their
+/// own, named after the calling program. Timestamps count executed instructions: each instruction
+/// lasts one microsecond, calls to helpers included.
+///
+/// Entries recorded earlier, for example with a `TraceLog` or read with `read_binary()`, can be
+/// converted by passing them to `trace()`.
+///
+/// I/O errors do not interrupt the execution of the program: once an error occurs, the following
+/// entries are dropped, and the error is returned by `finish()`.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::trace::{ChromeTraceWriter, Tracer};
+///
+/// let prog = vec![
+///     0xb7, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // mov64 r1, 16
+///     0x85, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // call 3 (sqrti)
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let mut vm = rbpf::EbpfVmNoData::new(&prog);
+/// vm.register_helper(3, rbpf::helpers::sqrti);
+/// let mut tracer = ChromeTraceWriter::new(Vec::new());
+/// tracer.set_program("sqrt");
+/// vm.prog_exec_trace(&mut tracer);
+///
+/// let json = String::from_utf8(tracer.finish().unwrap()).unwrap();
+/// assert!(json.starts_with("{\"traceEvents\":["));
+/// assert!(json.contains("{\"name\":\"call 0x3\",\"cat\":\"helper\",\"ph\":\"X\",\"ts\":1,\"dur\":1,\
+///                        \"pid\":1,\"tid\":1,\"args\":{\"insn_ptr\":1,\"r0\":\"0x4\"}}"));
+/// assert!(json.contains("{\"name\":\"sqrt\",\"cat\":\"run\",\"ph\":\"X\",\"ts\":0,\"dur\":3,"));
+/// ```
+#[derive(Debug)]
+pub struct ChromeTraceWriter<W: Write> {
+    writer:     W,
+    error:      Option<io::Error>,
+    line_info:  Option<LineInfo>,
+    // Names of the tracks, the track with identifier `tid` at index `tid - 1`.
+    tracks:     Vec<String>,
+    program:    String,
+    // Track of the program being run, and timestamp of the start of its run, if one is running.
+    run:        Option<(usize, u64)>,
+    tail_calls: usize,
+    prev_insn:  Option<ebpf::Insn>,
+    ts:         u64,
+}
+
+impl<W: Write> ChromeTraceWriter<W> {
+    /// Create a tracer writing to `writer`. The first events are written immediately.
+    pub fn new(mut writer: W) -> ChromeTraceWriter<W> {
+        let error = write!(writer, "{{\"traceEvents\":[\n{{\"name\":\"process_name\",\"ph\":\"M\",\
+                                    \"pid\":1,\"args\":{{\"name\":\"rbpf\"}}}}").err();
+        ChromeTraceWriter {
+            writer,
+            error,
+            line_info:  None,
+            tracks:     vec![],
+            program:    "program".to_string(),
+            run:        None,
+            tail_calls: 0,
+            prev_insn:  None,
+            ts:         0,
+        }
+    }
+
+    /// Add the source location of each instruction, as found in `info`, to the arguments of its
+    /// event (`loc`).
+    pub fn with_line_info(mut self, info: LineInfo) -> ChromeTraceWriter<W> {
+        self.line_info = Some(info);
+        self
+    }
+
+    /// Record the following runs on the track of program `name` (`program` by default). Runs of
+    /// programs with the same name share the same track. Programs run by VMs with a name or tags
+    /// are recorded on a track named after them, see `EbpfVmMbuff::set_name()`.
+    pub fn set_program(&mut self, name: &str) {
+        self.program = name.to_string();
+    }
+
+    /// Write the end of the trace, flush the writer and return it, or return the first error that
+    /// occurred while writing the trace.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        writeln!(self.writer, "\n]}}")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    // Identifier of the track named `name`, writing its metadata event if it is new.
+    fn track(&mut self, name: &str) -> io::Result<usize> {
+        if let Some(i) = self.tracks.iter().position(|track| track == name) {
+            return Ok(i + 1);
+        }
+        self.tracks.push(name.to_string());
+        let tid = self.tracks.len();
+        write!(self.writer, ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\
+                            \"args\":{{\"name\":\"{}\"}}}}", tid, json_escape(name))?;
+        Ok(tid)
+    }
+
+    // Writes the span of the run on track `tid`, started at `start` and ending now.
+    fn end_run(&mut self, tid: usize, start: u64) -> io::Result<()> {
+        let name = json_escape(&self.tracks[tid - 1]);
+        write!(self.writer, ",\n{{\"name\":\"{}\",\"cat\":\"run\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\
+                            \"pid\":1,\"tid\":{}}}", name, start, self.ts - start, tid)
+    }
+
+    fn write_entry(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        // A tail call was taken if the next instruction is the first one of a program.
+        let tail_call = self.prev_insn.is_some_and(|prev| prev.opc == ebpf::CALL &&
+                                                   prev.imm as u32 == BPF_TAIL_CALL_IDX) &&
+                        entry.insn_ptr == 0;
+        let (tid, start) = match self.run {
+            Some((tid, start)) if tail_call => {
+                self.end_run(tid, start)?;
+                self.tail_calls += 1;
+                let name = format!("{} (tail call {})", self.program, self.tail_calls);
+                (self.track(&name)?, self.ts)
+            },
+            Some(run) => run,
+            None      => {
+                self.tail_calls = 0;
+                let name = self.program.clone();
+                (self.track(&name)?, self.ts)
+            },
+        };
+        self.run = Some((tid, start));
+
+        let insn = &entry.insn;
+        let (name, cat) = match insn.opc {
+            ebpf::CALL => (format!("call {:#x}", insn.imm), "helper"),
+            _          => (disassembler::to_insn_vec(&insn.to_array())[0].name.clone(), "insn"),
+        };
+        write!(self.writer, ",\n{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":1,\
+                            \"pid\":1,\"tid\":{},\"args\":{{\"insn_ptr\":{}",
+               json_escape(&name), cat, self.ts, tid, entry.insn_ptr)?;
+        for delta in &entry.reg_deltas {
+            write!(self.writer, ",\"r{}\":\"{:#x}\"", delta.reg, delta.new)?;
+        }
+        if let Some(loc) = self.line_info.as_ref().and_then(|info| info.lookup(entry.insn_ptr)) {
+            write!(self.writer, ",\"loc\":\"{}\"", json_escape(&loc.to_string()))?;
+        }
+        if let Some(func) = self.line_info.as_ref().and_then(|info| info.function(entry.insn_ptr)) {
+            write!(self.writer, ",\"function\":\"{}\"", json_escape(func))?;
+        }
+        write!(self.writer, "}}}}")?;
+        self.ts += 1;
+
+        self.prev_insn = Some(*insn);
+        if insn.opc == ebpf::EXIT {
+            self.run = None;
+            self.prev_insn = None;
+            self.end_run(tid, start)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Tracer for ChromeTraceWriter<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            if let Err(err) = self.write_entry(entry) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    fn start_program(&mut self, program: &ProgramMeta) {
+        self.set_program(&program.to_string());
+    }
+}
+
+fn read_exact_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0)              => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                            "truncated trace entry")),
+            Ok(n)              => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e)             => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Decode a trace written by a `BinaryWriter`.
+///
+/// # Examples
+///
+/// ```
+/// let prog = vec![
+///     0x72, 0x0a, 0xf8, 0xff, 0x2a, 0x00, 0x00, 0x00, // stb [r10-8], 0x2a
+///     0x71, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r10-8]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let vm = rbpf::EbpfVmNoData::new(&prog);
+/// let mut tracer = rbpf::trace::BinaryWriter::new(Vec::new());
+/// vm.prog_exec_trace(&mut tracer);
+///
+/// let bytes = tracer.finish().unwrap();
+/// let entries = rbpf::trace::read_binary(&bytes[..]).unwrap();
+/// assert_eq!(entries.len(), 3);
+/// assert_eq!(entries[0].mem_access.unwrap().kind, rbpf::trace::AccessKind::Store);
+/// assert_eq!(entries[1].mem_access.unwrap().value, 0x2a);
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the data does not start with the expected header, if it is truncated, or
+/// on I/O errors from `reader`.
+pub fn read_binary<R: Read>(mut reader: R) -> io::Result<Vec<TraceEntry>> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != BINARY_MAGIC || header[4] != BINARY_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace, or unsupported version"));
+    }
+
+    let mut entries = vec![];
+    let mut head = [0u8; 13];
+    while read_exact_or_eof(&mut reader, &mut head)? {
+        let insn_ptr = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let insn = ebpf::get_insn(&head[4..12], 0);
+
+        let mut reg_deltas = Vec::with_capacity(head[12] as usize);
+        for _ in 0..head[12] {
+            let reg = read_u8(&mut reader)?;
+            let old = read_u64(&mut reader)?;
+            let new = read_u64(&mut reader)?;
+            reg_deltas.push(RegDelta { reg, old, new });
+        }
+
+        let kind = match read_u8(&mut reader)? {
+            0 => None,
+            1 => Some(AccessKind::Load),
+            2 => Some(AccessKind::Store),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid memory access kind")),
+        };
+        let mem_access = match kind {
+            Some(kind) => {
+                let len = read_u8(&mut reader)? as usize;
+                let addr = read_u64(&mut reader)?;
+                let value = read_u64(&mut reader)?;
+                Some(MemAccess { kind, addr, len, value })
+            },
+            None       => None,
+        };
+
+        entries.push(TraceEntry { insn_ptr, insn, reg_deltas, mem_access });
+    }
+    Ok(entries)
+}
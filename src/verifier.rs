@@ -22,88 +22,256 @@
 //
 // Contrary to the verifier of the Linux kernel, this one does not modify the bytecode at all.
 
+//! This module checks eBPF programs before they are run.
+//!
+//! `check()` is the simple verifier run by the VMs when loading a program: it rejects malformed
+//! programs (unknown opcodes, invalid registers, jumps out of the program...), but does not
+//! follow the values of registers. The VMs do not run subprograms yet: it rejects BPF-to-BPF
+//! calls, whose stack usage can be checked with `check_stack_depth()`.
+//!
+//! Deployments can restrict further the instructions allowed in programs with an `InsnPolicy`,
+//! run after the simple verifier when attached to a VM.
+//!
+//! Before any verification, VMs check the size of programs and the estimated complexity of their
+//! verification against their `ProgLimits`, see `check_limits()`. `check_worst_case()` rejects
+//! programs that may execute more instructions than a budget.
+//!
+//! `check_bounds()` goes further, and tracks the possible values of registers along all paths of
+//! the program, in order to prove statically that memory accesses are within the bounds of the
+//! stack and of the context, or to report accesses certain to fail. `check_bounds_within()` runs
+//! it under a `VerifyBudget`, so that adversarial programs cannot hold the thread loading them.
+//!
+//! `report()` gathers the results of these checks and of the analyses of module `analysis` in a
+//! `VerifierReport`, which can be written as JSON, so that CI systems can gate the submission of
+//! programs on it.
 
+use analysis;
+use disassembler;
 use ebpf;
+use error::EbpfError;
 use std;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tnum::Tnum;
+use trace::json_escape;
 
-fn check_prog_len(prog: &std::vec::Vec<u8>) {
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+fn check_prog_len(prog: &std::vec::Vec<u8>) -> Result<(), String> {
     if prog.len() % ebpf::INSN_SIZE != 0 {
-        panic!("[Verifier] Error: eBPF program length must be a multiple of {:?} octets",
-               ebpf::INSN_SIZE);
+        return Err(format!("[Verifier] Error: eBPF program length must be a multiple of {:?} octets",
+                           ebpf::INSN_SIZE));
     }
     if prog.len() > ebpf::PROG_MAX_SIZE {
-        panic!("[Verifier] Error: eBPF program length limited to {:?}, here {:?}",
-               ebpf::PROG_MAX_INSNS, prog.len() / ebpf::INSN_SIZE);
+        return Err(format!("[Verifier] Error: eBPF program length limited to {:?}, here {:?}",
+                           ebpf::PROG_MAX_INSNS, prog.len() / ebpf::INSN_SIZE));
     }
 
     if prog.len() == 0 {
-        panic!("[Verifier] Error: program does not end with “EXIT” instruction");
+        return Err("[Verifier] Error: program does not end with “EXIT” instruction".to_string());
     }
     let last_insn = ebpf::get_insn(prog, (prog.len() / ebpf::INSN_SIZE) - 1);
     if last_insn.opc != ebpf::EXIT {
-        panic!("[Verifier] Error: program does not end with “EXIT” instruction");
+        return Err("[Verifier] Error: program does not end with “EXIT” instruction".to_string());
     }
+    Ok(())
 }
 
-fn check_imm_nonzero(insn: &ebpf::Insn, insn_ptr: usize) {
+fn check_imm_nonzero(insn: &ebpf::Insn, insn_ptr: usize) -> Result<(), String> {
     if insn.imm == 0 {
-        panic!("[Verifier] Error: division by 0 (insn #{:?})", insn_ptr);
+        return Err(format!("[Verifier] Error: division by 0 (insn #{:?})", insn_ptr));
     }
+    Ok(())
 }
 
-fn check_imm_endian(insn: &ebpf::Insn, insn_ptr: usize) {
+fn check_imm_endian(insn: &ebpf::Insn, insn_ptr: usize) -> Result<(), String> {
     match insn.imm {
-        16 | 32 | 64 => return,
-        _ => panic!("[Verifier] Error: unsupported argument for LE/BE (insn #{:?})", insn_ptr)
+        16 | 32 | 64 => Ok(()),
+        _ => Err(format!("[Verifier] Error: unsupported argument for LE/BE (insn #{:?})", insn_ptr))
     }
 }
 
-fn check_load_dw(prog: &std::vec::Vec<u8>, insn_ptr: usize) {
-    // We know we can reach next insn since we enforce an EXIT insn at the end of program, while
-    // this function should be called only for LD_DW insn, that cannot be last in program.
+fn check_load_dw(prog: &std::vec::Vec<u8>, insn_ptr: usize) -> Result<(), String> {
+    // The EXIT instruction at the end of the program should prevent LD_DW from being the last
+    // instruction, but this function does not rely on it.
+    if (insn_ptr + 2) * ebpf::INSN_SIZE > prog.len() {
+        return Err(format!("[Verifier] Error: incomplete LD_DW instruction (insn #{:?})",
+                           insn_ptr));
+    }
     let next_insn = ebpf::get_insn(prog, insn_ptr + 1);
     if next_insn.opc != 0 {
-        panic!("[Verifier] Error: incomplete LD_DW instruction (insn #{:?})", insn_ptr);
+        return Err(format!("[Verifier] Error: incomplete LD_DW instruction (insn #{:?})",
+                           insn_ptr));
+    }
+    // Only the immediate of the second half is used, the other fields must be zero.
+    if next_insn.dst != 0 || next_insn.src != 0 || next_insn.off != 0 {
+        return Err(format!("[Verifier] Error: invalid second half of LD_DW instruction (insn #{:?})",
+                           insn_ptr));
     }
+    // Map identifiers are 32-bit.
+    if ebpf::get_insn(prog, insn_ptr).src == ebpf::BPF_PSEUDO_MAP_FD && next_insn.imm != 0 {
+        return Err(format!("[Verifier] Error: invalid map reference in LD_DW instruction (insn #{:?})",
+                           insn_ptr));
+    }
+    // Callbacks are subprograms of the program.
+    let insn = ebpf::get_insn(prog, insn_ptr);
+    if insn.src == ebpf::BPF_PSEUDO_FUNC {
+        let target = insn_ptr as i64 + 1 + insn.imm as i64;
+        if next_insn.imm != 0 || target < 0 || target as usize >= prog.len() / ebpf::INSN_SIZE {
+            return Err(format!("[Verifier] Error: invalid callback reference in LD_DW instruction (insn #{:?})",
+                               insn_ptr));
+        }
+    }
+    Ok(())
+}
 
+// Marks the instructions that are the second half of a LD_DW instruction, in the order in which
+// they are decoded. An instruction with opcode 0 is not necessarily one of them.
+fn lddw_second_halves(prog: &[u8]) -> Vec<bool> {
+    let len = prog.len() / ebpf::INSN_SIZE;
+    let mut halves = vec![false; len];
+    let mut insn_ptr = 0;
+    while insn_ptr < len {
+        if ebpf::get_insn(prog, insn_ptr).opc == ebpf::LD_DW_IMM && insn_ptr + 1 < len {
+            halves[insn_ptr + 1] = true;
+            insn_ptr += 1;
+        }
+        insn_ptr += 1;
+    }
+    halves
 }
 
-fn check_jmp_offset(prog: &std::vec::Vec<u8>, insn_ptr: usize) {
+fn check_jmp_offset(prog: &std::vec::Vec<u8>, insn_ptr: usize, lddw_halves: &[bool])
+                    -> Result<(), String> {
     let insn = ebpf::get_insn(prog, insn_ptr);
-    if insn.off == -1 {
-        panic!("[Verifier] Error: infinite loop (insn #{:?})", insn_ptr);
+    // `gotol` stores its offset in the immediate.
+    let off = match insn.opc {
+        ebpf::JA32 => insn.imm as isize,
+        _          => insn.off as isize,
+    };
+    if off == -1 {
+        return Err(format!("[Verifier] Error: infinite loop (insn #{:?})", insn_ptr));
     }
 
-    let dst_insn_ptr = insn_ptr as isize + 1 + insn.off as isize;
+    let dst_insn_ptr = insn_ptr as isize + 1 + off;
     if dst_insn_ptr < 0 || dst_insn_ptr as usize >= (prog.len() / ebpf::INSN_SIZE) {
-        panic!("[Verifier] Error: jump out of code to #{:?} (insn #{:?})",
-               dst_insn_ptr, insn_ptr);
+        return Err(format!("[Verifier] Error: jump out of code to #{:?} (insn #{:?})",
+                           dst_insn_ptr, insn_ptr));
     }
 
-    let dst_insn = ebpf::get_insn(prog, dst_insn_ptr as usize);
-    if dst_insn.opc == 0 {
-        panic!("[Verifier] Error: jump to middle of LD_DW at #{:?} (insn #{:?})",
-               dst_insn_ptr, insn_ptr);
+    if lddw_halves[dst_insn_ptr as usize] {
+        return Err(format!("[Verifier] Error: jump to middle of LD_DW at #{:?} (insn #{:?})",
+                           dst_insn_ptr, insn_ptr));
     }
+    Ok(())
 }
 
-fn check_registers(insn: &ebpf::Insn, store: bool, insn_ptr: usize) {
+// Rejects the instructions that the interpreter and the JIT-compiler do not support: the legacy
+// packet loads, atomic adds, and the tail call opcode.
+fn unsupported(insn: &ebpf::Insn, insn_ptr: usize) -> Result<(), String> {
+    Err(format!("[Verifier] Error: unsupported eBPF opcode {:#2x} (insn #{:?})", insn.opc, insn_ptr))
+}
+
+fn check_isa(insn: &ebpf::Insn, insn_ptr: usize, isa: ebpf::IsaVersion,
+             required: ebpf::IsaVersion) -> Result<(), String> {
+    if isa < required {
+        return Err(format!("[Verifier] Error: opcode {:#04x} requires ISA {:?} (insn #{:?})",
+                           insn.opc, required, insn_ptr));
+    }
+    Ok(())
+}
+
+// Checks that the offset of an arithmetic instruction is zero, or one of `v4_offsets` selecting a
+// variant of the operation added in ISA v4.
+fn check_alu_offset(insn: &ebpf::Insn, insn_ptr: usize, isa: ebpf::IsaVersion,
+                    v4_offsets: &[i16]) -> Result<(), String> {
+    match insn.off {
+        0 => Ok(()),
+        off if v4_offsets.contains(&off) => check_isa(insn, insn_ptr, isa, ebpf::IsaVersion::V4),
+        _ => Err(format!("[Verifier] Error: invalid offset for arithmetic instruction (insn #{:?})",
+                         insn_ptr)),
+    }
+}
+
+fn check_registers(insn: &ebpf::Insn, store: bool, insn_ptr: usize) -> Result<(), String> {
     if insn.src > 10 {
-        panic!("[Verifier] Error: invalid source register (insn #{:?})", insn_ptr);
+        return Err(format!("[Verifier] Error: invalid source register (insn #{:?})", insn_ptr));
     }
 
     match (insn.dst, store) {
-        (0 ... 9, _) => {},
-        (10, true)   => {},
-        (10, false)  => panic!("[Verifier] Error: cannot write into register r10 (insn #{:?})",
-                               insn_ptr),
-        (_, _)       => panic!("[Verifier] Error: invalid destination register (insn #{:?})",
-                               insn_ptr)
+        (0 ... 9, _) => Ok(()),
+        (10, true)   => Ok(()),
+        (10, false)  => Err(format!("[Verifier] Error: cannot write into register r10 (insn #{:?})",
+                                    insn_ptr)),
+        (_, _)       => Err(format!("[Verifier] Error: invalid destination register (insn #{:?})",
+                                    insn_ptr))
     }
 }
 
+/// Run the simple verifier on a program.
+///
+/// # Panics
+///
+/// Panics with a message describing the first error found, if any. See `try_check()` for a
+/// version returning the error instead.
 pub fn check(prog: &std::vec::Vec<u8>) -> bool {
-    check_prog_len(prog);
+    if let Err(err) = try_check(prog) {
+        panic!("{}", err);
+    }
+    true
+}
+
+/// Run the simple verifier on a program, returning an `EbpfError::VerifierError` describing the
+/// first error found, if any. The program may use the instructions of the latest version of the
+/// instruction set, see `try_check_isa()`.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::error::EbpfError;
+/// use rbpf::verifier;
+///
+/// let prog = vec![
+///     0x37, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, 0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::try_check(&prog),
+///            Err(EbpfError::VerifierError("[Verifier] Error: division by 0 (insn #0)".to_string())));
+/// ```
+pub fn try_check(prog: &std::vec::Vec<u8>) -> Result<(), EbpfError> {
+    try_check_isa(prog, ebpf::IsaVersion::default())
+}
+
+/// Run the simple verifier on a program, only accepting the instructions available in version
+/// `isa` of the instruction set.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf::IsaVersion;
+/// use rbpf::verifier;
+///
+/// let prog = vec![
+///     0xa5, 0x01, 0x01, 0x00, 0x08, 0x00, 0x00, 0x00, // jlt r1, 8, +1
+///     0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert!(verifier::try_check_isa(&prog, IsaVersion::V2).is_ok());
+/// assert_eq!(verifier::try_check_isa(&prog, IsaVersion::V1).unwrap_err().to_string(),
+///            "[Verifier] Error: opcode 0xa5 requires ISA V2 (insn #0)");
+/// ```
+pub fn try_check_isa(prog: &std::vec::Vec<u8>, isa: ebpf::IsaVersion) -> Result<(), EbpfError> {
+    check_insns(prog, isa).map_err(EbpfError::VerifierError)
+}
+
+fn check_insns(prog: &std::vec::Vec<u8>, isa: ebpf::IsaVersion) -> Result<(), String> {
+    use ebpf::IsaVersion::{V1, V4};
+
+    check_prog_len(prog)?;
+    let lddw_halves = lddw_second_halves(prog);
 
     let mut insn_ptr:usize = 0;
     while insn_ptr * ebpf::INSN_SIZE < prog.len() {
@@ -113,25 +281,28 @@ pub fn check(prog: &std::vec::Vec<u8>) -> bool {
         match insn.opc {
 
             // BPF_LD class
-            ebpf::LD_ABS_B   => { unimplemented!(); },
-            ebpf::LD_ABS_H   => { unimplemented!(); },
-            ebpf::LD_ABS_W   => { unimplemented!(); },
-            ebpf::LD_ABS_DW  => { unimplemented!(); },
-            ebpf::LD_IND_B   => { unimplemented!(); },
-            ebpf::LD_IND_H   => { unimplemented!(); },
-            ebpf::LD_IND_W   => { unimplemented!(); },
-            ebpf::LD_IND_DW  => { unimplemented!(); },
+            ebpf::LD_ABS_B   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_ABS_H   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_ABS_W   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_ABS_DW  => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_IND_B   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_IND_H   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_IND_W   => { unsupported(&insn, insn_ptr)?; },
+            ebpf::LD_IND_DW  => { unsupported(&insn, insn_ptr)?; },
 
             // BPF_LDX class
             ebpf::LD_DW_IMM  => {
                 store = true;
-                check_load_dw(prog, insn_ptr);
+                check_load_dw(prog, insn_ptr)?;
                 insn_ptr += 1;
             },
             ebpf::LD_B_REG   => {},
             ebpf::LD_H_REG   => {},
             ebpf::LD_W_REG   => {},
             ebpf::LD_DW_REG  => {},
+            ebpf::LD_B_SX    => { check_isa(&insn, insn_ptr, isa, V4)?; },
+            ebpf::LD_H_SX    => { check_isa(&insn, insn_ptr, isa, V4)?; },
+            ebpf::LD_W_SX    => { check_isa(&insn, insn_ptr, isa, V4)?; },
 
             // BPF_ST class
             ebpf::ST_B_IMM   => store = true,
@@ -144,8 +315,8 @@ pub fn check(prog: &std::vec::Vec<u8>) -> bool {
             ebpf::ST_H_REG   => store = true,
             ebpf::ST_W_REG   => store = true,
             ebpf::ST_DW_REG  => store = true,
-            ebpf::ST_W_XADD  => { unimplemented!(); },
-            ebpf::ST_DW_XADD => { unimplemented!(); },
+            ebpf::ST_W_XADD  => { unsupported(&insn, insn_ptr)?; },
+            ebpf::ST_DW_XADD => { unsupported(&insn, insn_ptr)?; },
 
             // BPF_ALU class
             ebpf::ADD32_IMM  => {},
@@ -154,8 +325,11 @@ pub fn check(prog: &std::vec::Vec<u8>) -> bool {
             ebpf::SUB32_REG  => {},
             ebpf::MUL32_IMM  => {},
             ebpf::MUL32_REG  => {},
-            ebpf::DIV32_IMM  => { check_imm_nonzero(&insn, insn_ptr); },
-            ebpf::DIV32_REG  => {},
+            ebpf::DIV32_IMM  => {
+                check_imm_nonzero(&insn, insn_ptr)?;
+                check_alu_offset(&insn, insn_ptr, isa, &[1])?;
+            },
+            ebpf::DIV32_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[1])?; },
             ebpf::OR32_IMM   => {},
             ebpf::OR32_REG   => {},
             ebpf::AND32_IMM  => {},
@@ -165,26 +339,32 @@ pub fn check(prog: &std::vec::Vec<u8>) -> bool {
             ebpf::RSH32_IMM  => {},
             ebpf::RSH32_REG  => {},
             ebpf::NEG32      => {},
-            ebpf::MOD32_IMM  => { check_imm_nonzero(&insn, insn_ptr); },
-            ebpf::MOD32_REG  => {},
+            ebpf::MOD32_IMM  => {
+                check_imm_nonzero(&insn, insn_ptr)?;
+                check_alu_offset(&insn, insn_ptr, isa, &[1])?;
+            },
+            ebpf::MOD32_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[1])?; },
             ebpf::XOR32_IMM  => {},
             ebpf::XOR32_REG  => {},
             ebpf::MOV32_IMM  => {},
-            ebpf::MOV32_REG  => {},
+            ebpf::MOV32_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[8, 16])?; },
             ebpf::ARSH32_IMM => {},
             ebpf::ARSH32_REG => {},
-            ebpf::LE         => { check_imm_endian(&insn, insn_ptr); },
-            ebpf::BE         => { check_imm_endian(&insn, insn_ptr); },
+            ebpf::LE         => { check_imm_endian(&insn, insn_ptr)?; },
+            ebpf::BE         => { check_imm_endian(&insn, insn_ptr)?; },
 
             // BPF_ALU64 class
             ebpf::ADD64_IMM  => {},
             ebpf::ADD64_REG  => {},
             ebpf::SUB64_IMM  => {},
             ebpf::SUB64_REG  => {},
-            ebpf::MUL64_IMM  => { check_imm_nonzero(&insn, insn_ptr); },
+            ebpf::MUL64_IMM  => { check_imm_nonzero(&insn, insn_ptr)?; },
             ebpf::MUL64_REG  => {},
-            ebpf::DIV64_IMM  => { check_imm_nonzero(&insn, insn_ptr); },
-            ebpf::DIV64_REG  => {},
+            ebpf::DIV64_IMM  => {
+                check_imm_nonzero(&insn, insn_ptr)?;
+                check_alu_offset(&insn, insn_ptr, isa, &[1])?;
+            },
+            ebpf::DIV64_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[1])?; },
             ebpf::OR64_IMM   => {},
             ebpf::OR64_REG   => {},
             ebpf::AND64_IMM  => {},
@@ -194,50 +374,1726 @@ pub fn check(prog: &std::vec::Vec<u8>) -> bool {
             ebpf::RSH64_IMM  => {},
             ebpf::RSH64_REG  => {},
             ebpf::NEG64      => {},
-            ebpf::MOD64_IMM  => {},
-            ebpf::MOD64_REG  => {},
+            ebpf::MOD64_IMM  => {
+                check_imm_nonzero(&insn, insn_ptr)?;
+                check_alu_offset(&insn, insn_ptr, isa, &[1])?;
+            },
+            ebpf::MOD64_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[1])?; },
             ebpf::XOR64_IMM  => {},
             ebpf::XOR64_REG  => {},
             ebpf::MOV64_IMM  => {},
-            ebpf::MOV64_REG  => {},
+            ebpf::MOV64_REG  => { check_alu_offset(&insn, insn_ptr, isa, &[8, 16, 32])?; },
             ebpf::ARSH64_IMM => {},
             ebpf::ARSH64_REG => {},
+            ebpf::BSWAP      => {
+                check_isa(&insn, insn_ptr, isa, V4)?;
+                check_imm_endian(&insn, insn_ptr)?;
+            },
 
-            // BPF_JMP class
-            ebpf::JA         => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JEQ_IMM    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JEQ_REG    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JGT_IMM    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JGT_REG    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JGE_IMM    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JGE_REG    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSET_IMM   => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSET_REG   => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JNE_IMM    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JNE_REG    => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSGT_IMM   => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSGT_REG   => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSGE_IMM   => { check_jmp_offset(prog, insn_ptr); },
-            ebpf::JSGE_REG   => { check_jmp_offset(prog, insn_ptr); },
+            // BPF_JMP and BPF_JMP32 classes
+            // The VMs do not run subprograms yet: BPF-to-BPF calls are rejected, rather than
+            // run as calls to helpers.
+            ebpf::CALL if insn.src != 0 => {
+                return Err(format!("[Verifier] Error: BPF-to-BPF calls are not supported (insn #{:?})",
+                                   insn_ptr));
+            },
             ebpf::CALL       => {},
-            ebpf::TAIL_CALL  => { unimplemented!() },
+            ebpf::TAIL_CALL  => { unsupported(&insn, insn_ptr)?; },
             ebpf::EXIT       => {},
+            _ if ebpf::is_jump(insn.opc) => {
+                // Some jumps were added in later versions of the ISA, see `ebpf::OPCODES`.
+                let required = ebpf::opcode_info(insn.opc).map_or(V1, |info| info.isa);
+                check_isa(&insn, insn_ptr, isa, required)?;
+                check_jmp_offset(prog, insn_ptr, &lddw_halves)?;
+            },
 
             _                => {
-                panic!("[Verifier] Error: unknown eBPF opcode {:#2x} (insn #{:?})",
-                       insn.opc, insn_ptr);
+                return Err(format!("[Verifier] Error: unknown eBPF opcode {:#2x} (insn #{:?})",
+                       insn.opc, insn_ptr));
             },
         }
 
-        check_registers(&insn, store, insn_ptr);
+        check_registers(&insn, store, insn_ptr)?;
 
         insn_ptr += 1;
     }
 
     // insn_ptr should now be equal to number of instructions.
     if insn_ptr != prog.len() / ebpf::INSN_SIZE {
-        panic!("[Verifier] Error: jumped out of code to #{:?}", insn_ptr);
+        return Err(format!("[Verifier] Error: jumped out of code to #{:?}", insn_ptr));
     }
 
-    true
+    Ok(())
+}
+
+/// Check that the maps referenced by a program with `lddw` instructions (see
+/// `ebpf::BPF_PSEUDO_MAP_FD` and `ebpf::BPF_PSEUDO_MAP_VALUE`) are among the `map_count` maps registered on the VM, with
+/// identifiers 0 to `map_count - 1`. The JIT-compiler runs this check, while the interpreter
+/// reports references to unknown maps when it executes them.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier;
+///
+/// let prog = vec![
+///     0x18, 0x11, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // lddw r1, map 1
+///     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert!(verifier::check_maps(&prog, 2).is_ok());
+/// assert_eq!(verifier::check_maps(&prog, 1).unwrap_err().to_string(),
+///            "[Verifier] Error: reference to unknown map 1 (insn #0)");
+/// ```
+pub fn check_maps(prog: &[u8], map_count: usize) -> Result<(), EbpfError> {
+    let unknown = ebpf::InsnIter::new(prog).enumerate().find(|(_, insn)| {
+        insn.opc == ebpf::LD_DW_IMM &&
+            (insn.src == ebpf::BPF_PSEUDO_MAP_FD || insn.src == ebpf::BPF_PSEUDO_MAP_VALUE) &&
+            insn.imm as u32 as usize >= map_count
+    });
+    match unknown {
+        Some((insn_ptr, insn)) => Err(EbpfError::VerifierError(format!(
+            "[Verifier] Error: reference to unknown map {} (insn #{:?})", insn.imm as u32, insn_ptr))),
+        None => Ok(()),
+    }
+}
+
+// Program limits
+
+/// Limits on the size and complexity of the programs loaded into a VM, see
+/// `EbpfVmMbuff::set_prog_limits()`. They are checked before the verifier runs, so that
+/// pathological programs are rejected before their analysis consumes too much time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgLimits {
+    /// Maximum number of instructions of a program, at most `ebpf::PROG_MAX_INSNS`.
+    pub max_insns:      usize,
+    /// Maximum estimated complexity of the verification of a program, see `complexity()`.
+    pub max_complexity: usize,
+}
+
+impl Default for ProgLimits {
+    fn default() -> ProgLimits {
+        ProgLimits {
+            max_insns:      ebpf::PROG_MAX_INSNS,
+            max_complexity: ebpf::MAX_COMPLEXITY,
+        }
+    }
+}
+
+/// Estimate the complexity of the verification of `prog`: the number of instructions the
+/// analyses of the verifier may process. Each backward jump closes a loop, which the analyses may
+/// follow until a fixed point is reached, so the estimate is the number of instructions, times
+/// the number of backward jumps plus one. Programs without loops are processed once.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier;
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r0, 10
+///     0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r0, 1
+///     0x55, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r0, 0, -2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::complexity(&prog), 8);
+/// ```
+pub fn complexity(prog: &[u8]) -> usize {
+    let insn_count = prog.len() / ebpf::INSN_SIZE;
+    let back_jumps = (0..insn_count).map(|ptr| ebpf::get_insn(prog, ptr))
+        .filter(|insn| ebpf::is_jump(insn.opc) && match insn.opc {
+            ebpf::JA32 => insn.imm < 0,
+            _          => insn.off < 0,
+        })
+        .count();
+    insn_count.saturating_mul(back_jumps + 1)
+}
+
+/// Check `prog` against `limits`: its number of instructions, then the estimated complexity of
+/// its verification. This does not verify the program.
+pub fn check_limits(prog: &[u8], limits: &ProgLimits) -> Result<(), EbpfError> {
+    let insn_count = prog.len() / ebpf::INSN_SIZE;
+    if insn_count > limits.max_insns {
+        return Err(EbpfError::VerifierError(format!(
+            "[Verifier] Error: program of {} instructions exceeds the limit of {} instructions",
+            insn_count, limits.max_insns)));
+    }
+    let complexity = complexity(prog);
+    if complexity > limits.max_complexity {
+        return Err(EbpfError::VerifierError(format!(
+            "[Verifier] Error: estimated verification complexity of {} exceeds the limit of {}",
+            complexity, limits.max_complexity)));
+    }
+    Ok(())
+}
+
+/// Check that `prog` executes at most `budget` instructions on any input, and return the bound
+/// found by `analysis::worst_case()`, with the bounds of its loops given in `loop_bounds`.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier;
+/// use std::collections::HashMap;
+///
+/// let prog = vec![
+///     0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+///     0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+///     0xa5, 0x01, 0xfe, 0xff, 0x64, 0x00, 0x00, 0x00, // jlt r1, 100, -2
+///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::check_worst_case(&prog, &HashMap::new(), 500), Ok(203));
+/// assert_eq!(verifier::check_worst_case(&prog, &HashMap::new(), 200).unwrap_err().to_string(),
+///            "[Verifier] Error: program may execute 203 instructions, more than the budget of 200");
+/// ```
+pub fn check_worst_case(prog: &[u8], loop_bounds: &HashMap<usize, u64>, budget: u64)
+                        -> Result<u64, EbpfError> {
+    let worst = analysis::worst_case(prog, loop_bounds)
+        .map_err(|err| EbpfError::VerifierError(format!("[Verifier] Error: {}", err)))?;
+    if worst.insns > budget {
+        return Err(EbpfError::VerifierError(format!(
+            "[Verifier] Error: program may execute {} instructions, more than the budget of {}",
+            worst.insns, budget)));
+    }
+    Ok(worst.insns)
+}
+
+// Instruction policies
+
+/// A policy restricting the instructions a program may use, beyond the checks of the simple
+/// verifier. The policy receives each instruction of the program, and may reject it: this can be
+/// used to enforce a profile for a given deployment, for example forbidding calls to helpers, or
+/// stores to memory. A policy is attached to a VM with `set_insn_policy()`.
+///
+/// Closures taking an instruction and its index are policies too.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf;
+/// use rbpf::verifier::{self, InsnPolicy};
+///
+/// // Forbids jumps backwards.
+/// let no_loops = |insn: &ebpf::Insn, _insn_ptr: usize| {
+///     match insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP && insn.off < 0 {
+///         true  => Err("backward jump denied by policy".to_string()),
+///         false => Ok(()),
+///     }
+/// };
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+///     0x05, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // ja -2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::check_policy(&prog, &no_loops).unwrap_err(),
+///            "[Verifier] Error: backward jump denied by policy (insn #1)");
+/// ```
+pub trait InsnPolicy {
+    /// Check instruction `insn`, at index `insn_ptr` in the program. Return an error with the
+    /// reason for rejecting it, if it is not allowed. For `lddw`, only the first half of the
+    /// instruction is checked.
+    fn check_insn(&self, insn: &ebpf::Insn, insn_ptr: usize) -> Result<(), String>;
+}
+
+impl<F: Fn(&ebpf::Insn, usize) -> Result<(), String>> InsnPolicy for F {
+    fn check_insn(&self, insn: &ebpf::Insn, insn_ptr: usize) -> Result<(), String> {
+        self(insn, insn_ptr)
+    }
+}
+
+/// An `InsnPolicy` rejecting a list of opcodes, or of instruction classes.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::ebpf;
+/// use rbpf::verifier::{self, DenyList};
+///
+/// // No helpers, no stores, no 64-bit division.
+/// let policy = DenyList::new()
+///     .deny_opcode(ebpf::CALL)
+///     .deny_class(ebpf::BPF_ST)
+///     .deny_class(ebpf::BPF_STX)
+///     .deny_opcode(ebpf::DIV64_IMM)
+///     .deny_opcode(ebpf::DIV64_REG);
+///
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+///     0x7b, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::check_policy(&prog, &policy).unwrap_err(),
+///            "[Verifier] Error: instruction class 0x03 denied by policy (insn #1)");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DenyList {
+    opcodes: Vec<u8>,
+    classes: Vec<u8>,
+}
+
+impl DenyList {
+    /// Create a policy allowing all instructions.
+    pub fn new() -> DenyList {
+        DenyList::default()
+    }
+
+    /// Reject instructions with opcode `opc`, for example `ebpf::CALL`.
+    pub fn deny_opcode(mut self, opc: u8) -> DenyList {
+        self.opcodes.push(opc);
+        self
+    }
+
+    /// Reject all instructions of class `class`, for example `ebpf::BPF_ALU64`.
+    pub fn deny_class(mut self, class: u8) -> DenyList {
+        self.classes.push(class & ebpf::BPF_CLS_MASK);
+        self
+    }
+}
+
+impl InsnPolicy for DenyList {
+    fn check_insn(&self, insn: &ebpf::Insn, _insn_ptr: usize) -> Result<(), String> {
+        let class = insn.opc & ebpf::BPF_CLS_MASK;
+        if self.classes.contains(&class) {
+            return Err(format!("instruction class {:#04x} denied by policy", class));
+        }
+        if self.opcodes.contains(&insn.opc) {
+            return Err(format!("opcode {:#04x} denied by policy", insn.opc));
+        }
+        Ok(())
+    }
+}
+
+/// An instruction policy allowing calls to the helpers of a list only, BPF-to-BPF calls being
+/// allowed. Unlike a helper hook (see `helpers::HelperHook`), the policy is checked when loading
+/// programs, and so applies to JIT-compiled programs as well.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::helpers;
+/// use rbpf::verifier::{self, HelperAllowList};
+///
+/// let policy = HelperAllowList::new().allow(helpers::BPF_KTIME_GET_NS_IDX);
+///
+/// let prog = vec![
+///     0x85, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // call bpf_ktime_getns
+///     0x85, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // call bpf_trace_printk
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::check_policy(&prog, &policy).unwrap_err(),
+///            "[Verifier] Error: call to helper function 0x6 denied by policy (insn #1)");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HelperAllowList {
+    keys: Vec<u32>,
+}
+
+impl HelperAllowList {
+    /// Create a policy allowing no helpers.
+    pub fn new() -> HelperAllowList {
+        HelperAllowList::default()
+    }
+
+    /// Allow calls to the helper of key `key`.
+    pub fn allow(mut self, key: u32) -> HelperAllowList {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// The keys of the helpers allowed, in the order they were allowed.
+    pub fn keys(&self) -> &[u32] {
+        &self.keys
+    }
+}
+
+impl InsnPolicy for HelperAllowList {
+    fn check_insn(&self, insn: &ebpf::Insn, _insn_ptr: usize) -> Result<(), String> {
+        let key = insn.imm as u32;
+        match insn.opc == ebpf::CALL && insn.src != ebpf::BPF_PSEUDO_CALL && !self.keys.contains(&key) {
+            true  => Err(format!("call to helper function {:#x} denied by policy", key)),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Check all instructions of a program against `policy`, in the order of the program.
+///
+/// # Errors
+///
+/// Returns an error message with the reason given by the policy for the first instruction it
+/// rejects.
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn check_policy(prog: &[u8], policy: &dyn InsnPolicy) -> Result<(), String> {
+    for hl_insn in disassembler::to_insn_vec(prog) {
+        let insn = ebpf::get_insn(prog, hl_insn.ptr);
+        if let Err(reason) = policy.check_insn(&insn, hl_insn.ptr) {
+            return Err(format!("[Verifier] Error: {} (insn #{:?})", reason, hl_insn.ptr));
+        }
+    }
+    Ok(())
+}
+
+// Bounds checking
+//
+// `check_bounds()` interprets the program on abstract values: for each register, and for each
+// 8-byte slot of the stack, it tracks whether it holds a scalar, a pointer to the stack or to the
+// context (with an offset), or nothing readable yet. Scalars, and offsets of pointers, are tracked
+// as a tristate number (which bits are known) together with unsigned and signed min/max bounds,
+// each of these refining the others. Conditional jumps refine the bounds of their operands on
+// each branch. The states at the entry of basic blocks are merged until a fixed point is reached;
+// bounds that keep changing in a loop are widened, so that this ends.
+//
+// The semantics modelled are those of the interpreter.
+
+/// Memory region accessed by a load or a store, as determined by `check_bounds()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Region {
+    /// The stack of the program, through a pointer derived from R10.
+    Stack,
+    /// The context of the program, through a pointer derived from the initial value of R1.
+    Ctx,
+    /// Any other memory area, or a region the analysis could not determine: for example, through
+    /// a pointer loaded from memory or returned by a helper.
+    Unknown,
+}
+
+/// The outcome of the analysis of one load or store instruction by `check_bounds()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemCheck {
+    /// Index of the instruction.
+    pub insn_ptr:   usize,
+    /// Region accessed.
+    pub region:     Region,
+    /// Number of bytes accessed: 1, 2, 4 or 8.
+    pub size:       usize,
+    /// `true` for a store, `false` for a load.
+    pub write:      bool,
+    /// Lowest possible offset of the access, relative to the start of the context, or to the
+    /// frame pointer R10 for the stack. 0 if the region is unknown.
+    pub min_offset: i64,
+    /// Highest possible offset of the access. 0 if the region is unknown.
+    pub max_offset: i64,
+    /// `true` if the access is in the bounds of its region for all possible offsets.
+    pub proven:     bool,
+}
+
+/// The result of `check_bounds()`: the analysis of all reachable load and store instructions of a
+/// program, in the order of the program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BoundsReport {
+    /// Memory accesses of the program. Unreachable instructions are not listed.
+    pub accesses: Vec<MemCheck>,
+}
+
+impl BoundsReport {
+    /// Return `true` if all memory accesses of the program were proven in bounds, so that the
+    /// program cannot make out-of-bounds accesses when run with a context of the size given to
+    /// `check_bounds()`.
+    pub fn all_proven(&self) -> bool {
+        self.accesses.iter().all(|access| access.proven)
+    }
+
+    /// Return the analysis of the access made by instruction `insn_ptr`, if it is a reachable
+    /// load or store.
+    pub fn get(&self, insn_ptr: usize) -> Option<&MemCheck> {
+        self.accesses.iter().find(|access| access.insn_ptr == insn_ptr)
+    }
+}
+
+const SIGN_BIT: u64 = 1 << 63;
+const STACK_SLOTS: usize = ebpf::STACK_SIZE / 8;
+// Number of times the entry state of a block may change before its bounds are widened.
+const WIDEN_AFTER: usize = 4;
+// Number of passes recomputing the states after widening, to recover some of the lost precision.
+const NARROW_PASSES: usize = 2;
+
+// A set of 64-bit integers: all integers matching `var_off`, within both unsigned and signed
+// bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Scalar {
+    var_off: Tnum,
+    umin:    u64,
+    umax:    u64,
+    smin:    i64,
+    smax:    i64,
+}
+
+impl Scalar {
+
+    fn constant(v: u64) -> Scalar {
+        Scalar { var_off: Tnum::constant(v), umin: v, umax: v, smin: v as i64, smax: v as i64 }
+    }
+
+    fn unknown() -> Scalar {
+        Scalar { var_off: Tnum::unknown(), umin: 0, umax: u64::MAX, smin: i64::MIN, smax: i64::MAX }
+    }
+
+    fn from_tnum(var_off: Tnum) -> Scalar {
+        Scalar { var_off, ..Scalar::unknown() }.finish()
+    }
+
+    fn from_unsigned(umin: u64, umax: u64) -> Scalar {
+        Scalar { umin, umax, ..Scalar::unknown() }.finish()
+    }
+
+    fn as_const(&self) -> Option<u64> {
+        match self.var_off.is_const() {
+            true  => Some(self.var_off.value),
+            false => None,
+        }
+    }
+
+    // Make each of the tnum, unsigned and signed bounds as precise as the others allow. Returns
+    // `None` if the set is empty.
+    fn sync(mut self) -> Option<Scalar> {
+        for _ in 0..2 {
+            let t = self.var_off;
+            self.umin = self.umin.max(t.min());
+            self.umax = self.umax.min(t.max());
+            self.smin = self.smin.max((t.value | (t.mask & SIGN_BIT)) as i64);
+            self.smax = self.smax.min((t.value | (t.mask & !SIGN_BIT)) as i64);
+
+            // When all values have the same sign bit, unsigned and signed orders agree.
+            let half = if self.smin >= 0 || self.umax < SIGN_BIT {
+                Some((0, i64::MAX))
+            } else if self.smax < 0 || self.umin >= SIGN_BIT {
+                Some((i64::MIN, -1))
+            } else {
+                None
+            };
+            if let Some((lo, hi)) = half {
+                let lo = (self.smin.max(lo) as u64).max(self.umin);
+                let hi = (self.smax.min(hi) as u64).min(self.umax);
+                self.umin = lo;
+                self.umax = hi;
+                self.smin = lo as i64;
+                self.smax = hi as i64;
+            }
+
+            if self.umin > self.umax || self.smin > self.smax {
+                return None;
+            }
+            self.var_off = t.intersect(Tnum::range(self.umin, self.umax))?;
+        }
+        Some(self)
+    }
+
+    // For results of operations, which are never empty.
+    fn finish(self) -> Scalar {
+        self.sync().unwrap_or_else(Scalar::unknown)
+    }
+
+    fn meet(self, other: Scalar) -> Option<Scalar> {
+        Scalar {
+            var_off: self.var_off.intersect(other.var_off)?,
+            umin:    self.umin.max(other.umin),
+            umax:    self.umax.min(other.umax),
+            smin:    self.smin.max(other.smin),
+            smax:    self.smax.min(other.smax),
+        }.sync()
+    }
+
+    fn join(self, other: Scalar) -> Scalar {
+        Scalar {
+            var_off: self.var_off.union(other.var_off),
+            umin:    self.umin.min(other.umin),
+            umax:    self.umax.max(other.umax),
+            smin:    self.smin.min(other.smin),
+            smax:    self.smax.max(other.smax),
+        }.finish()
+    }
+
+    fn add(self, other: Scalar) -> Scalar {
+        let (umin, umax) = match (self.umin.checked_add(other.umin), self.umax.checked_add(other.umax)) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _                    => (0, u64::MAX),
+        };
+        let (smin, smax) = match (self.smin.checked_add(other.smin), self.smax.checked_add(other.smax)) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _                    => (i64::MIN, i64::MAX),
+        };
+        Scalar { var_off: self.var_off.add(other.var_off), umin, umax, smin, smax }.finish()
+    }
+
+    fn sub(self, other: Scalar) -> Scalar {
+        let (umin, umax) = match self.umin >= other.umax {
+            true  => (self.umin - other.umax, self.umax - other.umin),
+            false => (0, u64::MAX),
+        };
+        let (smin, smax) = match (self.smin.checked_sub(other.smax), self.smax.checked_sub(other.smin)) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _                    => (i64::MIN, i64::MAX),
+        };
+        Scalar { var_off: self.var_off.sub(other.var_off), umin, umax, smin, smax }.finish()
+    }
+
+    fn mul(self, other: Scalar) -> Scalar {
+        let var_off = self.var_off.mul(other.var_off);
+        match self.umax <= u32::MAX as u64 && other.umax <= u32::MAX as u64 {
+            true  => Scalar {
+                var_off,
+                umin: self.umin * other.umin,
+                umax: self.umax * other.umax,
+                ..Scalar::unknown()
+            }.finish(),
+            false => Scalar::from_tnum(var_off),
+        }
+    }
+
+    fn div(self, other: Scalar) -> Scalar {
+        match other.umin {
+            0 => Scalar::unknown(),
+            _ => Scalar::from_unsigned(self.umin / other.umax, self.umax / other.umin),
+        }
+    }
+
+    fn modulo(self, other: Scalar) -> Scalar {
+        match other.umin {
+            0 => Scalar::unknown(),
+            _ => Scalar::from_unsigned(0, self.umax.min(other.umax - 1)),
+        }
+    }
+
+    fn and(self, other: Scalar) -> Scalar {
+        let var_off = self.var_off.and(other.var_off);
+        Scalar { var_off, umin: 0, umax: self.umax.min(other.umax), ..Scalar::unknown() }.finish()
+    }
+
+    fn or(self, other: Scalar) -> Scalar {
+        let var_off = self.var_off.or(other.var_off);
+        Scalar { var_off, umin: self.umin.max(other.umin), ..Scalar::unknown() }.finish()
+    }
+
+    fn xor(self, other: Scalar) -> Scalar {
+        Scalar::from_tnum(self.var_off.xor(other.var_off))
+    }
+
+    fn lsh(self, shift: u32) -> Scalar {
+        let var_off = self.var_off.lshift(shift);
+        match self.umax <= u64::MAX >> shift {
+            true  => Scalar {
+                var_off,
+                umin: self.umin << shift,
+                umax: self.umax << shift,
+                ..Scalar::unknown()
+            }.finish(),
+            false => Scalar::from_tnum(var_off),
+        }
+    }
+
+    fn rsh(self, shift: u32) -> Scalar {
+        Scalar {
+            var_off: self.var_off.rshift(shift),
+            umin:    self.umin >> shift,
+            umax:    self.umax >> shift,
+            ..Scalar::unknown()
+        }.finish()
+    }
+
+    fn arsh(self, shift: u32) -> Scalar {
+        Scalar {
+            var_off: self.var_off.arshift(shift),
+            smin:    self.smin >> shift,
+            smax:    self.smax >> shift,
+            ..Scalar::unknown()
+        }.finish()
+    }
+
+    fn neg(self) -> Scalar {
+        match self.smin {
+            i64::MIN => Scalar::unknown(),
+            _        => Scalar { smin: -self.smax, smax: -self.smin, ..Scalar::unknown() }.finish(),
+        }
+    }
+
+    // Keep the `size` lowest bytes (zero extension).
+    fn cast(self, size: usize) -> Scalar {
+        let max = match size {
+            8 => return self,
+            _ => (1u64 << (size * 8)) - 1,
+        };
+        match self.umax <= max {
+            true  => self,
+            false => Scalar { var_off: self.var_off.cast(size), umax: max, ..Scalar::unknown() }.finish(),
+        }
+    }
+
+    // Sign-extend the 32 lowest bits, for a value whose upper bits are cleared.
+    fn sext32(self) -> Scalar {
+        const HIGH: u64 = 0xffff_ffff_0000_0000;
+        if self.umax < 1 << 31 {
+            self
+        } else if self.umin >= 1 << 31 {
+            Scalar {
+                var_off: Tnum { value: self.var_off.value | HIGH, mask: self.var_off.mask },
+                umin:    self.umin | HIGH,
+                umax:    self.umax | HIGH,
+                ..Scalar::unknown()
+            }.finish()
+        } else {
+            let var_off = match self.var_off.mask & (1 << 31) {
+                0 => Tnum { value: self.var_off.value | HIGH, mask: self.var_off.mask },
+                _ => Tnum { value: self.var_off.value, mask: self.var_off.mask | HIGH },
+            };
+            Scalar { var_off, smin: i32::MIN as i64, smax: i32::MAX as i64, ..Scalar::unknown() }.finish()
+        }
+    }
+
+    // For values that keep changing in a loop: bounds that grow are pushed to their extremes.
+    // Tnums can only lose known bits a finite number of times, they need no widening.
+    fn widen(self, next: Scalar) -> Scalar {
+        Scalar {
+            var_off: self.var_off.union(next.var_off),
+            umin:    if next.umin < self.umin { 0 } else { self.umin },
+            umax:    if next.umax > self.umax { u64::MAX } else { self.umax },
+            smin:    if next.smin < self.smin { i64::MIN } else { self.smin },
+            smax:    if next.smax > self.smax { i64::MAX } else { self.smax },
+        }.finish()
+    }
+}
+
+// The abstract value of a register or of a stack slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    // Not initialized on at least one path.
+    Uninit,
+    Scalar(Scalar),
+    // A pointer to the stack (`Region::Stack`, offset from R10) or the context (`Region::Ctx`,
+    // offset from its start).
+    Ptr(Region, Scalar),
+}
+
+impl Value {
+    fn join(self, other: Value) -> Value {
+        match (self, other) {
+            (Value::Uninit, _) | (_, Value::Uninit)  => Value::Uninit,
+            (Value::Scalar(a), Value::Scalar(b))     => Value::Scalar(a.join(b)),
+            (Value::Ptr(r, a), Value::Ptr(q, b)) if r == q => Value::Ptr(r, a.join(b)),
+            _                                        => Value::Scalar(Scalar::unknown()),
+        }
+    }
+
+    fn widen(self, next: Value) -> Value {
+        match (self, next) {
+            (Value::Scalar(a), Value::Scalar(b))           => Value::Scalar(a.widen(b)),
+            (Value::Ptr(r, a), Value::Ptr(q, b)) if r == q => Value::Ptr(r, a.widen(b)),
+            _                                              => next,
+        }
+    }
+
+    fn is_stack_ptr(&self) -> bool {
+        matches!(*self, Value::Ptr(Region::Stack, _))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    regs:          [Value; 11],
+    // Values spilled to the 8-byte slots of the stack, from R10 - 512 to R10.
+    stack:         [Value; STACK_SLOTS],
+    // Set when the address of the stack may have been stored out of the registers and of the
+    // stack, or given to a helper: the stack may then be written through other pointers.
+    stack_escaped: bool,
+}
+
+impl State {
+    fn initial() -> State {
+        let mut regs = [Value::Uninit; 11];
+        regs[1] = Value::Ptr(Region::Ctx, Scalar::constant(0));
+        regs[10] = Value::Ptr(Region::Stack, Scalar::constant(0));
+        State {
+            regs,
+            stack:         [Value::Scalar(Scalar::unknown()); STACK_SLOTS],
+            stack_escaped: false,
+        }
+    }
+
+    // The state at the entry of a subprogram: its arguments in R1 to R5 may be anything, including
+    // pointers to the stack of its caller.
+    fn subprogram() -> State {
+        let mut state = State::initial();
+        for reg in state.regs[1..6].iter_mut() {
+            *reg = Value::Scalar(Scalar::unknown());
+        }
+        state
+    }
+
+    // Merge with the state of another path. With `widen`, values that changed are widened.
+    fn merge(&self, other: &State, widen: bool) -> State {
+        let mut merged = self.clone();
+        let values = merged.regs.iter_mut().zip(other.regs.iter())
+            .chain(merged.stack.iter_mut().zip(other.stack.iter()));
+        for (m, o) in values {
+            let joined = m.join(*o);
+            if m.is_stack_ptr() && !joined.is_stack_ptr() || o.is_stack_ptr() && !joined.is_stack_ptr() {
+                merged.stack_escaped = true;
+            }
+            *m = match widen {
+                true  => m.widen(joined),
+                false => joined,
+            };
+        }
+        merged.stack_escaped |= other.stack_escaped;
+        merged
+    }
+
+    fn clobber_stack(&mut self) {
+        for slot in self.stack.iter_mut() {
+            *slot = Value::Scalar(Scalar::unknown());
+        }
+    }
+}
+
+// Collects the results of the analysis; the states computed while looking for the fixed point are
+// not final, so the results are only kept for the last pass.
+struct Findings {
+    accesses: Vec<MemCheck>,
+    errors:   Vec<String>,
+}
+
+fn read_reg(state: &State, reg: u8, insn_ptr: usize, findings: &mut Findings) -> Value {
+    match state.regs.get(reg as usize) {
+        Some(&Value::Uninit) | None => {
+            findings.errors.push(format!("[Verifier] Error: R{} is read before being initialized (insn #{:?})",
+                                         reg, insn_ptr));
+            Value::Scalar(Scalar::unknown())
+        },
+        Some(&value) => value,
+    }
+}
+
+fn read_scalar(state: &State, reg: u8, insn_ptr: usize, findings: &mut Findings) -> Scalar {
+    match read_reg(state, reg, insn_ptr, findings) {
+        Value::Scalar(s) => s,
+        _                => Scalar::unknown(),
+    }
+}
+
+fn mem_size(opc: u8) -> usize {
+    match opc & 0x18 {
+        ebpf::BPF_B => 1,
+        ebpf::BPF_H => 2,
+        ebpf::BPF_W => 4,
+        _           => 8,
+    }
+}
+
+fn fmt_offsets(min: i64, max: i64) -> String {
+    match min == max {
+        true  => format!("offset {}", min),
+        false => format!("offsets {} to {}", min, max),
+    }
+}
+
+// Checks the memory access of a load or store through `base`, and returns the range of offsets
+// accessed in the stack, if that is where it goes.
+fn check_access(insn: &disassembler::HLInsn, base: Value, write: bool, ctx_len: Option<usize>,
+                findings: &mut Findings) -> Option<(i64, i64)> {
+    let size = mem_size(insn.opc);
+    let (region, off) = match base {
+        Value::Ptr(region, off) => (region, off),
+        _                       => {
+            findings.accesses.push(MemCheck {
+                insn_ptr: insn.ptr, region: Region::Unknown, size, write,
+                min_offset: 0, max_offset: 0, proven: false,
+            });
+            return None;
+        },
+    };
+    let min = off.smin.saturating_add(insn.off as i64);
+    let max = off.smax.saturating_add(insn.off as i64);
+    // Valid offsets for the first byte of the access, if the size of the region is known.
+    let valid = match (region, ctx_len) {
+        (Region::Stack, _)      => Some((-(ebpf::STACK_SIZE as i64), -(size as i64))),
+        (Region::Ctx, Some(len)) => Some((0, len as i64 - size as i64)),
+        _                       => None,
+    };
+    let proven = valid.is_some_and(|(lo, hi)| lo <= min && max <= hi);
+    findings.accesses.push(MemCheck {
+        insn_ptr: insn.ptr, region, size, write, min_offset: min, max_offset: max, proven,
+    });
+    if let Some((lo, hi)) = valid {
+        if max < lo || hi < min {
+            let msg = match region {
+                Region::Stack => format!("out of bounds stack access: {}, size {}", fmt_offsets(min, max), size),
+                _             => format!("out of bounds context access: {}, size {}, context size {}",
+                                         fmt_offsets(min, max), size, ctx_len.unwrap_or(0)),
+            };
+            findings.errors.push(format!("[Verifier] Error: {} (insn #{:?})", msg, insn.ptr));
+        }
+    }
+    match region {
+        Region::Stack => Some((min, max)),
+        _             => None,
+    }
+}
+
+fn stack_store(state: &mut State, range: Option<(i64, i64)>, size: usize, value: Value) {
+    let (min, max) = match range {
+        Some(range) => range,
+        None        => {
+            if state.stack_escaped {
+                state.clobber_stack();
+            }
+            return;
+        },
+    };
+    let slot = |off: i64| (off.saturating_add(ebpf::STACK_SIZE as i64) / 8).clamp(0, STACK_SLOTS as i64 - 1) as usize;
+    if min == max && size == 8 && min % 8 == 0 && min >= -(ebpf::STACK_SIZE as i64) && min < 0 {
+        state.stack[slot(min)] = value;
+        return;
+    }
+    for s in slot(min)..=slot(max.saturating_add(size as i64 - 1)) {
+        state.stack[s] = Value::Scalar(Scalar::unknown());
+    }
+}
+
+fn stack_load(state: &State, range: Option<(i64, i64)>, size: usize) -> Value {
+    match range {
+        Some((min, max)) if min == max && size == 8 && min % 8 == 0
+                            && min >= -(ebpf::STACK_SIZE as i64) && min < 0 =>
+            state.stack[((min + ebpf::STACK_SIZE as i64) / 8) as usize],
+        _ => Value::Scalar(Scalar::from_unsigned(0, u64::MAX >> (64 - 8 * size))),
+    }
+}
+
+fn alu_scalar(opc: u8, a: Scalar, b: Scalar) -> Scalar {
+    let is_alu32 = opc & ebpf::BPF_CLS_MASK == ebpf::BPF_ALU;
+    let (a, b) = match is_alu32 {
+        true  => (a.cast(4), b.cast(4)),
+        false => (a, b),
+    };
+    let bits = if is_alu32 { 32 } else { 64 };
+    // Shift amounts are masked to the width of the operation.
+    let shift = |b: Scalar| b.as_const().map(|k| (k & (bits - 1)) as u32);
+
+    let res = match opc & ebpf::BPF_ALU_OP_MASK {
+        ebpf::BPF_ADD  => a.add(b),
+        ebpf::BPF_SUB  => a.sub(b),
+        ebpf::BPF_MUL  => a.mul(b),
+        ebpf::BPF_DIV  => a.div(b),
+        ebpf::BPF_OR   => a.or(b),
+        ebpf::BPF_AND  => a.and(b),
+        ebpf::BPF_LSH  => shift(b).map_or_else(Scalar::unknown, |k| a.lsh(k)),
+        ebpf::BPF_RSH  => shift(b).map_or_else(Scalar::unknown, |k| a.rsh(k)),
+        ebpf::BPF_NEG  => a.neg(),
+        ebpf::BPF_MOD  => a.modulo(b),
+        ebpf::BPF_XOR  => a.xor(b),
+        ebpf::BPF_MOV  => b,
+        ebpf::BPF_ARSH => match is_alu32 {
+            true  => shift(b).map_or_else(Scalar::unknown, |k| a.sext32().arsh(k)),
+            false => shift(b).map_or_else(Scalar::unknown, |k| a.arsh(k)),
+        },
+        _              => Scalar::unknown(),
+    };
+    // 32-bit results are zero-extended.
+    match is_alu32 {
+        true  => res.cast(4),
+        false => res,
+    }
+}
+
+fn alu(insn: &disassembler::HLInsn, state: &mut State, findings: &mut Findings) {
+    let dst = insn.dst as usize;
+    let op = insn.opc & ebpf::BPF_ALU_OP_MASK;
+    let is_alu32 = insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_ALU;
+
+    if insn.opc == ebpf::LE || insn.opc == ebpf::BE {
+        let a = read_scalar(state, insn.dst, insn.ptr, findings);
+        let size = insn.imm as usize / 8;
+        let res = match (a.as_const(), insn.opc) {
+            (Some(v), ebpf::LE) => Scalar::constant(match size {
+                2 => (v as u16).to_le() as u64,
+                4 => (v as u32).to_le() as u64,
+                _ => v.to_le(),
+            }),
+            (Some(v), _)        => Scalar::constant(match size {
+                2 => (v as u16).to_be() as u64,
+                4 => (v as u32).to_be() as u64,
+                _ => v.to_be(),
+            }),
+            (None, ebpf::LE) if cfg!(target_endian = "little") => a.cast(size),
+            (None, _) if size < 8 => Scalar::from_unsigned(0, (1 << (size * 8)) - 1),
+            (None, _)             => Scalar::unknown(),
+        };
+        state.regs[dst] = Value::Scalar(res);
+        return;
+    }
+    if insn.opc == ebpf::BSWAP {
+        let a = read_scalar(state, insn.dst, insn.ptr, findings);
+        let size = insn.imm as usize / 8;
+        state.regs[dst] = Value::Scalar(match (a.as_const(), size) {
+            (Some(v), 2) => Scalar::constant((v as u16).swap_bytes() as u64),
+            (Some(v), 4) => Scalar::constant((v as u32).swap_bytes() as u64),
+            (Some(v), _) => Scalar::constant(v.swap_bytes()),
+            (None, 8)    => Scalar::unknown(),
+            (None, _)    => Scalar::from_unsigned(0, (1 << (size * 8)) - 1),
+        });
+        return;
+    }
+    // Signed divisions and modulos, and sign-extending moves (ISA v4), are not modelled.
+    let is_v4 = match op {
+        ebpf::BPF_DIV | ebpf::BPF_MOD => insn.off == 1,
+        ebpf::BPF_MOV                 => insn.opc & ebpf::BPF_X != 0 && insn.off != 0,
+        _                             => false,
+    };
+    if is_v4 {
+        if op != ebpf::BPF_MOV {
+            read_reg(state, insn.dst, insn.ptr, findings);
+        }
+        if insn.opc & ebpf::BPF_X != 0 {
+            read_reg(state, insn.src, insn.ptr, findings);
+        }
+        state.regs[dst] = Value::Scalar(match is_alu32 {
+            true  => Scalar::from_unsigned(0, u32::MAX as u64),
+            false => Scalar::unknown(),
+        });
+        return;
+    }
+
+    if insn.opc == ebpf::MOV32_IMM {
+        state.regs[dst] = Value::Scalar(Scalar::constant(insn.imm as u32 as u64));
+        return;
+    }
+    // `xor r0, r0` and `sub r0, r0` are used to clear registers, even uninitialized ones.
+    if (op == ebpf::BPF_XOR || op == ebpf::BPF_SUB) && insn.opc & ebpf::BPF_X != 0 && insn.src == insn.dst {
+        state.regs[dst] = Value::Scalar(Scalar::constant(0));
+        return;
+    }
+
+    let a = match op {
+        ebpf::BPF_MOV => Value::Scalar(Scalar::unknown()),
+        _             => read_reg(state, insn.dst, insn.ptr, findings),
+    };
+    let b = match (insn.opc & ebpf::BPF_X != 0, op) {
+        (_, ebpf::BPF_NEG) => Value::Scalar(Scalar::unknown()),
+        (true, _)          => read_reg(state, insn.src, insn.ptr, findings),
+        (false, _)         => Value::Scalar(Scalar::constant(match is_alu32 {
+            true  => insn.imm as u32 as u64,
+            false => insn.imm as u64,
+        })),
+    };
+
+    let res = match (a, b, insn.opc) {
+        (Value::Scalar(a), Value::Scalar(b), _) => Value::Scalar(alu_scalar(insn.opc, a, b)),
+        (_, Value::Ptr(..), ebpf::MOV64_REG)    => b,
+        (Value::Ptr(r, off), Value::Scalar(s), ebpf::ADD64_IMM) |
+        (Value::Ptr(r, off), Value::Scalar(s), ebpf::ADD64_REG) |
+        (Value::Scalar(s), Value::Ptr(r, off), ebpf::ADD64_REG) => Value::Ptr(r, off.add(s)),
+        (Value::Ptr(r, off), Value::Scalar(s), ebpf::SUB64_IMM) |
+        (Value::Ptr(r, off), Value::Scalar(s), ebpf::SUB64_REG) => Value::Ptr(r, off.sub(s)),
+        (Value::Ptr(r, a), Value::Ptr(q, b), ebpf::SUB64_REG) if r == q => Value::Scalar(a.sub(b)),
+        // Any other operation on a pointer gives a scalar we know nothing about.
+        _ => {
+            if a.is_stack_ptr() || b.is_stack_ptr() {
+                state.stack_escaped = true;
+            }
+            Value::Scalar(match op {
+                ebpf::BPF_MOV if is_alu32 => Scalar::from_unsigned(0, u32::MAX as u64),
+                _                         => Scalar::unknown(),
+            })
+        },
+    };
+    state.regs[dst] = res;
+}
+
+// Updates `state` with the effect of `insn`, other than jumping.
+fn transfer(insn: &disassembler::HLInsn, state: &mut State, ctx_len: Option<usize>,
+            findings: &mut Findings) {
+    let dst = insn.dst as usize;
+    let size = mem_size(insn.opc);
+    match insn.opc & ebpf::BPF_CLS_MASK {
+        ebpf::BPF_LD    => match insn.opc {
+            // The addresses of map values are not tracked.
+            ebpf::LD_DW_IMM if insn.src == ebpf::BPF_PSEUDO_MAP_VALUE => {
+                state.regs[dst] = Value::Scalar(Scalar::unknown())
+            },
+            ebpf::LD_DW_IMM => state.regs[dst] = Value::Scalar(Scalar::constant(insn.imm as u64)),
+            _               => state.regs[0] = Value::Scalar(Scalar::unknown()),
+        },
+        ebpf::BPF_LDX   => {
+            let base = read_reg(state, insn.src, insn.ptr, findings);
+            let range = check_access(insn, base, false, ctx_len, findings);
+            state.regs[dst] = match range {
+                _ if insn.opc & 0xe0 == ebpf::BPF_MEMSX => Value::Scalar(Scalar::unknown()),
+                Some(_) => stack_load(state, range, size),
+                None    => Value::Scalar(Scalar::from_unsigned(0, u64::MAX >> (64 - 8 * size))),
+            };
+        },
+        ebpf::BPF_ST    => {
+            let base = read_reg(state, insn.dst, insn.ptr, findings);
+            let range = check_access(insn, base, true, ctx_len, findings);
+            stack_store(state, range, size, Value::Scalar(Scalar::constant(insn.imm as u64)));
+        },
+        ebpf::BPF_STX   => {
+            let base = read_reg(state, insn.dst, insn.ptr, findings);
+            let value = read_reg(state, insn.src, insn.ptr, findings);
+            let range = check_access(insn, base, true, ctx_len, findings);
+            if range.is_none() && value.is_stack_ptr() {
+                state.stack_escaped = true;
+            }
+            let value = match insn.opc {
+                ebpf::ST_DW_REG => value,
+                _               => Value::Scalar(Scalar::unknown()),
+            };
+            stack_store(state, range, size, value);
+        },
+        ebpf::BPF_ALU | ebpf::BPF_ALU64 => alu(insn, state, findings),
+        ebpf::BPF_JMP   => match insn.opc {
+            ebpf::CALL | ebpf::TAIL_CALL => {
+                // Helpers may write to the stack through the pointers they receive. They return
+                // a scalar in R0, and we make no assumption on R1 to R5 after the call.
+                if state.regs[1..6].iter().any(|r| r.is_stack_ptr()) {
+                    state.stack_escaped = true;
+                }
+                if state.stack_escaped {
+                    state.clobber_stack();
+                }
+                for reg in state.regs[0..6].iter_mut() {
+                    *reg = Value::Scalar(Scalar::unknown());
+                }
+            },
+            ebpf::EXIT                   => {
+                read_reg(state, 0, insn.ptr, findings);
+            },
+            ebpf::JA                     => (),
+            _                            => {
+                read_reg(state, insn.dst, insn.ptr, findings);
+                if insn.opc & ebpf::BPF_X != 0 {
+                    read_reg(state, insn.src, insn.ptr, findings);
+                }
+            },
+        },
+        // The comparisons of 32-bit jumps do not refine the bounds of the registers.
+        ebpf::BPF_JMP32 if insn.opc != ebpf::JA32 => {
+            read_reg(state, insn.dst, insn.ptr, findings);
+            if insn.opc & ebpf::BPF_X != 0 {
+                read_reg(state, insn.src, insn.ptr, findings);
+            }
+        },
+        _ => (),
+    }
+}
+
+// Refines `a` and `b` assuming that the comparison of a conditional jump between them holds (or
+// does not hold, if `taken` is `false`). Returns `None` if it cannot.
+fn refine(opc: u8, a: Scalar, b: Scalar, taken: bool) -> Option<(Scalar, Scalar)> {
+    let with_umin = |s: Scalar, umin: u64| Scalar { umin: s.umin.max(umin), ..s }.sync();
+    let with_umax = |s: Scalar, umax: u64| Scalar { umax: s.umax.min(umax), ..s }.sync();
+    let with_smin = |s: Scalar, smin: i64| Scalar { smin: s.smin.max(smin), ..s }.sync();
+    let with_smax = |s: Scalar, smax: i64| Scalar { smax: s.smax.min(smax), ..s }.sync();
+    // Excludes constant `k` from `s`, when it is one of the bounds.
+    let without = |s: Scalar, k: Option<u64>| match k {
+        Some(k) if s.as_const() == Some(k) => None,
+        Some(k) if s.umin == k             => with_umin(s, k + 1),
+        Some(k) if s.umax == k             => with_umax(s, k - 1),
+        _                                  => Some(s),
+    };
+
+    match (opc & ebpf::BPF_ALU_OP_MASK, taken) {
+        (ebpf::BPF_JEQ, true) | (ebpf::BPF_JNE, false) => {
+            let m = a.meet(b)?;
+            Some((m, m))
+        },
+        (ebpf::BPF_JEQ, false) | (ebpf::BPF_JNE, true) =>
+            Some((without(a, b.as_const())?, without(b, a.as_const())?)),
+        // a > b
+        (ebpf::BPF_JGT, true) =>
+            Some((with_umin(a, b.umin.checked_add(1)?)?, with_umax(b, a.umax.checked_sub(1)?)?)),
+        // a <= b
+        (ebpf::BPF_JGT, false) =>
+            Some((with_umax(a, b.umax)?, with_umin(b, a.umin)?)),
+        // a >= b
+        (ebpf::BPF_JGE, true) =>
+            Some((with_umin(a, b.umin)?, with_umax(b, a.umax)?)),
+        // a < b
+        (ebpf::BPF_JGE, false) =>
+            Some((with_umax(a, b.umax.checked_sub(1)?)?, with_umin(b, a.umin.checked_add(1)?)?)),
+        (ebpf::BPF_JSGT, true) =>
+            Some((with_smin(a, b.smin.checked_add(1)?)?, with_smax(b, a.smax.checked_sub(1)?)?)),
+        (ebpf::BPF_JSGT, false) =>
+            Some((with_smax(a, b.smax)?, with_smin(b, a.smin)?)),
+        (ebpf::BPF_JSGE, true) =>
+            Some((with_smin(a, b.smin)?, with_smax(b, a.smax)?)),
+        (ebpf::BPF_JSGE, false) =>
+            Some((with_smax(a, b.smax.checked_sub(1)?)?, with_smin(b, a.smin.checked_add(1)?)?)),
+        // The other comparisons are those of `b` with `a`: a < b is b > a, and so on.
+        (ebpf::BPF_JLT, _) | (ebpf::BPF_JLE, _) | (ebpf::BPF_JSLT, _) | (ebpf::BPF_JSLE, _) => {
+            let reversed = match opc & ebpf::BPF_ALU_OP_MASK {
+                ebpf::BPF_JLT  => ebpf::BPF_JGT,
+                ebpf::BPF_JLE  => ebpf::BPF_JGE,
+                ebpf::BPF_JSLT => ebpf::BPF_JSGT,
+                _              => ebpf::BPF_JSGE,
+            };
+            let (b, a) = refine(reversed, b, a, taken)?;
+            Some((a, b))
+        },
+        (ebpf::BPF_JSET, taken) => match b.as_const() {
+            Some(k) if taken && a.var_off.max() & k == 0 => None,
+            Some(k) if !taken => {
+                let var_off = a.var_off.intersect(Tnum { value: 0, mask: !k })?;
+                Some((Scalar { var_off, ..a }.sync()?, b))
+            },
+            _ => Some((a, b)),
+        },
+        _ => Some((a, b)),
+    }
+}
+
+// Returns the state when the conditional jump `insn` is taken or not, or `None` if this is
+// impossible.
+fn branch_state(insn: &disassembler::HLInsn, state: &State, taken: bool) -> Option<State> {
+    let a = state.regs.get(insn.dst as usize).cloned();
+    let b = match insn.opc & ebpf::BPF_X != 0 {
+        true  => state.regs.get(insn.src as usize).cloned(),
+        false => Some(Value::Scalar(Scalar::constant(insn.imm as u64))),
+    };
+    let (a, b) = match (a, b) {
+        (Some(Value::Scalar(a)), Some(Value::Scalar(b))) => (a, b),
+        _                                                 => return Some(state.clone()),
+    };
+    let (a, b) = refine(insn.opc, a, b, taken)?;
+    let mut next = state.clone();
+    if insn.opc & ebpf::BPF_X != 0 {
+        next.regs[insn.src as usize] = Value::Scalar(b);
+    }
+    next.regs[insn.dst as usize] = Value::Scalar(a);
+    Some(next)
+}
+
+/// Check statically that the memory accesses of a program are within bounds, by tracking the
+/// possible values of registers along all paths of the program.
+///
+/// For each register, the analysis knows whether it holds a pointer to the stack (derived from
+/// R10), a pointer to the context (derived from the initial value of R1), or a scalar. Scalars,
+/// and offsets of pointers, are tracked with the bits known to be 0 or 1 and with their minimal
+/// and maximal values, as in the verifier of the Linux kernel. Conditional jumps refine these
+/// bounds, so a program can prove that an index is in bounds by comparing it first. 64-bit
+/// values spilled to and restored from the stack are followed.
+///
+/// Stack accesses must be in the 512 bytes below R10. Context accesses must be within the first
+/// `ctx_len` bytes of the context; if `ctx_len` is `None`, they are never proven in bounds. The
+/// program is supposed to have passed the simple verifier (`check()`) already.
+///
+/// Accesses that cannot be proven in bounds for all possible values are reported with
+/// `proven: false`, and need to be checked at runtime. Access to memory through other pointers,
+/// for example pointers read from the context, can never be proven in bounds.
+///
+/// The VMs run this analysis when loading a program, and their interpreter skips the runtime
+/// checks of the accesses proven in bounds. The size of the context is known to
+/// `EbpfVmFixedMbuff` (its metadata buffer), `EbpfVmTracing` and `EbpfVmCtx`; the other VMs only
+/// benefit from the proofs of stack accesses.
+///
+/// # Errors
+///
+/// Returns an error message for the first instruction, in the order of the program, that is
+/// certain to fail: an access out of the bounds of the stack (or of the context, if `ctx_len` is
+/// given) for all of its possible offsets, or a register read before being initialized on some
+/// path.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier::{self, Region};
+///
+/// let prog = vec![
+///     0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+///     0x57, 0x02, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // and64 r2, 0x7
+///     0x07, 0x02, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // add64 r2, 0x8
+///     0x0f, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r1, r2
+///     0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// // The second load reads one of bytes 8 to 15 of the context.
+/// let report = verifier::check_bounds(&prog, Some(16)).unwrap();
+/// let access = report.get(4).unwrap();
+/// assert_eq!(access.region, Region::Ctx);
+/// assert_eq!((access.min_offset, access.max_offset), (8, 15));
+/// assert!(report.all_proven());
+///
+/// // With a smaller context, it may be out of bounds.
+/// let report = verifier::check_bounds(&prog, Some(12)).unwrap();
+/// assert!(!report.all_proven());
+///
+/// // And with a much smaller one, it is out of bounds on all paths.
+/// assert!(verifier::check_bounds(&prog, Some(8)).is_err());
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn check_bounds(prog: &[u8], ctx_len: Option<usize>) -> Result<BoundsReport, String> {
+    bounds_from(prog, ctx_len, 0, State::initial(), &mut Meter::new(&VerifyBudget::default()))
+        .map_err(|err| err.to_string())
+}
+
+/// A token to cancel an analysis of the verifier from another thread, see `VerifyBudget`. Clones
+/// of a token share its state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+
+    /// Create a token, not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancel the analyses checking this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A budget for the analysis of `check_bounds_within()`, unlimited by default. The analysis
+/// proceeds in steps, each of them processing one basic block with one state; on adversarial
+/// programs, the number of steps can grow much faster than the number of instructions.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyBudget {
+    /// Maximum number of steps of the analysis.
+    pub max_steps: Option<u64>,
+    /// Maximum duration of the analysis, checked at each step.
+    pub timeout:   Option<Duration>,
+    /// A token cancelling the analysis, checked at each step.
+    pub cancel:    Option<CancelToken>,
+}
+
+// Counts the steps of an analysis against its budget.
+struct Meter<'b> {
+    budget:   &'b VerifyBudget,
+    deadline: Option<Instant>,
+    steps:    u64,
+}
+
+impl<'b> Meter<'b> {
+    fn new(budget: &'b VerifyBudget) -> Meter<'b> {
+        Meter { budget, deadline: budget.timeout.map(|timeout| Instant::now() + timeout), steps: 0 }
+    }
+
+    // Counts a step, unless the budget is exhausted or the analysis cancelled.
+    fn step(&mut self) -> Result<(), EbpfError> {
+        let cancelled = self.budget.cancel.as_ref().is_some_and(|token| token.is_cancelled());
+        if cancelled || self.budget.max_steps.is_some_and(|max| self.steps >= max) ||
+           self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(EbpfError::VerificationTimedOut { steps: self.steps, cancelled });
+        }
+        self.steps += 1;
+        Ok(())
+    }
+}
+
+/// Run the analysis of `check_bounds()` within `budget`: it stops with an
+/// `EbpfError::VerificationTimedOut` error once the budget is exhausted or the analysis is
+/// cancelled, rather than running on. The other errors found by the analysis are returned as
+/// `EbpfError::VerifierError`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use rbpf::error::EbpfError;
+/// use rbpf::verifier::{self, CancelToken, VerifyBudget};
+///
+/// // A loop.
+/// let prog = vec![
+///     0xb7, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r0, 10
+///     0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r0, 1
+///     0x55, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r0, 0, -2
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let budget = VerifyBudget { timeout: Some(Duration::from_secs(10)), ..VerifyBudget::default() };
+/// assert!(verifier::check_bounds_within(&prog, None, &budget).is_ok());
+///
+/// let budget = VerifyBudget { max_steps: Some(2), ..VerifyBudget::default() };
+/// assert_eq!(verifier::check_bounds_within(&prog, None, &budget).unwrap_err(),
+///            EbpfError::VerificationTimedOut { steps: 2, cancelled: false });
+///
+/// // Another thread may cancel the analysis with a clone of the token.
+/// let token = CancelToken::new();
+/// token.clone().cancel();
+/// let budget = VerifyBudget { cancel: Some(token), ..VerifyBudget::default() };
+/// assert_eq!(verifier::check_bounds_within(&prog, None, &budget).unwrap_err(),
+///            EbpfError::VerificationTimedOut { steps: 0, cancelled: true });
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn check_bounds_within(prog: &[u8], ctx_len: Option<usize>, budget: &VerifyBudget)
+                           -> Result<BoundsReport, EbpfError> {
+    bounds_from(prog, ctx_len, 0, State::initial(), &mut Meter::new(budget))
+}
+
+// Runs the analysis of `check_bounds()` on the instructions reachable from instruction `entry`,
+// the first instruction of a basic block, entered with state `initial`, counting its steps with
+// `meter`.
+fn bounds_from(prog: &[u8], ctx_len: Option<usize>, entry: usize, initial: State,
+               meter: &mut Meter) -> Result<BoundsReport, EbpfError> {
+    let insns = disassembler::to_insn_vec(prog);
+    let blocks = analysis::basic_blocks(prog);
+    let block_insns = |block: &analysis::BasicBlock| {
+        let (start, end) = (block.start, block.end);
+        insns.iter().filter(move |i| start <= i.ptr && i.ptr < end)
+    };
+    let block_index = |start: usize| blocks.iter().position(|block| block.start == start);
+
+    // The states at the entry of the successors of block `b`, when entering it with `state`.
+    let block_outputs = |b: usize, mut state: State| {
+        let mut scratch = Findings { accesses: vec![], errors: vec![] };
+        let mut last = None;
+        for insn in block_insns(&blocks[b]) {
+            transfer(insn, &mut state, ctx_len, &mut scratch);
+            last = Some(insn);
+        }
+        let cond_jump = last.filter(|insn| {
+            insn.opc & ebpf::BPF_CLS_MASK == ebpf::BPF_JMP && insn.opc != ebpf::JA
+                && blocks[b].branch.is_some()
+        });
+        let succs = [(blocks[b].branch, true), (blocks[b].fallthrough, false)];
+        let mut outputs = vec![];
+        for &(succ, taken) in succs.iter() {
+            let succ = match succ.and_then(&block_index) {
+                Some(succ) => succ,
+                None       => continue,
+            };
+            match cond_jump {
+                Some(insn) => if let Some(out) = branch_state(insn, &state, taken) {
+                    outputs.push((succ, out));
+                },
+                None       => outputs.push((succ, state.clone())),
+            }
+        }
+        outputs
+    };
+
+    // Propagate the states along the control flow graph until a fixed point is reached.
+    let entry = match block_index(entry) {
+        Some(entry) => entry,
+        None        => return Ok(BoundsReport::default()),
+    };
+    let mut entry_states: Vec<Option<State>> = vec![None; blocks.len()];
+    let mut visits = vec![0; blocks.len()];
+    entry_states[entry] = Some(initial.clone());
+    let mut worklist = vec![entry];
+    while let Some(b) = worklist.pop() {
+        meter.step()?;
+        let state = match entry_states.get(b) {
+            Some(Some(state)) => state.clone(),
+            _                 => continue,
+        };
+        for (succ, out) in block_outputs(b, state) {
+            let merged = match entry_states[succ] {
+                None           => out,
+                Some(ref prev) => prev.merge(&out, visits[succ] >= WIDEN_AFTER),
+            };
+            if entry_states[succ].as_ref() != Some(&merged) {
+                entry_states[succ] = Some(merged);
+                visits[succ] += 1;
+                worklist.push(succ);
+            }
+        }
+    }
+
+    // Widening may have lost the bounds of loop counters, that the conditions of the loops
+    // restore: compute the states again from the states of the predecessors.
+    for _ in 0..NARROW_PASSES {
+        let mut next: Vec<Option<State>> = vec![None; blocks.len()];
+        next[entry] = Some(initial.clone());
+        for (b, state) in entry_states.iter().enumerate() {
+            if let Some(ref state) = *state {
+                meter.step()?;
+                for (succ, out) in block_outputs(b, state.clone()) {
+                    next[succ] = Some(match next[succ] {
+                        None           => out,
+                        Some(ref prev) => prev.merge(&out, false),
+                    });
+                }
+            }
+        }
+        entry_states = next;
+    }
+
+    // Check the accesses with the final states.
+    let mut findings = Findings { accesses: vec![], errors: vec![] };
+    for (block, state) in blocks.iter().zip(entry_states.iter()) {
+        if let Some(ref state) = *state {
+            let mut state = state.clone();
+            for insn in block_insns(block) {
+                transfer(insn, &mut state, ctx_len, &mut findings);
+            }
+        }
+    }
+    match findings.errors.into_iter().next() {
+        Some(err) => Err(EbpfError::VerifierError(err)),
+        None      => Ok(BoundsReport { accesses: findings.accesses }),
+    }
+}
+
+// Size of the stack frames of subprograms are rounded up to a multiple of this size, as in the
+// kernel.
+const FRAME_ALIGN: usize = 32;
+
+/// Check the stack usage of a program made of subprograms, called with BPF-to-BPF calls (see
+/// `ebpf::BPF_PSEUDO_CALL`), with the rules of the Linux kernel, and return the largest combined
+/// size of the frames of a chain of calls, in bytes. The VMs do not run subprograms yet, and the
+/// simple verifier rejects the programs that contain such calls: this check is run separately.
+///
+/// Each subprogram starts at the target of a call, and ends before the next one, or at the end of
+/// the program. The function checks that:
+///
+/// * Calls target instructions of the program, and jumps stay within their subprogram, which ends
+///   with an `exit` or `ja` instruction.
+/// * Subprograms are not recursive, and chains of calls have at most `ebpf::MAX_CALL_DEPTH`
+///   frames.
+/// * The combined size of the frames of the subprograms of a chain of calls is at most
+///   `ebpf::STACK_SIZE`, the frame of each subprogram being rounded up to a multiple of 32 bytes.
+///
+/// The size of a frame is the deepest offset below R10 accessed by the loads and stores of the
+/// subprogram, as found by the analysis of `check_bounds()`. Accesses through pointers of unknown
+/// region are not counted.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier;
+///
+/// // The main program uses 8 bytes of stack, the subprogram 256 bytes.
+/// let mut prog = vec![
+///     0x7a, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-8], 0
+///     0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call +1 (subprogram)
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+///     0x7a, 0x0a, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-256], 0
+///     0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r1
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+/// assert_eq!(verifier::check_stack_depth(&prog), Ok(32 + 256));
+///
+/// // With 488 bytes for the main program, the chain uses more than 512 bytes.
+/// prog[2] = 0x18;
+/// prog[3] = 0xfe;
+/// assert_eq!(verifier::check_stack_depth(&prog).unwrap_err(),
+///            "[Verifier] Error: combined stack size of 2 calls is 768 bytes, more than 512 (insn #1)");
+/// ```
+///
+/// # Panics
+///
+/// Panics if the length of the program is not a multiple of the size of an instruction.
+pub fn check_stack_depth(prog: &[u8]) -> Result<usize, String> {
+    let insns = disassembler::to_insn_vec(prog);
+    let len = prog.len() / ebpf::INSN_SIZE;
+    let is_call = |insn: &&disassembler::HLInsn| insn.opc == ebpf::CALL && insn.src == ebpf::BPF_PSEUDO_CALL;
+
+    // Split the program into subprograms.
+    let mut starts = vec![0];
+    for insn in insns.iter().filter(is_call) {
+        let target = insn.ptr as i64 + 1 + insn.imm;
+        if target < 0 || target >= len as i64 {
+            return Err(format!("[Verifier] Error: call to a subprogram out of code (insn #{:?})", insn.ptr));
+        }
+        starts.push(target as usize);
+    }
+    starts.sort_unstable();
+    starts.dedup();
+    let subprog = |ptr: usize| starts.partition_point(|&start| start <= ptr) - 1;
+
+    let mut frames = vec![];
+    let mut calls = vec![vec![]; starts.len()];
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).cloned().unwrap_or(len);
+        let body = insns.iter().filter(|insn| start <= insn.ptr && insn.ptr < end);
+        match body.clone().next_back() {
+            Some(last) if last.opc == ebpf::EXIT || last.opc == ebpf::JA || last.opc == ebpf::JA32 => {},
+            _ => return Err(format!("[Verifier] Error: subprogram does not end with “EXIT” or “JA” \
+                                     instruction (insn #{:?})", end - 1)),
+        }
+        for insn in body.clone() {
+            if analysis::jump_target(insn).is_some_and(|target| target < start || target >= end) {
+                return Err(format!("[Verifier] Error: jump out of subprogram (insn #{:?})", insn.ptr));
+            }
+        }
+        calls[i] = body.filter(is_call).map(|insn| {
+            (insn.ptr, subprog((insn.ptr as i64 + 1 + insn.imm) as usize))
+        }).collect::<Vec<_>>();
+
+        let entry_state = match start {
+            0 => State::initial(),
+            _ => State::subprogram(),
+        };
+        let depth = bounds_from(prog, None, start, entry_state, &mut Meter::new(&VerifyBudget::default()))
+            .map_err(|err| err.to_string())?.accesses.iter()
+            .filter(|access| access.region == Region::Stack)
+            .map(|access| access.min_offset.min(0).unsigned_abs() as usize)
+            .max().unwrap_or(0);
+        frames.push(depth.max(1).div_ceil(FRAME_ALIGN) * FRAME_ALIGN);
+    }
+
+    // Walk the chains of calls from the main program, `path` holding the subprograms called.
+    fn walk(subprog: usize, call_ptr: usize, base: usize, frames: &[usize], calls: &[Vec<(usize, usize)>],
+            path: &mut Vec<usize>) -> Result<usize, String> {
+        if path.contains(&subprog) {
+            return Err(format!("[Verifier] Error: recursive call to a subprogram (insn #{:?})", call_ptr));
+        }
+        if path.len() == ebpf::MAX_CALL_DEPTH {
+            return Err(format!("[Verifier] Error: the call stack of {} frames is too deep (insn #{:?})",
+                               path.len() + 1, call_ptr));
+        }
+        let depth = base + frames[subprog];
+        if depth > ebpf::STACK_SIZE {
+            return Err(format!("[Verifier] Error: combined stack size of {} calls is {} bytes, more \
+                                than {} (insn #{:?})", path.len() + 1, depth, ebpf::STACK_SIZE, call_ptr));
+        }
+        path.push(subprog);
+        let mut max = depth;
+        for &(ptr, callee) in calls[subprog].iter() {
+            max = max.max(walk(callee, ptr, depth, frames, calls, path)?);
+        }
+        path.pop();
+        Ok(max)
+    }
+    walk(0, 0, 0, &frames, &calls, &mut vec![])
+}
+
+/// A finding of `report()` that does not prevent running the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReportWarning {
+    /// Index of the instruction the warning is about, if it is about a single one.
+    pub insn_ptr: Option<usize>,
+    /// Description of the finding.
+    pub message:  String,
+}
+
+/// The result of `report()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VerifierReport {
+    /// The error found by the simple verifier, see `try_check()`, or `None` if the program
+    /// passed. The other analyses only run on programs that passed.
+    pub error:           Option<String>,
+    /// Number of instructions, counting the two halves of `lddw` instructions.
+    pub insn_count:      usize,
+    /// Number of basic blocks, see `analysis::basic_blocks()`.
+    pub basic_blocks:    usize,
+    /// Number of bytes of the stack used by the loads and stores of the program, below R10, as
+    /// found by `check_bounds()`. Stack memory only accessed by helpers is not counted. `None`
+    /// if unknown: a store or load through a pointer of unknown region may access the stack.
+    pub max_stack_depth: Option<usize>,
+    /// Keys of the helpers called by the program, in increasing order.
+    pub helpers:         Vec<u32>,
+    /// The findings of `analysis::lint()`, and the error found by `check_bounds()`, if any, such
+    /// as a memory access out of bounds on all paths.
+    pub warnings:        Vec<ReportWarning>,
+}
+
+impl VerifierReport {
+    /// Return `true` if the program passed the simple verifier.
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Write the report as a JSON object, on a single line. The keys are the names of the fields
+    /// of the report.
+    pub fn to_json(&self) -> String {
+        let opt = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let helpers: Vec<String> = self.helpers.iter().map(|key| key.to_string()).collect();
+        let warnings: Vec<String> = self.warnings.iter().map(|warning| {
+            format!("{{\"insn_ptr\":{},\"message\":\"{}\"}}",
+                    opt(warning.insn_ptr.map(|ptr| ptr.to_string())), json_escape(&warning.message))
+        }).collect();
+        format!("{{\"error\":{},\"insn_count\":{},\"basic_blocks\":{},\"max_stack_depth\":{},\
+                 \"helpers\":[{}],\"warnings\":[{}]}}",
+                opt(self.error.as_ref().map(|err| format!("\"{}\"", json_escape(err)))),
+                self.insn_count, self.basic_blocks,
+                opt(self.max_stack_depth.map(|depth| depth.to_string())),
+                helpers.join(","), warnings.join(","))
+    }
+}
+
+/// Verify a program and report on it: the outcome of the simple verifier, the size and structure
+/// of the program, the helpers it uses, and warnings about code that does nothing useful or
+/// certain to fail at runtime. `ctx_len` is the size of the context, as for
+/// `check_bounds()`.
+///
+/// # Examples
+///
+/// ```
+/// use rbpf::verifier;
+///
+/// let prog = vec![
+///     0x7a, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-8], 0
+///     0x79, 0xa1, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r10-8]
+///     0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+///     0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r2, 0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let report = verifier::report(&prog, None);
+/// assert!(report.passed());
+/// assert_eq!((report.insn_count, report.max_stack_depth), (5, Some(8)));
+/// assert_eq!(report.to_json(),
+///            "{\"error\":null,\"insn_count\":5,\"basic_blocks\":1,\"max_stack_depth\":8,\
+///             \"helpers\":[1],\"warnings\":[{\"insn_ptr\":3,\
+///             \"message\":\"instruction 3 writes r2, which is never read\"}]}");
+/// ```
+///
+/// The report of a program rejected by the simple verifier only holds the error and the number
+/// of instructions:
+///
+/// ```
+/// let prog = vec![
+///     0x37, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div r0, 0
+///     0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+/// ];
+///
+/// let report = rbpf::verifier::report(&prog, None);
+/// assert_eq!(report.error, Some("[Verifier] Error: division by 0 (insn #0)".to_string()));
+/// assert_eq!(report.insn_count, 2);
+/// ```
+pub fn report(prog: &[u8], ctx_len: Option<usize>) -> VerifierReport {
+    let mut report = VerifierReport { insn_count: prog.len() / ebpf::INSN_SIZE, ..Default::default() };
+    if let Err(err) = try_check(&prog.to_vec()) {
+        report.error = Some(err.to_string());
+        return report;
+    }
+
+    report.basic_blocks = analysis::basic_blocks(prog).len();
+    report.helpers = analysis::dependencies(prog).helpers.into_iter().collect();
+    report.warnings = analysis::lint(prog).iter().map(|lint| {
+        ReportWarning { insn_ptr: Some(lint.insn_ptr()), message: lint.to_string() }
+    }).collect();
+    match check_bounds(prog, ctx_len) {
+        Ok(bounds) => {
+            let mut depth = Some(0);
+            for access in &bounds.accesses {
+                depth = match access.region {
+                    Region::Stack   => depth.map(|depth: usize| depth.max((-access.min_offset).max(0) as usize)),
+                    Region::Ctx     => depth,
+                    Region::Unknown => None,
+                };
+            }
+            report.max_stack_depth = depth;
+        },
+        Err(msg) => report.warnings.push(ReportWarning { insn_ptr: None, message: msg }),
+    }
+    report
 }
@@ -0,0 +1,110 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+extern crate rbpf;
+use rbpf::{assembler, disassembler};
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Assembles `src`, and returns the disassembly of the result.
+fn roundtrip(src: &str) -> Vec<String> {
+    let prog = assembler::assemble(src).unwrap();
+    disassembler::to_insn_vec(&prog).into_iter().map(|insn| insn.desc).collect()
+}
+
+#[test]
+fn test_assembler_roundtrip() {
+    let src = vec![
+        "add32 r1, 0x2", "add64 r1, r2", "neg64 r3", "mov32 r0, 0xffffffff", "sdiv64 r1, r2",
+        "smod32 r4, 0x3", "movsx64 r1, r2, 16", "be32 r4", "le64 r4", "bswap16 r2",
+        "ldxdw r1, [r2+0x8]", "ldxb r1, [r2-0x1]", "ldxsh r3, [r10-0x10]", "stw [r10-0x8], 0x5",
+        "stxh [r1], r9", "stxxadddw [r1+0x4], r2", "lddw r3, 0x100000002", "ldabsh 0xc",
+        "ldindw r1, 0x4", "ja -0x2", "ja32 +0x10000", "jsge r1, 0x3, +0x1", "jset r1, r2, -0x1",
+        "jeq32 r1, 0x7, +0x2", "jsgt32 r1, r2, +0x2", "call 0x4", "tail_call", "exit",
+    ];
+    assert_eq!(roundtrip(&src.join("\n")), src);
+}
+
+// Every operation code of the table assembles back from its disassembly.
+#[test]
+fn test_assembler_all_opcodes() {
+    use rbpf::ebpf::{self, OperandKind};
+
+    for info in ebpf::OPCODES {
+        // The offset of arithmetic operations selects their variants.
+        let (off, imm) = match info.operands {
+            OperandKind::DstWidth                      => (0, 32),
+            OperandKind::Dst | OperandKind::DstImm |
+            OperandKind::DstSrc                        => (0, 4),
+            _                                          => (3, 4),
+        };
+        let mut prog = ebpf::Insn { opc: info.opc, dst: 1, src: 2, off, imm }.to_vec();
+        if info.opc == ebpf::LD_DW_IMM {
+            prog.extend(ebpf::Insn { opc: 0, dst: 0, src: 0, off: 0, imm: 5 }.to_vec());
+        }
+        let desc = disassembler::to_insn_vec(&prog)[0].desc.clone();
+        let insns = disassembler::to_insn_vec(&assembler::assemble(&desc).unwrap());
+        assert_eq!(insns[0].desc, desc);
+        assert_eq!(insns[0].name, info.mnemonic, "{}", desc);
+    }
+}
+
+#[test]
+fn test_assembler_syntax() {
+    assert_eq!(roundtrip("
+        # Comments and empty lines are ignored.
+
+        add r1, 2        // 64-bit operation
+        mov32 r2, -8
+        jne r2, r1, 3
+        ldxw r0, [ r1 + 4 ]
+        lddw r0, -1
+    "), vec![
+        "add64 r1, 0x2", "mov32 r2, 0xfffffff8", "jne r2, r1, +0x3", "ldxw r0, [r1+0x4]",
+        "lddw r0, 0xffffffffffffffff",
+    ]);
+}
+
+#[test]
+fn test_assembler_errors() {
+    let error = |src: &str| assembler::assemble(src).unwrap_err();
+    assert_eq!(error("exit\nfoo r1"), "Error: unknown instruction foo (line 2)");
+    assert_eq!(error("add64 r1"), "Error: invalid operands for add64 (line 1)");
+    assert_eq!(error("exit r0"), "Error: invalid operands for exit (line 1)");
+    assert_eq!(error("ldxdw r0, [r1+0x8000]"), "Error: offset 32768 does not fit in 16 bits (line 1)");
+    assert_eq!(error("mov64 r0, 0x100000000"), "Error: immediate 0x100000000 does not fit in 32 bits (line 1)");
+    assert_eq!(error("mov64 r0, 0xz"), "Error: invalid number \"0xz\" (line 1)");
+}
+
+#[test]
+fn test_asm_disasm_tools() {
+    let run = |tool: &str, args: &[&str], input: &[u8]| -> Vec<u8> {
+        let mut child = Command::new(tool).args(args)
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let (asm, disasm) = (env!("CARGO_BIN_EXE_rbpf-asm"), env!("CARGO_BIN_EXE_rbpf-disasm"));
+    let src = "mov64 r0, 0x2a\nlddw r1, 0x1122334455667788\nexit\n";
+
+    let bin = run(asm, &[], src.as_bytes());
+    assert_eq!(bin, assembler::assemble(src).unwrap());
+    assert_eq!(run(disasm, &[], &bin), src.as_bytes());
+
+    let c = run(asm, &["-f", "c"], src.as_bytes());
+    assert_eq!(String::from_utf8(c.clone()).unwrap(), "unsigned char prog[] = {
+    0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 0x2a
+    0x18, 0x01, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55, // lddw r1, 0x1122334455667788
+    0x00, 0x00, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11,
+    0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+};
+");
+    assert_eq!(run(disasm, &["-f", "c"], &c), src.as_bytes());
+}
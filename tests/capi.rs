@@ -0,0 +1,95 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+// Tests for the C API, run with `cargo test --features capi`.
+
+#![cfg(feature = "capi")]
+
+extern crate rbpf;
+
+use std::ffi::CStr;
+use std::ptr;
+
+use rbpf::capi::*;
+
+extern "C" fn add_one(arg1: u64, _arg2: u64, _arg3: u64, _arg4: u64, _arg5: u64) -> u64 {
+    arg1 + 1
+}
+
+unsafe fn last_error(vm: *const RbpfVm) -> String {
+    CStr::from_ptr(rbpf_vm_last_error(vm)).to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_capi_exec() {
+    // Returns helper 1 applied to the first byte of the packet.
+    let prog = vec![
+        0x71, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1]
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0x2a];
+    let mut ret = 0;
+    unsafe {
+        let vm = rbpf_vm_new();
+        assert_eq!(rbpf_vm_exec(vm, mem.as_mut_ptr(), mem.len(), &mut ret), -1);
+        assert_eq!(last_error(vm), "Error: no program loaded");
+
+        assert_eq!(rbpf_vm_load(vm, prog.as_ptr(), prog.len()), 0);
+        assert!(rbpf_vm_last_error(vm).is_null());
+        assert_eq!(rbpf_vm_exec(vm, mem.as_mut_ptr(), mem.len(), &mut ret), -1);
+        assert_eq!(last_error(vm), "Error: unknown helper function (id: 0x1)");
+
+        assert_eq!(rbpf_vm_register_helper(vm, 1, Some(add_one)), 0);
+        assert_eq!(rbpf_vm_register_helper(vm, 2, None), -1);
+        assert_eq!(rbpf_vm_exec(vm, mem.as_mut_ptr(), mem.len(), &mut ret), 0);
+        assert_eq!(ret, 0x2b);
+        assert!(rbpf_vm_last_error(vm).is_null());
+
+        // The packet is too short.
+        assert_eq!(rbpf_vm_exec(vm, ptr::null_mut(), 0, &mut ret), -1);
+        assert!(last_error(vm).starts_with("Error: out of bounds memory load (insn #1)"));
+        assert_eq!(rbpf_vm_exec(vm, mem.as_mut_ptr(), mem.len(), ptr::null_mut()), -1);
+
+        rbpf_vm_free(vm);
+        rbpf_vm_free(ptr::null_mut());
+        assert_eq!(rbpf_vm_exec(ptr::null_mut(), ptr::null_mut(), 0, &mut ret), -1);
+        assert!(rbpf_vm_last_error(ptr::null()).is_null());
+    }
+}
+
+#[test]
+fn test_capi_load_error() {
+    // No exit instruction.
+    let prog = [
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // mov64 r0, 0
+    ];
+    unsafe {
+        let vm = rbpf_vm_new();
+        assert_eq!(rbpf_vm_load(vm, prog.as_ptr(), prog.len()), -1);
+        assert_eq!(last_error(vm), "[Verifier] Error: program does not end with “EXIT” instruction");
+        assert_eq!(rbpf_vm_load(vm, ptr::null(), 0), -1);
+        rbpf_vm_free(vm);
+    }
+}
+
+#[test]
+fn test_capi_header() {
+    // All functions of the API are declared in the header.
+    let header = include_str!("../include/rbpf.h");
+    let src = include_str!("../src/capi.rs");
+    let functions: Vec<&str> = src.lines()
+        .filter_map(|line| line.split("extern \"C\" fn ").nth(1))
+        .filter(|decl| decl.starts_with("rbpf_"))
+        .map(|decl| decl.split('(').next().unwrap())
+        .collect();
+    assert_eq!(functions.len(), 6);
+    for function in functions {
+        assert!(header.contains(&format!(" {}(", function)) || header.contains(&format!("*{}(", function)),
+                "{} is not declared in include/rbpf.h", function);
+    }
+}
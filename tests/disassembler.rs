@@ -0,0 +1,84 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+extern crate rbpf;
+use rbpf::disassembler;
+
+// Disassembles a single-instruction (or lddw) program, and returns its assembly.
+fn disasm(prog: &[u8]) -> String {
+    let insns = disassembler::to_insn_vec(prog);
+    assert_eq!(insns.len(), 1);
+    insns[0].desc.clone()
+}
+
+#[test]
+fn test_disassembler_alu() {
+    assert_eq!(disasm(&[0x04, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]), "add32 r1, 0x2");
+    assert_eq!(disasm(&[0x0f, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), "add64 r1, r2");
+    assert_eq!(disasm(&[0x87, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), "neg64 r3");
+    assert_eq!(disasm(&[0xb4, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff]), "mov32 r0, 0xffffffff");
+}
+
+#[test]
+fn test_disassembler_byteswap() {
+    assert_eq!(disasm(&[0xdc, 0x04, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00]), "be32 r4");
+    assert_eq!(disasm(&[0xd4, 0x04, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00]), "le64 r4");
+}
+
+#[test]
+fn test_disassembler_ld_st() {
+    assert_eq!(disasm(&[0x79, 0x21, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00]), "ldxdw r1, [r2+0x8]");
+    assert_eq!(disasm(&[0x71, 0x21, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]), "ldxb r1, [r2-0x1]");
+    assert_eq!(disasm(&[0x62, 0x0a, 0xf8, 0xff, 0x05, 0x00, 0x00, 0x00]), "stw [r10-0x8], 0x5");
+    assert_eq!(disasm(&[0x6b, 0x91, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), "stxh [r1], r9");
+    assert_eq!(disasm(&[
+        0x18, 0x03, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00
+    ]), "lddw r3, 0x100000002");
+}
+
+#[test]
+fn test_disassembler_jmp() {
+    assert_eq!(disasm(&[0x05, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00]), "ja -0x2");
+    assert_eq!(disasm(&[0x75, 0x01, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00]), "jsge r1, 0x3, +0x1");
+    assert_eq!(disasm(&[0x4d, 0x21, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]), "jset r1, r2, -0x1");
+    assert_eq!(disasm(&[0x85, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00]), "call 0x4");
+    assert_eq!(disasm(&[0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), "exit");
+}
+
+#[test]
+fn test_disassembler_isa_v2_to_v4() {
+    assert_eq!(disasm(&[0xad, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]), "jlt r1, r2, +0x1");
+    assert_eq!(disasm(&[0xd5, 0x01, 0xff, 0xff, 0x03, 0x00, 0x00, 0x00]), "jsle r1, 0x3, -0x1");
+    assert_eq!(disasm(&[0x16, 0x01, 0x02, 0x00, 0x07, 0x00, 0x00, 0x00]), "jeq32 r1, 0x7, +0x2");
+    assert_eq!(disasm(&[0x6e, 0x21, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00]), "jsgt32 r1, r2, +0x2");
+    assert_eq!(disasm(&[0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]), "ja32 +0x10000");
+    assert_eq!(disasm(&[0x3f, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]), "sdiv64 r1, r2");
+    assert_eq!(disasm(&[0x94, 0x01, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00]), "smod32 r1, 0x3");
+    assert_eq!(disasm(&[0xbf, 0x21, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00]), "movsx64 r1, r2, 16");
+    assert_eq!(disasm(&[0xd7, 0x04, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00]), "bswap32 r4");
+    assert_eq!(disasm(&[0x91, 0x21, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00]), "ldxsb r1, [r2+0x8]");
+}
+
+#[test]
+fn test_disassembler_unknown() {
+    assert_eq!(disasm(&[0xe7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]), "unknown opcode 0xe7");
+}
+
+#[test]
+fn test_disassembler_insn_ptr() {
+    let prog = vec![
+        0x18, 0x03, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // lddw r3, 0x100000002
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let insns = disassembler::to_insn_vec(&prog);
+    assert_eq!(insns.iter().map(|i| i.ptr).collect::<Vec<_>>(), vec![0, 2, 3]);
+    assert_eq!(insns[0].imm, 0x1_0000_0002);
+    assert_eq!(insns[1].name, "mov64");
+}
@@ -0,0 +1,77 @@
+// Copyright 2016 6WIND S.A. <quentin.monnet@6wind.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+
+// The budget of the cache of JIT-compiled programs is global to the process: these tests are kept
+// apart from the others, whose compiled programs would be evicted, and run as a single test.
+
+extern crate rbpf;
+
+// Returns `ret`.
+fn prog(ret: u8) -> Vec<u8> {
+    vec![
+        0xb7, 0x00, 0x00, 0x00, ret,  0x00, 0x00, 0x00, // mov r0, ret
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]
+}
+
+#[test]
+fn test_jit_code_budget() {
+    let (prog_a, prog_b, prog_c) = (prog(1), prog(2), prog(3));
+    let mut vm_a = rbpf::EbpfVmNoData::new(&prog_a);
+    let mut vm_b = rbpf::EbpfVmNoData::new(&prog_b);
+    let mut vm_c = rbpf::EbpfVmNoData::new(&prog_c);
+
+    assert_eq!(rbpf::jit_code_usage(), 0);
+    vm_a.jit_compile();
+    let size = rbpf::jit_code_usage();
+    assert!(size > 0);
+    rbpf::set_jit_code_budget(Some(2 * size));
+
+    // The least recently run program is evicted.
+    vm_b.jit_compile();
+    assert_eq!(vm_a.prog_exec_jit(), 1);
+    vm_c.jit_compile();
+    assert_eq!(rbpf::jit_code_usage(), 2 * size);
+    assert!(vm_a.stats().jit_code_size.is_some());
+    assert_eq!(vm_b.stats().jit_code_size, None);
+    assert!(vm_c.stats().jit_code_size.is_some());
+
+    // The VMs of evicted programs fall back to the interpreter.
+    assert_eq!(vm_b.prog_exec_jit(), 2);
+    assert_eq!(vm_b.try_prog_exec_jit(), Ok(2));
+
+    // Compiling the program again evicts the next one.
+    vm_b.jit_compile();
+    assert!(vm_b.stats().jit_code_size.is_some());
+    assert_eq!(vm_a.stats().jit_code_size, None);
+    assert_eq!(vm_a.prog_exec_jit(), 1);
+    assert_eq!(vm_c.prog_exec_jit(), 3);
+
+    // Shared programs are counted once.
+    let mut vm_c2 = rbpf::EbpfVmNoData::new(&prog_c);
+    vm_c2.jit_compile();
+    assert!(vm_c2.stats().jit_code_shared);
+    assert_eq!(rbpf::jit_code_usage(), 2 * size);
+
+    // Lowering the budget evicts programs at once, removing it stops the evictions.
+    rbpf::set_jit_code_budget(Some(size));
+    assert_eq!(rbpf::jit_code_usage(), size);
+    assert_eq!(vm_b.stats().jit_code_size, None);
+    rbpf::set_jit_code_budget(None);
+    vm_a.jit_compile();
+    vm_b.jit_compile();
+    assert_eq!(rbpf::jit_code_usage(), 3 * size);
+    for (vm, ret) in [(&vm_a, 1), (&vm_b, 2), (&vm_c, 3), (&vm_c2, 3)].iter() {
+        assert!(vm.stats().jit_code_size.is_some());
+        assert_eq!(vm.prog_exec_jit(), *ret);
+    }
+
+    // Programs compiled into a `JitProgram` are not counted.
+    let program = vm_a.jit_program();
+    assert_eq!(rbpf::jit_code_usage(), 3 * size);
+    assert_eq!(program.prog_exec(&mut [], &mut []), 1);
+}
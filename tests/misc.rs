@@ -19,7 +19,13 @@
 // extern crate elf;
 // use std::path::PathBuf;
 
+#[cfg(feature = "fuzz")]
+extern crate arbitrary;
+#[macro_use]
 extern crate rbpf;
+#[cfg(feature = "serde")]
+extern crate serde_test;
+use rbpf::ebpf;
 use rbpf::helpers;
 
 // The following two examples have been compiled from C with the following command:
@@ -303,3 +309,4845 @@ fn test_jit_mbuff() {
     vm.jit_compile();
     assert_eq!(vm.prog_exec_jit(&mut mem, &mut mbuff), 0x2211);
 }
+
+// Classifier dispatching on the first byte of packet data: the byte is used as the index, in
+// program array 1, of the action program to tail-call.
+#[test]
+fn test_registry_pipeline() {
+    let classifier = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0xbf, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r2
+        0xb7, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r2, 1
+        0x85, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // call 12 (tail call)
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let action1 = vec![
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x07, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // add64 r0, 0x10
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let action2 = vec![
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x07, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // add64 r0, 0x20
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let mut registry = rbpf::registry::ProgramRegistry::new();
+    registry.load("classifier", classifier);
+    registry.load("action1", action1);
+    registry.load("action2", action2);
+    registry.create_prog_array(1, 3);
+    registry.set_tail_call(1, 1, "action1");
+    registry.set_tail_call(1, 2, "action2");
+
+    assert_eq!(registry.prog_exec("classifier", &mut [1, 5], &mut []), 0x15);
+    assert_eq!(registry.prog_exec("classifier", &mut [2, 5], &mut []), 0x25);
+    // Empty slot, and index out of the bounds of the array: the classifier returns 0.
+    assert_eq!(registry.prog_exec("classifier", &mut [0, 5], &mut []), 0);
+    assert_eq!(registry.prog_exec("classifier", &mut [3, 5], &mut []), 0);
+
+    // Replacing a program is visible through the program array.
+    registry.load("action1", vec![
+        0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]);
+    assert_eq!(registry.prog_exec("classifier", &mut [1, 5], &mut []), 0x2a);
+}
+
+static REGISTRY_CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn count_calls(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    REGISTRY_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    0
+}
+
+// A program tail-calling itself: the chain must stop after `ebpf::MAX_TAIL_CALL_CNT` tail calls,
+// at which point the caller resumes after the call instruction.
+#[test]
+fn test_registry_tail_call_limit() {
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+        0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+        0xb7, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, 0
+        0x85, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // call 12 (tail call)
+        0xb7, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, // mov64 r0, 0xff
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let mut registry = rbpf::registry::ProgramRegistry::new();
+    registry.load("loop", prog);
+    registry.register_helper(1, count_calls);
+    registry.create_prog_array(0, 1);
+    registry.set_tail_call(0, 0, "loop");
+
+    assert_eq!(registry.prog_exec("loop", &mut [], &mut []), 0xff);
+    assert_eq!(REGISTRY_CALLS.load(std::sync::atomic::Ordering::SeqCst),
+               rbpf::ebpf::MAX_TAIL_CALL_CNT + 1);
+}
+
+#[test]
+#[should_panic(expected = "Error: no program named \"action\" in registry")]
+fn test_registry_unknown_program() {
+    let mut registry = rbpf::registry::ProgramRegistry::new();
+    registry.create_prog_array(0, 1);
+    registry.set_tail_call(0, 0, "action");
+}
+
+fn trace_test_prog() -> Vec<u8> {
+    vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x71, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1+1]
+        0x63, 0x2a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r2
+        0x61, 0xa0, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r10-4]
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]
+}
+
+#[test]
+fn test_trace_log() {
+    use rbpf::trace::{AccessKind, RegDelta};
+
+    let prog = trace_test_prog();
+    let mut mem = vec![0xaa, 0x42];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let mut log = rbpf::trace::TraceLog::new();
+    assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), 0x43);
+
+    let entries = log.entries();
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries.iter().map(|e| e.insn_ptr).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+
+    // R0 is already 0: the first instruction changes nothing.
+    assert!(entries[0].reg_deltas.is_empty());
+    assert!(entries[0].mem_access.is_none());
+
+    let load = entries[1].mem_access.unwrap();
+    assert_eq!(load.kind, AccessKind::Load);
+    assert_eq!(load.addr, mem.as_ptr() as u64 + 1);
+    assert_eq!(load.len, 1);
+    assert_eq!(load.value, 0x42);
+    assert_eq!(entries[1].reg_deltas, vec![RegDelta { reg: 2, old: 0, new: 0x42 }]);
+
+    let store = entries[2].mem_access.unwrap();
+    assert_eq!(store.kind, AccessKind::Store);
+    assert_eq!(store.len, 4);
+    assert_eq!(store.value, 0x42);
+    assert!(entries[2].reg_deltas.is_empty());
+
+    // The stored value is read back from the stack.
+    assert_eq!(entries[3].mem_access.unwrap().addr, store.addr);
+    assert_eq!(entries[4].reg_deltas, vec![RegDelta { reg: 0, old: 0x42, new: 0x43 }]);
+    assert_eq!(entries[5].insn.opc, rbpf::ebpf::EXIT);
+}
+
+struct LogAndBinary(rbpf::trace::TraceLog, rbpf::trace::BinaryWriter<Vec<u8>>);
+
+impl rbpf::trace::Tracer for LogAndBinary {
+    fn trace(&mut self, entry: &rbpf::trace::TraceEntry) {
+        self.0.trace(entry);
+        self.1.trace(entry);
+    }
+}
+
+#[test]
+fn test_trace_writers() {
+    let prog = trace_test_prog();
+    let mut mem = vec![0xaa, 0x42];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+
+    // The stack is allocated anew for each run: record both traces from the same run, so that
+    // the addresses of stack accesses match.
+    let mut both = LogAndBinary(rbpf::trace::TraceLog::new(), rbpf::trace::BinaryWriter::new(vec![]));
+    vm.prog_exec_trace(&mut mem, &mut both);
+    let LogAndBinary(log, binary) = both;
+    let bytes = binary.finish().unwrap();
+    assert_eq!(&bytes[..4], rbpf::trace::BINARY_MAGIC);
+    assert_eq!(rbpf::trace::read_binary(&bytes[..]).unwrap(), log.entries());
+
+    // Truncated traces are rejected.
+    assert!(rbpf::trace::read_binary(&bytes[..bytes.len() - 1]).is_err());
+    assert!(rbpf::trace::read_binary(&b"RBTX\x01"[..]).is_err());
+
+    let mut json = rbpf::trace::JsonLinesWriter::new(vec![]);
+    vm.prog_exec_trace(&mut mem, &mut json);
+    let json = String::from_utf8(json.finish().unwrap()).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert!(lines.iter().all(|l| l.starts_with('{') && l.ends_with('}')));
+    assert!(lines[2].contains("\"mem\":{\"kind\":\"store\","));
+    assert!(lines[2].ends_with("\"len\":4,\"value\":\"0x42\"}}"));
+
+    let mut text = rbpf::trace::TextWriter::new(vec![]);
+    vm.prog_exec_trace(&mut mem, &mut text);
+    let text = String::from_utf8(text.finish().unwrap()).unwrap();
+    assert_eq!(text.lines().nth(4).unwrap(),
+               "    4: opc 0x07 dst r0 src r0 off +0 imm 0x1 | r0: 0x42 -> 0x43");
+}
+
+#[test]
+fn test_trace_chrome() {
+    use rbpf::ebpf::Insn;
+    use rbpf::trace::{ChromeTraceWriter, TraceEntry, Tracer};
+
+    let prog = trace_test_prog();
+    let mut mem = vec![0xaa, 0x42];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let mut chrome = ChromeTraceWriter::new(vec![]);
+    chrome.set_program("test");
+    vm.prog_exec_trace(&mut mem, &mut chrome);
+    vm.prog_exec_trace(&mut mem, &mut chrome);
+
+    // A run with a tail call, converted from entries.
+    let regs = [0; 11];
+    let call = Insn { opc: rbpf::ebpf::CALL, dst: 0, src: 0, off: 0, imm: 12 };
+    let exit = Insn { opc: rbpf::ebpf::EXIT, dst: 0, src: 0, off: 0, imm: 0 };
+    chrome.set_program("entry");
+    chrome.trace(&TraceEntry::new(0, call, &regs, &regs));
+    chrome.trace(&TraceEntry::new(0, exit, &regs, &regs));
+
+    let json = String::from_utf8(chrome.finish().unwrap()).unwrap();
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines[0], "{\"traceEvents\":[");
+    assert_eq!(*lines.last().unwrap(), "]}");
+    assert!(lines[1..lines.len() - 2].iter().all(|l| l.starts_with('{') && l.ends_with("},")));
+
+    // Both runs of the first program are on the same track, one after the other.
+    let tracks: Vec<&&str> = lines.iter().filter(|l| l.contains("\"thread_name\"")).collect();
+    assert_eq!(tracks.len(), 3);
+    assert!(tracks[0].contains("\"tid\":1,\"args\":{\"name\":\"test\"}"));
+    assert!(tracks[2].contains("\"tid\":3,\"args\":{\"name\":\"entry (tail call 1)\"}"));
+    let runs: Vec<&&str> = lines.iter().filter(|l| l.contains("\"cat\":\"run\"")).collect();
+    assert_eq!(runs.len(), 4);
+    assert!(runs[0].starts_with("{\"name\":\"test\",\"cat\":\"run\",\"ph\":\"X\",\"ts\":0,\"dur\":6,\"pid\":1,\"tid\":1}"));
+    assert!(runs[1].starts_with("{\"name\":\"test\",\"cat\":\"run\",\"ph\":\"X\",\"ts\":6,\"dur\":6,\"pid\":1,\"tid\":1}"));
+    assert!(runs[2].contains("\"ts\":12,\"dur\":1,\"pid\":1,\"tid\":2}"));
+    assert!(runs[3].contains("\"ts\":13,\"dur\":1,\"pid\":1,\"tid\":3}"));
+    assert!(lines.iter().any(|l| l.starts_with("{\"name\":\"add64\",\"cat\":\"insn\",\"ph\":\"X\",\"ts\":4,\"dur\":1,\"pid\":1,\"tid\":1,\"args\":{\"insn_ptr\":4,\"r0\":\"0x43\"}}")));
+}
+
+fn helper_hook_test_prog() -> Vec<u8> {
+    vec![
+        0xb7, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // mov64 r1, 16
+        0xb7, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r2, 2
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0xbf, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r0
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]
+}
+
+// Helper key, arguments, and return value if the helper was called.
+type HelperCall = (u32, [u64; 5], Option<u64>);
+
+// Records all calls, and skips the calls with the given argument in R1.
+struct RecordingHook {
+    calls:   std::cell::RefCell<Vec<HelperCall>>,
+    skip_r1: Option<u64>,
+}
+
+impl rbpf::helpers::HelperHook for RecordingHook {
+    fn before_call(&self, key: u32, args: &[u64; 5]) -> rbpf::helpers::HookVerdict {
+        self.calls.borrow_mut().push((key, *args, None));
+        match self.skip_r1 {
+            Some(r1) if r1 == args[0] => rbpf::helpers::HookVerdict::Skip(7),
+            _                         => rbpf::helpers::HookVerdict::Allow,
+        }
+    }
+
+    fn after_call(&self, key: u32, args: &[u64; 5], ret: u64) {
+        let mut calls = self.calls.borrow_mut();
+        let last = calls.last_mut().unwrap();
+        assert_eq!((last.0, last.1), (key, *args));
+        last.2 = Some(ret);
+    }
+}
+
+#[test]
+fn test_helper_hook() {
+    let prog = helper_hook_test_prog();
+    let hook = RecordingHook { calls: std::cell::RefCell::new(vec![]), skip_r1: None };
+    let skip_hook = RecordingHook { calls: std::cell::RefCell::new(vec![]), skip_r1: Some(4) };
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_helper(1, helpers::sqrti);
+    vm.set_helper_hook(Box::new(&hook));
+    assert_eq!(vm.prog_exec(&mut vec![]), 2);
+    assert_eq!(*hook.calls.borrow(), vec![
+        (1, [16, 2, 0, 0, 0], Some(4)),
+        (1, [4, 2, 0, 0, 0], Some(2)),
+    ]);
+
+    // The second call is skipped: the helper does not run, and R0 takes the value chosen by the
+    // hook.
+    vm.set_helper_hook(Box::new(&skip_hook));
+    assert_eq!(vm.prog_exec(&mut vec![]), 7);
+    assert_eq!(*skip_hook.calls.borrow(), vec![
+        (1, [16, 2, 0, 0, 0], Some(4)),
+        (1, [4, 2, 0, 0, 0], None),
+    ]);
+}
+
+struct DenyAll;
+
+impl rbpf::helpers::HelperHook for DenyAll {
+    fn before_call(&self, _key: u32, _args: &[u64; 5]) -> rbpf::helpers::HookVerdict {
+        rbpf::helpers::HookVerdict::Deny
+    }
+}
+
+#[test]
+#[should_panic(expected = "Error: call to helper function 0x1 denied by hook (insn #2)")]
+fn test_helper_hook_deny() {
+    let prog = helper_hook_test_prog();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_helper(1, helpers::sqrti);
+    vm.set_helper_hook(Box::new(DenyAll));
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "Error: cannot run a JIT-compiled program with a helper hook attached")]
+fn test_helper_hook_jit() {
+    let prog = helper_hook_test_prog();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_helper(1, helpers::sqrti);
+    vm.jit_compile();
+    vm.set_helper_hook(Box::new(DenyAll));
+    vm.prog_exec_jit();
+}
+
+// Denies the calls to the helper with the given key, after recording them.
+struct DenyHelper {
+    key:   u32,
+    calls: std::cell::RefCell<Vec<u32>>,
+}
+
+impl rbpf::helpers::HelperHook for DenyHelper {
+    fn before_call(&self, key: u32, _args: &[u64; 5]) -> rbpf::helpers::HookVerdict {
+        self.calls.borrow_mut().push(key);
+        match key {
+            _ if key == self.key => rbpf::helpers::HookVerdict::Deny,
+            _                    => rbpf::helpers::HookVerdict::Allow,
+        }
+    }
+}
+
+#[test]
+fn test_helper_hook_map_helpers() {
+    use rbpf::error::EbpfError;
+    use rbpf::helpers::{BPF_MAP_LOOKUP_ELEM_IDX, BPF_MAP_UPDATE_ELEM_IDX};
+    use rbpf::maps::{Map, MapType};
+
+    // Stores 42 at key 1 in map 0, then looks it up.
+    let prog = vec![
+        0x62, 0x0a, 0xfc, 0xff, 0x01, 0x00, 0x00, 0x00, // stw [r10-4], 1
+        0x7a, 0x0a, 0xf0, 0xff, 0x2a, 0x00, 0x00, 0x00, // stdw [r10-16], 42
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r3, -16
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, 0
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call 2
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let map = std::sync::Arc::new(Map::new(MapType::Hash, 4, 8, 4));
+    let hook = DenyHelper { key: BPF_MAP_LOOKUP_ELEM_IDX, calls: std::cell::RefCell::new(vec![]) };
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(map.clone());
+    vm.set_helper_hook(Box::new(&hook));
+
+    // The update runs, the lookup is denied.
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::HelperDenied { pc: 12, key: BPF_MAP_LOOKUP_ELEM_IDX }));
+    assert_eq!(*hook.calls.borrow(), vec![BPF_MAP_UPDATE_ELEM_IDX, BPF_MAP_LOOKUP_ELEM_IDX]);
+    assert_eq!(map.lookup(&1u32.to_ne_bytes()), Some(42u64.to_ne_bytes().to_vec()));
+}
+
+#[test]
+fn test_try_prog_exec_errors() {
+    use rbpf::error::EbpfError;
+    use rbpf::trace::AccessKind;
+
+    let prog = helper_hook_test_prog();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::UnknownHelper { key: 1 }));
+    vm.register_helper(1, helpers::sqrti);
+    assert!(vm.try_prog_exec().is_ok());
+    vm.set_helper_hook(Box::new(DenyAll));
+    let err = vm.try_prog_exec().unwrap_err();
+    assert_eq!(err, EbpfError::HelperDenied { pc: 2, key: 1 });
+    assert_eq!(err.to_string(), "Error: call to helper function 0x1 denied by hook (insn #2)");
+
+    let prog = vec![
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r1, 0
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+        0x9f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mod r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::DivideByZero { pc: 2 }));
+
+    let prog = vec![
+        0x72, 0x01, 0x06, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r1+6], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0u8; 6];
+    let addr = mem.as_ptr() as u64 + 6;
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let err = vm.try_prog_exec(&mut mem).unwrap_err();
+    assert_eq!(err.pc(), Some(0));
+    match err {
+        EbpfError::OutOfBounds { kind, addr: a, len, .. } => {
+            assert_eq!((kind, a, len), (AccessKind::Store, addr, 1));
+        },
+        _ => panic!("expected an out-of-bounds access, got {:?}", err),
+    }
+    assert!(err.to_string().starts_with("Error: out of bounds memory store (insn #1)"));
+
+    // Programs are verified before being run, so they cannot jump out of the code; the
+    // interpreter still checks it.
+    let err = EbpfError::JumpOutOfBounds { pc: 3, target: -2 };
+    assert_eq!(err.pc(), Some(3));
+    assert_eq!(err.to_string(), "Error: jump out of code to #-2 (insn #3)");
+}
+
+#[test]
+fn test_try_check_and_jit_compile() {
+    use rbpf::error::EbpfError;
+
+    let prog = vec![
+        0xb7, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r10, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(rbpf::verifier::try_check(&prog), Err(EbpfError::VerifierError(
+        "[Verifier] Error: cannot write into register r10 (insn #0)".to_string())));
+
+    // Legacy packet loads, atomic adds and the tail call opcode are not supported. The
+    // interpreter rejects them as well, when running programs not verified.
+    for &(opc, name) in [(ebpf::LD_ABS_W, "0x20"), (ebpf::LD_IND_B, "0x50"), (ebpf::ST_DW_XADD, "0xdb"),
+                         (ebpf::TAIL_CALL, "0x8d")].iter() {
+        let prog = vec![
+            opc,  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        assert_eq!(rbpf::verifier::try_check(&prog), Err(EbpfError::VerifierError(
+            format!("[Verifier] Error: unsupported eBPF opcode {} (insn #0)", name))));
+    }
+    let err = EbpfError::UnsupportedInstruction { pc: 0, opc: ebpf::LD_ABS_W };
+    assert_eq!(err.pc(), Some(0));
+    assert_eq!(err.to_string(), "Error: unsupported instruction, opcode 0x20 (insn #0)");
+
+    let prog = helper_hook_test_prog();
+    assert_eq!(rbpf::verifier::try_check(&prog), Ok(()));
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    let err = vm.try_jit_compile().unwrap_err();
+    assert_eq!(err, EbpfError::JitError("[JIT] Error: unknown helper function (id: 0x1)".to_string()));
+    assert_eq!(err.pc(), None);
+    vm.register_helper(1, helpers::sqrti);
+    assert_eq!(vm.try_jit_compile(), Ok(()));
+}
+
+// 32-bit arithmetic only considers the lower 32 bits of the operands, and zeroes the upper 32 bits
+// of the destination, as described in the kernel documentation (Documentation/bpf/standardization/
+// instruction-set.rst). The interpreter and the JIT must both follow it.
+#[test]
+fn test_alu32_conformance() {
+    let cases = [
+        // opcode, dst, src or immediate, result
+        (ebpf::ADD32_REG,  0xffff_ffffu64,         1u64,                  0u64),
+        (ebpf::ADD32_IMM,  0x1_0000_0000,          (-1i64) as u64,        0xffff_ffff),
+        (ebpf::SUB32_REG,  0,                      1,                     0xffff_ffff),
+        (ebpf::SUB32_IMM,  0x5_0000_0001,          2,                     0xffff_ffff),
+        (ebpf::MUL32_REG,  0x8000_0000,            2,                     0),
+        (ebpf::MUL32_IMM,  0xffff_ffff,            (-1i64) as u64,        1),
+        (ebpf::DIV32_REG,  0x1_0000_0006,          0x7_0000_0003,         2),
+        (ebpf::MOD32_IMM,  0xffff_ffff_ffff_fff9,  (-4i64) as u64,        0xffff_fff9),
+        (ebpf::OR32_IMM,   0xffff_ffff_0000_0000,  1,                     1),
+        (ebpf::AND32_REG,  0xffff_ffff_ffff_ffff,  0xffff_ffff_0000_ffff, 0xffff),
+        (ebpf::XOR32_IMM,  0xffff_ffff_ffff_ffff,  0x0f,                  0xffff_fff0),
+        (ebpf::LSH32_IMM,  0xffff_ffff_8000_0001,  1,                     2),
+        (ebpf::RSH32_REG,  0xffff_ffff_8000_0000,  31,                    1),
+        (ebpf::ARSH32_IMM, 0x8000_0000,            4,                     0xf800_0000),
+        (ebpf::NEG32,      1,                      0,                     0xffff_ffff),
+        (ebpf::MOV32_IMM,  0x1234,                 (-1i64) as u64,        0xffff_ffff),
+        (ebpf::MOV32_REG,  0,                      0xffff_ffff_ffff_ffff, 0xffff_ffff),
+        (ebpf::LE,         0x1_2345_6789,          16,                    0x6789),
+        (ebpf::LE,         0x1_2345_6789,          32,                    0x2345_6789),
+        (ebpf::NEG64,      i64::MIN as u64,        0,                     i64::MIN as u64),
+    ];
+    for &(opc, dst, src, expected) in cases.iter() {
+        let imm = src as u32;
+        let prog = vec![
+            0x18, 0x00, 0x00, 0x00, dst as u8, (dst >> 8) as u8, (dst >> 16) as u8, (dst >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (dst >> 32) as u8, (dst >> 40) as u8, (dst >> 48) as u8, (dst >> 56) as u8,
+            0x18, 0x01, 0x00, 0x00, src as u8, (src >> 8) as u8, (src >> 16) as u8, (src >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (src >> 32) as u8, (src >> 40) as u8, (src >> 48) as u8, (src >> 56) as u8,
+            opc,  0x10, 0x00, 0x00, imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        assert_eq!(vm.prog_exec(), expected, "interpreter, opcode {:#x}, dst {:#x}", opc, dst);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected, "JIT, opcode {:#x}, dst {:#x}", opc, dst);
+    }
+}
+
+// Contents of `.BTF` and `.BTF.ext` sections for a program in section "socket", with two line
+// info records: instructions 0 and 1 at filter.c:40:5, instructions 2 and above at filter.c:42.
+fn btf_test_sections() -> (Vec<u8>, Vec<u8>) {
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&x.to_le_bytes());
+    }
+
+    let strings = b"\0socket\0filter.c\0";
+    let mut btf = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, 0, 0, strings.len() as u32] {
+        push_u32(&mut btf, *x);
+    }
+    btf.extend_from_slice(strings);
+
+    let mut lines = vec![];
+    for x in &[16, 1, 2, 0, 8, 0, 40 << 10 | 5, 16, 8, 0, 42 << 10] {
+        push_u32(&mut lines, *x);
+    }
+    let mut btf_ext = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, 0, 0, lines.len() as u32] {
+        push_u32(&mut btf_ext, *x);
+    }
+    btf_ext.extend_from_slice(&lines);
+
+    (btf, btf_ext)
+}
+
+#[test]
+fn test_btf_line_info() {
+    let (btf, btf_ext) = btf_test_sections();
+    let info = rbpf::btf::LineInfo::parse(&btf, &btf_ext, "socket").unwrap();
+    assert_eq!(info.len(), 2);
+    assert_eq!(info.lookup(1).unwrap().to_string(), "filter.c:40:5");
+    assert_eq!(info.lookup(2).unwrap().to_string(), "filter.c:42");
+    assert_eq!(info.lookup(9).unwrap().line, 42);
+
+    assert!(rbpf::btf::LineInfo::parse(&btf, &btf_ext, "xdp").is_err());
+    assert!(rbpf::btf::LineInfo::parse(&btf[..20], &btf_ext, "socket").is_err());
+    assert!(rbpf::btf::LineInfo::parse(&btf_ext[4..], &btf_ext, "socket").is_err());
+
+    // Source locations are appended to the lines of text traces.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    let mut tracer = rbpf::trace::TextWriter::new(vec![]).with_line_info(info);
+    vm.prog_exec_trace(&mut tracer);
+    let text = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    assert!(text.lines().all(|l| l.ends_with(" | filter.c:40:5")));
+}
+
+#[test]
+#[should_panic(expected = "Error: division by 0 (insn #2 at filter.c:42)")]
+fn test_btf_line_info_error() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (btf, btf_ext) = btf_test_sections();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_line_info(rbpf::btf::LineInfo::parse(&btf, &btf_ext, "socket").unwrap());
+    vm.prog_exec(&mut vec![]);
+}
+
+// The sections of `btf_test_sections()`, with two functions in section "socket": "allow" at
+// instruction 0, and "drop" at instruction 2.
+fn btf_function_sections() -> (Vec<u8>, Vec<u8>) {
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&x.to_le_bytes());
+    }
+
+    // Types: 1 is a function prototype, 2 and 3 are functions "allow" and "drop".
+    let strings = b"\0socket\0filter.c\0allow\0drop\0";
+    let types = [0, 13 << 24, 0, 17, 12 << 24, 1, 23, 12 << 24, 1];
+    let mut btf = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, 36, 36, strings.len() as u32] {
+        push_u32(&mut btf, *x);
+    }
+    for x in &types {
+        push_u32(&mut btf, *x);
+    }
+    btf.extend_from_slice(strings);
+
+    let mut funcs = vec![];
+    for x in &[8, 1, 2, 0, 2, 16, 3] {
+        push_u32(&mut funcs, *x);
+    }
+    let mut lines = vec![];
+    for x in &[16, 1, 1, 24, 8, 0, 42 << 10] {
+        push_u32(&mut lines, *x);
+    }
+    let mut btf_ext = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, funcs.len() as u32, funcs.len() as u32, lines.len() as u32] {
+        push_u32(&mut btf_ext, *x);
+    }
+    btf_ext.extend_from_slice(&funcs);
+    btf_ext.extend_from_slice(&lines);
+
+    (btf, btf_ext)
+}
+
+// Two programs in the same section, the second one divides by 0.
+fn btf_function_section() -> Vec<u8> {
+    vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]
+}
+
+#[test]
+fn test_btf_functions() {
+    use rbpf::btf::LineInfo;
+
+    let (btf, btf_ext) = btf_function_sections();
+    let info = LineInfo::parse(&btf, &btf_ext, "socket").unwrap();
+    assert_eq!(info.functions(), &[(0, "allow".to_string()), (2, "drop".to_string())]);
+    assert_eq!(info.function(1), Some("allow"));
+    assert_eq!(info.function(4), Some("drop"));
+    assert_eq!(info.function_start("drop"), Some(2));
+
+    // A function record must reference a function type.
+    let mut bad_ext = btf_ext.clone();
+    bad_ext[24 + 24] = 1;
+    assert!(LineInfo::parse(&btf, &bad_ext, "socket").is_err());
+
+    let section = btf_function_section();
+    let allow = info.function_code(&section, "allow").unwrap().to_vec();
+    assert_eq!(allow.len(), 16);
+    let vm = rbpf::EbpfVmNoData::new(&allow);
+    assert_eq!(vm.prog_exec(), 1);
+
+    // Functions are named in traces.
+    let mut tracer = rbpf::trace::TextWriter::new(vec![]).with_line_info(info);
+    vm.prog_exec_trace(&mut tracer);
+    let text = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    assert!(text.lines().all(|l| l.ends_with(" | allow")));
+}
+
+#[test]
+#[should_panic(expected = "Error: division by 0 (insn #4 in drop at filter.c:42)")]
+fn test_btf_functions_error() {
+    // Run into the second program, replacing the exit of the first one with `ja +0`.
+    let mut prog = btf_function_section();
+    prog[8] = 0x05;
+    let (btf, btf_ext) = btf_function_sections();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_line_info(rbpf::btf::LineInfo::parse(&btf, &btf_ext, "socket").unwrap());
+    vm.prog_exec();
+}
+
+#[test]
+fn test_btf_map_definitions() {
+    use rbpf::btf::{self, MapDefinition};
+    use rbpf::maps::MapType;
+
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&x.to_le_bytes());
+    }
+
+    // `.BTF` section defining, in section `.maps`:
+    //
+    //     struct { __uint(type, BPF_MAP_TYPE_HASH); __uint(max_entries, 16);
+    //              __type(key, int); __type(value, u64); } counters SEC(".maps");
+    //     struct { __uint(type, BPF_MAP_TYPE_ARRAY); __uint(key_size, 4);
+    //              __uint(value_size, 12); __uint(max_entries, 2); } config SEC(".maps");
+    let strings = b"\0int\0u64\0long\0type\0max_entries\0key\0value\0key_size\0value_size\0\
+                    counters\0config\0.maps\0";
+    let name = |s: &str| {
+        let s = format!("\0{}\0", s);
+        strings.windows(s.len()).position(|w| w == s.as_bytes()).unwrap() as u32 + 1
+    };
+    let kind = |kind: u32, vlen: u32| kind << 24 | vlen;
+    let mut types = vec![];
+    for x in &[
+        name("int"), kind(1, 0), 4, 32,                              // 1: int
+        name("long"), kind(1, 0), 8, 64,                             // 2: long
+        name("u64"), kind(8, 0), 2,                                  // 3: typedef long u64
+        0, kind(3, 0), 0, 1, 1, 1,                                   // 4: int[1] (hash)
+        0, kind(2, 0), 4,                                            // 5: int (*)[1]
+        0, kind(3, 0), 0, 1, 1, 16,                                  // 6: int[16]
+        0, kind(2, 0), 6,                                            // 7: int (*)[16]
+        0, kind(2, 0), 1,                                            // 8: int *
+        0, kind(2, 0), 3,                                            // 9: u64 *
+        0, kind(4, 4), 32,                                           // 10: counters
+            name("type"), 5, 0, name("max_entries"), 7, 64,
+            name("key"), 8, 128, name("value"), 9, 192,
+        0, kind(3, 0), 0, 1, 1, 2,                                   // 11: int[2] (array)
+        0, kind(2, 0), 11,                                           // 12: int (*)[2]
+        0, kind(3, 0), 0, 1, 1, 4,                                   // 13: int[4]
+        0, kind(2, 0), 13,                                           // 14: int (*)[4]
+        0, kind(3, 0), 0, 1, 1, 12,                                  // 15: int[12]
+        0, kind(2, 0), 15,                                           // 16: int (*)[12]
+        0, kind(4, 4), 32,                                           // 17: config
+            name("type"), 12, 0, name("key_size"), 14, 64,
+            name("value_size"), 16, 128, name("max_entries"), 12, 192,
+        name("counters"), kind(14, 0), 10, 1,                        // 18: counters
+        name("config"), kind(14, 0), 17, 1,                          // 19: config
+        name(".maps"), kind(15, 2), 64, 18, 0, 32, 19, 32, 32,       // 20: .maps
+    ] {
+        push_u32(&mut types, *x);
+    }
+    let mut btf = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, types.len() as u32, types.len() as u32, strings.len() as u32] {
+        push_u32(&mut btf, *x);
+    }
+    btf.extend_from_slice(&types);
+    btf.extend_from_slice(strings);
+
+    let definitions = MapDefinition::parse(&btf).unwrap();
+    assert_eq!(definitions, vec![
+        MapDefinition { name: "counters".to_string(), map_type: MapType::Hash, key_size: 4,
+                        value_size: 8, max_entries: 16, offset: 0 },
+        MapDefinition { name: "config".to_string(), map_type: MapType::Array, key_size: 4,
+                        value_size: 12, max_entries: 2, offset: 32 },
+    ]);
+    assert_eq!(definitions[1].create().value_size(), 12);
+    assert!(MapDefinition::parse(&btf[..btf.len() - 10]).is_err());
+
+    // Returns the sum of the identifiers of the maps.
+    let mut prog = vec![
+        0x18, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, config
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x18, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r2, counters
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, r1
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(btf::relocate_maps(&mut prog, &[(32, 0)], &definitions).is_err());
+    assert!(btf::relocate_maps(&mut prog, &[(0, 8)], &definitions).is_err());
+    btf::relocate_maps(&mut prog, &[(0, 32), (16, 0)], &definitions).unwrap();
+    assert_eq!(&prog[..8], &[0x18, 0x11, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    for definition in &definitions {
+        vm.register_map(std::sync::Arc::new(definition.create()));
+    }
+    assert_eq!(vm.prog_exec(), 1);
+
+    // Legacy definitions.
+    let mut maps = vec![];
+    for x in &[1, 4, 8, 16, 0, 9, 4, 4, 4, 0] {
+        push_u32(&mut maps, *x);
+    }
+    assert!(MapDefinition::parse_legacy(&maps).is_err());
+    let definitions = MapDefinition::parse_legacy(&maps[..20]).unwrap();
+    assert_eq!((definitions[0].map_type, definitions[0].key_size), (MapType::Hash, 4));
+    assert!(MapDefinition::parse_legacy(&maps[..30]).is_err());
+}
+
+#[test]
+fn test_btf_globals() {
+    use rbpf::btf::{self, Globals};
+    use rbpf::maps::{Map, MapType};
+
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend_from_slice(&x.to_le_bytes());
+    }
+
+    // `.BTF` section describing, in section `.data`:
+    //
+    //     __u64 limit = 100;
+    //     __u32 verbose = 1;
+    let strings = b"\0u64\0u32\0limit\0verbose\0.data\0";
+    let mut types = vec![];
+    for x in &[
+        1, 1 << 24, 8, 64,                              // 1: int u64
+        5, 1 << 24, 4, 32,                              // 2: int u32
+        9, 14 << 24, 1, 1,                              // 3: var limit
+        15, 14 << 24, 2, 1,                             // 4: var verbose
+        23, 15 << 24 | 2, 16, 3, 0, 8, 4, 8, 4,         // 5: section .data
+    ] {
+        push_u32(&mut types, *x);
+    }
+    let mut btf = vec![0x9f, 0xeb, 0x01, 0x00];
+    for x in &[24, 0, types.len() as u32, types.len() as u32, strings.len() as u32] {
+        push_u32(&mut btf, *x);
+    }
+    btf.extend_from_slice(&types);
+    btf.extend_from_slice(strings);
+    let mut data = vec![];
+    data.extend_from_slice(&100u64.to_ne_bytes());
+    data.extend_from_slice(&1u32.to_ne_bytes());
+    data.extend_from_slice(&[0; 4]);
+
+    assert!(Globals::new(&btf, ".bss", &[]).is_err());
+    assert!(Globals::new(&btf, ".data", &data[..12]).is_err());
+    let globals = Globals::new(&btf, ".data", &data).unwrap();
+    assert_eq!(globals.variables().len(), 2);
+    assert_eq!((globals.variables()[1].offset, globals.variables()[1].size), (8, 4));
+    assert_eq!(globals.read::<u64>("limit"), Some(100));
+    assert_eq!(globals.read::<u32>("verbose"), Some(1));
+    assert_eq!(globals.read::<u32>("limit"), None);
+    assert_eq!(globals.read::<u32>("debug"), None);
+    assert!(globals.write("limit", 1u32).is_err());
+
+    // Returns `limit` if `verbose` is set, 0 otherwise. The first relocation refers to the
+    // section, the second one to the variable.
+    let mut prog = vec![
+        0x18, 0x01, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // lddw r1, .data + 8 (verbose)
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x18, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r2, limit
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+        0x61, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r1, [r1]
+        0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+        0x79, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r2]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(btf::relocate_globals(&mut prog, &[(8, 0)], 1).is_err());
+    btf::relocate_globals(&mut prog, &[(0, 0), (16, 0)], 1).unwrap();
+    assert_eq!(&prog[..16], &[0x18, 0x21, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+                              0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00][..]);
+    assert_eq!(rbpf::analysis::dependencies(&prog).maps.into_iter().collect::<Vec<_>>(), vec![1]);
+
+    // The map of the variables is not the first one registered.
+    let hash = std::sync::Arc::new(Map::new(MapType::Hash, 4, 8, 4));
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(hash.clone());
+    vm.register_map(globals.map().clone());
+    assert_eq!(vm.prog_exec(), 100);
+    globals.write("limit", 42u64).unwrap();
+    assert_eq!(vm.prog_exec(), 42);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), 42);
+    globals.write("verbose", 0u32).unwrap();
+    assert_eq!(vm.prog_exec(), 0);
+    assert_eq!(vm.prog_exec_jit(), 0);
+
+    // The values of maps other than array maps cannot be referenced, nor offsets out of the
+    // values by JIT-compiled programs.
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(globals.map().clone());
+    vm.register_map(hash);
+    assert_eq!(vm.try_prog_exec(), Err(rbpf::error::EbpfError::UnknownMap { pc: 0, id: 1 }));
+    assert!(vm.try_jit_compile().is_err());
+    let jit_compiles = |prog: &Vec<u8>| {
+        let mut vm = rbpf::EbpfVmNoData::new(prog);
+        vm.register_map(globals.map().clone());
+        vm.try_jit_compile().is_ok()
+    };
+    let mut prog = prog.clone();
+    prog[4] = 0;
+    prog[20] = 0;
+    assert!(jit_compiles(&prog));
+    prog[12] = 16;
+    assert!(!jit_compiles(&prog));
+}
+
+#[test]
+#[should_panic(expected = "Error: out of bounds memory store (insn #1 at filter.c:40:5)")]
+fn test_btf_line_info_mem_error() {
+    let prog = vec![
+        0x72, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stb [r10], 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (btf, btf_ext) = btf_test_sections();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_line_info(rbpf::btf::LineInfo::parse(&btf, &btf_ext, "socket").unwrap());
+    vm.prog_exec();
+}
+
+#[test]
+fn test_cfg_dot() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x15, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +3
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x07, 0x01, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // add64 r1, -1
+        0x05, 0x00, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // ja -4
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let blocks = rbpf::analysis::basic_blocks(&prog);
+    assert_eq!(blocks, vec![
+        rbpf::analysis::BasicBlock { start: 0, end: 1, branch: None,    fallthrough: Some(1) },
+        rbpf::analysis::BasicBlock { start: 1, end: 2, branch: Some(5), fallthrough: Some(2) },
+        rbpf::analysis::BasicBlock { start: 2, end: 5, branch: Some(1), fallthrough: None },
+        rbpf::analysis::BasicBlock { start: 5, end: 6, branch: None,    fallthrough: None },
+    ]);
+
+    assert_eq!(rbpf::analysis::cfg_dot(&prog), "\
+digraph cfg {
+    node [shape=box, fontname=\"monospace\"];
+    b0 [label=\"0: mov64 r0, 0x0\\l\"];
+    b1 [label=\"1: jeq r1, 0x0, +0x3\\l\"];
+    b2 [label=\"2: add64 r0, 0x1\\l3: add64 r1, 0xffffffff\\l4: ja -0x4\\l\"];
+    b5 [label=\"5: exit\\l\"];
+    b0 -> b1;
+    b1 -> b5 [label=\"true\"];
+    b1 -> b2 [label=\"false\"];
+    b2 -> b1;
+}
+");
+}
+
+#[test]
+fn test_dependencies() {
+    use rbpf::analysis::CtxAccess;
+
+    let prog = vec![
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+        0x07, 0x02, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // add64 r2, 12
+        0x69, 0x20, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r0, [r2+2]
+        0x73, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x71, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r3, [r1]
+        0x85, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // call 3
+        0x61, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r2]
+        0x7b, 0x2a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let deps = rbpf::analysis::dependencies(&prog);
+    assert_eq!(deps.helpers.iter().cloned().collect::<Vec<_>>(), vec![3]);
+    assert!(deps.maps.is_empty());
+    // R1 is overwritten, and R2 is clobbered by the call: the last loads are not from the context.
+    assert_eq!(deps.ctx_accesses.iter().cloned().collect::<Vec<_>>(), vec![
+        CtxAccess { offset: 1,  size: 1, write: true },
+        CtxAccess { offset: 14, size: 2, write: false },
+    ]);
+    assert!(!deps.ctx_unknown);
+
+    // The two branches lead to different offsets in R1.
+    let prog = vec![
+        0x61, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1]
+        0x15, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r2, 0, +2
+        0x07, 0x01, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // add64 r1, 8
+        0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +1
+        0x07, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // add64 r1, 16
+        0x62, 0x01, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, // stw [r1+4], 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let deps = rbpf::analysis::dependencies(&prog);
+    assert_eq!(deps.ctx_accesses.iter().cloned().collect::<Vec<_>>(),
+               vec![CtxAccess { offset: 0, size: 4, write: false }]);
+    assert!(deps.ctx_unknown);
+
+    // Spilling the pointer to the context.
+    let prog = vec![
+        0x7b, 0x1a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(rbpf::analysis::dependencies(&prog).ctx_unknown);
+}
+
+#[test]
+fn test_lint() {
+    use rbpf::analysis::Lint;
+
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r2, 5
+        0xb7, 0x03, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r3, 7
+        0x7a, 0x0a, 0xf8, 0xff, 0x01, 0x00, 0x00, 0x00, // stdw [r10-8], 1
+        0x62, 0x0a, 0xf0, 0xff, 0x02, 0x00, 0x00, 0x00, // stw [r10-16], 2
+        0x61, 0xa0, 0xf0, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r10-16]
+        0x2d, 0x32, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jgt r2, r3, +1
+        0xb7, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r4, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0x05, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // ja -2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(rbpf::analysis::lint(&prog), vec![
+        Lint::UnusedStackStore { insn_ptr: 2, offset: -8, size: 8 },
+        Lint::ConstantCondition { insn_ptr: 5, taken: false },
+        Lint::DeadStore { insn_ptr: 6, reg: 4 },
+        Lint::Unreachable { start: 8, end: 10 },
+    ]);
+
+    // The helper receives a pointer to the stack, and may read what was stored. The stack pointer
+    // in R1 is clobbered by the call, so the result of `ldxw` is not a load from the stack.
+    let prog = vec![
+        0x7a, 0x0a, 0xf8, 0xff, 0x01, 0x00, 0x00, 0x00, // stdw [r10-8], 1
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x5d, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r0, r0, +1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(rbpf::analysis::lint(&prog), vec![
+        Lint::ConstantCondition { insn_ptr: 4, taken: false },
+    ]);
+}
+
+#[test]
+fn test_convert_ctx_access() {
+    use rbpf::ctx::{self, CtxLayout};
+
+    let layout = CtxLayout::new()
+        .field(0x00, 4, 0x10, 4)
+        .writable_field(0x08, 4, 0x20, 8);
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x69, 0x60, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r0, [r6+2]
+        0x07, 0x06, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // add64 r6, 8
+        0x62, 0x06, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // stw [r6], 0x2a
+        0x61, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1+1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // The last load straddles the two fields.
+    assert_eq!(ctx::try_convert_ctx_access(&prog, &layout).unwrap_err().to_string(),
+               "[Verifier] Error: invalid context access at offset 0x1 of size 4 (insn #4)");
+
+    let prog = prog[..32].iter().chain(prog[40..].iter()).cloned().collect::<Vec<u8>>();
+    let xlated = ctx::convert_ctx_access(&prog, &layout);
+    assert_eq!(xlated, vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x69, 0x60, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r0, [r6+0x12]
+        0x07, 0x06, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // add64 r6, 8
+        0x7a, 0x06, 0x18, 0x00, 0x2a, 0x00, 0x00, 0x00, // stdw [r6+0x18], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]);
+    let mut mbuff = vec![0u8; 0x28];
+    mbuff[0x12] = 0x34;
+    mbuff[0x13] = 0x12;
+    mbuff[0x24] = 0xff;
+    let vm = rbpf::EbpfVmMbuff::new(&xlated);
+    assert_eq!(vm.prog_exec(&mut vec![], &mut mbuff), 0x1234);
+    assert_eq!(&mbuff[0x20..0x28], &[0x2a, 0, 0, 0, 0, 0, 0, 0]);
+
+    // Writing to a read-only field, or to part of a field of another size.
+    let prog = vec![
+        0x73, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1], r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(ctx::try_convert_ctx_access(&prog, &layout).unwrap_err().to_string(),
+               "[Verifier] Error: write to read-only context field at offset 0x0 (insn #0)");
+    let prog = vec![
+        0x73, 0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+8], r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(ctx::try_convert_ctx_access(&prog, &layout).unwrap_err().to_string(),
+               "[Verifier] Error: cannot convert context access at offset 0x8 of size 1 (insn #0)");
+
+    // The pointer to the context is spilled to the stack.
+    let prog = vec![
+        0x7b, 0x1a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(ctx::try_convert_ctx_access(&prog, &layout).is_err());
+}
+
+#[test]
+#[should_panic(expected = "Error: invalid context field size 3")]
+fn test_ctx_layout_invalid_size() {
+    rbpf::ctx::CtxLayout::new().field(0, 3, 0, 4);
+}
+
+#[test]
+fn test_check_bounds() {
+    use rbpf::verifier::{self, Region};
+
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x7b, 0x1a, 0xe8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-24], r1
+        0x25, 0x02, 0x06, 0x00, 0x07, 0x00, 0x00, 0x00, // jgt r2, 7, +6
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r3, -16
+        0x0f, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r3, r2
+        0x72, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // stb [r3], 1
+        0x79, 0xa4, 0xe8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r4, [r10-24]
+        0x61, 0x40, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r4+4]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let report = verifier::check_bounds(&prog, Some(8)).unwrap();
+    assert_eq!(report.accesses.len(), 5);
+    assert!(report.all_proven());
+    // R2 is at most 7 after the jump is not taken.
+    let store = report.get(7).unwrap();
+    assert_eq!((store.region, store.size, store.write), (Region::Stack, 1, true));
+    assert_eq!((store.min_offset, store.max_offset), (-16, -9));
+    // The pointer to the context is restored from the stack.
+    let load = report.get(9).unwrap();
+    assert_eq!((load.region, load.min_offset, load.max_offset), (Region::Ctx, 4, 4));
+
+    // Without the size of the context, accesses to it are not proven.
+    let report = verifier::check_bounds(&prog, None).unwrap();
+    assert!(!report.get(1).unwrap().proven);
+    assert!(report.get(7).unwrap().proven);
+
+    // Bounds are refined in loops.
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+        0x0f, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r3, r2
+        0x72, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stb [r3], 0
+        0x07, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r2, 1
+        0x25, 0x02, 0x01, 0x00, 0x07, 0x00, 0x00, 0x00, // jgt r2, 7, +1
+        0x05, 0x00, 0xf9, 0xff, 0x00, 0x00, 0x00, 0x00, // ja -7
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let report = verifier::check_bounds(&prog, None).unwrap();
+    let store = report.get(4).unwrap();
+    assert_eq!((store.min_offset, store.max_offset, store.proven), (-8, -1, true));
+
+    // The offset may or may not be in bounds: the access needs to be checked at runtime.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x0f, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r3, r2
+        0x72, 0x03, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, // stb [r3-1], 0
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let report = verifier::check_bounds(&prog, Some(1)).unwrap();
+    let store = report.get(3).unwrap();
+    assert_eq!((store.min_offset, store.max_offset, store.proven), (-1, 254, false));
+    assert!(!report.all_proven());
+}
+
+#[test]
+fn test_proven_accesses() {
+    // The accesses to the stack and to the metadata buffer are proven in bounds when loading the
+    // program, the access to packet data is checked at runtime.
+    let prog = vec![
+        0x7b, 0x1a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r1
+        0x79, 0x12, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r1+0x40]
+        0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r2]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let report = rbpf::verifier::check_bounds(&prog, Some(0x58)).unwrap();
+    assert_eq!(report.accesses.iter().map(|access| access.proven).collect::<Vec<_>>(),
+               vec![true, true, false]);
+
+    let mut mem1 = vec![0x2a];
+    let mut mem2 = vec![];
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    assert_eq!(vm.try_prog_exec(&mut mem1), Ok(0x2a));
+    assert_eq!(vm.try_prog_exec(&mut mem2).unwrap_err().pc(), Some(2));
+}
+
+#[test]
+fn test_check_bounds_errors() {
+    use rbpf::verifier;
+
+    let prog = vec![
+        0x79, 0xa0, 0xf8, 0xfd, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-520]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(verifier::check_bounds(&prog, None).unwrap_err(),
+               "[Verifier] Error: out of bounds stack access: offset -520, size 8 (insn #0)");
+
+    let prog = vec![
+        0xbf, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(verifier::check_bounds(&prog, None).unwrap_err(),
+               "[Verifier] Error: R2 is read before being initialized (insn #0)");
+}
+
+#[test]
+fn test_verifier_report() {
+    use rbpf::verifier::{self, ReportWarning};
+
+    let prog = vec![
+        0x15, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +2
+        0x79, 0xa0, 0xf8, 0xfd, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-520]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0x7b, 0x1a, 0xf0, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-16], r1
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let report = verifier::report(&prog, None);
+    assert!(report.passed());
+    assert_eq!((report.insn_count, report.basic_blocks), (6, 3));
+    assert!(report.helpers.is_empty());
+    // The stack depth is unknown, since the analysis of the accesses failed.
+    assert_eq!(report.max_stack_depth, None);
+    assert_eq!(report.warnings, vec![
+        ReportWarning { insn_ptr: Some(3),
+                        message: "instruction 3 stores 8 bytes at r10-16, which are never loaded".to_string() },
+        ReportWarning { insn_ptr: None,
+                        message: "[Verifier] Error: out of bounds stack access: offset -520, size 8 (insn #1)"
+                                 .to_string() },
+    ]);
+    assert!(report.to_json().contains("\"max_stack_depth\":null,\"helpers\":[],\"warnings\":[{\"insn_ptr\":3,"));
+
+    // Loads through pointers of unknown region may access the stack.
+    let prog = vec![
+        0x7b, 0x1a, 0xf0, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-16], r1
+        0x79, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1]
+        0x79, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r0]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(verifier::report(&prog, Some(8)).max_stack_depth, None);
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: opcode 0x3f denied by policy (insn #2)")]
+fn test_insn_policy_set_prog() {
+    use rbpf::verifier::DenyList;
+
+    let prog1 = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let prog2 = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov r0, 8
+        0xb7, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov r1, 2
+        0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog1);
+    vm.set_insn_policy(Box::new(DenyList::new()
+                                .deny_opcode(ebpf::DIV64_IMM)
+                                .deny_opcode(ebpf::DIV64_REG)));
+    assert_eq!(vm.prog_exec(), 1);
+    vm.set_prog(&prog2);
+}
+
+#[test]
+fn test_insn_policy_closure() {
+    // Only allows calls to helper 1.
+    let policy = |insn: &ebpf::Insn, _insn_ptr: usize| {
+        match insn.opc == ebpf::CALL && insn.imm != 1 {
+            true  => Err(format!("helper {} denied by policy", insn.imm)),
+            false => Ok(()),
+        }
+    };
+    let prog = vec![
+        0xb7, 0x01, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // mov r1, 9
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(rbpf::verifier::check_policy(&prog, &policy).is_ok());
+
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_helper(1, helpers::sqrti);
+    vm.set_insn_policy(Box::new(policy));
+    assert_eq!(vm.prog_exec(&mut vec![]), 3);
+
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call 2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(rbpf::verifier::check_policy(&prog, &policy).unwrap_err(),
+               "[Verifier] Error: helper 2 denied by policy (insn #0)");
+}
+
+#[test]
+fn test_equivalence() {
+    use rbpf::equivalence::EquivChecker;
+
+    // Copies the first byte of the input to the second one, and returns sqrti(4).
+    let prog1 = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x73, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r2
+        0xb7, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r1, 4
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Same, but does not copy the first byte if it is zero.
+    let prog2 = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x15, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r2, 0, +1
+        0x73, 0x21, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r2
+        0xb7, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r1, 4
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut checker = EquivChecker::new(&prog1, &prog2);
+    checker.register_helper(1, helpers::sqrti);
+
+    // Both programs leave these inputs in the same state.
+    let corpus = vec![vec![1, 2], vec![0, 0], vec![0xff, 0]];
+    assert!(checker.check_inputs(corpus).is_none());
+
+    let divergence = checker.check_random(2, 100, 1).unwrap();
+    assert_eq!(divergence.input[0], 0);
+    assert!(divergence.input[1] != 0);
+    assert_eq!(divergence.results, (2, 2));
+    assert_eq!(divergence.outputs, (vec![0, 0], divergence.input.clone()));
+    assert_eq!(divergence.step, 1);
+    let (insn1, insn2) = divergence.insns;
+    assert_eq!(insn1.unwrap().insn.opc, ebpf::ST_B_REG);
+    assert_eq!(insn2.unwrap().insn.opc, ebpf::JEQ_IMM);
+}
+
+#[test]
+fn test_generate_inputs() {
+    use rbpf::symbolic::InputGenerator;
+
+    // Returns 1 for IPv4 packets with destination port 80, 0 otherwise. The offset of the port
+    // depends on the length of the IPv4 header.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x69, 0x12, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r2, [r1+12]
+        0x55, 0x02, 0x0a, 0x00, 0x08, 0x00, 0x00, 0x00, // jne r2, 0x8, +10
+        0x71, 0x12, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1+14]
+        0x57, 0x02, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, // and64 r2, 0xf
+        0x67, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // lsh64 r2, 2
+        0xb7, 0x03, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, // mov64 r3, 20
+        0x2d, 0x23, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, // jgt r3, r2, +5
+        0x0f, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r2, r1
+        0x69, 0x23, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r3, [r2+16]
+        0xdc, 0x03, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // be16 r3
+        0x55, 0x03, 0x01, 0x00, 0x50, 0x00, 0x00, 0x00, // jne r3, 80, +1
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let generated = InputGenerator::new(&prog, 64).generate();
+    assert!(generated.uncovered.is_empty());
+    let mut covered: Vec<(usize, bool)> = generated.inputs.iter()
+        .flat_map(|i| i.new_branches.clone())
+        .collect();
+    covered.sort();
+    assert_eq!(covered, vec![(2, false), (2, true), (7, false), (7, true), (11, false), (11, true)]);
+
+    // The interpreter returns the same values on the packets.
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    for generated_input in &generated.inputs {
+        let mut packet = generated_input.input.clone();
+        assert_eq!(Ok(vm.prog_exec(&mut packet)), generated_input.result);
+    }
+    let accepted = generated.inputs.iter().find(|i| i.result == Ok(1)).unwrap();
+    assert_eq!(accepted.input[12], 0x08);
+    assert_eq!(accepted.input[13], 0x00);
+
+    // Exploration stops after the first execution.
+    let generated = InputGenerator::new(&prog, 64).max_runs(1).generate();
+    assert_eq!(generated.inputs.len(), 1);
+    assert!(generated.uncovered.contains(&(2, false)));
+
+    // A packet too short for the port triggers an out of bounds access.
+    let generated = InputGenerator::new(&prog, 36).generate();
+    assert!(generated.inputs.iter().any(|i| i.result == Err("out of bounds access (insn #9)".to_string())));
+}
+
+#[test]
+fn test_mem_helpers() {
+    // Fills 8 bytes of the stack with 0xaa, copies 4 of them to the end of the packet, and
+    // compares the two halves of the packet.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r1, -16
+        0xb7, 0x02, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00, // mov64 r2, 0xaa
+        0xb7, 0x03, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov64 r3, 8
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x7f, // call memset
+        0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+        0x07, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // add64 r1, 4
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r2, -16
+        0xb7, 0x03, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r3, 4
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x7f, // call memcpy
+        0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+        0xbf, 0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r6
+        0x07, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // add64 r2, 4
+        0xb7, 0x03, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r3, 4
+        0x85, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x7f, // call memcmp
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_mem_helpers();
+
+    let mut mem = vec![0x11, 0x22, 0x33, 0x44, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(vm.prog_exec(&mut mem) as i64, 0x11 - 0xaa);
+    assert_eq!(mem, vec![0x11, 0x22, 0x33, 0x44, 0xaa, 0xaa, 0xaa, 0xaa]);
+
+    let mut mem = vec![0xaa, 0xaa, 0xaa, 0xaa, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(vm.prog_exec(&mut mem), 0);
+}
+
+#[test]
+#[should_panic(expected = "Error: out of bounds memory load (insn #4)")]
+fn test_mem_helpers_out_of_bounds() {
+    // Copies 8 bytes starting at offset 2 of an 8-byte packet.
+    let prog = vec![
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+        0x07, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r2, 2
+        0xb7, 0x03, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov64 r3, 8
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x7f, // call memcpy
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_mem_helpers();
+    vm.prog_exec(&mut vec![0; 8]);
+}
+
+#[test]
+#[should_panic(expected = "Error: out of bounds memory store (insn #3)")]
+fn test_mem_helpers_length_overflow() {
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+        0xb7, 0x03, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // mov64 r3, -1
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x7f, // call memset
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_mem_helpers();
+    vm.prog_exec(&mut vec![0; 8]);
+}
+
+#[test]
+fn test_csum_diff() {
+    use rbpf::csum::Checksum;
+
+    // Rewrites the destination address of the IPv4 header in the packet to 10.0.0.1, and updates
+    // its checksum with bpf_csum_diff(). Returns the value returned by the helper for a 3-byte
+    // area, which is invalid.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x61, 0x62, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r6+16]
+        0x63, 0x2a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r2
+        0x62, 0x0a, 0xf8, 0xff, 0x0a, 0x00, 0x00, 0x01, // stw [r10-8], 10.0.0.1
+        0x61, 0xa2, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r10-8]
+        0x63, 0x26, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // stxw [r6+16], r2
+        0x69, 0x65, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r5, [r6+10]
+        0xa7, 0x05, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, // xor64 r5, 0xffff
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r1, -4
+        0xb7, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r2, 4
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+        0xb7, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r4, 4
+        0x85, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, // call bpf_csum_diff
+        0xbf, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r0
+        0x77, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // rsh64 r1, 16
+        0x57, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, // and64 r0, 0xffff
+        0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+        0xbf, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r0
+        0x77, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // rsh64 r1, 16
+        0x57, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, // and64 r0, 0xffff
+        0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+        0xa7, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, // xor64 r0, 0xffff
+        0x6b, 0x06, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, // stxh [r6+10], r0
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r1, -4
+        0xb7, 0x02, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r2, 3
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, 0
+        0x85, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, // call bpf_csum_diff
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_mem_helpers();
+
+    let mut mem = vec![
+        0x45, 0x00, 0x00, 0x54, 0x12, 0x34, 0x40, 0x00, 0x40, 0x01, 0x00, 0x00,
+        0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    let check = Checksum::new(0).add(&mem).finish();
+    mem[10..12].copy_from_slice(&check.to_ne_bytes());
+    assert_eq!(Checksum::new(0).add(&mem).finish(), 0);
+
+    assert_eq!(vm.prog_exec(&mut mem) as i64, -helpers::EINVAL);
+    assert_eq!(&mem[16..20], &[10, 0, 0, 1]);
+    assert_eq!(Checksum::new(0).add(&mem).finish(), 0);
+
+    // The checksum does not depend on how the data is split.
+    let mut split = Checksum::new(0);
+    split.add(&mem[..3]).add(&mem[3..10]).add(&mem[10..]);
+    assert_eq!(split.finish(), 0);
+}
+
+#[test]
+fn test_dispatcher() {
+    use rbpf::dispatch::Dispatcher;
+    use std::cell::RefCell;
+
+    // Returns the first byte of the packet, after setting the second one to 0xff.
+    let prog = vec![
+        0x72, 0x01, 0x01, 0x00, 0xff, 0x00, 0x00, 0x00, // stb [r1+1], 0xff
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let calls = RefCell::new(vec![]);
+    let mut dispatcher = Dispatcher::new(rbpf::EbpfVmRaw::new(&prog));
+    dispatcher.on(1, |ret, packet| calls.borrow_mut().push(("one", ret, packet.to_vec())));
+    dispatcher.on_range(1..=9, |ret, packet| calls.borrow_mut().push(("range", ret, packet.to_vec())));
+    assert!(dispatcher.handles(5));
+    assert!(!dispatcher.handles(10));
+
+    // Unhandled verdicts are ignored until a default action is set.
+    assert_eq!(dispatcher.exec_and_dispatch(&mut [10, 0]), 10);
+    dispatcher.otherwise(|ret, _| calls.borrow_mut().push(("default", ret, vec![])));
+    assert!(dispatcher.handles(10));
+
+    for packet in [[1, 0], [5, 0], [10, 0]].iter_mut() {
+        dispatcher.exec_and_dispatch(packet);
+    }
+    // Errors are returned, and do not trigger actions.
+    assert!(dispatcher.try_exec_and_dispatch(&mut [1]).is_err());
+
+    drop(dispatcher);
+    assert_eq!(calls.into_inner(), vec![
+        ("one", 1, vec![1, 0xff]),
+        ("range", 5, vec![5, 0xff]),
+        ("default", 10, vec![]),
+    ]);
+}
+
+#[test]
+fn test_prog_exec_actions() {
+    use rbpf::ebpf::{TcAction, XdpAction};
+    use rbpf::error::EbpfError;
+
+    // Returns the first byte of the packet, sign-extended.
+    let prog = vec![
+        0x91, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxsb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+
+    assert_eq!(vm.prog_exec_xdp(&mut [3]), XdpAction::Tx);
+    assert_eq!(vm.try_prog_exec_xdp(&mut [5]),
+               Err(EbpfError::InvalidReturnValue { ret: 5, prog_type: "XDP" }));
+    assert_eq!(vm.prog_exec_tc(&mut [0xff]), TcAction::Unspec);
+    assert_eq!(vm.prog_exec_tc(&mut [2]), TcAction::Shot);
+    assert_eq!(TcAction::Unspec.value(), -1);
+    assert_eq!(TcAction::Trap.value(), 8);
+    assert_eq!(XdpAction::Redirect.value(), 4);
+
+    // Only the lower 32 bits of R0 are considered.
+    assert_eq!(XdpAction::from_ret(0x1_0000_0001), Ok(XdpAction::Drop));
+    assert_eq!(TcAction::from_ret(0xffff_ffff), Ok(TcAction::Unspec));
+    let err = vm.try_prog_exec_tc(&mut [0xf0]).unwrap_err();
+    assert_eq!(err.to_string(), "Error: program returned 0xfffffffffffffff0, which is not a valid tc action");
+}
+
+#[test]
+fn test_program_from_verified() {
+    use rbpf::ebpf::IsaVersion;
+    use rbpf::program::{Program, ProgramConfig};
+
+    // Returns 2 if the first byte of the context is 0, and its second byte otherwise.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x16, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq32 r2, 0, +1
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let err = Program::verify(prog.clone(), ProgramConfig { isa: IsaVersion::V2 }).unwrap_err();
+    assert_eq!(err.to_string(), "[Verifier] Error: opcode 0x16 requires ISA V3 (insn #2)");
+
+    let config = ProgramConfig { isa: IsaVersion::V3 };
+    let program = Program::verify(prog.clone(), config).unwrap();
+    assert_eq!(program.bytes(), &prog[..]);
+    assert_eq!(program.config(), &config);
+
+    let raw = rbpf::EbpfVmRaw::from_program(&program);
+    assert_eq!(raw.prog_exec(&mut vec![1, 7]), 7);
+    assert_eq!(raw.prog_exec(&mut vec![0, 7]), 2);
+    let ctx = rbpf::EbpfVmCtx::<[u8; 2]>::from_program(&program);
+    assert_eq!(ctx.prog_exec(&mut [1, 8]), 8);
+    let mut mem = vec![];
+    let mut fixed = rbpf::EbpfVmFixedMbuff::from_program(&program, 0x40, 0x50);
+    assert_eq!(fixed.prog_exec(&mut mem), 2);
+
+    // The VMs use the ISA version of the program, but may load other programs.
+    let prog_v4 = vec![
+        0xd7, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // bswap16 r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::from_program(&program);
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.set_prog(&prog_v4)));
+    assert!(res.is_err());
+    vm.set_isa_version(IsaVersion::V4);
+    vm.set_prog(&prog_v4);
+    assert_eq!(vm.prog_exec(), 0);
+}
+
+#[test]
+fn test_program_patch() {
+    use rbpf::ebpf::{self, Insn};
+    use rbpf::program::{Program, ProgramConfig};
+
+    let insn = |opc, dst, src, off, imm| Insn { opc, dst, src, off, imm };
+
+    // Adds 2 to R0 three times.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r1, 3
+        0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+        0x17, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r1, 1
+        0x55, 0x01, 0xfd, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut program = Program::verify(prog, ProgramConfig::default()).unwrap();
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 6);
+
+    program.patch(2, insn(ebpf::ADD64_IMM, 0, 0, 0, 5)).unwrap();
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 15);
+
+    // Inserted in the loop, the instruction runs at each iteration.
+    program.splice(3..3, &[insn(ebpf::ADD64_IMM, 0, 0, 0, 1)]).unwrap();
+    assert_eq!(ebpf::get_insn(program.bytes(), 5).off, -4);
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 18);
+
+    // Inserted before the target of the loop, only once.
+    program.splice(2..2, &[insn(ebpf::ADD64_IMM, 0, 0, 0, 100)]).unwrap();
+    assert_eq!(ebpf::get_insn(program.bytes(), 6).off, -4);
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 118);
+
+    // Removing the target of the loop sends it to the instruction replacing it.
+    program.splice(3..5, &[insn(ebpf::ADD64_IMM, 0, 0, 0, 7)]).unwrap();
+    assert_eq!(ebpf::InsnIter::new(program.bytes()).len(), 7);
+    assert_eq!(ebpf::get_insn(program.bytes(), 5).off, -3);
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 121);
+
+    // Rejected changes leave the program unchanged.
+    let bytes = program.bytes().to_vec();
+    assert!(program.patch(5, insn(ebpf::JNE_IMM, 1, 0, 10, 0)).is_err());
+    assert!(program.splice(6..7, &[]).is_err());
+    let far = vec![insn(ebpf::MOV64_IMM, 0, 0, 0, 0); 40000];
+    let err = program.splice(4..4, &far).unwrap_err();
+    assert_eq!(err.to_string(), "[Verifier] Error: jump offset -40003 out of range after splice (insn #40005)");
+    assert_eq!(program.bytes(), &bytes[..]);
+
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| program.splice(5..8, &[])));
+    assert!(res.is_err());
+
+    // Programs with BPF-to-BPF calls are rejected, the VMs do not run subprograms.
+    let prog = vec![
+        0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call +1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0xb7, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r0, 5
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let err = Program::verify(prog, ProgramConfig::default()).unwrap_err();
+    assert_eq!(err.to_string(), "[Verifier] Error: BPF-to-BPF calls are not supported (insn #0)");
+}
+
+#[test]
+fn test_max_call_depth() {
+    use rbpf::error::EbpfError;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static VM: Cell<Option<&'static rbpf::EbpfVmNoData<'static>>> = const { Cell::new(None) };
+        static ERROR: RefCell<Option<EbpfError>> = const { RefCell::new(None) };
+    }
+
+    // Runs the program of `VM` again, and returns its result, or 0 on errors.
+    fn recurse(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+        let vm = VM.with(|vm| vm.get().unwrap());
+        vm.try_prog_exec().unwrap_or_else(|err| {
+            ERROR.with(|error| *error.borrow_mut() = Some(err));
+            0
+        })
+    }
+
+    // Returns the result of the helper plus one: the number of nested executions.
+    let prog: &'static Vec<u8> = Box::leak(Box::new(vec![
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]));
+    for &(limit, expected) in [(None, rbpf::ebpf::MAX_CALL_DEPTH as u64), (Some(3), 3), (Some(1), 1)].iter() {
+        let mut vm = rbpf::EbpfVmNoData::new(prog);
+        vm.register_helper(1, recurse);
+        if let Some(limit) = limit {
+            vm.set_max_call_depth(limit);
+        }
+        VM.with(|cell| cell.set(Some(Box::leak(Box::new(vm)))));
+        let vm = VM.with(|vm| vm.get().unwrap());
+        assert_eq!(vm.prog_exec(), expected);
+        let err = ERROR.with(|error| error.borrow_mut().take()).unwrap();
+        assert_eq!(err, EbpfError::CallDepthExceeded { limit: expected as usize });
+    }
+
+    // The depth is restored once the executions are over.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 42
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_max_call_depth(1);
+    assert_eq!(vm.try_prog_exec(), Ok(42));
+    vm.set_max_call_depth(0);
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::CallDepthExceeded { limit: 0 }));
+}
+
+#[test]
+fn test_probe_read() {
+    use rbpf::helpers::AddressSpace;
+
+    // Copies 4 bytes from the address found in the first 8 bytes of the packet, to bytes 8 to 11
+    // of the packet, with the helper given by `key`.
+    fn prog(key: u32) -> Vec<u8> {
+        let mut prog = vec![
+            0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+            0x79, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r3, [r1]
+            0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+            0x07, 0x01, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // add64 r1, 8
+            0xb7, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r2, 4
+            0x85, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // call key
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        prog[44] = key as u8;
+        prog
+    }
+    fn packet(addr: u64) -> Vec<u8> {
+        let mut packet = addr.to_le_bytes().to_vec();
+        packet.extend_from_slice(&[0xff; 4]);
+        packet
+    }
+    let user = b"user";
+    let kernel = b"kern";
+    let efault = -helpers::EFAULT as u64;
+
+    let run = |key: u32, mem: &mut Vec<u8>| {
+        let prog = prog(key);
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        vm.register_probe_region(AddressSpace::User, 0x1000, user);
+        vm.register_probe_region(AddressSpace::Kernel, 0xffff_0000, kernel);
+        vm.prog_exec(mem)
+    };
+
+    let mut mem = packet(0x1000);
+    assert_eq!(run(helpers::BPF_PROBE_READ_USER_IDX, &mut mem), 0);
+    assert_eq!(&mem[8..], b"user");
+
+    // Reads are limited to the address space of the helper, and errors clear the destination.
+    let mut mem = packet(0xffff_0000);
+    assert_eq!(run(helpers::BPF_PROBE_READ_USER_IDX, &mut mem), efault);
+    assert_eq!(&mem[8..], &[0; 4]);
+    let mut mem = packet(0xffff_0000);
+    assert_eq!(run(helpers::BPF_PROBE_READ_KERNEL_IDX, &mut mem), 0);
+    assert_eq!(&mem[8..], b"kern");
+    let mut mem = packet(0x1000);
+    assert_eq!(run(helpers::BPF_PROBE_READ_KERNEL_IDX, &mut mem), efault);
+    let mut mem = packet(0x1000);
+    assert_eq!(run(helpers::BPF_PROBE_READ_IDX, &mut mem), 0);
+    assert_eq!(&mem[8..], b"user");
+
+    // Reads crossing the end of a region fail.
+    let mut mem = packet(0x1001);
+    assert_eq!(run(helpers::BPF_PROBE_READ_IDX, &mut mem), efault);
+
+    // The memory of the program can be read as kernel memory.
+    let mut mem = packet(0);
+    let addr = mem.as_ptr() as u64;
+    mem[..8].copy_from_slice(&addr.to_le_bytes());
+    assert_eq!(run(helpers::BPF_PROBE_READ_KERNEL_IDX, &mut mem), 0);
+    assert_eq!(&mem[8..], &addr.to_le_bytes()[..4]);
+    let mut mem = packet(0);
+    let addr = mem.as_ptr() as u64;
+    mem[..8].copy_from_slice(&addr.to_le_bytes());
+    assert_eq!(run(helpers::BPF_PROBE_READ_USER_IDX, &mut mem), efault);
+}
+
+#[test]
+fn test_tracing_vm() {
+    use rbpf::helpers::AddressSpace;
+
+    // Read the word at the top of the user stack of the probed task, and return it after
+    // overwriting the first argument with it.
+    let mut prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x79, 0x13, 0x98, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r3, [r1+0x98] (sp)
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+        0xb7, 0x02, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov64 r2, 8
+        0x85, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // call bpf_probe_read_user
+        0x79, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-8]
+        0x7b, 0x06, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r6+0x70], r0 (di)
+        0x79, 0x60, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r6+0x70] (di)
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    prog[44] = helpers::BPF_PROBE_READ_USER_IDX as u8;
+    let stack = 0x1122_3344_5566_7788u64.to_le_bytes();
+    let mut vm = rbpf::EbpfVmTracing::new(&prog);
+    vm.register_probe_region(AddressSpace::User, 0x7ffe_0000, &stack);
+
+    let regs = rbpf::PtRegs { sp: 0x7ffe_0000, di: 1, ..Default::default() };
+    assert_eq!(vm.prog_exec(&regs), 0x1122_3344_5566_7788);
+    // The program modified a copy of the registers.
+    assert_eq!(regs.di, 1);
+    assert_eq!(rbpf::PtRegs::from_bytes(&regs.to_bytes()), regs);
+
+    // Accesses past the end of the registers fail.
+    let prog = vec![
+        0x79, 0x10, 0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+0xa8]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmTracing::new(&prog);
+    assert_eq!(vm.try_prog_exec(&regs).unwrap_err().pc(), Some(0));
+
+    // Return the value returned by the probed function, from a register dump.
+    let prog = vec![
+        0x79, 0x10, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+0x50] (ax)
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut dump = vec![0u8; rbpf::PtRegs::SIZE];
+    dump[0x50..0x58].copy_from_slice(&0xdeadu64.to_ne_bytes());
+    let regs = rbpf::PtRegs::from_bytes(&dump);
+    let mut vm = rbpf::EbpfVmTracing::new(&prog);
+    assert_eq!(vm.prog_exec(&regs), 0xdead);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&regs), 0xdead);
+}
+
+#[test]
+fn test_ctx_vm() {
+    #[repr(C)]
+    struct Ctx {
+        input:  [u16; 4],
+        output: u64,
+    }
+    unsafe impl rbpf::ctx::Context for Ctx {}
+
+    // Store the sum of the inputs in the output, and return the first input.
+    let prog = vec![
+        0x69, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r2, [r1]
+        0x69, 0x13, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r3, [r1+2]
+        0x0f, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r2, r3
+        0x69, 0x13, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r3, [r1+4]
+        0x0f, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r2, r3
+        0x69, 0x13, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r3, [r1+6]
+        0x0f, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r2, r3
+        0x7b, 0x21, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r1+8], r2
+        0x69, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxh r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmCtx::<Ctx>::new(&prog);
+    let mut ctx = Ctx { input: [1, 2, 3, 0xfff0], output: 0 };
+    assert_eq!(vm.prog_exec(&mut ctx), 1);
+    assert_eq!(ctx.output, 0xfff6);
+
+    vm.jit_compile();
+    let mut ctx = Ctx { input: [4, 5, 6, 7], output: 0 };
+    assert_eq!(vm.prog_exec_jit(&mut ctx), 4);
+    assert_eq!(ctx.output, 22);
+
+    // The program cannot access memory past the end of the context.
+    let prog = vec![
+        0x79, 0x10, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+4]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmCtx::<u64>::new(&prog);
+    assert!(vm.try_prog_exec(&mut 0).is_err());
+    let vm = rbpf::EbpfVmCtx::<[u64; 2]>::new(&prog);
+    assert_eq!(vm.try_prog_exec(&mut [0x1111_1111_2222_2222, 0x3333_3333_4444_4444]),
+               Ok(0x4444_4444_1111_1111));
+}
+
+#[test]
+fn test_multi_buffer() {
+    let einval = -helpers::EINVAL as u64;
+
+    // Read 4 bytes at the offset in R6 of the packet, store them back at offset R7 after
+    // incrementing each of them, and return their previous value.
+    fn copy_prog(load: u8, store: u8) -> Vec<u8> {
+        let mut prog = vec![
+            0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+            0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, load
+            0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+            0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+            0xb7, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r4, 4
+            0x85, 0x00, 0x00, 0x00, 0xbd, 0x00, 0x00, 0x00, // call bpf_xdp_load_bytes
+            0x55, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r0, 0, +14
+            0x61, 0xa7, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r7, [r10-8]
+            0xbf, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r7
+            0x18, 0x01, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, // lddw r1, 0x01010101
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x0f, 0x17, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r7, r1
+            0x63, 0x7a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-8], r7
+            0xbf, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r7, r0
+            0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+            0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, store
+            0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+            0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+            0xb7, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r4, 4
+            0x85, 0x00, 0x00, 0x00, 0xbe, 0x00, 0x00, 0x00, // call bpf_xdp_store_bytes
+            0xbf, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r7
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        prog[12] = load;
+        prog[124] = store;
+        prog
+    }
+    let mut frag1 = vec![0x00, 0x01, 0x02];
+    let mut frag2 = vec![0x03];
+    let mut frag3 = vec![0x04, 0x05, 0x06, 0x07];
+
+    // Across the three fragments.
+    let prog = copy_prog(2, 1);
+    let vm = rbpf::EbpfVmMbuff::new(&prog);
+    assert_eq!(vm.prog_exec_frags(&mut [&mut frag1, &mut frag2, &mut frag3], &mut []), 0x0504_0302);
+    assert_eq!((frag1.as_slice(), frag2.as_slice(), frag3.as_slice()),
+               (&[0x00, 0x03, 0x04][..], &[0x05][..], &[0x06, 0x05, 0x06, 0x07][..]));
+
+    // Out of the packet.
+    let prog = copy_prog(6, 0);
+    let vm = rbpf::EbpfVmMbuff::new(&prog);
+    assert_eq!(vm.prog_exec_frags(&mut [&mut frag1, &mut frag2, &mut frag3], &mut []), einval);
+
+    // The helpers are only available for multi-buffer packets.
+    assert!(vm.try_prog_exec(&mut frag1, &mut []).is_err());
+
+    // The buffer must belong to the program.
+    let prog = vec![
+        0xb7, 0x03, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, // mov64 r3, 0x1000
+        0xb7, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r4, 1
+        0x85, 0x00, 0x00, 0x00, 0xbd, 0x00, 0x00, 0x00, // call bpf_xdp_load_bytes
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmMbuff::new(&prog);
+    let err = vm.try_prog_exec_frags(&mut [&mut frag1, &mut frag3], &mut []).unwrap_err();
+    assert_eq!(err.pc(), Some(2));
+    assert!(err.to_string().contains(&format!("frag: {:#x}/0x4", frag3.as_ptr() as u64)));
+}
+
+#[test]
+fn test_mbuff_hook() {
+    use std::cell::Cell;
+
+    let prog = vec![
+        0x61, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r1] (packet length)
+        0x61, 0x12, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1+4] (ifindex)
+        0x67, 0x02, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // lsh64 r2, 16
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut frag1 = vec![0xaa; 6];
+    let mut frag2 = vec![0xbb; 4];
+    let mut mem1 = vec![0xaa; 7];
+    let mut mem2 = vec![0xaa; 5];
+    let runs = Cell::new(0);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    vm.set_mbuff_hook(Box::new(|mbuff: &mut [u8], info: &rbpf::PacketInfo| {
+        runs.set(runs.get() + 1);
+        assert_eq!(info.vlan_tci, None);
+        mbuff[0..4].copy_from_slice(&(info.len as u32).to_le_bytes());
+        mbuff[4..8].copy_from_slice(&info.ifindex.to_le_bytes());
+        // Overwritten with the pointers to packet data after the hook returns.
+        mbuff[0x40..0x58].copy_from_slice(&[0xff; 0x18]);
+    }));
+    vm.set_ifindex(3);
+
+    assert_eq!(vm.prog_exec_frags(&mut [&mut frag1, &mut frag2]), 0x3_000a);
+    assert_eq!(vm.try_prog_exec(&mut mem1), Ok(0x3_0007));
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut mem2), 0x3_0005);
+    drop(vm);
+    assert_eq!(runs.get(), 3);
+}
+
+#[test]
+fn test_set_mbuff_offsets() {
+    let prog = vec![
+        0x79, 0x12, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r1+0x100]
+        0x79, 0x10, 0x08, 0x01, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+0x108]
+        0x1f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // sub64 r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem1 = vec![0xaa; 5];
+    let mut mem2 = vec![0xaa; 7];
+    let mut mem3 = vec![0xaa; 4];
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    vm.set_mbuff_offsets(0x100, 0x108);
+    assert_eq!(vm.prog_exec(&mut mem1), 5);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut mem2), 7);
+
+    // Programs compiled apart from the VM, or in the background, use the new offsets as well.
+    let program = vm.jit_program();
+    assert_eq!(program.prog_exec(&mut [0xaa; 3], &mut []), 3);
+    vm.jit_compile_async();
+    vm.wait_jit_compile().unwrap();
+    assert_eq!(vm.prog_exec_jit(&mut mem3), 4);
+}
+
+#[test]
+fn test_iov() {
+    use std::io::IoSliceMut;
+
+    // Store the first byte of packet data, read through R1, at the last offset of the packet.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x85, 0x00, 0x00, 0x00, 0xbc, 0x00, 0x00, 0x00, // call bpf_xdp_get_buff_len
+        0xbf, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r0
+        0x07, 0x02, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // add64 r2, -1
+        0xbf, 0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r6
+        0xb7, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r4, 1
+        0x85, 0x00, 0x00, 0x00, 0xbe, 0x00, 0x00, 0x00, // call bpf_xdp_store_bytes
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // A ring buffer holding a packet that wraps around its end.
+    let mut ring = [0x33, 0x44, 0x00, 0x00, 0x11, 0x22];
+    let (tail, head) = ring.split_at_mut(4);
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let mut iov = [IoSliceMut::new(&mut head[..]), IoSliceMut::new(&mut tail[..2])];
+    assert_eq!(vm.prog_exec_iov(&mut iov), 0);
+    assert_eq!(ring, [0x33, 0x11, 0x00, 0x00, 0x11, 0x22]);
+
+    // Loads are checked against the bounds of the buffers.
+    let prog = vec![
+        0x71, 0x10, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+2]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let mut short = vec![0x11, 0x22];
+    let mut iov = [IoSliceMut::new(&mut short)];
+    assert!(vm.try_prog_exec_iov(&mut iov).is_err());
+    let mut packet = [0x11, 0x22, 0x33];
+    let mut iov = [IoSliceMut::new(&mut packet)];
+    assert_eq!(vm.try_prog_exec_iov(&mut iov), Ok(0x33));
+    assert_eq!(vm.try_prog_exec_iov(&mut []).unwrap_err().pc(), Some(0));
+}
+
+#[test]
+fn test_scratch() {
+    use rbpf::error::EbpfError;
+
+    // Increment a counter kept in the scratch storage, and return its previous value.
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x7f, // call get_scratch
+        0xbf, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r0
+        0x61, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r1]
+        0xbf, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r0
+        0x07, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r2, 1
+        0x63, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxw [r1], r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::UnknownHelper { key: 0x7f00_0004 }));
+
+    vm.set_scratch(4);
+    assert_eq!(vm.prog_exec(), 0);
+    assert_eq!(vm.prog_exec(), 1);
+    vm.scratch_mut().unwrap().copy_from_slice(&[0x10, 0x00, 0x00, 0x00]);
+    assert_eq!(vm.prog_exec(), 0x10);
+    assert_eq!(vm.scratch_mut().unwrap(), &[0x11, 0x00, 0x00, 0x00]);
+
+    // Setting the region again resets it, a size of 0 removes it.
+    vm.set_scratch(4);
+    assert_eq!(vm.prog_exec(), 0);
+    vm.set_scratch(0);
+    assert_eq!(vm.scratch_mut(), None);
+    assert!(vm.try_prog_exec().is_err());
+}
+
+#[test]
+#[should_panic(expected = "Error: out of bounds memory load (insn #2)")]
+fn test_scratch_out_of_bounds() {
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x7f, // call get_scratch
+        0x79, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r0+4]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_scratch(8);
+    vm.prog_exec();
+}
+
+#[test]
+fn test_maps() {
+    use rbpf::helpers::{E2BIG, EEXIST, EINVAL, ENOENT};
+    use rbpf::maps::{self, Map, MapType};
+    use std::sync::Arc;
+
+    // Look up the key at the start of packet data, delete its entry, and return the value.
+    let prog = vec![
+        0xbf, 0x17, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r7, r1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r7
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x15, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +5
+        0xbf, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r7
+        0x85, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // call bpf_map_delete_elem
+        0x61, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r6]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let map = Arc::new(Map::new(MapType::Hash, 4, 4, 2));
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    assert_eq!(vm.register_map(map.clone()), 0);
+
+    assert_eq!(map.update(&[1, 0, 0, 0], &[0x11, 0x22, 0x33, 0x44], maps::BPF_ANY), Ok(()));
+    assert_eq!(map.update(&[2, 0, 0, 0], &[0x55, 0x66, 0x77, 0x88], maps::BPF_NOEXIST), Ok(()));
+    assert_eq!(map.update(&[3, 0, 0, 0], &[0; 4], maps::BPF_ANY), Err(E2BIG));
+    assert_eq!(map.update(&[1, 0, 0, 0], &[0; 4], maps::BPF_NOEXIST), Err(EEXIST));
+    assert_eq!(map.update(&[3, 0, 0, 0], &[0; 4], maps::BPF_EXIST), Err(ENOENT));
+    assert_eq!(map.update(&[1, 0, 0, 0], &[0; 3], maps::BPF_ANY), Err(EINVAL));
+    assert_eq!(map.len(), 2);
+
+    // The value looked up remains valid after the entry is deleted.
+    assert_eq!(vm.prog_exec(&mut vec![1, 0, 0, 0]), 0x44332211);
+    assert_eq!(map.lookup(&[1, 0, 0, 0]), None);
+    assert_eq!(map.delete(&[1, 0, 0, 0]), Err(ENOENT));
+    assert_eq!(vm.prog_exec(&mut vec![1, 0, 0, 0]), 0);
+    assert_eq!(map.len(), 1);
+
+    // Entries of array maps, all present, cannot be deleted.
+    let array = Arc::new(Map::new(MapType::Array, 4, 4, 2));
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(array.clone());
+    assert_eq!(array.update(&[1, 0, 0, 0], &[0x11, 0x22, 0x33, 0x44], maps::BPF_EXIST), Ok(()));
+    assert_eq!(array.update(&[2, 0, 0, 0], &[0; 4], maps::BPF_ANY), Err(E2BIG));
+    assert_eq!(vm.prog_exec(&mut vec![1, 0, 0, 0]), 0x44332211);
+    assert_eq!(array.lookup(&[0, 0, 0, 0]), Some(vec![0; 4]));
+    assert_eq!(array.delete(&[0, 0, 0, 0]), Err(EINVAL));
+    assert_eq!(array.len(), 2);
+}
+
+#[test]
+fn test_for_each_map_elem() {
+    use rbpf::helpers::{BPF_FOR_EACH_MAP_ELEM_IDX, BPF_MAP_DELETE_ELEM_IDX, EINVAL};
+    use rbpf::maps::{self, Map, MapType};
+    use rbpf::testing::HelperRecorder;
+    use std::sync::Arc;
+
+    // Sum the 64-bit values of map 0 on the stack with the callback, and return the number of
+    // elements visited in the upper 32 bits, and the sum in the lower ones. The callback doubles
+    // each value, deletes the element of key 2, and stops the iteration at key 3.
+    let prog = vec![
+        0x7a, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-8], 0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x18, 0x42, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // lddw r2, callback
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, 0
+        0x85, 0x00, 0x00, 0x00, 0xa4, 0x00, 0x00, 0x00, // call bpf_for_each_map_elem
+        0x79, 0xa1, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r10-8]
+        0x67, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, // lsh64 r0, 32
+        0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        // callback(map, key, value, ctx):
+        0x7a, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-8], 0 (its own stack)
+        0x79, 0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r5, [r3]
+        0x79, 0x46, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r6, [r4]
+        0x0f, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r6, r5
+        0x7b, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r4], r6
+        0x67, 0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // lsh64 r5, 1
+        0x7b, 0x53, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r3], r5
+        0x61, 0x26, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r6, [r2]
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x15, 0x06, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00, // jeq r6, 3, +3
+        0x55, 0x06, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, // jne r6, 2, +1
+        0x85, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // call bpf_map_delete_elem
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    rbpf::verifier::try_check(&prog).unwrap();
+    let value = |v: u64| v.to_ne_bytes().to_vec();
+
+    // Elements of arrays are visited in order, up to the one of key 3, and cannot be deleted.
+    let array = Arc::new(Map::new(MapType::Array, 4, 8, 5));
+    for key in 0..5u32 {
+        array.update(&key.to_ne_bytes(), &value(key as u64 + 1), maps::BPF_ANY).unwrap();
+    }
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(array.clone());
+    assert_eq!(vm.prog_exec(), 4 << 32 | 10);
+    let values: Vec<_> = array.iter().map(|(_, v)| v).collect();
+    assert_eq!(values, vec![value(2), value(4), value(6), value(8), value(5)]);
+
+    // All the elements of the hash map are visited, in no particular order, and the one of key 2 is
+    // deleted.
+    let hash = Arc::new(Map::new(MapType::Hash, 4, 8, 8));
+    for &key in [1u32, 2, 4, 5].iter() {
+        hash.update(&key.to_ne_bytes(), &value(key as u64 * 10), maps::BPF_ANY).unwrap();
+    }
+    let recorder = HelperRecorder::new();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(hash.clone());
+    vm.set_helper_hook(Box::new(&recorder));
+    assert_eq!(vm.prog_exec(), 4 << 32 | 120);
+    assert_eq!(hash.len(), 3);
+    assert_eq!(hash.lookup(&2u32.to_ne_bytes()), None);
+    assert_eq!(hash.lookup(&5u32.to_ne_bytes()), Some(value(100)));
+    assert_eq!(recorder.sequence(), vec![BPF_MAP_DELETE_ELEM_IDX, BPF_FOR_EACH_MAP_ELEM_IDX]);
+    assert_eq!(recorder.calls_to(BPF_FOR_EACH_MAP_ELEM_IDX)[0].ret, 4);
+
+    // The callback is not called on an empty map.
+    hash.clear();
+    assert_eq!(vm.prog_exec(), 0);
+
+    // Flags must be 0.
+    let mut with_flags = prog.clone();
+    with_flags[6 * 8 + 4] = 1;
+    let mut vm = rbpf::EbpfVmNoData::new(&with_flags);
+    vm.register_map(array.clone());
+    assert_eq!(vm.prog_exec(), (-EINVAL as u64) << 32);
+}
+
+#[test]
+#[should_panic(expected = "Error: out of bounds memory load (insn #5)")]
+fn test_maps_value_out_of_bounds() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    let prog = vec![
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x15, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +1
+        0x61, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r0+4]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(Arc::new(Map::new(MapType::Array, 4, 4, 1)));
+    vm.prog_exec(&mut vec![0, 0, 0, 0]);
+}
+
+#[test]
+fn test_vm_stats() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    let prog = vec![
+        0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1+0x40]
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let snapshot = vec![0u8; 24];
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    let stats = vm.stats();
+    assert_eq!(stats.prog_size, 24);
+    assert_eq!(stats.mbuff_size, 0x58);
+    assert_eq!(stats.jit_code_size, None);
+
+    vm.jit_compile();
+    assert!(vm.stats().jit_code_size.unwrap() > 0);
+
+    vm.register_probe_region(helpers::AddressSpace::User, 0x1000, &snapshot);
+    vm.register_probe_region(helpers::AddressSpace::User, 0x2000, &snapshot[..8]);
+    let map = Arc::new(Map::new(MapType::Hash, 2, 4, 16));
+    vm.register_map(map.clone());
+    map.update(&[0, 1], &[0; 4], 0).unwrap();
+    map.update(&[0, 2], &[0; 4], 0).unwrap();
+    let stats = vm.stats();
+    assert_eq!((stats.probe_regions, stats.probe_regions_size), (2, 32));
+    assert_eq!((stats.maps, stats.map_memory), (1, 12));
+}
+
+#[test]
+fn test_maps_threads() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+    use std::thread;
+
+    // Increment the 64-bit counter of the hash map under the key found in packet data, creating
+    // it if needed.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r6
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0xb7, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r1, 1
+        0x15, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +2
+        0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0x7b, 0x1a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xbf, 0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r6
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, 0
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call bpf_map_update_elem
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Each thread updates its own key, so that no increment is lost.
+    let map = Arc::new(Map::new(MapType::Hash, 4, 8, 64));
+    let workers: Vec<_> = (0..8u32).map(|index| {
+        let (prog, map) = (prog.clone(), map.clone());
+        thread::spawn(move || {
+            let mut vm = rbpf::EbpfVmRaw::new(&prog);
+            vm.register_map(map);
+            for _ in 0..100 {
+                assert_eq!(vm.prog_exec(&mut index.to_le_bytes().to_vec()), 0);
+            }
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(map.len(), 8);
+    for index in 0..8u32 {
+        assert_eq!(map.lookup(&index.to_le_bytes()), Some(100u64.to_le_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn test_batch_executor() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    // Return the first byte of packet data, passed through helper 16, multiplied by the value in
+    // slot 0 of an array map.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x63, 0x1a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r1
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x79, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r7, [r0]
+        0x71, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r6]
+        0x85, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // call 16
+        0x2f, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mul64 r0, r7
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    fn double(a: u64, _b: u64, _c: u64, _d: u64, _e: u64) -> u64 {
+        a * 2
+    }
+    let map = Arc::new(Map::new(MapType::Array, 4, 8, 1));
+    let mut executor = rbpf::batch::BatchExecutor::new(prog);
+    executor.register_helper(16, double);
+    executor.register_map(map.clone());
+    map.update(&[0, 0, 0, 0], &3u64.to_le_bytes(), rbpf::maps::BPF_ANY).unwrap();
+
+    let mut packets: Vec<Vec<u8>> = (0..1000u32).map(|i| vec![(i % 100) as u8]).collect();
+    for &threads in &[1, 3, 8, 2000] {
+        executor.set_threads(threads);
+        assert_eq!(executor.threads(), threads);
+        let results = executor.prog_exec(&mut packets);
+        assert_eq!(results, (0..1000).map(|i| (i % 100) * 6).collect::<Vec<u64>>());
+    }
+    assert_eq!(executor.prog_exec(&mut Vec::<Vec<u8>>::new()), vec![]);
+
+    // An empty packet fails while the others run.
+    packets[500].clear();
+    let results = executor.try_prog_exec(&mut packets);
+    assert_eq!(results.iter().filter(|res| res.is_err()).count(), 1);
+    assert_eq!(results[500].as_ref().unwrap_err().pc(), Some(7));
+    assert_eq!(results[501], Ok(6));
+}
+
+#[test]
+fn test_jit_compile_async() {
+    use rbpf::error::EbpfError;
+    use std::panic;
+
+    // Divide 1 by the first byte of packet data: the interpreter panics on a division by zero,
+    // while JIT-compiled programs return u64::MAX.
+    let prog = vec![
+        0x71, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1]
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let interpreted = |vm: &rbpf::EbpfVmRaw| {
+        panic::catch_unwind(panic::AssertUnwindSafe(|| vm.prog_exec(&mut vec![0]))).is_err()
+    };
+
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    assert!(!vm.jit_ready());
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    vm.jit_compile_async();
+    assert_eq!(vm.prog_exec(&mut vec![1]), 1);
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    assert!(vm.jit_ready());
+    assert_eq!(vm.prog_exec(&mut vec![0]), u64::MAX);
+    assert_eq!(vm.prog_exec_jit(&mut vec![0]), u64::MAX);
+
+    // Features run by the interpreter only prevent the switch.
+    vm.set_scratch(8);
+    assert!(interpreted(&vm));
+    vm.set_scratch(0);
+    assert!(!interpreted(&vm));
+
+    // So does loading a new program.
+    vm.set_prog(&prog);
+    assert!(!vm.jit_ready());
+    assert!(interpreted(&vm));
+
+    // The VM keeps interpreting programs that fail to compile.
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, // call 0x3f
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.jit_compile_async();
+    assert_eq!(vm.wait_jit_compile(),
+               Err(EbpfError::JitError("[JIT] Error: unknown helper function (id: 0x3f)".to_string())));
+    assert!(!vm.jit_ready());
+    assert_eq!(vm.try_prog_exec(), Err(EbpfError::UnknownHelper { key: 0x3f }));
+}
+
+#[test]
+fn test_jit_threshold() {
+    // Divide 1 by the first byte of packet data: once JIT-compiled, the program returns u64::MAX
+    // instead of panicking on a division by zero.
+    let prog = vec![
+        0x71, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1]
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x3f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // div64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_jit_threshold(Some(3));
+    for _ in 0..2 {
+        assert_eq!(vm.prog_exec(&mut vec![1]), 1);
+    }
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    assert!(!vm.jit_ready());
+    assert_eq!(vm.prog_exec(&mut vec![1]), 1);
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    assert!(vm.jit_ready());
+    assert_eq!(vm.prog_exec(&mut vec![0]), u64::MAX);
+    assert_eq!(vm.interpreted_runs(), 3);
+
+    // Loading the program again resets the count.
+    vm.set_prog(&prog);
+    assert_eq!(vm.interpreted_runs(), 0);
+    assert!(!vm.jit_ready());
+
+    // The compiled program updates the pointers of the fixed metadata buffer.
+    let prog = vec![
+        0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1+0x40]
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (mut first, mut second) = (vec![0xaa, 0xbb], vec![0xcc, 0xdd]);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    vm.set_jit_threshold(Some(1));
+    assert_eq!(vm.prog_exec(&mut first), 0xbb);
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    assert!(vm.jit_ready());
+    assert_eq!(vm.prog_exec(&mut second), 0xdd);
+    assert_eq!(vm.interpreted_runs(), 1);
+}
+
+#[test]
+fn test_jit_program() {
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<rbpf::JitProgram>();
+
+    // Returns the sum of the first two bytes of packet data.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let program = {
+        let vm = rbpf::EbpfVmRaw::new(&prog);
+        Arc::new(vm.jit_program())
+    };
+    let workers: Vec<_> = (0..4u8).map(|i| {
+        let program = program.clone();
+        thread::spawn(move || {
+            (0..100u8).map(|j| program.prog_exec(&mut [i, j], &mut [])).sum::<u64>()
+        })
+    }).collect();
+    for (i, worker) in workers.into_iter().enumerate() {
+        assert_eq!(worker.join().unwrap(), 100 * i as u64 + 4950);
+    }
+
+    // Programs compiled by an EbpfVmFixedMbuff receive a metadata buffer with the pointers to
+    // packet data.
+    let prog = vec![
+        0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1+0x40]
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let program = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50).jit_program();
+    assert_eq!(program.prog_exec(&mut [0xaa, 0xbb], &mut []), 0xbb);
+    assert_eq!(program.prog_exec(&mut [0xcc, 0xdd], &mut []), 0xdd);
+    drop(program);
+
+    // Programs that cannot be compiled are reported as errors.
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert!(rbpf::EbpfVmNoData::new(&prog).try_jit_program().is_err());
+}
+
+#[test]
+fn test_jit_function() {
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<rbpf::JitFunction>();
+
+    // Returns the first byte of packet data, or 0x2a if R1 is null.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 0x2a
+        0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let program = rbpf::EbpfVmRaw::new(&prog).jit_program();
+    let function = program.function();
+    assert_eq!(function.call(&mut [7, 8], &mut []), 7);
+    // Empty packet data is passed as a null pointer.
+    assert_eq!(function.call(&mut [], &mut []), 0x2a);
+
+    // The function is copied into the threads of the application.
+    let sum: u64 = thread::scope(|scope| {
+        let workers: Vec<_> = (0..4u8).map(|i| {
+            scope.spawn(move || function.call(&mut [i], &mut []))
+        }).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).sum()
+    });
+    assert_eq!(sum, 6);
+
+    // Programs compiled by an EbpfVmMbuff read the metadata buffer given by the caller.
+    let prog = vec![
+        0x79, 0x10, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r1+8]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let program = rbpf::EbpfVmMbuff::new(&prog).jit_program();
+    let mut mbuff = vec![0u8; 16];
+    mbuff[8..16].copy_from_slice(&0x1122u64.to_ne_bytes());
+    assert_eq!(program.function().call(&mut [], &mut mbuff), 0x1122);
+}
+
+#[test]
+fn test_jit_intrinsics() {
+    use rbpf::helpers::Intrinsics;
+
+    // Call helper 1 with R1 set to `x`, and R2 to R5 set to 1 to 4.
+    fn call_prog(x: u64) -> Vec<u8> {
+        let mut prog = vec![
+            0x18, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, x
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb7, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r2, 1
+            0xb7, 0x03, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r3, 2
+            0xb7, 0x04, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r4, 3
+            0xb7, 0x05, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r5, 4
+            0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        prog[4..8].copy_from_slice(&(x as u32).to_le_bytes());
+        prog[12..16].copy_from_slice(&((x >> 32) as u32).to_le_bytes());
+        prog
+    }
+    fn run(x: u64, helper: rbpf::ebpf::Helper, intrinsics: Intrinsics) -> (u64, usize) {
+        let prog = call_prog(x);
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.register_helper(1, helper);
+        vm.set_intrinsics(intrinsics);
+        vm.jit_compile();
+        (vm.prog_exec_jit(), vm.stats().jit_code_size.unwrap())
+    }
+
+    let values = [0, 1, 2, 80, 81, 0xffff_ffff, 0x1234_5678_9abc_def0, 1 << 63, (1 << 63) + 1,
+                  0xffff_fffe_0000_0001, u64::MAX];
+    for &x in values.iter() {
+        let (res, inlined_size) = run(x, helpers::sqrti, Intrinsics::all());
+        assert_eq!(res, helpers::sqrti(x, 0, 0, 0, 0), "sqrti({:#x})", x);
+        let (res, called_size) = run(x, helpers::sqrti, Intrinsics::default());
+        assert_eq!(res, helpers::sqrti(x, 0, 0, 0, 0));
+        assert!(inlined_size != called_size);
+
+        let (res, _) = run(x, helpers::gather_bytes, Intrinsics { gather_bytes: true, ..Default::default() });
+        assert_eq!(res, helpers::gather_bytes(x, 1, 2, 3, 4));
+    }
+
+    // Not inlined unless selected.
+    let (_, called_size) = run(4, helpers::sqrti, Intrinsics { gather_bytes: true, ..Default::default() });
+    assert_eq!(called_size, run(4, helpers::sqrti, Intrinsics::default()).1);
+
+    let before = helpers::bpf_ktime_get_ns(0, 0, 0, 0, 0);
+    let (res, _) = run(0, helpers::bpf_ktime_get_ns, Intrinsics::all());
+    let after = helpers::bpf_ktime_get_ns(0, 0, 0, 0, 0);
+    assert!(before <= res && res <= after);
+}
+
+#[test]
+fn test_jit_hardening() {
+    use rbpf::ebpf::{self, Insn};
+
+    let insn = |opc, dst, off, imm| Insn { opc, dst, src: 0, off, imm };
+    let exit = insn(ebpf::EXIT, 0, 0, 0);
+    // Runs `insns` after loading `x` into R0, interpreted, and JIT-compiled with and without
+    // hardening. Returns the results and the sizes of the code.
+    let run = |x: u64, insns: &[Insn]| {
+        let mut prog = vec![];
+        prog.extend(insn(ebpf::LD_DW_IMM, 0, 0, x as i32).to_vec());
+        prog.extend(insn(0, 0, 0, (x >> 32) as i32).to_vec());
+        for insn in insns {
+            prog.extend(insn.to_vec());
+        }
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        let expected = vm.prog_exec();
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected, "{:?} on {:#x}", insns, x);
+        let size = vm.stats().jit_code_size.unwrap();
+        vm.set_jit_hardening(true);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected, "{:?} on {:#x}, hardened", insns, x);
+        assert!(vm.stats().jit_code_size.unwrap() > size);
+    };
+
+    let values = [0, 1, 7, 0x7fff_ffff, 0xffff_ffff, 0x1234_5678_9abc_def0, 1 << 63, u64::MAX];
+    let imms = [1, 3, -1, -7, 0x1234_5678, i32::MIN];
+    let alu_ops = [ebpf::BPF_ADD, ebpf::BPF_SUB, ebpf::BPF_MUL, ebpf::BPF_DIV, ebpf::BPF_OR,
+                   ebpf::BPF_AND, ebpf::BPF_MOD, ebpf::BPF_XOR, ebpf::BPF_MOV];
+    let jmp_ops = [ebpf::BPF_JEQ, ebpf::BPF_JGT, ebpf::BPF_JGE, ebpf::BPF_JSET, ebpf::BPF_JNE,
+                   ebpf::BPF_JSGT, ebpf::BPF_JSGE, ebpf::BPF_JLT, ebpf::BPF_JLE, ebpf::BPF_JSLT,
+                   ebpf::BPF_JSLE];
+    let st_sizes = [ebpf::BPF_B, ebpf::BPF_H, ebpf::BPF_W, ebpf::BPF_DW];
+    for &x in values.iter() {
+        for &imm in imms.iter() {
+            for &op in alu_ops.iter() {
+                for &class in [ebpf::BPF_ALU, ebpf::BPF_ALU64].iter() {
+                    run(x, &[insn(class | ebpf::BPF_K | op, 0, 0, imm), exit]);
+                    if op == ebpf::BPF_DIV || op == ebpf::BPF_MOD {
+                        // Signed division and modulo
+                        run(x, &[insn(class | ebpf::BPF_K | op, 0, 1, imm), exit]);
+                    }
+                }
+            }
+            for &op in jmp_ops.iter() {
+                for &class in [ebpf::BPF_JMP, ebpf::BPF_JMP32].iter() {
+                    run(x, &[insn(class | ebpf::BPF_K | op, 0, 1, imm), exit,
+                             insn(ebpf::MOV64_IMM, 0, 0, 1), exit]);
+                }
+            }
+            for &size in st_sizes.iter() {
+                // Store x, overwrite part of it with the immediate, and load it back.
+                run(x, &[Insn { opc: ebpf::ST_DW_REG, dst: 10, src: 0, off: -8, imm: 0 },
+                         insn(ebpf::BPF_ST | ebpf::BPF_MEM | size, 10, -8, imm),
+                         Insn { opc: ebpf::LD_DW_REG, dst: 0, src: 10, off: -8, imm: 0 }, exit]);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_jit_code_memory() {
+    use rbpf::ebpf::{self, Insn};
+
+    // A long program, whose code spans many pages.
+    let insn = |opc, imm| Insn { opc, dst: 0, src: 0, off: 0, imm };
+    let mut prog = insn(ebpf::MOV64_IMM, 0).to_vec();
+    for _ in 0..ebpf::PROG_MAX_INSNS - 2 {
+        prog.extend(insn(ebpf::ADD64_IMM, 0x1234).to_vec());
+    }
+    prog.extend(insn(ebpf::EXIT, 0).to_vec());
+    let expected = 0x1234 * (ebpf::PROG_MAX_INSNS as u64 - 2);
+
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_jit_hardening(true);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), expected);
+    let program = vm.jit_program();
+    assert!(program.code_size() > 4096 * 4);
+    assert_eq!(Some(program.code_size()), vm.stats().jit_code_size);
+    assert_eq!(program.prog_exec(&mut [], &mut []), expected);
+
+    // The code backed by huge pages is the same.
+    vm.set_jit_huge_pages(true);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), expected);
+    let huge = vm.jit_program();
+    assert_eq!(huge.code_size(), program.code_size());
+    assert_eq!(huge.prog_exec(&mut [], &mut []), expected);
+}
+
+#[test]
+fn test_stack_guard() {
+    // Fill the whole stack with the first byte of packet data, then sum its first and last
+    // double words.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r1, -8
+        0x7b, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r1], r2
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, // add64 r3, -0x200
+        0x2d, 0x31, 0xfb, 0xff, 0x00, 0x00, 0x00, 0x00, // if r1 > r3 goto -5
+        0x79, 0xa0, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-8]
+        0x79, 0xa1, 0x00, 0xfe, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r10-0x200]
+        0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_stack_guard(true);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut vec![0x11]), 0x22);
+    assert_eq!(vm.prog_exec_jit(&mut vec![0x21]), 0x42);
+    assert_eq!(vm.prog_exec(&mut vec![0x11]), 0x22);
+
+    // Programs compiled in the background use the guarded stack too.
+    vm.set_jit_threshold(Some(1));
+    vm.set_prog(&prog);
+    assert_eq!(vm.prog_exec(&mut vec![0x11]), 0x22);
+    assert_eq!(vm.wait_jit_compile(), Ok(()));
+    assert!(vm.jit_ready());
+    assert_eq!(vm.prog_exec(&mut vec![0x30]), 0x60);
+
+    // Changing the setting discards the compiled program.
+    vm.set_stack_guard(false);
+    assert!(!vm.jit_ready());
+}
+
+#[test]
+#[should_panic(expected = "Error: program has not been JIT-compiled")]
+fn test_stack_guard_discards_jit() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), 1);
+    vm.set_stack_guard(true);
+    vm.prog_exec_jit();
+}
+
+#[test]
+fn test_catch_faults() {
+    use rbpf::error::EbpfError;
+
+    // Loads from the address in the first double word of packet data.
+    let prog = vec![
+        0x79, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1]
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_catch_faults(true);
+    vm.jit_compile();
+    let err = vm.try_prog_exec_jit(&mut [0; 8]).unwrap_err();
+    assert!(matches!(err, EbpfError::MemoryFault { ebpf_pc: 1, .. }));
+    assert_eq!(err.pc(), Some(1));
+    assert!(err.to_string().starts_with("Error: memory fault in JIT-compiled program at 0x"));
+    assert!(err.to_string().ends_with("(insn #1)"));
+
+    // The VM can still run the program afterwards.
+    let byte = [0x2a];
+    let mut mem = (byte.as_ptr() as u64).to_ne_bytes();
+    assert_eq!(vm.try_prog_exec_jit(&mut mem), Ok(0x2a));
+
+    // Overrunning the guarded stack faults at once.
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r2, 1
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf8, 0xfd, 0xff, 0xff, // add64 r1, -0x208
+        0x7b, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r1], r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_stack_guard(true);
+    vm.set_catch_faults(true);
+    vm.jit_compile();
+    assert!(matches!(vm.try_prog_exec_jit(), Err(EbpfError::MemoryFault { ebpf_pc: 3, .. })));
+}
+
+#[test]
+#[should_panic(expected = "Error: memory fault in JIT-compiled program")]
+fn test_catch_faults_panic() {
+    let prog = vec![
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x7a, 0x01, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // stdw [r1], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_catch_faults(true);
+    vm.jit_compile();
+    vm.prog_exec_jit();
+}
+
+#[test]
+fn test_strtol() {
+    // Parses the string starting at byte 2 of the packet, with the flags in byte 0 and the length
+    // in byte 1, and stores the result at byte 2.
+    fn prog(key: u32) -> Vec<u8> {
+        let mut prog = vec![
+            0xbf, 0x17, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r7, r1
+            0x7a, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stdw [r10-8], 0
+            0x71, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r3, [r1]
+            0x71, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1+1]
+            0x07, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r1, 2
+            0xbf, 0xa4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r10
+            0x07, 0x04, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r4, -8
+            0x85, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // call key
+            0x79, 0xa1, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r10-8]
+            0x7b, 0x17, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r7+2], r1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        prog[60] = key as u8;
+        prog
+    }
+    // Returns the value returned by the helper, and the result it stored.
+    fn parse(key: u32, flags: u8, s: &str) -> (i64, i64) {
+        let prog = prog(key);
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        vm.register_mem_helpers();
+        let mut mem = vec![flags, s.len() as u8];
+        mem.extend_from_slice(s.as_bytes());
+        mem.resize(s.len().max(8) + 2, 0);
+        let ret = vm.prog_exec(&mut mem) as i64;
+        let mut res = [0u8; 8];
+        res.copy_from_slice(&mem[2..10]);
+        (ret, i64::from_le_bytes(res))
+    }
+    let strtol = |flags, s| parse(helpers::BPF_STRTOL_IDX, flags, s);
+    let strtoul = |flags, s| parse(helpers::BPF_STRTOUL_IDX, flags, s);
+    let einval = (-helpers::EINVAL, 0);
+    let erange = (-helpers::ERANGE, 0);
+
+    assert_eq!(strtol(10, "  -42xyz"), (5, -42));
+    assert_eq!(strtol(0, "0x1f"), (4, 31));
+    assert_eq!(strtol(10, "0x1f"), (1, 0));
+    assert_eq!(strtol(16, "0x1f"), (4, 31));
+    assert_eq!(strtol(16, "1F"), (2, 31));
+    assert_eq!(strtol(0, "017"), (3, 15));
+    assert_eq!(strtol(0, "0xg"), (1, 0));
+    assert_eq!(strtol(10, "12\0 34"), (2, 12));
+    assert_eq!(strtol(10, "-0"), (2, 0));
+    assert_eq!(strtol(10, "-9223372036854775808"), (20, i64::MIN));
+    assert_eq!(strtol(10, "9223372036854775808"), erange);
+    assert_eq!(strtol(10, "abc"), einval);
+    assert_eq!(strtol(10, "   "), einval);
+    assert_eq!(strtol(10, ""), einval);
+    assert_eq!(strtol(3, "12"), einval);
+    assert_eq!(strtol(0x2a, "12"), einval);
+
+    assert_eq!(strtoul(10, "18446744073709551615"), (20, -1));
+    assert_eq!(strtoul(10, "18446744073709551616"), erange);
+    assert_eq!(strtoul(10, "-1"), einval);
+}
+
+#[test]
+fn test_event_output() {
+    use std::cell::RefCell;
+    use std::sync::mpsc;
+
+    // Outputs the first byte of the packet, then the next two bytes, as two events.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0xbf, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r1
+        0xb7, 0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r5, 1
+        0x85, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, // call bpf_perf_event_output
+        0xbf, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r6
+        0x07, 0x04, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r4, 1
+        0xb7, 0x05, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r5, 2
+        0x85, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, // call bpf_perf_event_output
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0x11, 0x22, 0x33];
+
+    let (sender, receiver) = mpsc::channel();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_event_sink(Box::new(sender));
+    assert_eq!(vm.prog_exec(&mut mem), 0);
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![vec![0x11], vec![0x22, 0x33]]);
+    drop(receiver);
+    assert_eq!(vm.prog_exec(&mut mem) as i64, -helpers::ENOENT);
+
+    // Events are dropped when a bounded channel is full.
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_event_sink(Box::new(sender));
+    assert_eq!(vm.prog_exec(&mut mem) as i64, -helpers::ENOSPC);
+    assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![vec![0x11]]);
+
+    let events = RefCell::new(vec![]);
+    let sink = |data: &[u8]| {
+        events.borrow_mut().push(data.to_vec());
+        Ok(())
+    };
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_event_sink(Box::new(sink));
+    assert_eq!(vm.prog_exec(&mut mem), 0);
+    drop(vm);
+    assert_eq!(events.into_inner(), vec![vec![0x11], vec![0x22, 0x33]]);
+}
+
+#[test]
+#[cfg(feature = "pcap")]
+fn test_pcap_capture() {
+    use rbpf::capture::{self, PacketSource, PcapReader};
+    use std::time::Duration;
+
+    // ldxb r0, [r1]
+    // exit
+    let prog = vec![
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+
+    // Little-endian, timestamps in microseconds, Ethernet.
+    let file = vec![
+        0xd4, 0xc3, 0xb2, 0xa1, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        // Packet truncated from 60 to 2 bytes, captured at 1.000500 s.
+        0x01, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x3c, 0x00, 0x00, 0x00, 0x2a, 0x01,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x07,
+    ];
+    let mut reader = PcapReader::new(&file[..]).unwrap();
+    assert_eq!(reader.link_type(), 1);
+    assert_eq!(reader.snap_len(), 0xffff);
+    let mut results = vec![];
+    let count = capture::run(&mut reader, |data| vm.prog_exec(data), |ret, packet| {
+        results.push((ret, packet.orig_len, packet.timestamp));
+    }).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(results, vec![(0x2a, 60, Some(Duration::new(1, 500_000))),
+                             (0x07, 1, Some(Duration::new(2, 0)))]);
+
+    // Big-endian, timestamps in nanoseconds.
+    let file = vec![
+        0xa1, 0xb2, 0x3c, 0x4d, 0x00, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x65,
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x01, 0x05,
+    ];
+    let mut reader = PcapReader::new(&file[..]).unwrap();
+    assert_eq!(reader.link_type(), 101);
+    let packet = reader.next_packet().unwrap().unwrap();
+    assert_eq!(packet.data, vec![0x05]);
+    assert_eq!(packet.timestamp, Some(Duration::new(3, 9)));
+    assert!(reader.next_packet().unwrap().is_none());
+
+    // Not a pcap file, truncated record.
+    assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+    let mut reader = PcapReader::new(&file[..file.len() - 1]).unwrap();
+    assert!(reader.next_packet().is_err());
+}
+
+// Chain of tc-like classifiers: each program returns TC_ACT_UNSPEC (-1) to let the next one run.
+#[test]
+fn test_hooks_chain() {
+    use rbpf::hooks::{ChainPolicy, Hooks};
+
+    const TC_ACT_UNSPEC: u64 = -1i64 as u64;
+    const TC_ACT_SHOT: u64 = 2;
+
+    // Writes 0xaa in the second byte of the packet.
+    let mark = vec![
+        0x72, 0x01, 0x01, 0x00, 0xaa, 0x00, 0x00, 0x00, // stb [r1+1], 0xaa
+        0xb7, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // mov64 r0, -1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Drops packets whose first byte is 0.
+    let drop = vec![
+        0xb7, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, // mov64 r0, -1
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x55, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r2, 0, +1
+        0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Returns the second byte of the packet.
+    let verdict = vec![
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let mut hooks = Hooks::new();
+    hooks.define("tc", ChainPolicy::ContinueOn(TC_ACT_UNSPEC), 0);
+    assert!(hooks.is_defined("tc"));
+    assert_eq!(hooks.invoke("tc", &mut [0, 0], &mut []).ret, 0);
+
+    // Same priority: run in the order of attachment.
+    hooks.attach("tc", "verdict", 5, verdict);
+    hooks.attach("tc", "drop", 1, drop);
+    hooks.attach("tc", "mark", 5, mark.clone());
+    assert_eq!(hooks.attached("tc"), vec!["drop", "verdict", "mark"]);
+    let res = hooks.invoke("tc", &mut [1, 7], &mut []);
+    assert_eq!((res.ret, res.run, res.stopped_by), (7, 2, Some("verdict".to_string())));
+    let res = hooks.invoke("tc", &mut [0, 7], &mut []);
+    assert_eq!((res.ret, res.run), (TC_ACT_SHOT, 1));
+
+    // Re-attaching a program under the same name moves it.
+    hooks.attach("tc", "mark", 2, mark);
+    assert_eq!(hooks.attached("tc"), vec!["drop", "mark", "verdict"]);
+    let mut packet = vec![1, 7];
+    let res = hooks.invoke("tc", &mut packet, &mut []);
+    assert_eq!((res.ret, res.run, res.stopped_by), (0xaa, 3, None));
+    assert_eq!(packet, vec![1, 0xaa]);
+
+    // Changing the policy keeps the programs.
+    hooks.define("tc", ChainPolicy::AbortOn(TC_ACT_SHOT), 0);
+    let res = hooks.invoke("tc", &mut [0, 7], &mut []);
+    assert_eq!((res.ret, res.run, res.stopped_by), (TC_ACT_SHOT, 1, Some("drop".to_string())));
+    hooks.define("tc", ChainPolicy::RunAll, 0);
+    assert_eq!(hooks.invoke("tc", &mut [0, 7], &mut []).ret, 0xaa);
+
+    assert!(hooks.detach("tc", "drop").is_some());
+    assert!(hooks.detach("tc", "drop").is_none());
+    assert_eq!(hooks.attached("tc"), vec!["mark", "verdict"]);
+    assert!(hooks.remove("tc"));
+    assert!(!hooks.is_defined("tc"));
+}
+
+#[test]
+#[should_panic(expected = "Error: no hook point named \"egress\"")]
+fn test_hooks_undefined() {
+    let hooks = rbpf::hooks::Hooks::new();
+    hooks.invoke("egress", &mut [], &mut []);
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_serde() {
+    use rbpf::trace::{AccessKind, MemAccess};
+    use rbpf::verifier::Region;
+    use serde_test::{assert_tokens, Token};
+
+    let insn = ebpf::Insn { opc: ebpf::ADD64_IMM, dst: 1, src: 0, off: -2, imm: 0x2a };
+    assert_tokens(&insn, &[
+        Token::Struct { name: "Insn", len: 5 },
+        Token::Str("opc"), Token::U8(ebpf::ADD64_IMM),
+        Token::Str("dst"), Token::U8(1),
+        Token::Str("src"), Token::U8(0),
+        Token::Str("off"), Token::I16(-2),
+        Token::Str("imm"), Token::I32(0x2a),
+        Token::StructEnd,
+    ]);
+
+    let access = MemAccess { kind: AccessKind::Store, addr: 0x1000, len: 2, value: 0x11 };
+    assert_tokens(&access, &[
+        Token::Struct { name: "MemAccess", len: 4 },
+        Token::Str("kind"), Token::UnitVariant { name: "AccessKind", variant: "Store" },
+        Token::Str("addr"), Token::U64(0x1000),
+        Token::Str("len"), Token::U64(2),
+        Token::Str("value"), Token::U64(0x11),
+        Token::StructEnd,
+    ]);
+
+    assert_tokens(&Region::Ctx, &[Token::UnitVariant { name: "Region", variant: "Ctx" }]);
+}
+
+// Generated programs must pass the verifier, and run without errors.
+#[test]
+#[cfg(feature = "fuzz")]
+fn test_fuzz_generator() {
+    use arbitrary::{Arbitrary, Unstructured};
+    use rbpf::fuzz::{ProgramGenerator, ValidProgram, DEFAULT_MEM_LEN};
+
+    // xorshift64
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut random_bytes = |len: usize| -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    };
+
+    for i in 0..500 {
+        let data = random_bytes(64 + i * 4);
+        let prog = ValidProgram::arbitrary(&mut Unstructured::new(&data)).unwrap().prog;
+        rbpf::verifier::check(&prog);
+        let mut mem: Vec<u8> = (0..DEFAULT_MEM_LEN as u8).collect();
+        let mut mem_jit = mem.clone();
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        let res = vm.prog_exec(&mut mem);
+        vm.jit_compile();
+        // The interpreter and the JIT agree, on the result and on the memory written.
+        assert_eq!(vm.prog_exec_jit(&mut mem_jit), res, "program {:x?}", prog);
+        assert_eq!(mem_jit, mem, "program {:x?}", prog);
+    }
+
+    // Without packet data.
+    let data = random_bytes(4096);
+    let prog = ProgramGenerator::new().mem_len(0).max_insns(200)
+        .generate(&mut Unstructured::new(&data)).unwrap();
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[cfg(feature = "fuzz")]
+fn test_fuzz_shrink() {
+    // The jump over the instructions removed must keep its target.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x02, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r2, 7
+        0x05, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +3
+        0x18, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // lddw r3, 0x200000001
+        0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x07, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // add64 r0, 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Returns 0x2a after a jump.
+    let is_failing = |p: &[u8]| {
+        let p = p.to_vec();
+        rbpf::verifier::check(&p);
+        let vm = rbpf::EbpfVmNoData::new(&p);
+        p[0] == ebpf::JA && vm.prog_exec() == 0x2a
+    };
+    let shrunk = rbpf::fuzz::shrink(&prog, is_failing);
+    assert_eq!(shrunk, vec![
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +0
+        0x07, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // add64 r0, 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]);
+}
+
+#[test]
+#[should_panic(expected = "Error: cannot reach instruction at index 2305843009213693952 in program containing 16 bytes")]
+fn test_get_insn_huge_index() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // The offset of the instruction, 8 * 2^61, overflows to 0.
+    rbpf::ebpf::get_insn(&prog, 1 << 61);
+}
+
+#[test]
+fn test_jit_array_lookups() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    // Increments the counter at index `key` of map 0, and returns it, or 0 if it is absent.
+    fn counter_prog(key: u32) -> Vec<u8> {
+        let mut prog = vec![
+            0x62, 0x0a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stw [r10-4], key
+            0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+            0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+            0x18, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, map 0
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+            0x15, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +4
+            0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+            0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+            0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+            0xbf, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        prog[4..8].copy_from_slice(&key.to_le_bytes());
+        prog
+    }
+
+    let map = Arc::new(Map::new(MapType::Array, 4, 8, 2));
+    for &(key, expected) in [(1, 1), (1, 2), (0, 1), (2, 0), (0xffff_ffff, 0)].iter() {
+        let prog = counter_prog(key);
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.register_map(map.clone());
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected);
+    }
+    assert_eq!(map.lookup(&[1, 0, 0, 0]), Some(2u64.to_le_bytes().to_vec()));
+
+    // The interpreter shares the counters.
+    let prog = counter_prog(1);
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(map.clone());
+    assert_eq!(vm.prog_exec(), 3);
+
+    // Lookups in hash maps are not inlined.
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_map(Arc::new(Map::new(MapType::Hash, 4, 8, 2)));
+    assert!(vm.try_jit_compile().is_err());
+}
+
+#[test]
+fn test_pseudo_map_fd() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    // Returns the handle of map 1, as produced by libbpf.
+    let prog = vec![
+        0x18, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // lddw r0, map 1
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (mut mem, mut mem_jit) = (vec![], vec![]);
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(Arc::new(Map::new(MapType::Array, 4, 8, 1)));
+    match vm.try_prog_exec(&mut []) {
+        Err(rbpf::error::EbpfError::UnknownMap { pc: 0, id: 1 }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(vm.try_jit_compile().is_err());
+
+    vm.register_map(Arc::new(Map::new(MapType::Array, 4, 8, 1)));
+    assert_eq!(vm.prog_exec(&mut mem), 1);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut mem_jit), 1);
+
+    // Map identifiers are 32-bit.
+    let mut prog = prog.clone();
+    prog[12] = 1;
+    assert!(rbpf::verifier::try_check(&prog).is_err());
+}
+
+#[test]
+fn test_jit_code_cache() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x20, 0x04, 0x00, 0x00, // mov r0, 0x420
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let compile = |helper: rbpf::ebpf::Helper| {
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.register_helper(1, helper);
+        vm.jit_compile();
+        vm
+    };
+
+    let first = compile(helpers::sqrti);
+    assert!(!first.stats().jit_code_shared);
+    let second = compile(helpers::sqrti);
+    assert!(first.stats().jit_code_shared);
+    assert!(second.stats().jit_code_shared);
+    assert_eq!(second.prog_exec_jit(), 0);
+
+    // Different helpers, different code.
+    let other = compile(helpers::gather_bytes);
+    assert!(!other.stats().jit_code_shared);
+}
+
+#[test]
+fn test_program_meta() {
+    use rbpf::error::EbpfError;
+    use rbpf::trace::ChromeTraceWriter;
+
+    let prog = vec![
+        0x71, 0x10, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+8]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0u8; 4];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+
+    // Without name nor tags, errors are not wrapped.
+    assert!(vm.meta().is_empty());
+    assert!(matches!(vm.try_prog_exec(&mut mem), Err(EbpfError::OutOfBounds { pc: 0, .. })));
+
+    vm.set_name("counter");
+    vm.set_tag("tenant", "t1");
+    vm.set_tag("zone", "z1");
+    vm.set_tag("tenant", "t2");
+    assert_eq!(vm.meta().to_string(), "counter tenant=t2 zone=z1");
+    let err = vm.try_prog_exec(&mut mem).unwrap_err();
+    assert_eq!(err.pc(), Some(0));
+    assert!(err.to_string().starts_with("[counter tenant=t2 zone=z1] Error: out of bounds memory load"));
+
+    // Traces are recorded on a track named after the program.
+    let mut tracer = ChromeTraceWriter::new(Vec::new());
+    assert_eq!(vm.prog_exec_trace(&mut [0u8; 16], &mut tracer), 0);
+    let json = String::from_utf8(tracer.finish().unwrap()).unwrap();
+    assert!(json.contains("\"args\":{\"name\":\"counter tenant=t2 zone=z1\"}"));
+
+    // Faults caught in JIT-compiled programs too.
+    vm.set_catch_faults(true);
+    vm.jit_compile();
+    match vm.try_prog_exec_jit(&mut []) {
+        Err(EbpfError::InProgram { program, error }) => {
+            assert_eq!(program.name, "counter");
+            assert!(matches!(*error, EbpfError::MemoryFault { ebpf_pc: 0, .. }));
+        },
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn test_map_introspection() {
+    use rbpf::maps::{Map, MapType};
+    use std::sync::Arc;
+
+    // Counts the packets for each value of their first byte, in a hash map.
+    let prog = vec![
+        0x71, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1]
+        0x63, 0x1a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r1
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x15, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +4
+        0x79, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r0]
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0x7b, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxdw [r0], r1
+        0x05, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +10
+        0x7a, 0x0a, 0xf0, 0xff, 0x01, 0x00, 0x00, 0x00, // stdw [r10-16], 1
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // add64 r2, -4
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r3, -16
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, BPF_ANY
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call bpf_map_update_elem
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(Arc::new(Map::new(MapType::Hash, 4, 8, 16)));
+    for &byte in [1u8, 2, 1, 3, 1].iter() {
+        vm.prog_exec(&mut vec![byte]);
+    }
+
+    let map = vm.maps()[0].clone();
+    let mut counters: Vec<(u32, u64)> = map.iter().map(|(key, value)| {
+        let mut key_bytes = [0; 4];
+        let mut value_bytes = [0; 8];
+        key_bytes.copy_from_slice(&key);
+        value_bytes.copy_from_slice(&value);
+        (u32::from_le_bytes(key_bytes), u64::from_le_bytes(value_bytes))
+    }).collect();
+    counters.sort();
+    assert_eq!(counters, vec![(1, 3), (2, 1), (3, 1)]);
+
+    let keys: Vec<[u8; 4]> = (1..5u32).map(|key| key.to_le_bytes()).collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+    assert_eq!(map.lookup_batch(&keys), vec![
+        Some(3u64.to_le_bytes().to_vec()),
+        Some(1u64.to_le_bytes().to_vec()),
+        Some(1u64.to_le_bytes().to_vec()),
+        None,
+    ]);
+
+    map.clear();
+    assert!(map.is_empty());
+    assert_eq!(map.iter().len(), 0);
+    vm.prog_exec(&mut vec![2]);
+    assert_eq!(map.lookup(&2u32.to_le_bytes()), Some(1u64.to_le_bytes().to_vec()));
+
+    // Clearing an array sets its values to zero.
+    let array = Map::new(MapType::Array, 4, 2, 3);
+    array.update(&1u32.to_le_bytes(), &[1, 2], rbpf::maps::BPF_ANY).unwrap();
+    assert_eq!(array.iter().nth(1), Some((vec![1, 0, 0, 0], vec![1, 2])));
+    array.clear();
+    assert!(array.iter().all(|(_, value)| value == vec![0, 0]));
+}
+
+#[test]
+fn test_redirect_map() {
+    use rbpf::ebpf::XdpAction;
+    use rbpf::helpers::E2BIG;
+    use rbpf::maps::{self, Map, MapType, Redirect};
+    use std::sync::Arc;
+
+    // Redirects packets to the port given by their first byte, with the flags given by the second
+    // one, in the map given by the third one.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x71, 0x13, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r3, [r1+1]
+        0x71, 0x11, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1+2]
+        0x85, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00, // call bpf_redirect_map
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let ports = Arc::new(Map::new(MapType::DevMap, 4, 4, 4));
+    ports.update(&3u32.to_le_bytes(), &7u32.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(ports.update(&4u32.to_le_bytes(), &7u32.to_le_bytes(), maps::BPF_ANY), Err(E2BIG));
+
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(ports.clone());
+    vm.register_map(Arc::new(Map::new(MapType::Array, 4, 4, 4)));
+
+    let redirect = Redirect { map: 0, key: 3, flags: 2 };
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [3, 2, 0]), (XdpAction::Redirect, Some(redirect)));
+    // Absent target: the action in the flags.
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [1, 2, 0]), (XdpAction::Pass, None));
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [1, 1, 0]), (XdpAction::Drop, None));
+    // Invalid flags, not a map of redirect targets, or unknown map.
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [3, 4, 0]), (XdpAction::Aborted, None));
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [3, 0, 1]), (XdpAction::Aborted, None));
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [3, 0, 2]), (XdpAction::Aborted, None));
+    // Other execution methods ignore the redirect.
+    assert_eq!(vm.prog_exec_xdp(&mut [3, 2, 0]), XdpAction::Redirect);
+
+    ports.delete(&3u32.to_le_bytes()).unwrap();
+    assert_eq!(vm.prog_exec_xdp_redirect(&mut [3, 2, 0]), (XdpAction::Pass, None));
+
+    let mut packet = [0, 0, 0];
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    vm.register_map(ports.clone());
+    ports.update(&0u32.to_le_bytes(), &7u32.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(vm.try_prog_exec_xdp_redirect(&mut packet).unwrap(),
+               (XdpAction::Redirect, Some(Redirect { map: 0, key: 0, flags: 0 })));
+}
+
+#[test]
+fn test_lpm_trie() {
+    use rbpf::helpers::{EINVAL, ENOENT};
+    use rbpf::maps::{self, Map, MapType};
+    use std::sync::Arc;
+
+    fn key(prefixlen: u32, addr: [u8; 4]) -> Vec<u8> {
+        let mut key = prefixlen.to_le_bytes().to_vec();
+        key.extend_from_slice(&addr);
+        key
+    }
+
+    let routes = Arc::new(Map::new(MapType::LpmTrie, 8, 8, 16));
+    routes.update(&key(0, [0, 0, 0, 0]), &1u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    // Bits beyond the prefix are ignored.
+    routes.update(&key(12, [172, 31, 0, 1]), &2u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(routes.update(&key(12, [172, 16, 0, 0]), &3u64.to_le_bytes(), maps::BPF_NOEXIST),
+               Err(rbpf::helpers::EEXIST));
+    routes.update(&key(32, [172, 16, 0, 1]), &4u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(routes.update(&key(33, [172, 16, 0, 1]), &4u64.to_le_bytes(), maps::BPF_ANY), Err(EINVAL));
+    assert_eq!(routes.len(), 3);
+
+    // Looks up the route for the IPv4 address in packet data, returns its value or 0.
+    let prog = vec![
+        0x61, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1]
+        0x63, 0x2a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r2
+        0x62, 0x0a, 0xf8, 0xff, 0x20, 0x00, 0x00, 0x00, // stw [r10-8], 32
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r2, -8
+        0x18, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // lddw r1, map 0
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x15, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +1
+        0x79, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r0]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(routes.clone());
+    assert_eq!(vm.prog_exec(&mut vec![172, 16, 0, 1]), 4);
+    assert_eq!(vm.prog_exec(&mut vec![172, 16, 0, 2]), 2);
+    assert_eq!(vm.prog_exec(&mut vec![172, 31, 255, 255]), 2);
+    assert_eq!(vm.prog_exec(&mut vec![172, 32, 0, 1]), 1);
+
+    // Lookups match prefixes up to the prefix length of the key.
+    assert_eq!(routes.lookup(&key(8, [172, 16, 0, 1])), Some(1u64.to_le_bytes().to_vec()));
+    assert_eq!(routes.lookup(&key(64, [172, 16, 0, 1])), Some(4u64.to_le_bytes().to_vec()));
+
+    assert_eq!(routes.delete(&key(12, [172, 20, 0, 0])), Ok(()));
+    assert_eq!(routes.delete(&key(12, [172, 20, 0, 0])), Err(ENOENT));
+    assert_eq!(vm.prog_exec(&mut vec![172, 16, 0, 2]), 1);
+    routes.delete(&key(0, [1, 2, 3, 4])).unwrap();
+    assert_eq!(vm.prog_exec(&mut vec![172, 16, 0, 2]), 0);
+    assert_eq!(routes.iter().map(|(key, _)| key).collect::<Vec<_>>(), vec![key(32, [172, 16, 0, 1])]);
+}
+
+#[test]
+fn test_lpm_trie_overlapping_prefixes() {
+    use rbpf::helpers::E2BIG;
+    use rbpf::maps::{self, Map, MapType};
+
+    fn key(prefixlen: u32, addr: [u8; 4]) -> Vec<u8> {
+        let mut key = prefixlen.to_le_bytes().to_vec();
+        key.extend_from_slice(&addr);
+        key
+    }
+    let value = |routes: &Map, len, addr| routes.lookup(&key(len, addr)).map(|value| value[0]);
+
+    let routes = Map::new(MapType::LpmTrie, 8, 1, 5);
+    for &(len, addr, value) in [(8, [10, 0, 0, 0], 8), (16, [10, 1, 0, 0], 16), (24, [10, 1, 2, 0], 24),
+                                (9, [10, 128, 0, 0], 9), (31, [10, 1, 2, 2], 31)].iter() {
+        routes.update(&key(len, addr), &[value], maps::BPF_NOEXIST).unwrap();
+    }
+    assert_eq!(routes.update(&key(32, [10, 1, 2, 3]), &[32], maps::BPF_ANY), Err(E2BIG));
+
+    assert_eq!(value(&routes, 32, [10, 1, 2, 3]), Some(31));
+    assert_eq!(value(&routes, 32, [10, 1, 2, 4]), Some(24));
+    assert_eq!(value(&routes, 32, [10, 1, 3, 3]), Some(16));
+    assert_eq!(value(&routes, 32, [10, 2, 0, 0]), Some(8));
+    assert_eq!(value(&routes, 32, [10, 129, 0, 0]), Some(9));
+    assert_eq!(value(&routes, 32, [11, 1, 2, 3]), None);
+    // The prefix length of the key bounds the prefixes matched.
+    assert_eq!(value(&routes, 30, [10, 1, 2, 3]), Some(24));
+    assert_eq!(value(&routes, 23, [10, 1, 2, 3]), Some(16));
+    assert_eq!(value(&routes, 15, [10, 1, 2, 3]), Some(8));
+    assert_eq!(value(&routes, 7, [10, 1, 2, 3]), None);
+
+    // Removing a prefix exposes the shorter ones, and keeps the longer ones.
+    routes.delete(&key(16, [10, 1, 0, 0])).unwrap();
+    assert_eq!(value(&routes, 32, [10, 1, 3, 3]), Some(8));
+    assert_eq!(value(&routes, 32, [10, 1, 2, 4]), Some(24));
+    routes.delete(&key(8, [10, 0, 0, 0])).unwrap();
+    assert_eq!(value(&routes, 32, [10, 1, 3, 3]), None);
+    assert_eq!(value(&routes, 32, [10, 1, 2, 3]), Some(31));
+    routes.update(&key(32, [10, 1, 2, 3]), &[32], maps::BPF_ANY).unwrap();
+    assert_eq!(value(&routes, 32, [10, 1, 2, 3]), Some(32));
+    assert_eq!(value(&routes, 32, [10, 1, 2, 2]), Some(31));
+
+    let mut entries: Vec<_> = routes.iter().map(|(key, value)| (key[0], value[0])).collect();
+    entries.sort();
+    assert_eq!(entries, vec![(9, 9), (24, 24), (31, 31), (32, 32)]);
+    routes.clear();
+    assert_eq!(value(&routes, 32, [10, 1, 2, 3]), None);
+    assert!(routes.is_empty());
+
+    // Keys hold at most 256 bytes of data, as in the kernel: the trie has one level per bit.
+    let res = std::panic::catch_unwind(|| Map::new(MapType::LpmTrie, 4 + 257, 1, 1));
+    assert!(res.is_err());
+    let deep = Map::new(MapType::LpmTrie, 4 + 256, 1, 2);
+    let mut key = (256u32 * 8).to_le_bytes().to_vec();
+    key.extend_from_slice(&[0xa5; 256]);
+    deep.update(&key, &[1], maps::BPF_ANY).unwrap();
+    deep.update(&key[..4].iter().chain(&[0xa5; 128]).chain(&[0; 128]).cloned().collect::<Vec<_>>(), &[2],
+                maps::BPF_ANY).unwrap();
+    assert_eq!(deep.lookup(&key), Some(vec![1]));
+    deep.delete(&key).unwrap();
+    assert_eq!(deep.lookup(&key), None);
+    deep.update(&key, &[1], maps::BPF_ANY).unwrap();
+    drop(deep);
+}
+
+#[test]
+fn test_testing_support() {
+    use rbpf::maps::{self, Map, MapType};
+    use rbpf::testing::{HelperRecorder, MapSnapshot};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    // Calls helpers 40 and 41 with the first byte of packet data, stores their results at index 0
+    // and 1 of map 0.
+    let prog = vec![
+        0x71, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r6, [r1]
+        0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+        0x85, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, // call 40
+        0x63, 0x0a, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-4], r0
+        0xbf, 0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r6
+        0x85, 0x00, 0x00, 0x00, 0x29, 0x00, 0x00, 0x00, // call 41
+        0x63, 0x0a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxw [r10-8], r0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+        0xbf, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r10
+        0x07, 0x02, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r2, -16
+        0x62, 0x0a, 0xf0, 0xff, 0x01, 0x00, 0x00, 0x00, // stw [r10-16], 1
+        0xbf, 0xa3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r10
+        0x07, 0x03, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, // add64 r3, -8
+        0xb7, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, BPF_ANY
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // call bpf_map_update_elem
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    fn double(x: u64, _: u64, _: u64, _: u64, _: u64) -> u64 { x * 2 }
+    fn square(x: u64, _: u64, _: u64, _: u64, _: u64) -> u64 { x * x }
+
+    let map = Arc::new(Map::new(MapType::Array, 4, 4, 2));
+    map.update(&0u32.to_le_bytes(), &[0xaa; 4], maps::BPF_ANY).unwrap();
+    let recorder = HelperRecorder::new();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(map.clone());
+    vm.set_helper_hook(Box::new(&recorder));
+    vm.register_helper(40, double);
+    vm.register_helper(41, square);
+
+    let before = MapSnapshot::take(&map);
+    vm.prog_exec(&mut vec![7]);
+    let after = MapSnapshot::take(&map);
+
+    // The map helper, run by the interpreter, is recorded as well.
+    assert_eq!(recorder.sequence(), vec![40, 41, 2]);
+    assert_eq!(recorder.calls_to(41)[0].args, [7, 0, 0, 0, 0]);
+    assert_eq!(recorder.calls_to(41)[0].ret, 49);
+    assert_helper_called!(recorder, 40);
+    assert_helper_called!(recorder, 41, 1);
+    assert_map_contains!(after, 1u32.to_le_bytes(), 49u32.to_le_bytes());
+    assert_map_contains!(before, 0u32.to_le_bytes(), [0xaa; 4]);
+    assert_map_contains!(map, 1u32.to_le_bytes());
+    assert_eq!(before.changed_keys(&after), vec![1u32.to_le_bytes().to_vec()]);
+
+    recorder.clear();
+    vm.prog_exec(&mut vec![3]);
+    assert_helper_called!(recorder, 40, 1);
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| assert_helper_called!(recorder, 42))).is_err());
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| assert_helper_called!(recorder, 41, 2))).is_err());
+    assert!(panic::catch_unwind(|| assert_map_contains!(after, 1u32.to_le_bytes(), [0; 4])).is_err());
+    assert!(panic::catch_unwind(|| assert_map_contains!(map, 2u32.to_le_bytes())).is_err());
+}
+
+#[test]
+fn test_prog_limits() {
+    use rbpf::verifier::{self, ProgLimits};
+    use std::panic::{self, AssertUnwindSafe};
+
+    // A loop, closed by a backward jump.
+    let looping = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r0, 10
+        0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r0, 1
+        0x55, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r0, 0, -2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let straight = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(verifier::complexity(&straight), 2);
+    assert_eq!(verifier::complexity(&looping), 8);
+
+    let limits = ProgLimits { max_insns: 4, max_complexity: 6 };
+    assert!(verifier::check_limits(&straight, &limits).is_ok());
+    assert_eq!(verifier::check_limits(&looping, &limits).unwrap_err().to_string(),
+               "[Verifier] Error: estimated verification complexity of 8 exceeds the limit of 6");
+    let oversize = vec![0x07; 5 * 8];
+    assert_eq!(verifier::check_limits(&oversize, &limits).unwrap_err().to_string(),
+               "[Verifier] Error: program of 5 instructions exceeds the limit of 4 instructions");
+
+    let mut vm = rbpf::EbpfVmNoData::new(&straight);
+    vm.set_prog_limits(limits);
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| vm.set_prog(&looping))).is_err());
+    assert_eq!(vm.prog_exec(), 1);
+    vm.set_prog_limits(ProgLimits::default());
+    vm.set_prog(&looping);
+    assert_eq!(vm.prog_exec(), 0);
+
+    // The limits are checked before the verifier: the invalid oversize program is rejected for
+    // its size.
+    let mut vm = rbpf::EbpfVmMbuff::new(&straight);
+    vm.set_prog_limits(limits);
+    let err = panic::catch_unwind(AssertUnwindSafe(|| vm.set_prog(&oversize))).unwrap_err();
+    assert!(err.downcast_ref::<String>().unwrap().contains("exceeds the limit of 4 instructions"));
+}
+
+#[test]
+fn test_frame_helpers() {
+    use rbpf::error::EbpfError;
+    use rbpf::testing::HelperRecorder;
+
+    // Weighted sum of seven arguments.
+    fn weighted(args: [u64; 7]) -> u64 {
+        args.iter().enumerate().map(|(i, arg)| (i as u64 + 1) * arg).sum()
+    }
+
+    // Stores 1 to 7 on the stack, and calls helper 1 with them as frame.
+    let mut prog = vec![];
+    for i in 0..7u8 {
+        prog.extend_from_slice(&[0x7a, 0x0a, 0xc8 + 8 * i, 0xff, i + 1, 0x00, 0x00, 0x00]); // stdw [r10-56+8*i], i+1
+    }
+    prog.extend_from_slice(&[
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xc8, 0xff, 0xff, 0xff, // add64 r1, -56
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]);
+    let recorder = HelperRecorder::new();
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_frame_helper(1, weighted);
+    vm.set_helper_hook(Box::new(&recorder));
+    assert_eq!(vm.prog_exec(), 140);
+    assert_eq!(recorder.calls()[0].ret, 140);
+
+    // The frame must belong to the memory of the program: here, it overflows the stack.
+    let mut overflow = prog.clone();
+    overflow[7 * 8 + 12] = 0xd0;
+    let mut vm = rbpf::EbpfVmNoData::new(&overflow);
+    vm.register_frame_helper(1, weighted);
+    match vm.try_prog_exec() {
+        Err(EbpfError::OutOfBounds { .. }) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Registering a helper under the same key replaces the frame helper.
+    vm.register_helper(1, |r1, _, _, _, _| r1 & 0xf);
+    assert_eq!(vm.prog_exec(), 0);
+}
+
+#[test]
+fn test_pin_dir() {
+    use std::io::ErrorKind;
+    use rbpf::maps::{self, MapType};
+    use rbpf::pin::PinDir;
+    use rbpf::program::{Program, ProgramConfig};
+
+    let path = std::env::temp_dir().join(format!("rbpf-pin-test-{}", std::process::id()));
+    let dir = PinDir::open(&path).unwrap();
+
+    // Programs keep the version of the instruction set they were verified against.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mov64 r0, 3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let config = ProgramConfig { isa: ebpf::IsaVersion::V2 };
+    dir.pin_program("prog", &Program::verify(prog.clone(), config).unwrap()).unwrap();
+    let program = dir.open_program("prog").unwrap();
+    assert_eq!(program.bytes(), &prog[..]);
+    assert_eq!(program.config(), &config);
+    assert_eq!(rbpf::EbpfVmNoData::from_program(&program).prog_exec(), 3);
+
+    // Array maps are shared by the maps opened from the same file.
+    let array = dir.create_map("array", MapType::Array, 4, 8, 4).unwrap();
+    let opened = dir.open_map("array").unwrap();
+    array.update(&2u32.to_le_bytes(), &7u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(opened.lookup(&2u32.to_le_bytes()), Some(7u64.to_le_bytes().to_vec()));
+    opened.clear();
+    assert_eq!(array.lookup(&2u32.to_le_bytes()), Some(vec![0; 8]));
+
+    // Other maps are pinned as a copy of their entries.
+    let hash = maps::Map::new(MapType::Hash, 2, 1, 8);
+    hash.update(&[0, 1], &[10], maps::BPF_ANY).unwrap();
+    dir.pin_map("hash", &hash).unwrap();
+    hash.update(&[0, 2], &[20], maps::BPF_ANY).unwrap();
+    let opened = dir.open_map("hash").unwrap();
+    assert_eq!(opened.map_type(), MapType::Hash);
+    assert_eq!(opened.iter().collect::<Vec<_>>(), vec![(vec![0, 1], vec![10])]);
+
+    assert_eq!(dir.names().unwrap(), vec!["array", "hash", "prog"]);
+    assert_eq!(dir.pin_map("prog", &hash).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(dir.pin_map(".tmp", &hash).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(dir.open_map("prog").err().unwrap().kind(), ErrorKind::InvalidData);
+    assert_eq!(dir.open_program("array").unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Maps opened remain valid once unpinned.
+    dir.unpin("array").unwrap();
+    assert_eq!(dir.open_map("array").err().unwrap().kind(), ErrorKind::NotFound);
+    array.update(&1u32.to_le_bytes(), &5u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(array.lookup(&1u32.to_le_bytes()), Some(5u64.to_le_bytes().to_vec()));
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_shm_map() {
+    use std::io::ErrorKind;
+    use rbpf::maps::{self, MapType};
+    use rbpf::shm;
+
+    // Hash maps are shared with their entries.
+    let name = format!("/rbpf-shm-test-{}", std::process::id());
+    let map = shm::create(&name, MapType::Hash, 2, 4, 2).unwrap();
+    let opened = shm::open(&name).unwrap();
+    assert_eq!(shm::create(&name, MapType::Hash, 2, 4, 2).err().unwrap().kind(), ErrorKind::AlreadyExists);
+    shm::unlink(&name).unwrap();
+    assert_eq!(shm::open(&name).err().unwrap().kind(), ErrorKind::NotFound);
+
+    map.update(&[0, 1], &[1, 0, 0, 0], maps::BPF_ANY).unwrap();
+    map.update(&[0, 2], &[2, 0, 0, 0], maps::BPF_NOEXIST).unwrap();
+    assert_eq!(opened.len(), 2);
+    assert_eq!(opened.lookup(&[0, 2]), Some(vec![2, 0, 0, 0]));
+    assert_eq!(opened.update(&[0, 3], &[3, 0, 0, 0], maps::BPF_ANY), Err(rbpf::helpers::E2BIG));
+    assert_eq!(opened.update(&[0, 1], &[4, 0, 0, 0], maps::BPF_NOEXIST), Err(rbpf::helpers::EEXIST));
+    opened.update(&[0, 1], &[4, 0, 0, 0], maps::BPF_EXIST).unwrap();
+    assert_eq!(map.lookup(&[0, 1]), Some(vec![4, 0, 0, 0]));
+
+    // Deleted entries are not found, and their slots are reused.
+    opened.delete(&[0, 1]).unwrap();
+    assert_eq!(map.lookup(&[0, 1]), None);
+    assert_eq!(map.delete(&[0, 1]), Err(rbpf::helpers::ENOENT));
+    map.update(&[0, 3], &[3, 0, 0, 0], maps::BPF_ANY).unwrap();
+    let mut entries: Vec<_> = opened.iter().collect();
+    entries.sort();
+    assert_eq!(entries, vec![(vec![0, 2], vec![2, 0, 0, 0]), (vec![0, 3], vec![3, 0, 0, 0])]);
+    map.clear();
+    assert!(opened.is_empty());
+
+    // Programs update the values in place.
+    let prog = vec![
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1 (key)
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0 (map)
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call bpf_map_lookup_elem
+        0x15, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r0, 0, +1
+        0x72, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r0], 42
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    map.update(&[0, 5], &[0; 4], maps::BPF_ANY).unwrap();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_map(map);
+    vm.prog_exec(&mut vec![0, 5]);
+    assert_eq!(opened.lookup(&[0, 5]), Some(vec![42, 0, 0, 0]));
+
+    // Array maps are shared through anonymous files too.
+    let (array, file) = shm::create_memfd("counters", MapType::Array, 4, 8, 4).unwrap();
+    let opened = shm::open_file(&file).unwrap();
+    array.update(&3u32.to_le_bytes(), &9u64.to_le_bytes(), maps::BPF_ANY).unwrap();
+    assert_eq!(opened.lookup(&3u32.to_le_bytes()), Some(9u64.to_le_bytes().to_vec()));
+
+    assert!(shm::create_memfd("trie", MapType::LpmTrie, 8, 1, 4).is_err());
+    let mut file = tempfile_with(b"RBPX");
+    assert_eq!(shm::open_file(&file).err().unwrap().kind(), ErrorKind::InvalidData);
+    file = tempfile_with(&[0; 8]);
+    assert_eq!(shm::open_file(&file).err().unwrap().kind(), ErrorKind::InvalidData);
+
+    fn tempfile_with(contents: &[u8]) -> std::fs::File {
+        use std::io::Write;
+        let (_, mut file) = shm::create_memfd("invalid", MapType::Array, 4, 1, 1).unwrap();
+        file.set_len(0).unwrap();
+        file.write_all(contents).unwrap();
+        file
+    }
+}
+
+#[test]
+#[cfg(all(feature = "kernel", target_os = "linux"))]
+fn test_kernel_checker() {
+    use rbpf::kernel::KernelChecker;
+
+    // Returns the first byte of the packet, after setting the second one to 0x2a.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+        0x61, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r2, [r1] (ctx->data)
+        0x61, 0x13, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxw r3, [r1+4] (ctx->data_end)
+        0xbf, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r2
+        0x07, 0x04, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r4, 2
+        0x2d, 0x34, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, // jgt r4, r3, +2
+        0x71, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r2]
+        0x72, 0x02, 0x01, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r2+1], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    // Without the capability to load programs, only check the rbpf side.
+    let checker = match KernelChecker::load(&prog) {
+        Ok(checker) => checker,
+        Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => return,
+        Err(err) => panic!("{}", err),
+    };
+    let (res, output) = checker.run_rbpf(&[3; 14]);
+    assert_eq!(res, Ok(3));
+    assert_eq!(output[..2], [3, 0x2a]);
+
+    let corpus = (0..16).map(|b| vec![b; 14]);
+    assert_eq!(checker.check_inputs(corpus).unwrap(), None);
+
+    // Accesses to other fields of the context are rejected.
+    let mut prog = prog;
+    prog[10] = 0x08;
+    assert_eq!(KernelChecker::load(&prog).err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_test_run() {
+    // Increments the first byte of the packet, and returns it.
+    let prog = vec![
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x73, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1], r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    let run = vm.test_run(&[0xfe, 1], 0).unwrap();
+    assert_eq!(run.retval, 0xff);
+    assert_eq!(run.data_out, vec![0xff, 1]);
+    assert!(run.ctx_out.is_empty());
+
+    // Runs share the same data.
+    let run = vm.test_run(&[0xfe, 1], 3).unwrap();
+    assert_eq!(run.retval, 1);
+    assert_eq!(run.data_out, vec![1, 1]);
+
+    assert!(vm.test_run(&[], 1).is_err());
+
+    // The value returned is truncated to 32 bits.
+    let prog = vec![
+        0x18, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // lddw r0, 0x10000002a
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(rbpf::EbpfVmRaw::new(&prog).test_run(&[], 1).unwrap().retval, 0x2a);
+
+    // The pointers to packet data are stored in the context, and returned as offsets.
+    let prog = vec![
+        0x79, 0x12, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r1+16] (data)
+        0x71, 0x20, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r2+1]
+        0x71, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r3, [r1]
+        0x0f, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x10, 0x18);
+    let run = vm.test_run(&[1, 2, 3], &[40], 10).unwrap();
+    assert_eq!(run.retval, 42);
+    assert_eq!(run.ctx_out.len(), 0x20);
+    assert_eq!(run.ctx_out[0], 40);
+    assert_eq!(&run.ctx_out[0x10..0x20], &[0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_cost_tracer() {
+    use rbpf::cost::{CostModel, CostTracer, DefaultCostModel, InsnClass};
+
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r0, 5
+        0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +1
+        0x37, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // div64 r0, 2 (skipped)
+        0x27, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mul64 r0, 3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    let mut tracer = CostTracer::new();
+    assert_eq!(vm.prog_exec_trace(&mut tracer), 15);
+    {
+        let report = tracer.report();
+        assert_eq!(report.runs, 1);
+        assert_eq!(report.insns, 4);
+        assert_eq!(report.cycles, 1 + 1 + 3 + 1);
+        assert_eq!(report.by_class[&InsnClass::Mul], 3);
+        assert!(!report.by_class.contains_key(&InsnClass::Div));
+        assert_eq!(report.by_insn, vec![1, 1, 0, 3, 1]);
+    }
+    vm.prog_exec_trace(&mut tracer);
+    assert_eq!(tracer.report().runs, 2);
+    assert_eq!(tracer.report().cycles_per_run(), 6.0);
+    tracer.reset();
+    assert_eq!(tracer.report().cycles_per_run(), 0.0);
+
+    // Models can be changed or replaced.
+    let mut model = DefaultCostModel::new();
+    model.set_class_cost(InsnClass::Mul, 20);
+    let mut tracer = CostTracer::with_model(model);
+    vm.prog_exec_trace(&mut tracer);
+    assert_eq!(tracer.report().cycles, 23);
+
+    struct Unit;
+    impl CostModel for Unit {
+        fn cycles(&self, _insn: &ebpf::Insn) -> u64 { 1 }
+    }
+    let mut tracer = CostTracer::with_model(Unit);
+    vm.prog_exec_trace(&mut tracer);
+    assert_eq!(tracer.report().cycles, tracer.report().insns);
+
+    // Helpers are given their own cost.
+    let prog = vec![
+        0x85, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // call 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.register_helper(0, helpers::sqrti);
+    let mut model = DefaultCostModel::new();
+    model.set_helper_cost(0, 30);
+    let mut tracer = CostTracer::with_model(model);
+    vm.prog_exec_trace(&mut tracer);
+    assert_eq!(tracer.report().cycles, 31);
+    assert_eq!(tracer.report().runs, 1);
+}
+
+#[test]
+fn test_worst_case() {
+    use rbpf::{analysis, verifier};
+    use rbpf::assembler::assemble;
+    use rbpf::cost::CostTracer;
+    use std::collections::HashMap;
+
+    let executed = |prog: &Vec<u8>, mem: &mut [u8]| {
+        let vm = rbpf::EbpfVmRaw::new(prog);
+        let mut tracer = CostTracer::new();
+        vm.prog_exec_trace(mem, &mut tracer);
+        tracer.report().insns
+    };
+
+    // Nested loops, running 3 and 4 times.
+    let nested = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0xb7, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, 0
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x07, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r2, 1
+        0xa5, 0x02, 0xfd, 0xff, 0x03, 0x00, 0x00, 0x00, // jlt r2, 3, -3
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0xa5, 0x01, 0xfa, 0xff, 0x04, 0x00, 0x00, 0x00, // jlt r1, 4, -6
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let worst = analysis::worst_case(&nested, &HashMap::new()).unwrap();
+    assert_eq!(worst.loop_bounds.iter().collect::<Vec<_>>(), vec![(&5, &2), (&7, &3)]);
+    assert_eq!(worst.insns, 51);
+    assert_eq!(executed(&nested, &mut [0]), 51);
+
+    // The longest path through the body of a loop counts for each iteration.
+    let branchy = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x45, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, // jset r1, 1, +1
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0xa5, 0x01, 0xfc, 0xff, 0x08, 0x00, 0x00, 0x00, // jlt r1, 8, -4
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(analysis::worst_case(&branchy, &HashMap::new()).unwrap().insns, 2 + 8 * 4 + 1);
+    assert_eq!(executed(&branchy, &mut [0]), 2 + 4 * 4 + 4 * 3 + 1);
+
+    // Loops counting from an input need a bound.
+    let counted = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x17, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r2, 1
+        0x65, 0x02, 0xfd, 0xff, 0x00, 0x00, 0x00, 0x00, // jsgt r2, 0, -3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(analysis::worst_case(&counted, &HashMap::new()).unwrap_err(),
+               "no bound for the loop closed by insn #4");
+    let mut bounds = HashMap::new();
+    bounds.insert(4, 254);
+    assert_eq!(analysis::worst_case(&counted, &bounds).unwrap().insns, 2 + 255 * 3 + 1);
+    assert_eq!(executed(&counted, &mut [255]), 2 + 255 * 3 + 1);
+    assert_eq!(verifier::check_worst_case(&counted, &bounds, 100).unwrap_err().to_string(),
+               "[Verifier] Error: program may execute 768 instructions, more than the budget of 100");
+
+    // Loops must be entered through their first instruction.
+    let entered = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x15, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r1, 0, +1
+        0x07, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r0, 1
+        0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+        0xa5, 0x00, 0xfd, 0xff, 0x0a, 0x00, 0x00, 0x00, // jlt r0, 10, -3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(analysis::worst_case(&entered, &HashMap::new()).unwrap_err(),
+               "jump into the loop starting at insn #2 (insn #1)");
+    assert_eq!(verifier::check_worst_case(&entered, &HashMap::new(), 100).unwrap_err().to_string(),
+               "[Verifier] Error: jump into the loop starting at insn #2 (insn #1)");
+
+    // Programs without loops.
+    let prog = assemble("
+        jeq r1, 0, +2
+        mov64 r0, 1
+        add64 r0, 1
+        exit").unwrap();
+    assert_eq!(verifier::check_worst_case(&prog, &HashMap::new(), 4), Ok(4));
+    assert_eq!(analysis::worst_case(&[], &HashMap::new()).unwrap().insns, 0);
+}
+
+#[test]
+fn test_jit_profile() {
+    use rbpf::error::EbpfError;
+    use rbpf::profile::Profile;
+
+    // Sums the values from the first byte of the packet down to 1, adding 100 for even values.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x15, 0x02, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, // jeq r2, 0, +5
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r2
+        0x45, 0x02, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, // jset r2, 1, +1
+        0x07, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, // add64 r0, 100
+        0x17, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r2, 1
+        0x55, 0x02, 0xfb, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r2, 0, -5
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut profile = Profile::new();
+    let mut packets: Vec<Vec<u8>> = (0..20).map(|b| vec![b]).collect();
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    for packet in packets.iter_mut() {
+        vm.prog_exec_trace(packet, &mut profile);
+    }
+    assert_eq!(profile.runs(), 20);
+    assert_eq!(profile.executions(3), (1..20).sum::<u64>());
+    assert_eq!(profile.taken(2), 1);
+    assert_eq!(profile.taken(4), (1..20).filter(|b| b % 2 == 1).sum::<u64>());
+    assert_eq!(profile.opcode_count(ebpf::EXIT), 20);
+    let mut layout = profile.layout(&prog);
+    assert_eq!(layout[0], 0);
+    layout.sort_unstable();
+    assert_eq!(layout, vec![0, 3, 5, 6, 8]);
+
+    // The code laid out with the profile, hardened or not, computes the same results.
+    for &harden in [false, true].iter() {
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        vm.set_jit_hardening(harden);
+        vm.set_jit_profile(Some(profile.clone()));
+        vm.jit_compile();
+        for b in 0..=255u8 {
+            let expected: u64 = (1..=b as u64).map(|v| v + if v % 2 == 0 { 100 } else { 0 }).sum();
+            assert_eq!(vm.prog_exec_jit(&mut vec![b]), expected);
+        }
+    }
+
+    // Faults are reported at the right instruction in blocks moved out of order.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x55, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, // jne r2, 0, +3
+        0x79, 0x11, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1+8]
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut profile = Profile::new();
+    let mut packet = vec![1u8; 16];
+    let vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.prog_exec_trace(&mut packet, &mut profile);
+    assert_eq!(profile.layout(&prog), vec![0, 5, 2]);
+
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_jit_profile(Some(profile));
+    vm.set_catch_faults(true);
+    vm.jit_compile();
+    assert_eq!(vm.try_prog_exec_jit(&mut [1; 16]), Ok(1));
+    let err = vm.try_prog_exec_jit(&mut [0; 16]).unwrap_err();
+    assert!(matches!(err, EbpfError::MemoryFault { ebpf_pc: 3, .. }));
+}
+
+#[test]
+fn test_memory_poisoning() {
+    use rbpf::error::EbpfError;
+
+    let prog = vec![
+        0xbf, 0xa1, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, r10
+        0x07, 0x01, 0x00, 0x00, 0xf0, 0xff, 0xff, 0xff, // add64 r1, -16
+        0xb7, 0x02, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r2, 7
+        0xb7, 0x03, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // mov64 r3, 8
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x7f, // call memset
+        0x62, 0x0a, 0xf8, 0xff, 0x01, 0x00, 0x00, 0x00, // stw [r10-8], 1
+        0x79, 0xa0, 0xf0, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r0, [r10-16]
+        0x79, 0xa2, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r2, [r10-8]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_mem_helpers();
+    assert_eq!(vm.try_prog_exec(&mut []), Ok(0x0707_0707_0707_0707));
+
+    // The bytes written by the helper and the store are initialized, not the upper half of the
+    // last word loaded.
+    vm.set_memory_poisoning(true);
+    let err = vm.try_prog_exec(&mut []).unwrap_err();
+    assert_eq!(err, EbpfError::UninitializedRead { pc: 7, off: -8, len: 8 });
+    assert_eq!(err.pc(), Some(7));
+    assert_eq!(err.to_string(), "Error: load of uninitialized stack at r10-8 (insn #7), size 8");
+
+    // The padding of the metadata buffer is poisoned too.
+    let prog = vec![
+        0x71, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (mut mem1, mut mem2, mut mem3) = (vec![], vec![], vec![]);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 8, 16);
+    assert_eq!(vm.prog_exec(&mut mem1), 0);
+    vm.set_memory_poisoning(true);
+    assert_eq!(vm.prog_exec(&mut mem2), ebpf::POISON_BYTE as u64);
+    vm.set_memory_poisoning(false);
+    assert_eq!(vm.prog_exec(&mut mem3), 0);
+}
+
+#[test]
+fn test_minimize() {
+    use rbpf::minimize;
+    use rbpf::verifier;
+
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // mov64 r2, 5
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x15, 0x02, 0x02, 0x00, 0x05, 0x00, 0x00, 0x00, // jeq r2, 5, +2
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0x07, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, // add64 r0, 40
+        0x18, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // lddw r3, 1
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+        0x27, 0x02, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // mul64 r2, 3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut tests = 0;
+    let returns_42 = |p: &[u8]| {
+        tests += 1;
+        let p = p.to_vec();
+        verifier::try_check(&p).is_ok() && rbpf::EbpfVmNoData::new(&p).try_prog_exec() == Ok(42)
+    };
+    let reduced = minimize::minimize(&prog, returns_42);
+    assert_eq!(reduced, vec![
+        0x07, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, // add64 r0, 40
+        0x07, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // add64 r0, 2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ]);
+    assert!(tests < 100);
+
+    // The interpreter and the JIT-compiler agree: the program is returned unchanged.
+    let mismatch = minimize::jit_mismatch(&[0x2a; 16]);
+    assert_eq!(minimize::minimize(&prog, mismatch), prog);
+    assert!(!minimize::verifier_panics(&prog));
+}
+
+#[test]
+fn test_conformance_corpus() {
+    use rbpf::conformance::{self, Engine, Runner, TestVector};
+
+    let dir = std::env::temp_dir().join(format!("rbpf-conformance-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let vectors = [
+        ("add.data", "-- asm\nmov64 r0, 1\nadd64 r0, 2\nexit\n-- result\n3\n"),
+        ("div.data", "# Division by zero.\n-- asm\nmov64 r1, 0\ndiv64 r0, r1\nexit\n-- error\ndivision by 0 (insn #1)\n"),
+        ("ldxh.data", "-- raw\n69 10 01 00 00 00 00 00\n95 00 00 00 00 00 00 00\n-- mem\n11 22 33\n-- result\n0x3322\n"),
+        ("verifier.data", "-- asm\nmov64 r0, 1\n-- error\nprogram does not end with “EXIT” instruction\n"),
+        ("wrong.data", "-- asm\nmov64 r0, 1\nexit\n-- result\n2\n"),
+        ("README", "not a test vector"),
+    ];
+    for &(name, text) in vectors.iter() {
+        std::fs::write(dir.join(name), text).unwrap();
+    }
+
+    let loaded = conformance::load_dir(&dir).unwrap();
+    let names: Vec<&str> = loaded.iter().map(|vector| vector.name.as_str()).collect();
+    assert_eq!(names, vec!["add", "div", "ldxh", "verifier", "wrong"]);
+    assert_eq!(loaded[2].mem, vec![0x11, 0x22, 0x33]);
+
+    let report = Runner::new().run_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(report.passed, 4);
+    let engines: Vec<Engine> = report.failures.iter().map(|failure| failure.engine).collect();
+    assert_eq!(engines, vec![Engine::Interpreter, Engine::Jit]);
+    assert!(report.failures.iter().all(|failure| failure.name == "wrong" && failure.actual == Ok(1)));
+
+    let mut runner = Runner::new();
+    runner.set_jit(false);
+    assert_eq!(runner.run(&loaded).failures.len(), 1);
+
+    let err = TestVector::parse("bad", "-- asm\nexit\n").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "bad: expected exactly one of sections \"result\" and \"error\"");
+    assert!(TestVector::parse("bad", "-- asm\nexit\n-- output\n").is_err());
+}
+
+#[test]
+fn test_packet_builder() {
+    use rbpf::csum::Checksum;
+    use rbpf::packet::{self, PacketBuilder};
+
+    let (mac1, mac2) = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
+    let udp = PacketBuilder::new()
+        .ethernet(mac1, mac2)
+        .ipv4([10, 0, 0, 1], [10, 0, 0, 2])
+        .ttl(1)
+        .udp(1234, 53)
+        .payload(&[0xaa; 3])
+        .build();
+    assert_eq!(&udp[..14], &[2, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 1, 0x08, 0x00]);
+    assert_eq!(&udp[14..24], &[0x45, 0, 0, 31, 0, 0, 0x40, 0, 1, packet::IPPROTO_UDP]);
+    assert_eq!(Checksum::new(0).add(&udp[14..34]).finish(), 0);
+    assert_eq!(&udp[34..40], &[0x04, 0xd2, 0, 53, 0, 11]);
+    let pseudo = [10, 0, 0, 1, 10, 0, 0, 2, 0, packet::IPPROTO_UDP, 0, 11];
+    assert_eq!(Checksum::new(0).add(&pseudo).add(&udp[34..]).finish(), 0);
+
+    let src = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    let dst = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+    let tcp = PacketBuilder::new()
+        .ethernet(mac1, mac2)
+        .vlan(0x2064)
+        .ipv6(src, dst)
+        .tcp(80, 40000, packet::TCP_SYN | packet::TCP_ACK)
+        .payload(b"data")
+        .build();
+    assert_eq!(tcp.len(), 18 + 40 + 20 + 4);
+    assert_eq!(&tcp[12..18], &[0x81, 0x00, 0x20, 0x64, 0x86, 0xdd]);
+    assert_eq!(&tcp[22..26], &[0, 24, packet::IPPROTO_TCP, 64]);
+    assert_eq!(tcp[18 + 40 + 13], 0x12);
+    let mut pseudo = [src, dst].concat();
+    pseudo.extend_from_slice(&[0, 0, 0, 24, 0, 0, 0, packet::IPPROTO_TCP]);
+    assert_eq!(Checksum::new(0).add(&pseudo).add(&tcp[58..]).finish(), 0);
+
+    // The VM reports the VLAN tag of the packets built.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vlan = std::rc::Rc::new(std::cell::Cell::new(None));
+    let seen = vlan.clone();
+    let mut mem = tcp.clone();
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    vm.set_mbuff_hook(Box::new(move |_: &mut [u8], info: &rbpf::PacketInfo| seen.set(info.vlan_tci)));
+    vm.prog_exec(&mut mem);
+    assert_eq!(vlan.get(), Some(0x2064));
+}
+
+#[test]
+#[should_panic(expected = "TCP and UDP headers require an IP header")]
+fn test_packet_builder_no_ip() {
+    rbpf::packet::PacketBuilder::new().udp(1, 2).build();
+}
+
+#[test]
+fn test_prog_exec_resizable() {
+    use rbpf::assembler::assemble;
+    use rbpf::error::EbpfError;
+    use rbpf::packet::PacketBuilder;
+    use rbpf::PacketBuffer;
+
+    let udp = PacketBuilder::new()
+        .ethernet([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+        .ipv4([10, 0, 0, 1], [10, 0, 0, 2])
+        .udp(1234, 4789)
+        .payload(b"inner frame")
+        .build();
+    let mut packet = PacketBuffer::new(&udp, 16, 16);
+
+    // Insert 8 bytes after the IPv4 header, then remove them.
+    let room = |len_diff: i32| assemble(&format!("
+        mov64 r2, {}
+        mov64 r3, {}
+        mov64 r4, 0
+        call {}
+        exit", len_diff, helpers::BPF_ADJ_ROOM_NET, helpers::BPF_SKB_ADJUST_ROOM_IDX)).unwrap();
+    let prog = room(8);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    assert_eq!(vm.prog_exec_resizable(&mut packet), 0);
+    assert_eq!(packet.headroom(), 8);
+    assert_eq!(packet.data().len(), udp.len() + 8);
+    assert_eq!(&packet.data()[..34], &udp[..34]);
+    assert_eq!(&packet.data()[34..42], &[0; 8]);
+    assert_eq!(&packet.data()[42..], &udp[34..]);
+
+    let prog = room(-8);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    assert_eq!(vm.prog_exec_resizable(&mut packet), 0);
+    assert_eq!(packet.data(), &udp[..]);
+    assert_eq!(packet.headroom(), 16);
+
+    // Not enough headroom.
+    let prog = assemble(&format!("
+        mov64 r2, -17
+        call {}
+        exit", helpers::BPF_XDP_ADJUST_HEAD_IDX)).unwrap();
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    assert_eq!(vm.prog_exec_resizable(&mut packet), -helpers::EINVAL as u64);
+    assert_eq!(packet.data(), &udp[..]);
+
+    // Pointers loaded before the packet shrinks are checked against the packet resized.
+    let prog = assemble(&format!("
+        ldxdw r6, [r1+8]
+        mov64 r2, -10
+        call {}
+        ldxb r0, [r6-1]
+        exit", helpers::BPF_XDP_ADJUST_TAIL_IDX)).unwrap();
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0, 8);
+    match vm.try_prog_exec_resizable(&mut packet) {
+        Err(EbpfError::OutOfBounds { pc: 3, .. }) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(packet.data(), &udp[..udp.len() - 10]);
+    assert_eq!(packet.tailroom(), 26);
+}
+
+#[test]
+fn test_config_store() {
+    use std::collections::HashMap;
+    use rbpf::assembler::assemble;
+    use rbpf::error::EbpfError;
+
+    // Looks up the key of the first 4 bytes of the packet, and returns the length of its value.
+    let len = assemble(&format!("
+        mov64 r2, 4
+        call {}
+        exit", helpers::CONFIG_VALUE_LEN_IDX)).unwrap();
+    let mut config = HashMap::new();
+    config.insert("mode".to_string(), b"strict".to_vec());
+    let mut vm = rbpf::EbpfVmRaw::new(&len);
+    vm.set_config(config.clone());
+    assert_eq!(vm.prog_exec(&mut b"mode".to_vec()), 6);
+    assert_eq!(vm.prog_exec(&mut b"node".to_vec()), -helpers::ENOENT as u64);
+    vm.config_mut().remove("mode");
+    assert!(vm.config_mut().is_empty());
+
+    // Values are read-only.
+    let store = assemble(&format!("
+        mov64 r2, 4
+        call {}
+        stb [r0], 0
+        exit", helpers::CONFIG_LOOKUP_IDX)).unwrap();
+    let mut vm = rbpf::EbpfVmRaw::new(&store);
+    vm.set_config(config);
+    match vm.try_prog_exec(&mut b"mode".to_vec()) {
+        Err(EbpfError::OutOfBounds { pc: 2, .. }) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn test_check_bounds_within() {
+    use std::time::Duration;
+    use rbpf::assembler::assemble;
+    use rbpf::error::EbpfError;
+    use rbpf::verifier::{self, CancelToken, VerifyBudget};
+
+    // Nested loops, and a load out of the bounds of the context on all paths.
+    let prog = assemble("
+        mov64 r2, 0
+        mov64 r3, 0
+        add64 r3, 1
+        jlt r3, 8, -2
+        add64 r2, 1
+        jlt r2, 8, -5
+        ldxdw r0, [r1+16]
+        exit").unwrap();
+
+    let unlimited = verifier::check_bounds_within(&prog, Some(8), &VerifyBudget::default());
+    let err = verifier::check_bounds(&prog, Some(8)).unwrap_err();
+    assert_eq!(unlimited, Err(EbpfError::VerifierError(err)));
+    let steps = (1..).find(|&max_steps| {
+        let budget = VerifyBudget { max_steps: Some(max_steps), ..VerifyBudget::default() };
+        verifier::check_bounds_within(&prog, Some(8), &budget) == unlimited
+    }).unwrap();
+    let budget = VerifyBudget { max_steps: Some(steps - 1), ..VerifyBudget::default() };
+    let err = verifier::check_bounds_within(&prog, Some(8), &budget).unwrap_err();
+    assert_eq!(err, EbpfError::VerificationTimedOut { steps: steps - 1, cancelled: false });
+    assert_eq!(err.to_string(),
+               format!("[Verifier] Error: verification ran out of its budget after {} steps", steps - 1));
+
+    let budget = VerifyBudget { timeout: Some(Duration::ZERO), ..VerifyBudget::default() };
+    assert_eq!(verifier::check_bounds_within(&prog, Some(8), &budget),
+               Err(EbpfError::VerificationTimedOut { steps: 0, cancelled: false }));
+
+    // Cancelled from another thread.
+    let token = CancelToken::new();
+    let canceller = token.clone();
+    std::thread::spawn(move || canceller.cancel()).join().unwrap();
+    assert!(token.is_cancelled());
+    let budget = VerifyBudget { cancel: Some(token), ..VerifyBudget::default() };
+    assert_eq!(verifier::check_bounds_within(&prog, Some(8), &budget).unwrap_err().to_string(),
+               "[Verifier] Error: verification cancelled after 0 steps");
+}
+
+#[test]
+fn test_security_profile() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use rbpf::ebpf::SecurityProfile;
+    use rbpf::maps::{Map, MapType};
+
+    let nop = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let sqrti = vec![
+        0xb7, 0x01, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, // mov64 r1, 9
+        0x85, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // call 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // Loads a byte of the stack never written.
+    let uninit = vec![
+        0x71, 0xa0, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r10-1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    // Helper 1 is registered: calls to it are allowed, the others are not.
+    let mut vm = rbpf::EbpfVmRaw::new(&nop);
+    vm.register_helper(1, helpers::sqrti);
+    vm.set_security_profile(SecurityProfile::Strict);
+    vm.set_prog(&sqrti);
+    assert_eq!(vm.prog_exec(&mut vec![]), 3);
+    vm.set_prog(&uninit);
+    assert!(vm.try_prog_exec(&mut []).is_err());
+
+    let mut vm = rbpf::EbpfVmRaw::new(&nop);
+    vm.set_security_profile(SecurityProfile::Strict);
+    let res = panic::catch_unwind(AssertUnwindSafe(|| vm.set_prog(&sqrti)));
+    assert_eq!(res.unwrap_err().downcast_ref::<String>().unwrap(),
+               "[Verifier] Error: call to helper function 0x1 denied by policy (insn #1)");
+
+    // The default profile removes the policy. Built-in helpers are allowed once enabled: key 1 is
+    // also `bpf_map_lookup_elem()`.
+    vm.set_security_profile(SecurityProfile::Default);
+    vm.set_prog(&sqrti);
+    vm.register_map(Arc::new(Map::new(MapType::Array, 4, 8, 1)));
+    vm.set_prog(&nop);
+    vm.set_security_profile(SecurityProfile::Strict);
+    vm.set_prog(&sqrti);
+    vm.set_prog(&uninit);
+    assert!(vm.try_prog_exec(&mut []).is_err());
+    vm.set_security_profile(SecurityProfile::Default);
+    assert_eq!(vm.try_prog_exec(&mut []), Ok(0));
+
+    // Packet data is read-only, and executions are limited, under the strict profile only.
+    let store = vec![
+        0x72, 0x01, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r1], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let spin = vec![
+        0xb7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r1, 0
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0x55, 0x01, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&store);
+    vm.set_security_profile(SecurityProfile::Strict);
+    assert!(vm.try_prog_exec(&mut [0]).is_err());
+    assert!(vm.try_jit_compile().is_err());
+    vm.set_prog(&spin);
+    assert_eq!(vm.try_prog_exec(&mut []).unwrap_err().to_string(),
+               "Error: exceeded the limit of 1000000 instructions (insn #2)");
+    vm.set_security_profile(SecurityProfile::Default);
+    vm.set_prog(&store);
+    assert_eq!(vm.try_prog_exec(&mut [0]), Ok(0));
+}
+
+#[test]
+fn test_insn_limit() {
+    use rbpf::Metered;
+    use rbpf::error::EbpfError;
+
+    // Sums the integers from 1 to 10, in 33 instructions.
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // mov64 r1, 10
+        0x0f, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r1
+        0x17, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // sub64 r1, 1
+        0x55, 0x01, 0xfd, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+
+    let mut vm = rbpf::EbpfVmMbuff::new(&prog);
+    vm.set_insn_limit(Some(33));
+    assert_eq!(vm.try_prog_exec(&mut [], &mut []), Ok(55));
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut vec![], &mut vec![]), 55);
+
+    // The instructions are counted across the slices of a metered execution.
+    vm.set_insn_limit(Some(32));
+    let mut outcome = vm.try_prog_exec_metered(&mut [], &mut [], 10).unwrap();
+    let err = loop {
+        match outcome {
+            Metered::Done(_)                  => panic!("expected the limit to be exceeded"),
+            Metered::Suspended(continuation) => match vm.try_resume(continuation, &mut [], &mut [], 10) {
+                Ok(next) => outcome = next,
+                Err(err) => break err,
+            },
+        }
+    };
+    assert_eq!(err, EbpfError::ExceededLimit("Error: exceeded the limit of 32 instructions (insn #5)".to_string()));
+
+    // The JIT-compiler fails for the programs that may run too many instructions, and for the
+    // ones whose loops are not bounded.
+    assert_eq!(vm.try_jit_compile().unwrap_err().to_string(),
+               "[JIT] Error: program may execute 33 instructions, more than the limit of 32");
+    let spin = vec![
+        0x07, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r1, 1
+        0x55, 0x01, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00, // jne r1, 0, -2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmMbuff::new(&spin);
+    vm.set_insn_limit(Some(100));
+    assert!(vm.try_jit_compile().unwrap_err().to_string().starts_with("[JIT] Error: "));
+}
+
+#[test]
+fn test_read_only_packet() {
+    // Stores to the stack are allowed, and the program can be JIT-compiled.
+    let prog = vec![
+        0x72, 0x0a, 0xff, 0xff, 0x2a, 0x00, 0x00, 0x00, // stb [r10-1], 0x2a
+        0x71, 0xa0, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r10-1]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0u8; 8];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_read_only_packet(true);
+    assert_eq!(vm.try_prog_exec(&mut mem), Ok(0x2a));
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(&mut mem), 0x2a);
+
+    // Stores to packet data are rejected.
+    let prog = vec![
+        0x72, 0x01, 0x04, 0x00, 0x2a, 0x00, 0x00, 0x00, // stb [r1+4], 0x2a
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut mem = vec![0u8; 8];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_read_only_packet(true);
+    let err = vm.try_prog_exec(&mut mem).unwrap_err();
+    assert!(err.to_string().contains("packet read-only"), "{}", err);
+    assert_eq!(vm.try_jit_compile().unwrap_err().to_string(),
+               "[JIT] Error: program may store to packet data, which is read-only");
+
+    // So are the writes of the memory helpers, and the helpers resizing packets.
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x79, 0x11, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxdw r1, [r1+0x40] (data)
+        0xb7, 0x02, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r2, 0x2a
+        0xb7, 0x03, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // mov64 r3, 4
+        0x85, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x7f, // call memset
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let (mut read_only, mut writable) = (vec![0u8; 8], vec![0u8; 8]);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    vm.register_mem_helpers();
+    vm.set_read_only_packet(true);
+    assert!(vm.try_prog_exec(&mut read_only).is_err());
+    vm.set_read_only_packet(false);
+    assert_eq!(vm.try_prog_exec(&mut writable), Ok(0));
+    drop(vm);
+    assert_eq!(read_only, vec![0u8; 8]);
+    assert_eq!(&writable[..5], &[0x2a, 0x2a, 0x2a, 0x2a, 0]);
+
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0xfc, 0xff, 0xff, 0xff, // mov64 r2, -4
+        0x85, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, // call bpf_xdp_adjust_head
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut packet = rbpf::PacketBuffer::new(&[0xaa; 60], 16, 0);
+    let mut vm = rbpf::EbpfVmFixedMbuff::new(&prog, 0x40, 0x50);
+    vm.set_read_only_packet(true);
+    assert_eq!(vm.prog_exec_resizable(&mut packet), -helpers::EPERM as u64);
+    assert_eq!(packet.headroom(), 16);
+}
+
+#[test]
+fn test_regression_test() {
+    use rbpf::testing::RegressionTest;
+    use rbpf::trace::TraceLog;
+
+    let prog = vec![
+        0xbf, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r6, r1
+        0x7b, 0x6a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r6
+        0x79, 0xa3, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r3, [r10-8]
+        0xb7, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // mov64 r1, 16
+        0x85, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // call 0x2a
+        0xbf, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r7, r0
+        0x07, 0x07, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r7, 1
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let input: Vec<u8> = (0..20).collect();
+    let mut mem = input.clone();
+    let mut log = TraceLog::new();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_helper(0x2a, helpers::sqrti);
+    assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), 4);
+
+    let test = RegressionTest::new("sqrti_spill", &prog, &input)
+        .helper(0x2a, "rbpf::helpers::sqrti")
+        .generate(log.entries());
+    assert!(test.starts_with("#[test]\nfn sqrti_spill() {\n    let prog = vec![\n"));
+    assert!(test.contains("        0x85, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // call 0x2a\n"));
+    assert!(test.contains(concat!(
+        "    let mut mem: Vec<u8> = vec![\n",
+        "        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,\n",
+        "        0x10, 0x11, 0x12, 0x13,\n",
+        "    ];\n")));
+    assert!(test.contains(concat!(
+        "    let mut vm = rbpf::EbpfVmRaw::new(&prog);\n",
+        "    vm.register_helper(0x2a, rbpf::helpers::sqrti);\n")));
+    assert!(test.contains("    assert_eq!(vm.prog_exec_trace(&mut mem, &mut log), 0x4);\n"));
+    assert!(test.contains("    assert_eq!(log.entries().len(), 8);\n"));
+    // The addresses of the packet data, in R6, and of the stack, in R3 through the stack, are not
+    // checked.
+    assert!(test.contains("    assert_eq!(regs[1], 0x10);\n    assert_eq!(regs[7], 0x5);\n}\n"));
+    assert!(!test.contains("regs[3]") && !test.contains("regs[6]") && !test.contains("FIXME"));
+    assert!(!test.contains("assert_eq!(mem"));
+
+    // Without the helper, the test asks for it.
+    let test = RegressionTest::new("sqrti_spill", &prog, &input).generate(log.entries());
+    assert!(test.contains(concat!(
+        "    // FIXME: register helper 0x2a, unless the interpreter runs it.\n",
+        "    let vm = rbpf::EbpfVmRaw::new(&prog);\n")));
+}
+
+#[test]
+fn test_ctx_bytes() {
+    use rbpf::ctx::CtxBytes;
+
+    let mut buf = vec![0u8; 16];
+    buf.write_u8(0, 0x01);
+    buf.write_u16_le(1, 0x0302);
+    buf.write_u32_le(3, 0x07060504);
+    buf.write_u64_le(7, 0x0f0e0d0c0b0a0908);
+    assert_eq!(buf, (1..16).chain(0..1).collect::<Vec<u8>>());
+    assert_eq!(buf.read_u8(0), 1);
+    assert_eq!(buf.read_u16_le(1), 0x0302);
+    assert_eq!(buf.read_u32_le(3), 0x07060504);
+    assert_eq!(buf.read_u64_le(7), 0x0f0e0d0c0b0a0908);
+    assert_eq!(buf[4..].read_u32_le(0), 0x08070605);
+
+    // The fields must fit in the buffer.
+    let res = std::panic::catch_unwind(move || buf.read_u64_le(9));
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_insn_context() {
+    let prog = vec![
+        0x18, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, // lddw r1, 0x1000
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+        0x07, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // add64 r2, 4
+        0x61, 0x20, 0xfc, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxw r0, [r2-4]
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    let err = vm.try_prog_exec().unwrap_err();
+    let context = err.insn_context(&prog).unwrap();
+    assert_eq!(context.pc, 5);
+    assert_eq!(context.insn, ebpf::Insn { opc: ebpf::LD_W_REG, dst: 0, src: 2, off: -4, imm: 0 });
+    assert_eq!(context.previous.iter().map(|&(pc, _)| pc).collect::<Vec<_>>(), vec![2, 3, 4]);
+    assert_eq!(context.to_string(), concat!(
+        "    #2: mov64 r0, 0x0\n",
+        "    #3: mov64 r2, r1\n",
+        "    #4: add64 r2, 0x4\n",
+        " => #5: ldxw r0, [r2-0x4] (opc 0x61, dst r0, src r2, off -4, imm 0x0)"));
+
+    // The previous instructions include `lddw`, taking two slots, and stop at the start.
+    let err = rbpf::error::EbpfError::DivideByZero { pc: 2 };
+    let context = err.insn_context(&prog).unwrap();
+    assert_eq!(context.previous, vec![(0, "lddw r1, 0x1000".to_string())]);
+    assert_eq!(rbpf::error::EbpfError::DivideByZero { pc: 1 }.insn_context(&prog).unwrap().desc,
+               "(second half of lddw)");
+    assert_eq!(rbpf::error::EbpfError::DivideByZero { pc: 7 }.insn_context(&prog), None);
+    assert_eq!(rbpf::error::EbpfError::UnknownHelper { key: 1 }.insn_context(&prog), None);
+
+    // Panics show the instructions after the message of the error.
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.prog_exec()));
+    let msg = res.unwrap_err().downcast_ref::<String>().unwrap().clone();
+    assert!(msg.starts_with("Error: out of bounds memory load (insn #6), addr 0x1000, size 4\n"));
+    assert!(msg.ends_with(" => #5: ldxw r0, [r2-0x4] (opc 0x61, dst r0, src r2, off -4, imm 0x0)"));
+}
+
+#[test]
+fn test_fallible_helper() {
+    use std::panic::{self, AssertUnwindSafe};
+    use rbpf::error::{EbpfError, HelperError};
+    use rbpf::testing::HelperRecorder;
+
+    fn even(a: u64, _b: u64, _c: u64, _d: u64, _e: u64) -> Result<u64, HelperError> {
+        match a % 2 {
+            0 => Ok(a / 2),
+            _ => Err(HelperError::new("odd argument")),
+        }
+    }
+
+    let prog = vec![
+        0x71, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r1, [r1]
+        0x85, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // call 7
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let recorder = HelperRecorder::new();
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.register_fallible_helper(7, even);
+    vm.set_helper_hook(Box::new(&recorder));
+    assert_eq!(vm.try_prog_exec(&mut [8]), Ok(4));
+    let err = vm.try_prog_exec(&mut [5]).unwrap_err();
+    assert_eq!(err, EbpfError::HelperFailed { pc: 1, key: 7, error: HelperError::new("odd argument") });
+    assert_eq!(err.pc(), Some(1));
+    // Only the call that succeeded is reported to the hook.
+    assert_eq!(recorder.sequence(), vec![7]);
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| vm.prog_exec(&mut vec![3])));
+    let msg = res.unwrap_err().downcast_ref::<String>().unwrap().clone();
+    assert!(msg.starts_with("Error: helper function 0x7 failed: odd argument (insn #1)\n"));
+
+    // Registering a plain helper under the same key replaces the fallible one, and conversely.
+    vm.register_helper(7, helpers::sqrti);
+    assert_eq!(vm.try_prog_exec(&mut [9]), Ok(3));
+    vm.register_fallible_helper(7, even);
+    assert!(vm.try_prog_exec(&mut [9]).is_err());
+}
+
+#[test]
+fn test_metered_execution() {
+    use rbpf::Metered;
+    use rbpf::error::EbpfError;
+
+    // Stores the first byte of the packet on the stack, loads it back, and stores it increased
+    // by one as the second byte of the packet.
+    let prog = vec![
+        0x71, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r2, [r1]
+        0x7b, 0x2a, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // stxdw [r10-8], r2
+        0x79, 0xa3, 0xf8, 0xff, 0x00, 0x00, 0x00, 0x00, // ldxdw r3, [r10-8]
+        0x07, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // add64 r3, 1
+        0x73, 0x31, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // stxb [r1+1], r3
+        0xbf, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r3
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmRaw::new(&prog);
+    vm.set_memory_poisoning(true);
+
+    // Two tenants, interleaved one instruction at a time, each on its own packet.
+    let (mut a, mut b) = (vec![10u8, 0], vec![20u8, 0]);
+    let mut outcomes = [vm.try_prog_exec_metered(&mut a, 1).unwrap(), vm.try_prog_exec_metered(&mut b, 1).unwrap()];
+    let mut steps = 1;
+    while let [Metered::Suspended(_), _] | [_, Metered::Suspended(_)] = outcomes {
+        for (outcome, mem) in outcomes.iter_mut().zip([&mut a, &mut b]) {
+            if let Metered::Suspended(continuation) = std::mem::replace(outcome, Metered::Done(0)) {
+                *outcome = vm.try_resume(continuation, mem, 1).unwrap();
+            }
+        }
+        steps += 1;
+    }
+    assert_eq!(steps, 7);
+    assert!(matches!(outcomes, [Metered::Done(11), Metered::Done(21)]));
+    assert_eq!((a, b), (vec![10, 11], vec![20, 21]));
+
+    // With no fuel, the execution stops before the first instruction.
+    let mut mem = vec![1u8, 0];
+    let continuation = match vm.try_prog_exec_metered(&mut mem, 0).unwrap() {
+        Metered::Suspended(continuation) => continuation,
+        Metered::Done(_)                 => panic!("expected a suspended execution"),
+    };
+    assert_eq!(continuation.insn_ptr(), 0);
+    let continuation = match vm.try_resume(continuation, &mut mem, 3).unwrap() {
+        Metered::Suspended(continuation) => continuation,
+        Metered::Done(_)                 => panic!("expected a suspended execution"),
+    };
+    assert_eq!(continuation.insn_ptr(), 3);
+
+    // The execution must resume on the same packet, with the same VM.
+    let mut other = vec![1u8, 0];
+    match vm.try_resume(continuation, &mut other, 10) {
+        Err(EbpfError::InvalidResume(msg)) => {
+            assert_eq!(msg, "Error: execution resumed on other packet data or metadata buffer than it started on");
+        },
+        _ => panic!("expected an invalid resume"),
+    }
+    let continuation = match vm.try_prog_exec_metered(&mut mem, 1).unwrap() {
+        Metered::Suspended(continuation) => continuation,
+        Metered::Done(_)                 => panic!("expected a suspended execution"),
+    };
+    let other_prog = prog.clone();
+    let other_vm = rbpf::EbpfVmRaw::new(&other_prog);
+    let err = other_vm.try_resume(continuation, &mut mem, 10).err().unwrap();
+    assert_eq!(err.to_string(), "Error: execution resumed by another VM");
+
+    // Enough fuel runs the program to completion, and errors are returned as usual.
+    assert!(matches!(vm.try_prog_exec_metered(&mut mem, 100), Ok(Metered::Done(2))));
+    assert!(vm.try_prog_exec_metered(&mut [1], 100).is_err());
+}
+
+#[test]
+fn test_jit_mov_chains() {
+    fn compile(prog: &[u8]) -> (u64, usize) {
+        let prog = prog.to_vec();
+        let mut mem = vec![0u8, 21];
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        assert_eq!(vm.prog_exec(&mut mem.clone()), 42);
+        vm.jit_compile();
+        (vm.prog_exec_jit(&mut mem), vm.stats().jit_code_size.unwrap())
+    }
+
+    let chain = vec![
+        0xbf, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r2, r1
+        0xbf, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r2
+        0xb7, 0x04, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // mov64 r4, 7
+        0x71, 0x30, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r3+1]
+        0xbf, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r4, r0
+        0x0f, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r4
+        0xbf, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let direct = vec![
+        0x71, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // ldxb r0, [r1+1]
+        0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r0
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    // The copies are read through the original registers, and none of the moves is emitted.
+    assert_eq!(compile(&chain), compile(&direct));
+
+    // Copies do not survive a write to the original register, nor the end of a basic block.
+    let prog = vec![
+        0xb7, 0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r2, 1
+        0xbf, 0x23, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r3, r2
+        0xb7, 0x02, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, // mov64 r2, 20
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ja +0
+        0xbf, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, r3
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r2
+        0x07, 0x00, 0x00, 0x00, 0x15, 0x00, 0x00, 0x00, // add64 r0, 21
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    assert_eq!(compile(&prog).0, 42);
+}
+
+#[test]
+fn test_capabilities() {
+    let caps = rbpf::capabilities();
+    assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+    assert!(caps.opcodes.windows(2).all(|pair| pair[0] < pair[1]));
+    for info in ebpf::OPCODES {
+        assert!(caps.opcodes.binary_search(&info.opc).is_ok());
+        assert!(info.isa <= caps.isa_version);
+    }
+    assert_eq!(caps.features.contains(&"pcap"), cfg!(feature = "pcap"));
+    assert_eq!(caps.features.contains(&"serde"), cfg!(feature = "serde"));
+
+    // Programs made of the opcodes reported run on the JIT targets reported.
+    if caps.jit_targets.contains(&std::env::consts::ARCH) {
+        let prog = vec![
+            0xb7, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, // mov64 r0, 42
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), 42);
+    }
+}
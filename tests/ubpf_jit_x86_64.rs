@@ -23,6 +23,7 @@
 
 extern crate rbpf;
 
+use rbpf::ebpf;
 use rbpf::helpers;
 
 #[test]
@@ -402,6 +403,22 @@ fn test_jit_div64_imm() {
     assert_eq!(vm.prog_exec_jit(), 0x300000000);
 }
 
+// The JIT-compiler used to check the (unused) source register for a zero divisor.
+#[test]
+fn test_jit_div_mod_imm_zero_src() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+        0xb7, 0x02, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // mov64 r2, 12
+        0x37, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // div64 r2, 4
+        0x94, 0x02, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mod32 r2, 2
+        0x0f, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // add64 r0, r2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), 0x1);
+}
+
 #[test]
 fn test_jit_div64_reg() {
     let prog = vec![
@@ -1339,6 +1356,195 @@ fn test_jit_rsh_reg() {
     assert_eq!(vm.prog_exec_jit(), 0x1);
 }
 
+#[test]
+fn test_jit_div_by_zero_kernel() {
+    use rbpf::ebpf::DivByZero;
+    // Divisions yield zero, modulos leave the destination unchanged. 32-bit operations only
+    // consider the lower half of the divisor, and clear the upper half of the destination.
+    let cases = [
+        (ebpf::DIV64_REG, 0u64, 0u64),
+        (ebpf::MOD64_REG, 0, 0xffff_ffff_0000_0007),
+        (ebpf::DIV32_REG, 0x1_0000_0000, 0),
+        (ebpf::MOD32_REG, 0x1_0000_0000, 0x7),
+    ];
+    for &(opc, divisor, expected) in cases.iter() {
+        let prog = vec![
+            0x18, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, // lddw r0, 0xffffffff00000007
+            0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+            0x18, 0x01, 0x00, 0x00, divisor as u8, 0x00, 0x00, 0x00, // lddw r1, divisor
+            0x00, 0x00, 0x00, 0x00, (divisor >> 32) as u8, 0x00, 0x00, 0x00,
+            opc,  0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // op r0, r1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.set_div_by_zero(DivByZero::Kernel);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected, "opcode {:#x}, divisor {:#x}", opc, divisor);
+    }
+}
+
+// Shift amounts are masked to the width of the operation, as in the kernel.
+#[test]
+fn test_jit_shift_masked() {
+    type ShiftOp = fn(u64, u32) -> u64;
+    let value: u64 = 0x8000_0000_8000_0001;
+    let cases: [(u8, ShiftOp); 12] = [
+        (ebpf::LSH64_REG,  |v, n| v.wrapping_shl(n)),
+        (ebpf::RSH64_REG,  |v, n| v.wrapping_shr(n)),
+        (ebpf::ARSH64_REG, |v, n| (v as i64).wrapping_shr(n) as u64),
+        (ebpf::LSH32_REG,  |v, n| (v as u32).wrapping_shl(n) as u64),
+        (ebpf::RSH32_REG,  |v, n| (v as u32).wrapping_shr(n) as u64),
+        (ebpf::ARSH32_REG, |v, n| (v as i32).wrapping_shr(n) as u32 as u64),
+        (ebpf::LSH64_IMM,  |v, n| v.wrapping_shl(n)),
+        (ebpf::RSH64_IMM,  |v, n| v.wrapping_shr(n)),
+        (ebpf::ARSH64_IMM, |v, n| (v as i64).wrapping_shr(n) as u64),
+        (ebpf::LSH32_IMM,  |v, n| (v as u32).wrapping_shl(n) as u64),
+        (ebpf::RSH32_IMM,  |v, n| (v as u32).wrapping_shr(n) as u64),
+        (ebpf::ARSH32_IMM, |v, n| (v as i32).wrapping_shr(n) as u32 as u64),
+    ];
+    for &(opc, op) in cases.iter() {
+        for &shift in [32, 63, 64, 127].iter() {
+            let prog = vec![
+                0x18, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x80, // lddw r0, 0x8000000080000001
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+                0xb7, 0x01, 0x00, 0x00, shift,   0x00, 0x00, 0x00, // mov r1, shift
+                opc,  0x10, 0x00, 0x00, shift,   0x00, 0x00, 0x00, // op r0, r1 (or op r0, shift)
+                0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+            ];
+            let expected = op(value, shift as u32);
+            let mut vm = rbpf::EbpfVmNoData::new(&prog);
+            vm.jit_compile();
+            assert_eq!(vm.prog_exec_jit(), expected, "opcode {:#x}, shift {}", opc, shift);
+        }
+    }
+}
+
+// Signed divisions and modulos, sign-extending moves and unconditional byte swaps (ISA v4).
+#[test]
+fn test_jit_isa_v4_alu() {
+    let cases = [
+        // opcode, offset, immediate, dst, src, result
+        (ebpf::DIV64_REG, 1,  0, (-7i64) as u64,         2u64,           (-3i64) as u64),
+        (ebpf::MOD64_REG, 1,  0, (-7i64) as u64,         2,              (-1i64) as u64),
+        (ebpf::DIV64_REG, 1,  0, i64::MIN as u64,        (-1i64) as u64, i64::MIN as u64),
+        (ebpf::MOD64_REG, 1,  0, i64::MIN as u64,        (-1i64) as u64, 0),
+        (ebpf::DIV32_REG, 1,  0, 0x1_ffff_fff9,          2,              0xffff_fffd),
+        (ebpf::MOD32_REG, 1,  0, 0x1_ffff_fff9,          2,              0xffff_ffff),
+        (ebpf::DIV32_REG, 1,  0, 0x8000_0000,            0xffff_ffff,    0x8000_0000),
+        (ebpf::DIV64_IMM, 1, -1, 5,                      0,              (-5i64) as u64),
+        (ebpf::DIV64_IMM, 1,  2, (-7i64) as u64,         0,              (-3i64) as u64),
+        (ebpf::MOD32_IMM, 1, -3, 7,                      0,              1),
+        (ebpf::MOV64_REG, 8,  0, 0,                      0x80,           0xffff_ffff_ffff_ff80),
+        (ebpf::MOV64_REG, 16, 0, 0,                      0x1234_8000,    0xffff_ffff_ffff_8000),
+        (ebpf::MOV64_REG, 32, 0, 0,                      0x8000_0000,    0xffff_ffff_8000_0000),
+        (ebpf::MOV32_REG, 8,  0, 0,                      0x80,           0xffff_ff80),
+        (ebpf::MOV32_REG, 16, 0, 0,                      0xffff_0000_0000_7fff, 0x7fff),
+        (ebpf::BSWAP,     0, 16, 0x1122_3344_5566_7788,  0,              0x8877),
+        (ebpf::BSWAP,     0, 32, 0x1122_3344_5566_7788,  0,              0x8877_6655),
+        (ebpf::BSWAP,     0, 64, 0x1122_3344_5566_7788,  0,              0x8877_6655_4433_2211),
+    ];
+    for &(opc, off, imm, dst, src, expected) in cases.iter() {
+        let (off, imm) = (off as i16 as u16, imm as u32);
+        let prog = vec![
+            0x18, 0x00, 0x00, 0x00, dst as u8, (dst >> 8) as u8, (dst >> 16) as u8, (dst >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (dst >> 32) as u8, (dst >> 40) as u8, (dst >> 48) as u8, (dst >> 56) as u8,
+            0x18, 0x01, 0x00, 0x00, src as u8, (src >> 8) as u8, (src >> 16) as u8, (src >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (src >> 32) as u8, (src >> 40) as u8, (src >> 48) as u8, (src >> 56) as u8,
+            opc,  0x10, off as u8, (off >> 8) as u8, imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), expected, "opcode {:#x}, offset {}", opc, off);
+    }
+}
+
+// Sign-extending loads (ISA v4).
+#[test]
+fn test_jit_isa_v4_ldsx() {
+    let cases = [
+        (ebpf::LD_B_SX, 0, 0xffff_ffff_ffff_ff80u64),
+        (ebpf::LD_B_SX, 2, 0x7f),
+        (ebpf::LD_H_SX, 2, 0xffff_ffff_ffff_807f),
+        (ebpf::LD_W_SX, 0, 0xffff_ffff_807f_ff80),
+        (ebpf::LD_W_SX, 4, 0x0000_0000_0012_3456),
+    ];
+    for &(opc, off, expected) in cases.iter() {
+        let prog = vec![
+            opc,  0x10, off,  0x00, 0x00, 0x00, 0x00, 0x00, // ldxs r0, [r1+off]
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut mem = vec![0x80, 0xff, 0x7f, 0x80, 0x56, 0x34, 0x12, 0x00];
+        let mut vm = rbpf::EbpfVmRaw::new(&prog);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(&mut mem), expected, "opcode {:#x}, offset {}", opc, off);
+    }
+}
+
+// Jumps on unsigned and signed lower comparisons (ISA v2), and jumps on 32-bit comparisons (ISA v3).
+#[test]
+fn test_jit_isa_v2_v3_jumps() {
+    let cases = [
+        // opcode, dst, src or immediate, taken
+        (ebpf::JLT_REG,  1u64,           2u64,          true),
+        (ebpf::JLT_REG,  2,              2,             false),
+        (ebpf::JLE_IMM,  2,              2,             true),
+        (ebpf::JLT_IMM,  1,              (-1i64) as u64, true),
+        (ebpf::JSLT_REG, (-1i64) as u64, 0,             true),
+        (ebpf::JSLT_IMM, 0,              (-1i64) as u64, false),
+        (ebpf::JSLE_REG, 0,              (-1i64) as u64, false),
+        (ebpf::JSLE_IMM, (-2i64) as u64, (-1i64) as u64, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JEQ,  0x1_0000_0001, 1, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JNE,  0x1_0000_0001, 1, false),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JGT,  0x1_0000_0000, 1, false),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JGE,  0xffff_ffff,   (-1i64) as u64, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JSGT, 0x8000_0000,   0, false),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JSGE, 0x1_0000_0000, (-1i64) as u64, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JSET, 0x1_0000_0000, 0x1_0000_0000, false),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JSET, 0x1_0000_0001, 1, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JLT,  0x2_0000_0001, 0x1_0000_0002, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JLE,  0x1_8000_0000, 1, false),
+        (ebpf::BPF_JMP32 | ebpf::BPF_X | ebpf::BPF_JSLT, 0x8000_0000,   0, true),
+        (ebpf::BPF_JMP32 | ebpf::BPF_K | ebpf::BPF_JSLE, 0x1_0000_0000, (-1i64) as u64, false),
+    ];
+    for &(opc, dst, src, taken) in cases.iter() {
+        let (src_reg, imm) = match opc & ebpf::BPF_X {
+            0 => (0x00, src as u32),
+            _ => (0x10, 0),
+        };
+        let prog = vec![
+            0x18, 0x00, 0x00, 0x00, dst as u8, (dst >> 8) as u8, (dst >> 16) as u8, (dst >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (dst >> 32) as u8, (dst >> 40) as u8, (dst >> 48) as u8, (dst >> 56) as u8,
+            0x18, 0x01, 0x00, 0x00, src as u8, (src >> 8) as u8, (src >> 16) as u8, (src >> 24) as u8,
+            0x00, 0x00, 0x00, 0x00, (src >> 32) as u8, (src >> 40) as u8, (src >> 48) as u8, (src >> 56) as u8,
+            opc,  src_reg, 0x02, 0x00, imm as u8, (imm >> 8) as u8, (imm >> 16) as u8, (imm >> 24) as u8,
+            0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov64 r0, 0
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+            0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+        ];
+        let mut vm = rbpf::EbpfVmNoData::new(&prog);
+        vm.jit_compile();
+        assert_eq!(vm.prog_exec_jit(), taken as u64, "opcode {:#x}, dst {:#x}, src {:#x}", opc, dst, src);
+    }
+}
+
+// `ja32` (ISA v4) has its offset in the immediate.
+#[test]
+fn test_jit_isa_v4_ja32() {
+    let prog = vec![
+        0xb7, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // mov64 r0, 1
+        0x06, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // ja32 +2
+        0xb7, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // mov64 r0, 2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // exit
+        0x06, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff, // ja32 -2
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // exit
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.jit_compile();
+    assert_eq!(vm.prog_exec_jit(), 0x1);
+}
+
 #[test]
 fn test_jit_stack() {
     let prog = vec![
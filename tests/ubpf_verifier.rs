@@ -106,6 +106,60 @@ fn test_verifier_err_jmp_lddw() {
     vm.prog_exec();
 }
 
+#[test]
+#[should_panic(expected = "[Verifier] Error: jump to middle of LD_DW at #1 (insn #2)")]
+fn test_verifier_err_jmp_lddw_backward() {
+    // Not a uBPF test: the jump lands on the second half of the preceding LD_DW.
+    let prog = vec![
+        0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55,
+        0x00, 0x00, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11,
+        0x05, 0x00, 0xfe, 0xff, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: invalid second half of LD_DW instruction (insn #0)")]
+fn test_verifier_err_lddw_second_half() {
+    // Not a uBPF test: only the immediate of the second half of LD_DW may be set.
+    let prog = vec![
+        0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55,
+        0x00, 0x01, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: program does not end with “EXIT” instruction")]
+fn test_verifier_err_lddw_truncated() {
+    // Not a uBPF test: LD_DW is the last instruction, its second half is missing.
+    let prog = vec![
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: unknown eBPF opcode 0x0 (insn #2)")]
+fn test_verifier_err_jmp_opcode_zero() {
+    // Not a uBPF test: an instruction with opcode 0 that does not follow LD_DW is not its second
+    // half, jumping to it is not the error.
+    let prog = vec![
+        0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
 #[test]
 #[should_panic(expected = "[Verifier] Error: jump out of code to #3 (insn #0)")]
 fn test_verifier_err_jmp_out() {
@@ -144,10 +198,35 @@ fn test_verifier_err_too_many_instructions() {
 }
 
 #[test]
-#[should_panic(expected = "[Verifier] Error: unknown eBPF opcode 0x6 (insn #0)")]
+#[should_panic(expected = "[Verifier] Error: unknown eBPF opcode 0xe7 (insn #0)")]
 fn test_verifier_err_unknown_opcode() {
+    // uBPF uses opcode 0x06, which is `ja32` since ISA v4.
     let prog = vec![
-        0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xe7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: opcode 0x16 requires ISA V3 (insn #0)")]
+fn test_verifier_err_isa_version() {
+    // Not a uBPF test: jumps on 32-bit comparisons are only available from ISA v3.
+    let prog = vec![
+        0x16, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let mut vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.set_isa_version(ebpf::IsaVersion::V2);
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: invalid offset for arithmetic instruction (insn #0)")]
+fn test_verifier_err_alu_offset() {
+    // Not a uBPF test: `sdiv` is encoded with offset 1, other offsets are invalid.
+    let prog = vec![
+        0x3f, 0x21, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
     ];
     let vm = rbpf::EbpfVmNoData::new(&prog);
@@ -164,3 +243,63 @@ fn test_verifier_err_write_r10() {
     let vm = rbpf::EbpfVmNoData::new(&prog);
     vm.prog_exec();
 }
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: BPF-to-BPF calls are not supported (insn #0)")]
+fn test_verifier_err_bpf_to_bpf_call() {
+    // Not a uBPF test: the VMs do not run subprograms, the call must not reach helper 1.
+    let prog = vec![
+        0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    let vm = rbpf::EbpfVmNoData::new(&prog);
+    vm.prog_exec();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: recursive call to a subprogram (insn #3)")]
+fn test_verifier_err_recursive_call() {
+    // Not a uBPF test: the subprogram calls itself.
+    let prog = vec![
+        0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x85, 0x10, 0x00, 0x00, 0xfe, 0xff, 0xff, 0xff,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    rbpf::verifier::check_stack_depth(&prog).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: the call stack of 9 frames is too deep (insn #14)")]
+fn test_verifier_err_call_stack_too_deep() {
+    // Not a uBPF test: the main program and eight subprograms each call the next subprogram.
+    let mut prog = vec![];
+    for _ in 0..9 {
+        prog.extend_from_slice(&[
+            0x85, 0x10, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ]);
+    }
+    prog.extend_from_slice(&[
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ]);
+    rbpf::verifier::check_stack_depth(&prog).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "[Verifier] Error: jump out of subprogram (insn #1)")]
+fn test_verifier_err_jump_out_of_subprogram() {
+    // Not a uBPF test: the main program jumps into the subprogram.
+    let prog = vec![
+        0x85, 0x10, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xb7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ];
+    rbpf::verifier::check_stack_depth(&prog).unwrap();
+}